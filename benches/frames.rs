@@ -0,0 +1,48 @@
+//! Renders a fixed scene for a number of frames and prints the CPU and GPU frame times, for tracking performance
+//! across commits, once per latency mode to show what low latency costs. Needs a GPU and a window. Run with
+//! `cargo bench --bench frames`; `BENCH_FRAMES` sets the frame count.
+
+use cgmath::{Matrix4, Point3, Vector3};
+use legaming::renderer3d::{Camera, InstanceData, MeshData};
+use legaming::sync::LatencyMode;
+use legaming::EngineBuilder;
+
+const DEFAULT_FRAMES: u32 = 1000;
+const GRID: i32 = 16;
+
+fn main() -> anyhow::Result<()> {
+    pretty_env_logger::init();
+
+    let frames = std::env::var("BENCH_FRAMES").ok()
+        .and_then(|frames| frames.parse().ok())
+        .unwrap_or(DEFAULT_FRAMES);
+
+    let mut app = EngineBuilder::new()
+        .with_title("legaming benchmark")
+        .with_size(1280, 720)
+        .with_renderer3d(true)
+        .build()?;
+
+    let renderer = app.renderer3d_mut().expect("the 3D renderer was enabled");
+    let cube = unsafe { renderer.create_mesh("cube", MeshData::cube()) }?;
+    let material = unsafe { renderer.create_instance(renderer.default_material()) }?;
+    renderer.set_camera(Camera::look_at(Point3::new(0.0, 20.0, 40.0), Point3::new(0.0, 0.0, 0.0)));
+
+    let instances: Vec<InstanceData> = (-GRID..GRID)
+        .flat_map(|x| (-GRID..GRID).map(move |z| {
+            InstanceData::new(Matrix4::from_translation(Vector3::new(x as f32 * 2.0, 0.0, z as f32 * 2.0)))
+        }))
+        .collect();
+
+    for latency_mode in [LatencyMode::Throughput, LatencyMode::LowLatency] {
+        app.set_latency_mode(latency_mode);
+        let report = app.benchmark_with(frames, |frame| {
+            frame.renderer3d().draw_instanced(cube, material, &instances);
+            Ok(())
+        })?;
+
+        println!("{}", report);
+    }
+
+    Ok(())
+}
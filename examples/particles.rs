@@ -0,0 +1,66 @@
+//! A fountain of particles simulated and drawn entirely on the GPU. Each frame compute shaders emit new particles
+//! into the emitter's storage buffers, integrate and age them, and compact the survivors into an alive list and an
+//! indirect draw command; after a buffer barrier, one instanced draw reads that list and expands every particle
+//! into a camera facing quad in the vertex shader. Needs a GPU and a window. Run with
+//! `cargo run --example particles`; `PARTICLES` sets the pool size.
+
+use std::time::Duration;
+use cgmath::{Matrix4, Point3, Vector3};
+use legaming::particles::{Curve, EmitterDesc};
+use legaming::pipeline::BlendMode;
+use legaming::renderer3d::{Camera, MeshData};
+use legaming::EngineBuilder;
+
+const DEFAULT_PARTICLES: u32 = 100_000;
+const ORBIT_RADIUS: f32 = 4.0;
+const BURST_INTERVAL: Duration = Duration::from_secs(2);
+
+fn main() -> anyhow::Result<()> {
+    pretty_env_logger::init();
+
+    let particles = std::env::var("PARTICLES").ok()
+        .and_then(|particles| particles.parse().ok())
+        .unwrap_or(DEFAULT_PARTICLES);
+
+    let mut app = EngineBuilder::new()
+        .with_title("legaming particles")
+        .with_size(1280, 720)
+        .with_renderer3d(true)
+        .build()?;
+
+    let renderer = app.renderer3d_mut().expect("the 3D renderer was enabled");
+    let cube = unsafe { renderer.create_mesh("cube", MeshData::cube()) }?;
+    let material = unsafe { renderer.create_instance(renderer.default_material()) }?;
+    renderer.set_camera(Camera::look_at(Point3::new(0.0, 8.0, 16.0), Point3::new(0.0, 3.0, 0.0)));
+
+    let fountain = unsafe {
+        renderer.create_emitter(EmitterDesc::new(particles)
+            .with_rate(particles as f32 / 3.0)
+            .with_lifetime(2.0, 3.0)
+            .with_spawn_radius(0.2)
+            .with_velocity(Vector3::new(0.0, 9.0, 0.0), 2.5)
+            .with_acceleration(Vector3::new(0.0, -9.81, 0.0))
+            .with_size_over_life(Curve::linear(0.08, 0.02))
+            .with_color_over_life(Curve::linear([1.0, 0.6, 0.2, 1.0], [0.3, 0.1, 1.0, 0.0]))
+            .with_blend(BlendMode::Additive))
+    }?;
+
+    let mut elapsed = Duration::ZERO;
+    let mut since_burst = Duration::ZERO;
+    app.run_with(move |frame| {
+        elapsed += frame.delta();
+        since_burst += frame.delta();
+
+        let renderer = frame.renderer3d();
+        let angle = elapsed.as_secs_f32() * 0.5;
+        let emitter = renderer.emitter_mut(fountain);
+        emitter.set_position(Point3::new(angle.cos() * ORBIT_RADIUS, 0.5, angle.sin() * ORBIT_RADIUS));
+        if since_burst >= BURST_INTERVAL {
+            since_burst = Duration::ZERO;
+            emitter.burst(particles / 10);
+        }
+
+        renderer.draw(cube, material, Matrix4::from_nonuniform_scale(8.0, 0.1, 8.0));
+        Ok(())
+    })
+}
@@ -0,0 +1,16 @@
+// For material shaders compiled with BINDLESS defined: the frame's texture array, every instance's texture handles,
+// TEXTURE_SLOTS of them each, and the instance being drawn. `material_texture(slot)` is the texture in a slot.
+#extension GL_EXT_nonuniform_qualifier : require
+
+layout(set = 0, binding = 9) uniform sampler2D bindless_textures[];
+
+layout(set = 1, binding = 1, std430) readonly buffer MaterialTextures {
+    uint handles[];
+} material_textures;
+
+// The last word of the 128 bytes every device has, past the renderer's own push constants.
+layout(push_constant) uniform MaterialIndex {
+    layout(offset = 124) uint index;
+} material_index;
+
+#define material_texture(slot) bindless_textures[material_textures.handles[material_index.index * TEXTURE_SLOTS + (slot)]]
@@ -0,0 +1,18 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform CameraUniform {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    vec4 position;
+} camera;
+
+layout(location = 0) in vec3 in_position;
+layout(location = 1) in vec4 in_color;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    gl_Position = camera.view_projection * vec4(in_position, 1.0);
+    out_color = in_color;
+}
@@ -0,0 +1,34 @@
+#version 450
+
+layout(set = 1, binding = 0) uniform sampler2D gbuffer_albedo;
+layout(set = 1, binding = 1) uniform sampler2D gbuffer_normal;
+layout(set = 1, binding = 2) uniform sampler2D gbuffer_position;
+layout(set = 1, binding = 3) uniform sampler2D gbuffer_emissive;
+// White when ambient occlusion is off.
+layout(set = 1, binding = 4) uniform sampler2D ambient_occlusion;
+
+layout(location = 0) in vec2 in_uv;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    ivec2 texel = ivec2(gl_FragCoord.xy);
+    vec4 position = texelFetch(gbuffer_position, texel, 0);
+    if (position.w == 0.0) {
+        discard;
+    }
+
+    vec4 albedo_occlusion = texelFetch(gbuffer_albedo, texel, 0);
+    vec4 normal_roughness = texelFetch(gbuffer_normal, texel, 0);
+    vec4 emissive_metallic = texelFetch(gbuffer_emissive, texel, 0);
+
+    Surface surface = Surface(
+        albedo_occlusion.rgb,
+        normalize(normal_roughness.xyz),
+        emissive_metallic.a,
+        normal_roughness.w,
+        albedo_occlusion.a * texture(ambient_occlusion, in_uv).r,
+        emissive_metallic.rgb
+    );
+    out_color = vec4(pbr_lighting(gl_FragCoord.xy, position.xyz, surface), 1.0);
+}
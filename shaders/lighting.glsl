@@ -0,0 +1,264 @@
+// Declares set 0 as the 3D renderer binds it and shades with the directional light and the lights of the
+// fragment's cluster, both shadowed. Inserted into fragment shaders by `lighting::with_lighting`.
+
+const uint MAX_LIGHTS_PER_CLUSTER = 128u;
+const uint MAX_CASCADES = 4u;
+const uint MAX_SHADOWED_SPOT_LIGHTS = 4u;
+const float LIGHT_KIND_SPOT = 1.0;
+
+const uint DEBUG_VIEW_NORMALS = 2u;
+const uint DEBUG_VIEW_ALBEDO = 3u;
+const uint DEBUG_VIEW_SHADOW_CASCADES = 5u;
+const uint DEBUG_VIEW_LIGHT_COMPLEXITY = 6u;
+
+struct Light {
+    vec4 position_range;
+    vec4 color_intensity;
+    vec4 direction_kind;
+    vec4 attenuation;
+    vec4 spot_cone;
+};
+
+layout(set = 0, binding = 0) uniform CameraUniform {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    vec4 position;
+    // x is the `DebugView` shaded instead of the lit color, when not 0.
+    uvec4 debug;
+} camera;
+
+layout(std430, set = 0, binding = 1) readonly buffer Lights {
+    Light lights[];
+};
+
+layout(std430, set = 0, binding = 2) readonly buffer Clusters {
+    uint cluster_lights[];
+};
+
+layout(set = 0, binding = 3) uniform ClusterParams {
+    mat4 inverse_projection;
+    mat4 view;
+    uvec4 grid;
+    vec4 screen;
+    // Top-left corner of the viewport in xy, for split screens.
+    vec4 offset;
+} clusters;
+
+layout(set = 0, binding = 4) uniform Shadows {
+    mat4 cascade_view_projection[MAX_CASCADES];
+    mat4 spot_view_projection[MAX_SHADOWED_SPOT_LIGHTS];
+    vec4 cascade_splits;
+    vec4 sun_direction;
+    vec4 sun_color;
+    uvec4 counts;
+    vec4 texel_size;
+} shadows;
+
+// Cascades first, then shadowed spot lights.
+layout(set = 0, binding = 5) uniform sampler2DArrayShadow shadow_maps;
+
+// How much of the light rendering shadow map `layer` reaches `world_position`, filtered over a square of texels.
+float shadow_factor(uint layer, mat4 view_projection, vec3 world_position) {
+    vec4 clip = view_projection * vec4(world_position, 1.0);
+    vec3 coords = clip.xyz / clip.w;
+    if (coords.z <= 0.0 || coords.z >= 1.0) {
+        return 1.0;
+    }
+
+    vec2 uv = coords.xy * 0.5 + 0.5;
+    int radius = int(shadows.counts.y);
+    float lit = 0.0;
+
+    for (int y = -radius; y <= radius; ++y) {
+        for (int x = -radius; x <= radius; ++x) {
+            vec2 offset = vec2(x, y) * shadows.texel_size.x;
+            lit += texture(shadow_maps, vec4(uv + offset, float(layer), coords.z));
+        }
+    }
+
+    float side = float(2 * radius + 1);
+    return lit / (side * side);
+}
+
+// The cascade `world_position` samples, or the cascade count past the last one.
+uint shadow_cascade(vec3 world_position) {
+    float depth = -(camera.view * vec4(world_position, 1.0)).z;
+
+    for (uint i = 0u; i < shadows.counts.x; ++i) {
+        if (depth < shadows.cascade_splits[i]) {
+            return i;
+        }
+    }
+
+    return shadows.counts.x;
+}
+
+float directional_shadow(vec3 world_position) {
+    uint cascade = shadow_cascade(world_position);
+    if (cascade < shadows.counts.x) {
+        return shadow_factor(cascade, shadows.cascade_view_projection[cascade], world_position);
+    }
+
+    return 1.0;
+}
+
+uint cluster_index(vec2 frag_coord, vec3 world_position) {
+    uvec3 grid = clusters.grid.xyz;
+    float near = clusters.screen.z;
+    float far = clusters.screen.w;
+
+    float depth = -(camera.view * vec4(world_position, 1.0)).z;
+    uint slice = uint(clamp(log(depth / near) / log(far / near) * float(grid.z), 0.0, float(grid.z - 1u)));
+    uvec2 tile = min(uvec2((frag_coord - clusters.offset.xy) / clusters.screen.xy * vec2(grid.xy)), grid.xy - 1u);
+
+    return tile.x + tile.y * grid.x + slice * grid.x * grid.y;
+}
+
+bool debug_view_active() {
+    return camera.debug.x != 0u;
+}
+
+// What the debug view shows in place of the lit color of a fragment.
+vec3 debug_color(vec3 world_position, vec3 normal, vec3 albedo) {
+    switch (camera.debug.x) {
+    case DEBUG_VIEW_NORMALS:
+        return normal * 0.5 + 0.5;
+    case DEBUG_VIEW_SHADOW_CASCADES: {
+        const vec3 tints[4] = vec3[](vec3(1.0, 0.3, 0.3), vec3(0.3, 1.0, 0.3), vec3(0.3, 0.3, 1.0), vec3(1.0, 1.0, 0.3));
+        uint cascade = shadow_cascade(world_position);
+        return cascade < MAX_CASCADES ? albedo * tints[cascade] : albedo;
+    }
+    case DEBUG_VIEW_LIGHT_COMPLEXITY: {
+        uint count = cluster_lights[cluster_index(gl_FragCoord.xy, world_position) * (MAX_LIGHTS_PER_CLUSTER + 1u)];
+        float heat = clamp(float(count) / 16.0, 0.0, 1.0);
+        return vec3(smoothstep(0.5, 1.0, heat), 1.0 - abs(heat * 2.0 - 1.0), 1.0 - smoothstep(0.0, 0.5, heat));
+    }
+    case DEBUG_VIEW_ALBEDO:
+    default:
+        return albedo;
+    }
+}
+
+// Becomes the debug color when a debug view is active, and `clustered_lighting` adds nothing to it.
+vec3 directional_lighting(vec3 world_position, vec3 normal, vec3 albedo) {
+    if (debug_view_active()) {
+        return debug_color(world_position, normal, albedo);
+    }
+
+    float diffuse = max(dot(normal, shadows.sun_direction.xyz), 0.0) * shadows.sun_direction.w;
+    if (diffuse > 0.0) {
+        diffuse *= directional_shadow(world_position);
+    }
+
+    return albedo * (shadows.sun_color.w + shadows.sun_color.rgb * diffuse);
+}
+
+float light_attenuation(Light light, float distance) {
+    vec3 coefficients = light.attenuation.xyz;
+    float falloff = 1.0 / max(coefficients.x + coefficients.y * distance + coefficients.z * distance * distance, 0.0001);
+
+    // Fades out towards the range so lights culled by it don't pop.
+    float window = clamp(1.0 - pow(distance / light.position_range.w, 4.0), 0.0, 1.0);
+    return falloff * window * window;
+}
+
+// Light arriving at `world_position` from a clustered light, and the direction towards it.
+vec3 light_radiance(Light light, vec3 world_position, out vec3 direction) {
+    vec3 to_light = light.position_range.xyz - world_position;
+    float distance = length(to_light);
+    direction = to_light / max(distance, 0.0001);
+
+    float intensity = light_attenuation(light, distance) * light.color_intensity.w;
+    if (light.direction_kind.w == LIGHT_KIND_SPOT) {
+        float cos_angle = dot(-direction, light.direction_kind.xyz);
+        intensity *= smoothstep(light.spot_cone.y, light.spot_cone.x, cos_angle);
+    }
+
+    int shadow = int(light.spot_cone.z);
+    if (shadow >= 0 && intensity > 0.0) {
+        intensity *= shadow_factor(MAX_CASCADES + uint(shadow), shadows.spot_view_projection[shadow], world_position);
+    }
+
+    return light.color_intensity.rgb * intensity;
+}
+
+vec3 clustered_lighting(vec2 frag_coord, vec3 world_position, vec3 normal, vec3 albedo) {
+    if (debug_view_active()) {
+        return vec3(0.0);
+    }
+
+    uint base = cluster_index(frag_coord, world_position) * (MAX_LIGHTS_PER_CLUSTER + 1u);
+    uint count = cluster_lights[base];
+    vec3 result = vec3(0.0);
+
+    for (uint i = 0u; i < count; ++i) {
+        vec3 direction;
+        vec3 radiance = light_radiance(lights[cluster_lights[base + 1u + i]], world_position, direction);
+        result += albedo * radiance * max(dot(normal, direction), 0.0);
+    }
+
+    return result;
+}
+
+const float PI = 3.14159265359;
+
+// What `pbr_lighting` shades, in the metallic-roughness model glTF uses.
+struct Surface {
+    vec3 albedo;
+    vec3 normal;
+    float metallic;
+    float roughness;
+    float occlusion;
+    vec3 emissive;
+};
+
+// Cook-Torrance with a GGX distribution, Smith-Schlick geometry and Schlick's Fresnel. Radiance is scaled by pi, so
+// lights are as bright on a rough dielectric as with `clustered_lighting`.
+vec3 cook_torrance(Surface surface, vec3 view_direction, vec3 light_direction, vec3 radiance) {
+    vec3 halfway = normalize(view_direction + light_direction);
+    float n_dot_l = max(dot(surface.normal, light_direction), 0.0);
+    float n_dot_v = max(dot(surface.normal, view_direction), 0.0001);
+    float n_dot_h = max(dot(surface.normal, halfway), 0.0);
+
+    float alpha = surface.roughness * surface.roughness;
+    float alpha2 = alpha * alpha;
+    float denominator = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+    float distribution = alpha2 / (PI * denominator * denominator);
+
+    float k = (surface.roughness + 1.0) * (surface.roughness + 1.0) / 8.0;
+    float geometry = n_dot_v / (n_dot_v * (1.0 - k) + k) * n_dot_l / (n_dot_l * (1.0 - k) + k);
+
+    vec3 f0 = mix(vec3(0.04), surface.albedo, surface.metallic);
+    vec3 fresnel = f0 + (1.0 - f0) * pow(1.0 - max(dot(halfway, view_direction), 0.0), 5.0);
+
+    vec3 specular = distribution * geometry * fresnel / max(4.0 * n_dot_v * n_dot_l, 0.0001);
+    vec3 diffuse = (1.0 - fresnel) * (1.0 - surface.metallic) * surface.albedo / PI;
+    return (diffuse + specular) * radiance * PI * n_dot_l;
+}
+
+vec3 pbr_lighting(vec2 frag_coord, vec3 world_position, Surface surface) {
+    if (debug_view_active()) {
+        return debug_color(world_position, surface.normal, surface.albedo);
+    }
+
+    vec3 view_direction = normalize(camera.position.xyz - world_position);
+    vec3 color = surface.albedo * shadows.sun_color.w * surface.occlusion + surface.emissive;
+
+    vec3 sun_direction = shadows.sun_direction.xyz;
+    if (dot(surface.normal, sun_direction) > 0.0) {
+        vec3 radiance = shadows.sun_color.rgb * shadows.sun_direction.w * directional_shadow(world_position);
+        color += cook_torrance(surface, view_direction, sun_direction, radiance);
+    }
+
+    uint base = cluster_index(frag_coord, world_position) * (MAX_LIGHTS_PER_CLUSTER + 1u);
+    uint count = cluster_lights[base];
+
+    for (uint i = 0u; i < count; ++i) {
+        vec3 direction;
+        vec3 radiance = light_radiance(lights[cluster_lights[base + 1u + i]], world_position, direction);
+        color += cook_torrance(surface, view_direction, direction, radiance);
+    }
+
+    return color;
+}
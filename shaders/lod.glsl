@@ -0,0 +1,21 @@
+// Levels cross-fading into each other are drawn with complementary halves of a 4x4 ordered dither. A positive
+// `fade` keeps that part of the pixels, a negative one the rest of them; 0 keeps them all. Inserted into fragment
+// shaders by `lod::with_lod_dither`.
+void lod_dither(float fade) {
+    if (fade == 0.0) {
+        return;
+    }
+
+    const float bayer[16] = float[](
+        0.0, 8.0, 2.0, 10.0,
+        12.0, 4.0, 14.0, 6.0,
+        3.0, 11.0, 1.0, 9.0,
+        15.0, 7.0, 13.0, 5.0
+    );
+
+    ivec2 pixel = ivec2(gl_FragCoord.xy) & 3;
+    float threshold = (bayer[pixel.y * 4 + pixel.x] + 0.5) / 16.0;
+    if ((threshold < abs(fade)) != (fade > 0.0)) {
+        discard;
+    }
+}
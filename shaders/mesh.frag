@@ -0,0 +1,39 @@
+#version 450
+
+#ifdef BINDLESS
+struct MaterialParams {
+    vec4 base_color;
+};
+
+layout(set = 1, binding = 0, std430) readonly buffer Materials {
+    MaterialParams params[];
+} materials;
+
+#define material materials.params[material_index.index]
+#define base_color_texture material_texture(0)
+#else
+layout(set = 1, binding = 0) uniform MaterialParams {
+    vec4 base_color;
+} material;
+
+layout(set = 1, binding = 1) uniform sampler2D base_color_texture;
+#endif
+
+layout(location = 0) in vec3 in_normal;
+layout(location = 1) in vec2 in_uv;
+layout(location = 2) in vec3 in_world_position;
+layout(location = 3) flat in float in_lod_fade;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    vec3 normal = normalize(in_normal);
+    vec4 base_color = texture(base_color_texture, in_uv) * material.base_color;
+
+    vec3 color = directional_lighting(in_world_position, normal, base_color.rgb);
+    color += clustered_lighting(gl_FragCoord.xy, in_world_position, normal, base_color.rgb);
+    out_color = vec4(color, base_color.a);
+
+    // Last, so texture() still has the whole quad for its derivatives.
+    lod_dither(in_lod_fade);
+}
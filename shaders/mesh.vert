@@ -0,0 +1,38 @@
+#version 450
+
+#ifdef MULTIVIEW
+#extension GL_EXT_multiview : require
+#endif
+
+layout(set = 0, binding = 0) uniform CameraUniform {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    vec4 position;
+    uvec4 debug;
+    mat4 view_projections[2];
+} camera;
+
+layout(location = 0) in vec3 in_position;
+layout(location = 1) in vec3 in_normal;
+layout(location = 2) in vec2 in_uv;
+layout(location = 3) in mat4 in_model;
+layout(location = 8) in float in_lod_fade;
+
+layout(location = 0) out vec3 out_normal;
+layout(location = 1) out vec2 out_uv;
+layout(location = 2) out vec3 out_world_position;
+layout(location = 3) flat out float out_lod_fade;
+
+void main() {
+    vec4 world_position = in_model * vec4(in_position, 1.0);
+#ifdef MULTIVIEW
+    gl_Position = camera.view_projections[gl_ViewIndex] * world_position;
+#else
+    gl_Position = camera.view_projection * world_position;
+#endif
+    out_world_position = world_position.xyz;
+    out_normal = mat3(in_model) * in_normal;
+    out_uv = in_uv;
+    out_lod_fade = in_lod_fade;
+}
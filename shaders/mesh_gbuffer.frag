@@ -0,0 +1,43 @@
+#version 450
+
+#ifdef BINDLESS
+struct MaterialParams {
+    vec4 base_color;
+};
+
+layout(set = 1, binding = 0, std430) readonly buffer Materials {
+    MaterialParams params[];
+} materials;
+
+#define material materials.params[material_index.index]
+#define base_color_texture material_texture(0)
+#else
+layout(set = 1, binding = 0) uniform MaterialParams {
+    vec4 base_color;
+} material;
+
+layout(set = 1, binding = 1) uniform sampler2D base_color_texture;
+#endif
+
+layout(location = 0) in vec3 in_normal;
+layout(location = 1) in vec2 in_uv;
+layout(location = 2) in vec3 in_world_position;
+layout(location = 3) flat in float in_lod_fade;
+
+layout(location = 0) out vec4 out_albedo;
+layout(location = 1) out vec4 out_normal;
+layout(location = 2) out vec4 out_position;
+layout(location = 3) out vec4 out_emissive;
+
+void main() {
+    vec4 base_color = texture(base_color_texture, in_uv) * material.base_color;
+
+    // Fully rough and dielectric, which shades close to the forward version.
+    out_albedo = vec4(base_color.rgb, 1.0);
+    out_normal = vec4(normalize(in_normal), 1.0);
+    // w marks the pixel as covered, so the resolve pass leaves the background alone.
+    out_position = vec4(in_world_position, 1.0);
+    out_emissive = vec4(0.0);
+
+    lod_dither(in_lod_fade);
+}
@@ -0,0 +1,17 @@
+#version 450
+
+layout(push_constant) uniform Draw {
+    uint current;
+    uint premultiply;
+} draw;
+
+layout(location = 0) in vec2 in_corner;
+layout(location = 1) in vec4 in_color;
+
+layout(location = 0) out vec4 out_color;
+
+// A soft round sprite.
+void main() {
+    float alpha = in_color.a * (1.0 - smoothstep(0.5, 1.0, length(in_corner)));
+    out_color = draw.premultiply != 0u ? vec4(in_color.rgb * alpha, alpha) : vec4(in_color.rgb, alpha);
+}
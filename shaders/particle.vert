@@ -0,0 +1,56 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform CameraUniform {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    vec4 position;
+} camera;
+
+#ifdef BUFFER_REFERENCE
+// The emitter's pool, reached through the addresses pushed with the draw.
+layout(buffer_reference, std430) readonly buffer ParticlePool {
+    Particle particles[];
+};
+
+layout(buffer_reference, std430) readonly buffer AliveList {
+    uint alive[];
+};
+#endif
+
+layout(push_constant) uniform Draw {
+    uint current;
+    uint premultiply;
+#ifdef BUFFER_REFERENCE
+    ParticlePool pool;
+    AliveList alive_list;
+#endif
+} draw;
+
+layout(location = 0) out vec2 out_corner;
+layout(location = 1) out vec4 out_color;
+
+const vec2 CORNERS[6] = vec2[](
+    vec2(-1.0, -1.0), vec2(1.0, -1.0), vec2(1.0, 1.0),
+    vec2(1.0, 1.0), vec2(-1.0, 1.0), vec2(-1.0, -1.0)
+);
+
+// One camera facing quad per instance, for the particles compaction left on the current alive list.
+void main() {
+#ifdef BUFFER_REFERENCE
+    Particle particle = draw.pool.particles[draw.alive_list.alive[draw.current * emitter.pool.x + gl_InstanceIndex]];
+#else
+    Particle particle = particles[alive[draw.current * emitter.pool.x + gl_InstanceIndex]];
+#endif
+    float life = particle.age / particle.lifetime;
+    float size = SAMPLE_CURVE(scalar_curves, life).y;
+
+    vec2 corner = CORNERS[gl_VertexIndex];
+    vec3 right = vec3(camera.view[0][0], camera.view[1][0], camera.view[2][0]);
+    vec3 up = vec3(camera.view[0][1], camera.view[1][1], camera.view[2][1]);
+    vec3 world = particle.position + (right * corner.x + up * corner.y) * size * 0.5;
+
+    gl_Position = camera.view_projection * vec4(world, 1.0);
+    out_corner = corner;
+    out_color = SAMPLE_CURVE(color_curve, life);
+}
@@ -0,0 +1,65 @@
+// An emitter's parameters and particle pool, in set PARTICLE_SET. Must match `EmitterUniform` and the buffers of
+// `ParticleEmitter` in particles.rs.
+const uint CURVE_SAMPLES = 16u;
+
+#ifdef PARTICLES_READONLY
+#define POOL_ACCESS readonly
+#else
+#define POOL_ACCESS
+#endif
+
+struct Particle {
+    vec3 position;
+    float age;
+    vec3 velocity;
+    float lifetime;
+};
+
+layout(set = PARTICLE_SET, binding = 0) uniform Emitter {
+    vec4 position_radius;
+    vec4 velocity_spread;
+    vec4 acceleration;
+    // Shortest and longest lifetime.
+    vec4 lifetime;
+    // Capacity of the pool.
+    uvec4 pool;
+    // Speed multiplier in x and size in y.
+    vec4 scalar_curves[CURVE_SAMPLES];
+    vec4 velocity_curve[CURVE_SAMPLES];
+    vec4 color_curve[CURVE_SAMPLES];
+} emitter;
+
+layout(std430, set = PARTICLE_SET, binding = 1) POOL_ACCESS buffer Particles {
+    Particle particles[];
+};
+
+// Two lists of `pool.x` indices each, of the particles alive before and after this frame's compaction.
+layout(std430, set = PARTICLE_SET, binding = 2) POOL_ACCESS buffer AliveLists {
+    uint alive[];
+};
+
+layout(std430, set = PARTICLE_SET, binding = 3) POOL_ACCESS buffer DeadList {
+    uint dead[];
+};
+
+// The last four are the `VkDrawIndirectCommand` of the billboards.
+layout(std430, set = PARTICLE_SET, binding = 4) POOL_ACCESS buffer Counters {
+    uint alive_count[2];
+    uint dead_count;
+    uint padding;
+    uint vertex_count;
+    uint instance_count;
+    uint first_vertex;
+    uint first_instance;
+} counters;
+
+uint curve_index(float life) {
+    return min(uint(clamp(life, 0.0, 1.0) * float(CURVE_SAMPLES - 1u)), CURVE_SAMPLES - 2u);
+}
+
+float curve_fraction(float life) {
+    return clamp(life, 0.0, 1.0) * float(CURVE_SAMPLES - 1u) - float(curve_index(life));
+}
+
+// Samples one of the emitter's curves at `life`, from 0 at emission to 1 at death.
+#define SAMPLE_CURVE(curve, life) mix(emitter.curve[curve_index(life)], emitter.curve[curve_index(life) + 1u], curve_fraction(life))
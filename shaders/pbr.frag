@@ -0,0 +1,103 @@
+#version 450
+
+// Compiled a second time with GBUFFER defined for the deferred path.
+
+#ifdef BINDLESS
+struct MaterialParams {
+    vec4 base_color;
+    vec3 emissive;
+    float metallic;
+    float roughness;
+    float normal_scale;
+    float occlusion_strength;
+};
+
+layout(set = 1, binding = 0, std430) readonly buffer Materials {
+    MaterialParams params[];
+} materials;
+
+#define material materials.params[material_index.index]
+#define base_color_texture material_texture(0)
+#define normal_texture material_texture(1)
+#define metallic_roughness_texture material_texture(2)
+#define occlusion_texture material_texture(3)
+#define emissive_texture material_texture(4)
+#else
+layout(set = 1, binding = 0) uniform MaterialParams {
+    vec4 base_color;
+    vec3 emissive;
+    float metallic;
+    float roughness;
+    float normal_scale;
+    float occlusion_strength;
+} material;
+
+layout(set = 1, binding = 1) uniform sampler2D base_color_texture;
+layout(set = 1, binding = 2) uniform sampler2D normal_texture;
+// Roughness in green and metallic in blue, as in glTF.
+layout(set = 1, binding = 3) uniform sampler2D metallic_roughness_texture;
+layout(set = 1, binding = 4) uniform sampler2D occlusion_texture;
+layout(set = 1, binding = 5) uniform sampler2D emissive_texture;
+#endif
+
+layout(location = 0) in vec3 in_normal;
+layout(location = 1) in vec2 in_uv;
+layout(location = 2) in vec3 in_world_position;
+layout(location = 3) flat in float in_lod_fade;
+
+#ifdef GBUFFER
+layout(location = 0) out vec4 out_albedo;
+layout(location = 1) out vec4 out_normal;
+layout(location = 2) out vec4 out_position;
+layout(location = 3) out vec4 out_emissive;
+#else
+layout(location = 0) out vec4 out_color;
+#endif
+
+// Builds the tangent frame from screen space derivatives, so meshes don't need tangents.
+vec3 perturb_normal(vec3 normal, vec3 tangent_normal) {
+    vec3 dp1 = dFdx(in_world_position);
+    vec3 dp2 = dFdy(in_world_position);
+    vec2 duv1 = dFdx(in_uv);
+    vec2 duv2 = dFdy(in_uv);
+
+    vec3 dp2_perp = cross(dp2, normal);
+    vec3 dp1_perp = cross(normal, dp1);
+    vec3 tangent = dp2_perp * duv1.x + dp1_perp * duv2.x;
+    vec3 bitangent = dp2_perp * duv1.y + dp1_perp * duv2.y;
+
+    float length_squared = max(dot(tangent, tangent), dot(bitangent, bitangent));
+    if (length_squared <= 0.0) {
+        return normal;
+    }
+
+    float scale = inversesqrt(length_squared);
+    return normalize(mat3(tangent * scale, bitangent * scale, normal) * tangent_normal);
+}
+
+void main() {
+    vec4 base_color = texture(base_color_texture, in_uv) * material.base_color;
+
+    vec3 tangent_normal = texture(normal_texture, in_uv).xyz * 2.0 - 1.0;
+    tangent_normal.xy *= material.normal_scale;
+    vec3 normal = perturb_normal(normalize(in_normal), normalize(tangent_normal));
+
+    vec4 metallic_roughness = texture(metallic_roughness_texture, in_uv);
+    float metallic = clamp(metallic_roughness.b * material.metallic, 0.0, 1.0);
+    float roughness = clamp(metallic_roughness.g * material.roughness, 0.04, 1.0);
+    float occlusion = mix(1.0, texture(occlusion_texture, in_uv).r, material.occlusion_strength);
+    vec3 emissive = texture(emissive_texture, in_uv).rgb * material.emissive;
+
+#ifdef GBUFFER
+    out_albedo = vec4(base_color.rgb, occlusion);
+    out_normal = vec4(normal, roughness);
+    out_position = vec4(in_world_position, 1.0);
+    out_emissive = vec4(emissive, metallic);
+#else
+    Surface surface = Surface(base_color.rgb, normal, metallic, roughness, occlusion, emissive);
+    out_color = vec4(pbr_lighting(gl_FragCoord.xy, in_world_position, surface), base_color.a);
+#endif
+
+    // After everything taking derivatives, which need the whole quad.
+    lod_dither(in_lod_fade);
+}
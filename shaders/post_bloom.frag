@@ -0,0 +1,57 @@
+#version 450
+
+// One stage of the bloom chain, picked with a define: PREFILTER, DOWNSAMPLE, UPSAMPLE or COMPOSITE.
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+#if defined(UPSAMPLE) || defined(COMPOSITE)
+layout(set = 0, binding = 1) uniform sampler2D base;
+#endif
+
+layout(push_constant) uniform BloomParams {
+    float threshold;
+    float knee;
+    float intensity;
+} params;
+
+layout(location = 0) in vec2 in_uv;
+
+layout(location = 0) out vec4 out_color;
+
+// Four bilinear taps between texels, averaging a 4x4 block of the source.
+vec3 downsample(vec2 uv) {
+    vec2 texel = 1.0 / vec2(textureSize(source, 0));
+    return 0.25 * (
+        texture(source, uv + texel * vec2(-1.0, -1.0)).rgb +
+        texture(source, uv + texel * vec2(1.0, -1.0)).rgb +
+        texture(source, uv + texel * vec2(-1.0, 1.0)).rgb +
+        texture(source, uv + texel * vec2(1.0, 1.0)).rgb
+    );
+}
+
+// A 3x3 tent filter, which hides the blockiness of the lower level.
+vec3 upsample(vec2 uv) {
+    vec2 texel = 1.0 / vec2(textureSize(source, 0));
+    vec3 color = texture(source, uv).rgb * 4.0;
+    color += (texture(source, uv + vec2(texel.x, 0.0)).rgb + texture(source, uv - vec2(texel.x, 0.0)).rgb) * 2.0;
+    color += (texture(source, uv + vec2(0.0, texel.y)).rgb + texture(source, uv - vec2(0.0, texel.y)).rgb) * 2.0;
+    color += texture(source, uv + texel).rgb + texture(source, uv - texel).rgb;
+    color += texture(source, uv + vec2(texel.x, -texel.y)).rgb + texture(source, uv + vec2(-texel.x, texel.y)).rgb;
+    return color / 16.0;
+}
+
+void main() {
+#if defined(PREFILTER)
+    vec3 color = downsample(in_uv);
+    float brightness = max(color.r, max(color.g, color.b));
+    float soft = clamp(brightness - params.threshold + params.knee, 0.0, 2.0 * params.knee);
+    soft = soft * soft / (4.0 * params.knee + 1e-4);
+    out_color = vec4(color * max(soft, brightness - params.threshold) / max(brightness, 1e-4), 1.0);
+#elif defined(DOWNSAMPLE)
+    out_color = vec4(downsample(in_uv), 1.0);
+#elif defined(UPSAMPLE)
+    out_color = vec4(upsample(in_uv) + texture(base, in_uv).rgb, 1.0);
+#else
+    vec4 scene = texture(base, in_uv);
+    out_color = vec4(scene.rgb + upsample(in_uv) * params.intensity, scene.a);
+#endif
+}
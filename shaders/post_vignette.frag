@@ -0,0 +1,23 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+
+layout(push_constant) uniform VignetteParams {
+    float intensity;
+    float radius;
+    float smoothness;
+} params;
+
+layout(location = 0) in vec2 in_uv;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    vec4 color = texture(source, in_uv);
+
+    // 0 in the center, 1 in the corners.
+    float distance = length(in_uv - 0.5) * sqrt(2.0);
+    float falloff = smoothstep(params.radius, params.radius + params.smoothness, distance);
+
+    out_color = vec4(color.rgb * (1.0 - params.intensity * falloff), color.a);
+}
@@ -0,0 +1,12 @@
+#version 450
+
+layout(push_constant) uniform PushConstants {
+    mat4 light_view_projection;
+} push;
+
+layout(location = 0) in vec3 in_position;
+layout(location = 3) in mat4 in_model;
+
+void main() {
+    gl_Position = push.light_view_projection * in_model * vec4(in_position, 1.0);
+}
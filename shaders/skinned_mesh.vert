@@ -0,0 +1,66 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform CameraUniform {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    vec4 position;
+} camera;
+
+// Every skinned draw's joints of the frame, one after the other.
+layout(std430, set = 0, binding = 6) readonly buffer JointPalette {
+    mat4 joints[];
+} palette;
+
+// Per vertex, a position and a normal delta for each morph target of its mesh.
+layout(std430, set = 0, binding = 7) readonly buffer MorphDeltas {
+    vec4 deltas[];
+} morph_deltas;
+
+// Every skinned draw's morph target weights of the frame, one after the other.
+layout(std430, set = 0, binding = 8) readonly buffer MorphWeights {
+    float weights[];
+} morph_weights;
+
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+    uint first_joint;
+    uint first_weight;
+    uint first_delta;
+    uint morph_target_count;
+} push;
+
+layout(location = 0) in vec3 in_position;
+layout(location = 1) in vec3 in_normal;
+layout(location = 2) in vec2 in_uv;
+layout(location = 9) in uvec4 in_joints;
+layout(location = 10) in vec4 in_weights;
+
+layout(location = 0) out vec3 out_normal;
+layout(location = 1) out vec2 out_uv;
+layout(location = 2) out vec3 out_world_position;
+layout(location = 3) flat out float out_lod_fade;
+
+void main() {
+    vec3 position = in_position;
+    vec3 normal = in_normal;
+    for (uint morph = 0; morph < push.morph_target_count; morph++) {
+        float weight = morph_weights.weights[push.first_weight + morph];
+        uint delta = push.first_delta + (uint(gl_VertexIndex) * push.morph_target_count + morph) * 2;
+        position += weight * morph_deltas.deltas[delta].xyz;
+        normal += weight * morph_deltas.deltas[delta + 1].xyz;
+    }
+
+    mat4 skin = mat4(0.0);
+    for (int influence = 0; influence < 4; influence++) {
+        skin += in_weights[influence] * palette.joints[push.first_joint + in_joints[influence]];
+    }
+
+    mat4 model = push.model * skin;
+    vec4 world_position = model * vec4(position, 1.0);
+    gl_Position = camera.view_projection * world_position;
+    out_world_position = world_position.xyz;
+    out_normal = mat3(model) * normal;
+    out_uv = in_uv;
+    out_lod_fade = 0.0;
+}
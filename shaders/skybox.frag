@@ -0,0 +1,11 @@
+#version 450
+
+layout(set = 1, binding = 0) uniform samplerCube environment;
+
+layout(location = 0) in vec3 in_direction;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    out_color = vec4(texture(environment, normalize(in_direction)).rgb, 1.0);
+}
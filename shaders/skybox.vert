@@ -0,0 +1,19 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform CameraUniform {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    vec4 position;
+} camera;
+
+layout(location = 0) out vec3 out_direction;
+
+// A fullscreen triangle on the far plane, drawn where the depth buffer still holds its clear value.
+void main() {
+    vec2 position = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2) * 2.0 - 1.0;
+    gl_Position = vec4(position, 1.0, 1.0);
+
+    vec4 world = inverse(camera.view_projection) * vec4(position, 1.0, 1.0);
+    out_direction = world.xyz / world.w - camera.position.xyz;
+}
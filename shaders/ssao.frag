@@ -0,0 +1,106 @@
+#version 450
+
+// Screen-space ambient occlusion of the G-buffer, or with BLUR defined, a blur of it that keeps edges.
+
+layout(set = 0, binding = 0) uniform CameraUniform {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    vec4 position;
+} camera;
+
+#ifdef BLUR
+layout(set = 1, binding = 0) uniform sampler2D occlusion;
+#else
+layout(set = 1, binding = 0) uniform sampler2D gbuffer_normal;
+#endif
+layout(set = 1, binding = 1) uniform sampler2D gbuffer_position;
+
+layout(push_constant) uniform SsaoParams {
+    uint samples;
+    float radius;
+    float bias;
+    float intensity;
+    int blur_radius;
+} params;
+
+layout(location = 0) in vec2 in_uv;
+
+layout(location = 0) out vec4 out_occlusion;
+
+const float GOLDEN_ANGLE = 2.39996323;
+
+// Turns the sample pattern per pixel, trading banding for noise the blur smooths out.
+float interleaved_gradient_noise(vec2 frag_coord) {
+    return fract(52.9829189 * fract(dot(frag_coord, vec2(0.06711056, 0.00583715))));
+}
+
+float view_depth(vec3 world_position) {
+    return -(camera.view * vec4(world_position, 1.0)).z;
+}
+
+void main() {
+    ivec2 texel = ivec2(gl_FragCoord.xy);
+    vec4 position = texelFetch(gbuffer_position, texel, 0);
+    if (position.w == 0.0) {
+        out_occlusion = vec4(1.0);
+        return;
+    }
+
+#ifdef BLUR
+    ivec2 size = textureSize(occlusion, 0);
+    float sum = 0.0;
+    float total = 0.0;
+
+    for (int y = -params.blur_radius; y <= params.blur_radius; ++y) {
+        for (int x = -params.blur_radius; x <= params.blur_radius; ++x) {
+            ivec2 neighbour = clamp(texel + ivec2(x, y), ivec2(0), size - 1);
+            vec4 neighbour_position = texelFetch(gbuffer_position, neighbour, 0);
+
+            // Neighbours far from this surface, or on the sky, barely count.
+            vec3 offset = neighbour_position.xyz - position.xyz;
+            float weight = neighbour_position.w == 0.0 ? 0.0 : 1.0 / (1.0 + dot(offset, offset) / (params.radius * params.radius));
+            sum += texelFetch(occlusion, neighbour, 0).r * weight;
+            total += weight;
+        }
+    }
+
+    out_occlusion = vec4(sum / max(total, 1e-4));
+#else
+    vec3 normal = normalize(texelFetch(gbuffer_normal, texel, 0).xyz);
+    vec3 helper = abs(normal.y) < 0.99 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0);
+    vec3 tangent = normalize(cross(helper, normal));
+    vec3 bitangent = cross(normal, tangent);
+
+    ivec2 size = textureSize(gbuffer_position, 0);
+    float depth = view_depth(position.xyz);
+    float rotation = interleaved_gradient_noise(gl_FragCoord.xy) * 6.28318531;
+    float occluded = 0.0;
+
+    for (uint i = 0u; i < params.samples; ++i) {
+        // A cosine weighted spiral over the hemisphere around the normal, denser close to the surface.
+        float t = (float(i) + 0.5) / float(params.samples);
+        float angle = float(i) * GOLDEN_ANGLE + rotation;
+        vec3 direction = (tangent * cos(angle) + bitangent * sin(angle)) * sqrt(t) + normal * sqrt(1.0 - t);
+        vec3 sample_position = position.xyz + direction * params.radius * mix(0.1, 1.0, t * t);
+
+        vec4 clip = camera.view_projection * vec4(sample_position, 1.0);
+        vec2 uv = clip.xy / clip.w * 0.5 + 0.5;
+        if (clip.w <= 0.0 || any(lessThan(uv, vec2(0.0))) || any(greaterThanEqual(uv, vec2(1.0)))) {
+            continue;
+        }
+
+        vec4 scene = texelFetch(gbuffer_position, ivec2(uv * vec2(size)), 0);
+        if (scene.w == 0.0) {
+            continue;
+        }
+
+        // Geometry in front of the sample occludes it, unless it's far out of reach of this pixel.
+        float scene_depth = view_depth(scene.xyz);
+        float in_range = smoothstep(0.0, 1.0, params.radius / max(abs(depth - scene_depth), 1e-4));
+        occluded += (scene_depth <= view_depth(sample_position) - params.bias ? 1.0 : 0.0) * in_range;
+    }
+
+    out_occlusion = vec4(clamp(1.0 - occluded / float(max(params.samples, 1u)) * params.intensity, 0.0, 1.0));
+#endif
+}
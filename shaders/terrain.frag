@@ -0,0 +1,56 @@
+#version 450
+
+// Compiled a second time with GBUFFER defined for the deferred path.
+
+layout(set = 1, binding = 0) uniform MaterialParams {
+    vec4 base_color;
+    float tiling;
+} material;
+
+// How much of layers 1 to 3 to blend in, in red, green and blue. Layer 0 gets the rest.
+layout(set = 1, binding = 1) uniform sampler2D control_texture;
+layout(set = 1, binding = 2) uniform sampler2D layer0_texture;
+layout(set = 1, binding = 3) uniform sampler2D layer1_texture;
+layout(set = 1, binding = 4) uniform sampler2D layer2_texture;
+layout(set = 1, binding = 5) uniform sampler2D layer3_texture;
+
+layout(location = 0) in vec3 in_normal;
+// Across the whole terrain, from 0 to 1.
+layout(location = 1) in vec2 in_uv;
+layout(location = 2) in vec3 in_world_position;
+
+#ifdef GBUFFER
+layout(location = 0) out vec4 out_albedo;
+layout(location = 1) out vec4 out_normal;
+layout(location = 2) out vec4 out_position;
+layout(location = 3) out vec4 out_emissive;
+#else
+layout(location = 0) out vec4 out_color;
+#endif
+
+void main() {
+    vec3 control = texture(control_texture, in_uv).rgb;
+    vec4 weights = vec4(max(1.0 - control.r - control.g - control.b, 0.0), control);
+    weights /= max(weights.x + weights.y + weights.z + weights.w, 1e-4);
+
+    vec2 layer_uv = in_uv * material.tiling;
+    vec4 base_color = texture(layer0_texture, layer_uv) * weights.x
+        + texture(layer1_texture, layer_uv) * weights.y
+        + texture(layer2_texture, layer_uv) * weights.z
+        + texture(layer3_texture, layer_uv) * weights.w;
+    base_color *= material.base_color;
+
+    vec3 normal = normalize(in_normal);
+
+#ifdef GBUFFER
+    // Fully rough and dielectric, like the lit material.
+    out_albedo = vec4(base_color.rgb, 1.0);
+    out_normal = vec4(normal, 1.0);
+    out_position = vec4(in_world_position, 1.0);
+    out_emissive = vec4(0.0);
+#else
+    vec3 color = directional_lighting(in_world_position, normal, base_color.rgb);
+    color += clustered_lighting(gl_FragCoord.xy, in_world_position, normal, base_color.rgb);
+    out_color = vec4(color, 1.0);
+#endif
+}
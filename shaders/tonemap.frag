@@ -0,0 +1,57 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D scene;
+
+layout(set = 0, binding = 1) readonly buffer Exposure {
+    uint histogram[256];
+    float luminance;
+} auto_exposure;
+
+layout(push_constant) uniform ToneMappingParams {
+    float exposure;
+    // 0 for ACES, 1 for Reinhard, 2 for none.
+    uint tone_operator;
+    uint use_auto_exposure;
+    // Set when the output format doesn't encode to sRGB itself.
+    uint encode_srgb;
+} params;
+
+layout(location = 0) in vec2 in_uv;
+
+layout(location = 0) out vec4 out_color;
+
+// Krzysztof Narkowicz's fit of the ACES filmic curve.
+vec3 aces(vec3 color) {
+    return clamp((color * (2.51 * color + 0.03)) / (color * (2.43 * color + 0.59) + 0.14), 0.0, 1.0);
+}
+
+vec3 reinhard(vec3 color) {
+    return color / (1.0 + color);
+}
+
+vec3 encode_srgb(vec3 color) {
+    return mix(color * 12.92, 1.055 * pow(color, vec3(1.0 / 2.4)) - 0.055, step(vec3(0.0031308), color));
+}
+
+void main() {
+    float exposure = params.exposure;
+    if (params.use_auto_exposure != 0u) {
+        // Exposes the average luminance to middle grey, as a camera metering at ISO 100 would.
+        exposure /= 9.6 * max(auto_exposure.luminance, 1e-4);
+    }
+
+    vec3 color = texture(scene, in_uv).rgb * exposure;
+    if (params.tone_operator == 0u) {
+        color = aces(color);
+    } else if (params.tone_operator == 1u) {
+        color = reinhard(color);
+    } else {
+        color = clamp(color, 0.0, 1.0);
+    }
+
+    if (params.encode_srgb != 0u) {
+        color = encode_srgb(color);
+    }
+
+    out_color = vec4(color, 1.0);
+}
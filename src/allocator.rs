@@ -0,0 +1,371 @@
+use std::ptr::NonNull;
+use ash::vk;
+use log::{debug, warn};
+use thiserror::Error;
+
+/// Regular allocations are sub-allocated from blocks of this size. Smaller heaps use an eighth of the heap instead.
+const DEFAULT_BLOCK_SIZE: vk::DeviceSize = 256 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum AllocationError {
+    #[error("No memory type matches {location:?} for type bits {type_bits:#b}")]
+    NoCompatibleMemoryType { location: MemoryLocation, type_bits: u32 },
+    #[error("Allocation of {0} bytes has zero size or an invalid alignment")]
+    InvalidRequest(vk::DeviceSize),
+}
+
+/// Where an allocation should live, which decides the memory type it is taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryLocation {
+    /// Device local memory that the CPU never touches (render targets, static vertex data uploaded via staging).
+    GpuOnly,
+    /// Host visible, coherent memory written by the CPU every frame (staging and uniform buffers).
+    CpuToGpu,
+    /// Host visible memory the GPU writes and the CPU reads back, preferably cached.
+    GpuToCpu,
+}
+
+impl MemoryLocation {
+    fn required_flags(self) -> vk::MemoryPropertyFlags {
+        match self {
+            Self::GpuOnly => vk::MemoryPropertyFlags::empty(),
+            Self::CpuToGpu => vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            Self::GpuToCpu => vk::MemoryPropertyFlags::HOST_VISIBLE,
+        }
+    }
+
+    fn preferred_flags(self) -> vk::MemoryPropertyFlags {
+        match self {
+            Self::GpuOnly => vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            Self::CpuToGpu => vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            Self::GpuToCpu => vk::MemoryPropertyFlags::HOST_CACHED | vk::MemoryPropertyFlags::HOST_COHERENT,
+        }
+    }
+
+    fn is_mapped(self) -> bool {
+        self != Self::GpuOnly
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AllocationDesc<'a> {
+    pub name: &'a str,
+    pub requirements: vk::MemoryRequirements,
+    pub location: MemoryLocation,
+    /// `true` for buffers and linearly tiled images. Linear and optimal resources are kept in separate blocks so
+    /// `bufferImageGranularity` never has to be considered.
+    pub linear: bool,
+}
+
+/// A range of device memory handed out by the [`Allocator`]. It must be returned with [`Allocator::free`]; dropping
+/// it leaks the range until the allocator is destroyed.
+#[derive(Debug)]
+pub struct Allocation {
+    memory: vk::DeviceMemory,
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+    mapped_ptr: Option<NonNull<u8>>,
+    memory_type_index: u32,
+    block_id: u64,
+}
+
+// The mapped pointer is only a view into persistently mapped memory owned by the allocator.
+unsafe impl Send for Allocation {}
+unsafe impl Sync for Allocation {}
+
+impl Allocation {
+    pub fn memory(&self) -> vk::DeviceMemory {
+        self.memory
+    }
+
+    pub fn offset(&self) -> vk::DeviceSize {
+        self.offset
+    }
+
+    pub fn size(&self) -> vk::DeviceSize {
+        self.size
+    }
+
+    pub fn memory_type_index(&self) -> u32 {
+        self.memory_type_index
+    }
+
+    /// Pointer to the start of the allocation for host visible locations.
+    pub fn mapped_ptr(&self) -> Option<NonNull<u8>> {
+        self.mapped_ptr
+    }
+
+    /// The mapped allocation as a read-only byte slice, or `None` for [`MemoryLocation::GpuOnly`].
+    pub fn mapped_slice(&self) -> Option<&[u8]> {
+        self.mapped_ptr.map(|ptr| unsafe { std::slice::from_raw_parts(ptr.as_ptr(), self.size as usize) })
+    }
+
+    /// The mapped allocation as a byte slice, or `None` for [`MemoryLocation::GpuOnly`].
+    pub fn mapped_slice_mut(&mut self) -> Option<&mut [u8]> {
+        self.mapped_ptr.map(|ptr| unsafe { std::slice::from_raw_parts_mut(ptr.as_ptr(), self.size as usize) })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FreeRange {
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+}
+
+struct MemoryBlock {
+    id: u64,
+    memory: vk::DeviceMemory,
+    size: vk::DeviceSize,
+    mapped_ptr: Option<NonNull<u8>>,
+    linear: bool,
+    dedicated: bool,
+    /// Free ranges sorted by offset, never adjacent to each other.
+    free_ranges: Vec<FreeRange>,
+    allocation_count: usize,
+}
+
+impl MemoryBlock {
+    fn try_allocate(&mut self, size: vk::DeviceSize, alignment: vk::DeviceSize) -> Option<vk::DeviceSize> {
+        let (index, offset) = self.free_ranges.iter()
+            .enumerate()
+            .find_map(|(index, range)| {
+                let offset = range.offset.next_multiple_of(alignment);
+                (offset + size <= range.offset + range.size).then_some((index, offset))
+            })?;
+
+        let range = self.free_ranges.remove(index);
+        let tail = FreeRange {
+            offset: offset + size,
+            size: range.offset + range.size - (offset + size),
+        };
+
+        if tail.size > 0 {
+            self.free_ranges.insert(index, tail);
+        }
+
+        if offset > range.offset {
+            self.free_ranges.insert(index, FreeRange {
+                offset: range.offset,
+                size: offset - range.offset,
+            });
+        }
+
+        self.allocation_count += 1;
+        Some(offset)
+    }
+
+    fn free(&mut self, offset: vk::DeviceSize, size: vk::DeviceSize) {
+        let index = self.free_ranges.partition_point(|range| range.offset < offset);
+        self.free_ranges.insert(index, FreeRange { offset, size });
+
+        if index + 1 < self.free_ranges.len() {
+            let next = self.free_ranges[index + 1];
+            if offset + size == next.offset {
+                self.free_ranges[index].size += next.size;
+                self.free_ranges.remove(index + 1);
+            }
+        }
+
+        if index > 0 {
+            let previous = self.free_ranges[index - 1];
+            if previous.offset + previous.size == offset {
+                self.free_ranges[index - 1].size += self.free_ranges[index].size;
+                self.free_ranges.remove(index);
+            }
+        }
+
+        self.allocation_count -= 1;
+    }
+}
+
+/// Sub-allocates buffers and images from large `VkDeviceMemory` blocks so the number of live allocations stays far
+/// below `maxMemoryAllocationCount`. Requests larger than half a block get a dedicated block of their own. Host
+/// visible blocks stay mapped for their whole lifetime.
+pub struct Allocator {
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    blocks: Vec<Vec<MemoryBlock>>,
+    next_block_id: u64,
+    device_address: bool,
+}
+
+// Block pointers refer to mapped device memory, which any thread may access; the device keeps the allocator behind
+// a mutex.
+unsafe impl Send for Allocator {}
+
+impl Allocator {
+    pub fn new(memory_properties: vk::PhysicalDeviceMemoryProperties) -> Self {
+        Self {
+            blocks: (0..memory_properties.memory_type_count).map(|_| Vec::new()).collect(),
+            memory_properties,
+            next_block_id: 0,
+            device_address: false,
+        }
+    }
+
+    /// Allocates the blocks of buffers so their device address can be queried, which needs the
+    /// `bufferDeviceAddress` feature.
+    pub fn with_device_address(mut self, device_address: bool) -> Self {
+        self.device_address = device_address;
+        self
+    }
+
+    pub fn memory_properties(&self) -> &vk::PhysicalDeviceMemoryProperties {
+        &self.memory_properties
+    }
+
+    /// Finds the memory type allowed by `type_bits` that has all of `required` flags, preferring one that also has
+    /// `preferred`.
+    pub fn find_memory_type(
+        &self,
+        type_bits: u32,
+        required: vk::MemoryPropertyFlags,
+        preferred: vk::MemoryPropertyFlags,
+    ) -> Option<u32> {
+        let types = &self.memory_properties.memory_types[..self.memory_properties.memory_type_count as usize];
+        let find = |flags: vk::MemoryPropertyFlags| {
+            types.iter()
+                .enumerate()
+                .position(|(index, memory_type)| type_bits & (1 << index) != 0 && memory_type.property_flags.contains(flags))
+                .map(|index| index as u32)
+        };
+
+        find(required | preferred).or_else(|| find(required))
+    }
+
+    fn block_size(&self, memory_type_index: u32) -> vk::DeviceSize {
+        let heap_index = self.memory_properties.memory_types[memory_type_index as usize].heap_index;
+        let heap_size = self.memory_properties.memory_heaps[heap_index as usize].size;
+        DEFAULT_BLOCK_SIZE.min(heap_size / 8)
+    }
+
+    pub unsafe fn allocate(&mut self, device: &ash::Device, desc: &AllocationDesc) -> anyhow::Result<Allocation> {
+        let requirements = desc.requirements;
+        if requirements.size == 0 || !requirements.alignment.is_power_of_two() {
+            return Err(AllocationError::InvalidRequest(requirements.size).into());
+        }
+
+        let memory_type_index = self.find_memory_type(
+            requirements.memory_type_bits,
+            desc.location.required_flags(),
+            desc.location.preferred_flags(),
+        ).ok_or(AllocationError::NoCompatibleMemoryType {
+            location: desc.location,
+            type_bits: requirements.memory_type_bits,
+        })?;
+
+        let block_size = self.block_size(memory_type_index);
+        let dedicated = requirements.size > block_size / 2;
+
+        let blocks = &mut self.blocks[memory_type_index as usize];
+        if !dedicated {
+            for block in blocks.iter_mut().filter(|block| !block.dedicated && block.linear == desc.linear) {
+                if let Some(offset) = block.try_allocate(requirements.size, requirements.alignment) {
+                    return Ok(Allocation {
+                        memory: block.memory,
+                        offset,
+                        size: requirements.size,
+                        mapped_ptr: block.mapped_ptr.map(|ptr| NonNull::new_unchecked(ptr.as_ptr().add(offset as usize))),
+                        memory_type_index,
+                        block_id: block.id,
+                    });
+                }
+            }
+        }
+
+        let size = if dedicated { requirements.size } else { block_size };
+        let mut flags_info = vk::MemoryAllocateFlagsInfo::builder().flags(vk::MemoryAllocateFlags::DEVICE_ADDRESS);
+        let mut allocate_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(size)
+            .memory_type_index(memory_type_index);
+
+        if self.device_address && desc.linear {
+            allocate_info = allocate_info.push_next(&mut flags_info);
+        }
+
+        let memory = device.allocate_memory(&allocate_info, None)?;
+        let mapped_ptr = if desc.location.is_mapped() {
+            match device.map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty()) {
+                Ok(ptr) => NonNull::new(ptr as *mut u8),
+                Err(err) => {
+                    device.free_memory(memory, None);
+                    return Err(err.into());
+                }
+            }
+        } else {
+            None
+        };
+
+        debug!(
+            "Allocated {} memory block of {} bytes from type {} for '{}'",
+            if dedicated { "dedicated" } else { "shared" },
+            size,
+            memory_type_index,
+            desc.name,
+        );
+
+        let mut block = MemoryBlock {
+            id: self.next_block_id,
+            memory,
+            size,
+            mapped_ptr,
+            linear: desc.linear,
+            dedicated,
+            free_ranges: vec![FreeRange { offset: 0, size }],
+            allocation_count: 0,
+        };
+        self.next_block_id += 1;
+
+        let offset = block.try_allocate(requirements.size, requirements.alignment)
+            .expect("a fresh block fits the allocation it was sized for");
+
+        let allocation = Allocation {
+            memory,
+            offset,
+            size: requirements.size,
+            mapped_ptr: mapped_ptr.map(|ptr| NonNull::new_unchecked(ptr.as_ptr().add(offset as usize))),
+            memory_type_index,
+            block_id: block.id,
+        };
+
+        self.blocks[memory_type_index as usize].push(block);
+        Ok(allocation)
+    }
+
+    /// Returns an allocation to its block. Dedicated blocks, and shared blocks that become empty while another block
+    /// of the same kind exists, are released back to the driver.
+    pub unsafe fn free(&mut self, device: &ash::Device, allocation: Allocation) {
+        let blocks = &mut self.blocks[allocation.memory_type_index as usize];
+        let Some(index) = blocks.iter().position(|block| block.id == allocation.block_id) else {
+            warn!("Freed an allocation that does not belong to this allocator");
+            return;
+        };
+
+        let block = &mut blocks[index];
+        block.free(allocation.offset, allocation.size);
+
+        if block.allocation_count > 0 {
+            return;
+        }
+
+        let linear = block.linear;
+        let keep = !block.dedicated
+            && blocks.iter().filter(|other| !other.dedicated && other.linear == linear).count() == 1;
+
+        if !keep {
+            let block = blocks.swap_remove(index);
+            debug!("Released memory block of {} bytes", block.size);
+            device.free_memory(block.memory, None);
+        }
+    }
+
+    /// Frees every block. Called by the device right before it is destroyed.
+    pub unsafe fn destroy(&mut self, device: &ash::Device) {
+        for block in self.blocks.iter_mut().flat_map(|blocks| blocks.drain(..)) {
+            if block.allocation_count > 0 {
+                warn!("Destroying memory block with {} live allocation(s)", block.allocation_count);
+            }
+
+            device.free_memory(block.memory, None);
+        }
+    }
+}
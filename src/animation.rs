@@ -0,0 +1,375 @@
+use std::ops::{Add, Mul};
+use anyhow::anyhow;
+use cgmath::{InnerSpace, Matrix4, One, Quaternion, Vector3, VectorSpace};
+
+/// Translation, rotation and scale, applied in reverse order, as glTF nodes store them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl Transform {
+    pub fn new(translation: Vector3<f32>, rotation: Quaternion<f32>, scale: Vector3<f32>) -> Self {
+        Self { translation, rotation, scale }
+    }
+
+    pub fn to_matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::new(Vector3::new(0.0, 0.0, 0.0), Quaternion::one(), Vector3::new(1.0, 1.0, 1.0))
+    }
+}
+
+/// A joint of a [`Skeleton`], as a glTF skin lists it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Joint {
+    pub name: String,
+    /// Index of the parent joint, which must come before this one.
+    pub parent: Option<usize>,
+    /// Takes mesh space to the joint's space in the bind pose.
+    pub inverse_bind: Matrix4<f32>,
+    /// The joint's transform relative to its parent when no animation moves it.
+    pub rest: Transform,
+}
+
+/// The joint hierarchy skinned vertices are weighted to. Joints are sorted so that parents come before their
+/// children; importers of glTF skins have to reorder their joints when they aren't.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Skeleton {
+    joints: Vec<Joint>,
+}
+
+impl Skeleton {
+    pub fn new(joints: Vec<Joint>) -> anyhow::Result<Self> {
+        if joints.is_empty() {
+            return Err(anyhow!("Skeletons need at least one joint"));
+        }
+
+        if let Some((index, joint)) = joints.iter().enumerate().find(|(index, joint)| joint.parent.is_some_and(|parent| parent >= *index)) {
+            return Err(anyhow!("Joint {} '{}' comes before its parent {:?}", index, joint.name, joint.parent));
+        }
+
+        Ok(Self { joints })
+    }
+
+    pub fn joints(&self) -> &[Joint] {
+        &self.joints
+    }
+
+    pub fn joint_count(&self) -> usize {
+        self.joints.len()
+    }
+
+    pub fn find_joint(&self, name: &str) -> Option<usize> {
+        self.joints.iter().position(|joint| joint.name == name)
+    }
+
+    /// Every joint at rest, the starting point clips are sampled onto.
+    pub fn rest_pose(&self) -> Vec<Transform> {
+        self.joints.iter().map(|joint| joint.rest).collect()
+    }
+
+    /// Appends a skinning matrix for each joint of `pose`, taking mesh space vertices from the bind pose to where
+    /// the posed joint puts them. `pose` holds one local transform per joint.
+    pub fn write_palette(&self, pose: &[Transform], palette: &mut Vec<[[f32; 4]; 4]>) -> anyhow::Result<()> {
+        if pose.len() != self.joints.len() {
+            return Err(anyhow!("Pose has {} joints, but the skeleton has {}", pose.len(), self.joints.len()));
+        }
+
+        let mut world = Vec::with_capacity(self.joints.len());
+        for (joint, local) in self.joints.iter().zip(pose) {
+            let local = local.to_matrix();
+            world.push(match joint.parent {
+                Some(parent) => world[parent] * local,
+                None => local,
+            });
+        }
+
+        palette.extend(world.iter().zip(&self.joints).map(|(world, joint)| -> [[f32; 4]; 4] { (world * joint.inverse_bind).into() }));
+        Ok(())
+    }
+}
+
+/// How values between keyframes are found, as in glTF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Interpolation {
+    /// The last keyframe's value until the next one.
+    Step,
+    /// Linear, or spherical linear for rotations.
+    #[default]
+    Linear,
+    /// Hermite splines. Every keyframe has three values: the in-tangent, the value and the out-tangent.
+    CubicSpline,
+}
+
+/// What a [`Channel`] animates, with the values of its keyframes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Keyframes {
+    Translation(Vec<Vector3<f32>>),
+    Rotation(Vec<Quaternion<f32>>),
+    Scale(Vec<Vector3<f32>>),
+    /// Morph target weights of the mesh, `targets` of them per value.
+    Weights { targets: usize, values: Vec<f32> },
+}
+
+impl Keyframes {
+    fn len(&self) -> usize {
+        match self {
+            Self::Translation(values) | Self::Scale(values) => values.len(),
+            Self::Rotation(values) => values.len(),
+            Self::Weights { targets, values } => values.len() / (*targets).max(1),
+        }
+    }
+}
+
+/// One property of one joint over time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Channel {
+    /// Ignored by weight channels, which animate the mesh itself.
+    pub joint: usize,
+    /// Increasing keyframe times in seconds.
+    pub times: Vec<f32>,
+    pub keyframes: Keyframes,
+    pub interpolation: Interpolation,
+}
+
+impl Channel {
+    fn sample(&self, time: f32, transform: &mut Transform) {
+        match &self.keyframes {
+            Keyframes::Translation(values) => {
+                transform.translation = sample(&self.times, values, self.interpolation, time, |a, b, t| a.lerp(b, t));
+            }
+            Keyframes::Rotation(values) => {
+                let rotation = sample(&self.times, values, self.interpolation, time, |a, b, t| a.slerp(b, t));
+                transform.rotation = rotation.normalize();
+            }
+            Keyframes::Scale(values) => {
+                transform.scale = sample(&self.times, values, self.interpolation, time, |a, b, t| a.lerp(b, t));
+            }
+            Keyframes::Weights { .. } => {}
+        }
+    }
+
+    fn sample_weights(&self, time: f32, weights: &mut [f32]) {
+        let Keyframes::Weights { targets, values } = &self.keyframes else {
+            return;
+        };
+
+        for (target, weight) in weights.iter_mut().enumerate().take(*targets) {
+            let target_values: Vec<f32> = values.iter().skip(target).step_by(*targets).copied().collect();
+            *weight = sample(&self.times, &target_values, self.interpolation, time, |a, b, t| a + (b - a) * t);
+        }
+    }
+}
+
+/// A named set of channels, like a glTF animation targeting the joints of one skin.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimationClip {
+    name: String,
+    channels: Vec<Channel>,
+    duration: f32,
+}
+
+impl AnimationClip {
+    pub fn new(name: &str, channels: Vec<Channel>) -> anyhow::Result<Self> {
+        for channel in &channels {
+            if channel.times.is_empty() || channel.times.windows(2).any(|pair| pair[0] >= pair[1]) {
+                return Err(anyhow!("Channel of joint {} in clip '{}' needs increasing keyframe times", channel.joint, name));
+            }
+
+            if let Keyframes::Weights { targets, values } = &channel.keyframes {
+                if *targets == 0 || values.len() % targets != 0 {
+                    return Err(anyhow!("Weight channel in clip '{}' has {} values for {} morph targets", name, values.len(), targets));
+                }
+            }
+
+            let values_per_key = if channel.interpolation == Interpolation::CubicSpline { 3 } else { 1 };
+            if channel.keyframes.len() != channel.times.len() * values_per_key {
+                return Err(anyhow!(
+                    "Channel of joint {} in clip '{}' has {} values for {} keyframes",
+                    channel.joint,
+                    name,
+                    channel.keyframes.len(),
+                    channel.times.len(),
+                ));
+            }
+        }
+
+        let duration = channels.iter().map(|channel| channel.times[channel.times.len() - 1]).fold(0.0, f32::max);
+        Ok(Self {
+            name: name.to_owned(),
+            channels,
+            duration,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn channels(&self) -> &[Channel] {
+        &self.channels
+    }
+
+    /// Time of the last keyframe of any channel.
+    pub fn duration(&self) -> f32 {
+        self.duration
+    }
+
+    /// Overwrites what the clip animates at `time` in `pose`, leaving other properties and joints alone. Channels of
+    /// joints `pose` doesn't have are skipped.
+    pub fn sample(&self, time: f32, pose: &mut [Transform]) {
+        for channel in &self.channels {
+            if let Some(transform) = pose.get_mut(channel.joint) {
+                channel.sample(time, transform);
+            }
+        }
+    }
+
+    /// Overwrites the morph target weights the clip animates at `time`, leaving the rest alone.
+    pub fn sample_weights(&self, time: f32, weights: &mut [f32]) {
+        for channel in &self.channels {
+            channel.sample_weights(time, weights);
+        }
+    }
+}
+
+/// Playback state of a clip, advanced once per frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnimationPlayer {
+    time: f32,
+    speed: f32,
+    looping: bool,
+    playing: bool,
+}
+
+impl AnimationPlayer {
+    /// Plays from the start at normal speed, looping.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_speed(mut self, speed: f32) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    pub fn with_looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
+
+    pub fn time(&self) -> f32 {
+        self.time
+    }
+
+    pub fn set_time(&mut self, time: f32) {
+        self.time = time;
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Negative speeds play backwards.
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn set_playing(&mut self, playing: bool) {
+        self.playing = playing;
+    }
+
+    /// Moves `delta` seconds on through `clip`, wrapping around when looping and stopping at either end otherwise.
+    pub fn advance(&mut self, clip: &AnimationClip, delta: f32) {
+        if !self.playing {
+            return;
+        }
+
+        let duration = clip.duration();
+        self.time += delta * self.speed;
+
+        if self.looping && duration > 0.0 {
+            self.time = self.time.rem_euclid(duration);
+        } else if !(0.0..=duration).contains(&self.time) {
+            self.time = self.time.clamp(0.0, duration);
+            self.playing = false;
+        }
+    }
+
+    /// The rest pose of `skeleton` with `clip` applied at the current time.
+    pub fn pose(&self, skeleton: &Skeleton, clip: &AnimationClip) -> Vec<Transform> {
+        let mut pose = skeleton.rest_pose();
+        clip.sample(self.time, &mut pose);
+        pose
+    }
+
+    /// `defaults`, e.g. the weights of a glTF mesh, with the weights `clip` animates at the current time applied.
+    pub fn weights(&self, clip: &AnimationClip, defaults: &[f32]) -> Vec<f32> {
+        let mut weights = defaults.to_vec();
+        clip.sample_weights(self.time, &mut weights);
+        weights
+    }
+}
+
+impl Default for AnimationPlayer {
+    fn default() -> Self {
+        Self {
+            time: 0.0,
+            speed: 1.0,
+            looping: true,
+            playing: true,
+        }
+    }
+}
+
+/// The value of a channel at `time`, clamped to its first and last keyframes.
+fn sample<T>(times: &[f32], values: &[T], interpolation: Interpolation, time: f32, lerp: impl Fn(T, T, f32) -> T) -> T
+where
+    T: Copy + Add<Output = T> + Mul<f32, Output = T>,
+{
+    let value = |key: usize| match interpolation {
+        Interpolation::CubicSpline => values[key * 3 + 1],
+        _ => values[key],
+    };
+
+    let next = times.partition_point(|&key_time| key_time <= time);
+    if next == 0 {
+        return value(0);
+    }
+
+    if next == times.len() {
+        return value(times.len() - 1);
+    }
+
+    let key = next - 1;
+    let span = times[next] - times[key];
+    let t = (time - times[key]) / span;
+
+    match interpolation {
+        Interpolation::Step => value(key),
+        Interpolation::Linear => lerp(value(key), value(next), t),
+        Interpolation::CubicSpline => {
+            let (t2, t3) = (t * t, t * t * t);
+            let out_tangent = values[key * 3 + 2] * span;
+            let in_tangent = values[next * 3] * span;
+
+            value(key) * (2.0 * t3 - 3.0 * t2 + 1.0)
+                + out_tangent * (t3 - 2.0 * t2 + t)
+                + value(next) * (-2.0 * t3 + 3.0 * t2)
+                + in_tangent * (t3 - t2)
+        }
+    }
+}
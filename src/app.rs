@@ -0,0 +1,1779 @@
+use std::ffi::CStr;
+use std::sync::{Arc, MutexGuard};
+use std::time::{Duration, Instant};
+use anyhow::anyhow;
+use ash::extensions::khr;
+use ash::vk;
+use ash::vk::API_VERSION_1_3;
+use log::{debug, error, info, warn};
+use winit::dpi::LogicalSize;
+use winit::event::{ElementState, Event, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop, EventLoopBuilder, EventLoopProxy};
+use winit::keyboard::{KeyCode, PhysicalKey};
+use winit::window::{Window, WindowBuilder};
+use bytemuck::Pod;
+use crate::allocator::{Allocator, MemoryLocation};
+use crate::benchmark::{BenchReport, TimingStats};
+use crate::buffer::{Buffer, ScratchArena, DEFAULT_SCRATCH_SIZE};
+use crate::commands::FrameCommands;
+use crate::descriptors::DescriptorManager;
+use crate::device::Device;
+use crate::events::{RedrawPolicy, UserEvent, WakeHandle};
+use crate::format::{clear_value_for_swapchain, encode_clear_color, find_depth_format, supported_sample_count};
+use crate::frame_arena::FrameArena;
+use crate::glsl::GlslCompiler;
+use crate::hot_reload::PipelineRegistry;
+use crate::image::{Image, ImageDesc, Texture};
+use crate::physical_device::{physical_device_name, select_physical_device, AdapterSelection};
+use crate::instance::Instance;
+use crate::platform::get_required_instance_extensions;
+use crate::pipeline::{set_viewport_and_scissor, PipelineTarget};
+use crate::post::{PostStack, HDR_FORMAT};
+use crate::query::{FrameTimes, GpuTimer, DEFAULT_TIMING_WINDOW};
+use crate::handles::{BufferHandle, GpuResources, GpuTextureHandle, ImageHandle};
+use crate::recovery::{Loss, ResourceLoader, ResourceRegistry, DEFAULT_SURFACE_RETRIES};
+use crate::render_graph::{GraphImage, ImageAccess, ImageState, ImportedImage, RenderGraph, TransientImages};
+use crate::render_pass::{AttachmentDesc, FramebufferCache, RenderPass, RenderPassBuilder};
+use crate::renderer2d::Renderer2d;
+use crate::renderer3d::{RenderPath, Renderer3d, Viewport};
+use crate::rendering::{RenderingFormats, STEREO_VIEW_MASK};
+use crate::requirements::{DeviceRequirements, Feature};
+use crate::ssao::SsaoQuality;
+use crate::surface::Surface;
+use crate::swapchain::{PresentPreference, Swapchain};
+use crate::sync::{FrameSync, LatencyMode, DEFAULT_FRAMES_IN_FLIGHT};
+use crate::upload::{UploadToken, Uploader, DEFAULT_STAGING_SIZE};
+use crate::validation::{is_validation_layer_available, ValidationConfig, VALIDATION_LAYER_NAME};
+use crate::validation_overlay::ValidationOverlay;
+
+pub struct WindowConfig {
+    pub title: String,
+    pub size: Option<LogicalSize<u32>>,
+    pub min_size: Option<LogicalSize<u32>>,
+    pub max_size: Option<LogicalSize<u32>>,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            title: "Hello!".into(),
+            size: None,
+            min_size: None,
+            max_size: None,
+        }
+    }
+}
+
+pub const VALIDATION_ENV_VAR: &str = "LEGAMING_VALIDATION";
+
+pub struct AppConfig {
+    pub window: WindowConfig,
+    pub api_version: u32,
+    pub adapter: AdapterSelection,
+    /// Enables `VK_LAYER_KHRONOS_validation` and a debug messenger that forwards messages to `log`. Setting
+    /// `LEGAMING_VALIDATION=1` enables it with the default config when this is `None`.
+    pub validation: Option<ValidationConfig>,
+    pub redraw_policy: RedrawPolicy,
+    /// How many frames the CPU may record ahead of the GPU, either 2 or 3.
+    pub frames_in_flight: usize,
+    /// Watches the shader sources of pipelines registered with `App::pipelines_mut` and rebuilds them on change.
+    pub shader_hot_reload: bool,
+    /// Requested MSAA sample count for the main render target. The highest supported count not above it is used.
+    pub msaa_samples: vk::SampleCountFlags,
+    /// Records the main pass with dynamic rendering when the device supports it. Otherwise, or when this is
+    /// off, a render pass and framebuffers are used.
+    pub dynamic_rendering: bool,
+    /// Extensions and features on top of what the engine itself needs. Check `Device::capabilities` for which
+    /// optional ones were enabled.
+    pub requirements: DeviceRequirements,
+    pub present_preference: PresentPreference,
+    pub latency_mode: LatencyMode,
+    /// Creates a [`Renderer2d`] that draws into the main pass, reachable through `Frame::renderer2d`.
+    pub renderer2d: bool,
+    /// Creates a [`Renderer3d`] that draws into the main pass before the 2D renderer, reachable through
+    /// `Frame::renderer3d`.
+    pub renderer3d: bool,
+    /// How the 3D renderer shades. Deferred needs dynamic rendering and no MSAA, and falls back to forward otherwise.
+    pub render_path: RenderPath,
+    /// Screen-space ambient occlusion of the 3D renderer, which only the deferred path draws.
+    pub ambient_occlusion: Option<SsaoQuality>,
+    /// Traces that ambient occlusion against the scene on devices that can, instead of estimating it from the screen.
+    #[cfg(feature = "ray-tracing")]
+    pub ray_traced_ambient_occlusion: bool,
+    /// Renders the main pass into an HDR offscreen image and runs it through a [`PostStack`], which tone maps it
+    /// into the swapchain, reachable through `Frame::post_stack`. Needs dynamic rendering.
+    pub post_processing: bool,
+    /// Draws the main pass once for each eye with multiview, into the two layers of its targets, and shows the eyes
+    /// side by side. Needs dynamic rendering and `Feature::Multiview`, and is off without them.
+    pub multiview: bool,
+    /// Pressing it cycles the 3D renderer through its [`DebugView`](crate::debug_view::DebugView)s.
+    pub debug_view_key: Option<KeyCode>,
+    /// Font for a [`ValidationOverlay`] of the latest validation messages, drawn with the 2D renderer. No font is
+    /// bundled, so there's no overlay without one.
+    pub validation_overlay_font: Option<Vec<u8>>,
+    /// Pressing it shows or hides the validation overlay.
+    pub validation_overlay_key: KeyCode,
+    /// How many frames `App::cpu_frame_times` and `App::gpu_frame_times` average over.
+    pub timing_window: usize,
+    /// How many times recovering from a surface loss recreates the surface and swapchain, with a growing pause in
+    /// between, before giving up. Covers outputs coming and going while monitors are plugged in or out.
+    pub surface_retries: u32,
+    /// Size of the part of `App::uploader`'s staging buffer each frame in flight stages uploads in. Larger uploads
+    /// get a temporary buffer of their own.
+    pub staging_size: vk::DeviceSize,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            window: WindowConfig::default(),
+            api_version: API_VERSION_1_3,
+            adapter: AdapterSelection::default(),
+            validation: None,
+            redraw_policy: RedrawPolicy::default(),
+            frames_in_flight: DEFAULT_FRAMES_IN_FLIGHT,
+            shader_hot_reload: false,
+            msaa_samples: vk::SampleCountFlags::TYPE_1,
+            dynamic_rendering: true,
+            requirements: DeviceRequirements::new(),
+            present_preference: PresentPreference::default(),
+            latency_mode: LatencyMode::default(),
+            renderer2d: false,
+            renderer3d: false,
+            render_path: RenderPath::Forward,
+            ambient_occlusion: None,
+            #[cfg(feature = "ray-tracing")]
+            ray_traced_ambient_occlusion: false,
+            post_processing: false,
+            multiview: false,
+            debug_view_key: Some(KeyCode::F3),
+            validation_overlay_font: None,
+            validation_overlay_key: KeyCode::F4,
+            timing_window: DEFAULT_TIMING_WINDOW,
+            surface_retries: DEFAULT_SURFACE_RETRIES,
+            staging_size: DEFAULT_STAGING_SIZE,
+        }
+    }
+}
+
+/// Fluent front end over [`AppConfig`]. `build` creates the window and every Vulkan object the app needs.
+pub struct EngineBuilder {
+    config: AppConfig,
+}
+
+impl EngineBuilder {
+    pub fn new() -> Self {
+        Self {
+            config: AppConfig::default(),
+        }
+    }
+
+    pub fn with_config(config: AppConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn with_title(mut self, title: impl Into<String>) -> Self {
+        self.config.window.title = title.into();
+        self
+    }
+
+    pub fn with_size(mut self, width: u32, height: u32) -> Self {
+        self.config.window.size = Some(LogicalSize::new(width, height));
+        self
+    }
+
+    pub fn with_min_size(mut self, width: u32, height: u32) -> Self {
+        self.config.window.min_size = Some(LogicalSize::new(width, height));
+        self
+    }
+
+    pub fn with_max_size(mut self, width: u32, height: u32) -> Self {
+        self.config.window.max_size = Some(LogicalSize::new(width, height));
+        self
+    }
+
+    /// Vulkan API version requested from the instance, e.g. `ash::vk::API_VERSION_1_3`.
+    pub fn with_vulkan_version(mut self, api_version: u32) -> Self {
+        self.config.api_version = api_version;
+        self
+    }
+
+    pub fn with_adapter(mut self, adapter: AdapterSelection) -> Self {
+        self.config.adapter = adapter;
+        self
+    }
+
+    pub fn with_validation(mut self, validation: ValidationConfig) -> Self {
+        self.config.validation = Some(validation);
+        self
+    }
+
+    pub fn with_redraw_policy(mut self, redraw_policy: RedrawPolicy) -> Self {
+        self.config.redraw_policy = redraw_policy;
+        self
+    }
+
+    pub fn with_frames_in_flight(mut self, frames_in_flight: usize) -> Self {
+        self.config.frames_in_flight = frames_in_flight;
+        self
+    }
+
+    pub fn with_shader_hot_reload(mut self, enabled: bool) -> Self {
+        self.config.shader_hot_reload = enabled;
+        self
+    }
+
+    /// Renders the main pass with `samples` per pixel and resolves into the swapchain image.
+    pub fn with_msaa(mut self, samples: vk::SampleCountFlags) -> Self {
+        self.config.msaa_samples = samples;
+        self
+    }
+
+    /// Uses dynamic rendering for the main pass where available (the default), or always the render pass path.
+    pub fn with_dynamic_rendering(mut self, enabled: bool) -> Self {
+        self.config.dynamic_rendering = enabled;
+        self
+    }
+
+    /// Presentation behavior, with fallbacks when the surface doesn't support the requested mode. Can be changed
+    /// later with `App::set_present_mode`.
+    pub fn with_present_mode(mut self, present_preference: PresentPreference) -> Self {
+        self.config.present_preference = present_preference;
+        self
+    }
+
+    /// Whether frames wait for the GPU early to sample input later. Can be changed later with
+    /// `App::set_latency_mode`.
+    pub fn with_latency_mode(mut self, latency_mode: LatencyMode) -> Self {
+        self.config.latency_mode = latency_mode;
+        self
+    }
+
+    pub fn with_renderer2d(mut self, enabled: bool) -> Self {
+        self.config.renderer2d = enabled;
+        self
+    }
+
+    pub fn with_renderer3d(mut self, enabled: bool) -> Self {
+        self.config.renderer3d = enabled;
+        self
+    }
+
+    pub fn with_render_path(mut self, render_path: RenderPath) -> Self {
+        self.config.render_path = render_path;
+        self
+    }
+
+    pub fn with_ambient_occlusion(mut self, quality: Option<SsaoQuality>) -> Self {
+        self.config.ambient_occlusion = quality;
+        self
+    }
+
+    #[cfg(feature = "ray-tracing")]
+    pub fn with_ray_traced_ambient_occlusion(mut self, ray_traced: bool) -> Self {
+        self.config.ray_traced_ambient_occlusion = ray_traced;
+        self
+    }
+
+    pub fn with_post_processing(mut self, enabled: bool) -> Self {
+        self.config.post_processing = enabled;
+        self
+    }
+
+    /// Stereo rendering of the main pass, see [`AppConfig::multiview`].
+    pub fn with_multiview(mut self, enabled: bool) -> Self {
+        self.config.multiview = enabled;
+        self
+    }
+
+    pub fn with_debug_view_key(mut self, key: Option<KeyCode>) -> Self {
+        self.config.debug_view_key = key;
+        self
+    }
+
+    /// Lists the latest validation messages with `font_data` while `key` toggles it on. Needs the 2D renderer and
+    /// shows nothing useful without validation.
+    pub fn with_validation_overlay(mut self, font_data: Vec<u8>, key: KeyCode) -> Self {
+        self.config.validation_overlay_font = Some(font_data);
+        self.config.validation_overlay_key = key;
+        self
+    }
+
+    pub fn with_staging_size(mut self, size: vk::DeviceSize) -> Self {
+        self.config.staging_size = size;
+        self
+    }
+
+    pub fn with_surface_retries(mut self, retries: u32) -> Self {
+        self.config.surface_retries = retries;
+        self
+    }
+
+    pub fn with_timing_window(mut self, frames: usize) -> Self {
+        self.config.timing_window = frames;
+        self
+    }
+
+    pub fn with_requirements(mut self, requirements: DeviceRequirements) -> Self {
+        self.config.requirements = requirements;
+        self
+    }
+
+    /// Requires a device extension on top of [`with_requirements`](Self::with_requirements). Device selection fails
+    /// with `SelectionError::MissingDeviceExtension` when no GPU has it.
+    pub fn with_device_extension(mut self, name: &CStr) -> Self {
+        self.config.requirements = std::mem::take(&mut self.config.requirements).require_extension(name);
+        self
+    }
+
+    pub fn config(&self) -> &AppConfig {
+        &self.config
+    }
+
+    pub fn build(self) -> anyhow::Result<App> {
+        unsafe { App::new(&self.config) }
+    }
+}
+
+impl Default for EngineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct SmokeTestConfig {
+    pub api_version: u32,
+    pub validation: ValidationConfig,
+    pub adapter: AdapterSelection,
+}
+
+impl Default for SmokeTestConfig {
+    fn default() -> Self {
+        Self {
+            api_version: API_VERSION_1_3,
+            validation: ValidationConfig::default(),
+            adapter: AdapterSelection::default(),
+        }
+    }
+}
+
+/// In linear space, encoded for the target with `encode_clear_color`.
+const CLEAR_COLOR: [f32; 4] = [0.01, 0.01, 0.02, 1.0];
+
+/// Depth and, with MSAA, multisampled color images of the main pass, sized to the swapchain or, with multiview, to
+/// one eye with a layer for each.
+struct RenderTargets {
+    depth: Image,
+    color: Option<Image>,
+}
+
+impl RenderTargets {
+    unsafe fn new(
+        device: &Arc<Device>,
+        color_format: vk::Format,
+        depth_format: vk::Format,
+        samples: vk::SampleCountFlags,
+        extent: vk::Extent2D,
+        layers: u32,
+    ) -> anyhow::Result<Self> {
+        let width = extent.width.max(1);
+        let height = extent.height.max(1);
+        let layered = |desc: ImageDesc| if layers > 1 { desc.array(layers) } else { desc };
+
+        // Single sampled depth is also read back for occlusion culling.
+        let depth_usage = match samples {
+            vk::SampleCountFlags::TYPE_1 => vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+            _ => vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+        };
+        let depth_desc = layered(ImageDesc::new_2d(width, height, depth_format, depth_usage).with_samples(samples));
+        let depth = Image::new(device, "depth", &depth_desc)?;
+
+        let color = if samples == vk::SampleCountFlags::TYPE_1 {
+            None
+        } else {
+            let usage = vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT;
+            let color_desc = layered(ImageDesc::new_2d(width, height, color_format, usage).with_samples(samples));
+            Some(Image::new(device, "msaa color", &color_desc)?)
+        };
+
+        Ok(Self { depth, color })
+    }
+
+    /// Framebuffer attachments in render pass order: color, depth, then the resolve target when multisampled.
+    fn attachments(&self, swapchain_view: vk::ImageView) -> Vec<vk::ImageView> {
+        match &self.color {
+            Some(color) => vec![color.view(), self.depth.view(), swapchain_view],
+            None => vec![swapchain_view, self.depth.view()],
+        }
+    }
+}
+
+/// Render pass for the fallback path: present color and depth, or multisampled color and depth resolved into the
+/// swapchain image.
+unsafe fn main_render_pass(
+    device: &Arc<Device>,
+    color_format: vk::Format,
+    depth_format: vk::Format,
+    samples: vk::SampleCountFlags,
+) -> anyhow::Result<RenderPass> {
+    let builder = if samples == vk::SampleCountFlags::TYPE_1 {
+        RenderPassBuilder::new()
+            .color(AttachmentDesc::present(color_format))
+            .depth(AttachmentDesc::depth(depth_format))
+    } else {
+        RenderPassBuilder::new()
+            .color(AttachmentDesc::color(color_format)
+                .with_samples(samples)
+                .with_store_op(vk::AttachmentStoreOp::DONT_CARE))
+            .depth(AttachmentDesc::depth(depth_format).with_samples(samples))
+            .resolve(AttachmentDesc::present(color_format).with_load_op(vk::AttachmentLoadOp::DONT_CARE))
+    };
+
+    builder.build(device)
+}
+
+/// Assumed when the monitor doesn't report its refresh rate.
+const FALLBACK_REFRESH_RATE_MILLIHERTZ: u32 = 60_000;
+
+/// Time between two refreshes of the monitor the window is on.
+fn refresh_interval(window: &Window) -> Duration {
+    let millihertz = window.current_monitor()
+        .and_then(|monitor| monitor.refresh_rate_millihertz())
+        .filter(|&millihertz| millihertz > 0)
+        .unwrap_or_else(|| {
+            debug!("The monitor has no known refresh rate, assuming {} Hz", FALLBACK_REFRESH_RATE_MILLIHERTZ / 1000);
+            FALLBACK_REFRESH_RATE_MILLIHERTZ
+        });
+    Duration::from_secs(1000) / millihertz
+}
+
+fn window_extent(window: &Window) -> vk::Extent2D {
+    let size = window.inner_size();
+    vk::Extent2D {
+        width: size.width,
+        height: size.height,
+    }
+}
+
+/// How the main pass is recorded.
+enum MainPass {
+    Dynamic(RenderingFormats),
+    RenderPass {
+        render_pass: RenderPass,
+        framebuffers: FramebufferCache,
+    },
+}
+
+impl MainPass {
+    /// The views the main pass renders with multiview, zero for a single view.
+    fn view_mask(&self) -> u32 {
+        match self {
+            Self::Dynamic(formats) => formats.view_mask,
+            Self::RenderPass { .. } => 0,
+        }
+    }
+}
+
+/// What each view in `view_mask` gets of `extent` when the views are shown side by side. The first takes the odd
+/// column so that together they cover it.
+fn view_extent(extent: vk::Extent2D, view_mask: u32) -> vk::Extent2D {
+    let views = view_mask.count_ones().max(1);
+    vk::Extent2D {
+        width: extent.width.div_ceil(views),
+        height: extent.height,
+    }
+}
+
+/// Copies the views a multiview main pass drew into the layers of `views` next to each other into `target`, of
+/// `extent`, so a stereo pair ends up in its left and right halves.
+unsafe fn add_side_by_side_pass(graph: &mut RenderGraph<'_>, views: GraphImage, target: GraphImage, extent: vk::Extent2D, view_mask: u32) {
+    let layers = view_mask.count_ones();
+    let view_extent = view_extent(extent, view_mask);
+    graph.add_pass("side by side")
+        .image(views, ImageAccess::TransferSrc)
+        .image(target, ImageAccess::TransferDst)
+        .execute(move |ctx| {
+            let regions: Vec<vk::ImageCopy> = (0..layers)
+                .map(|layer| {
+                    let x = layer * view_extent.width;
+                    vk::ImageCopy {
+                        src_subresource: vk::ImageSubresourceLayers {
+                            aspect_mask: vk::ImageAspectFlags::COLOR,
+                            mip_level: 0,
+                            base_array_layer: layer,
+                            layer_count: 1,
+                        },
+                        src_offset: vk::Offset3D::default(),
+                        dst_subresource: vk::ImageSubresourceLayers {
+                            aspect_mask: vk::ImageAspectFlags::COLOR,
+                            mip_level: 0,
+                            base_array_layer: 0,
+                            layer_count: 1,
+                        },
+                        dst_offset: vk::Offset3D { x: x as i32, y: 0, z: 0 },
+                        extent: vk::Extent3D {
+                            width: view_extent.width.min(extent.width.saturating_sub(x)),
+                            height: view_extent.height,
+                            depth: 1,
+                        },
+                    }
+                })
+                .filter(|region| region.extent.width > 0)
+                .collect();
+
+            ctx.device().cmd_copy_image(
+                ctx.command_buffer(),
+                ctx.image(views),
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                ctx.image(target),
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &regions,
+            );
+            Ok(())
+        });
+}
+
+/// The parts of [`AppConfig`] needed to create the device again after it was lost.
+struct GpuConfig {
+    adapter: AdapterSelection,
+    requirements: DeviceRequirements,
+    frames_in_flight: usize,
+    msaa_samples: vk::SampleCountFlags,
+    dynamic_rendering: bool,
+    present_preference: PresentPreference,
+    post_processing: bool,
+    multiview: bool,
+    renderer3d: bool,
+    timing_window: usize,
+    staging_size: vk::DeviceSize,
+}
+
+/// The device and everything the main loop creates from it. Rebuilt as a whole when the device or the surface is
+/// lost; the device has to go along with the surface because its present queue was picked for the old one.
+struct GpuState {
+    transients: TransientImages,
+    uploader: Uploader,
+    /// Dropped after the uploader, which waits for uploads still writing them.
+    white_texture: Option<Texture>,
+    normal_texture: Option<Texture>,
+    descriptors: DescriptorManager,
+    scratch: ScratchArena,
+    main_pass: MainPass,
+    render_targets: RenderTargets,
+    swapchain_generation: u64,
+    /// Counts submitted frames, for the queue label around each one.
+    frame_number: u64,
+    frame_commands: FrameCommands,
+    frame_sync: FrameSync,
+    gpu_timer: Option<GpuTimer>,
+    swapchain: Swapchain,
+    device: Arc<Device>,
+    /// What the main pass renders into: [`HDR_FORMAT`] with post processing, the swapchain's format otherwise.
+    scene_format: vk::Format,
+    depth_format: vk::Format,
+    msaa_samples: vk::SampleCountFlags,
+}
+
+impl GpuState {
+    unsafe fn new(
+        instance: &Arc<Instance>,
+        surface: &Arc<Surface>,
+        extent: vk::Extent2D,
+        config: &GpuConfig,
+    ) -> anyhow::Result<Self> {
+        let mut requirements = DeviceRequirements::new()
+            .require_extension(khr::Swapchain::name())
+            .require_feature(Feature::TimelineSemaphore)
+            .optional_feature(Feature::SamplerAnisotropy)
+            .optional_feature(Feature::BufferDeviceAddress)
+            .optional_feature(Feature::Maintenance4)
+            .optional_feature(Feature::Multiview)
+            .optional_feature(Feature::OcclusionQueryPrecise)
+            .optional_feature(Feature::DepthClamp)
+            .optional_extension(vk::KhrIncrementalPresentFn::name())
+            .merge(&config.requirements);
+
+        if config.dynamic_rendering {
+            requirements = requirements.optional_feature(Feature::DynamicRendering);
+        }
+
+        // For `DrawSubmission::Indirect`, `DrawSubmission::Meshlets`, `DebugView::Wireframe`, bindless textures and
+        // ray traced ambient occlusion.
+        if config.renderer3d {
+            requirements = requirements
+                .optional_feature(Feature::DrawIndirectCount)
+                .optional_feature(Feature::DrawIndirectFirstInstance)
+                .optional_feature(Feature::TaskShader)
+                .optional_feature(Feature::MeshShader)
+                .optional_feature(Feature::FillModeNonSolid)
+                .merge(&crate::bindless::bindless_requirements());
+
+            #[cfg(feature = "ray-tracing")]
+            {
+                requirements = requirements.merge(&crate::ray_tracing::ray_tracing_requirements());
+            }
+        }
+
+        let physical_device = select_physical_device(
+            instance,
+            Some((surface.loader(), surface.handle())),
+            &requirements,
+            &config.adapter,
+        )?;
+        info!("Selected physical device: {}", physical_device_name(instance, physical_device));
+
+        let depth_format = find_depth_format(instance, physical_device).ok_or(anyhow!("No supported depth format"))?;
+        info!("Selected depth format: {:?}", depth_format);
+
+        let device = Device::new(instance, surface, physical_device, &requirements)?;
+        let swapchain = Swapchain::new(&device, surface, extent, config.present_preference)?;
+
+        let frame_sync = FrameSync::new(&device, config.frames_in_flight, swapchain.images().len())?;
+        let frame_commands = FrameCommands::new(&device, device.queue_families().graphics, config.frames_in_flight)?;
+        let gpu_timer = GpuTimer::new(&device, config.frames_in_flight, config.timing_window)?;
+
+        let msaa_samples = supported_sample_count(instance, physical_device, config.msaa_samples);
+        if msaa_samples != config.msaa_samples {
+            warn!("{:?} MSAA is not supported, using {:?}", config.msaa_samples, msaa_samples);
+        }
+
+        let color_format = swapchain.color_format();
+        let mut scene_format = color_format;
+        let main_pass = if config.dynamic_rendering && device.supports_dynamic_rendering() {
+            info!("Rendering the main pass with dynamic rendering");
+            if config.post_processing {
+                scene_format = HDR_FORMAT;
+            }
+
+            let mut formats = RenderingFormats::new(&[scene_format], Some(depth_format)).with_samples(msaa_samples);
+            if config.multiview {
+                if device.supports_multiview() {
+                    info!("Rendering the main pass for two views with multiview");
+                    formats = formats.with_view_mask(STEREO_VIEW_MASK);
+                } else {
+                    warn!("Multiview is not supported, rendering a single view");
+                }
+            }
+
+            MainPass::Dynamic(formats)
+        } else {
+            if config.dynamic_rendering {
+                info!("Dynamic rendering is not supported, falling back to a render pass");
+            }
+            if config.multiview {
+                warn!("Multiview needs dynamic rendering, rendering a single view");
+            }
+
+            MainPass::RenderPass {
+                render_pass: main_render_pass(&device, color_format, depth_format, msaa_samples)?,
+                framebuffers: FramebufferCache::new(&device),
+            }
+        };
+
+        let view_mask = main_pass.view_mask();
+        let render_targets = RenderTargets::new(
+            &device,
+            scene_format,
+            depth_format,
+            msaa_samples,
+            view_extent(swapchain.extent(), view_mask),
+            view_mask.count_ones().max(1),
+        )?;
+
+        Ok(Self {
+            transients: TransientImages::new(&device),
+            uploader: Uploader::with_staging_size(&device, config.staging_size, config.frames_in_flight)?,
+            white_texture: None,
+            normal_texture: None,
+            descriptors: DescriptorManager::new(&device, config.frames_in_flight),
+            scratch: ScratchArena::new(&device, DEFAULT_SCRATCH_SIZE, config.frames_in_flight)?,
+            main_pass,
+            render_targets,
+            swapchain_generation: swapchain.generation(),
+            frame_number: 0,
+            frame_commands,
+            frame_sync,
+            gpu_timer,
+            swapchain,
+            device,
+            scene_format,
+            depth_format,
+            msaa_samples,
+        })
+    }
+
+    /// Uploads a 1x1 texture of `pixel`.
+    unsafe fn pixel_texture(&mut self, name: &str, format: vk::Format, pixel: [u8; 4]) -> anyhow::Result<Texture> {
+        let desc = ImageDesc::new_2d(1, 1, format, vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST);
+        let image = Image::new(&self.device, name, &desc)?;
+        self.uploader.upload_image(&image, &pixel, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)?;
+        Ok(Texture::from_image(image))
+    }
+
+    fn pipeline_target(&self) -> PipelineTarget {
+        match &self.main_pass {
+            MainPass::Dynamic(formats) => PipelineTarget::Dynamic(formats.clone()),
+            MainPass::RenderPass { render_pass, .. } => PipelineTarget::RenderPass {
+                render_pass: render_pass.handle(),
+                subpass: 0,
+                color_count: render_pass.color_formats().len(),
+                samples: render_pass.samples(),
+            },
+        }
+    }
+
+    /// Waits for the current frame's previous submission, acquires an image, records and submits the frame, then
+    /// presents it. Frames are skipped while the window is minimized or the swapchain is being recreated.
+    unsafe fn draw_frame(
+        &mut self,
+        renderer2d: Option<&mut Renderer2d>,
+        mut renderer3d: Option<&mut Renderer3d>,
+        post_stack: Option<&mut PostStack>,
+        arena: &FrameArena,
+    ) -> anyhow::Result<()> {
+        self.frame_sync.wait_for_current_frame()?;
+
+        let Some(image_index) = self.swapchain.acquire_next_image(self.frame_sync.image_available())? else {
+            // What the update queued is for this frame only; the next one queues it again.
+            if let Some(renderer) = renderer2d {
+                renderer.discard_frame();
+            }
+            if let Some(renderer) = renderer3d {
+                renderer.end_frame();
+            }
+            self.swapchain.clear_damage();
+            return Ok(());
+        };
+
+        if self.swapchain.generation() != self.swapchain_generation {
+            // Recreation waited for the device to go idle, so nothing still uses the old framebuffers.
+            if let MainPass::RenderPass { framebuffers, .. } = &mut self.main_pass {
+                framebuffers.clear();
+            }
+
+            let view_mask = self.main_pass.view_mask();
+            self.render_targets = RenderTargets::new(
+                &self.device,
+                self.scene_format,
+                self.depth_format,
+                self.msaa_samples,
+                view_extent(self.swapchain.extent(), view_mask),
+                view_mask.count_ones().max(1),
+            )?;
+            self.frame_sync.set_image_count(self.swapchain.images().len())?;
+            self.swapchain_generation = self.swapchain.generation();
+        }
+
+        let extent = self.swapchain.extent();
+        let swapchain_image = self.swapchain.images()[image_index as usize];
+        let swapchain_view = self.swapchain.image_views()[image_index as usize];
+
+        self.descriptors.begin_frame(self.frame_sync.current_frame())?;
+        let command_buffer = self.frame_commands.begin_frame(self.frame_sync.current_frame())?;
+        if let Some(gpu_timer) = &mut self.gpu_timer {
+            gpu_timer.begin(command_buffer, self.frame_sync.current_frame())?;
+        }
+        self.uploader.acquire_ready(command_buffer, self.frame_sync.current_frame())?;
+
+        match &mut self.main_pass {
+            MainPass::Dynamic(formats) => {
+                let view_mask = formats.view_mask;
+                let color_range = vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                };
+
+                // Every target is cleared or discarded, so they all start out undefined but still wait for the
+                // previous frame's writes.
+                let color_output = vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT;
+                let fragment_tests = vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS;
+
+                if let Some(renderer) = renderer3d.as_deref_mut() {
+                    renderer.prepare(command_buffer, self.frame_sync.current_frame(), view_extent(extent, view_mask), arena)?;
+                }
+
+                let mut graph = RenderGraph::new();
+                let swapchain = graph.import_image(
+                    "swapchain",
+                    ImportedImage::new(swapchain_image, swapchain_view, extent, color_range)
+                        .with_initial_state(ImageState::new(vk::ImageLayout::UNDEFINED, color_output, vk::AccessFlags::empty()))
+                        .with_final_layout(vk::ImageLayout::PRESENT_SRC_KHR),
+                );
+                let depth = graph.import_image(
+                    "depth",
+                    ImportedImage::from_image(&self.render_targets.depth)
+                        .with_initial_state(ImageState::new(vk::ImageLayout::UNDEFINED, fragment_tests, vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE))
+                        .discard_contents(),
+                );
+                let msaa_color = self.render_targets.color.as_ref().map(|color| graph.import_image(
+                    "msaa color",
+                    ImportedImage::from_image(color)
+                        .with_initial_state(ImageState::new(vk::ImageLayout::UNDEFINED, color_output, vk::AccessFlags::COLOR_ATTACHMENT_WRITE))
+                        .discard_contents(),
+                ));
+
+                // With post processing, the scene is drawn offscreen and the stack writes the swapchain image.
+                let scene = match &post_stack {
+                    Some(post_stack) => graph.create_image(
+                        "scene color",
+                        ImageDesc::new_2d(extent.width, extent.height, post_stack.format(), vk::ImageUsageFlags::empty()),
+                    ),
+                    None => swapchain,
+                };
+
+                // With multiview, each view is drawn into a layer of its own and copied into its part of the scene.
+                let views = (view_mask != 0).then(|| {
+                    let view_extent = view_extent(extent, view_mask);
+                    graph.create_image(
+                        "views",
+                        ImageDesc::new_2d(view_extent.width.max(1), view_extent.height.max(1), self.scene_format, vk::ImageUsageFlags::empty())
+                            .array(view_mask.count_ones()),
+                    )
+                });
+
+                let frame_index = self.frame_sync.current_frame();
+                let renderer3d_ref = renderer3d.as_deref();
+
+                // The offscreen scene stays linear until the post stack encodes it for the swapchain.
+                let clear = match &post_stack {
+                    Some(_) => CLEAR_COLOR,
+                    None => encode_clear_color(CLEAR_COLOR, self.swapchain.format()),
+                };
+
+                let shadow_maps = match renderer3d_ref {
+                    Some(renderer) => renderer.add_shadow_passes(&mut graph, frame_index),
+                    None => Vec::new(),
+                };
+
+                // The deferred passes clear and fill the targets, leaving the main pass to draw on top.
+                let deferred = match renderer3d_ref {
+                    Some(renderer) => renderer.add_deferred_passes(&mut graph, scene, depth, &shadow_maps, frame_index, clear),
+                    None => false,
+                };
+                let (clear_color, clear_depth) = if deferred { (None, None) } else { (Some(clear), Some(1.0)) };
+
+                let main = shadow_maps.iter().fold(graph.add_pass("main"), |pass, &image| {
+                    pass.image(image, ImageAccess::Sampled(vk::PipelineStageFlags::FRAGMENT_SHADER))
+                });
+                let main_color = views.unwrap_or(scene);
+                let main = match msaa_color {
+                    Some(color) => main.color_resolved(color, main_color, clear_color),
+                    None => main.color(main_color, clear_color),
+                };
+
+                main.depth(depth, clear_depth).multiview(view_mask).execute(move |ctx| {
+                    if let Some(renderer) = renderer3d_ref {
+                        renderer.record(ctx.command_buffer(), frame_index)?;
+                    }
+
+                    if let Some(renderer) = renderer2d {
+                        renderer.record(ctx.command_buffer(), frame_index, ctx.extent(), arena)?;
+                    }
+
+                    Ok(())
+                });
+
+                if let Some(views) = views {
+                    add_side_by_side_pass(&mut graph, views, scene, extent, view_mask);
+                }
+
+                if let Some(renderer) = renderer3d_ref {
+                    renderer.add_occlusion_pass(&mut graph, depth, frame_index);
+                }
+
+                if let Some(post_stack) = post_stack {
+                    post_stack.add_passes(&mut graph, scene, swapchain, frame_index)?;
+                }
+
+                graph.execute(&mut self.transients, arena, command_buffer)?;
+            }
+            MainPass::RenderPass { render_pass, framebuffers } => {
+                let framebuffer = framebuffers.get(render_pass, &self.render_targets.attachments(swapchain_view), extent)?;
+                let clear_values = [
+                    clear_value_for_swapchain(CLEAR_COLOR, self.swapchain.format()),
+                    vk::ClearValue {
+                        depth_stencil: vk::ClearDepthStencilValue { depth: 1.0, stencil: 0 },
+                    },
+                ];
+
+                if let Some(renderer) = renderer3d.as_deref_mut() {
+                    renderer.prepare(command_buffer, self.frame_sync.current_frame(), extent, arena)?;
+                }
+
+                render_pass.begin(command_buffer, framebuffer, extent, &clear_values);
+                set_viewport_and_scissor(&self.device, command_buffer, extent);
+
+                if let Some(renderer) = renderer3d.as_deref() {
+                    renderer.record(command_buffer, self.frame_sync.current_frame())?;
+                }
+
+                if let Some(renderer) = renderer2d {
+                    renderer.record(command_buffer, self.frame_sync.current_frame(), extent, arena)?;
+                }
+                render_pass.end(command_buffer);
+            }
+        }
+
+        if let Some(renderer) = renderer3d {
+            renderer.end_frame();
+        }
+
+        if let Some(gpu_timer) = &mut self.gpu_timer {
+            gpu_timer.end(self.frame_commands.main_buffer(), self.frame_sync.current_frame());
+        }
+
+        let command_buffer = self.frame_commands.end_frame()?;
+
+        let queue = self.device.graphics_queue();
+        self.device.begin_queue_label(queue, &format!("Frame {}", self.frame_number));
+        let submitted = self.frame_sync.submit(queue, &[command_buffer], image_index, &[]);
+        self.device.end_queue_label(queue);
+        submitted?;
+        self.frame_number += 1;
+
+        self.swapchain.present(self.device.present_queue(), &[self.frame_sync.render_finished(image_index)], image_index)?;
+        self.frame_sync.advance();
+
+        Ok(())
+    }
+}
+
+/// How many times in a row recovery is attempted before giving up, so a GPU that keeps failing doesn't loop forever.
+const MAX_RECOVERY_ATTEMPTS: u32 = 3;
+
+/// Pause before the first surface retry, doubled for each one after it.
+const SURFACE_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+pub struct App {
+    resources: ResourceRegistry,
+    gpu_resources: GpuResources,
+    frame_arena: FrameArena,
+    pipelines: PipelineRegistry,
+    renderer2d: Option<Renderer2d>,
+    renderer3d: Option<Renderer3d>,
+    post_stack: Option<PostStack>,
+    gpu: Option<GpuState>,
+    surface: Arc<Surface>,
+    instance: Arc<Instance>,
+    event_loop: Option<EventLoop<UserEvent>>,
+    event_loop_proxy: EventLoopProxy<UserEvent>,
+    gpu_config: GpuConfig,
+    recovery_attempts: u32,
+    surface_retries: u32,
+    redraw_policy: RedrawPolicy,
+    latency_mode: LatencyMode,
+    debug_view_key: Option<KeyCode>,
+    validation_overlay: Option<ValidationOverlay>,
+    validation_overlay_key: KeyCode,
+    /// What the overlay covered last frame, damaged again once it covers something else.
+    validation_overlay_area: Option<vk::Rect2D>,
+    /// Set through `Frame::request_redraw`, for the event loop to act on once the frame is drawn.
+    redraw_requested: bool,
+    last_frame: Option<Instant>,
+    /// Frame interval of `PresentPreference::CappedImmediate`, updated when the window moves to another monitor.
+    refresh_interval: Duration,
+    /// When the next capped frame may start, a whole number of intervals after the first so that sleeping late
+    /// doesn't add up over frames. `None` while uncapped.
+    next_deadline: Option<Instant>,
+    cpu_times: FrameTimes,
+    window: Window,
+}
+
+impl App {
+    pub unsafe fn new(config: &AppConfig) -> anyhow::Result<App> {
+        let window_config = &config.window;
+        let entry = ash::Entry::load()?;
+
+        let event_loop = EventLoopBuilder::<UserEvent>::with_user_event().build()?;
+        let event_loop_proxy = event_loop.create_proxy();
+
+        let mut window_builder = WindowBuilder::new()
+            .with_title(&window_config.title);
+
+        if let Some(size) = window_config.size {
+            window_builder = window_builder.with_inner_size(size);
+        }
+
+        if let Some(min_size) = window_config.min_size {
+            window_builder = window_builder.with_min_inner_size(min_size);
+        }
+
+        if let Some(max_size) = window_config.max_size {
+            window_builder = window_builder.with_max_inner_size(max_size);
+        }
+
+        let window = window_builder.build(&event_loop)?;
+
+        let validation = config.validation.clone().or_else(|| {
+            std::env::var(VALIDATION_ENV_VAR).ok()
+                .filter(|value| !value.is_empty() && value != "0")
+                .map(|_| ValidationConfig::default())
+        });
+
+        let validation = match validation {
+            Some(_) if !is_validation_layer_available(&entry)? => {
+                warn!("Validation was requested but {} is not available", VALIDATION_LAYER_NAME.to_string_lossy());
+                None
+            }
+            validation => validation,
+        };
+
+        let extensions = get_required_instance_extensions(&window)?;
+        let instance = Instance::new(entry, config.api_version, &extensions, validation)?;
+        let surface = Surface::new(&instance, &window)?;
+
+        let gpu_config = GpuConfig {
+            adapter: config.adapter.clone(),
+            requirements: config.requirements.clone(),
+            frames_in_flight: config.frames_in_flight,
+            msaa_samples: config.msaa_samples,
+            dynamic_rendering: config.dynamic_rendering,
+            present_preference: config.present_preference,
+            post_processing: config.post_processing,
+            multiview: config.multiview,
+            renderer3d: config.renderer3d,
+            timing_window: config.timing_window,
+            staging_size: config.staging_size,
+        };
+
+        let gpu = GpuState::new(&instance, &surface, window_extent(&window), &gpu_config)?;
+
+        let mut pipelines = PipelineRegistry::new(&gpu.device, gpu.pipeline_target(), GlslCompiler::new());
+        if config.shader_hot_reload {
+            pipelines.enable_hot_reload(WakeHandle::new(event_loop_proxy.clone()));
+        }
+
+        let mut renderer2d = if config.renderer2d {
+            Some(Renderer2d::new(&gpu.device, &gpu.pipeline_target(), pipelines.compiler(), config.frames_in_flight)?)
+        } else {
+            None
+        };
+
+        let validation_overlay = match (config.validation_overlay_font.clone(), renderer2d.as_mut()) {
+            (Some(font_data), Some(renderer)) => {
+                if instance.debug_messenger().is_none() {
+                    warn!("The validation overlay is enabled without validation and will stay empty");
+                }
+                Some(ValidationOverlay::new(renderer, font_data)?)
+            }
+            (Some(_), None) => {
+                warn!("The validation overlay needs the 2D renderer, leaving it out");
+                None
+            }
+            (None, _) => None,
+        };
+
+        let renderer3d = if config.renderer3d {
+            let mut renderer = Renderer3d::new(&gpu.device, &gpu.pipeline_target(), pipelines.compiler(), config.frames_in_flight)?;
+            renderer.set_render_path(config.render_path);
+            renderer.set_ambient_occlusion(config.ambient_occlusion);
+            #[cfg(feature = "ray-tracing")]
+            renderer.set_ray_traced_ambient_occlusion(config.ray_traced_ambient_occlusion);
+            Some(renderer)
+        } else {
+            None
+        };
+
+        let post_stack = match (&gpu.main_pass, config.post_processing) {
+            (MainPass::Dynamic(_), true) => {
+                Some(PostStack::new(&gpu.device, pipelines.compiler(), gpu.swapchain.color_format(), config.frames_in_flight)?)
+            }
+            (MainPass::RenderPass { .. }, true) => {
+                warn!("Post processing needs dynamic rendering, drawing straight to the swapchain instead");
+                None
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            resources: ResourceRegistry::new(),
+            gpu_resources: GpuResources::new(),
+            frame_arena: FrameArena::default(),
+            pipelines,
+            renderer2d,
+            renderer3d,
+            post_stack,
+            gpu: Some(gpu),
+            surface,
+            instance,
+            event_loop: Some(event_loop),
+            event_loop_proxy,
+            gpu_config,
+            recovery_attempts: 0,
+            surface_retries: config.surface_retries,
+            redraw_policy: config.redraw_policy,
+            latency_mode: config.latency_mode,
+            debug_view_key: config.debug_view_key,
+            validation_overlay,
+            validation_overlay_key: config.validation_overlay_key,
+            validation_overlay_area: None,
+            redraw_requested: false,
+            last_frame: None,
+            next_deadline: None,
+            refresh_interval: refresh_interval(&window),
+            cpu_times: FrameTimes::new(config.timing_window),
+            window,
+        })
+    }
+
+    /// Creates an instance with validation enabled, picks a device and creates a logical device on it, then tears
+    /// everything down again. No window or swapchain is involved. Fails if validation reported any error along the
+    /// way; otherwise returns the name of the selected device.
+    pub unsafe fn smoke_test(config: &SmokeTestConfig) -> anyhow::Result<String> {
+        let entry = ash::Entry::load()?;
+
+        if !is_validation_layer_available(&entry)? {
+            return Err(anyhow!("{} is not available", VALIDATION_LAYER_NAME.to_string_lossy()));
+        }
+
+        let instance = Instance::new(entry, config.api_version, &[], Some(config.validation.clone()))?;
+        let device_name = smoke_test_device(&instance, &config.adapter)?;
+
+        let error_count = instance.debug_messenger().map_or(0, |messenger| messenger.error_count());
+        if error_count > 0 {
+            return Err(anyhow!("Validation reported {} error(s) during setup on {}", error_count, device_name));
+        }
+
+        Ok(device_name)
+    }
+
+    pub fn entry(&self) -> &ash::Entry {
+        self.instance.entry()
+    }
+
+    pub fn instance(&self) -> &Arc<Instance> {
+        &self.instance
+    }
+
+    pub fn physical_device(&self) -> vk::PhysicalDevice {
+        self.gpu().device.physical_device()
+    }
+
+    pub fn device(&self) -> &Arc<Device> {
+        &self.gpu().device
+    }
+
+    pub fn graphics_queue(&self) -> vk::Queue {
+        self.gpu().device.graphics_queue()
+    }
+
+    /// The device's memory allocator, locked for as long as the guard lives.
+    pub fn allocator(&self) -> MutexGuard<'_, Allocator> {
+        self.gpu().device.allocator()
+    }
+
+    pub fn swapchain(&self) -> &Swapchain {
+        &self.gpu().swapchain
+    }
+
+    pub fn surface(&self) -> &Arc<Surface> {
+        &self.surface
+    }
+
+    pub fn window(&self) -> &Window {
+        &self.window
+    }
+
+    /// The color format of the main pass's render target, which pipelines drawing in it are built against:
+    /// [`HDR_FORMAT`] with post processing, the swapchain's format otherwise. See `Swapchain::encodes_srgb` for
+    /// whether shaders writing to the swapchain have to encode to sRGB themselves.
+    pub fn color_format(&self) -> vk::Format {
+        self.gpu().scene_format
+    }
+
+    pub fn depth_format(&self) -> vk::Format {
+        self.gpu().depth_format
+    }
+
+    /// The depth attachment of the default render pass, matching the swapchain extent.
+    pub fn depth_image(&self) -> &Image {
+        &self.gpu().render_targets.depth
+    }
+
+    /// The sample count the main render pass actually uses.
+    pub fn msaa_samples(&self) -> vk::SampleCountFlags {
+        self.gpu().msaa_samples
+    }
+
+    pub fn wake_handle(&self) -> WakeHandle {
+        WakeHandle::new(self.event_loop_proxy.clone())
+    }
+
+    /// Changes presentation behavior from the next frame on.
+    pub fn set_present_mode(&mut self, present_preference: PresentPreference) {
+        self.gpu_config.present_preference = present_preference;
+        if let Some(gpu) = self.gpu.as_mut() {
+            gpu.swapchain.set_present_preference(present_preference);
+        }
+    }
+
+    pub fn set_redraw_policy(&mut self, redraw_policy: RedrawPolicy) {
+        self.redraw_policy = redraw_policy;
+    }
+
+    /// Changes when frames wait for the GPU from the next frame on.
+    pub fn set_latency_mode(&mut self, latency_mode: LatencyMode) {
+        self.latency_mode = latency_mode;
+    }
+
+    pub fn latency_mode(&self) -> LatencyMode {
+        self.latency_mode
+    }
+
+    pub fn request_redraw(&self) {
+        self.window.request_redraw();
+    }
+
+    pub fn frames_in_flight(&self) -> usize {
+        self.gpu().frame_sync.frames_in_flight()
+    }
+
+    /// The last value the frame timeline has completed on the GPU. Every frame submission signals a value one higher
+    /// than the one before, so it only ever increases: anything used by work submitted at or before a value can be
+    /// freed once this reaches it. See [`FrameSync::timeline`] for waiting on it from other queues.
+    pub fn gpu_timeline_value(&self) -> anyhow::Result<u64> {
+        unsafe { self.gpu().frame_sync.timeline().value() }
+    }
+
+    /// Time from one frame to the next on the CPU, latest and averaged over the timing window.
+    pub fn cpu_frame_times(&self) -> &FrameTimes {
+        &self.cpu_times
+    }
+
+    /// How long the GPU took for each frame's command buffer, or `None` when the graphics queue has no timestamps.
+    /// Frames are measured once they have finished, a few frames behind the CPU.
+    pub fn gpu_frame_times(&self) -> Option<&FrameTimes> {
+        self.gpu().gpu_timer.as_ref().map(GpuTimer::times)
+    }
+
+    /// Changes how many frames the CPU and GPU frame times average over.
+    pub fn set_timing_window(&mut self, frames: usize) {
+        self.gpu_config.timing_window = frames;
+        self.cpu_times.set_window(frames);
+        if let Some(gpu_timer) = self.gpu.as_mut().and_then(|gpu| gpu.gpu_timer.as_mut()) {
+            gpu_timer.set_window(frames);
+        }
+    }
+
+    /// The main render pass, or `None` when the main pass uses dynamic rendering.
+    pub fn render_pass(&self) -> Option<&RenderPass> {
+        match &self.gpu().main_pass {
+            MainPass::Dynamic(_) => None,
+            MainPass::RenderPass { render_pass, .. } => Some(render_pass),
+        }
+    }
+
+    /// What pipelines drawing in the main pass have to be built for, whichever path it uses.
+    pub fn pipeline_target(&self) -> PipelineTarget {
+        self.gpu().pipeline_target()
+    }
+
+    pub fn descriptors_mut(&mut self) -> &mut DescriptorManager {
+        &mut self.gpu_mut().descriptors
+    }
+
+    pub fn uploader(&self) -> &Uploader {
+        &self.gpu().uploader
+    }
+
+    /// Buffers, images and textures behind typed handles, e.g. for creating them before `run`.
+    pub fn gpu_resources(&self) -> &GpuResources {
+        &self.gpu_resources
+    }
+
+    pub fn gpu_resources_mut(&mut self) -> &mut GpuResources {
+        &mut self.gpu_resources
+    }
+
+    /// Creates a buffer in [`gpu_resources`](Self::gpu_resources).
+    pub fn create_buffer(
+        &mut self,
+        name: &str,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+        location: MemoryLocation,
+    ) -> anyhow::Result<BufferHandle> {
+        let buffer = unsafe { Buffer::new(&self.gpu().device, name, size, usage, location)? };
+        Ok(self.gpu_resources.insert_buffer(buffer))
+    }
+
+    /// Creates an image in [`gpu_resources`](Self::gpu_resources), with undefined contents until it is uploaded to
+    /// or rendered into.
+    pub fn create_image(&mut self, name: &str, desc: &ImageDesc) -> anyhow::Result<ImageHandle> {
+        let image = unsafe { Image::new(&self.gpu().device, name, desc)? };
+        Ok(self.gpu_resources.insert_image(image))
+    }
+
+    /// Creates a sampled texture from tightly packed pixels in [`gpu_resources`](Self::gpu_resources), see
+    /// `Texture::from_pixels`.
+    pub fn create_texture(&mut self, name: &str, desc: ImageDesc, pixels: &[u8], mipmaps: bool) -> anyhow::Result<GpuTextureHandle> {
+        let texture = unsafe { Texture::from_pixels(&self.gpu().device, name, desc, pixels, mipmaps)? };
+        Ok(self.gpu_resources.insert_texture(texture))
+    }
+
+    /// Starts copying `data` to `offset` of the device local buffer behind `buffer` through the uploader. Fails on
+    /// a stale handle.
+    pub fn upload_buffer<T: Pod>(&mut self, buffer: BufferHandle, offset: vk::DeviceSize, data: &[T]) -> anyhow::Result<UploadToken> {
+        let buffer = self.gpu_resources.try_buffer(buffer)?;
+        let uploader = &mut self.gpu.as_mut().expect("GPU state is only missing while it is being recreated").uploader;
+        unsafe { uploader.upload_buffer(buffer, offset, data) }
+    }
+
+    /// Starts copying tightly packed pixels into the image behind `image` through the uploader, leaving it in
+    /// `final_layout`. Fails on a stale handle.
+    pub fn upload_image(&mut self, image: ImageHandle, pixels: &[u8], final_layout: vk::ImageLayout) -> anyhow::Result<UploadToken> {
+        let image = self.gpu_resources.try_image(image)?;
+        let uploader = &mut self.gpu.as_mut().expect("GPU state is only missing while it is being recreated").uploader;
+        unsafe { uploader.upload_image(image, pixels, final_layout) }
+    }
+
+    pub fn uploader_mut(&mut self) -> &mut Uploader {
+        &mut self.gpu_mut().uploader
+    }
+
+    /// Whether what the upload behind `token` wrote may be used by draws recorded in the update callback: the frame's
+    /// command buffer acquires every finished upload before anything else. Lets the callback skip objects whose
+    /// textures or buffers are still in flight. Tokens from before a device loss don't refer to anything anymore.
+    pub fn is_resource_ready(&self, token: UploadToken) -> anyhow::Result<bool> {
+        unsafe { self.gpu().uploader.is_complete(token) }
+    }
+
+    /// Blocks until [`is_resource_ready`](Self::is_resource_ready) would return true for `token`.
+    pub fn wait_resource_ready(&self, token: UploadToken) -> anyhow::Result<()> {
+        unsafe { self.gpu().uploader.wait(token) }
+    }
+
+    /// A 1x1 opaque white texture for materials to bind in texture slots they have nothing for, such as a missing
+    /// roughness map. It is uploaded on first use and, like any upload, can be sampled from the next frame on, once
+    /// `Uploader::acquire_ready` has picked it up.
+    pub fn default_white_texture(&mut self) -> anyhow::Result<&Texture> {
+        let gpu = self.gpu_mut();
+        if gpu.white_texture.is_none() {
+            gpu.white_texture = Some(unsafe { gpu.pixel_texture("default white", vk::Format::R8G8B8A8_SRGB, [255, 255, 255, 255])? });
+        }
+
+        Ok(gpu.white_texture.as_ref().unwrap())
+    }
+
+    /// A 1x1 linear texture of (128, 128, 255), the tangent space normal pointing straight out of the surface, for
+    /// materials without a normal map. Uploaded on first use like [`default_white_texture`](Self::default_white_texture).
+    pub fn default_normal_texture(&mut self) -> anyhow::Result<&Texture> {
+        let gpu = self.gpu_mut();
+        if gpu.normal_texture.is_none() {
+            gpu.normal_texture = Some(unsafe { gpu.pixel_texture("default normal", vk::Format::R8G8B8A8_UNORM, [128, 128, 255, 255])? });
+        }
+
+        Ok(gpu.normal_texture.as_ref().unwrap())
+    }
+
+    pub fn pipelines(&self) -> &PipelineRegistry {
+        &self.pipelines
+    }
+
+    pub fn pipelines_mut(&mut self) -> &mut PipelineRegistry {
+        &mut self.pipelines
+    }
+
+    /// The 2D renderer enabled with `EngineBuilder::with_renderer2d`, e.g. for loading textures before `run`.
+    pub fn renderer2d_mut(&mut self) -> Option<&mut Renderer2d> {
+        self.renderer2d.as_mut()
+    }
+
+    /// The 3D renderer enabled with `EngineBuilder::with_renderer3d`, e.g. for creating meshes before `run`.
+    pub fn renderer3d_mut(&mut self) -> Option<&mut Renderer3d> {
+        self.renderer3d.as_mut()
+    }
+
+    /// The post processing enabled with `EngineBuilder::with_post_processing`, e.g. for adding effects before `run`.
+    pub fn post_stack_mut(&mut self) -> Option<&mut PostStack> {
+        self.post_stack.as_mut()
+    }
+
+    /// Runs `loader` now and again on every device created after a device or surface loss.
+    pub fn register_resource(&mut self, name: &str, loader: ResourceLoader) -> anyhow::Result<()> {
+        let device = self.gpu().device.clone();
+        self.resources.register(name, &device, loader)
+    }
+
+    fn gpu(&self) -> &GpuState {
+        self.gpu.as_ref().expect("GPU state is only missing while it is being recreated")
+    }
+
+    fn gpu_mut(&mut self) -> &mut GpuState {
+        self.gpu.as_mut().expect("GPU state is only missing while it is being recreated")
+    }
+
+    unsafe fn draw_frame(&mut self, update: &mut impl FnMut(&mut Frame) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        let Some(gpu) = self.gpu.as_mut() else {
+            return Ok(());
+        };
+
+        // Waiting here instead of in `GpuState::draw_frame` moves acquire and recording right behind the update.
+        if self.latency_mode == LatencyMode::LowLatency {
+            gpu.frame_sync.wait_for_all_frames()?;
+        }
+
+        // Nothing holds IMMEDIATE back, so the cap is kept here; it doesn't apply to the FIFO fallback.
+        let capped = gpu.swapchain.present_preference() == PresentPreference::CappedImmediate
+            && gpu.swapchain.present_mode() == vk::PresentModeKHR::IMMEDIATE;
+        if capped {
+            let now = Instant::now();
+            let deadline = match self.next_deadline {
+                // More than an interval behind, e.g. after a hitch: start over instead of rushing frames to catch up.
+                Some(deadline) if now.saturating_duration_since(deadline) <= self.refresh_interval => deadline,
+                _ => now,
+            };
+            if deadline > now {
+                std::thread::sleep(deadline - now);
+            }
+            self.next_deadline = Some(deadline + self.refresh_interval);
+        } else {
+            self.next_deadline = None;
+        }
+
+        // Before the update callback, so its scratch allocations don't overwrite what the GPU may still read.
+        gpu.frame_sync.wait_for_current_frame()?;
+        gpu.scratch.begin_frame(gpu.frame_sync.current_frame())?;
+        self.frame_arena.begin_frame();
+        self.gpu_resources.begin_frame(gpu.frame_sync.timeline().value()?, gpu.frame_sync.pending_value());
+
+        let now = Instant::now();
+        let delta = self.last_frame.map_or(Duration::ZERO, |last_frame| now - last_frame);
+        if self.last_frame.is_some() {
+            self.cpu_times.push(delta);
+        }
+        self.last_frame = Some(now);
+
+        let mut frame = Frame {
+            renderer2d: self.renderer2d.as_mut(),
+            renderer3d: self.renderer3d.as_mut(),
+            post_stack: self.post_stack.as_mut(),
+            scratch: &self.frame_arena,
+            gpu_scratch: &mut gpu.scratch,
+            gpu_resources: &mut self.gpu_resources,
+            extent: gpu.swapchain.extent(),
+            delta,
+            damage: Vec::new(),
+            viewports: Vec::new(),
+            redraw_requested: false,
+        };
+        update(&mut frame)?;
+        let (mut damage, viewports) = (frame.damage, frame.viewports);
+        self.redraw_requested |= frame.redraw_requested;
+        if let Some(renderer3d) = &mut self.renderer3d {
+            renderer3d.set_viewports(self.pipelines.compiler(), viewports)?;
+        }
+
+        if let (Some(overlay), Some(renderer)) = (&mut self.validation_overlay, &mut self.renderer2d) {
+            let messages = self.instance.debug_messenger().map_or_else(Vec::new, |messenger| messenger.recent_messages());
+            let area = overlay.draw(renderer, &messages, gpu.swapchain.extent())?;
+            // Without damage the whole image is presented anyway.
+            if !damage.is_empty() {
+                damage.extend(area);
+                // Where the panel no longer reaches, e.g. after hiding it, the scene has to be shown again.
+                if self.validation_overlay_area != area {
+                    damage.extend(self.validation_overlay_area);
+                }
+            }
+            self.validation_overlay_area = area;
+        }
+
+        for rect in damage {
+            gpu.swapchain.add_damage(rect);
+        }
+
+        self.pipelines.apply_changes()?;
+        let post_stack = self.post_stack.as_mut().filter(|_| matches!(gpu.main_pass, MainPass::Dynamic(_)));
+        gpu.draw_frame(self.renderer2d.as_mut(), self.renderer3d.as_mut(), post_stack, &self.frame_arena)
+    }
+
+    /// Tears down everything built on the lost device (and the surface, if that was lost), creates it all again and
+    /// rebuilds registered pipelines and resources on the new device.
+    unsafe fn recover(&mut self, loss: Loss) -> anyhow::Result<()> {
+        self.recovery_attempts += 1;
+        if self.recovery_attempts > MAX_RECOVERY_ATTEMPTS {
+            return Err(anyhow!("Giving up after {} failed recovery attempts", MAX_RECOVERY_ATTEMPTS));
+        }
+
+        warn!("{:?} lost, recreating it (attempt {})", loss, self.recovery_attempts);
+
+        // The old swapchain must be gone before a new one is created for the window.
+        self.gpu_resources.clear();
+        self.gpu = None;
+
+        let mut loss = loss;
+        let mut retry = 0;
+        let gpu = loop {
+            match self.recreate_gpu(loss) {
+                Ok(gpu) => break gpu,
+                Err(err) if retry < self.surface_retries && Loss::from_error(&err) == Some(Loss::Surface) => {
+                    let backoff = SURFACE_RETRY_BACKOFF * 2u32.pow(retry);
+                    retry += 1;
+                    warn!("Surface lost again while recreating it, retry {} of {} in {:?}", retry, self.surface_retries, backoff);
+                    std::thread::sleep(backoff);
+                    loss = Loss::Surface;
+                }
+                Err(err) => return Err(err),
+            }
+        };
+
+        self.pipelines.recreate(&gpu.device, gpu.pipeline_target())?;
+        if let Some(renderer2d) = &mut self.renderer2d {
+            renderer2d.recreate(&gpu.device, &gpu.pipeline_target(), self.pipelines.compiler())?;
+        }
+
+        if let Some(renderer3d) = &mut self.renderer3d {
+            renderer3d.recreate(&gpu.device, &gpu.pipeline_target(), self.pipelines.compiler())?;
+        }
+
+        if let Some(post_stack) = &mut self.post_stack {
+            post_stack.recreate(&gpu.device, self.pipelines.compiler(), gpu.swapchain.color_format(), self.gpu_config.frames_in_flight)?;
+        }
+        self.resources.reload_all(&gpu.device)?;
+        self.gpu = Some(gpu);
+
+        info!("Recovered from {:?} loss", loss);
+        Ok(())
+    }
+
+    /// Creates the surface again when `loss` is a surface loss, then everything on a new device.
+    unsafe fn recreate_gpu(&mut self, loss: Loss) -> anyhow::Result<GpuState> {
+        if loss == Loss::Surface {
+            self.surface = Surface::new(&self.instance, &self.window)?;
+        }
+
+        GpuState::new(&self.instance, &self.surface, window_extent(&self.window), &self.gpu_config)
+    }
+
+    pub fn run(self) -> anyhow::Result<()> {
+        self.run_with(|_| Ok(()))
+    }
+
+    /// Draws `frames` frames back to back with IMMEDIATE presentation, so vsync doesn't hold them back, and reports
+    /// their CPU and GPU times in the current latency mode. Events aren't processed in between, so this is meant to be called before `run`. It
+    /// still renders to the window, since that is what the swapchain presents to.
+    pub fn benchmark(&mut self, frames: u32) -> anyhow::Result<BenchReport> {
+        self.benchmark_with(frames, |_| Ok(()))
+    }
+
+    /// Like [`benchmark`](Self::benchmark), calling `update` before every frame as `run_with` does.
+    pub fn benchmark_with(&mut self, frames: u32, mut update: impl FnMut(&mut Frame) -> anyhow::Result<()>) -> anyhow::Result<BenchReport> {
+        let present_preference = self.gpu_config.present_preference;
+        self.set_present_mode(PresentPreference::Immediate);
+
+        let mut cpu_times = Vec::with_capacity(frames as usize);
+        let mut gpu_times = Vec::with_capacity(frames as usize);
+        let start = Instant::now();
+
+        let result = (|| -> anyhow::Result<()> {
+            let mut measured = self.gpu().gpu_timer.as_ref().map_or(0, GpuTimer::measured);
+            for _ in 0..frames {
+                let frame_start = Instant::now();
+                unsafe { self.draw_frame(&mut update)? };
+                cpu_times.push(frame_start.elapsed());
+
+                if let Some(gpu_timer) = self.gpu().gpu_timer.as_ref().filter(|gpu_timer| gpu_timer.measured() > measured) {
+                    measured = gpu_timer.measured();
+                    gpu_times.extend(gpu_timer.times().latest());
+                }
+            }
+
+            Ok(())
+        })();
+
+        let wall_time = start.elapsed();
+        self.set_present_mode(present_preference);
+        result?;
+
+        Ok(BenchReport {
+            latency_mode: self.latency_mode,
+            frames,
+            wall_time,
+            cpu: TimingStats::from_samples(&cpu_times),
+            gpu: TimingStats::from_samples(&gpu_times),
+        })
+    }
+
+    /// Runs the event loop, calling `update` before every frame is drawn. Returning an error stops the loop.
+    pub fn run_with(mut self, mut update: impl FnMut(&mut Frame) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        let event_loop = self.event_loop.take().ok_or(anyhow!("App is already running"))?;
+
+        event_loop.run(move |event, elwt| {
+            elwt.set_control_flow(match self.redraw_policy {
+                RedrawPolicy::Continuous => ControlFlow::Poll,
+                RedrawPolicy::OnDemand => ControlFlow::Wait,
+            });
+
+            match event {
+                Event::WindowEvent { event: WindowEvent::CloseRequested, .. } => elwt.exit(),
+                Event::WindowEvent { event: WindowEvent::Resized(size), .. } => {
+                    if let Some(gpu) = &mut self.gpu {
+                        gpu.swapchain.resize(vk::Extent2D {
+                            width: size.width,
+                            height: size.height,
+                        });
+                    }
+                }
+                Event::WindowEvent { event: WindowEvent::Moved(_) | WindowEvent::ScaleFactorChanged { .. }, .. } => {
+                    self.refresh_interval = refresh_interval(&self.window);
+                }
+                Event::WindowEvent { event: WindowEvent::KeyboardInput { event, .. }, .. } => {
+                    let pressed = event.state == ElementState::Pressed && !event.repeat;
+                    if let (true, Some(overlay)) = (pressed, &mut self.validation_overlay) {
+                        if event.physical_key == PhysicalKey::Code(self.validation_overlay_key) {
+                            overlay.toggle();
+                            self.window.request_redraw();
+                        }
+                    }
+                    if let (true, Some(key), Some(renderer)) = (pressed, self.debug_view_key, &mut self.renderer3d) {
+                        if event.physical_key == PhysicalKey::Code(key) {
+                            let view = renderer.debug_view().next();
+                            info!("Debug view: {:?}", view);
+                            renderer.set_debug_view(view);
+                            self.window.request_redraw();
+                        }
+                    }
+                }
+                Event::WindowEvent { event: WindowEvent::RedrawRequested, .. } => {
+                    let result = match unsafe { self.draw_frame(&mut update) } {
+                        Ok(()) => {
+                            self.recovery_attempts = 0;
+                            Ok(())
+                        }
+                        Err(err) => match Loss::from_error(&err) {
+                            Some(loss) => unsafe { self.recover(loss) },
+                            None => Err(err),
+                        },
+                    };
+
+                    if std::mem::take(&mut self.redraw_requested) {
+                        self.window.request_redraw();
+                    }
+
+                    if let Err(err) = result {
+                        error!("Failed to draw frame: {:?}", err);
+                        elwt.exit();
+                    }
+                }
+                Event::UserEvent(user_event) => {
+                    debug!("Received user event: {:?}", user_event);
+                    self.window.request_redraw();
+                }
+                Event::AboutToWait if self.redraw_policy == RedrawPolicy::Continuous => {
+                    self.window.request_redraw();
+                }
+                _ => {}
+            }
+        })?;
+
+        Ok(())
+    }
+}
+
+/// What the callback passed to `App::run_with` can touch while a frame is being built.
+pub struct Frame<'f> {
+    renderer2d: Option<&'f mut Renderer2d>,
+    renderer3d: Option<&'f mut Renderer3d>,
+    post_stack: Option<&'f mut PostStack>,
+    scratch: &'f FrameArena,
+    gpu_scratch: &'f mut ScratchArena,
+    gpu_resources: &'f mut GpuResources,
+    extent: vk::Extent2D,
+    delta: Duration,
+    damage: Vec<vk::Rect2D>,
+    viewports: Vec<Viewport>,
+    redraw_requested: bool,
+}
+
+impl Frame<'_> {
+    pub fn renderer2d(&mut self) -> &mut Renderer2d {
+        self.renderer2d.as_deref_mut().expect("The 2D renderer is only available with EngineBuilder::with_renderer2d")
+    }
+
+    pub fn renderer3d(&mut self) -> &mut Renderer3d {
+        self.renderer3d.as_deref_mut().expect("The 3D renderer is only available with EngineBuilder::with_renderer3d")
+    }
+
+    pub fn post_stack(&mut self) -> &mut PostStack {
+        self.post_stack.as_deref_mut().expect("Post processing is only available with EngineBuilder::with_post_processing")
+    }
+
+    /// CPU memory for lists and values only needed while this frame is built. Everything in it is freed before the
+    /// next frame's update, which the borrow of the frame enforces.
+    pub fn scratch(&self) -> &FrameArena {
+        self.scratch
+    }
+
+    /// Host visible memory for data that only this frame reads on the GPU. Allocations are freed when the frame's
+    /// slot is recorded again, `frames_in_flight` frames later, and must not be used from then on.
+    pub fn gpu_scratch(&mut self) -> &mut ScratchArena {
+        self.gpu_scratch
+    }
+
+    /// Buffers, images and textures behind typed handles. Ones destroyed here are freed once the frames in flight,
+    /// this one included, have finished.
+    pub fn gpu_resources(&mut self) -> &mut GpuResources {
+        self.gpu_resources
+    }
+
+    /// The size of the image this frame renders to.
+    pub fn extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+
+    /// Time since the previous frame, zero on the first one.
+    pub fn delta(&self) -> Duration {
+        self.delta
+    }
+
+    /// Marks `rect` as changed since the previous frame. When regions are marked, presentation only asks for those
+    /// to be updated; see `Swapchain::add_damage`.
+    pub fn mark_dirty(&mut self, rect: vk::Rect2D) {
+        self.damage.push(rect);
+    }
+
+    /// Where the 3D renderer draws the scene this frame, each part through its own camera, e.g. from
+    /// `Viewport::side_by_side` for split-screen. Starts out empty every frame, which draws the renderer's camera
+    /// over the whole image.
+    pub fn viewports_mut(&mut self) -> &mut Vec<Viewport> {
+        &mut self.viewports
+    }
+
+    /// Draws another frame after this one under `RedrawPolicy::OnDemand`, e.g. while an animation runs. The
+    /// counterpart of `App::request_redraw` for the update callback, which can't reach the app `run_with` consumed.
+    pub fn request_redraw(&mut self) {
+        self.redraw_requested = true;
+    }
+}
+
+unsafe fn smoke_test_device(instance: &Instance, adapter: &AdapterSelection) -> anyhow::Result<String> {
+    let physical_device = select_physical_device(instance, None, &DeviceRequirements::new(), adapter)?;
+    let device_name = physical_device_name(instance, physical_device);
+    info!("Selected physical device: {}", device_name);
+
+    let queue_family_index = instance.get_physical_device_queue_family_properties(physical_device)
+        .iter()
+        .position(|family| family.queue_flags.contains(vk::QueueFlags::GRAPHICS))
+        .ok_or(anyhow!("{} has no graphics queue family", device_name))? as u32;
+
+    let queue_priorities = [1.0];
+    let queue_create_infos = [vk::DeviceQueueCreateInfo::builder()
+        .queue_family_index(queue_family_index)
+        .queue_priorities(&queue_priorities)
+        .build()];
+
+    let create_info = vk::DeviceCreateInfo::builder()
+        .queue_create_infos(&queue_create_infos);
+
+    let device = instance.create_device(physical_device, &create_info, None)?;
+    info!("Created logical device");
+
+    let result = device.device_wait_idle();
+    device.destroy_device(None);
+    result?;
+
+    Ok(device_name)
+}
@@ -0,0 +1,66 @@
+use std::fmt;
+use std::time::Duration;
+use crate::sync::LatencyMode;
+
+/// Summary of a set of frame times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimingStats {
+    pub average: Duration,
+    pub min: Duration,
+    pub max: Duration,
+    /// 99% of the frames took at most this long.
+    pub p99: Duration,
+}
+
+impl TimingStats {
+    /// `None` for no samples.
+    pub fn from_samples(samples: &[Duration]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        let mut sorted = samples.to_vec();
+        sorted.sort_unstable();
+        let p99_index = (sorted.len() * 99).div_ceil(100) - 1;
+
+        Some(Self {
+            average: sorted.iter().sum::<Duration>() / sorted.len() as u32,
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+            p99: sorted[p99_index],
+        })
+    }
+}
+
+impl fmt::Display for TimingStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "avg {:?}, min {:?}, max {:?}, p99 {:?}", self.average, self.min, self.max, self.p99)
+    }
+}
+
+/// What `App::benchmark` measured.
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub latency_mode: LatencyMode,
+    pub frames: u32,
+    pub wall_time: Duration,
+    /// Time spent in each frame on the CPU, from the update callback to presentation.
+    pub cpu: Option<TimingStats>,
+    /// GPU time of each frame's command buffer, `None` without timestamp support. The last frames in flight finish
+    /// after the benchmark and aren't included.
+    pub gpu: Option<TimingStats>,
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} frames in {:?} ({:?})", self.frames, self.wall_time, self.latency_mode)?;
+        match &self.cpu {
+            Some(cpu) => writeln!(f, "CPU: {}", cpu)?,
+            None => writeln!(f, "CPU: no frames")?,
+        }
+        match &self.gpu {
+            Some(gpu) => write!(f, "GPU: {}", gpu),
+            None => write!(f, "GPU: unavailable"),
+        }
+    }
+}
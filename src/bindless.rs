@@ -0,0 +1,187 @@
+use std::sync::Arc;
+use anyhow::anyhow;
+use ash::vk;
+use log::debug;
+use crate::descriptors::SetLayoutDesc;
+use crate::device::Device;
+use crate::glsl::insert_after_version;
+use crate::requirements::{DeviceRequirements, Feature};
+
+const BINDLESS_GLSL: &str = include_str!("../shaders/bindless.glsl");
+
+/// The most textures a [`BindlessTextures`] holds, unless the device allows fewer.
+pub const MAX_BINDLESS_TEXTURES: u32 = 4096;
+
+/// The features [`BindlessTextures`] needs, all optional so devices without them still run, binding textures per
+/// material instance.
+pub fn bindless_requirements() -> DeviceRequirements {
+    DeviceRequirements::new()
+        .optional_feature(Feature::ShaderSampledImageArrayDynamicIndexing)
+        .optional_feature(Feature::RuntimeDescriptorArray)
+        .optional_feature(Feature::DescriptorBindingPartiallyBound)
+        .optional_feature(Feature::DescriptorBindingVariableDescriptorCount)
+        .optional_feature(Feature::DescriptorBindingSampledImageUpdateAfterBind)
+}
+
+/// Whether the device was created with everything from [`bindless_requirements`].
+pub fn supports_bindless(device: &Device) -> bool {
+    let capabilities = device.capabilities();
+    [
+        Feature::ShaderSampledImageArrayDynamicIndexing,
+        Feature::RuntimeDescriptorArray,
+        Feature::DescriptorBindingPartiallyBound,
+        Feature::DescriptorBindingVariableDescriptorCount,
+        Feature::DescriptorBindingSampledImageUpdateAfterBind,
+    ]
+    .into_iter()
+    .all(|feature| capabilities.has_feature(feature))
+}
+
+/// How many textures fit in the array, with room left in the fragment stage for the samplers sets bind besides it.
+pub unsafe fn bindless_capacity(device: &Device) -> u32 {
+    let mut indexing = vk::PhysicalDeviceDescriptorIndexingProperties::default();
+    let mut properties = vk::PhysicalDeviceProperties2::builder().push_next(&mut indexing);
+    device.instance().get_physical_device_properties2(device.physical_device(), &mut properties);
+
+    let limit = indexing.max_per_stage_descriptor_update_after_bind_samplers
+        .min(indexing.max_per_stage_descriptor_update_after_bind_sampled_images)
+        .min(indexing.max_descriptor_set_update_after_bind_samplers)
+        .min(indexing.max_descriptor_set_update_after_bind_sampled_images);
+    MAX_BINDLESS_TEXTURES.min(limit / 2)
+}
+
+/// Prepends `bindless.glsl` with `BINDLESS` defined, for a material shader with `texture_slots` texture slots.
+pub fn with_bindless(source: &str, texture_slots: usize) -> String {
+    insert_after_version(source, &format!("#define BINDLESS\n#define TEXTURE_SLOTS {}\n{}", texture_slots, BINDLESS_GLSL))
+}
+
+/// A texture's index in a [`BindlessTextures`] array, which is what shaders look it up by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TextureHandle(u32);
+
+impl TextureHandle {
+    pub fn index(self) -> u32 {
+        self.0
+    }
+}
+
+/// One large combined image sampler array shared by every draw, put at the last binding of a few sets allocated
+/// from its own pool, such as one frame set per frame in flight. Textures are handed a [`TextureHandle`] when
+/// inserted and written into every set at once; since the array is partially bound and updated after bind, that
+/// doesn't wait for frames in flight.
+pub struct BindlessTextures {
+    device: Arc<Device>,
+    pool: vk::DescriptorPool,
+    binding: u32,
+    capacity: u32,
+    sets: Vec<vk::DescriptorSet>,
+    /// What each handle points at, to write it into sets allocated later.
+    textures: Vec<Option<(vk::ImageView, vk::Sampler)>>,
+    free: Vec<u32>,
+}
+
+impl BindlessTextures {
+    /// Creates the pool for `count` sets of `desc`, whose last binding, `binding`, is an array from
+    /// [`SetLayoutDesc::bindless_array`] of `capacity` descriptors.
+    pub unsafe fn new(device: &Arc<Device>, desc: &SetLayoutDesc, binding: u32, capacity: u32, count: usize) -> anyhow::Result<Self> {
+        let pool_sizes: Vec<vk::DescriptorPoolSize> = desc.bindings().iter()
+            .map(|layout_binding| vk::DescriptorPoolSize {
+                ty: layout_binding.descriptor_type,
+                descriptor_count: layout_binding.count * count as u32,
+            })
+            .collect();
+
+        let create_info = vk::DescriptorPoolCreateInfo::builder()
+            .flags(vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND)
+            .max_sets(count as u32)
+            .pool_sizes(&pool_sizes);
+
+        debug!("Bindless texture array of {} textures", capacity);
+        Ok(Self {
+            device: device.clone(),
+            pool: device.create_descriptor_pool(&create_info, None)?,
+            binding,
+            capacity,
+            sets: Vec::new(),
+            textures: Vec::new(),
+            free: Vec::new(),
+        })
+    }
+
+    /// Allocates a set of `layout` with the whole array, holding every texture inserted so far.
+    pub unsafe fn allocate(&mut self, layout: vk::DescriptorSetLayout) -> anyhow::Result<vk::DescriptorSet> {
+        let layouts = [layout];
+        let counts = [self.capacity];
+        let mut variable_count = vk::DescriptorSetVariableDescriptorCountAllocateInfo::builder()
+            .descriptor_counts(&counts);
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(self.pool)
+            .set_layouts(&layouts)
+            .push_next(&mut variable_count);
+
+        let set = self.device.allocate_descriptor_sets(&allocate_info)?[0];
+        for (index, texture) in self.textures.iter().enumerate() {
+            if let Some((view, sampler)) = *texture {
+                self.write(set, index as u32, view, sampler);
+            }
+        }
+
+        self.sets.push(set);
+        Ok(set)
+    }
+
+    /// Puts a texture in the array. `view` must stay alive until it's removed again.
+    pub unsafe fn insert(&mut self, view: vk::ImageView, sampler: vk::Sampler) -> anyhow::Result<TextureHandle> {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None if (self.textures.len() as u32) < self.capacity => {
+                self.textures.push(None);
+                self.textures.len() as u32 - 1
+            }
+            None => return Err(anyhow!("The bindless texture array is full at {} textures", self.capacity)),
+        };
+
+        self.textures[index as usize] = Some((view, sampler));
+        for &set in &self.sets {
+            self.write(set, index, view, sampler);
+        }
+
+        Ok(TextureHandle(index))
+    }
+
+    /// Makes `handle` available to the next texture inserted. No frame in flight may still sample it.
+    pub fn remove(&mut self, handle: TextureHandle) {
+        if self.textures.get_mut(handle.0 as usize).and_then(Option::take).is_some() {
+            self.free.push(handle.0);
+        }
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    unsafe fn write(&self, set: vk::DescriptorSet, index: u32, view: vk::ImageView, sampler: vk::Sampler) {
+        let info = vk::DescriptorImageInfo {
+            sampler,
+            image_view: view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        };
+        let write = vk::WriteDescriptorSet::builder()
+            .dst_set(set)
+            .dst_binding(self.binding)
+            .dst_array_element(index)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(std::slice::from_ref(&info))
+            .build();
+
+        self.device.update_descriptor_sets(&[write], &[]);
+    }
+}
+
+impl Drop for BindlessTextures {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_descriptor_pool(self.pool, None);
+        }
+    }
+}
@@ -0,0 +1,401 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+use anyhow::anyhow;
+use ash::vk;
+use log::debug;
+use bytemuck::Pod;
+use crate::allocator::{Allocation, MemoryLocation};
+use crate::commands::submit_one_time;
+use crate::device::Device;
+
+/// A `vk::Buffer` with its memory. Device local buffers are filled through a staging buffer; host visible ones
+/// stay mapped and are written directly.
+pub struct Buffer {
+    device: Arc<Device>,
+    handle: vk::Buffer,
+    allocation: Option<Allocation>,
+    size: vk::DeviceSize,
+    usage: vk::BufferUsageFlags,
+    location: MemoryLocation,
+}
+
+impl Buffer {
+    pub unsafe fn new(
+        device: &Arc<Device>,
+        name: &str,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+        location: MemoryLocation,
+    ) -> anyhow::Result<Self> {
+        if usage.contains(vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS) && !device.supports_buffer_device_address() {
+            return Err(anyhow!("Buffer '{}' needs a device address, but bufferDeviceAddress is not enabled", name));
+        }
+
+        let usage = if location == MemoryLocation::GpuOnly {
+            usage | vk::BufferUsageFlags::TRANSFER_DST
+        } else {
+            usage
+        };
+
+        let create_info = vk::BufferCreateInfo::builder()
+            .size(size)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+        let (handle, allocation) = device.create_buffer_with_memory(&create_info, location, name)?;
+
+        Ok(Self {
+            device: device.clone(),
+            handle,
+            allocation: Some(allocation),
+            size,
+            usage,
+            location,
+        })
+    }
+
+    /// Like [`new`](Self::new), with `SHADER_DEVICE_ADDRESS` usage so shaders can reach it through
+    /// [`device_address`](Self::device_address) instead of a descriptor, e.g. pushed as a `u64` and read through a
+    /// `GL_EXT_buffer_reference` block. Check `Device::supports_buffer_device_address` first; this fails without it.
+    pub unsafe fn addressable(
+        device: &Arc<Device>,
+        name: &str,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+        location: MemoryLocation,
+    ) -> anyhow::Result<Self> {
+        Self::new(device, name, size, usage | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS, location)
+    }
+
+    /// Creates a device local buffer holding `data`.
+    pub unsafe fn with_data<T: Pod>(device: &Arc<Device>, name: &str, usage: vk::BufferUsageFlags, data: &[T]) -> anyhow::Result<Self> {
+        let mut buffer = Self::new(device, name, std::mem::size_of_val(data) as vk::DeviceSize, usage, MemoryLocation::GpuOnly)?;
+        buffer.upload(data)?;
+        Ok(buffer)
+    }
+
+    pub unsafe fn vertex<T: Pod>(device: &Arc<Device>, name: &str, vertices: &[T]) -> anyhow::Result<Self> {
+        Self::with_data(device, name, vk::BufferUsageFlags::VERTEX_BUFFER, vertices)
+    }
+
+    /// Index buffer from `u16` or `u32` indices.
+    pub unsafe fn index<T: Pod>(device: &Arc<Device>, name: &str, indices: &[T]) -> anyhow::Result<Self> {
+        Self::with_data(device, name, vk::BufferUsageFlags::INDEX_BUFFER, indices)
+    }
+
+    /// A host visible uniform buffer of `size` bytes, written with [`write`](Self::write).
+    pub unsafe fn uniform(device: &Arc<Device>, name: &str, size: vk::DeviceSize) -> anyhow::Result<Self> {
+        Self::new(device, name, size, vk::BufferUsageFlags::UNIFORM_BUFFER, MemoryLocation::CpuToGpu)
+    }
+
+    pub unsafe fn storage(device: &Arc<Device>, name: &str, size: vk::DeviceSize) -> anyhow::Result<Self> {
+        Self::new(device, name, size, vk::BufferUsageFlags::STORAGE_BUFFER, MemoryLocation::GpuOnly)
+    }
+
+    pub fn handle(&self) -> vk::Buffer {
+        self.handle
+    }
+
+    pub fn size(&self) -> vk::DeviceSize {
+        self.size
+    }
+
+    pub fn usage(&self) -> vk::BufferUsageFlags {
+        self.usage
+    }
+
+    pub fn location(&self) -> MemoryLocation {
+        self.location
+    }
+
+    /// Needs a buffer created with `SHADER_DEVICE_ADDRESS` usage, such as one from [`addressable`](Self::addressable).
+    pub unsafe fn device_address(&self) -> vk::DeviceAddress {
+        debug_assert!(
+            self.usage.contains(vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS),
+            "Buffer was created without SHADER_DEVICE_ADDRESS usage",
+        );
+        self.device.get_buffer_device_address(&vk::BufferDeviceAddressInfo::builder().buffer(self.handle))
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        self.allocation.as_ref().is_some_and(|allocation| allocation.mapped_ptr().is_some())
+    }
+
+    /// The mapped memory of a host visible buffer as `T`s. Fails for buffers that aren't mapped, and when the
+    /// buffer's size isn't a multiple of `T` or its memory isn't aligned for it.
+    pub fn mapped_slice<T: Pod>(&self) -> anyhow::Result<&[T]> {
+        let size = self.size as usize;
+        let bytes = self.allocation.as_ref()
+            .and_then(|allocation| allocation.mapped_slice())
+            .ok_or(anyhow!("Buffer is not host visible"))?;
+
+        bytemuck::try_cast_slice(&bytes[..size])
+            .map_err(|err| anyhow!("Buffer of {} bytes can't be viewed as {}: {:?}", size, std::any::type_name::<T>(), err))
+    }
+
+    /// Mutable version of [`mapped_slice`](Self::mapped_slice).
+    pub fn mapped_slice_mut<T: Pod>(&mut self) -> anyhow::Result<&mut [T]> {
+        let size = self.size as usize;
+        let bytes = self.allocation.as_mut()
+            .and_then(|allocation| allocation.mapped_slice_mut())
+            .ok_or(anyhow!("Buffer is not host visible"))?;
+
+        bytemuck::try_cast_slice_mut(&mut bytes[..size])
+            .map_err(|err| anyhow!("Buffer of {} bytes can't be viewed as {}: {:?}", size, std::any::type_name::<T>(), err))
+    }
+
+    /// Copies `data` to `offset` of a mapped buffer.
+    pub fn write<T: Pod>(&mut self, offset: vk::DeviceSize, data: &[T]) -> anyhow::Result<()> {
+        let bytes: &[u8] = bytemuck::cast_slice(data);
+        let offset = offset as usize;
+
+        let mapped = self.mapped_slice_mut::<u8>()?;
+        mapped.get_mut(offset..offset + bytes.len())
+            .ok_or(anyhow!("Write of {} bytes at offset {} is out of bounds", bytes.len(), offset))?
+            .copy_from_slice(bytes);
+
+        Ok(())
+    }
+
+    /// Replaces the start of the buffer with `data`. Mapped buffers are written directly; device local ones go
+    /// through a temporary staging buffer and a blocking copy on the graphics queue.
+    pub unsafe fn upload<T: Pod>(&mut self, data: &[T]) -> anyhow::Result<()> {
+        if self.is_mapped() {
+            return self.write(0, data);
+        }
+
+        let size = std::mem::size_of_val(data) as vk::DeviceSize;
+        if size > self.size {
+            return Err(anyhow!("Upload of {} bytes does not fit a buffer of {} bytes", size, self.size));
+        }
+
+        if size == 0 {
+            return Ok(());
+        }
+
+        let mut staging = Self::new(&self.device, "staging", size, vk::BufferUsageFlags::TRANSFER_SRC, MemoryLocation::CpuToGpu)?;
+        staging.write(0, data)?;
+
+        let device = &self.device;
+        submit_one_time(device, device.queue_families().graphics, device.graphics_queue(), |command_buffer| {
+            device.cmd_copy_buffer(command_buffer, staging.handle, self.handle, &[vk::BufferCopy {
+                src_offset: 0,
+                dst_offset: 0,
+                size,
+            }]);
+        })
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_buffer(self.handle, None);
+
+            if let Some(allocation) = self.allocation.take() {
+                self.device.free(allocation);
+            }
+        }
+    }
+}
+
+/// One persistently mapped uniform buffer per frame in flight, so the CPU can update this frame's copy while the
+/// GPU still reads the previous ones.
+pub struct PerFrameUniform<T: Pod> {
+    buffers: Vec<Buffer>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Pod> PerFrameUniform<T> {
+    pub unsafe fn new(device: &Arc<Device>, name: &str, frames_in_flight: usize) -> anyhow::Result<Self> {
+        let buffers = (0..frames_in_flight)
+            .map(|_| Buffer::uniform(device, name, std::mem::size_of::<T>() as vk::DeviceSize))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            buffers,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn write(&mut self, frame_index: usize, value: &T) -> anyhow::Result<()> {
+        self.buffers[frame_index].write(0, std::slice::from_ref(value))
+    }
+
+    pub fn buffer(&self, frame_index: usize) -> &Buffer {
+        &self.buffers[frame_index]
+    }
+
+    pub fn descriptor_info(&self, frame_index: usize) -> vk::DescriptorBufferInfo {
+        vk::DescriptorBufferInfo {
+            buffer: self.buffers[frame_index].handle(),
+            offset: 0,
+            range: std::mem::size_of::<T>() as vk::DeviceSize,
+        }
+    }
+}
+
+/// Values gathered on the CPU over a frame and uploaded into that frame's host visible storage buffer, one per
+/// frame in flight. A frame's buffer grows when its values don't fit.
+pub struct PerFrameStorage<T: Pod> {
+    device: Arc<Device>,
+    name: String,
+    buffers: Vec<Buffer>,
+    values: Vec<T>,
+}
+
+impl<T: Pod> PerFrameStorage<T> {
+    pub unsafe fn new(device: &Arc<Device>, name: &str, capacity: usize, frames_in_flight: usize) -> anyhow::Result<Self> {
+        let buffers = (0..frames_in_flight)
+            .map(|_| create_storage_buffer::<T>(device, name, capacity))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            device: device.clone(),
+            name: name.to_owned(),
+            buffers,
+            values: Vec::new(),
+        })
+    }
+
+    /// This frame's values, to append to.
+    pub fn values_mut(&mut self) -> &mut Vec<T> {
+        &mut self.values
+    }
+
+    pub fn buffer(&self, frame_index: usize) -> &Buffer {
+        &self.buffers[frame_index]
+    }
+
+    /// Writes this frame's values into the buffer of `frame_index`. Returns whether the buffer grew, in which case
+    /// descriptors pointing at it have to be written again.
+    pub unsafe fn upload(&mut self, frame_index: usize) -> anyhow::Result<bool> {
+        let size = std::mem::size_of_val(self.values.as_slice()) as vk::DeviceSize;
+        let grew = size > self.buffers[frame_index].size();
+        if grew {
+            let capacity = self.values.len().next_power_of_two();
+            debug!("Growing the {} buffer of frame {} to {} elements", self.name, frame_index, capacity);
+            self.buffers[frame_index] = create_storage_buffer::<T>(&self.device, &self.name, capacity)?;
+        }
+
+        self.buffers[frame_index].write(0, &self.values)?;
+        Ok(grew)
+    }
+
+    /// Starts over for the next frame.
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+}
+
+/// Size of each frame's block in a [`ScratchArena`] unless configured otherwise.
+pub const DEFAULT_SCRATCH_SIZE: vk::DeviceSize = 4 << 20;
+
+const SCRATCH_USAGE: vk::BufferUsageFlags = vk::BufferUsageFlags::from_raw(
+    vk::BufferUsageFlags::UNIFORM_BUFFER.as_raw()
+        | vk::BufferUsageFlags::STORAGE_BUFFER.as_raw()
+        | vk::BufferUsageFlags::VERTEX_BUFFER.as_raw()
+        | vk::BufferUsageFlags::INDEX_BUFFER.as_raw()
+        | vk::BufferUsageFlags::INDIRECT_BUFFER.as_raw(),
+);
+
+/// Where [`ScratchArena::allocate`] put some data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScratchAllocation {
+    pub buffer: vk::Buffer,
+    pub offset: vk::DeviceSize,
+    pub size: vk::DeviceSize,
+}
+
+impl ScratchAllocation {
+    pub fn descriptor_info(&self) -> vk::DescriptorBufferInfo {
+        vk::DescriptorBufferInfo {
+            buffer: self.buffer,
+            offset: self.offset,
+            range: self.size,
+        }
+    }
+}
+
+/// A bump allocator over host visible buffers for data that only lives for one frame, such as uniforms and vertices
+/// written while recording it. Each frame in flight has its own blocks, reset by [`begin_frame`](Self::begin_frame)
+/// once the frame's previous submission has finished, so an allocation stays valid until its frame slot comes
+/// around again and must not be used after that. A frame that fills its block gets another one for the rest of it,
+/// and the slot is merged into a single block large enough for them all when it is next reset.
+pub struct ScratchArena {
+    device: Arc<Device>,
+    block_size: vk::DeviceSize,
+    /// Satisfies the offset alignment of uniform and storage buffer descriptors.
+    alignment: vk::DeviceSize,
+    frames: Vec<Vec<Buffer>>,
+    current_frame: usize,
+    offset: vk::DeviceSize,
+}
+
+impl ScratchArena {
+    pub unsafe fn new(device: &Arc<Device>, block_size: vk::DeviceSize, frames_in_flight: usize) -> anyhow::Result<Self> {
+        let limits = device.instance().get_physical_device_properties(device.physical_device()).limits;
+        let frames = (0..frames_in_flight)
+            .map(|_| Ok(vec![Buffer::new(device, "scratch", block_size, SCRATCH_USAGE, MemoryLocation::CpuToGpu)?]))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            device: device.clone(),
+            block_size,
+            alignment: limits.min_uniform_buffer_offset_alignment.max(limits.min_storage_buffer_offset_alignment),
+            frames,
+            current_frame: 0,
+            offset: 0,
+        })
+    }
+
+    /// Frees everything allocated the last time `frame_index` was recorded. The GPU must be done with it.
+    pub unsafe fn begin_frame(&mut self, frame_index: usize) -> anyhow::Result<()> {
+        self.current_frame = frame_index;
+        self.offset = 0;
+
+        let blocks = &mut self.frames[frame_index];
+        if blocks.len() > 1 {
+            let size = blocks.iter().map(Buffer::size).sum();
+            debug!("Merging the scratch blocks of frame {} into one of {} bytes", frame_index, size);
+            blocks.clear();
+            blocks.push(Buffer::new(&self.device, "scratch", size, SCRATCH_USAGE, MemoryLocation::CpuToGpu)?);
+        }
+
+        Ok(())
+    }
+
+    /// Copies `data` into this frame's blocks.
+    pub unsafe fn allocate<T: Pod>(&mut self, data: &[T]) -> anyhow::Result<ScratchAllocation> {
+        let size = std::mem::size_of_val(data) as vk::DeviceSize;
+        let alignment = self.alignment.max(std::mem::align_of::<T>() as vk::DeviceSize);
+        let blocks = &mut self.frames[self.current_frame];
+
+        let mut offset = self.offset.next_multiple_of(alignment);
+        if offset + size > blocks.last().expect("every frame has a block").size() {
+            let block_size = self.block_size.max(size);
+            blocks.push(Buffer::new(&self.device, "scratch", block_size, SCRATCH_USAGE, MemoryLocation::CpuToGpu)?);
+            offset = 0;
+        }
+
+        let block = blocks.last_mut().expect("every frame has a block");
+        block.write(offset, data)?;
+        self.offset = offset + size;
+
+        Ok(ScratchAllocation {
+            buffer: block.handle(),
+            offset,
+            size,
+        })
+    }
+
+    /// Bytes allocated this frame so far, including alignment padding, not counting blocks filled before the last.
+    pub fn used(&self) -> vk::DeviceSize {
+        self.offset
+    }
+}
+
+unsafe fn create_storage_buffer<T>(device: &Arc<Device>, name: &str, capacity: usize) -> anyhow::Result<Buffer> {
+    let size = (capacity.max(1) * std::mem::size_of::<T>()) as vk::DeviceSize;
+    Buffer::new(device, name, size, vk::BufferUsageFlags::STORAGE_BUFFER, MemoryLocation::CpuToGpu)
+}
@@ -0,0 +1,186 @@
+use std::sync::Arc;
+use anyhow::anyhow;
+use ash::vk;
+use crate::device::Device;
+use crate::pipeline::DepthState;
+
+struct FramePool {
+    pool: vk::CommandPool,
+    buffers: Vec<vk::CommandBuffer>,
+    used: usize,
+}
+
+/// One command pool per frame in flight. Starting a frame resets that frame's whole pool, so command buffers are
+/// never reset one by one and are reused from frame to frame.
+pub struct FrameCommands {
+    device: Arc<Device>,
+    frames: Vec<FramePool>,
+    current: Option<usize>,
+    main_buffer: vk::CommandBuffer,
+}
+
+impl FrameCommands {
+    pub unsafe fn new(device: &Arc<Device>, queue_family_index: u32, frames_in_flight: usize) -> anyhow::Result<Self> {
+        let mut commands = Self {
+            device: device.clone(),
+            frames: Vec::with_capacity(frames_in_flight),
+            current: None,
+            main_buffer: vk::CommandBuffer::null(),
+        };
+
+        let create_info = vk::CommandPoolCreateInfo::builder()
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+            .queue_family_index(queue_family_index);
+
+        for _ in 0..frames_in_flight {
+            commands.frames.push(FramePool {
+                pool: device.create_command_pool(&create_info, None)?,
+                buffers: Vec::new(),
+                used: 0,
+            });
+        }
+
+        Ok(commands)
+    }
+
+    pub fn frames_in_flight(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Resets the pool of `frame_index` and begins its main primary command buffer. The caller must have waited for
+    /// the GPU to finish the previous submission of this frame.
+    pub unsafe fn begin_frame(&mut self, frame_index: usize) -> anyhow::Result<vk::CommandBuffer> {
+        if self.current.is_some() {
+            return Err(anyhow!("begin_frame called while a frame is already being recorded"));
+        }
+
+        let frame = &mut self.frames[frame_index];
+        self.device.reset_command_pool(frame.pool, vk::CommandPoolResetFlags::empty())?;
+        frame.used = 0;
+        self.current = Some(frame_index);
+
+        self.main_buffer = self.allocate_primary()?;
+        Ok(self.main_buffer)
+    }
+
+    /// Hands out another primary command buffer from the current frame's pool, already begun for one-time submit.
+    pub unsafe fn allocate_primary(&mut self) -> anyhow::Result<vk::CommandBuffer> {
+        let frame_index = self.current.ok_or(anyhow!("No frame is being recorded"))?;
+        let frame = &mut self.frames[frame_index];
+
+        if frame.used == frame.buffers.len() {
+            let allocate_info = vk::CommandBufferAllocateInfo::builder()
+                .command_pool(frame.pool)
+                .level(vk::CommandBufferLevel::PRIMARY)
+                .command_buffer_count(1);
+
+            frame.buffers.extend(self.device.allocate_command_buffers(&allocate_info)?);
+        }
+
+        let buffer = frame.buffers[frame.used];
+        frame.used += 1;
+
+        let begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        self.device.begin_command_buffer(buffer, &begin_info)?;
+
+        Ok(buffer)
+    }
+
+    /// The command buffer returned by [`begin_frame`](Self::begin_frame).
+    pub fn main_buffer(&self) -> vk::CommandBuffer {
+        self.main_buffer
+    }
+
+    /// Ends the main command buffer of the current frame and returns it for submission. Extra buffers from
+    /// [`allocate_primary`](Self::allocate_primary) must be ended by whoever recorded them.
+    pub unsafe fn end_frame(&mut self) -> anyhow::Result<vk::CommandBuffer> {
+        self.current.take().ok_or(anyhow!("end_frame called without begin_frame"))?;
+        self.device.end_command_buffer(self.main_buffer)?;
+        Ok(self.main_buffer)
+    }
+}
+
+impl Drop for FrameCommands {
+    fn drop(&mut self) {
+        unsafe {
+            for frame in &self.frames {
+                self.device.destroy_command_pool(frame.pool, None);
+            }
+        }
+    }
+}
+
+/// Records commands with `record` into a temporary command buffer, submits it to `queue` and blocks until it has
+/// finished. Meant for uploads and other setup work outside the frame loop.
+pub unsafe fn submit_one_time<F>(device: &Device, queue_family_index: u32, queue: vk::Queue, record: F) -> anyhow::Result<()>
+where
+    F: FnOnce(vk::CommandBuffer),
+{
+    let create_info = vk::CommandPoolCreateInfo::builder()
+        .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+        .queue_family_index(queue_family_index);
+    let pool = device.create_command_pool(&create_info, None)?;
+
+    let result = (|| -> anyhow::Result<()> {
+        let allocate_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+        let buffer = device.allocate_command_buffers(&allocate_info)?[0];
+
+        let begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        device.begin_command_buffer(buffer, &begin_info)?;
+        record(buffer);
+        device.end_command_buffer(buffer)?;
+
+        let fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+        let buffers = [buffer];
+        let submit_info = vk::SubmitInfo::builder().command_buffers(&buffers).build();
+
+        let submitted = device.queue_submit(queue, &[submit_info], fence)
+            .and_then(|_| device.wait_for_fences(&[fence], true, u64::MAX));
+        device.destroy_fence(fence, None);
+
+        Ok(submitted?)
+    })();
+
+    device.destroy_command_pool(pool, None);
+    result
+}
+
+/// The states a pipeline built with `GraphicsPipelineBuilder::extended_dynamic_state` leaves to the command buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct ExtendedDynamicState {
+    pub cull_mode: vk::CullModeFlags,
+    pub front_face: vk::FrontFace,
+    pub topology: vk::PrimitiveTopology,
+    pub depth: DepthState,
+}
+
+impl Default for ExtendedDynamicState {
+    fn default() -> Self {
+        Self {
+            cull_mode: vk::CullModeFlags::BACK,
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            depth: DepthState::READ_WRITE,
+        }
+    }
+}
+
+/// Sets `state` for the following draws. Does nothing on devices without Vulkan 1.3, where such pipelines were built
+/// with their static state instead.
+pub unsafe fn set_extended_dynamic_state(device: &Device, command_buffer: vk::CommandBuffer, state: &ExtendedDynamicState) {
+    if !device.supports_extended_dynamic_state() {
+        return;
+    }
+
+    device.cmd_set_cull_mode(command_buffer, state.cull_mode);
+    device.cmd_set_front_face(command_buffer, state.front_face);
+    device.cmd_set_primitive_topology(command_buffer, state.topology);
+    device.cmd_set_depth_test_enable(command_buffer, state.depth.test);
+    device.cmd_set_depth_write_enable(command_buffer, state.depth.write);
+    device.cmd_set_depth_compare_op(command_buffer, state.depth.compare_op);
+}
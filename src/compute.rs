@@ -0,0 +1,199 @@
+use std::sync::Arc;
+use anyhow::anyhow;
+use ash::vk;
+use bytemuck::Pod;
+use crate::device::Device;
+use crate::pipeline::{create_pipeline_layout, push_constants, ShaderStage};
+use crate::shader::ShaderModule;
+
+#[derive(Default)]
+pub struct ComputePipelineBuilder {
+    stage: Option<ShaderStage>,
+    set_layouts: Vec<vk::DescriptorSetLayout>,
+    push_constant_ranges: Vec<vk::PushConstantRange>,
+}
+
+impl ComputePipelineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stage(mut self, stage: ShaderStage) -> Self {
+        self.stage = Some(stage);
+        self
+    }
+
+    pub fn shader(self, module: &ShaderModule) -> Self {
+        self.stage(module.stage_info())
+    }
+
+    pub fn descriptor_set_layout(mut self, layout: vk::DescriptorSetLayout) -> Self {
+        self.set_layouts.push(layout);
+        self
+    }
+
+    /// Declares a push constant range holding a `T` at `offset`.
+    pub fn push_constants<T: Pod>(mut self, offset: u32) -> Self {
+        self.push_constant_ranges.push(vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset,
+            size: std::mem::size_of::<T>() as u32,
+        });
+        self
+    }
+
+    pub unsafe fn build(&self, device: &Arc<Device>) -> anyhow::Result<ComputePipeline> {
+        let stage = self.stage.as_ref().ok_or(anyhow!("Compute pipeline has no shader"))?;
+        if stage.stage != vk::ShaderStageFlags::COMPUTE {
+            return Err(anyhow!("Compute pipeline was given a {:?} shader", stage.stage));
+        }
+
+        let layout = create_pipeline_layout(device, &self.set_layouts, &self.push_constant_ranges)?;
+
+        let stage_info = vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::COMPUTE)
+            .module(stage.module)
+            .name(&stage.entry_point)
+            .build();
+
+        let create_info = vk::ComputePipelineCreateInfo::builder()
+            .stage(stage_info)
+            .layout(layout)
+            .build();
+
+        let handle = match device.create_compute_pipelines(device.pipeline_cache(), &[create_info], None) {
+            Ok(pipelines) => pipelines[0],
+            Err((_, err)) => {
+                device.destroy_pipeline_layout(layout, None);
+                return Err(err.into());
+            }
+        };
+
+        Ok(ComputePipeline {
+            device: device.clone(),
+            handle,
+            layout,
+            push_constant_ranges: self.push_constant_ranges.clone(),
+        })
+    }
+}
+
+pub struct ComputePipeline {
+    device: Arc<Device>,
+    handle: vk::Pipeline,
+    layout: vk::PipelineLayout,
+    push_constant_ranges: Vec<vk::PushConstantRange>,
+}
+
+impl ComputePipeline {
+    pub fn handle(&self) -> vk::Pipeline {
+        self.handle
+    }
+
+    pub fn layout(&self) -> vk::PipelineLayout {
+        self.layout
+    }
+
+    pub unsafe fn bind(&self, command_buffer: vk::CommandBuffer) {
+        self.device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.handle);
+    }
+
+    pub unsafe fn bind_descriptor_sets(&self, command_buffer: vk::CommandBuffer, first_set: u32, sets: &[vk::DescriptorSet]) {
+        self.device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE, self.layout, first_set, sets, &[]);
+    }
+
+    pub unsafe fn push_constants<T: Pod>(&self, command_buffer: vk::CommandBuffer, offset: u32, value: &T) {
+        push_constants(&self.device, command_buffer, self.layout, &self.push_constant_ranges, vk::ShaderStageFlags::COMPUTE, offset, value);
+    }
+
+    pub unsafe fn dispatch(&self, command_buffer: vk::CommandBuffer, x: u32, y: u32, z: u32) {
+        self.device.cmd_dispatch(command_buffer, x, y, z);
+    }
+
+    /// Dispatches enough `local_size` workgroups to cover `width * height` invocations.
+    pub unsafe fn dispatch_2d(&self, command_buffer: vk::CommandBuffer, width: u32, height: u32, local_size: [u32; 2]) {
+        self.dispatch(command_buffer, width.div_ceil(local_size[0]), height.div_ceil(local_size[1]), 1);
+    }
+
+    pub unsafe fn dispatch_indirect(&self, command_buffer: vk::CommandBuffer, buffer: vk::Buffer, offset: vk::DeviceSize) {
+        self.device.cmd_dispatch_indirect(command_buffer, buffer, offset);
+    }
+}
+
+impl Drop for ComputePipeline {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_pipeline(self.handle, None);
+            self.device.destroy_pipeline_layout(self.layout, None);
+        }
+    }
+}
+
+/// Stage and access pair on one side of a barrier.
+#[derive(Debug, Clone, Copy)]
+pub struct Access {
+    pub stage: vk::PipelineStageFlags,
+    pub access: vk::AccessFlags,
+}
+
+impl Access {
+    pub const COMPUTE_READ: Self = Self {
+        stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+        access: vk::AccessFlags::SHADER_READ,
+    };
+
+    pub const COMPUTE_WRITE: Self = Self {
+        stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+        access: vk::AccessFlags::SHADER_WRITE,
+    };
+
+    pub const VERTEX_INPUT: Self = Self {
+        stage: vk::PipelineStageFlags::VERTEX_INPUT,
+        access: vk::AccessFlags::from_raw(vk::AccessFlags::VERTEX_ATTRIBUTE_READ.as_raw() | vk::AccessFlags::INDEX_READ.as_raw()),
+    };
+
+    pub const INDIRECT: Self = Self {
+        stage: vk::PipelineStageFlags::DRAW_INDIRECT,
+        access: vk::AccessFlags::INDIRECT_COMMAND_READ,
+    };
+
+    pub const FRAGMENT_READ: Self = Self {
+        stage: vk::PipelineStageFlags::FRAGMENT_SHADER,
+        access: vk::AccessFlags::SHADER_READ,
+    };
+
+    pub const TRANSFER_WRITE: Self = Self {
+        stage: vk::PipelineStageFlags::TRANSFER,
+        access: vk::AccessFlags::TRANSFER_WRITE,
+    };
+
+    pub const TRANSFER_READ: Self = Self {
+        stage: vk::PipelineStageFlags::TRANSFER,
+        access: vk::AccessFlags::TRANSFER_READ,
+    };
+}
+
+/// Global memory barrier, e.g. between a compute pass writing a storage buffer and a draw reading it.
+pub unsafe fn memory_barrier(device: &Device, command_buffer: vk::CommandBuffer, src: Access, dst: Access) {
+    let barrier = vk::MemoryBarrier::builder()
+        .src_access_mask(src.access)
+        .dst_access_mask(dst.access)
+        .build();
+
+    device.cmd_pipeline_barrier(command_buffer, src.stage, dst.stage, vk::DependencyFlags::empty(), &[barrier], &[], &[]);
+}
+
+/// Barrier limited to the whole of `buffer`.
+pub unsafe fn buffer_barrier(device: &Device, command_buffer: vk::CommandBuffer, buffer: vk::Buffer, src: Access, dst: Access) {
+    let barrier = vk::BufferMemoryBarrier::builder()
+        .src_access_mask(src.access)
+        .dst_access_mask(dst.access)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .buffer(buffer)
+        .offset(0)
+        .size(vk::WHOLE_SIZE)
+        .build();
+
+    device.cmd_pipeline_barrier(command_buffer, src.stage, dst.stage, vk::DependencyFlags::empty(), &[], &[barrier], &[]);
+}
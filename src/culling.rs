@@ -0,0 +1,141 @@
+use cgmath::{EuclideanSpace, InnerSpace, Matrix, Matrix4, Point3, Vector3, Vector4};
+
+/// An axis aligned bounding box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: Point3<f32>, max: Point3<f32>) -> Self {
+        Self { min, max }
+    }
+
+    /// The smallest box around `points`, or `None` when there are none.
+    pub fn from_points(points: impl IntoIterator<Item = Point3<f32>>) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = points.next()?;
+
+        Some(points.fold(Self::new(first, first), |aabb, point| Self {
+            min: Point3::new(aabb.min.x.min(point.x), aabb.min.y.min(point.y), aabb.min.z.min(point.z)),
+            max: Point3::new(aabb.max.x.max(point.x), aabb.max.y.max(point.y), aabb.max.z.max(point.z)),
+        }))
+    }
+
+    pub fn center(&self) -> Point3<f32> {
+        self.min.midpoint(self.max)
+    }
+
+    /// Half the size along each axis.
+    pub fn half_extents(&self) -> Vector3<f32> {
+        (self.max - self.min) * 0.5
+    }
+
+    /// The box around this one moved by `transform`.
+    pub fn transform(&self, transform: &Matrix4<f32>) -> Self {
+        let center = Point3::from_homogeneous(transform * self.center().to_homogeneous());
+        let half_extents = self.half_extents();
+        let extent = |row: usize| {
+            let row = transform.row(row).truncate();
+            row.x.abs() * half_extents.x + row.y.abs() * half_extents.y + row.z.abs() * half_extents.z
+        };
+        let half_extents = Vector3::new(extent(0), extent(1), extent(2));
+
+        Self::new(center - half_extents, center + half_extents)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Point3<f32>,
+    pub radius: f32,
+}
+
+impl BoundingSphere {
+    pub fn new(center: Point3<f32>, radius: f32) -> Self {
+        Self { center, radius }
+    }
+
+    /// A sphere around `points` centered on their bounding box, which is close to the smallest one for most meshes.
+    pub fn from_points(points: impl IntoIterator<Item = Point3<f32>> + Clone) -> Option<Self> {
+        let center = Aabb::from_points(points.clone())?.center();
+        let radius = points.into_iter().fold(0.0f32, |radius, point| radius.max((point - center).magnitude()));
+        Some(Self::new(center, radius))
+    }
+
+    /// The sphere moved by `transform` and grown by its largest scale, so it still bounds what this one did.
+    pub fn transform(&self, transform: &Matrix4<f32>) -> Self {
+        let center = Point3::from_homogeneous(transform * self.center.to_homogeneous());
+        let scale = [transform.x, transform.y, transform.z].iter()
+            .map(|column| column.truncate().magnitude())
+            .fold(0.0f32, f32::max);
+
+        Self::new(center, self.radius * scale)
+    }
+
+    /// Center, then radius, as the shaders take it.
+    pub fn to_vec4(&self) -> [f32; 4] {
+        [self.center.x, self.center.y, self.center.z, self.radius]
+    }
+}
+
+/// The six planes of a camera's view volume, with normalized normals pointing inwards.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frustum {
+    planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    /// The frustum of `view_projection`, for Vulkan's 0 to 1 depth range.
+    pub fn from_view_projection(view_projection: Matrix4<f32>) -> Self {
+        let rows: [Vector4<f32>; 4] = std::array::from_fn(|row| view_projection.row(row));
+        let planes = [
+            rows[3] + rows[0],
+            rows[3] - rows[0],
+            rows[3] + rows[1],
+            rows[3] - rows[1],
+            rows[2],
+            rows[3] - rows[2],
+        ];
+
+        Self {
+            planes: planes.map(|plane| plane / plane.truncate().magnitude()),
+        }
+    }
+
+    /// Left, right, bottom, top, near and far, as a normal and the distance along it.
+    pub fn planes(&self) -> [[f32; 4]; 6] {
+        self.planes.map(Into::into)
+    }
+
+    pub fn intersects_sphere(&self, sphere: &BoundingSphere) -> bool {
+        self.planes.iter().all(|plane| plane.truncate().dot(sphere.center.to_vec()) + plane.w >= -sphere.radius)
+    }
+
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        // Tests the corner furthest along each plane's normal.
+        self.planes.iter().all(|plane| {
+            let corner = Vector3::new(
+                if plane.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                if plane.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                if plane.z >= 0.0 { aabb.max.z } else { aabb.min.z },
+            );
+
+            plane.truncate().dot(corner) + plane.w >= 0.0
+        })
+    }
+}
+
+/// What frustum culling did to a frame's objects.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CullingStats {
+    /// Instances queued for the frame.
+    pub objects: usize,
+    /// Instances outside the camera's view. Opaque ones are still drawn into the shadow maps.
+    pub culled: usize,
+    /// Instances submitted to the main pass.
+    pub submitted: usize,
+    /// Draw calls the submitted instances took.
+    pub draw_calls: usize,
+}
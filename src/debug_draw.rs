@@ -0,0 +1,302 @@
+use std::f32::consts::TAU;
+use std::mem::offset_of;
+use std::sync::Arc;
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use cgmath::{Matrix4, Point3, SquareMatrix, Vector2, Vector3, Vector4};
+use log::debug;
+use crate::allocator::MemoryLocation;
+use crate::buffer::Buffer;
+use crate::culling::{Aabb, BoundingSphere};
+use crate::device::Device;
+use crate::glsl::GlslCompiler;
+use crate::pipeline::{BlendMode, DepthState, GraphicsPipeline, GraphicsPipelineBuilder, PipelineTarget, Vertex, VertexAttribute};
+use crate::renderer2d::Renderer2d;
+use crate::renderer3d::Camera;
+use crate::shader::ShaderModule;
+use crate::text::{Font, TextStyle};
+
+const DEBUG_LINE_VERT: &str = include_str!("../shaders/debug_line.vert");
+const DEBUG_LINE_FRAG: &str = include_str!("../shaders/debug_line.frag");
+
+/// Vertices each frame's buffer has room for before it first grows.
+const INITIAL_VERTEX_CAPACITY: usize = 4096;
+
+/// Segments of every circle of a sphere.
+const CIRCLE_SEGMENTS: usize = 32;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DebugVertex {
+    pub position: [f32; 3],
+    pub color: [f32; 4],
+}
+
+unsafe impl Zeroable for DebugVertex {}
+unsafe impl Pod for DebugVertex {}
+
+impl Vertex for DebugVertex {
+    fn attributes() -> Vec<VertexAttribute> {
+        vec![
+            VertexAttribute {
+                location: 0,
+                format: vk::Format::R32G32B32_SFLOAT,
+                offset: offset_of!(DebugVertex, position) as u32,
+            },
+            VertexAttribute {
+                location: 1,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                offset: offset_of!(DebugVertex, color) as u32,
+            },
+        ]
+    }
+}
+
+struct DebugLabel {
+    position: Point3<f32>,
+    text: String,
+    color: [f32; 4],
+}
+
+/// Unlit lines and labels queued from anywhere during a frame, e.g. to see physics shapes or culling volumes, and
+/// drawn once after everything else. Shapes are depth tested against the scene unless overlay mode is on, which
+/// draws them on top. Everything queued is dropped at the end of the frame.
+pub struct DebugDraw {
+    device: Arc<Device>,
+    depth_tested: GraphicsPipeline,
+    overlay: GraphicsPipeline,
+    lines: Vec<DebugVertex>,
+    overlay_lines: Vec<DebugVertex>,
+    labels: Vec<DebugLabel>,
+    overlay_mode: bool,
+    /// One per frame in flight, holding the depth tested lines followed by the overlay ones.
+    buffers: Vec<Buffer>,
+}
+
+impl DebugDraw {
+    /// The pipelines read the camera from set 0, binding 0 of `frame_layout`.
+    pub unsafe fn new(
+        device: &Arc<Device>,
+        compiler: &GlslCompiler,
+        target: &PipelineTarget,
+        frame_layout: vk::DescriptorSetLayout,
+        frames_in_flight: usize,
+    ) -> anyhow::Result<Self> {
+        let vertex = ShaderModule::from_bytes_with_stage(
+            device,
+            "debug_line.vert",
+            &compiler.compile_source(DEBUG_LINE_VERT, vk::ShaderStageFlags::VERTEX, "debug_line.vert")?,
+            vk::ShaderStageFlags::VERTEX,
+        )?;
+        let fragment = ShaderModule::from_bytes_with_stage(
+            device,
+            "debug_line.frag",
+            &compiler.compile_source(DEBUG_LINE_FRAG, vk::ShaderStageFlags::FRAGMENT, "debug_line.frag")?,
+            vk::ShaderStageFlags::FRAGMENT,
+        )?;
+
+        let builder = GraphicsPipelineBuilder::new()
+            .shader(&vertex)
+            .shader(&fragment)
+            .vertex::<DebugVertex>(0)
+            .topology(vk::PrimitiveTopology::LINE_LIST)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .blend(BlendMode::Alpha)
+            .descriptor_set_layout(frame_layout)
+            .target(target.clone())
+            .depth(DepthState::READ_ONLY);
+        let depth_tested = builder.build(device)?;
+        let overlay = builder.depth(DepthState::DISABLED).build(device)?;
+
+        let buffers = (0..frames_in_flight)
+            .map(|_| create_vertex_buffer(device, INITIAL_VERTEX_CAPACITY))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            device: device.clone(),
+            depth_tested,
+            overlay,
+            lines: Vec::new(),
+            overlay_lines: Vec::new(),
+            labels: Vec::new(),
+            overlay_mode: false,
+            buffers,
+        })
+    }
+
+    pub fn overlay_mode(&self) -> bool {
+        self.overlay_mode
+    }
+
+    /// Whether shapes queued from now on are drawn on top of the scene instead of being hidden behind it.
+    pub fn set_overlay_mode(&mut self, overlay_mode: bool) {
+        self.overlay_mode = overlay_mode;
+    }
+
+    pub fn line(&mut self, from: Point3<f32>, to: Point3<f32>, color: [f32; 4]) {
+        let lines = if self.overlay_mode { &mut self.overlay_lines } else { &mut self.lines };
+        lines.extend([from, to].map(|position| DebugVertex {
+            position: position.into(),
+            color,
+        }));
+    }
+
+    /// The edges of the unit cube centered on the origin, placed by `transform`, for oriented boxes.
+    pub fn cube(&mut self, transform: Matrix4<f32>, color: [f32; 4]) {
+        let corners = box_corners(Point3::new(-0.5, -0.5, -0.5), Point3::new(0.5, 0.5, 0.5))
+            .map(|corner| Point3::from_homogeneous(transform * corner.to_homogeneous()));
+        self.box_edges(&corners, color);
+    }
+
+    pub fn aabb(&mut self, aabb: &Aabb, color: [f32; 4]) {
+        self.box_edges(&box_corners(aabb.min, aabb.max), color);
+    }
+
+    /// A circle around each axis.
+    pub fn sphere(&mut self, sphere: &BoundingSphere, color: [f32; 4]) {
+        let axes = [(Vector3::unit_x(), Vector3::unit_y()), (Vector3::unit_y(), Vector3::unit_z()), (Vector3::unit_z(), Vector3::unit_x())];
+        for (u, v) in axes {
+            let point = |segment: usize| {
+                let angle = segment as f32 / CIRCLE_SEGMENTS as f32 * TAU;
+                sphere.center + (u * angle.cos() + v * angle.sin()) * sphere.radius
+            };
+
+            for segment in 0..CIRCLE_SEGMENTS {
+                self.line(point(segment), point(segment + 1), color);
+            }
+        }
+    }
+
+    /// The x, y and z axes of `transform` in red, green and blue, `size` long before scaling.
+    pub fn axis(&mut self, transform: Matrix4<f32>, size: f32) {
+        let origin = Point3::from_homogeneous(transform * Vector4::new(0.0, 0.0, 0.0, 1.0));
+        let axes = [
+            (Vector3::unit_x(), [1.0, 0.0, 0.0, 1.0]),
+            (Vector3::unit_y(), [0.0, 1.0, 0.0, 1.0]),
+            (Vector3::unit_z(), [0.0, 0.0, 1.0, 1.0]),
+        ];
+
+        for (axis, color) in axes {
+            let end = Point3::from_homogeneous(transform * (axis * size).extend(1.0));
+            self.line(origin, end, color);
+        }
+    }
+
+    /// The edges of the volume `view_projection` maps to Vulkan's clip space, e.g. of a camera or a shadow cascade.
+    pub fn frustum(&mut self, view_projection: Matrix4<f32>, color: [f32; 4]) {
+        let Some(inverse) = view_projection.invert() else {
+            return;
+        };
+
+        let corners = box_corners(Point3::new(-1.0, -1.0, 0.0), Point3::new(1.0, 1.0, 1.0))
+            .map(|corner| Point3::from_homogeneous(inverse * corner.to_homogeneous()));
+        self.box_edges(&corners, color);
+    }
+
+    /// Text at `position` in the world, drawn by [`draw_labels`](Self::draw_labels). Labels are always on top.
+    pub fn text3d(&mut self, position: Point3<f32>, text: &str, color: [f32; 4]) {
+        self.labels.push(DebugLabel {
+            position,
+            text: text.to_owned(),
+            color,
+        });
+    }
+
+    /// Lines queued this frame, whether depth tested or not.
+    pub fn queued_lines(&self) -> usize {
+        (self.lines.len() + self.overlay_lines.len()) / 2
+    }
+
+    /// Queues this frame's labels on `renderer`, centered on where `camera` sees their positions on a screen of
+    /// `extent`. `renderer` must draw in screen pixels, without a camera. Labels behind the camera are left out.
+    pub unsafe fn draw_labels(
+        &self,
+        renderer: &mut Renderer2d,
+        font: &mut Font,
+        camera: &Camera,
+        extent: vk::Extent2D,
+        size: f32,
+    ) -> anyhow::Result<()> {
+        let view_projection = camera.projection(extent) * camera.view();
+        for label in &self.labels {
+            let clip = view_projection * label.position.to_homogeneous();
+            if clip.w <= 0.0 {
+                continue;
+            }
+
+            let style = TextStyle::new(size).with_color(label.color);
+            let screen = Vector2::new(
+                (clip.x / clip.w + 1.0) * 0.5 * extent.width as f32,
+                (clip.y / clip.w + 1.0) * 0.5 * extent.height as f32,
+            );
+            font.draw_text(renderer, &label.text, screen - font.measure(&label.text, &style) * 0.5, &style)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes this frame's lines into the vertex buffer of `frame_index`, which grows when they don't fit.
+    pub unsafe fn prepare(&mut self, frame_index: usize) -> anyhow::Result<()> {
+        let count = self.lines.len() + self.overlay_lines.len();
+        if count * std::mem::size_of::<DebugVertex>() > self.buffers[frame_index].size() as usize {
+            let capacity = count.next_power_of_two();
+            debug!("Growing the debug line buffer of frame {} to {} vertices", frame_index, capacity);
+            self.buffers[frame_index] = create_vertex_buffer(&self.device, capacity)?;
+        }
+
+        let buffer = &mut self.buffers[frame_index];
+        buffer.write(0, &self.lines)?;
+        buffer.write(std::mem::size_of_val(self.lines.as_slice()) as vk::DeviceSize, &self.overlay_lines)
+    }
+
+    /// Draws the lines `prepare` wrote into the current pass, which must have a depth attachment if any are depth
+    /// tested.
+    pub unsafe fn record(&self, command_buffer: vk::CommandBuffer, frame_set: vk::DescriptorSet, frame_index: usize) {
+        self.device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.buffers[frame_index].handle()], &[0]);
+
+        let runs = [(&self.depth_tested, 0, self.lines.len()), (&self.overlay, self.lines.len(), self.overlay_lines.len())];
+        for (pipeline, first, count) in runs {
+            if count == 0 {
+                continue;
+            }
+
+            pipeline.bind(command_buffer);
+            self.device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.layout(), 0, &[frame_set], &[]);
+            self.device.cmd_draw(command_buffer, count as u32, 1, first as u32, 0);
+        }
+    }
+
+    /// Drops everything queued this frame.
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.overlay_lines.clear();
+        self.labels.clear();
+    }
+
+    /// The edges between `corners` as [`box_corners`] orders them.
+    fn box_edges(&mut self, corners: &[Point3<f32>; 8], color: [f32; 4]) {
+        const EDGES: [(usize, usize); 12] = [
+            (0, 1), (1, 3), (3, 2), (2, 0),
+            (4, 5), (5, 7), (7, 6), (6, 4),
+            (0, 4), (1, 5), (2, 6), (3, 7),
+        ];
+
+        for (a, b) in EDGES {
+            self.line(corners[a], corners[b], color);
+        }
+    }
+}
+
+/// The corners of the box from `min` to `max`, with bit 0 of the index picking x, bit 1 y and bit 2 z.
+fn box_corners(min: Point3<f32>, max: Point3<f32>) -> [Point3<f32>; 8] {
+    std::array::from_fn(|corner| Point3::new(
+        if corner & 1 == 0 { min.x } else { max.x },
+        if corner & 2 == 0 { min.y } else { max.y },
+        if corner & 4 == 0 { min.z } else { max.z },
+    ))
+}
+
+unsafe fn create_vertex_buffer(device: &Arc<Device>, capacity: usize) -> anyhow::Result<Buffer> {
+    let size = (capacity * std::mem::size_of::<DebugVertex>()) as vk::DeviceSize;
+    Buffer::new(device, "debug lines", size, vk::BufferUsageFlags::VERTEX_BUFFER, MemoryLocation::CpuToGpu)
+}
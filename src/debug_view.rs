@@ -0,0 +1,116 @@
+use std::sync::Arc;
+use ash::vk;
+use crate::device::Device;
+use crate::glsl::GlslCompiler;
+use crate::pipeline::{BlendMode, DepthState, GraphicsPipeline, GraphicsPipelineBuilder};
+use crate::requirements::Feature;
+use crate::shader::ShaderModule;
+
+const DEBUG_FLAT_FRAG: &str = include_str!("../shaders/debug_flat.frag");
+
+/// What a [`Renderer3d`](crate::renderer3d::Renderer3d) shows in place of the lit scene. Normals, albedo, shadow
+/// cascades and light complexity are shaded by the materials themselves, through `lighting.glsl`; wireframe and
+/// overdraw draw every static mesh with a flat color instead of its material and leave out skinned meshes and the
+/// skybox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DebugView {
+    #[default]
+    Lit,
+    /// Triangle edges only. Needs the `fillModeNonSolid` feature.
+    Wireframe,
+    /// World space normals, mapped to 0 to 1.
+    Normals,
+    /// Base color without lighting.
+    Albedo,
+    /// Brighter the more fragments land on a pixel, whether or not they're hidden.
+    Overdraw,
+    /// Albedo tinted red, green, blue and yellow for cascades 0 to 3.
+    ShadowCascades,
+    /// How many lights the cluster of each fragment holds, from blue for none to red for 16 or more.
+    LightComplexity,
+}
+
+impl DebugView {
+    pub const ALL: [Self; 7] = [
+        Self::Lit,
+        Self::Wireframe,
+        Self::Normals,
+        Self::Albedo,
+        Self::Overdraw,
+        Self::ShadowCascades,
+        Self::LightComplexity,
+    ];
+
+    /// The view after this one in [`ALL`](Self::ALL), wrapping around to lit.
+    pub fn next(self) -> Self {
+        let index = Self::ALL.iter().position(|&view| view == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+
+    /// What `lighting.glsl` switches on, in `CameraUniform::debug`. 0 for the views it shades lit.
+    pub fn shader_mode(self) -> u32 {
+        match self {
+            Self::Lit | Self::Wireframe | Self::Overdraw => 0,
+            view => view as u32,
+        }
+    }
+}
+
+/// Flat colored pipelines replacing the materials of static meshes in the wireframe and overdraw views. They only
+/// bind set 0, and take their color as a fragment push constant.
+pub struct DebugViewPipelines {
+    /// `None` without `fillModeNonSolid`.
+    wireframe: Option<GraphicsPipeline>,
+    overdraw: GraphicsPipeline,
+}
+
+impl DebugViewPipelines {
+    /// `base` has the vertex layouts, set 0 and target of the static mesh pipelines, and `vertex_shader` their
+    /// `mesh.vert`.
+    pub unsafe fn new(device: &Arc<Device>, compiler: &GlslCompiler, base: GraphicsPipelineBuilder, vertex_shader: &str) -> anyhow::Result<Self> {
+        let vertex = ShaderModule::from_bytes_with_stage(
+            device,
+            "mesh.vert",
+            &compiler.compile_source(vertex_shader, vk::ShaderStageFlags::VERTEX, "mesh.vert")?,
+            vk::ShaderStageFlags::VERTEX,
+        )?;
+        let fragment = ShaderModule::from_bytes_with_stage(
+            device,
+            "debug_flat.frag",
+            &compiler.compile_source(DEBUG_FLAT_FRAG, vk::ShaderStageFlags::FRAGMENT, "debug_flat.frag")?,
+            vk::ShaderStageFlags::FRAGMENT,
+        )?;
+        let builder = base
+            .shader(&vertex)
+            .shader(&fragment)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .push_constants::<[f32; 4]>(vk::ShaderStageFlags::FRAGMENT, 0)
+            .polygon_mode(vk::PolygonMode::LINE);
+
+        let wireframe = match device.capabilities().has_feature(Feature::FillModeNonSolid) {
+            true => Some(builder.build(device)?),
+            false => None,
+        };
+
+        let overdraw = builder
+            .polygon_mode(vk::PolygonMode::FILL)
+            .depth(DepthState::DISABLED)
+            .blend(BlendMode::Additive)
+            .build(device)?;
+
+        Ok(Self { wireframe, overdraw })
+    }
+
+    pub fn supports(&self, view: DebugView) -> bool {
+        view != DebugView::Wireframe || self.wireframe.is_some()
+    }
+
+    /// The pipeline `view` draws static meshes with, and the color to push to it.
+    pub fn pipeline(&self, view: DebugView) -> Option<(&GraphicsPipeline, [f32; 4])> {
+        match view {
+            DebugView::Wireframe => Some((self.wireframe.as_ref()?, [0.9, 0.9, 0.9, 1.0])),
+            DebugView::Overdraw => Some((&self.overdraw, [0.1, 0.04, 0.02, 1.0])),
+            _ => None,
+        }
+    }
+}
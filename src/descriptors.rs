@@ -0,0 +1,431 @@
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::sync::Arc;
+use ash::vk;
+use thiserror::Error;
+use crate::device::Device;
+use crate::reflect::ShaderReflection;
+
+const INITIAL_SETS_PER_POOL: u32 = 64;
+const MAX_SETS_PER_POOL: u32 = 4096;
+
+/// Descriptors per set reserved for each type when a pool is created.
+const POOL_RATIOS: [(vk::DescriptorType, f32); 7] = [
+    (vk::DescriptorType::UNIFORM_BUFFER, 2.0),
+    (vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC, 1.0),
+    (vk::DescriptorType::STORAGE_BUFFER, 2.0),
+    (vk::DescriptorType::STORAGE_BUFFER_DYNAMIC, 0.5),
+    (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 4.0),
+    (vk::DescriptorType::SAMPLED_IMAGE, 1.0),
+    (vk::DescriptorType::STORAGE_IMAGE, 1.0),
+];
+
+#[derive(Error, Debug)]
+pub enum LayoutError {
+    #[error("Binding {binding} of set {set} is {first:?} in one stage and {second:?} in another")]
+    ConflictingBinding {
+        set: u32,
+        binding: u32,
+        first: vk::DescriptorType,
+        second: vk::DescriptorType,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LayoutBinding {
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    pub count: u32,
+    pub stages: vk::ShaderStageFlags,
+    pub flags: vk::DescriptorBindingFlags,
+}
+
+/// Declarative description of a descriptor set layout, used as the key of the layout cache.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct SetLayoutDesc {
+    bindings: Vec<LayoutBinding>,
+}
+
+impl SetLayoutDesc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn binding(self, binding: u32, descriptor_type: vk::DescriptorType, stages: vk::ShaderStageFlags) -> Self {
+        self.array(binding, descriptor_type, 1, stages)
+    }
+
+    pub fn array(self, binding: u32, descriptor_type: vk::DescriptorType, count: u32, stages: vk::ShaderStageFlags) -> Self {
+        self.binding_with_flags(binding, descriptor_type, count, stages, vk::DescriptorBindingFlags::empty())
+    }
+
+    /// An array of up to `max_count` descriptors that sets choose the size of when they are allocated, may leave
+    /// partly unwritten and can be written while bound. It must be the set's last binding, and sets of the layout
+    /// must come from pools created with `UPDATE_AFTER_BIND`. Needs the descriptor indexing features.
+    pub fn bindless_array(self, binding: u32, descriptor_type: vk::DescriptorType, max_count: u32, stages: vk::ShaderStageFlags) -> Self {
+        let flags = vk::DescriptorBindingFlags::PARTIALLY_BOUND
+            | vk::DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT
+            | vk::DescriptorBindingFlags::UPDATE_AFTER_BIND;
+        self.binding_with_flags(binding, descriptor_type, max_count, stages, flags)
+    }
+
+    fn binding_with_flags(
+        mut self,
+        binding: u32,
+        descriptor_type: vk::DescriptorType,
+        count: u32,
+        stages: vk::ShaderStageFlags,
+        flags: vk::DescriptorBindingFlags,
+    ) -> Self {
+        self.bindings.retain(|existing| existing.binding != binding);
+        self.bindings.push(LayoutBinding {
+            binding,
+            descriptor_type,
+            count,
+            stages,
+            flags,
+        });
+        self.bindings.sort_by_key(|binding| binding.binding);
+        self
+    }
+
+    /// Collects the bindings of `set` from the reflection of every stage, merging stage flags of bindings that
+    /// appear in several of them. Fails when stages disagree on a binding's type.
+    pub fn from_reflection(reflections: &[&ShaderReflection], set: u32) -> Result<Self, LayoutError> {
+        let mut desc = Self::new();
+
+        for binding in reflections.iter().flat_map(|reflection| &reflection.descriptor_bindings) {
+            if binding.set != set {
+                continue;
+            }
+
+            let existing = desc.bindings.iter().find(|existing| existing.binding == binding.binding);
+            if let Some(existing) = existing.filter(|existing| existing.descriptor_type != binding.descriptor_type) {
+                return Err(LayoutError::ConflictingBinding {
+                    set,
+                    binding: binding.binding,
+                    first: existing.descriptor_type,
+                    second: binding.descriptor_type,
+                });
+            }
+
+            let stages = existing.map_or(binding.stage, |existing| existing.stages | binding.stage);
+            desc = desc.array(binding.binding, binding.descriptor_type, binding.count, stages);
+        }
+
+        Ok(desc)
+    }
+
+    pub fn bindings(&self) -> &[LayoutBinding] {
+        &self.bindings
+    }
+}
+
+/// Creates each distinct set layout once and hands out the same handle for identical descriptions.
+pub struct DescriptorLayoutCache {
+    device: Arc<Device>,
+    layouts: HashMap<SetLayoutDesc, vk::DescriptorSetLayout>,
+}
+
+impl DescriptorLayoutCache {
+    pub fn new(device: &Arc<Device>) -> Self {
+        Self {
+            device: device.clone(),
+            layouts: HashMap::new(),
+        }
+    }
+
+    pub unsafe fn get(&mut self, desc: &SetLayoutDesc) -> anyhow::Result<vk::DescriptorSetLayout> {
+        if let Some(&layout) = self.layouts.get(desc) {
+            return Ok(layout);
+        }
+
+        let bindings: Vec<vk::DescriptorSetLayoutBinding> = desc.bindings.iter()
+            .map(|binding| vk::DescriptorSetLayoutBinding::builder()
+                .binding(binding.binding)
+                .descriptor_type(binding.descriptor_type)
+                .descriptor_count(binding.count)
+                .stage_flags(binding.stages)
+                .build())
+            .collect();
+
+        // Only chained when a binding has flags, so layouts without them don't need descriptor indexing.
+        let binding_flags: Vec<vk::DescriptorBindingFlags> = desc.bindings.iter().map(|binding| binding.flags).collect();
+        let mut flags_info = vk::DescriptorSetLayoutBindingFlagsCreateInfo::builder()
+            .binding_flags(&binding_flags);
+
+        let mut create_info = vk::DescriptorSetLayoutCreateInfo::builder()
+            .bindings(&bindings);
+        if binding_flags.iter().any(|flags| !flags.is_empty()) {
+            create_info = create_info.push_next(&mut flags_info);
+        }
+        if binding_flags.iter().any(|flags| flags.contains(vk::DescriptorBindingFlags::UPDATE_AFTER_BIND)) {
+            create_info = create_info.flags(vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL);
+        }
+
+        let layout = self.device.create_descriptor_set_layout(&create_info, None)?;
+        self.layouts.insert(desc.clone(), layout);
+        Ok(layout)
+    }
+}
+
+impl Drop for DescriptorLayoutCache {
+    fn drop(&mut self) {
+        unsafe {
+            for (_, layout) in self.layouts.drain() {
+                self.device.destroy_descriptor_set_layout(layout, None);
+            }
+        }
+    }
+}
+
+/// Allocates descriptor sets from a growing list of pools. When a pool runs out a new, larger one is created;
+/// [`reset`](Self::reset) returns every set at once.
+pub struct DescriptorAllocator {
+    device: Arc<Device>,
+    ready_pools: Vec<vk::DescriptorPool>,
+    full_pools: Vec<vk::DescriptorPool>,
+    sets_per_pool: u32,
+}
+
+impl DescriptorAllocator {
+    pub fn new(device: &Arc<Device>) -> Self {
+        Self {
+            device: device.clone(),
+            ready_pools: Vec::new(),
+            full_pools: Vec::new(),
+            sets_per_pool: INITIAL_SETS_PER_POOL,
+        }
+    }
+
+    unsafe fn create_pool(&mut self) -> anyhow::Result<vk::DescriptorPool> {
+        let sets = self.sets_per_pool;
+        self.sets_per_pool = (self.sets_per_pool * 2).min(MAX_SETS_PER_POOL);
+
+        let pool_sizes: Vec<vk::DescriptorPoolSize> = POOL_RATIOS.iter()
+            .map(|&(ty, ratio)| vk::DescriptorPoolSize {
+                ty,
+                descriptor_count: ((sets as f32 * ratio) as u32).max(1),
+            })
+            .collect();
+
+        let create_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(sets)
+            .pool_sizes(&pool_sizes);
+
+        Ok(self.device.create_descriptor_pool(&create_info, None)?)
+    }
+
+    unsafe fn current_pool(&mut self) -> anyhow::Result<vk::DescriptorPool> {
+        match self.ready_pools.last() {
+            Some(&pool) => Ok(pool),
+            None => {
+                let pool = self.create_pool()?;
+                self.ready_pools.push(pool);
+                Ok(pool)
+            }
+        }
+    }
+
+    pub unsafe fn allocate(&mut self, layout: vk::DescriptorSetLayout) -> anyhow::Result<vk::DescriptorSet> {
+        let layouts = [layout];
+        let pool = self.current_pool()?;
+
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(pool)
+            .set_layouts(&layouts);
+
+        match self.device.allocate_descriptor_sets(&allocate_info) {
+            Ok(sets) => Ok(sets[0]),
+            Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY | vk::Result::ERROR_FRAGMENTED_POOL) => {
+                self.full_pools.push(self.ready_pools.pop().unwrap());
+
+                let pool = self.current_pool()?;
+                let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+                    .descriptor_pool(pool)
+                    .set_layouts(&layouts);
+
+                Ok(self.device.allocate_descriptor_sets(&allocate_info)?[0])
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Frees every set allocated so far. None of them may still be in use by the GPU.
+    pub unsafe fn reset(&mut self) -> anyhow::Result<()> {
+        for pool in self.full_pools.drain(..).chain(self.ready_pools.drain(..)).collect::<Vec<_>>() {
+            self.device.reset_descriptor_pool(pool, vk::DescriptorPoolResetFlags::empty())?;
+            self.ready_pools.push(pool);
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for DescriptorAllocator {
+    fn drop(&mut self) {
+        unsafe {
+            for &pool in self.ready_pools.iter().chain(&self.full_pools) {
+                self.device.destroy_descriptor_pool(pool, None);
+            }
+        }
+    }
+}
+
+/// One [`DescriptorAllocator`] per frame in flight for sets that are written and used within a single frame.
+pub struct TransientDescriptors {
+    frames: Vec<DescriptorAllocator>,
+    current: usize,
+}
+
+impl TransientDescriptors {
+    pub fn new(device: &Arc<Device>, frames_in_flight: usize) -> Self {
+        Self {
+            frames: (0..frames_in_flight).map(|_| DescriptorAllocator::new(device)).collect(),
+            current: 0,
+        }
+    }
+
+    /// Releases the sets allocated the last time `frame_index` was recorded. The frame's fence must have signalled.
+    pub unsafe fn begin_frame(&mut self, frame_index: usize) -> anyhow::Result<()> {
+        self.current = frame_index;
+        self.frames[frame_index].reset()
+    }
+
+    pub unsafe fn allocate(&mut self, layout: vk::DescriptorSetLayout) -> anyhow::Result<vk::DescriptorSet> {
+        self.frames[self.current].allocate(layout)
+    }
+}
+
+/// Layout cache, a long-lived allocator and the per-frame transient allocators in one place.
+pub struct DescriptorManager {
+    layouts: DescriptorLayoutCache,
+    persistent: DescriptorAllocator,
+    transient: TransientDescriptors,
+}
+
+impl DescriptorManager {
+    pub fn new(device: &Arc<Device>, frames_in_flight: usize) -> Self {
+        Self {
+            layouts: DescriptorLayoutCache::new(device),
+            persistent: DescriptorAllocator::new(device),
+            transient: TransientDescriptors::new(device, frames_in_flight),
+        }
+    }
+
+    pub unsafe fn layout(&mut self, desc: &SetLayoutDesc) -> anyhow::Result<vk::DescriptorSetLayout> {
+        self.layouts.get(desc)
+    }
+
+    /// Allocates a set that lives until the manager is dropped.
+    pub unsafe fn allocate(&mut self, desc: &SetLayoutDesc) -> anyhow::Result<vk::DescriptorSet> {
+        let layout = self.layouts.get(desc)?;
+        self.persistent.allocate(layout)
+    }
+
+    /// Allocates a set that is only valid for the frame being recorded.
+    pub unsafe fn allocate_transient(&mut self, desc: &SetLayoutDesc) -> anyhow::Result<vk::DescriptorSet> {
+        let layout = self.layouts.get(desc)?;
+        self.transient.allocate(layout)
+    }
+
+    pub unsafe fn begin_frame(&mut self, frame_index: usize) -> anyhow::Result<()> {
+        self.transient.begin_frame(frame_index)
+    }
+}
+
+enum PendingWrite {
+    Buffer(u32, vk::DescriptorType, vk::DescriptorBufferInfo),
+    Image(u32, vk::DescriptorType, vk::DescriptorImageInfo),
+    AccelerationStructure(u32, vk::AccelerationStructureKHR),
+}
+
+/// Collects buffer, image and acceleration structure writes for one set and applies them with a single `vkUpdateDescriptorSets`.
+#[derive(Default)]
+pub struct DescriptorWriter {
+    writes: Vec<PendingWrite>,
+}
+
+impl DescriptorWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn buffer(
+        mut self,
+        binding: u32,
+        descriptor_type: vk::DescriptorType,
+        buffer: vk::Buffer,
+        offset: vk::DeviceSize,
+        range: vk::DeviceSize,
+    ) -> Self {
+        self.writes.push(PendingWrite::Buffer(binding, descriptor_type, vk::DescriptorBufferInfo {
+            buffer,
+            offset,
+            range,
+        }));
+        self
+    }
+
+    pub fn image(
+        mut self,
+        binding: u32,
+        descriptor_type: vk::DescriptorType,
+        image_view: vk::ImageView,
+        sampler: vk::Sampler,
+        layout: vk::ImageLayout,
+    ) -> Self {
+        self.writes.push(PendingWrite::Image(binding, descriptor_type, vk::DescriptorImageInfo {
+            sampler,
+            image_view,
+            image_layout: layout,
+        }));
+        self
+    }
+
+    /// Needs the `accelerationStructure` feature.
+    pub fn acceleration_structure(mut self, binding: u32, acceleration_structure: vk::AccelerationStructureKHR) -> Self {
+        self.writes.push(PendingWrite::AccelerationStructure(binding, acceleration_structure));
+        self
+    }
+
+    pub unsafe fn update(&self, device: &Device, set: vk::DescriptorSet) {
+        // Chained into their writes, so they must outlive them.
+        let structures: Vec<vk::WriteDescriptorSetAccelerationStructureKHR> = self.writes.iter()
+            .filter_map(|write| match write {
+                PendingWrite::AccelerationStructure(_, handle) => Some(vk::WriteDescriptorSetAccelerationStructureKHR::builder()
+                    .acceleration_structures(std::slice::from_ref(handle))
+                    .build()),
+                _ => None,
+            })
+            .collect();
+        let mut structures = structures.iter();
+
+        let writes: Vec<vk::WriteDescriptorSet> = self.writes.iter()
+            .map(|write| match write {
+                PendingWrite::Buffer(binding, descriptor_type, info) => vk::WriteDescriptorSet::builder()
+                    .dst_set(set)
+                    .dst_binding(*binding)
+                    .descriptor_type(*descriptor_type)
+                    .buffer_info(std::slice::from_ref(info))
+                    .build(),
+                PendingWrite::Image(binding, descriptor_type, info) => vk::WriteDescriptorSet::builder()
+                    .dst_set(set)
+                    .dst_binding(*binding)
+                    .descriptor_type(*descriptor_type)
+                    .image_info(std::slice::from_ref(info))
+                    .build(),
+                PendingWrite::AccelerationStructure(binding, _) => vk::WriteDescriptorSet {
+                    p_next: structures.next().unwrap() as *const _ as *const c_void,
+                    dst_set: set,
+                    dst_binding: *binding,
+                    descriptor_count: 1,
+                    descriptor_type: vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+                    ..Default::default()
+                },
+            })
+            .collect();
+
+        device.update_descriptor_sets(&writes, &[]);
+    }
+}
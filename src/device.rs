@@ -0,0 +1,460 @@
+use std::collections::BTreeSet;
+use std::ffi::{c_char, CStr};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard};
+use ash::extensions::khr;
+use ash::vk;
+use log::{info, warn};
+use thiserror::Error;
+use crate::allocator::{Allocation, AllocationDesc, Allocator, MemoryLocation};
+use crate::instance::Instance;
+use crate::physical_device::SelectionError;
+use crate::pipeline_cache::PipelineCache;
+use crate::requirements::{DeviceCapabilities, DeviceRequirements, DeviceSupport, Feature};
+use crate::sampler::{SamplerCache, SamplerDesc};
+use crate::surface::Surface;
+
+#[derive(Error, Debug)]
+pub enum DeviceError {
+    #[error("No queue family supports graphics")]
+    NoGraphicsQueue,
+    #[error("No queue family can present to the surface")]
+    NoPresentQueue,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFamilies {
+    pub graphics: u32,
+    pub present: u32,
+    pub transfer: u32,
+    pub compute: u32,
+}
+
+impl QueueFamilies {
+    /// Picks queue families for each kind of work. Presentation prefers the graphics family, while transfer and
+    /// compute prefer dedicated families and fall back to the graphics family when there are none.
+    pub unsafe fn find(
+        instance: &ash::Instance,
+        surface_fn: &khr::Surface,
+        physical_device: vk::PhysicalDevice,
+        surface: vk::SurfaceKHR,
+    ) -> anyhow::Result<Self> {
+        let families = instance.get_physical_device_queue_family_properties(physical_device);
+
+        let mut present_support = Vec::with_capacity(families.len());
+        for index in 0..families.len() {
+            present_support.push(surface_fn.get_physical_device_surface_support(physical_device, index as u32, surface)?);
+        }
+
+        let find_family = |predicate: &dyn Fn(usize, &vk::QueueFamilyProperties) -> bool| {
+            families.iter()
+                .enumerate()
+                .find(|(index, family)| family.queue_count > 0 && predicate(*index, family))
+                .map(|(index, _)| index as u32)
+        };
+
+        let graphics = find_family(&|index, family| family.queue_flags.contains(vk::QueueFlags::GRAPHICS) && present_support[index])
+            .or_else(|| find_family(&|_, family| family.queue_flags.contains(vk::QueueFlags::GRAPHICS)))
+            .ok_or(DeviceError::NoGraphicsQueue)?;
+
+        let present = if present_support[graphics as usize] {
+            graphics
+        } else {
+            find_family(&|index, _| present_support[index]).ok_or(DeviceError::NoPresentQueue)?
+        };
+
+        let transfer = find_family(&|_, family| {
+            family.queue_flags.contains(vk::QueueFlags::TRANSFER)
+                && !family.queue_flags.intersects(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+        })
+            .or_else(|| find_family(&|_, family| {
+                family.queue_flags.contains(vk::QueueFlags::COMPUTE) && !family.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+            }))
+            .unwrap_or(graphics);
+
+        let compute = find_family(&|_, family| {
+            family.queue_flags.contains(vk::QueueFlags::COMPUTE) && !family.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+        })
+            .unwrap_or(graphics);
+
+        Ok(Self {
+            graphics,
+            present,
+            transfer,
+            compute,
+        })
+    }
+
+    pub fn unique(&self) -> Vec<u32> {
+        BTreeSet::from([self.graphics, self.present, self.transfer, self.compute]).into_iter().collect()
+    }
+}
+
+pub struct Device {
+    instance: Arc<Instance>,
+    handle: ash::Device,
+    physical_device: vk::PhysicalDevice,
+    queue_families: QueueFamilies,
+    graphics_queue: vk::Queue,
+    present_queue: vk::Queue,
+    transfer_queue: vk::Queue,
+    compute_queue: vk::Queue,
+    allocator: Mutex<Allocator>,
+    samplers: Mutex<SamplerCache>,
+    pipeline_cache: PipelineCache,
+    capabilities: DeviceCapabilities,
+    enabled_features: vk::PhysicalDeviceFeatures,
+}
+
+impl Device {
+    /// Creates the device with every required and supported optional item of `requirements` enabled. The physical
+    /// device must have been selected against the same requirements.
+    pub unsafe fn new(
+        instance: &Arc<Instance>,
+        surface: &Surface,
+        physical_device: vk::PhysicalDevice,
+        requirements: &DeviceRequirements,
+    ) -> anyhow::Result<Arc<Self>> {
+        log_queue_families(instance, surface.loader(), physical_device, surface.handle())?;
+
+        let queue_families = QueueFamilies::find(instance, surface.loader(), physical_device, surface.handle())?;
+        info!(
+            "Selected queue families: graphics {}, present {}, transfer {}, compute {}",
+            queue_families.graphics,
+            queue_families.present,
+            queue_families.transfer,
+            queue_families.compute,
+        );
+
+        let queue_priorities = [1.0];
+        let queue_create_infos: Vec<vk::DeviceQueueCreateInfo> = queue_families.unique()
+            .into_iter()
+            .map(|family| vk::DeviceQueueCreateInfo::builder()
+                .queue_family_index(family)
+                .queue_priorities(&queue_priorities)
+                .build())
+            .collect();
+
+        let support = DeviceSupport::query(instance, instance.api_version(), physical_device)?;
+        if let Some(extension) = requirements.missing_extension(&support) {
+            return Err(SelectionError::MissingDeviceExtension(extension).into());
+        }
+
+        if let Some(missing) = requirements.missing(&support) {
+            return Err(anyhow::anyhow!("Device does not meet the requirements: {}", missing));
+        }
+
+        let capabilities = requirements.resolve(&support);
+        log_capabilities(&capabilities, requirements);
+        let extension_names: Vec<_> = capabilities.extensions().map(CStr::to_string_lossy).collect();
+        info!("Enabled device extensions: {}", extension_names.join(", "));
+
+        let extension_ptrs: Vec<*const c_char> = capabilities.extensions()
+            .map(|name| name.as_ptr())
+            .collect();
+
+        let enabled_features = capabilities.core_features();
+        let create_info = vk::DeviceCreateInfo::builder()
+            .queue_create_infos(&queue_create_infos)
+            .enabled_extension_names(&extension_ptrs);
+
+        let handle = if capabilities.api_version() >= vk::API_VERSION_1_1 {
+            capabilities.with_feature_chain(|features| {
+                instance.create_device(physical_device, &create_info.push_next(features), None)
+            })?
+        } else {
+            instance.create_device(physical_device, &create_info.enabled_features(&enabled_features), None)?
+        };
+        info!("Created logical device");
+
+        let allocator = Allocator::new(instance.get_physical_device_memory_properties(physical_device))
+            .with_device_address(capabilities.has_feature(Feature::BufferDeviceAddress));
+        let anisotropy_limit = (enabled_features.sampler_anisotropy == vk::TRUE)
+            .then(|| instance.get_physical_device_properties(physical_device).limits.max_sampler_anisotropy);
+        let pipeline_cache = match PipelineCache::load(&handle, &instance.get_physical_device_properties(physical_device)) {
+            Ok(pipeline_cache) => pipeline_cache,
+            Err(err) => {
+                handle.destroy_device(None);
+                return Err(err);
+            }
+        };
+
+        Ok(Arc::new(Self {
+            instance: instance.clone(),
+            graphics_queue: handle.get_device_queue(queue_families.graphics, 0),
+            present_queue: handle.get_device_queue(queue_families.present, 0),
+            transfer_queue: handle.get_device_queue(queue_families.transfer, 0),
+            compute_queue: handle.get_device_queue(queue_families.compute, 0),
+            handle,
+            physical_device,
+            queue_families,
+            allocator: Mutex::new(allocator),
+            samplers: Mutex::new(SamplerCache::new(anisotropy_limit)),
+            pipeline_cache,
+            capabilities,
+            enabled_features,
+        }))
+    }
+
+    pub fn instance(&self) -> &Arc<Instance> {
+        &self.instance
+    }
+
+    pub fn handle(&self) -> &ash::Device {
+        &self.handle
+    }
+
+    pub fn physical_device(&self) -> vk::PhysicalDevice {
+        self.physical_device
+    }
+
+    pub fn queue_families(&self) -> &QueueFamilies {
+        &self.queue_families
+    }
+
+    pub fn graphics_queue(&self) -> vk::Queue {
+        self.graphics_queue
+    }
+
+    pub fn present_queue(&self) -> vk::Queue {
+        self.present_queue
+    }
+
+    pub fn transfer_queue(&self) -> vk::Queue {
+        self.transfer_queue
+    }
+
+    pub fn compute_queue(&self) -> vk::Queue {
+        self.compute_queue
+    }
+
+    pub fn enabled_features(&self) -> &vk::PhysicalDeviceFeatures {
+        &self.enabled_features
+    }
+
+    /// The extensions and features the device was created with.
+    pub fn capabilities(&self) -> &DeviceCapabilities {
+        &self.capabilities
+    }
+
+    /// Whether the core Vulkan 1.3 `dynamicRendering` feature is enabled.
+    pub fn supports_dynamic_rendering(&self) -> bool {
+        self.capabilities.has_feature(Feature::DynamicRendering)
+    }
+
+    /// Opens a debug utils label on `queue` around the work submitted until [`end_queue_label`](Self::end_queue_label).
+    /// Does nothing without a debug messenger.
+    pub unsafe fn begin_queue_label(&self, queue: vk::Queue, name: &str) {
+        if let Some(messenger) = self.instance.debug_messenger() {
+            messenger.begin_queue_label(queue, name);
+        }
+    }
+
+    pub unsafe fn end_queue_label(&self, queue: vk::Queue) {
+        if let Some(messenger) = self.instance.debug_messenger() {
+            messenger.end_queue_label(queue);
+        }
+    }
+
+    /// Whether the core Vulkan 1.2 `bufferDeviceAddress` feature is enabled, which buffers with
+    /// `SHADER_DEVICE_ADDRESS` usage need.
+    pub fn supports_buffer_device_address(&self) -> bool {
+        self.capabilities.has_feature(Feature::BufferDeviceAddress)
+    }
+
+    /// Whether the core Vulkan 1.3 `maintenance4` feature is enabled, which lets memory requirements be queried
+    /// before the object exists.
+    pub fn supports_maintenance4(&self) -> bool {
+        self.capabilities.has_feature(Feature::Maintenance4)
+    }
+
+    /// The memory requirements of a buffer created from `create_info`, without creating it. `None` without
+    /// [`maintenance4`](Self::supports_maintenance4).
+    pub unsafe fn buffer_memory_requirements(&self, create_info: &vk::BufferCreateInfo) -> Option<vk::MemoryRequirements> {
+        if !self.supports_maintenance4() {
+            return None;
+        }
+
+        let info = vk::DeviceBufferMemoryRequirements::builder().create_info(create_info);
+        let mut requirements = vk::MemoryRequirements2::default();
+        self.get_device_buffer_memory_requirements(&info, &mut requirements);
+        Some(requirements.memory_requirements)
+    }
+
+    /// Whether the core Vulkan 1.1 `multiview` feature is enabled, which rendering with a view mask needs.
+    pub fn supports_multiview(&self) -> bool {
+        self.capabilities.has_feature(Feature::Multiview)
+    }
+
+    /// Whether the Vulkan 1.3 extended dynamic state commands, such as `cmd_set_cull_mode`, are available.
+    pub fn supports_extended_dynamic_state(&self) -> bool {
+        self.capabilities.api_version() >= vk::API_VERSION_1_3
+    }
+
+    /// The cache every pipeline is created with. It is saved to disk when the device is dropped.
+    pub fn pipeline_cache(&self) -> vk::PipelineCache {
+        self.pipeline_cache.handle()
+    }
+
+    /// Returns the shared sampler for `desc`, creating it on first use.
+    pub unsafe fn sampler(&self, desc: &SamplerDesc) -> anyhow::Result<vk::Sampler> {
+        self.samplers.lock().unwrap().get(&self.handle, desc)
+    }
+
+    /// The allocator behind [`allocate`](Self::allocate) and [`free`](Self::free), locked until the guard is dropped.
+    pub fn allocator(&self) -> MutexGuard<'_, Allocator> {
+        self.allocator.lock().unwrap()
+    }
+
+    pub unsafe fn allocate(&self, desc: &AllocationDesc) -> anyhow::Result<Allocation> {
+        self.allocator.lock().unwrap().allocate(&self.handle, desc)
+    }
+
+    pub unsafe fn free(&self, allocation: Allocation) {
+        self.allocator.lock().unwrap().free(&self.handle, allocation)
+    }
+
+    /// Creates a buffer from `create_info` with memory bound to it. With `maintenance4` the memory is allocated
+    /// first, so a failed allocation leaves no buffer behind to destroy.
+    pub unsafe fn create_buffer_with_memory(
+        &self,
+        create_info: &vk::BufferCreateInfo,
+        location: MemoryLocation,
+        name: &str,
+    ) -> anyhow::Result<(vk::Buffer, Allocation)> {
+        let Some(requirements) = self.buffer_memory_requirements(create_info) else {
+            let buffer = self.create_buffer(create_info, None)?;
+            return match self.allocate_buffer_memory(buffer, location, name) {
+                Ok(allocation) => Ok((buffer, allocation)),
+                Err(err) => {
+                    self.destroy_buffer(buffer, None);
+                    Err(err)
+                }
+            };
+        };
+
+        let allocation = self.allocate(&AllocationDesc {
+            name,
+            requirements,
+            location,
+            linear: true,
+        })?;
+
+        let buffer = match self.create_buffer(create_info, None) {
+            Ok(buffer) => buffer,
+            Err(err) => {
+                self.free(allocation);
+                return Err(err.into());
+            }
+        };
+
+        if let Err(err) = self.bind_buffer_memory(buffer, allocation.memory(), allocation.offset()) {
+            self.destroy_buffer(buffer, None);
+            self.free(allocation);
+            return Err(err.into());
+        }
+
+        Ok((buffer, allocation))
+    }
+
+    /// Allocates memory for `buffer` and binds it.
+    pub unsafe fn allocate_buffer_memory(&self, buffer: vk::Buffer, location: MemoryLocation, name: &str) -> anyhow::Result<Allocation> {
+        let allocation = self.allocate(&AllocationDesc {
+            name,
+            requirements: self.get_buffer_memory_requirements(buffer),
+            location,
+            linear: true,
+        })?;
+
+        if let Err(err) = self.bind_buffer_memory(buffer, allocation.memory(), allocation.offset()) {
+            self.free(allocation);
+            return Err(err.into());
+        }
+
+        Ok(allocation)
+    }
+
+    /// Allocates memory for an optimally tiled `image` and binds it.
+    pub unsafe fn allocate_image_memory(&self, image: vk::Image, location: MemoryLocation, name: &str) -> anyhow::Result<Allocation> {
+        let allocation = self.allocate(&AllocationDesc {
+            name,
+            requirements: self.get_image_memory_requirements(image),
+            location,
+            linear: false,
+        })?;
+
+        if let Err(err) = self.bind_image_memory(image, allocation.memory(), allocation.offset()) {
+            self.free(allocation);
+            return Err(err.into());
+        }
+
+        Ok(allocation)
+    }
+}
+
+impl Deref for Device {
+    type Target = ash::Device;
+
+    fn deref(&self) -> &Self::Target {
+        &self.handle
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        unsafe {
+            if let Err(err) = self.handle.device_wait_idle() {
+                warn!("device_wait_idle failed during shutdown: {}", err);
+            }
+
+            self.pipeline_cache.save(&self.handle);
+            self.pipeline_cache.destroy(&self.handle);
+            self.samplers.get_mut().unwrap().destroy(&self.handle);
+            self.allocator.get_mut().unwrap().destroy(&self.handle);
+
+            self.handle.destroy_device(None);
+        }
+    }
+}
+
+fn log_capabilities(capabilities: &DeviceCapabilities, requirements: &DeviceRequirements) {
+    let enabled: Vec<&str> = requirements.optional_features()
+        .filter(|&feature| capabilities.has_feature(feature))
+        .map(|feature| feature.name())
+        .collect();
+    let missing: Vec<&str> = requirements.optional_features()
+        .filter(|&feature| !capabilities.has_feature(feature))
+        .map(|feature| feature.name())
+        .collect();
+
+    if !enabled.is_empty() {
+        info!("Enabled optional features: {}", enabled.join(", "));
+    }
+
+    if !missing.is_empty() {
+        info!("Unsupported optional features: {}", missing.join(", "));
+    }
+}
+
+pub unsafe fn log_queue_families(
+    instance: &ash::Instance,
+    surface_fn: &khr::Surface,
+    physical_device: vk::PhysicalDevice,
+    surface: vk::SurfaceKHR,
+) -> anyhow::Result<()> {
+    let families = instance.get_physical_device_queue_family_properties(physical_device);
+
+    for (index, family) in families.iter().enumerate() {
+        let present = surface_fn.get_physical_device_surface_support(physical_device, index as u32, surface)?;
+
+        info!(
+            "Queue family {}: {} queue(s), flags {:?}, present support: {}",
+            index,
+            family.queue_count,
+            family.queue_flags,
+            present,
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,41 @@
+use winit::event_loop::EventLoopProxy;
+
+/// How the run loop decides when to redraw.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RedrawPolicy {
+    /// Poll the event loop and redraw every iteration. Suited to games.
+    #[default]
+    Continuous,
+    /// Sleep until an event arrives and only redraw on `RedrawRequested`, e.g. after `App::request_redraw` or a
+    /// `WakeHandle` signal. Suited to tools and UI that are mostly static.
+    OnDemand,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserEvent {
+    Wake,
+    AssetLoaded,
+    ShaderReloaded,
+}
+
+/// Cloneable, `Send` handle that lets off-thread work wake the run loop. Every event sent through
+/// it is delivered to the loop as a user event and causes a redraw to be requested.
+#[derive(Clone)]
+pub struct WakeHandle {
+    proxy: EventLoopProxy<UserEvent>,
+}
+
+impl WakeHandle {
+    pub fn new(proxy: EventLoopProxy<UserEvent>) -> Self {
+        Self { proxy }
+    }
+
+    pub fn wake(&self) -> anyhow::Result<()> {
+        self.send(UserEvent::Wake)
+    }
+
+    pub fn send(&self, event: UserEvent) -> anyhow::Result<()> {
+        self.proxy.send_event(event)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,307 @@
+use ash::vk;
+
+pub const DEPTH_FORMAT_CANDIDATES: [vk::Format; 3] = [
+    vk::Format::D32_SFLOAT,
+    vk::Format::D32_SFLOAT_S8_UINT,
+    vk::Format::D24_UNORM_S8_UINT,
+];
+
+/// Returns the first format in `candidates` that supports all of `features` with the given tiling.
+pub unsafe fn find_supported_format(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+    candidates: &[vk::Format],
+    tiling: vk::ImageTiling,
+    features: vk::FormatFeatureFlags,
+) -> Option<vk::Format> {
+    select_format(candidates, tiling, features, |format| {
+        instance.get_physical_device_format_properties(physical_device, format)
+    })
+}
+
+/// [`find_supported_format`] over the properties `properties` reports for each candidate.
+pub fn select_format(
+    candidates: &[vk::Format],
+    tiling: vk::ImageTiling,
+    features: vk::FormatFeatureFlags,
+    properties: impl Fn(vk::Format) -> vk::FormatProperties,
+) -> Option<vk::Format> {
+    candidates.iter().copied().find(|&format| {
+        let properties = properties(format);
+
+        let supported = match tiling {
+            vk::ImageTiling::LINEAR => properties.linear_tiling_features,
+            vk::ImageTiling::OPTIMAL => properties.optimal_tiling_features,
+            _ => vk::FormatFeatureFlags::empty(),
+        };
+
+        supported.contains(features)
+    })
+}
+
+/// Picks from the surface's `available` formats: the first of `preferences` it has, then any other sRGB format, and
+/// only then whatever comes first. A single undefined format means the surface takes anything, so it gets the first
+/// preference.
+pub fn select_surface_format(
+    available: &[vk::SurfaceFormatKHR],
+    preferences: &[vk::SurfaceFormatKHR],
+) -> Option<vk::SurfaceFormatKHR> {
+    if let [format] = available {
+        if format.format == vk::Format::UNDEFINED {
+            return preferences.first().copied();
+        }
+    }
+
+    preferences.iter()
+        .copied()
+        .find(|preferred| available.contains(preferred))
+        .or_else(|| available.iter()
+            .copied()
+            .find(|format| is_srgb(format.format) && format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR))
+        .or_else(|| available.first().copied())
+}
+
+pub unsafe fn find_depth_format(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> Option<vk::Format> {
+    find_supported_format(
+        instance,
+        physical_device,
+        &DEPTH_FORMAT_CANDIDATES,
+        vk::ImageTiling::OPTIMAL,
+        vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT,
+    )
+}
+
+/// Whether the hardware converts between linear values and the sRGB transfer function when reading or writing
+/// `format`.
+pub fn is_srgb(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::R8_SRGB
+            | vk::Format::R8G8_SRGB
+            | vk::Format::R8G8B8_SRGB
+            | vk::Format::B8G8R8_SRGB
+            | vk::Format::R8G8B8A8_SRGB
+            | vk::Format::B8G8R8A8_SRGB
+            | vk::Format::A8B8G8R8_SRGB_PACK32
+    )
+}
+
+/// SDR white in nits when clearing to HDR10, the reference white of ITU-R BT.2408.
+const HDR10_REFERENCE_WHITE: f32 = 203.0;
+
+/// Linear BT.709 to linear BT.2020 primaries.
+const BT709_TO_BT2020: [[f32; 3]; 3] = [
+    [0.6274, 0.3293, 0.0433],
+    [0.0691, 0.9195, 0.0114],
+    [0.0164, 0.0880, 0.8956],
+];
+
+/// The color to clear an image of `format` to, for it to show `linear_rgba` in the given color space. sRGB formats
+/// and linear color spaces take linear values as they are, UNORM formats in a nonlinear color space get the sRGB
+/// transfer function applied, and HDR10 gets BT.2020 primaries with the PQ curve, 1.0 being SDR white. Alpha is
+/// always linear.
+pub fn encode_clear_color(linear_rgba: [f32; 4], format: vk::SurfaceFormatKHR) -> [f32; 4] {
+    let [r, g, b, a] = linear_rgba;
+    match format.color_space {
+        vk::ColorSpaceKHR::HDR10_ST2084_EXT => {
+            let [r, g, b] = BT709_TO_BT2020.map(|row| row[0] * r + row[1] * g + row[2] * b);
+            [pq_encode(r), pq_encode(g), pq_encode(b), a]
+        }
+        vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT
+        | vk::ColorSpaceKHR::BT709_LINEAR_EXT
+        | vk::ColorSpaceKHR::BT2020_LINEAR_EXT
+        | vk::ColorSpaceKHR::DISPLAY_P3_LINEAR_EXT
+        | vk::ColorSpaceKHR::ADOBERGB_LINEAR_EXT
+        | vk::ColorSpaceKHR::PASS_THROUGH_EXT => linear_rgba,
+        _ if is_srgb(format.format) => linear_rgba,
+        _ => [srgb_encode(r), srgb_encode(g), srgb_encode(b), a],
+    }
+}
+
+/// [`encode_clear_color`] for a swapchain image, as a clear value.
+pub fn clear_value_for_swapchain(linear_rgba: [f32; 4], format: vk::SurfaceFormatKHR) -> vk::ClearValue {
+    vk::ClearValue {
+        color: vk::ClearColorValue { float32: encode_clear_color(linear_rgba, format) },
+    }
+}
+
+/// The sRGB transfer function, mirrored for the negative values of extended sRGB.
+fn srgb_encode(linear: f32) -> f32 {
+    let magnitude = linear.abs();
+    let encoded = if magnitude <= 0.0031308 {
+        magnitude * 12.92
+    } else {
+        1.055 * magnitude.powf(1.0 / 2.4) - 0.055
+    };
+    encoded.copysign(linear)
+}
+
+/// The SMPTE ST 2084 (PQ) inverse EOTF, for a linear value relative to [`HDR10_REFERENCE_WHITE`].
+fn pq_encode(linear: f32) -> f32 {
+    const M1: f32 = 2610.0 / 16384.0;
+    const M2: f32 = 2523.0 / 4096.0 * 128.0;
+    const C1: f32 = 3424.0 / 4096.0;
+    const C2: f32 = 2413.0 / 4096.0 * 32.0;
+    const C3: f32 = 2392.0 / 4096.0 * 32.0;
+
+    let y = (linear.max(0.0) * HDR10_REFERENCE_WHITE / 10000.0).min(1.0).powf(M1);
+    ((C1 + C2 * y) / (1.0 + C3 * y)).powf(M2)
+}
+
+pub fn has_stencil_component(format: vk::Format) -> bool {
+    matches!(format, vk::Format::D32_SFLOAT_S8_UINT | vk::Format::D24_UNORM_S8_UINT | vk::Format::D16_UNORM_S8_UINT)
+}
+
+/// Returns the highest sample count not above `requested` that both color and depth framebuffers support.
+pub unsafe fn supported_sample_count(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+    requested: vk::SampleCountFlags,
+) -> vk::SampleCountFlags {
+    let limits = instance.get_physical_device_properties(physical_device).limits;
+    let supported = limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts;
+
+    [
+        vk::SampleCountFlags::TYPE_64,
+        vk::SampleCountFlags::TYPE_32,
+        vk::SampleCountFlags::TYPE_16,
+        vk::SampleCountFlags::TYPE_8,
+        vk::SampleCountFlags::TYPE_4,
+        vk::SampleCountFlags::TYPE_2,
+    ]
+        .into_iter()
+        .find(|&count| count.as_raw() <= requested.as_raw() && supported.contains(count))
+        .unwrap_or(vk::SampleCountFlags::TYPE_1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const B8G8R8A8_SRGB: vk::SurfaceFormatKHR = vk::SurfaceFormatKHR {
+        format: vk::Format::B8G8R8A8_SRGB,
+        color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+    };
+    const R8G8B8A8_SRGB: vk::SurfaceFormatKHR = vk::SurfaceFormatKHR {
+        format: vk::Format::R8G8B8A8_SRGB,
+        color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+    };
+    const A8B8G8R8_SRGB: vk::SurfaceFormatKHR = vk::SurfaceFormatKHR {
+        format: vk::Format::A8B8G8R8_SRGB_PACK32,
+        color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+    };
+    const B8G8R8A8_UNORM: vk::SurfaceFormatKHR = vk::SurfaceFormatKHR {
+        format: vk::Format::B8G8R8A8_UNORM,
+        color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+    };
+    const PREFERENCES: [vk::SurfaceFormatKHR; 2] = [B8G8R8A8_SRGB, R8G8B8A8_SRGB];
+
+    fn assert_close(actual: [f32; 4], expected: [f32; 4]) {
+        for (actual, expected) in actual.into_iter().zip(expected) {
+            assert!((actual - expected).abs() < 1e-3, "{:?} is not {:?}", actual, expected);
+        }
+    }
+
+    fn optimal(features: vk::FormatFeatureFlags) -> vk::FormatProperties {
+        vk::FormatProperties {
+            optimal_tiling_features: features,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn surface_format_follows_preference_order() {
+        let available = [B8G8R8A8_UNORM, R8G8B8A8_SRGB, B8G8R8A8_SRGB];
+        assert_eq!(select_surface_format(&available, &PREFERENCES), Some(B8G8R8A8_SRGB));
+
+        let available = [B8G8R8A8_UNORM, R8G8B8A8_SRGB];
+        assert_eq!(select_surface_format(&available, &PREFERENCES), Some(R8G8B8A8_SRGB));
+    }
+
+    #[test]
+    fn surface_format_prefers_any_srgb_over_linear() {
+        let available = [B8G8R8A8_UNORM, A8B8G8R8_SRGB];
+        assert_eq!(select_surface_format(&available, &PREFERENCES), Some(A8B8G8R8_SRGB));
+    }
+
+    #[test]
+    fn surface_format_falls_back_to_first_linear() {
+        let linear_rgba = vk::SurfaceFormatKHR {
+            format: vk::Format::R8G8B8A8_UNORM,
+            ..B8G8R8A8_UNORM
+        };
+        let available = [B8G8R8A8_UNORM, linear_rgba];
+        assert_eq!(select_surface_format(&available, &PREFERENCES), Some(B8G8R8A8_UNORM));
+    }
+
+    #[test]
+    fn undefined_surface_format_takes_first_preference() {
+        let undefined = vk::SurfaceFormatKHR {
+            format: vk::Format::UNDEFINED,
+            color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+        };
+        assert_eq!(select_surface_format(&[undefined], &PREFERENCES), Some(B8G8R8A8_SRGB));
+        assert_eq!(select_surface_format(&[], &PREFERENCES), None);
+    }
+
+    #[test]
+    fn depth_format_is_first_supported_candidate() {
+        let properties = |format| match format {
+            vk::Format::D32_SFLOAT => optimal(vk::FormatFeatureFlags::SAMPLED_IMAGE),
+            vk::Format::D32_SFLOAT_S8_UINT | vk::Format::D24_UNORM_S8_UINT => {
+                optimal(vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT | vk::FormatFeatureFlags::SAMPLED_IMAGE)
+            }
+            _ => vk::FormatProperties::default(),
+        };
+
+        let selected = select_format(
+            &DEPTH_FORMAT_CANDIDATES,
+            vk::ImageTiling::OPTIMAL,
+            vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT,
+            properties,
+        );
+        assert_eq!(selected, Some(vk::Format::D32_SFLOAT_S8_UINT));
+
+        // Linear tiling reports no features here.
+        let selected = select_format(
+            &DEPTH_FORMAT_CANDIDATES,
+            vk::ImageTiling::LINEAR,
+            vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT,
+            properties,
+        );
+        assert_eq!(selected, None);
+    }
+
+    #[test]
+    fn srgb_clear_color_stays_linear() {
+        let color = [0.2, 0.5, 1.0, 0.5];
+        assert_eq!(encode_clear_color(color, B8G8R8A8_SRGB), color);
+    }
+
+    #[test]
+    fn unorm_clear_color_is_srgb_encoded() {
+        assert_close(encode_clear_color([0.0, 0.2140, 1.0, 0.5], B8G8R8A8_UNORM), [0.0, 0.5, 1.0, 0.5]);
+        assert_close(encode_clear_color([0.002, 0.0, 0.0, 1.0], B8G8R8A8_UNORM), [0.02584, 0.0, 0.0, 1.0]);
+
+        let clear = clear_value_for_swapchain([0.2140, 0.2140, 0.2140, 1.0], B8G8R8A8_UNORM);
+        assert_close(unsafe { clear.color.float32 }, [0.5, 0.5, 0.5, 1.0]);
+    }
+
+    #[test]
+    fn hdr_clear_color_follows_color_space() {
+        let scrgb = vk::SurfaceFormatKHR {
+            format: vk::Format::R16G16B16A16_SFLOAT,
+            color_space: vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT,
+        };
+        let color = [2.0, -0.1, 0.5, 1.0];
+        assert_eq!(encode_clear_color(color, scrgb), color);
+
+        // SDR white is 203 nits, about 0.58 on the PQ curve, whatever the format.
+        let hdr10 = vk::SurfaceFormatKHR {
+            format: vk::Format::A2B10G10R10_UNORM_PACK32,
+            color_space: vk::ColorSpaceKHR::HDR10_ST2084_EXT,
+        };
+        assert_close(encode_clear_color([1.0, 1.0, 1.0, 1.0], hdr10), [0.5807, 0.5807, 0.5807, 1.0]);
+        assert_close(encode_clear_color([0.0, 0.0, 0.0, 0.25], hdr10), [0.0, 0.0, 0.0, 0.25]);
+    }
+}
@@ -0,0 +1,226 @@
+use std::alloc::{alloc, dealloc, handle_alloc_error, Layout};
+use std::cell::{Cell, RefCell};
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+use std::ptr::NonNull;
+use log::debug;
+
+/// Size of the first chunk of a [`FrameArena`] unless configured otherwise.
+pub const DEFAULT_FRAME_ARENA_SIZE: usize = 256 << 10;
+
+/// Alignment of every chunk, the largest an allocation may ask for.
+const CHUNK_ALIGNMENT: usize = 16;
+
+struct Chunk {
+    data: NonNull<u8>,
+    size: usize,
+}
+
+impl Chunk {
+    fn new(size: usize) -> Self {
+        let layout = Self::layout(size);
+        // SAFETY: the layout has a non-zero size.
+        let data = NonNull::new(unsafe { alloc(layout) }).unwrap_or_else(|| handle_alloc_error(layout));
+        Self { data, size }
+    }
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size.max(1), CHUNK_ALIGNMENT).expect("frame arena chunk size overflows")
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        // SAFETY: allocated in `new` with the same layout.
+        unsafe { dealloc(self.data.as_ptr(), Self::layout(self.size)) }
+    }
+}
+
+/// A bump allocator for CPU data that only lives while one frame is recorded, such as barrier lists and the
+/// vertices and instances copied out of the draw queues. Allocating is a pointer bump in the current chunk instead
+/// of a trip to the global allocator; a frame that fills it gets another chunk for the rest, and the chunks are
+/// merged into one large enough for them all by the next [`begin_frame`](Self::begin_frame).
+///
+/// Everything allocated is borrowed from the arena and freed at once by `begin_frame`, which needs `&mut self`, so
+/// no allocation outlives the frame. Only `Copy` types can be stored, since nothing is dropped.
+pub struct FrameArena {
+    chunks: RefCell<Vec<Chunk>>,
+    /// Bytes in use of the last chunk.
+    offset: Cell<usize>,
+    /// Bytes allocated since `begin_frame`, padding included.
+    allocated: Cell<usize>,
+}
+
+impl FrameArena {
+    pub fn new(size: usize) -> Self {
+        Self {
+            chunks: RefCell::new(vec![Chunk::new(size)]),
+            offset: Cell::new(0),
+            allocated: Cell::new(0),
+        }
+    }
+
+    /// Frees everything allocated since the last call.
+    pub fn begin_frame(&mut self) {
+        let chunks = self.chunks.get_mut();
+        if chunks.len() > 1 {
+            let size = chunks.iter().map(|chunk| chunk.size).sum();
+            debug!("Merging the frame arena's chunks into one of {} bytes", size);
+            chunks.clear();
+            chunks.push(Chunk::new(size));
+        }
+
+        self.offset.set(0);
+        self.allocated.set(0);
+    }
+
+    /// Room for `len` values of `T`, valid until the next `begin_frame`.
+    // Handing out `&mut` from `&self` is the point of an arena; every allocation is a distinct range.
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_uninit<T: Copy>(&self, len: usize) -> &mut [MaybeUninit<T>] {
+        let layout = Layout::array::<T>(len).expect("frame arena allocation size overflows");
+        assert!(layout.align() <= CHUNK_ALIGNMENT, "Frame arena allocations can be aligned to at most {} bytes", CHUNK_ALIGNMENT);
+
+        let mut chunks = self.chunks.borrow_mut();
+        let mut start = self.offset.get().next_multiple_of(layout.align());
+        let mut padding = start - self.offset.get();
+        if start + layout.size() > chunks.last().expect("the arena has a chunk").size {
+            let size = chunks.last().expect("the arena has a chunk").size.max(layout.size());
+            chunks.push(Chunk::new(size));
+            (start, padding) = (0, 0);
+        }
+
+        let chunk = chunks.last().expect("the arena has a chunk");
+        self.allocated.set(self.allocated.get() + padding + layout.size());
+        self.offset.set(start + layout.size());
+
+        // SAFETY: `start..start + size` lies inside the chunk, is aligned for `T` and is handed out only once until
+        // `begin_frame`, which can't run while the returned borrow of `self` is alive. Chunks are never moved or
+        // freed before then.
+        unsafe { std::slice::from_raw_parts_mut(chunk.data.as_ptr().add(start).cast(), len) }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_slice<T: Copy>(&self, data: &[T]) -> &mut [T] {
+        let slice = self.alloc_uninit(data.len());
+        for (slot, &value) in slice.iter_mut().zip(data) {
+            slot.write(value);
+        }
+
+        // SAFETY: every element was just written.
+        unsafe { &mut *(slice as *mut [MaybeUninit<T>] as *mut [T]) }
+    }
+
+    /// Collects `values`, in place of a `Vec` that would be dropped at the end of the frame.
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_from_iter<T: Copy, I>(&self, values: I) -> &mut [T]
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let values = values.into_iter();
+        let slice = self.alloc_uninit(values.len());
+        let mut written = 0;
+        for (slot, value) in slice.iter_mut().zip(values) {
+            slot.write(value);
+            written += 1;
+        }
+        assert_eq!(written, slice.len(), "Iterator yielded fewer values than it reported");
+
+        // SAFETY: every element was just written.
+        unsafe { &mut *(slice as *mut [MaybeUninit<T>] as *mut [T]) }
+    }
+
+    /// An empty list growing inside the arena.
+    pub fn vec<T: Copy>(&self) -> ArenaVec<'_, T> {
+        ArenaVec {
+            arena: self,
+            data: &mut [],
+            len: 0,
+        }
+    }
+
+    /// Bytes allocated since [`begin_frame`](Self::begin_frame), including alignment padding.
+    pub fn allocated(&self) -> usize {
+        self.allocated.get()
+    }
+
+    /// Bytes the arena holds on to between frames.
+    pub fn capacity(&self) -> usize {
+        self.chunks.borrow().iter().map(|chunk| chunk.size).sum()
+    }
+}
+
+impl Default for FrameArena {
+    fn default() -> Self {
+        Self::new(DEFAULT_FRAME_ARENA_SIZE)
+    }
+}
+
+/// A growable list in a [`FrameArena`], for building lists of unknown length such as barriers. Growing copies the
+/// list to a new allocation twice the size; the old one is only reclaimed with the rest of the frame.
+pub struct ArenaVec<'a, T: Copy> {
+    arena: &'a FrameArena,
+    data: &'a mut [MaybeUninit<T>],
+    len: usize,
+}
+
+impl<'a, T: Copy> ArenaVec<'a, T> {
+    pub fn push(&mut self, value: T) {
+        if self.len == self.data.len() {
+            let grown = self.arena.alloc_uninit((self.data.len() * 2).max(4));
+            grown[..self.len].copy_from_slice(&self.data[..self.len]);
+            self.data = grown;
+        }
+
+        self.data[self.len].write(value);
+        self.len += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        // SAFETY: the first `len` elements have been written.
+        unsafe { &*(&self.data[..self.len] as *const [MaybeUninit<T>] as *const [T]) }
+    }
+}
+
+impl<T: Copy> Deref for ArenaVec<'_, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocations_survive_new_chunks() {
+        let mut arena = FrameArena::new(16);
+        let first = arena.alloc_slice(&[1u32, 2, 3]);
+        let second = arena.alloc_from_iter((0..64u32).map(|value| value as u64 * 2));
+        assert_eq!(first, &[1, 2, 3]);
+        assert_eq!(second[63], 126);
+        assert!(arena.capacity() > 16);
+
+        arena.begin_frame();
+        assert_eq!(arena.allocated(), 0);
+        assert_eq!(arena.chunks.borrow().len(), 1);
+    }
+
+    #[test]
+    fn arena_vec_grows_in_place_of_a_vec() {
+        let arena = FrameArena::new(64);
+        let mut list = arena.vec();
+        for value in 0..100u16 {
+            list.push(value);
+        }
+        assert_eq!(list.len(), 100);
+        assert!(list.iter().copied().eq(0..100));
+    }
+}
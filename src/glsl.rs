@@ -0,0 +1,294 @@
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use ash::vk;
+use log::warn;
+use thiserror::Error;
+use crate::device::Device;
+use crate::shader::ShaderModule;
+
+/// Overrides the `glslc` executable used for runtime compilation.
+pub const GLSLC_ENV_VAR: &str = "LEGAMING_GLSLC";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: Option<u32>,
+    pub message: String,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.file, line, self.message),
+            None => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CompileError {
+    #[error("Failed to run {executable}: {source}. Install the Vulkan SDK or set {GLSLC_ENV_VAR}")]
+    CompilerNotFound { executable: String, source: std::io::Error },
+    #[error("Failed to compile {name}:\n{}", format_diagnostics(.diagnostics))]
+    Compile { name: String, diagnostics: Vec<Diagnostic> },
+    #[error("Cannot tell the shader stage of {0} from its extension")]
+    UnknownStage(String),
+}
+
+fn format_diagnostics(diagnostics: &[Diagnostic]) -> String {
+    diagnostics.iter()
+        .map(|diagnostic| diagnostic.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Inserts `text` after the `#version` line of `source`, or in front when it has none, with a `#line` directive so
+/// diagnostics still point at the lines of `source`.
+pub fn insert_after_version(source: &str, text: &str) -> String {
+    match source.split_once('\n') {
+        Some((version, rest)) if version.trim_start().starts_with("#version") => {
+            format!("{}\n{}\n#line 2\n{}", version, text, rest)
+        }
+        _ => format!("{}\n#line 1\n{}", text, source),
+    }
+}
+
+/// Maps GLSL file extensions (`.vert`, `.frag`, `.comp`, ...) to shader stages, ignoring a trailing `.glsl`.
+pub fn stage_from_path(path: &Path) -> Option<vk::ShaderStageFlags> {
+    let name = path.file_name()?.to_str()?;
+    let name = name.strip_suffix(".glsl").unwrap_or(name);
+
+    Some(match name.rsplit_once('.')?.1 {
+        "vert" => vk::ShaderStageFlags::VERTEX,
+        "frag" => vk::ShaderStageFlags::FRAGMENT,
+        "comp" => vk::ShaderStageFlags::COMPUTE,
+        "geom" => vk::ShaderStageFlags::GEOMETRY,
+        "tesc" => vk::ShaderStageFlags::TESSELLATION_CONTROL,
+        "tese" => vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+        "mesh" => vk::ShaderStageFlags::MESH_EXT,
+        "task" => vk::ShaderStageFlags::TASK_EXT,
+        "rgen" => vk::ShaderStageFlags::RAYGEN_KHR,
+        "rmiss" => vk::ShaderStageFlags::MISS_KHR,
+        "rchit" => vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+        "rahit" => vk::ShaderStageFlags::ANY_HIT_KHR,
+        _ => return None,
+    })
+}
+
+fn stage_name(stage: vk::ShaderStageFlags) -> Option<&'static str> {
+    Some(match stage {
+        vk::ShaderStageFlags::VERTEX => "vert",
+        vk::ShaderStageFlags::FRAGMENT => "frag",
+        vk::ShaderStageFlags::COMPUTE => "comp",
+        vk::ShaderStageFlags::GEOMETRY => "geom",
+        vk::ShaderStageFlags::TESSELLATION_CONTROL => "tesc",
+        vk::ShaderStageFlags::TESSELLATION_EVALUATION => "tese",
+        vk::ShaderStageFlags::MESH_EXT => "mesh",
+        vk::ShaderStageFlags::TASK_EXT => "task",
+        vk::ShaderStageFlags::RAYGEN_KHR => "rgen",
+        vk::ShaderStageFlags::MISS_KHR => "rmiss",
+        vk::ShaderStageFlags::CLOSEST_HIT_KHR => "rchit",
+        vk::ShaderStageFlags::ANY_HIT_KHR => "rahit",
+        _ => return None,
+    })
+}
+
+/// Parses `file:line: error: message` lines as printed by glslc. Lines that don't look like diagnostics are kept
+/// as messages attributed to `name`.
+fn parse_diagnostics(name: &str, output: &str) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = output.lines()
+        .filter(|line| !line.trim().is_empty() && !line.ends_with("generated."))
+        .map(|line| {
+            let Some((location, message)) = line.split_once(": error: ").or_else(|| line.split_once(": warning: ")) else {
+                return Diagnostic {
+                    file: name.to_owned(),
+                    line: None,
+                    message: line.trim().to_owned(),
+                };
+            };
+
+            let (file, line) = match location.rsplit_once(':') {
+                Some((file, line)) if line.parse::<u32>().is_ok() => (file, line.parse().ok()),
+                _ => (location, None),
+            };
+
+            Diagnostic {
+                file: file.to_owned(),
+                line,
+                message: message.trim().to_owned(),
+            }
+        })
+        .collect();
+
+    if diagnostics.is_empty() {
+        diagnostics.push(Diagnostic {
+            file: name.to_owned(),
+            line: None,
+            message: "glslc failed without output".into(),
+        });
+    }
+
+    diagnostics
+}
+
+/// Compiles GLSL to SPIR-V at runtime by running `glslc` from the Vulkan SDK. `#include` directives are resolved
+/// relative to the including file and to every include directory.
+#[derive(Debug, Clone)]
+pub struct GlslCompiler {
+    executable: PathBuf,
+    include_dirs: Vec<PathBuf>,
+    defines: Vec<(String, Option<String>)>,
+    target_env: String,
+    optimize: bool,
+}
+
+impl GlslCompiler {
+    pub fn new() -> Self {
+        Self {
+            executable: std::env::var_os(GLSLC_ENV_VAR).map_or_else(|| PathBuf::from("glslc"), PathBuf::from),
+            include_dirs: Vec::new(),
+            defines: Vec::new(),
+            target_env: "vulkan1.3".into(),
+            optimize: !cfg!(debug_assertions),
+        }
+    }
+
+    pub fn with_executable(mut self, executable: impl Into<PathBuf>) -> Self {
+        self.executable = executable.into();
+        self
+    }
+
+    pub fn with_include_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.include_dirs.push(dir.into());
+        self
+    }
+
+    pub fn with_define(mut self, name: impl Into<String>, value: Option<&str>) -> Self {
+        self.defines.push((name.into(), value.map(str::to_owned)));
+        self
+    }
+
+    /// Target environment passed as `--target-env`, `vulkan1.3` by default.
+    pub fn with_target_env(mut self, target_env: impl Into<String>) -> Self {
+        self.target_env = target_env.into();
+        self
+    }
+
+    pub fn with_optimization(mut self, optimize: bool) -> Self {
+        self.optimize = optimize;
+        self
+    }
+
+    /// Whether the compiler executable can be run at all.
+    pub fn is_available(&self) -> bool {
+        Command::new(&self.executable)
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.executable);
+        command.arg(format!("--target-env={}", self.target_env));
+
+        for dir in &self.include_dirs {
+            command.arg("-I").arg(dir);
+        }
+
+        for (name, value) in &self.defines {
+            match value {
+                Some(value) => command.arg(format!("-D{}={}", name, value)),
+                None => command.arg(format!("-D{}", name)),
+            };
+        }
+
+        if self.optimize {
+            command.arg("-O");
+        }
+
+        command.arg("-o").arg("-");
+        command
+    }
+
+    fn run(&self, mut command: Command, name: &str, stdin: Option<&str>) -> Result<Vec<u8>, CompileError> {
+        let not_found = |source| CompileError::CompilerNotFound {
+            executable: self.executable.display().to_string(),
+            source,
+        };
+
+        let mut child = command
+            .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(not_found)?;
+
+        if let (Some(source), Some(mut pipe)) = (stdin, child.stdin.take()) {
+            pipe.write_all(source.as_bytes()).map_err(not_found)?;
+        }
+
+        let output = child.wait_with_output().map_err(not_found)?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        if !output.status.success() {
+            return Err(CompileError::Compile {
+                name: name.to_owned(),
+                diagnostics: parse_diagnostics(name, &stderr),
+            });
+        }
+
+        if !stderr.trim().is_empty() {
+            for diagnostic in parse_diagnostics(name, &stderr) {
+                warn!("{}", diagnostic);
+            }
+        }
+
+        Ok(output.stdout)
+    }
+
+    /// Compiles a GLSL file; the stage comes from its extension.
+    pub fn compile_file(&self, path: impl AsRef<Path>) -> Result<Vec<u8>, CompileError> {
+        let path = path.as_ref();
+        let name = path.display().to_string();
+
+        if stage_from_path(path).is_none() {
+            return Err(CompileError::UnknownStage(name));
+        }
+
+        let mut command = self.command();
+        command.arg(path);
+        self.run(command, &name, None)
+    }
+
+    /// Compiles GLSL source held in memory. Includes are only searched for in the include directories.
+    pub fn compile_source(&self, source: &str, stage: vk::ShaderStageFlags, name: &str) -> Result<Vec<u8>, CompileError> {
+        let stage_name = stage_name(stage).ok_or_else(|| CompileError::UnknownStage(name.to_owned()))?;
+
+        let mut command = self.command();
+        command.arg(format!("-fshader-stage={}", stage_name)).arg("-");
+        self.run(command, name, Some(source))
+    }
+}
+
+impl Default for GlslCompiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShaderModule {
+    /// Compiles a GLSL file with `compiler` and creates a module from the result.
+    pub unsafe fn from_glsl_file(device: &Arc<Device>, compiler: &GlslCompiler, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let spirv = compiler.compile_file(path)?;
+        let stage = stage_from_path(path).ok_or_else(|| CompileError::UnknownStage(path.display().to_string()))?;
+
+        let module = Self::from_bytes_with_stage(device, &path.display().to_string(), &spirv, stage)?;
+        Ok(module.with_path(path))
+    }
+}
@@ -0,0 +1,266 @@
+use thiserror::Error;
+use crate::buffer::Buffer;
+use crate::image::{Image, Texture};
+use crate::timeline::RetireQueue;
+
+/// A slot of an [`Arena`] and the generation it was filled in, so a handle to a removed object doesn't resolve to
+/// whatever took its slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct RawHandle {
+    index: u32,
+    generation: u32,
+}
+
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>,
+}
+
+/// Slab of objects addressed by [`RawHandle`]s, reusing the slots of removed ones. Not the `slab` crate, whose keys
+/// are bare indices: a key kept after its object was removed would silently resolve to whatever reused the slot,
+/// which the generation in every handle here rules out.
+struct Arena<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<u32>,
+}
+
+impl<T> Arena<T> {
+    fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, value: T) -> RawHandle {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot { generation: 0, value: None });
+                self.slots.len() as u32 - 1
+            }
+        };
+
+        let slot = &mut self.slots[index as usize];
+        slot.value = Some(value);
+        RawHandle { index, generation: slot.generation }
+    }
+
+    fn get(&self, handle: RawHandle) -> Option<&T> {
+        self.slots.get(handle.index as usize)
+            .filter(|slot| slot.generation == handle.generation)
+            .and_then(|slot| slot.value.as_ref())
+    }
+
+    fn get_mut(&mut self, handle: RawHandle) -> Option<&mut T> {
+        self.slots.get_mut(handle.index as usize)
+            .filter(|slot| slot.generation == handle.generation)
+            .and_then(|slot| slot.value.as_mut())
+    }
+
+    fn remove(&mut self, handle: RawHandle) -> Option<T> {
+        let slot = self.slots.get_mut(handle.index as usize).filter(|slot| slot.generation == handle.generation)?;
+        let value = slot.value.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(handle.index);
+        Some(value)
+    }
+
+    /// Removes everything, leaving every handle handed out so far stale.
+    fn clear(&mut self) -> Vec<T> {
+        let handles: Vec<RawHandle> = self.slots.iter().enumerate()
+            .filter(|(_, slot)| slot.value.is_some())
+            .map(|(index, slot)| RawHandle { index: index as u32, generation: slot.generation })
+            .collect();
+        handles.into_iter().filter_map(|handle| self.remove(handle)).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+}
+
+/// A [`Buffer`] in [`GpuResources`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BufferHandle(RawHandle);
+
+/// An [`Image`] in [`GpuResources`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImageHandle(RawHandle);
+
+/// A [`Texture`] in [`GpuResources`], as opposed to a texture's index in a bindless array,
+/// [`bindless::TextureHandle`](crate::bindless::TextureHandle).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GpuTextureHandle(RawHandle);
+
+#[derive(Error, Debug)]
+pub enum HandleError {
+    #[error("{0:?} was destroyed or belongs to a lost device")]
+    StaleBuffer(BufferHandle),
+    #[error("{0:?} was destroyed or belongs to a lost device")]
+    StaleImage(ImageHandle),
+    #[error("{0:?} was destroyed or belongs to a lost device")]
+    StaleTexture(GpuTextureHandle),
+}
+
+/// Buffers, images and textures owned by the app and passed around as typed handles, so one kind can't be handed
+/// where another is expected. Destroying one stops its handle from resolving right away and queues it until the frame
+/// timeline reaches the value of the last frame that may still use it. The objects belong to the current device and are dropped, making their
+/// handles stale, when it is lost; recreate them through `App::register_resource`.
+pub struct GpuResources {
+    buffers: Arena<Buffer>,
+    images: Arena<Image>,
+    textures: Arena<Texture>,
+    /// Timeline value objects destroyed now are retired at.
+    retire_value: u64,
+    retired_buffers: RetireQueue<Buffer>,
+    retired_images: RetireQueue<Image>,
+    retired_textures: RetireQueue<Texture>,
+}
+
+impl GpuResources {
+    pub fn new() -> Self {
+        Self {
+            buffers: Arena::new(),
+            images: Arena::new(),
+            textures: Arena::new(),
+            retire_value: 0,
+            retired_buffers: RetireQueue::new(),
+            retired_images: RetireQueue::new(),
+            retired_textures: RetireQueue::new(),
+        }
+    }
+
+    pub fn insert_buffer(&mut self, buffer: Buffer) -> BufferHandle {
+        BufferHandle(self.buffers.insert(buffer))
+    }
+
+    pub fn buffer(&self, handle: BufferHandle) -> Option<&Buffer> {
+        self.buffers.get(handle.0)
+    }
+
+    /// Like [`buffer`](Self::buffer), for entry points that fail on a stale handle.
+    pub fn try_buffer(&self, handle: BufferHandle) -> Result<&Buffer, HandleError> {
+        self.buffer(handle).ok_or(HandleError::StaleBuffer(handle))
+    }
+
+    pub fn buffer_mut(&mut self, handle: BufferHandle) -> Option<&mut Buffer> {
+        self.buffers.get_mut(handle.0)
+    }
+
+    pub fn destroy_buffer(&mut self, handle: BufferHandle) {
+        if let Some(buffer) = self.buffers.remove(handle.0) {
+            self.retired_buffers.retire(self.retire_value, buffer);
+        }
+    }
+
+    pub fn insert_image(&mut self, image: Image) -> ImageHandle {
+        ImageHandle(self.images.insert(image))
+    }
+
+    pub fn image(&self, handle: ImageHandle) -> Option<&Image> {
+        self.images.get(handle.0)
+    }
+
+    pub fn try_image(&self, handle: ImageHandle) -> Result<&Image, HandleError> {
+        self.image(handle).ok_or(HandleError::StaleImage(handle))
+    }
+
+    pub fn image_mut(&mut self, handle: ImageHandle) -> Option<&mut Image> {
+        self.images.get_mut(handle.0)
+    }
+
+    pub fn destroy_image(&mut self, handle: ImageHandle) {
+        if let Some(image) = self.images.remove(handle.0) {
+            self.retired_images.retire(self.retire_value, image);
+        }
+    }
+
+    pub fn insert_texture(&mut self, texture: Texture) -> GpuTextureHandle {
+        GpuTextureHandle(self.textures.insert(texture))
+    }
+
+    pub fn texture(&self, handle: GpuTextureHandle) -> Option<&Texture> {
+        self.textures.get(handle.0)
+    }
+
+    pub fn try_texture(&self, handle: GpuTextureHandle) -> Result<&Texture, HandleError> {
+        self.texture(handle).ok_or(HandleError::StaleTexture(handle))
+    }
+
+    pub fn texture_mut(&mut self, handle: GpuTextureHandle) -> Option<&mut Texture> {
+        self.textures.get_mut(handle.0)
+    }
+
+    pub fn destroy_texture(&mut self, handle: GpuTextureHandle) {
+        if let Some(texture) = self.textures.remove(handle.0) {
+            self.retired_textures.retire(self.retire_value, texture);
+        }
+    }
+
+    /// Frees what was retired at or before `completed`, the frame timeline's current value, and retires what is
+    /// destroyed from now on at `pending`, the value the frame being recorded will signal.
+    pub fn begin_frame(&mut self, completed: u64, pending: u64) {
+        self.retire_value = pending;
+        self.retired_buffers.free_completed(completed);
+        self.retired_images.free_completed(completed);
+        self.retired_textures.free_completed(completed);
+    }
+
+    /// Drops everything at once, for when the device is idle or lost.
+    pub fn clear(&mut self) {
+        self.retired_buffers.clear();
+        self.retired_images.clear();
+        self.retired_textures.clear();
+        self.buffers.clear();
+        self.images.clear();
+        self.textures.clear();
+    }
+
+    /// How many destroyed objects are still waiting for their frames to finish.
+    pub fn pending_destruction(&self) -> usize {
+        self.retired_buffers.len() + self.retired_images.len() + self.retired_textures.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffers.len() + self.images.len() + self.textures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for GpuResources {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removed_handles_go_stale() {
+        let mut arena = Arena::new();
+        let first = arena.insert(1);
+        assert_eq!(arena.remove(first), Some(1));
+
+        let second = arena.insert(2);
+        assert_eq!(second.index, first.index);
+        assert_eq!(arena.get(first), None);
+        assert_eq!(arena.get(second), Some(&2));
+        assert_eq!(arena.remove(first), None);
+        assert_eq!(arena.len(), 1);
+    }
+
+    #[test]
+    fn clear_invalidates_every_handle() {
+        let mut arena = Arena::new();
+        let handles: Vec<RawHandle> = (0..3).map(|value| arena.insert(value)).collect();
+        assert_eq!(arena.clear(), vec![0, 1, 2]);
+        assert!(handles.iter().all(|&handle| arena.get(handle).is_none()));
+        assert_eq!(arena.len(), 0);
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+use log::{debug, error, info};
+use crate::device::Device;
+use crate::events::{UserEvent, WakeHandle};
+use crate::glsl::GlslCompiler;
+use crate::pipeline::{GraphicsPipeline, PipelineTarget};
+use crate::shader::ShaderModule;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Default)]
+struct WatchState {
+    modified: HashMap<PathBuf, Option<SystemTime>>,
+    changed: HashSet<PathBuf>,
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Polls the modification time of watched files on a background thread and wakes the run loop with
+/// `UserEvent::ShaderReloaded` when one changes.
+pub struct FileWatcher {
+    state: Arc<Mutex<WatchState>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FileWatcher {
+    pub fn new(wake: WakeHandle) -> Self {
+        let state = Arc::new(Mutex::new(WatchState::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let state = state.clone();
+            let stop = stop.clone();
+
+            std::thread::Builder::new()
+                .name("legaming-file-watcher".into())
+                .spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        std::thread::sleep(POLL_INTERVAL);
+
+                        let mut state = state.lock().unwrap();
+                        let mut any_changed = false;
+                        let WatchState { modified, changed } = &mut *state;
+
+                        for (path, last_modified) in modified.iter_mut() {
+                            let current = modified_time(path);
+                            if current != *last_modified {
+                                *last_modified = current;
+                                any_changed |= changed.insert(path.clone());
+                            }
+                        }
+
+                        if any_changed {
+                            let _ = wake.send(UserEvent::ShaderReloaded);
+                        }
+                    }
+                })
+                .ok()
+        };
+
+        Self { state, stop, thread }
+    }
+
+    pub fn watch(&self, path: impl AsRef<Path>) {
+        let path = path.as_ref().to_owned();
+        let modified = modified_time(&path);
+        self.state.lock().unwrap().modified.entry(path).or_insert(modified);
+    }
+
+    /// Returns every watched path that changed since the last call.
+    pub fn take_changed(&self) -> Vec<PathBuf> {
+        self.state.lock().unwrap().changed.drain().collect()
+    }
+}
+
+impl Drop for FileWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Builds a pipeline from its shader modules for `target`, the main pass the registry was created for.
+pub type PipelineRecipe = Box<dyn Fn(&Arc<Device>, &[ShaderModule], &PipelineTarget) -> anyhow::Result<GraphicsPipeline>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PipelineHandle(usize);
+
+struct RegisteredPipeline {
+    name: String,
+    sources: Vec<PathBuf>,
+    dependencies: Vec<PathBuf>,
+    recipe: PipelineRecipe,
+    pipeline: GraphicsPipeline,
+}
+
+/// Owns pipelines built from shader files so they can be found and rebuilt when a source changes. Files ending in
+/// `.spv` are loaded as SPIR-V, anything else is compiled as GLSL.
+pub struct PipelineRegistry {
+    device: Arc<Device>,
+    target: PipelineTarget,
+    compiler: GlslCompiler,
+    pipelines: Vec<RegisteredPipeline>,
+    watcher: Option<FileWatcher>,
+}
+
+impl PipelineRegistry {
+    pub fn new(device: &Arc<Device>, target: PipelineTarget, compiler: GlslCompiler) -> Self {
+        Self {
+            device: device.clone(),
+            target,
+            compiler,
+            pipelines: Vec::new(),
+            watcher: None,
+        }
+    }
+
+    /// Starts watching the sources of every registered pipeline, now and in the future.
+    pub fn enable_hot_reload(&mut self, wake: WakeHandle) {
+        let watcher = FileWatcher::new(wake);
+        for path in self.pipelines.iter().flat_map(|pipeline| pipeline.sources.iter().chain(&pipeline.dependencies)) {
+            watcher.watch(path);
+        }
+
+        self.watcher = Some(watcher);
+        info!("Shader hot reload enabled");
+    }
+
+    pub fn is_hot_reload_enabled(&self) -> bool {
+        self.watcher.is_some()
+    }
+
+    pub fn compiler(&self) -> &GlslCompiler {
+        &self.compiler
+    }
+
+    unsafe fn load_modules(&self, sources: &[PathBuf]) -> anyhow::Result<Vec<ShaderModule>> {
+        sources.iter()
+            .map(|path| if path.extension().is_some_and(|extension| extension == "spv") {
+                ShaderModule::from_file(&self.device, path)
+            } else {
+                ShaderModule::from_glsl_file(&self.device, &self.compiler, path)
+            })
+            .collect()
+    }
+
+    pub unsafe fn register(
+        &mut self,
+        name: &str,
+        sources: &[PathBuf],
+        recipe: PipelineRecipe,
+    ) -> anyhow::Result<PipelineHandle> {
+        let modules = self.load_modules(sources)?;
+        let pipeline = recipe(&self.device, &modules, &self.target)?;
+
+        if let Some(watcher) = &self.watcher {
+            for path in sources {
+                watcher.watch(path);
+            }
+        }
+
+        self.pipelines.push(RegisteredPipeline {
+            name: name.to_owned(),
+            sources: sources.to_vec(),
+            dependencies: Vec::new(),
+            recipe,
+            pipeline,
+        });
+
+        Ok(PipelineHandle(self.pipelines.len() - 1))
+    }
+
+    /// Rebuilds `handle` whenever `path` changes too, e.g. for a file pulled in with `#include`.
+    pub fn add_dependency(&mut self, handle: PipelineHandle, path: impl AsRef<Path>) {
+        let path = path.as_ref().to_owned();
+
+        if let Some(watcher) = &self.watcher {
+            watcher.watch(&path);
+        }
+
+        self.pipelines[handle.0].dependencies.push(path);
+    }
+
+    pub fn get(&self, handle: PipelineHandle) -> &GraphicsPipeline {
+        &self.pipelines[handle.0].pipeline
+    }
+
+    /// Rebuilds every pipeline whose sources changed since the last call. A pipeline that fails to rebuild keeps its
+    /// previous version and the error is logged. Waits for the device to go idle before swapping, so call this at
+    /// the start of a frame before anything is recorded. Returns the number of pipelines that were replaced.
+    pub unsafe fn apply_changes(&mut self) -> anyhow::Result<usize> {
+        let Some(watcher) = &self.watcher else {
+            return Ok(0);
+        };
+
+        let changed = watcher.take_changed();
+        if changed.is_empty() {
+            return Ok(0);
+        }
+
+        debug!("Changed shader files: {:?}", changed);
+
+        let mut rebuilt = Vec::new();
+        for (index, pipeline) in self.pipelines.iter().enumerate() {
+            let affected = pipeline.sources.iter()
+                .chain(&pipeline.dependencies)
+                .any(|path| changed.contains(path));
+
+            if !affected {
+                continue;
+            }
+
+            match self.load_modules(&pipeline.sources).and_then(|modules| (pipeline.recipe)(&self.device, &modules, &self.target)) {
+                Ok(new_pipeline) => rebuilt.push((index, new_pipeline)),
+                Err(err) => error!("Failed to reload pipeline '{}': {:?}", pipeline.name, err),
+            }
+        }
+
+        if rebuilt.is_empty() {
+            return Ok(0);
+        }
+
+        self.device.device_wait_idle()?;
+
+        let count = rebuilt.len();
+        for (index, new_pipeline) in rebuilt {
+            info!("Reloaded pipeline '{}'", self.pipelines[index].name);
+            self.pipelines[index].pipeline = new_pipeline;
+        }
+
+        Ok(count)
+    }
+
+    /// Rebuilds every pipeline on `device` for `target`, after the device they were created on was lost.
+    pub unsafe fn recreate(&mut self, device: &Arc<Device>, target: PipelineTarget) -> anyhow::Result<()> {
+        self.device = device.clone();
+        self.target = target;
+
+        for index in 0..self.pipelines.len() {
+            let pipeline = &self.pipelines[index];
+            let modules = self.load_modules(&pipeline.sources)?;
+            let new_pipeline = (pipeline.recipe)(&self.device, &modules, &self.target)
+                .map_err(|err| err.context(format!("Failed to recreate pipeline '{}'", pipeline.name)))?;
+
+            self.pipelines[index].pipeline = new_pipeline;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,437 @@
+use std::sync::Arc;
+use anyhow::anyhow;
+use ash::vk;
+use crate::allocator::{Allocation, MemoryLocation};
+use crate::buffer::Buffer;
+use crate::commands::submit_one_time;
+use crate::device::Device;
+use crate::format::has_stencil_component;
+
+/// Number of mip levels in a full chain down to 1x1.
+pub fn mip_levels_for(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+pub fn aspect_for_format(format: vk::Format) -> vk::ImageAspectFlags {
+    match format {
+        vk::Format::D16_UNORM | vk::Format::D32_SFLOAT | vk::Format::X8_D24_UNORM_PACK32 => vk::ImageAspectFlags::DEPTH,
+        vk::Format::S8_UINT => vk::ImageAspectFlags::STENCIL,
+        format if has_stencil_component(format) => vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL,
+        _ => vk::ImageAspectFlags::COLOR,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageDesc {
+    pub width: u32,
+    pub height: u32,
+    pub format: vk::Format,
+    pub usage: vk::ImageUsageFlags,
+    pub mip_levels: u32,
+    pub array_layers: u32,
+    pub samples: vk::SampleCountFlags,
+    pub flags: vk::ImageCreateFlags,
+    pub view_type: vk::ImageViewType,
+}
+
+impl ImageDesc {
+    /// A single-sampled 2D image with one mip level and layer.
+    pub fn new_2d(width: u32, height: u32, format: vk::Format, usage: vk::ImageUsageFlags) -> Self {
+        Self {
+            width,
+            height,
+            format,
+            usage,
+            mip_levels: 1,
+            array_layers: 1,
+            samples: vk::SampleCountFlags::TYPE_1,
+            flags: vk::ImageCreateFlags::empty(),
+            view_type: vk::ImageViewType::TYPE_2D,
+        }
+    }
+
+    pub fn with_mip_levels(mut self, mip_levels: u32) -> Self {
+        self.mip_levels = mip_levels;
+        self
+    }
+
+    pub fn with_samples(mut self, samples: vk::SampleCountFlags) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    /// `layers` layers viewed as a 2D array.
+    pub fn array(mut self, layers: u32) -> Self {
+        self.array_layers = layers;
+        self.view_type = vk::ImageViewType::TYPE_2D_ARRAY;
+        self
+    }
+
+    /// Six layers viewed as a cube map.
+    pub fn cube(mut self) -> Self {
+        self.array_layers = 6;
+        self.flags |= vk::ImageCreateFlags::CUBE_COMPATIBLE;
+        self.view_type = vk::ImageViewType::CUBE;
+        self
+    }
+}
+
+/// An optimally tiled device local image with its memory and a view over every mip level and layer.
+pub struct Image {
+    device: Arc<Device>,
+    handle: vk::Image,
+    view: vk::ImageView,
+    allocation: Option<Allocation>,
+    desc: ImageDesc,
+    aspect: vk::ImageAspectFlags,
+}
+
+impl Image {
+    pub unsafe fn new(device: &Arc<Device>, name: &str, desc: &ImageDesc) -> anyhow::Result<Self> {
+        let handle = create_image_handle(device, desc)?;
+        let allocation = match device.allocate_image_memory(handle, MemoryLocation::GpuOnly, name) {
+            Ok(allocation) => allocation,
+            Err(err) => {
+                device.destroy_image(handle, None);
+                return Err(err);
+            }
+        };
+
+        let aspect = aspect_for_format(desc.format);
+        let view = match create_image_view(device, handle, desc) {
+            Ok(view) => view,
+            Err(err) => {
+                device.destroy_image(handle, None);
+                device.free(allocation);
+                return Err(err);
+            }
+        };
+
+        Ok(Self {
+            device: device.clone(),
+            handle,
+            view,
+            allocation: Some(allocation),
+            desc: *desc,
+            aspect,
+        })
+    }
+
+    pub fn handle(&self) -> vk::Image {
+        self.handle
+    }
+
+    pub fn view(&self) -> vk::ImageView {
+        self.view
+    }
+
+    pub fn desc(&self) -> &ImageDesc {
+        &self.desc
+    }
+
+    pub fn format(&self) -> vk::Format {
+        self.desc.format
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        vk::Extent2D {
+            width: self.desc.width,
+            height: self.desc.height,
+        }
+    }
+
+    pub fn mip_levels(&self) -> u32 {
+        self.desc.mip_levels
+    }
+
+    pub fn aspect(&self) -> vk::ImageAspectFlags {
+        self.aspect
+    }
+
+    pub fn full_range(&self) -> vk::ImageSubresourceRange {
+        full_range(&self.desc)
+    }
+
+    /// Records a transition of every mip level and layer from `old_layout` to `new_layout`.
+    pub unsafe fn transition(&self, command_buffer: vk::CommandBuffer, old_layout: vk::ImageLayout, new_layout: vk::ImageLayout) {
+        transition_layout(&self.device, command_buffer, self.handle, self.full_range(), old_layout, new_layout);
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_image_view(self.view, None);
+            self.device.destroy_image(self.handle, None);
+
+            if let Some(allocation) = self.allocation.take() {
+                self.device.free(allocation);
+            }
+        }
+    }
+}
+
+/// Creates an optimally tiled image for `desc` without binding any memory to it.
+pub(crate) unsafe fn create_image_handle(device: &Device, desc: &ImageDesc) -> anyhow::Result<vk::Image> {
+    let create_info = vk::ImageCreateInfo::builder()
+        .flags(desc.flags)
+        .image_type(vk::ImageType::TYPE_2D)
+        .format(desc.format)
+        .extent(vk::Extent3D {
+            width: desc.width,
+            height: desc.height,
+            depth: 1,
+        })
+        .mip_levels(desc.mip_levels)
+        .array_layers(desc.array_layers)
+        .samples(desc.samples)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .usage(desc.usage)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .initial_layout(vk::ImageLayout::UNDEFINED);
+
+    Ok(device.create_image(&create_info, None)?)
+}
+
+/// Creates a view over every mip level and layer of `image`.
+pub(crate) unsafe fn create_image_view(device: &Device, image: vk::Image, desc: &ImageDesc) -> anyhow::Result<vk::ImageView> {
+    let view_create_info = vk::ImageViewCreateInfo::builder()
+        .image(image)
+        .view_type(desc.view_type)
+        .format(desc.format)
+        .subresource_range(full_range(desc));
+
+    Ok(device.create_image_view(&view_create_info, None)?)
+}
+
+/// Every mip level and layer of an image created from `desc`.
+pub fn full_range(desc: &ImageDesc) -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask: aspect_for_format(desc.format),
+        base_mip_level: 0,
+        level_count: desc.mip_levels,
+        base_array_layer: 0,
+        layer_count: desc.array_layers,
+    }
+}
+
+/// Stages and access masks that a layout is produced or consumed with, for the common layouts.
+fn layout_sync(layout: vk::ImageLayout) -> (vk::PipelineStageFlags, vk::AccessFlags) {
+    match layout {
+        vk::ImageLayout::UNDEFINED => (vk::PipelineStageFlags::TOP_OF_PIPE, vk::AccessFlags::empty()),
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL => (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE),
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL => (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_READ),
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL => (
+            vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::SHADER_READ,
+        ),
+        vk::ImageLayout::GENERAL => (
+            vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+        ),
+        vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL => (
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+        ),
+        vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL | vk::ImageLayout::DEPTH_ATTACHMENT_OPTIMAL => (
+            vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+        ),
+        vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL | vk::ImageLayout::DEPTH_READ_ONLY_OPTIMAL => (
+            vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+            vk::AccessFlags::SHADER_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ,
+        ),
+        vk::ImageLayout::PRESENT_SRC_KHR => (vk::PipelineStageFlags::BOTTOM_OF_PIPE, vk::AccessFlags::empty()),
+        _ => (vk::PipelineStageFlags::ALL_COMMANDS, vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE),
+    }
+}
+
+/// Records an image memory barrier for `range`, deriving stages and access masks from the two layouts.
+pub unsafe fn transition_layout(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
+    image: vk::Image,
+    range: vk::ImageSubresourceRange,
+    old_layout: vk::ImageLayout,
+    new_layout: vk::ImageLayout,
+) {
+    let (src_stage, src_access) = layout_sync(old_layout);
+    let (dst_stage, dst_access) = layout_sync(new_layout);
+
+    let barrier = vk::ImageMemoryBarrier::builder()
+        .src_access_mask(src_access)
+        .dst_access_mask(dst_access)
+        .old_layout(old_layout)
+        .new_layout(new_layout)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .image(image)
+        .subresource_range(range)
+        .build();
+
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        src_stage,
+        dst_stage,
+        vk::DependencyFlags::empty(),
+        &[],
+        &[],
+        &[barrier],
+    );
+}
+
+/// A sampled image uploaded from pixel data, left in `SHADER_READ_ONLY_OPTIMAL`.
+pub struct Texture {
+    image: Image,
+}
+
+impl Texture {
+    /// Uploads tightly packed pixels for mip level 0 of every layer (layer after layer) and, when `mipmaps` is
+    /// set, generates the rest of the chain with linear blits. Falls back to a single level when the format cannot
+    /// be blitted with linear filtering.
+    pub unsafe fn from_pixels(
+        device: &Arc<Device>,
+        name: &str,
+        desc: ImageDesc,
+        pixels: &[u8],
+        mipmaps: bool,
+    ) -> anyhow::Result<Self> {
+        let format_properties = device.instance()
+            .get_physical_device_format_properties(device.physical_device(), desc.format);
+        let can_blit = format_properties.optimal_tiling_features.contains(
+            vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR
+                | vk::FormatFeatureFlags::BLIT_SRC
+                | vk::FormatFeatureFlags::BLIT_DST,
+        );
+
+        let mip_levels = if mipmaps && can_blit { mip_levels_for(desc.width, desc.height) } else { 1 };
+        let desc = ImageDesc {
+            mip_levels,
+            usage: desc.usage | vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::TRANSFER_SRC,
+            ..desc
+        };
+
+        let image = Image::new(device, name, &desc)?;
+
+        let mut staging = Buffer::new(device, "texture staging", pixels.len() as vk::DeviceSize, vk::BufferUsageFlags::TRANSFER_SRC, MemoryLocation::CpuToGpu)?;
+        staging.write(0, pixels)?;
+
+        let layer_size = pixels.len() as vk::DeviceSize / desc.array_layers as vk::DeviceSize;
+        if layer_size == 0 {
+            return Err(anyhow!("Texture '{}' has no pixel data", name));
+        }
+
+        let regions: Vec<vk::BufferImageCopy> = (0..desc.array_layers)
+            .map(|layer| vk::BufferImageCopy {
+                buffer_offset: layer as vk::DeviceSize * layer_size,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: image.aspect(),
+                    mip_level: 0,
+                    base_array_layer: layer,
+                    layer_count: 1,
+                },
+                image_offset: vk::Offset3D::default(),
+                image_extent: vk::Extent3D {
+                    width: desc.width,
+                    height: desc.height,
+                    depth: 1,
+                },
+            })
+            .collect();
+
+        submit_one_time(device, device.queue_families().graphics, device.graphics_queue(), |command_buffer| {
+            image.transition(command_buffer, vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL);
+            device.cmd_copy_buffer_to_image(command_buffer, staging.handle(), image.handle(), vk::ImageLayout::TRANSFER_DST_OPTIMAL, &regions);
+            generate_mipmaps(device, command_buffer, &image);
+        })?;
+
+        Ok(Self { image })
+    }
+
+    /// An sRGB RGBA8 texture from `width * height * 4` bytes.
+    pub unsafe fn from_rgba8(
+        device: &Arc<Device>,
+        name: &str,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+        mipmaps: bool,
+    ) -> anyhow::Result<Self> {
+        if pixels.len() != width as usize * height as usize * 4 {
+            return Err(anyhow!("Texture '{}' expects {} bytes of RGBA8, got {}", name, width * height * 4, pixels.len()));
+        }
+
+        let desc = ImageDesc::new_2d(width, height, vk::Format::R8G8B8A8_SRGB, vk::ImageUsageFlags::SAMPLED);
+        Self::from_pixels(device, name, desc, pixels, mipmaps)
+    }
+
+    /// Wraps an image the caller filled and left in `SHADER_READ_ONLY_OPTIMAL`.
+    pub fn from_image(image: Image) -> Self {
+        Self { image }
+    }
+
+    pub fn image(&self) -> &Image {
+        &self.image
+    }
+
+    pub fn view(&self) -> vk::ImageView {
+        self.image.view()
+    }
+}
+
+/// Expects every level in `TRANSFER_DST_OPTIMAL` and leaves every level in `SHADER_READ_ONLY_OPTIMAL`, blitting each
+/// level from the one above it.
+pub unsafe fn generate_mipmaps(device: &Device, command_buffer: vk::CommandBuffer, image: &Image) {
+    let desc = image.desc();
+    let level_range = |level: u32| vk::ImageSubresourceRange {
+        aspect_mask: image.aspect(),
+        base_mip_level: level,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: desc.array_layers,
+    };
+
+    let level_layers = |level: u32| vk::ImageSubresourceLayers {
+        aspect_mask: image.aspect(),
+        mip_level: level,
+        base_array_layer: 0,
+        layer_count: desc.array_layers,
+    };
+
+    let mut width = desc.width as i32;
+    let mut height = desc.height as i32;
+
+    for level in 1..desc.mip_levels {
+        transition_layout(device, command_buffer, image.handle(), level_range(level - 1), vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::TRANSFER_SRC_OPTIMAL);
+
+        let next_width = (width / 2).max(1);
+        let next_height = (height / 2).max(1);
+
+        let blit = vk::ImageBlit {
+            src_subresource: level_layers(level - 1),
+            src_offsets: [vk::Offset3D::default(), vk::Offset3D { x: width, y: height, z: 1 }],
+            dst_subresource: level_layers(level),
+            dst_offsets: [vk::Offset3D::default(), vk::Offset3D { x: next_width, y: next_height, z: 1 }],
+        };
+
+        device.cmd_blit_image(
+            command_buffer,
+            image.handle(),
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            image.handle(),
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[blit],
+            vk::Filter::LINEAR,
+        );
+
+        transition_layout(device, command_buffer, image.handle(), level_range(level - 1), vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+
+        width = next_width;
+        height = next_height;
+    }
+
+    let last = desc.mip_levels - 1;
+    transition_layout(device, command_buffer, image.handle(), level_range(last), vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+}
@@ -0,0 +1,354 @@
+use std::sync::Arc;
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use log::debug;
+use crate::allocator::MemoryLocation;
+use crate::buffer::Buffer;
+use crate::compute::{memory_barrier, Access, ComputePipeline, ComputePipelineBuilder};
+use crate::culling::Frustum;
+use crate::descriptors::{DescriptorAllocator, DescriptorLayoutCache, DescriptorWriter, SetLayoutDesc};
+use crate::device::Device;
+use crate::glsl::GlslCompiler;
+use crate::image::{ImageDesc, Texture};
+use crate::occlusion::{HiZPyramid, HIZ_FORMAT};
+use crate::renderer3d::MeshData;
+use crate::sampler::SamplerDesc;
+use crate::shader::ShaderModule;
+
+const INDIRECT_CULL_COMP: &str = include_str!("../shaders/indirect_cull.comp");
+
+const WORKGROUP_SIZE: u32 = 64;
+
+/// Objects each frame's buffers have room for before they first grow.
+const INITIAL_CAPACITY: usize = 1024;
+
+const DRAW_COMMAND_SIZE: vk::DeviceSize = std::mem::size_of::<vk::DrawIndexedIndirectCommand>() as vk::DeviceSize;
+
+const COMPUTE_READ_WRITE: Access = Access {
+    stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+    access: vk::AccessFlags::from_raw(vk::AccessFlags::SHADER_READ.as_raw() | vk::AccessFlags::SHADER_WRITE.as_raw()),
+};
+
+/// Set in [`GpuObject::flags`] for objects drawn into the shadow maps.
+pub const CASTS_SHADOWS: u32 = 1;
+
+/// Set in [`GpuObject::flags`] for objects drawn into the main pass when they pass culling. Objects without it
+/// only cast shadows, and their `group` is ignored.
+pub const DRAWN: u32 = 2;
+
+/// Where one mesh lives in a [`MeshArena`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MeshRange {
+    pub first_index: u32,
+    pub index_count: u32,
+    pub vertex_offset: i32,
+}
+
+/// The vertices and indices of many meshes in one pair of buffers, so a single indirect draw can reach all of them.
+pub struct MeshArena {
+    vertex_buffer: Buffer,
+    index_buffer: Buffer,
+    ranges: Vec<MeshRange>,
+}
+
+impl MeshArena {
+    pub unsafe fn new<'m>(device: &Arc<Device>, meshes: impl IntoIterator<Item = &'m MeshData>) -> anyhow::Result<Self> {
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        let mut ranges = Vec::new();
+
+        for mesh in meshes {
+            ranges.push(MeshRange {
+                first_index: indices.len() as u32,
+                index_count: mesh.indices.len() as u32,
+                vertex_offset: vertices.len() as i32,
+            });
+            vertices.extend_from_slice(&mesh.vertices);
+            indices.extend_from_slice(&mesh.indices);
+        }
+
+        debug!("Built a mesh arena of {} meshes, {} vertices and {} indices", ranges.len(), vertices.len(), indices.len());
+        Ok(Self {
+            vertex_buffer: Buffer::vertex(device, "mesh arena vertices", &vertices)?,
+            index_buffer: Buffer::index(device, "mesh arena indices", &indices)?,
+            ranges,
+        })
+    }
+
+    pub fn mesh_count(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// The range of the `index`th mesh the arena was built from.
+    pub fn range(&self, index: usize) -> MeshRange {
+        self.ranges[index]
+    }
+
+    pub unsafe fn bind(&self, device: &Device, command_buffer: vk::CommandBuffer) {
+        device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.vertex_buffer.handle()], &[0]);
+        device.cmd_bind_index_buffer(command_buffer, self.index_buffer.handle(), 0, vk::IndexType::UINT32);
+    }
+}
+
+/// An object as the culling pass sees it: a world space bounding sphere, the [`MeshRange`] it draws and where its
+/// group's commands start. Its instance data is at the same index in the instance buffer.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct GpuObject {
+    pub sphere: [f32; 4],
+    pub index_count: u32,
+    pub first_index: u32,
+    pub vertex_offset: i32,
+    pub group: u32,
+    /// Index of the first command of `group`, which has room for every object in it.
+    pub first_command: u32,
+    pub flags: u32,
+    pub _padding: [u32; 2],
+}
+
+unsafe impl Zeroable for GpuObject {}
+unsafe impl Pod for GpuObject {}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct CullingParams {
+    planes: [[f32; 4]; 6],
+    object_count: u32,
+    shadow_group: u32,
+    shadow_first_command: u32,
+}
+
+unsafe impl Zeroable for CullingParams {}
+unsafe impl Pod for CullingParams {}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct OcclusionParams {
+    view_projection: [[f32; 4]; 4],
+    depth_size: [f32; 2],
+    mip_levels: u32,
+    enabled: u32,
+}
+
+unsafe impl Zeroable for OcclusionParams {}
+unsafe impl Pod for OcclusionParams {}
+
+struct FrameBuffers {
+    objects: Buffer,
+    commands: Buffer,
+    counts: Buffer,
+    occlusion: Buffer,
+    set: vk::DescriptorSet,
+    capacity: usize,
+    object_count: u32,
+    group_count: u32,
+}
+
+/// Builds the frame's draw commands on the GPU. Objects are sorted into groups that are drawn with the same
+/// pipeline and descriptor sets; a compute pass appends a command for every object that passes culling to its
+/// group's range of the command buffer and counts them, so each group takes a single `vkCmdDrawIndexedIndirectCount`.
+/// An extra group after the others holds every shadow caster for the shadow passes. Given a [`HiZPyramid`] of the
+/// previous frame, objects hidden behind what it drew are left out as well.
+pub struct IndirectCulling {
+    device: Arc<Device>,
+    _layouts: DescriptorLayoutCache,
+    descriptor_allocator: DescriptorAllocator,
+    set_layout: vk::DescriptorSetLayout,
+    pipeline: ComputePipeline,
+    frames: Vec<FrameBuffers>,
+    /// Bound in place of a pyramid when culling without one.
+    no_occlusion: Texture,
+}
+
+impl IndirectCulling {
+    pub unsafe fn new(device: &Arc<Device>, compiler: &GlslCompiler, frames_in_flight: usize) -> anyhow::Result<Self> {
+        let mut layouts = DescriptorLayoutCache::new(device);
+        let set_layout = layouts.get(&SetLayoutDesc::new()
+            .binding(0, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE)
+            .binding(1, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE)
+            .binding(2, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE)
+            .binding(3, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE)
+            .binding(4, vk::DescriptorType::UNIFORM_BUFFER, vk::ShaderStageFlags::COMPUTE))?;
+
+        let shader = ShaderModule::from_bytes_with_stage(
+            device,
+            "indirect_cull.comp",
+            &compiler.compile_source(INDIRECT_CULL_COMP, vk::ShaderStageFlags::COMPUTE, "indirect_cull.comp")?,
+            vk::ShaderStageFlags::COMPUTE,
+        )?;
+
+        let pipeline = ComputePipelineBuilder::new()
+            .shader(&shader)
+            .descriptor_set_layout(set_layout)
+            .push_constants::<CullingParams>(0)
+            .build(device)?;
+
+        let mut descriptor_allocator = DescriptorAllocator::new(device);
+        let frames = (0..frames_in_flight)
+            .map(|_| create_frame_buffers(device, &mut descriptor_allocator, set_layout, INITIAL_CAPACITY))
+            .collect::<anyhow::Result<_>>()?;
+
+        let no_occlusion = Texture::from_pixels(
+            device,
+            "no occlusion",
+            ImageDesc::new_2d(1, 1, HIZ_FORMAT, vk::ImageUsageFlags::SAMPLED),
+            bytemuck::bytes_of(&1.0f32),
+            false,
+        )?;
+
+        Ok(Self {
+            device: device.clone(),
+            _layouts: layouts,
+            descriptor_allocator,
+            set_layout,
+            pipeline,
+            frames,
+            no_occlusion,
+        })
+    }
+
+    /// Uploads `objects` for `frame_index` and records the culling pass against `frustum`, followed by a barrier
+    /// that makes the commands visible to indirect draws. Record it outside of any render pass. Group indices must
+    /// be below `group_count`. Objects are also tested against `occlusion` when it has been built; shadow casters
+    /// never are.
+    pub unsafe fn record(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        objects: &[GpuObject],
+        group_count: u32,
+        frustum: &Frustum,
+        occlusion: Option<&HiZPyramid>,
+    ) -> anyhow::Result<()> {
+        if objects.len() > self.frames[frame_index].capacity {
+            let capacity = objects.len().next_power_of_two();
+            debug!("Growing the indirect draw buffers of frame {} to {} objects", frame_index, capacity);
+            self.frames[frame_index] = create_frame_buffers(&self.device, &mut self.descriptor_allocator, self.set_layout, capacity)?;
+        }
+
+        let frame = &mut self.frames[frame_index];
+        frame.objects.write(0, objects)?;
+        frame.object_count = objects.len() as u32;
+        frame.group_count = group_count;
+
+        let occlusion = occlusion.and_then(|hiz| Some((hiz.view()?, hiz.view_projection()?, hiz)));
+        let (hiz_view, hiz_layout, params) = match occlusion {
+            Some((view, view_projection, hiz)) => {
+                let extent = hiz.depth_extent();
+                (view, vk::ImageLayout::GENERAL, OcclusionParams {
+                    view_projection: view_projection.into(),
+                    depth_size: [extent.width as f32, extent.height as f32],
+                    mip_levels: hiz.mip_levels(),
+                    enabled: 1,
+                })
+            }
+            None => (self.no_occlusion.view(), vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, OcclusionParams::zeroed()),
+        };
+
+        frame.occlusion.write(0, &[params])?;
+        DescriptorWriter::new()
+            .image(
+                3,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                hiz_view,
+                self.device.sampler(&SamplerDesc::nearest())?,
+                hiz_layout,
+            )
+            .update(&self.device, frame.set);
+
+        self.device.cmd_fill_buffer(command_buffer, frame.counts.handle(), 0, vk::WHOLE_SIZE, 0);
+        memory_barrier(&self.device, command_buffer, Access::TRANSFER_WRITE, COMPUTE_READ_WRITE);
+
+        self.pipeline.bind(command_buffer);
+        self.pipeline.bind_descriptor_sets(command_buffer, 0, &[frame.set]);
+        self.pipeline.push_constants(command_buffer, 0, &CullingParams {
+            planes: frustum.planes(),
+            object_count: frame.object_count,
+            shadow_group: group_count,
+            shadow_first_command: frame.object_count,
+        });
+        self.pipeline.dispatch(command_buffer, frame.object_count.div_ceil(WORKGROUP_SIZE), 1, 1);
+
+        memory_barrier(&self.device, command_buffer, Access::COMPUTE_WRITE, Access::INDIRECT);
+        Ok(())
+    }
+
+    /// Draws what the culling pass kept of `group`, which holds up to `max_count` objects starting at
+    /// `first_command`. The mesh arena and instance buffer must be bound.
+    pub unsafe fn draw_group(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        group: u32,
+        first_command: u32,
+        max_count: u32,
+    ) {
+        let frame = &self.frames[frame_index];
+        self.device.cmd_draw_indexed_indirect_count(
+            command_buffer,
+            frame.commands.handle(),
+            first_command as vk::DeviceSize * DRAW_COMMAND_SIZE,
+            frame.counts.handle(),
+            group as vk::DeviceSize * std::mem::size_of::<u32>() as vk::DeviceSize,
+            max_count,
+            DRAW_COMMAND_SIZE as u32,
+        );
+    }
+
+    /// Draws every shadow caster of the last recorded frame `frame_index`.
+    pub unsafe fn draw_shadow_casters(&self, command_buffer: vk::CommandBuffer, frame_index: usize) {
+        let frame = &self.frames[frame_index];
+        self.draw_group(command_buffer, frame_index, frame.group_count, frame.object_count, frame.object_count);
+    }
+}
+
+unsafe fn create_frame_buffers(
+    device: &Arc<Device>,
+    allocator: &mut DescriptorAllocator,
+    set_layout: vk::DescriptorSetLayout,
+    capacity: usize,
+) -> anyhow::Result<FrameBuffers> {
+    let indirect_storage = vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::INDIRECT_BUFFER;
+    let objects = Buffer::new(
+        device,
+        "indirect objects",
+        (capacity * std::mem::size_of::<GpuObject>()) as vk::DeviceSize,
+        vk::BufferUsageFlags::STORAGE_BUFFER,
+        MemoryLocation::CpuToGpu,
+    )?;
+    // The main groups and the shadow group each have room for every object.
+    let commands = Buffer::new(
+        device,
+        "indirect commands",
+        2 * capacity as vk::DeviceSize * DRAW_COMMAND_SIZE,
+        indirect_storage,
+        MemoryLocation::GpuOnly,
+    )?;
+    let counts = Buffer::new(
+        device,
+        "indirect counts",
+        ((capacity + 1) * std::mem::size_of::<u32>()) as vk::DeviceSize,
+        indirect_storage,
+        MemoryLocation::GpuOnly,
+    )?;
+    let occlusion = Buffer::uniform(device, "indirect occlusion", std::mem::size_of::<OcclusionParams>() as vk::DeviceSize)?;
+
+    let set = allocator.allocate(set_layout)?;
+    DescriptorWriter::new()
+        .buffer(0, vk::DescriptorType::STORAGE_BUFFER, objects.handle(), 0, vk::WHOLE_SIZE)
+        .buffer(1, vk::DescriptorType::STORAGE_BUFFER, commands.handle(), 0, vk::WHOLE_SIZE)
+        .buffer(2, vk::DescriptorType::STORAGE_BUFFER, counts.handle(), 0, vk::WHOLE_SIZE)
+        .buffer(4, vk::DescriptorType::UNIFORM_BUFFER, occlusion.handle(), 0, vk::WHOLE_SIZE)
+        .update(device, set);
+
+    Ok(FrameBuffers {
+        objects,
+        commands,
+        counts,
+        occlusion,
+        set,
+        capacity,
+        object_count: 0,
+        group_count: 0,
+    })
+}
@@ -0,0 +1,129 @@
+use std::ffi::{c_char, c_void, CStr};
+use std::ops::Deref;
+use std::sync::Arc;
+use ash::extensions::ext;
+use ash::vk;
+use ash::vk::StructureType;
+use log::info;
+use crate::validation::{
+    supports_layer_settings, DebugMessenger, MessageIdFilter, MessengerState, ValidationConfig, LAYER_SETTINGS_EXTENSION_NAME,
+    VALIDATION_LAYER_NAME,
+};
+
+/// Owns the `ash::Entry`, the `VkInstance` and the optional debug messenger. Everything created from the instance
+/// holds an `Arc<Instance>`, so it is destroyed last.
+pub struct Instance {
+    entry: ash::Entry,
+    handle: ash::Instance,
+    api_version: u32,
+    debug_messenger: Option<DebugMessenger>,
+}
+
+impl Instance {
+    /// Creates the instance with `extensions` enabled. When `validation` is given, `VK_LAYER_KHRONOS_validation` and
+    /// `VK_EXT_debug_utils` are enabled too and a debug messenger is attached; the caller is responsible for checking
+    /// that the layer is available.
+    pub unsafe fn new(
+        entry: ash::Entry,
+        api_version: u32,
+        extensions: &[&CStr],
+        validation: Option<ValidationConfig>,
+    ) -> anyhow::Result<Arc<Self>> {
+        let mut extensions = extensions.to_vec();
+        let mut layers = Vec::new();
+        let messenger_state = validation.map(MessengerState::new);
+        let messenger_create_info = messenger_state.as_ref().map(|state| state.create_info());
+
+        if messenger_state.is_some() {
+            extensions.push(ext::DebugUtils::name());
+            layers.push(VALIDATION_LAYER_NAME);
+        }
+
+        let messenger_ptr = messenger_create_info.as_ref().map_or(std::ptr::null(), |info| info as *const _ as *const c_void);
+        let message_id_filter = match &messenger_state {
+            Some(state) if supports_layer_settings(&entry)? => MessageIdFilter::new(state.config(), messenger_ptr),
+            _ => None,
+        };
+        if message_id_filter.is_some() {
+            extensions.push(LAYER_SETTINGS_EXTENSION_NAME);
+        }
+
+        let app_info = vk::ApplicationInfo::builder()
+            .api_version(api_version).build();
+
+        let extension_ptrs: Vec<*const c_char> = extensions.iter()
+            .map(|s| s.as_ptr())
+            .collect();
+
+        let layer_ptrs: Vec<*const c_char> = layers.iter()
+            .map(|s| s.as_ptr())
+            .collect();
+
+        let handle = entry.create_instance(&vk::InstanceCreateInfo {
+            s_type: StructureType::INSTANCE_CREATE_INFO,
+            p_next: message_id_filter.as_ref().map_or(messenger_ptr, |filter| filter.as_ptr()),
+            flags: Default::default(),
+            p_application_info: &app_info,
+            enabled_layer_count: layer_ptrs.len() as u32,
+            pp_enabled_layer_names: layer_ptrs.as_ptr(),
+            enabled_extension_count: extension_ptrs.len() as u32,
+            pp_enabled_extension_names: extension_ptrs.as_ptr(),
+        }, None)?;
+        info!("Created instance");
+
+        let debug_messenger = match messenger_state {
+            Some(state) => match DebugMessenger::new(&entry, &handle, state) {
+                Ok(messenger) => {
+                    info!("Validation enabled");
+                    Some(messenger)
+                }
+                Err(err) => {
+                    handle.destroy_instance(None);
+                    return Err(err);
+                }
+            },
+            None => None,
+        };
+
+        Ok(Arc::new(Self {
+            entry,
+            handle,
+            api_version,
+            debug_messenger,
+        }))
+    }
+
+    pub fn entry(&self) -> &ash::Entry {
+        &self.entry
+    }
+
+    pub fn handle(&self) -> &ash::Instance {
+        &self.handle
+    }
+
+    pub fn api_version(&self) -> u32 {
+        self.api_version
+    }
+
+    pub fn debug_messenger(&self) -> Option<&DebugMessenger> {
+        self.debug_messenger.as_ref()
+    }
+}
+
+impl Deref for Instance {
+    type Target = ash::Instance;
+
+    fn deref(&self) -> &Self::Target {
+        &self.handle
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        self.debug_messenger = None;
+
+        unsafe {
+            self.handle.destroy_instance(None);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+// Vulkan-facing functions are `unsafe` because they wrap raw ash calls; their contracts follow the Vulkan spec
+// rather than being restated on every function.
+#![allow(clippy::missing_safety_doc)]
+
+mod app;
+pub mod allocator;
+pub mod animation;
+pub mod benchmark;
+pub mod bindless;
+pub mod buffer;
+pub mod commands;
+pub mod compute;
+pub mod culling;
+pub mod debug_draw;
+pub mod debug_view;
+pub mod descriptors;
+pub mod device;
+pub mod events;
+pub mod format;
+pub mod frame_arena;
+pub mod glsl;
+pub mod handles;
+pub mod hot_reload;
+pub mod image;
+pub mod indirect;
+pub mod instance;
+pub mod lighting;
+pub mod lod;
+pub mod material;
+pub mod meshlet;
+pub mod occlusion;
+pub mod particles;
+pub mod physical_device;
+pub mod pipeline;
+pub mod pipeline_cache;
+pub mod platform;
+pub mod post;
+pub mod query;
+#[cfg(feature = "ray-tracing")]
+pub mod ray_tracing;
+pub mod recovery;
+pub mod reflect;
+pub mod render_graph;
+pub mod render_pass;
+pub mod renderer2d;
+pub mod renderer3d;
+pub mod rendering;
+pub mod requirements;
+pub mod sampler;
+pub mod shader;
+pub mod shadows;
+pub mod skinning;
+pub mod skybox;
+pub mod ssao;
+pub mod surface;
+pub mod swapchain;
+pub mod sync;
+pub mod terrain;
+pub mod text;
+pub mod timeline;
+pub mod upload;
+pub mod validation;
+pub mod validation_overlay;
+
+pub use app::{App, AppConfig, EngineBuilder, Frame, SmokeTestConfig, WindowConfig, VALIDATION_ENV_VAR};
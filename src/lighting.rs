@@ -0,0 +1,387 @@
+use std::sync::Arc;
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use cgmath::{InnerSpace, Matrix4, Point3, Rad, SquareMatrix, Vector3};
+use log::warn;
+use crate::allocator::MemoryLocation;
+use crate::buffer::{Buffer, PerFrameUniform};
+use crate::compute::{buffer_barrier, Access, ComputePipeline, ComputePipelineBuilder};
+use crate::descriptors::{DescriptorAllocator, DescriptorLayoutCache, DescriptorWriter, SetLayoutDesc};
+use crate::device::Device;
+use crate::glsl::{insert_after_version, GlslCompiler};
+use crate::renderer3d::Camera;
+use crate::shader::ShaderModule;
+
+const LIGHT_CLUSTERS_COMP: &str = include_str!("../shaders/light_clusters.comp");
+
+/// GLSL that declares set 0 of the 3D renderer, `directional_lighting`, `clustered_lighting` and `pbr_lighting`,
+/// see [`with_lighting`].
+pub const LIGHTING_GLSL: &str = include_str!("../shaders/lighting.glsl");
+
+/// Clusters across the screen, down it and along the view direction. Depth slices are spaced exponentially.
+pub const CLUSTER_GRID: [u32; 3] = [16, 9, 24];
+
+/// Lights beyond this many in a frame are dropped.
+pub const MAX_LIGHTS: usize = 1024;
+
+/// Lights beyond this many touching one cluster are ignored there. Must match the shaders.
+pub const MAX_LIGHTS_PER_CLUSTER: usize = 128;
+
+const CLUSTER_COUNT: usize = (CLUSTER_GRID[0] * CLUSTER_GRID[1] * CLUSTER_GRID[2]) as usize;
+const WORKGROUP_SIZE: u32 = 64;
+
+/// Inserts [`LIGHTING_GLSL`] after the `#version` line of a fragment shader, so it can call
+/// `directional_lighting(world_position, normal, albedo)` and
+/// `clustered_lighting(gl_FragCoord.xy, world_position, normal, albedo)` for diffuse shading, or
+/// `pbr_lighting(gl_FragCoord.xy, world_position, surface)` for the full BRDF. The shader must not declare set 0
+/// itself.
+pub fn with_lighting(source: &str) -> String {
+    insert_after_version(source, LIGHTING_GLSL)
+}
+
+/// `1 / (constant + linear * d + quadratic * d²)`, faded to zero at the light's range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attenuation {
+    pub constant: f32,
+    pub linear: f32,
+    pub quadratic: f32,
+}
+
+impl Attenuation {
+    /// Physically based falloff, only limited by the range.
+    pub const INVERSE_SQUARE: Self = Self {
+        constant: 1.0,
+        linear: 0.0,
+        quadratic: 1.0,
+    };
+
+    pub fn new(constant: f32, linear: f32, quadratic: f32) -> Self {
+        Self { constant, linear, quadratic }
+    }
+}
+
+impl Default for Attenuation {
+    fn default() -> Self {
+        Self::INVERSE_SQUARE
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightKind {
+    Point,
+    /// Full intensity inside `inner_angle` from `direction`, fading out to nothing at `outer_angle`.
+    Spot {
+        direction: Vector3<f32>,
+        inner_angle: Rad<f32>,
+        outer_angle: Rad<f32>,
+    },
+}
+
+/// Light from infinitely far away, like the sun. The 3D renderer has one, which casts cascaded shadows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DirectionalLight {
+    /// The direction the light travels in.
+    pub direction: Vector3<f32>,
+    /// Linear RGB.
+    pub color: [f32; 3],
+    pub intensity: f32,
+    /// Constant light on every surface, standing in for indirect light.
+    pub ambient: f32,
+    pub casts_shadows: bool,
+}
+
+impl DirectionalLight {
+    pub fn new(direction: Vector3<f32>, color: [f32; 3]) -> Self {
+        Self {
+            direction: direction.normalize(),
+            color,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    pub fn with_ambient(mut self, ambient: f32) -> Self {
+        self.ambient = ambient;
+        self
+    }
+
+    pub fn with_shadows(mut self, casts_shadows: bool) -> Self {
+        self.casts_shadows = casts_shadows;
+        self
+    }
+}
+
+impl Default for DirectionalLight {
+    fn default() -> Self {
+        Self {
+            direction: -Vector3::new(0.4, 1.0, 0.6).normalize(),
+            color: [1.0; 3],
+            intensity: 0.5,
+            ambient: 0.1,
+            casts_shadows: true,
+        }
+    }
+}
+
+/// A point or spot light. Nothing is lit beyond `range`, which is also what lights are culled by.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Light {
+    pub kind: LightKind,
+    pub position: Point3<f32>,
+    /// Linear RGB.
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub range: f32,
+    pub attenuation: Attenuation,
+    /// Only spot lights cast shadows so far, and only the first `shadows::MAX_SHADOWED_SPOT_LIGHTS` of a frame.
+    pub casts_shadows: bool,
+}
+
+impl Light {
+    pub fn point(position: Point3<f32>, color: [f32; 3], range: f32) -> Self {
+        Self {
+            kind: LightKind::Point,
+            position,
+            color,
+            intensity: 1.0,
+            range,
+            attenuation: Attenuation::default(),
+            casts_shadows: false,
+        }
+    }
+
+    pub fn spot(
+        position: Point3<f32>,
+        direction: Vector3<f32>,
+        color: [f32; 3],
+        range: f32,
+        inner_angle: impl Into<Rad<f32>>,
+        outer_angle: impl Into<Rad<f32>>,
+    ) -> Self {
+        Self {
+            kind: LightKind::Spot {
+                direction: direction.normalize(),
+                inner_angle: inner_angle.into(),
+                outer_angle: outer_angle.into(),
+            },
+            ..Self::point(position, color, range)
+        }
+    }
+
+    pub fn with_intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    pub fn with_attenuation(mut self, attenuation: Attenuation) -> Self {
+        self.attenuation = attenuation;
+        self
+    }
+
+    pub fn with_shadows(mut self) -> Self {
+        self.casts_shadows = true;
+        self
+    }
+}
+
+/// A light as the shaders see it, in the `Lights` storage buffer.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct GpuLight {
+    pub position_range: [f32; 4],
+    pub color_intensity: [f32; 4],
+    /// Spot direction, with 0 for point lights and 1 for spot lights in `w`.
+    pub direction_kind: [f32; 4],
+    pub attenuation: [f32; 4],
+    /// Cosines of the inner and outer spot angles, then the light's shadow map slot or -1.
+    pub spot_cone: [f32; 4],
+}
+
+unsafe impl Zeroable for GpuLight {}
+unsafe impl Pod for GpuLight {}
+
+impl GpuLight {
+    pub fn new(light: &Light, shadow_slot: Option<u32>) -> Self {
+        let mut gpu_light = Self::from(light);
+        gpu_light.spot_cone[2] = shadow_slot.map_or(-1.0, |slot| slot as f32);
+        gpu_light
+    }
+}
+
+impl From<&Light> for GpuLight {
+    fn from(light: &Light) -> Self {
+        let (direction_kind, spot_cone) = match light.kind {
+            LightKind::Point => ([0.0, 0.0, -1.0, 0.0], [-1.0, -1.0, -1.0, 0.0]),
+            LightKind::Spot { direction, inner_angle, outer_angle } => (
+                [direction.x, direction.y, direction.z, 1.0],
+                [inner_angle.0.cos(), outer_angle.0.cos(), -1.0, 0.0],
+            ),
+        };
+
+        Self {
+            position_range: [light.position.x, light.position.y, light.position.z, light.range],
+            color_intensity: [light.color[0], light.color[1], light.color[2], light.intensity],
+            direction_kind,
+            attenuation: [light.attenuation.constant, light.attenuation.linear, light.attenuation.quadratic, 0.0],
+            spot_cone,
+        }
+    }
+}
+
+/// What the culling pass and the forward shaders need to find a fragment's cluster.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ClusterParams {
+    pub inverse_projection: [[f32; 4]; 4],
+    pub view: [[f32; 4]; 4],
+    /// Clusters along x, y and z, then the number of lights.
+    pub grid: [u32; 4],
+    /// Screen width and height, then the near and far planes.
+    pub screen: [f32; 4],
+    /// Where the viewport starts on the target in x and y, then unused.
+    pub offset: [f32; 4],
+}
+
+unsafe impl Zeroable for ClusterParams {}
+unsafe impl Pod for ClusterParams {}
+
+/// Assigns lights to a grid of view space clusters with a compute pass, so forward shaders only loop over the
+/// lights that can reach their fragment. Each cluster stores its light count followed by up to
+/// [`MAX_LIGHTS_PER_CLUSTER`] light indices.
+pub struct LightCulling {
+    device: Arc<Device>,
+    _layouts: DescriptorLayoutCache,
+    _descriptor_allocator: DescriptorAllocator,
+    pipeline: ComputePipeline,
+    sets: Vec<vk::DescriptorSet>,
+    params: PerFrameUniform<ClusterParams>,
+    light_buffers: Vec<Buffer>,
+    cluster_buffers: Vec<Buffer>,
+    light_count: usize,
+}
+
+impl LightCulling {
+    pub unsafe fn new(device: &Arc<Device>, compiler: &GlslCompiler, frames_in_flight: usize) -> anyhow::Result<Self> {
+        let mut layouts = DescriptorLayoutCache::new(device);
+        let set_layout = layouts.get(&SetLayoutDesc::new()
+            .binding(0, vk::DescriptorType::UNIFORM_BUFFER, vk::ShaderStageFlags::COMPUTE)
+            .binding(1, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE)
+            .binding(2, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE))?;
+
+        let shader = ShaderModule::from_bytes_with_stage(
+            device,
+            "light_clusters.comp",
+            &compiler.compile_source(LIGHT_CLUSTERS_COMP, vk::ShaderStageFlags::COMPUTE, "light_clusters.comp")?,
+            vk::ShaderStageFlags::COMPUTE,
+        )?;
+
+        let pipeline = ComputePipelineBuilder::new()
+            .shader(&shader)
+            .descriptor_set_layout(set_layout)
+            .build(device)?;
+
+        let params = PerFrameUniform::new(device, "cluster params", frames_in_flight)?;
+        let light_size = (MAX_LIGHTS * std::mem::size_of::<GpuLight>()) as vk::DeviceSize;
+        let cluster_size = (CLUSTER_COUNT * (MAX_LIGHTS_PER_CLUSTER + 1) * std::mem::size_of::<u32>()) as vk::DeviceSize;
+
+        let light_buffers = (0..frames_in_flight)
+            .map(|_| Buffer::new(device, "lights", light_size, vk::BufferUsageFlags::STORAGE_BUFFER, MemoryLocation::CpuToGpu))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let cluster_buffers = (0..frames_in_flight)
+            .map(|_| Buffer::storage(device, "light clusters", cluster_size))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut descriptor_allocator = DescriptorAllocator::new(device);
+        let sets = (0..frames_in_flight)
+            .map(|frame_index| {
+                let set = descriptor_allocator.allocate(set_layout)?;
+                let params_info = params.descriptor_info(frame_index);
+
+                DescriptorWriter::new()
+                    .buffer(0, vk::DescriptorType::UNIFORM_BUFFER, params_info.buffer, 0, params_info.range)
+                    .buffer(1, vk::DescriptorType::STORAGE_BUFFER, light_buffers[frame_index].handle(), 0, vk::WHOLE_SIZE)
+                    .buffer(2, vk::DescriptorType::STORAGE_BUFFER, cluster_buffers[frame_index].handle(), 0, vk::WHOLE_SIZE)
+                    .update(device, set);
+
+                Ok(set)
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            device: device.clone(),
+            _layouts: layouts,
+            _descriptor_allocator: descriptor_allocator,
+            pipeline,
+            sets,
+            params,
+            light_buffers,
+            cluster_buffers,
+            light_count: 0,
+        })
+    }
+
+    /// Uploads `lights` for `frame_index` and records the culling pass, followed by a barrier that makes its
+    /// results visible to fragment shaders. Record it outside of any render pass, before the frame's draws.
+    /// `shadow_slots` holds the shadow map of each light, from `ShadowMaps::update`.
+    pub unsafe fn record(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        camera: &Camera,
+        viewport: vk::Rect2D,
+        lights: &[Light],
+        shadow_slots: &[Option<u32>],
+    ) -> anyhow::Result<()> {
+        let extent = viewport.extent;
+        if lights.len() > MAX_LIGHTS {
+            warn!("{} lights were submitted, only the first {} are used", lights.len(), MAX_LIGHTS);
+        }
+
+        let gpu_lights: Vec<GpuLight> = lights.iter()
+            .take(MAX_LIGHTS)
+            .enumerate()
+            .map(|(index, light)| GpuLight::new(light, shadow_slots.get(index).copied().flatten()))
+            .collect();
+        self.light_count = gpu_lights.len();
+        self.light_buffers[frame_index].write(0, &gpu_lights)?;
+
+        let projection: Matrix4<f32> = camera.projection(extent);
+        let inverse_projection = projection.invert().unwrap_or_else(Matrix4::identity);
+
+        self.params.write(frame_index, &ClusterParams {
+            inverse_projection: inverse_projection.into(),
+            view: camera.view().into(),
+            grid: [CLUSTER_GRID[0], CLUSTER_GRID[1], CLUSTER_GRID[2], self.light_count as u32],
+            screen: [extent.width as f32, extent.height as f32, camera.near, camera.far],
+            offset: [viewport.offset.x as f32, viewport.offset.y as f32, 0.0, 0.0],
+        })?;
+
+        self.pipeline.bind(command_buffer);
+        self.pipeline.bind_descriptor_sets(command_buffer, 0, &[self.sets[frame_index]]);
+        self.pipeline.dispatch(command_buffer, (CLUSTER_COUNT as u32).div_ceil(WORKGROUP_SIZE), 1, 1);
+
+        buffer_barrier(&self.device, command_buffer, self.cluster_buffers[frame_index].handle(), Access::COMPUTE_WRITE, Access::FRAGMENT_READ);
+        Ok(())
+    }
+
+    /// Lights used by the last recorded frame.
+    pub fn light_count(&self) -> usize {
+        self.light_count
+    }
+
+    pub fn params_info(&self, frame_index: usize) -> vk::DescriptorBufferInfo {
+        self.params.descriptor_info(frame_index)
+    }
+
+    pub fn light_buffer(&self, frame_index: usize) -> &Buffer {
+        &self.light_buffers[frame_index]
+    }
+
+    pub fn cluster_buffer(&self, frame_index: usize) -> &Buffer {
+        &self.cluster_buffers[frame_index]
+    }
+}
@@ -0,0 +1,120 @@
+use anyhow::anyhow;
+use crate::glsl::insert_after_version;
+use crate::renderer3d::MeshId;
+
+const LOD_GLSL: &str = include_str!("../shaders/lod.glsl");
+
+/// Makes `lod_dither(fade)` available to `source`, which discards the pixels a level cross-fading with another
+/// leaves to it. Call it after anything taking derivatives with the instance's fade, passed on from location 8
+/// of the vertex shader.
+pub fn with_lod_dither(source: &str) -> String {
+    insert_after_version(source, LOD_GLSL)
+}
+
+/// What an [`LodGroupDesc`]'s thresholds measure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LodMetric {
+    /// World space distance from the camera to the center of the instance's bounds. Thresholds go up.
+    #[default]
+    Distance,
+    /// The part of the screen's height the instance's bounding sphere covers, from 0 to 1. Thresholds go down.
+    ScreenCoverage,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LodLevel {
+    pub mesh: MeshId,
+    /// Where the level stops being drawn: the distance it reaches up to, or the coverage it reaches down to.
+    pub threshold: f32,
+}
+
+/// One of the levels an instance is drawn with, with the fade to pass on to `lod_dither`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LodDraw {
+    pub level: usize,
+    pub fade: f32,
+}
+
+/// Meshes of the same object in decreasing detail. Instances are drawn with the first level whose threshold they
+/// haven't passed, and not at all past the last one. With a cross-fade, instances within `cross_fade` of a
+/// threshold are drawn with both levels dithered into each other instead of popping from one to the next.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LodGroupDesc {
+    pub levels: Vec<LodLevel>,
+    pub metric: LodMetric,
+    /// Part of each threshold, from 0 to below 1, before it over which levels fade. 0 switches levels at once.
+    pub cross_fade: f32,
+}
+
+impl LodGroupDesc {
+    pub fn new(metric: LodMetric) -> Self {
+        Self {
+            metric,
+            ..Self::default()
+        }
+    }
+
+    /// Adds a level after the existing ones.
+    pub fn with_level(mut self, mesh: MeshId, threshold: f32) -> Self {
+        self.levels.push(LodLevel { mesh, threshold });
+        self
+    }
+
+    pub fn with_cross_fade(mut self, cross_fade: f32) -> Self {
+        self.cross_fade = cross_fade;
+        self
+    }
+
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if self.levels.is_empty() {
+            return Err(anyhow!("LOD groups need at least one level"));
+        }
+
+        if !(0.0..1.0).contains(&self.cross_fade) {
+            return Err(anyhow!("Invalid LOD cross-fade {}", self.cross_fade));
+        }
+
+        let boundaries: Vec<f32> = self.levels.iter().map(|level| self.boundary(level.threshold)).collect();
+        let positive = boundaries.iter().all(|&boundary| boundary > 0.0);
+        if !positive || boundaries.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(anyhow!("LOD thresholds must be positive and {}", match self.metric {
+                LodMetric::Distance => "increasing",
+                LodMetric::ScreenCoverage => "decreasing",
+            }));
+        }
+
+        Ok(())
+    }
+
+    /// The levels to draw an instance `distance` away from the camera and covering `coverage` of the screen with.
+    /// Both are `None` past the last level.
+    pub fn select(&self, distance: f32, coverage: f32) -> [Option<LodDraw>; 2] {
+        // Coverage falls off with one over the distance, so both are compared the same way as distances.
+        let value = match self.metric {
+            LodMetric::Distance => distance,
+            LodMetric::ScreenCoverage => 1.0 / coverage,
+        };
+
+        let Some(level) = self.levels.iter().position(|level| value < self.boundary(level.threshold)) else {
+            return [None; 2];
+        };
+
+        let boundary = self.boundary(self.levels[level].threshold);
+        let fade_start = boundary * (1.0 - self.cross_fade);
+        if value <= fade_start {
+            return [Some(LodDraw { level, fade: 0.0 }), None];
+        }
+
+        // The outgoing level keeps the pixels the incoming one dithers away, if there is one.
+        let progress = (value - fade_start) / (boundary - fade_start);
+        let next = (level + 1 < self.levels.len()).then_some(LodDraw { level: level + 1, fade: progress });
+        [Some(LodDraw { level, fade: -progress }), next]
+    }
+
+    fn boundary(&self, threshold: f32) -> f32 {
+        match self.metric {
+            LodMetric::Distance => threshold,
+            LodMetric::ScreenCoverage => 1.0 / threshold,
+        }
+    }
+}
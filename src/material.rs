@@ -0,0 +1,783 @@
+use std::sync::Arc;
+use ash::vk;
+use bytemuck::Pod;
+use thiserror::Error;
+use crate::bindless::{supports_bindless, with_bindless, TextureHandle};
+use crate::buffer::{Buffer, PerFrameStorage};
+use crate::descriptors::{DescriptorAllocator, DescriptorLayoutCache, DescriptorWriter, SetLayoutDesc};
+use crate::device::Device;
+use crate::glsl::GlslCompiler;
+use crate::pipeline::{BlendMode, DepthState, GraphicsPipeline, GraphicsPipelineBuilder};
+use crate::shader::ShaderModule;
+
+/// The descriptor set materials bind their parameters and textures to. Set 0 belongs to the renderer.
+pub const MATERIAL_SET: u32 = 1;
+
+/// Binding of the parameter block in [`MATERIAL_SET`]. Texture slots follow it, in declaration order.
+pub const PARAMS_BINDING: u32 = 0;
+
+/// Binding of the texture handles of every instance in the [`MATERIAL_SET`] of bindless materials, after their
+/// parameter blocks at [`PARAMS_BINDING`].
+pub const TEXTURE_HANDLES_BINDING: u32 = 1;
+
+/// Where bindless materials' fragment shaders get the index of the instance being drawn, as a `uint`.
+pub const MATERIAL_INDEX_OFFSET: u32 = 124;
+
+/// Instances a bindless material's storage buffers have room for before they grow.
+const INITIAL_RECORD_CAPACITY: usize = 16;
+
+#[derive(Error, Debug)]
+pub enum MaterialError {
+    #[error("Material '{material}' has no parameter '{name}'")]
+    UnknownParam { material: String, name: String },
+    #[error("Parameter '{name}' of material '{material}' is a {expected:?}, not a {found:?}")]
+    TypeMismatch { material: String, name: String, expected: ParamType, found: ParamType },
+    #[error("Material '{material}' has no texture slot '{name}'")]
+    UnknownTexture { material: String, name: String },
+}
+
+/// The type of a material parameter, laid out by std140 rules in the parameter block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamType {
+    Float,
+    Vec2,
+    Vec3,
+    Vec4,
+}
+
+impl ParamType {
+    pub fn size(self) -> usize {
+        match self {
+            Self::Float => 4,
+            Self::Vec2 => 8,
+            Self::Vec3 => 12,
+            Self::Vec4 => 16,
+        }
+    }
+
+    pub fn alignment(self) -> usize {
+        match self {
+            Self::Float => 4,
+            Self::Vec2 => 8,
+            Self::Vec3 | Self::Vec4 => 16,
+        }
+    }
+}
+
+/// A Rust type a material parameter can be set from.
+pub trait ParamValue: Pod {
+    const TYPE: ParamType;
+}
+
+impl ParamValue for f32 {
+    const TYPE: ParamType = ParamType::Float;
+}
+
+impl ParamValue for [f32; 2] {
+    const TYPE: ParamType = ParamType::Vec2;
+}
+
+impl ParamValue for [f32; 3] {
+    const TYPE: ParamType = ParamType::Vec3;
+}
+
+impl ParamValue for [f32; 4] {
+    const TYPE: ParamType = ParamType::Vec4;
+}
+
+/// What a texture slot samples until a texture is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DefaultTexture {
+    #[default]
+    White,
+    Black,
+    /// A tangent space normal map pointing straight out of the surface.
+    FlatNormal,
+}
+
+#[derive(Debug, Clone)]
+struct ParamDesc {
+    name: String,
+    ty: ParamType,
+    offset: usize,
+    default: [f32; 4],
+}
+
+/// Shaders, fixed function state and the parameters of a material. Parameters make up a uniform block at
+/// [`PARAMS_BINDING`] whose members must be declared in the same order; texture slots are combined image samplers
+/// at the bindings after it.
+///
+/// Bindless materials are drawn that way on devices with the descriptor indexing features instead: their fragment
+/// shaders are compiled with `bindless.glsl` and `BINDLESS` defined, which has them read their parameters from an
+/// array of every instance's block in a storage buffer and their textures through `material_texture(slot)`, both
+/// indexed by the instance being drawn. Their shaders have to handle both ways, as the stock ones do.
+#[derive(Debug, Clone)]
+pub struct MaterialDesc {
+    pub name: String,
+    /// GLSL source, compiled when the material is created.
+    pub vertex_shader: String,
+    pub fragment_shader: String,
+    /// Writes the G-buffer instead of shading, for renderers with a deferred path. Materials without one are
+    /// always drawn forward.
+    pub gbuffer_fragment_shader: Option<String>,
+    /// Takes the place of the vertex shader for skinned meshes, for renderers that draw them. Materials without one
+    /// can't draw skinned meshes.
+    pub skinned_vertex_shader: Option<String>,
+    pub bindless: bool,
+    pub blend: BlendMode,
+    pub cull_mode: vk::CullModeFlags,
+    pub depth: DepthState,
+    params: Vec<ParamDesc>,
+    block_size: usize,
+    textures: Vec<String>,
+    texture_defaults: Vec<DefaultTexture>,
+}
+
+impl MaterialDesc {
+    pub fn new(name: &str, vertex_shader: &str, fragment_shader: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            vertex_shader: vertex_shader.to_owned(),
+            fragment_shader: fragment_shader.to_owned(),
+            gbuffer_fragment_shader: None,
+            skinned_vertex_shader: None,
+            bindless: false,
+            blend: BlendMode::Opaque,
+            cull_mode: vk::CullModeFlags::BACK,
+            depth: DepthState::READ_WRITE,
+            params: Vec::new(),
+            block_size: 0,
+            textures: Vec::new(),
+            texture_defaults: Vec::new(),
+        }
+    }
+
+    fn param(mut self, name: &str, ty: ParamType, default: [f32; 4]) -> Self {
+        let offset = self.block_size.next_multiple_of(ty.alignment());
+        self.block_size = offset + ty.size();
+        self.params.push(ParamDesc {
+            name: name.to_owned(),
+            ty,
+            offset,
+            default,
+        });
+        self
+    }
+
+    pub fn float(self, name: &str, default: f32) -> Self {
+        self.param(name, ParamType::Float, [default, 0.0, 0.0, 0.0])
+    }
+
+    pub fn vec2(self, name: &str, default: [f32; 2]) -> Self {
+        self.param(name, ParamType::Vec2, [default[0], default[1], 0.0, 0.0])
+    }
+
+    pub fn vec3(self, name: &str, default: [f32; 3]) -> Self {
+        self.param(name, ParamType::Vec3, [default[0], default[1], default[2], 0.0])
+    }
+
+    pub fn vec4(self, name: &str, default: [f32; 4]) -> Self {
+        self.param(name, ParamType::Vec4, default)
+    }
+
+    /// A linear RGBA color, stored as a `vec4`.
+    pub fn color(self, name: &str, default: [f32; 4]) -> Self {
+        self.vec4(name, default)
+    }
+
+    pub fn texture(self, name: &str) -> Self {
+        self.texture_with_default(name, DefaultTexture::White)
+    }
+
+    pub fn texture_with_default(mut self, name: &str, default: DefaultTexture) -> Self {
+        self.textures.push(name.to_owned());
+        self.texture_defaults.push(default);
+        self
+    }
+
+    pub fn with_gbuffer_shader(mut self, fragment_shader: &str) -> Self {
+        self.gbuffer_fragment_shader = Some(fragment_shader.to_owned());
+        self
+    }
+
+    pub fn with_skinned_shader(mut self, vertex_shader: &str) -> Self {
+        self.skinned_vertex_shader = Some(vertex_shader.to_owned());
+        self
+    }
+
+    pub fn with_bindless(mut self) -> Self {
+        self.bindless = true;
+        self
+    }
+
+    pub fn with_blend(mut self, blend: BlendMode) -> Self {
+        self.blend = blend;
+        self
+    }
+
+    pub fn with_cull_mode(mut self, cull_mode: vk::CullModeFlags) -> Self {
+        self.cull_mode = cull_mode;
+        self
+    }
+
+    pub fn with_depth(mut self, depth: DepthState) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Size of the parameter block, padded to a multiple of 16 bytes.
+    pub fn block_size(&self) -> usize {
+        self.block_size.next_multiple_of(16)
+    }
+
+    /// Distance between instances' parameter blocks in the storage buffer of a bindless material, which is the
+    /// std430 stride of a struct with the block's members.
+    pub fn record_stride(&self) -> usize {
+        let alignment = self.params.iter().map(|param| param.ty.alignment()).max().unwrap_or(1);
+        self.block_size.next_multiple_of(alignment)
+    }
+
+    pub fn texture_slots(&self) -> &[String] {
+        &self.textures
+    }
+
+    pub fn texture_default(&self, slot: usize) -> DefaultTexture {
+        self.texture_defaults[slot]
+    }
+
+    pub fn texture_binding(&self, slot: usize) -> u32 {
+        PARAMS_BINDING + 1 + slot as u32
+    }
+
+    pub fn set_layout(&self) -> SetLayoutDesc {
+        let mut layout = SetLayoutDesc::new();
+        if !self.params.is_empty() {
+            layout = layout.binding(PARAMS_BINDING, vk::DescriptorType::UNIFORM_BUFFER, vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT);
+        }
+
+        (0..self.textures.len()).fold(layout, |layout, slot| {
+            layout.binding(self.texture_binding(slot), vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::FRAGMENT)
+        })
+    }
+
+    /// The layout of bindless materials, whose set is shared by every instance.
+    pub fn bindless_set_layout(&self) -> SetLayoutDesc {
+        let mut layout = SetLayoutDesc::new();
+        if !self.params.is_empty() {
+            layout = layout.binding(PARAMS_BINDING, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT);
+        }
+
+        if !self.textures.is_empty() {
+            layout = layout.binding(TEXTURE_HANDLES_BINDING, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::FRAGMENT);
+        }
+
+        layout
+    }
+
+    /// The parameter block with every parameter at its default.
+    fn default_block(&self) -> Vec<u8> {
+        let mut block = vec![0; self.block_size()];
+        for param in &self.params {
+            let bytes: &[u8] = bytemuck::cast_slice(&param.default);
+            block[param.offset..param.offset + param.ty.size()].copy_from_slice(&bytes[..param.ty.size()]);
+        }
+
+        block
+    }
+
+    fn find_param(&self, name: &str) -> Result<&ParamDesc, MaterialError> {
+        self.params.iter()
+            .find(|param| param.name == name)
+            .ok_or_else(|| MaterialError::UnknownParam {
+                material: self.name.clone(),
+                name: name.to_owned(),
+            })
+    }
+
+    fn find_texture(&self, name: &str) -> Result<usize, MaterialError> {
+        self.textures.iter()
+            .position(|slot| slot == name)
+            .ok_or_else(|| MaterialError::UnknownTexture {
+                material: self.name.clone(),
+                name: name.to_owned(),
+            })
+    }
+}
+
+/// A pipeline and the layout of the descriptor set its instances bind at [`MATERIAL_SET`].
+pub struct Material {
+    desc: Arc<MaterialDesc>,
+    /// Whether the material is drawn bindless, which takes a bindless desc and a device that supports it.
+    bindless: bool,
+    set_layout: vk::DescriptorSetLayout,
+    pipeline: GraphicsPipeline,
+    gbuffer_pipeline: Option<GraphicsPipeline>,
+    skinned_pipeline: Option<GraphicsPipeline>,
+    meshlet_pipeline: Option<GraphicsPipeline>,
+    meshlet_gbuffer_pipeline: Option<GraphicsPipeline>,
+    /// Set by `with_records` on bindless materials.
+    records: Option<MaterialRecords>,
+}
+
+impl Material {
+    /// Builds the pipeline on top of `base`, which sets up what the renderer provides: vertex input, the layouts of
+    /// the sets before [`MATERIAL_SET`], push constants and the target. When the material has a G-buffer shader,
+    /// a second pipeline is built on `gbuffer_base` as well, and with a skinned shader, a forward one for skinned
+    /// meshes on `skinned_base`. Bindless materials add a fragment push constant at [`MATERIAL_INDEX_OFFSET`] to
+    /// each of them.
+    pub unsafe fn new(
+        device: &Arc<Device>,
+        layouts: &mut DescriptorLayoutCache,
+        compiler: &GlslCompiler,
+        desc: MaterialDesc,
+        base: GraphicsPipelineBuilder,
+        gbuffer_base: Option<GraphicsPipelineBuilder>,
+        skinned_base: Option<GraphicsPipelineBuilder>,
+    ) -> anyhow::Result<Self> {
+        let bindless = desc.bindless && supports_bindless(device);
+        let vertex = compile(device, compiler, &desc.name, "vert", &desc.vertex_shader, vk::ShaderStageFlags::VERTEX)?;
+        let fragment = compile_fragment(device, compiler, &desc, bindless, "frag", &desc.fragment_shader)?;
+
+        let set_layout = match bindless {
+            true => layouts.get(&desc.bindless_set_layout())?,
+            false => layouts.get(&desc.set_layout())?,
+        };
+        let pipeline = with_material_index(base, bindless)
+            .shader(&vertex)
+            .shader(&fragment)
+            .cull_mode(desc.cull_mode)
+            .depth(desc.depth)
+            .blend(desc.blend)
+            .descriptor_set_layout(set_layout)
+            .build(device)?;
+
+        let gbuffer_pipeline = match (&desc.gbuffer_fragment_shader, gbuffer_base) {
+            (Some(source), Some(gbuffer_base)) => {
+                let fragment = compile_fragment(device, compiler, &desc, bindless, "gbuffer.frag", source)?;
+                let pipeline = with_material_index(gbuffer_base, bindless)
+                    .shader(&vertex)
+                    .shader(&fragment)
+                    .cull_mode(desc.cull_mode)
+                    .depth(desc.depth)
+                    .blend(BlendMode::Opaque)
+                    .descriptor_set_layout(set_layout)
+                    .build(device)?;
+
+                Some(pipeline)
+            }
+            _ => None,
+        };
+
+        let skinned_pipeline = match (&desc.skinned_vertex_shader, skinned_base) {
+            (Some(source), Some(skinned_base)) => {
+                let vertex = compile(device, compiler, &desc.name, "skinned.vert", source, vk::ShaderStageFlags::VERTEX)?;
+                let pipeline = with_material_index(skinned_base, bindless)
+                    .shader(&vertex)
+                    .shader(&fragment)
+                    .cull_mode(desc.cull_mode)
+                    .depth(desc.depth)
+                    .blend(desc.blend)
+                    .descriptor_set_layout(set_layout)
+                    .build(device)?;
+
+                Some(pipeline)
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            desc: Arc::new(desc),
+            bindless,
+            set_layout,
+            pipeline,
+            gbuffer_pipeline,
+            skinned_pipeline,
+            meshlet_pipeline: None,
+            meshlet_gbuffer_pipeline: None,
+            records: None,
+        })
+    }
+
+    /// Creates the storage buffers and sets holding every instance's parameters and texture handles, when the
+    /// material is bindless.
+    pub unsafe fn with_records(mut self, device: &Arc<Device>, allocator: &mut DescriptorAllocator, frames_in_flight: usize) -> anyhow::Result<Self> {
+        if self.bindless {
+            self.records = Some(MaterialRecords::new(device, &self, allocator, frames_in_flight)?);
+        }
+
+        Ok(self)
+    }
+
+    /// Builds the material's pipelines again on `base`, whose task and mesh shaders take the place of its vertex
+    /// shader, for the forward and, with a G-buffer shader, the G-buffer pass. `meshlet_layout` is bound after
+    /// [`MATERIAL_SET`].
+    pub unsafe fn with_meshlet_pipelines(
+        mut self,
+        device: &Arc<Device>,
+        compiler: &GlslCompiler,
+        base: GraphicsPipelineBuilder,
+        gbuffer_base: Option<GraphicsPipelineBuilder>,
+        meshlet_layout: vk::DescriptorSetLayout,
+    ) -> anyhow::Result<Self> {
+        let desc = &self.desc;
+        let fragment = compile_fragment(device, compiler, desc, self.bindless, "frag", &desc.fragment_shader)?;
+        self.meshlet_pipeline = Some(with_material_index(base, self.bindless)
+            .shader(&fragment)
+            .cull_mode(desc.cull_mode)
+            .depth(desc.depth)
+            .blend(desc.blend)
+            .descriptor_set_layout(self.set_layout)
+            .descriptor_set_layout(meshlet_layout)
+            .build(device)?);
+
+        if let (Some(source), Some(gbuffer_base)) = (&desc.gbuffer_fragment_shader, gbuffer_base) {
+            let fragment = compile_fragment(device, compiler, desc, self.bindless, "gbuffer.frag", source)?;
+            self.meshlet_gbuffer_pipeline = Some(with_material_index(gbuffer_base, self.bindless)
+                .shader(&fragment)
+                .cull_mode(desc.cull_mode)
+                .depth(desc.depth)
+                .blend(BlendMode::Opaque)
+                .descriptor_set_layout(self.set_layout)
+                .descriptor_set_layout(meshlet_layout)
+                .build(device)?);
+        }
+
+        Ok(self)
+    }
+
+    pub fn desc(&self) -> &MaterialDesc {
+        &self.desc
+    }
+
+    pub fn name(&self) -> &str {
+        &self.desc.name
+    }
+
+    pub fn is_bindless(&self) -> bool {
+        self.bindless
+    }
+
+    pub fn set_layout(&self) -> vk::DescriptorSetLayout {
+        self.set_layout
+    }
+
+    pub fn records(&self) -> Option<&MaterialRecords> {
+        self.records.as_ref()
+    }
+
+    pub fn records_mut(&mut self) -> Option<&mut MaterialRecords> {
+        self.records.as_mut()
+    }
+
+    pub fn pipeline(&self) -> &GraphicsPipeline {
+        &self.pipeline
+    }
+
+    pub fn gbuffer_pipeline(&self) -> Option<&GraphicsPipeline> {
+        self.gbuffer_pipeline.as_ref()
+    }
+
+    pub fn skinned_pipeline(&self) -> Option<&GraphicsPipeline> {
+        self.skinned_pipeline.as_ref()
+    }
+
+    pub fn meshlet_pipeline(&self) -> Option<&GraphicsPipeline> {
+        self.meshlet_pipeline.as_ref()
+    }
+
+    pub fn meshlet_gbuffer_pipeline(&self) -> Option<&GraphicsPipeline> {
+        self.meshlet_gbuffer_pipeline.as_ref()
+    }
+}
+
+unsafe fn compile(
+    device: &Arc<Device>,
+    compiler: &GlslCompiler,
+    material: &str,
+    extension: &str,
+    source: &str,
+    stage: vk::ShaderStageFlags,
+) -> anyhow::Result<ShaderModule> {
+    let name = format!("{}.{}", material, extension);
+    ShaderModule::from_bytes_with_stage(device, &name, &compiler.compile_source(source, stage, &name)?, stage)
+}
+
+unsafe fn compile_fragment(
+    device: &Arc<Device>,
+    compiler: &GlslCompiler,
+    desc: &MaterialDesc,
+    bindless: bool,
+    extension: &str,
+    source: &str,
+) -> anyhow::Result<ShaderModule> {
+    match bindless {
+        true => compile(device, compiler, &desc.name, extension, &with_bindless(source, desc.textures.len()), vk::ShaderStageFlags::FRAGMENT),
+        false => compile(device, compiler, &desc.name, extension, source, vk::ShaderStageFlags::FRAGMENT),
+    }
+}
+
+fn with_material_index(base: GraphicsPipelineBuilder, bindless: bool) -> GraphicsPipelineBuilder {
+    match bindless {
+        true => base.push_constants::<u32>(vk::ShaderStageFlags::FRAGMENT, MATERIAL_INDEX_OFFSET),
+        false => base,
+    }
+}
+
+/// The parameter blocks and texture handles of every instance of a bindless material, in a storage buffer each per
+/// frame in flight, and the set pointing at them that draws of every instance share. Draws pick their instance by
+/// its index in the slice passed to `upload`, pushed at [`MATERIAL_INDEX_OFFSET`].
+pub struct MaterialRecords {
+    device: Arc<Device>,
+    desc: Arc<MaterialDesc>,
+    blocks: PerFrameStorage<u8>,
+    handles: PerFrameStorage<u32>,
+    sets: Vec<vk::DescriptorSet>,
+}
+
+impl MaterialRecords {
+    unsafe fn new(device: &Arc<Device>, material: &Material, allocator: &mut DescriptorAllocator, frames_in_flight: usize) -> anyhow::Result<Self> {
+        let desc = material.desc.clone();
+        let blocks = PerFrameStorage::new(device, &format!("{} params", desc.name), INITIAL_RECORD_CAPACITY * desc.record_stride(), frames_in_flight)?;
+        let handles = PerFrameStorage::new(device, &format!("{} textures", desc.name), INITIAL_RECORD_CAPACITY * desc.textures.len(), frames_in_flight)?;
+        let sets = (0..frames_in_flight)
+            .map(|_| allocator.allocate(material.set_layout))
+            .collect::<anyhow::Result<_>>()?;
+
+        let records = Self {
+            device: device.clone(),
+            desc,
+            blocks,
+            handles,
+            sets,
+        };
+
+        for frame_index in 0..frames_in_flight {
+            records.write_set(frame_index);
+        }
+
+        Ok(records)
+    }
+
+    /// Writes the records of `instances`, all of this material, into the buffers of `frame_index` when any of them
+    /// changed since. Call it once the GPU is done with that frame and before their own `prepare`.
+    pub unsafe fn upload(&mut self, frame_index: usize, instances: &[&MaterialInstance]) -> anyhow::Result<()> {
+        if !instances.iter().any(|instance| instance.is_dirty(frame_index)) {
+            return Ok(());
+        }
+
+        self.blocks.clear();
+        self.handles.clear();
+        for instance in instances {
+            self.blocks.values_mut().extend_from_slice(&instance.block[..self.desc.record_stride()]);
+            self.handles.values_mut().extend(instance.handles.iter().map(|handle| handle.map_or(0, TextureHandle::index)));
+        }
+
+        let blocks_grew = self.blocks.upload(frame_index)?;
+        let handles_grew = self.handles.upload(frame_index)?;
+        if blocks_grew || handles_grew {
+            self.write_set(frame_index);
+        }
+
+        Ok(())
+    }
+
+    pub fn set(&self, frame_index: usize) -> vk::DescriptorSet {
+        self.sets[frame_index]
+    }
+
+    unsafe fn write_set(&self, frame_index: usize) {
+        let mut writer = DescriptorWriter::new();
+        if !self.desc.params.is_empty() {
+            writer = writer.buffer(PARAMS_BINDING, vk::DescriptorType::STORAGE_BUFFER, self.blocks.buffer(frame_index).handle(), 0, vk::WHOLE_SIZE);
+        }
+
+        if !self.desc.textures.is_empty() {
+            writer = writer.buffer(TEXTURE_HANDLES_BINDING, vk::DescriptorType::STORAGE_BUFFER, self.handles.buffer(frame_index).handle(), 0, vk::WHOLE_SIZE);
+        }
+
+        writer.update(&self.device, self.sets[frame_index]);
+    }
+}
+
+/// Parameter values and textures for one use of a [`Material`]. Every frame in flight has its own copy of the
+/// parameter block and descriptor set; changes are applied to a frame's copy by `prepare` once the GPU is done with
+/// it, so parameters can be set at any time. Instances of bindless materials have neither, and reference their
+/// textures by handle; the material's [`MaterialRecords`] get their changes instead.
+pub struct MaterialInstance {
+    device: Arc<Device>,
+    desc: Arc<MaterialDesc>,
+    bindless: bool,
+    block: Vec<u8>,
+    textures: Vec<(vk::ImageView, vk::Sampler)>,
+    /// Slots without one read the array's first texture.
+    handles: Vec<Option<TextureHandle>>,
+    buffers: Vec<Buffer>,
+    sets: Vec<vk::DescriptorSet>,
+    dirty: Vec<bool>,
+}
+
+impl MaterialInstance {
+    /// Creates an instance with every parameter at its default and `default_texture` in every texture slot.
+    pub unsafe fn new(
+        device: &Arc<Device>,
+        material: &Material,
+        allocator: &mut DescriptorAllocator,
+        frames_in_flight: usize,
+        default_texture: (vk::ImageView, vk::Sampler),
+    ) -> anyhow::Result<Self> {
+        let desc = material.desc.clone();
+        let block = desc.default_block();
+
+        let buffers = if desc.params.is_empty() || material.bindless {
+            Vec::new()
+        } else {
+            let name = format!("{} params", desc.name);
+            (0..frames_in_flight)
+                .map(|_| Buffer::uniform(device, &name, block.len() as vk::DeviceSize))
+                .collect::<anyhow::Result<_>>()?
+        };
+
+        let set_count = if material.bindless { 0 } else { frames_in_flight };
+        let sets = (0..set_count)
+            .map(|_| allocator.allocate(material.set_layout))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            device: device.clone(),
+            bindless: material.bindless,
+            textures: vec![default_texture; desc.textures.len()],
+            handles: vec![None; desc.textures.len()],
+            desc,
+            block,
+            buffers,
+            sets,
+            dirty: vec![true; frames_in_flight],
+        })
+    }
+
+    pub fn material_name(&self) -> &str {
+        &self.desc.name
+    }
+
+    pub fn is_bindless(&self) -> bool {
+        self.bindless
+    }
+
+    pub fn set_param<T: ParamValue>(&mut self, name: &str, value: T) -> Result<(), MaterialError> {
+        let param = self.desc.find_param(name)?;
+        if param.ty != T::TYPE {
+            return Err(MaterialError::TypeMismatch {
+                material: self.desc.name.clone(),
+                name: name.to_owned(),
+                expected: param.ty,
+                found: T::TYPE,
+            });
+        }
+
+        let range = param.offset..param.offset + param.ty.size();
+        if self.block[range.clone()] != *bytemuck::bytes_of(&value) {
+            self.block[range].copy_from_slice(bytemuck::bytes_of(&value));
+            self.dirty.fill(true);
+        }
+
+        Ok(())
+    }
+
+    pub fn param<T: ParamValue>(&self, name: &str) -> Result<T, MaterialError> {
+        let param = self.desc.find_param(name)?;
+        if param.ty != T::TYPE {
+            return Err(MaterialError::TypeMismatch {
+                material: self.desc.name.clone(),
+                name: name.to_owned(),
+                expected: param.ty,
+                found: T::TYPE,
+            });
+        }
+
+        Ok(bytemuck::pod_read_unaligned(&self.block[param.offset..param.offset + param.ty.size()]))
+    }
+
+    /// Puts `view` in a texture slot. The view must stay alive as long as the instance uses it.
+    pub fn set_texture(&mut self, name: &str, view: vk::ImageView, sampler: vk::Sampler) -> Result<(), MaterialError> {
+        let slot = self.desc.find_texture(name)?;
+        if self.textures[slot] != (view, sampler) {
+            self.textures[slot] = (view, sampler);
+            self.dirty.fill(true);
+        }
+
+        Ok(())
+    }
+
+    /// Puts the texture behind `handle` in a slot, for instances of bindless materials.
+    pub fn set_texture_handle(&mut self, name: &str, handle: TextureHandle) -> Result<(), MaterialError> {
+        let slot = self.desc.find_texture(name)?;
+        if self.handles[slot] != Some(handle) {
+            self.handles[slot] = Some(handle);
+            self.dirty.fill(true);
+        }
+
+        Ok(())
+    }
+
+    /// The parameter block as the shader sees it.
+    pub fn block(&self) -> &[u8] {
+        &self.block
+    }
+
+    pub fn is_dirty(&self, frame_index: usize) -> bool {
+        self.dirty[frame_index]
+    }
+
+    /// Writes pending changes into the parameter block and descriptor set of `frame_index`. Call it once the GPU is
+    /// done with that frame and before the set is bound.
+    pub unsafe fn prepare(&mut self, frame_index: usize) -> anyhow::Result<()> {
+        if !self.dirty[frame_index] {
+            return Ok(());
+        }
+
+        if self.bindless {
+            self.dirty[frame_index] = false;
+            return Ok(());
+        }
+
+        let mut writer = DescriptorWriter::new();
+        if let Some(buffer) = self.buffers.get_mut(frame_index) {
+            buffer.write(0, &self.block)?;
+            writer = writer.buffer(PARAMS_BINDING, vk::DescriptorType::UNIFORM_BUFFER, buffer.handle(), 0, self.block.len() as vk::DeviceSize);
+        }
+
+        for (slot, (view, sampler)) in self.textures.iter().enumerate() {
+            writer = writer.image(
+                self.desc.texture_binding(slot),
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                *view,
+                *sampler,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            );
+        }
+
+        writer.update(&self.device, self.sets[frame_index]);
+        self.dirty[frame_index] = false;
+        Ok(())
+    }
+
+    /// Not for instances of bindless materials, which bind the material's set instead.
+    pub fn set(&self, frame_index: usize) -> vk::DescriptorSet {
+        self.sets[frame_index]
+    }
+
+    /// Moves the instance to `material` on a new device, keeping its parameters. Texture slots are reset to
+    /// `default_texture`.
+    pub unsafe fn recreate(
+        &mut self,
+        device: &Arc<Device>,
+        material: &Material,
+        allocator: &mut DescriptorAllocator,
+        default_texture: (vk::ImageView, vk::Sampler),
+    ) -> anyhow::Result<()> {
+        let block = std::mem::take(&mut self.block);
+        *self = Self::new(device, material, allocator, self.dirty.len(), default_texture)?;
+        self.block = block;
+        Ok(())
+    }
+}
@@ -0,0 +1,393 @@
+use std::sync::Arc;
+use ash::extensions::ext;
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use cgmath::{InnerSpace, Vector3};
+use log::debug;
+use crate::allocator::MemoryLocation;
+use crate::buffer::Buffer;
+use crate::descriptors::{DescriptorAllocator, DescriptorLayoutCache, DescriptorWriter, SetLayoutDesc};
+use crate::device::Device;
+use crate::glsl::GlslCompiler;
+use crate::pipeline::{GraphicsPipeline, GraphicsPipelineBuilder, PipelineTarget};
+use crate::renderer3d::MeshData;
+use crate::requirements::Feature;
+use crate::shader::ShaderModule;
+
+const MESHLET_TASK: &str = include_str!("../shaders/meshlet.task");
+const MESHLET_MESH: &str = include_str!("../shaders/meshlet.mesh");
+
+/// The most vertices a meshlet holds, which is what the mesh shader outputs at most.
+pub const MAX_MESHLET_VERTICES: usize = 64;
+
+/// The most triangles a meshlet holds. Keeps the mesh shader's output within 8 KiB on every vendor's layout.
+pub const MAX_MESHLET_TRIANGLES: usize = 124;
+
+/// Meshlets one task shader workgroup culls, and the size of its workgroup.
+const TASK_WORKGROUP_SIZE: u32 = 32;
+
+/// Below `maxTaskWorkGroupCount` and `maxTaskWorkGroupTotalCount` on every device with mesh shaders.
+const MAX_TASK_GROUPS_Y: u32 = 65535;
+const MAX_TASK_GROUPS_TOTAL: u32 = 1 << 22;
+
+/// Where in the mesh set the mesh shading pipelines read each buffer.
+const INSTANCE_BINDING: u32 = 0;
+const VERTEX_BINDING: u32 = 1;
+const MESHLET_BINDING: u32 = 2;
+const MESHLET_VERTEX_BINDING: u32 = 3;
+const MESHLET_TRIANGLE_BINDING: u32 = 4;
+
+/// The set the pipelines from [`MeshShading::pipeline_base`] read meshlets and instances from, after the material's.
+pub const MESHLET_SET: u32 = 2;
+
+/// Up to [`MAX_MESHLET_VERTICES`] vertices and [`MAX_MESHLET_TRIANGLES`] triangles of a mesh, with the bounds the
+/// task shader culls it by. Laid out like the `Meshlet` struct of `meshlet.task`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Meshlet {
+    /// Where the meshlet's vertices start in [`MeshletData::vertices`].
+    pub vertex_offset: u32,
+    /// Where the meshlet's triangles start in [`MeshletData::triangles`].
+    pub triangle_offset: u32,
+    pub vertex_count: u32,
+    pub triangle_count: u32,
+    /// Bounding sphere in model space.
+    pub center: [f32; 3],
+    pub radius: f32,
+    /// Average normal of the triangles. The meshlet faces away from every point where the direction to its center
+    /// is within `acos(cone_cutoff)` of the axis, after accounting for the radius.
+    pub cone_axis: [f32; 3],
+    /// 1 when the triangles face too many ways for the cone to cull anything.
+    pub cone_cutoff: f32,
+}
+
+unsafe impl Zeroable for Meshlet {}
+unsafe impl Pod for Meshlet {}
+
+/// A mesh split into meshlets. Built greedily in index order like meshoptimizer's scan builder, so index buffers
+/// optimized for vertex cache locality give tighter meshlets.
+#[derive(Debug, Clone, Default)]
+pub struct MeshletData {
+    pub meshlets: Vec<Meshlet>,
+    /// The mesh vertex each meshlet vertex is, by meshlet.
+    pub vertices: Vec<u32>,
+    /// Three meshlet vertex indices per triangle, packed into the low three bytes.
+    pub triangles: Vec<u32>,
+}
+
+impl MeshletData {
+    /// Splits the triangle list `indices` into meshlets. Only the positions of the vertices are read.
+    pub fn build(positions: &[[f32; 3]], indices: &[u32]) -> Self {
+        let mut data = Self::default();
+        // The meshlet vertex of each mesh vertex in the meshlet being filled.
+        let mut local = vec![u32::MAX; positions.len()];
+        let mut meshlet = Meshlet::default();
+
+        for triangle in indices.chunks_exact(3) {
+            let mut new_vertices = 0;
+            for (corner, &vertex) in triangle.iter().enumerate() {
+                if local[vertex as usize] == u32::MAX && !triangle[..corner].contains(&vertex) {
+                    new_vertices += 1;
+                }
+            }
+
+            if meshlet.vertex_count as usize + new_vertices > MAX_MESHLET_VERTICES
+                || meshlet.triangle_count as usize == MAX_MESHLET_TRIANGLES
+            {
+                data.finish(&mut meshlet, &mut local, positions);
+            }
+
+            let mut packed = 0;
+            for (corner, &vertex) in triangle.iter().enumerate() {
+                if local[vertex as usize] == u32::MAX {
+                    local[vertex as usize] = meshlet.vertex_count;
+                    data.vertices.push(vertex);
+                    meshlet.vertex_count += 1;
+                }
+
+                packed |= local[vertex as usize] << (8 * corner);
+            }
+
+            data.triangles.push(packed);
+            meshlet.triangle_count += 1;
+        }
+
+        if meshlet.triangle_count > 0 {
+            data.finish(&mut meshlet, &mut local, positions);
+        }
+
+        data
+    }
+
+    /// Computes the bounds of `meshlet`, stores it and starts the next one.
+    fn finish(&mut self, meshlet: &mut Meshlet, local: &mut [u32], positions: &[[f32; 3]]) {
+        let vertices = &self.vertices[meshlet.vertex_offset as usize..];
+        let triangles = &self.triangles[meshlet.triangle_offset as usize..];
+        let position = |local_index: u32| Vector3::from(positions[vertices[local_index as usize] as usize]);
+
+        let mut min = Vector3::from(positions[vertices[0] as usize]);
+        let mut max = min;
+        for &vertex in vertices {
+            let p = Vector3::from(positions[vertex as usize]);
+            min = Vector3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z));
+            max = Vector3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z));
+        }
+
+        let center = (min + max) * 0.5;
+        let radius = vertices.iter()
+            .map(|&vertex| (Vector3::from(positions[vertex as usize]) - center).magnitude())
+            .fold(0.0, f32::max);
+
+        let normals: Vec<Vector3<f32>> = triangles.iter()
+            .map(|&packed| {
+                let [a, b, c] = [packed & 0xff, (packed >> 8) & 0xff, (packed >> 16) & 0xff].map(position);
+                (b - a).cross(c - a)
+            })
+            .filter(|normal| normal.magnitude2() > 0.0)
+            .map(|normal| normal.normalize())
+            .collect();
+        let sum = normals.iter().fold(Vector3::new(0.0, 0.0, 0.0), |sum, &normal| sum + normal);
+
+        let (axis, cutoff) = match sum.magnitude2() > 0.0 {
+            true => {
+                let axis = sum.normalize();
+                let spread = normals.iter().map(|normal| normal.dot(axis)).fold(1.0, f32::min);
+                // Wider than about 84 degrees from the axis, some triangle faces the camera from almost anywhere.
+                (axis, if spread <= 0.1 { 1.0 } else { (1.0 - spread * spread).sqrt() })
+            }
+            false => (Vector3::new(0.0, 0.0, 1.0), 1.0),
+        };
+
+        meshlet.center = center.into();
+        meshlet.radius = radius;
+        meshlet.cone_axis = axis.into();
+        meshlet.cone_cutoff = cutoff;
+
+        for &vertex in vertices {
+            local[vertex as usize] = u32::MAX;
+        }
+
+        self.meshlets.push(*meshlet);
+        *meshlet = Meshlet {
+            vertex_offset: self.vertices.len() as u32,
+            triangle_offset: self.triangles.len() as u32,
+            ..Meshlet::default()
+        };
+    }
+}
+
+/// Where a mesh's meshlets are in a [`MeshletArena`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MeshletRange {
+    pub first_meshlet: u32,
+    pub meshlet_count: u32,
+}
+
+/// The vertices and meshlets of many meshes in one set of storage buffers, with the meshlets' offsets rebased onto
+/// them, so one descriptor set reaches every mesh.
+pub struct MeshletArena {
+    vertex_buffer: Buffer,
+    meshlet_buffer: Buffer,
+    meshlet_vertex_buffer: Buffer,
+    meshlet_triangle_buffer: Buffer,
+    ranges: Vec<MeshletRange>,
+}
+
+impl MeshletArena {
+    pub unsafe fn new<'m>(device: &Arc<Device>, meshes: impl IntoIterator<Item = (&'m MeshData, &'m MeshletData)>) -> anyhow::Result<Self> {
+        let mut vertices = Vec::new();
+        let mut meshlets = Vec::new();
+        let mut meshlet_vertices = Vec::new();
+        let mut meshlet_triangles = Vec::new();
+        let mut ranges = Vec::new();
+
+        for (mesh, data) in meshes {
+            ranges.push(MeshletRange {
+                first_meshlet: meshlets.len() as u32,
+                meshlet_count: data.meshlets.len() as u32,
+            });
+
+            let (vertex_base, triangle_base) = (meshlet_vertices.len() as u32, meshlet_triangles.len() as u32);
+            meshlets.extend(data.meshlets.iter().map(|meshlet| Meshlet {
+                vertex_offset: meshlet.vertex_offset + vertex_base,
+                triangle_offset: meshlet.triangle_offset + triangle_base,
+                ..*meshlet
+            }));
+            meshlet_vertices.extend(data.vertices.iter().map(|&vertex| vertex + vertices.len() as u32));
+            meshlet_triangles.extend_from_slice(&data.triangles);
+            vertices.extend_from_slice(&mesh.vertices);
+        }
+
+        debug!("Built a meshlet arena of {} meshes and {} meshlets", ranges.len(), meshlets.len());
+        Ok(Self {
+            vertex_buffer: storage_buffer(device, "meshlet arena vertices", &vertices)?,
+            meshlet_buffer: storage_buffer(device, "meshlet arena meshlets", &meshlets)?,
+            meshlet_vertex_buffer: storage_buffer(device, "meshlet arena meshlet vertices", &meshlet_vertices)?,
+            meshlet_triangle_buffer: storage_buffer(device, "meshlet arena meshlet triangles", &meshlet_triangles)?,
+            ranges,
+        })
+    }
+
+    pub fn mesh_count(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// The meshlets of the `index`th mesh the arena was built from.
+    pub fn range(&self, index: usize) -> MeshletRange {
+        self.ranges[index]
+    }
+}
+
+/// A device local storage buffer holding `data`, with room for one element when it's empty.
+unsafe fn storage_buffer<T: Pod>(device: &Arc<Device>, name: &str, data: &[T]) -> anyhow::Result<Buffer> {
+    let size = std::mem::size_of_val(data).max(std::mem::size_of::<T>()) as vk::DeviceSize;
+    let mut buffer = Buffer::new(device, name, size, vk::BufferUsageFlags::STORAGE_BUFFER, MemoryLocation::GpuOnly)?;
+    buffer.upload(data)?;
+    Ok(buffer)
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct MeshletPushConstants {
+    first_meshlet: u32,
+    meshlet_count: u32,
+    first_instance: u32,
+    /// Nonzero to cull meshlets facing away from the camera, which is only right for back face culled materials.
+    cone_culling: u32,
+}
+
+unsafe impl Zeroable for MeshletPushConstants {}
+unsafe impl Pod for MeshletPushConstants {}
+
+/// Whether the device was created with task and mesh shaders, which the frame set's camera binding has to know
+/// before [`MeshShading`] exists.
+pub fn supports_mesh_shading(device: &Device) -> bool {
+    let capabilities = device.capabilities();
+    capabilities.has_feature(Feature::TaskShader) && capabilities.has_feature(Feature::MeshShader)
+}
+
+/// The task and mesh shaders that take the place of `mesh.vert` for materials drawing meshlets. The task shader
+/// culls each instance's meshlets against the camera's frustum and, for back face culled materials, by their
+/// normal cones; the mesh shader writes the same outputs as `mesh.vert`, so material fragment shaders work
+/// unchanged. Both read the frame's instances and a [`MeshletArena`] from the set at [`MESHLET_SET`].
+pub struct MeshShading {
+    device: Arc<Device>,
+    loader: ext::MeshShader,
+    task: ShaderModule,
+    mesh: ShaderModule,
+    set_layout: vk::DescriptorSetLayout,
+    /// Written by `prepare` every frame, since the instance buffer can grow.
+    sets: Vec<vk::DescriptorSet>,
+}
+
+impl MeshShading {
+    /// `None` unless [`supports_mesh_shading`].
+    pub unsafe fn new(
+        device: &Arc<Device>,
+        layouts: &mut DescriptorLayoutCache,
+        allocator: &mut DescriptorAllocator,
+        compiler: &GlslCompiler,
+        frames_in_flight: usize,
+    ) -> anyhow::Result<Option<Self>> {
+        if !supports_mesh_shading(device) {
+            return Ok(None);
+        }
+
+        let module = |name: &str, source: &str, stage: vk::ShaderStageFlags| {
+            ShaderModule::from_bytes_with_stage(device, name, &compiler.compile_source(source, stage, name)?, stage)
+        };
+        let both = vk::ShaderStageFlags::TASK_EXT | vk::ShaderStageFlags::MESH_EXT;
+        let set_layout = layouts.get(&SetLayoutDesc::new()
+            .binding(INSTANCE_BINDING, vk::DescriptorType::STORAGE_BUFFER, both)
+            .binding(VERTEX_BINDING, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::MESH_EXT)
+            .binding(MESHLET_BINDING, vk::DescriptorType::STORAGE_BUFFER, both)
+            .binding(MESHLET_VERTEX_BINDING, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::MESH_EXT)
+            .binding(MESHLET_TRIANGLE_BINDING, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::MESH_EXT))?;
+
+        Ok(Some(Self {
+            device: device.clone(),
+            loader: ext::MeshShader::new(device.instance().handle(), device.handle()),
+            task: module("meshlet.task", MESHLET_TASK, vk::ShaderStageFlags::TASK_EXT)?,
+            mesh: module("meshlet.mesh", MESHLET_MESH, vk::ShaderStageFlags::MESH_EXT)?,
+            set_layout,
+            sets: (0..frames_in_flight)
+                .map(|_| allocator.allocate(set_layout))
+                .collect::<anyhow::Result<_>>()?,
+        }))
+    }
+
+    /// The task and mesh shaders, the push constants and `frame_layout` at set 0, drawing into `target`. Materials
+    /// add their fragment shader and set, then [`set_layout`](Self::set_layout) at [`MESHLET_SET`].
+    pub fn pipeline_base(&self, frame_layout: vk::DescriptorSetLayout, target: PipelineTarget) -> GraphicsPipelineBuilder {
+        GraphicsPipelineBuilder::new()
+            .shader(&self.task)
+            .shader(&self.mesh)
+            .push_constants::<MeshletPushConstants>(vk::ShaderStageFlags::TASK_EXT | vk::ShaderStageFlags::MESH_EXT, 0)
+            .descriptor_set_layout(frame_layout)
+            .target(target)
+    }
+
+    pub fn set_layout(&self) -> vk::DescriptorSetLayout {
+        self.set_layout
+    }
+
+    /// Points the set of `frame_index` at this frame's `instances`, laid out as `InstanceData`, and at `arena`.
+    pub unsafe fn write_set(&self, frame_index: usize, instances: &Buffer, arena: &MeshletArena) {
+        [
+            (INSTANCE_BINDING, instances),
+            (VERTEX_BINDING, &arena.vertex_buffer),
+            (MESHLET_BINDING, &arena.meshlet_buffer),
+            (MESHLET_VERTEX_BINDING, &arena.meshlet_vertex_buffer),
+            (MESHLET_TRIANGLE_BINDING, &arena.meshlet_triangle_buffer),
+        ]
+            .iter()
+            .fold(DescriptorWriter::new(), |writer, &(binding, buffer)| {
+                writer.buffer(binding, vk::DescriptorType::STORAGE_BUFFER, buffer.handle(), 0, vk::WHOLE_SIZE)
+            })
+            .update(&self.device, self.sets[frame_index]);
+    }
+
+    pub unsafe fn bind_set(&self, command_buffer: vk::CommandBuffer, pipeline: &GraphicsPipeline, frame_index: usize) {
+        self.device.cmd_bind_descriptor_sets(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            pipeline.layout(),
+            MESHLET_SET,
+            &[self.sets[frame_index]],
+            &[],
+        );
+    }
+
+    /// Draws the meshlets of `range` for instances `first_instance..first_instance + instance_count` of the
+    /// frame's instance buffer, with a workgroup of the task shader per 32 meshlets and instance.
+    pub unsafe fn draw(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        pipeline: &GraphicsPipeline,
+        range: MeshletRange,
+        first_instance: u32,
+        instance_count: u32,
+        cone_culling: bool,
+    ) {
+        let groups_x = range.meshlet_count.div_ceil(TASK_WORKGROUP_SIZE);
+        if groups_x == 0 {
+            return;
+        }
+
+        let chunk = (MAX_TASK_GROUPS_TOTAL / groups_x).clamp(1, MAX_TASK_GROUPS_Y);
+        let mut drawn = 0;
+        while drawn < instance_count {
+            let count = chunk.min(instance_count - drawn);
+            let push = MeshletPushConstants {
+                first_meshlet: range.first_meshlet,
+                meshlet_count: range.meshlet_count,
+                first_instance: first_instance + drawn,
+                cone_culling: cone_culling as u32,
+            };
+
+            pipeline.push_constants(command_buffer, vk::ShaderStageFlags::TASK_EXT | vk::ShaderStageFlags::MESH_EXT, 0, &push);
+            self.loader.cmd_draw_mesh_tasks(command_buffer, groups_x, count, 1);
+            drawn += count;
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use std::cell::Cell;
+use std::sync::Arc;
+use ash::vk;
+use cgmath::Matrix4;
+use log::debug;
+use crate::compute::{memory_barrier, Access, ComputePipeline, ComputePipelineBuilder};
+use crate::descriptors::{DescriptorAllocator, DescriptorLayoutCache, DescriptorWriter, SetLayoutDesc};
+use crate::device::Device;
+use crate::glsl::GlslCompiler;
+use crate::image::{aspect_for_format, mip_levels_for, Image, ImageDesc};
+use crate::render_graph::{GraphImage, ImageAccess, RenderGraph};
+use crate::sampler::SamplerDesc;
+use crate::shader::ShaderModule;
+
+const HIZ_DOWNSAMPLE_COMP: &str = include_str!("../shaders/hiz_downsample.comp");
+
+const LOCAL_SIZE: u32 = 8;
+
+pub const HIZ_FORMAT: vk::Format = vk::Format::R32_SFLOAT;
+
+/// Whether [`HiZPyramid`] can read a depth buffer of `format`. It samples the buffer through its one view, which
+/// can't also cover a stencil aspect.
+pub fn supports_depth_format(format: vk::Format) -> bool {
+    aspect_for_format(format) == vk::ImageAspectFlags::DEPTH
+}
+
+struct Levels {
+    device: Arc<Device>,
+    image: Image,
+    /// One per mip level, written while building it and read while building the next.
+    views: Vec<vk::ImageView>,
+    /// The `i`th reads mip `i` and writes mip `i + 1`.
+    sets: Vec<vk::DescriptorSet>,
+    depth_extent: vk::Extent2D,
+}
+
+impl Drop for Levels {
+    fn drop(&mut self) {
+        unsafe {
+            for &view in &self.views {
+                self.device.destroy_image_view(view, None);
+            }
+        }
+    }
+}
+
+/// The farthest depth of every 2x2 block of a depth buffer, then of every 2x2 block of that and so on down to a
+/// single texel. Mip 0 is half the depth buffer's size, and every texel of mip `i` covers `2^(i+1)` pixels of it
+/// along each axis, so a few fetches tell whether something on screen lies behind everything drawn there.
+pub struct HiZPyramid {
+    device: Arc<Device>,
+    _layouts: DescriptorLayoutCache,
+    descriptor_allocator: DescriptorAllocator,
+    set_layout: vk::DescriptorSetLayout,
+    pipeline: ComputePipeline,
+    /// Read the depth buffer and write mip 0. One per frame in flight, since the depth view is written every frame.
+    depth_sets: Vec<vk::DescriptorSet>,
+    /// `None` until the first `resize`.
+    levels: Option<Levels>,
+    view_projection: Cell<Option<Matrix4<f32>>>,
+}
+
+impl HiZPyramid {
+    pub unsafe fn new(device: &Arc<Device>, compiler: &GlslCompiler, frames_in_flight: usize) -> anyhow::Result<Self> {
+        let mut layouts = DescriptorLayoutCache::new(device);
+        let set_layout = layouts.get(&SetLayoutDesc::new()
+            .binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE)
+            .binding(1, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE))?;
+
+        let shader = ShaderModule::from_bytes_with_stage(
+            device,
+            "hiz_downsample.comp",
+            &compiler.compile_source(HIZ_DOWNSAMPLE_COMP, vk::ShaderStageFlags::COMPUTE, "hiz_downsample.comp")?,
+            vk::ShaderStageFlags::COMPUTE,
+        )?;
+
+        let pipeline = ComputePipelineBuilder::new()
+            .shader(&shader)
+            .descriptor_set_layout(set_layout)
+            .build(device)?;
+
+        let mut descriptor_allocator = DescriptorAllocator::new(device);
+        let depth_sets = (0..frames_in_flight)
+            .map(|_| descriptor_allocator.allocate(set_layout))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            device: device.clone(),
+            _layouts: layouts,
+            descriptor_allocator,
+            set_layout,
+            pipeline,
+            depth_sets,
+            levels: None,
+            view_projection: Cell::new(None),
+        })
+    }
+
+    /// Sizes the pyramid for a depth buffer of `depth_extent`, waiting for the device to go idle when that changes
+    /// it. A resized pyramid holds nothing until it is built again.
+    pub unsafe fn resize(&mut self, depth_extent: vk::Extent2D) -> anyhow::Result<()> {
+        if self.levels.as_ref().is_some_and(|levels| levels.depth_extent == depth_extent) {
+            return Ok(());
+        }
+
+        self.device.device_wait_idle()?;
+        self.levels = None;
+        self.view_projection.set(None);
+
+        let width = (depth_extent.width / 2).max(1);
+        let height = (depth_extent.height / 2).max(1);
+        let desc = ImageDesc::new_2d(width, height, HIZ_FORMAT, vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED)
+            .with_mip_levels(mip_levels_for(width, height));
+        let image = Image::new(&self.device, "hiz", &desc)?;
+        debug!("Created a {}x{} HiZ pyramid of {} levels", width, height, desc.mip_levels);
+
+        let mut levels = Levels {
+            device: self.device.clone(),
+            image,
+            views: Vec::new(),
+            sets: Vec::new(),
+            depth_extent,
+        };
+
+        for level in 0..desc.mip_levels {
+            let view_info = vk::ImageViewCreateInfo::builder()
+                .image(levels.image.handle())
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(HIZ_FORMAT)
+                .subresource_range(vk::ImageSubresourceRange {
+                    base_mip_level: level,
+                    level_count: 1,
+                    ..levels.image.full_range()
+                });
+            levels.views.push(self.device.create_image_view(&view_info, None)?);
+        }
+
+        let sampler = self.device.sampler(&SamplerDesc::nearest())?;
+        for pair in levels.views.windows(2) {
+            let set = self.descriptor_allocator.allocate(self.set_layout)?;
+            DescriptorWriter::new()
+                .image(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, pair[0], sampler, vk::ImageLayout::GENERAL)
+                .image(1, vk::DescriptorType::STORAGE_IMAGE, pair[1], vk::Sampler::null(), vk::ImageLayout::GENERAL)
+                .update(&self.device, set);
+            levels.sets.push(set);
+        }
+
+        self.levels = Some(levels);
+        Ok(())
+    }
+
+    /// Every mip level, in `GENERAL` layout once the pyramid has been built. `None` before the first `resize`.
+    pub fn view(&self) -> Option<vk::ImageView> {
+        self.levels.as_ref().map(|levels| levels.image.view())
+    }
+
+    pub fn mip_levels(&self) -> u32 {
+        self.levels.as_ref().map_or(0, |levels| levels.image.mip_levels())
+    }
+
+    pub fn depth_extent(&self) -> vk::Extent2D {
+        self.levels.as_ref().map_or(vk::Extent2D::default(), |levels| levels.depth_extent)
+    }
+
+    /// What the depth buffer the pyramid was last built from was drawn with. `None` when it hasn't been built since
+    /// it was last resized.
+    pub fn view_projection(&self) -> Option<Matrix4<f32>> {
+        self.view_projection.get()
+    }
+
+    /// Forgets the last build, so nothing is culled against a pyramid that fell out of date.
+    pub fn invalidate(&self) {
+        self.view_projection.set(None);
+    }
+
+    /// Adds a compute pass reducing `depth`, which the scene was drawn into with `view_projection`, into the
+    /// pyramid. Add it after the last pass writing `depth`; nothing reads the result before the next frame. Does
+    /// nothing when `depth` isn't the size the pyramid was last resized for.
+    pub unsafe fn add_pass<'a>(&'a self, graph: &mut RenderGraph<'a>, depth: GraphImage, frame_index: usize, view_projection: Matrix4<f32>) {
+        let Some(levels) = &self.levels else {
+            return;
+        };
+
+        if graph.extent(depth) != levels.depth_extent {
+            return;
+        }
+
+        graph.add_pass("hiz")
+            .image(depth, ImageAccess::Sampled(vk::PipelineStageFlags::COMPUTE_SHADER))
+            .keep()
+            .execute(move |ctx| {
+                let command_buffer = ctx.command_buffer();
+                let depth_set = self.depth_sets[frame_index];
+                DescriptorWriter::new()
+                    .image(
+                        0,
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        ctx.view(depth),
+                        self.device.sampler(&SamplerDesc::nearest())?,
+                        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    )
+                    .image(1, vk::DescriptorType::STORAGE_IMAGE, levels.views[0], vk::Sampler::null(), vk::ImageLayout::GENERAL)
+                    .update(&self.device, depth_set);
+
+                // The whole pyramid is rewritten, but only after the culling pass is done reading it.
+                let barrier = vk::ImageMemoryBarrier::builder()
+                    .src_access_mask(vk::AccessFlags::SHADER_READ)
+                    .dst_access_mask(vk::AccessFlags::SHADER_WRITE)
+                    .old_layout(vk::ImageLayout::UNDEFINED)
+                    .new_layout(vk::ImageLayout::GENERAL)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .image(levels.image.handle())
+                    .subresource_range(levels.image.full_range())
+                    .build();
+                self.device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[barrier],
+                );
+
+                self.pipeline.bind(command_buffer);
+                let extent = levels.image.extent();
+                for level in 0..levels.image.mip_levels() {
+                    let set = match level {
+                        0 => depth_set,
+                        level => levels.sets[level as usize - 1],
+                    };
+
+                    let width = (extent.width >> level).max(1);
+                    let height = (extent.height >> level).max(1);
+                    self.pipeline.bind_descriptor_sets(command_buffer, 0, &[set]);
+                    self.pipeline.dispatch(command_buffer, width.div_ceil(LOCAL_SIZE), height.div_ceil(LOCAL_SIZE), 1);
+                    memory_barrier(&self.device, command_buffer, Access::COMPUTE_WRITE, Access::COMPUTE_READ);
+                }
+
+                self.view_projection.set(Some(view_projection));
+                Ok(())
+            });
+    }
+}
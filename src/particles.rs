@@ -0,0 +1,655 @@
+use std::sync::Arc;
+use std::time::Instant;
+use anyhow::anyhow;
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use cgmath::{Point3, Vector3};
+use crate::allocator::MemoryLocation;
+use crate::buffer::{Buffer, PerFrameUniform};
+use crate::compute::{memory_barrier, Access, ComputePipeline, ComputePipelineBuilder};
+use crate::descriptors::{DescriptorAllocator, DescriptorLayoutCache, DescriptorWriter, SetLayoutDesc};
+use crate::device::Device;
+use crate::glsl::{insert_after_version, GlslCompiler};
+use crate::pipeline::{BlendMode, DepthState, GraphicsPipeline, GraphicsPipelineBuilder, PipelineTarget};
+use crate::shader::ShaderModule;
+
+const PARTICLES_GLSL: &str = include_str!("../shaders/particles.glsl");
+const PARTICLE_EMIT_COMP: &str = include_str!("../shaders/particle_emit.comp");
+const PARTICLE_UPDATE_COMP: &str = include_str!("../shaders/particle_update.comp");
+const PARTICLE_COMPACT_COMP: &str = include_str!("../shaders/particle_compact.comp");
+const PARTICLE_VERT: &str = include_str!("../shaders/particle.vert");
+const PARTICLE_FRAG: &str = include_str!("../shaders/particle.frag");
+
+/// Samples of every curve the shaders interpolate between.
+pub const CURVE_SAMPLES: usize = 16;
+
+const WORKGROUP_SIZE: u32 = 64;
+
+/// Longest step simulated at once, so a hitch doesn't fling particles across the scene.
+const MAX_TIME_STEP: f32 = 0.1;
+
+/// `vec3 position, float age, vec3 velocity, float lifetime`.
+const PARTICLE_SIZE: vk::DeviceSize = 32;
+
+/// Byte offset of the `VkDrawIndirectCommand` in the counters buffer.
+const DRAW_OFFSET: vk::DeviceSize = 16;
+
+const COMPUTE_READ_WRITE: Access = Access {
+    stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+    access: vk::AccessFlags::from_raw(vk::AccessFlags::SHADER_READ.as_raw() | vk::AccessFlags::SHADER_WRITE.as_raw()),
+};
+
+const DRAW_READ: Access = Access {
+    stage: vk::PipelineStageFlags::from_raw(
+        vk::PipelineStageFlags::DRAW_INDIRECT.as_raw() | vk::PipelineStageFlags::VERTEX_SHADER.as_raw(),
+    ),
+    access: vk::AccessFlags::from_raw(vk::AccessFlags::INDIRECT_COMMAND_READ.as_raw() | vk::AccessFlags::SHADER_READ.as_raw()),
+};
+
+/// Values a [`Curve`] interpolates between.
+pub trait Lerp: Copy {
+    fn lerp(self, other: Self, t: f32) -> Self;
+}
+
+impl Lerp for f32 {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+}
+
+impl<const N: usize> Lerp for [f32; N] {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        std::array::from_fn(|index| Lerp::lerp(self[index], other[index], t))
+    }
+}
+
+/// A value over a particle's life, from 0 when it's emitted to 1 when it dies. Keys are linearly interpolated
+/// and held before the first and after the last one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Curve<T> {
+    keys: Vec<(f32, T)>,
+}
+
+impl<T: Lerp> Curve<T> {
+    pub fn constant(value: T) -> Self {
+        Self { keys: vec![(0.0, value)] }
+    }
+
+    pub fn linear(start: T, end: T) -> Self {
+        Self {
+            keys: vec![(0.0, start), (1.0, end)],
+        }
+    }
+
+    /// Adds a key at `time`, clamped to 0..=1, replacing the one already there.
+    pub fn with_key(mut self, time: f32, value: T) -> Self {
+        let time = time.clamp(0.0, 1.0);
+        match self.keys.iter().position(|&(key, _)| key >= time) {
+            Some(index) if self.keys[index].0 == time => self.keys[index].1 = value,
+            Some(index) => self.keys.insert(index, (time, value)),
+            None => self.keys.push((time, value)),
+        }
+
+        self
+    }
+
+    /// Sorted by time.
+    pub fn keys(&self) -> &[(f32, T)] {
+        &self.keys
+    }
+
+    pub fn sample(&self, time: f32) -> T {
+        match self.keys.iter().position(|&(key, _)| key > time) {
+            Some(0) => self.keys[0].1,
+            Some(index) => {
+                let (start_time, start) = self.keys[index - 1];
+                let (end_time, end) = self.keys[index];
+                start.lerp(end, (time - start_time) / (end_time - start_time))
+            }
+            None => self.keys[self.keys.len() - 1].1,
+        }
+    }
+
+    /// [`CURVE_SAMPLES`] evenly spaced samples, the first at 0 and the last at 1.
+    fn samples(&self) -> [T; CURVE_SAMPLES] {
+        std::array::from_fn(|index| self.sample(index as f32 / (CURVE_SAMPLES - 1) as f32))
+    }
+}
+
+/// What a [`ParticleEmitter`] emits and how its particles move and look over their life.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmitterDesc {
+    /// Size of the particle pool. Emission stalls while all of them are alive.
+    pub max_particles: u32,
+    /// Particles emitted per second.
+    pub rate: f32,
+    /// Shortest and longest lifetime in seconds; each particle gets a random one in between.
+    pub lifetime: [f32; 2],
+    /// Particles start at a random point within this distance of the emitter.
+    pub spawn_radius: f32,
+    pub velocity: Vector3<f32>,
+    /// Each particle's starting velocity gets a random offset up to this long.
+    pub velocity_spread: f32,
+    /// Added to the velocity every second, e.g. gravity.
+    pub acceleration: Vector3<f32>,
+    /// Multiplies the particle's own velocity.
+    pub speed_over_life: Curve<f32>,
+    /// Added to what the particle moves with, e.g. wind picking up.
+    pub velocity_over_life: Curve<[f32; 3]>,
+    /// Width and height of the billboard in world units.
+    pub size_over_life: Curve<f32>,
+    /// Linear RGBA the round sprite is tinted with.
+    pub color_over_life: Curve<[f32; 4]>,
+    /// Any mode but `Opaque`. Particles aren't sorted, so `Additive` holds up best.
+    pub blend: BlendMode,
+}
+
+impl EmitterDesc {
+    pub fn new(max_particles: u32) -> Self {
+        Self {
+            max_particles,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_rate(mut self, rate: f32) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    pub fn with_lifetime(mut self, min: f32, max: f32) -> Self {
+        self.lifetime = [min, max];
+        self
+    }
+
+    pub fn with_spawn_radius(mut self, spawn_radius: f32) -> Self {
+        self.spawn_radius = spawn_radius;
+        self
+    }
+
+    pub fn with_velocity(mut self, velocity: Vector3<f32>, spread: f32) -> Self {
+        self.velocity = velocity;
+        self.velocity_spread = spread;
+        self
+    }
+
+    pub fn with_acceleration(mut self, acceleration: Vector3<f32>) -> Self {
+        self.acceleration = acceleration;
+        self
+    }
+
+    pub fn with_speed_over_life(mut self, curve: Curve<f32>) -> Self {
+        self.speed_over_life = curve;
+        self
+    }
+
+    pub fn with_velocity_over_life(mut self, curve: Curve<[f32; 3]>) -> Self {
+        self.velocity_over_life = curve;
+        self
+    }
+
+    pub fn with_size_over_life(mut self, curve: Curve<f32>) -> Self {
+        self.size_over_life = curve;
+        self
+    }
+
+    pub fn with_color_over_life(mut self, curve: Curve<[f32; 4]>) -> Self {
+        self.color_over_life = curve;
+        self
+    }
+
+    pub fn with_blend(mut self, blend: BlendMode) -> Self {
+        self.blend = blend;
+        self
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.max_particles == 0 {
+            return Err(anyhow!("Particle emitters need room for at least one particle"));
+        }
+
+        if !(self.lifetime[0] > 0.0 && self.lifetime[0] <= self.lifetime[1]) {
+            return Err(anyhow!("Invalid particle lifetime {:?}", self.lifetime));
+        }
+
+        if self.blend == BlendMode::Opaque {
+            return Err(anyhow!("Particles can't be drawn opaque"));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for EmitterDesc {
+    fn default() -> Self {
+        Self {
+            max_particles: 1024,
+            rate: 64.0,
+            lifetime: [1.0, 2.0],
+            spawn_radius: 0.1,
+            velocity: Vector3::new(0.0, 1.0, 0.0),
+            velocity_spread: 0.25,
+            acceleration: Vector3::new(0.0, 0.0, 0.0),
+            speed_over_life: Curve::constant(1.0),
+            velocity_over_life: Curve::constant([0.0; 3]),
+            size_over_life: Curve::linear(0.1, 0.05),
+            color_over_life: Curve::linear([1.0; 4], [1.0, 1.0, 1.0, 0.0]),
+            blend: BlendMode::Additive,
+        }
+    }
+}
+
+/// An [`EmitterDesc`] as the shaders see it, in binding 0 of the emitter's set.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct EmitterUniform {
+    position_radius: [f32; 4],
+    velocity_spread: [f32; 4],
+    acceleration: [f32; 4],
+    lifetime: [f32; 4],
+    pool: [u32; 4],
+    scalar_curves: [[f32; 4]; CURVE_SAMPLES],
+    velocity_curve: [[f32; 4]; CURVE_SAMPLES],
+    color_curve: [[f32; 4]; CURVE_SAMPLES],
+}
+
+unsafe impl Zeroable for EmitterUniform {}
+unsafe impl Pod for EmitterUniform {}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct SimulationParams {
+    delta: f32,
+    emit_count: u32,
+    current: u32,
+    seed: u32,
+}
+
+unsafe impl Zeroable for SimulationParams {}
+unsafe impl Pod for SimulationParams {}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct DrawParams {
+    current: u32,
+    premultiply: u32,
+    /// Where `particle.vert` built with `BUFFER_REFERENCE` reads the particles and alive lists, zero otherwise.
+    particles: vk::DeviceAddress,
+    alive: vk::DeviceAddress,
+}
+
+unsafe impl Zeroable for DrawParams {}
+unsafe impl Pod for DrawParams {}
+
+/// The GPU side pool of one emitter: its particles, two alive lists that compaction ping-pongs between, a dead
+/// list of free slots and the counters of all three, which double as the indirect draw of the billboards.
+pub struct ParticleEmitter {
+    desc: EmitterDesc,
+    position: Point3<f32>,
+    emitting: bool,
+    uniform: PerFrameUniform<EmitterUniform>,
+    _particles: Buffer,
+    _alive: Buffer,
+    _dead: Buffer,
+    /// Device addresses of the particles and alive lists when the system reads them that way, zero otherwise.
+    pool_addresses: [vk::DeviceAddress; 2],
+    counters: Buffer,
+    sets: Vec<vk::DescriptorSet>,
+    /// The alive list holding the particles that are drawn.
+    current: u32,
+    /// The fraction of a particle `rate` owes from earlier frames.
+    carry: f32,
+    burst: u32,
+}
+
+impl ParticleEmitter {
+    /// An emitter at the origin with every particle dead.
+    pub unsafe fn new(
+        device: &Arc<Device>,
+        system: &ParticleSystem,
+        allocator: &mut DescriptorAllocator,
+        desc: EmitterDesc,
+        frames_in_flight: usize,
+    ) -> anyhow::Result<Self> {
+        desc.validate()?;
+
+        let capacity = desc.max_particles as vk::DeviceSize;
+        let index_size = std::mem::size_of::<u32>() as vk::DeviceSize;
+        let storage = |name: &str, size: vk::DeviceSize| if system.buffer_reference {
+            Buffer::addressable(device, name, size, vk::BufferUsageFlags::STORAGE_BUFFER, MemoryLocation::GpuOnly)
+        } else {
+            Buffer::storage(device, name, size)
+        };
+        let particles = storage("particles", capacity * PARTICLE_SIZE)?;
+        let alive = storage("alive particles", 2 * capacity * index_size)?;
+        let dead = Buffer::with_data(
+            device,
+            "dead particles",
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            &(0..desc.max_particles).collect::<Vec<u32>>(),
+        )?;
+        let counters = Buffer::with_data(
+            device,
+            "particle counters",
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::INDIRECT_BUFFER,
+            &[0, 0, desc.max_particles, 0, 6, 0, 0, 0u32],
+        )?;
+        let uniform = PerFrameUniform::new(device, "emitter", frames_in_flight)?;
+        let pool_addresses = if system.buffer_reference {
+            [particles.device_address(), alive.device_address()]
+        } else {
+            [0; 2]
+        };
+
+        let sets = (0..frames_in_flight)
+            .map(|frame_index| {
+                let set = allocator.allocate(system.set_layout)?;
+                let info = uniform.descriptor_info(frame_index);
+
+                DescriptorWriter::new()
+                    .buffer(0, vk::DescriptorType::UNIFORM_BUFFER, info.buffer, info.offset, info.range)
+                    .buffer(1, vk::DescriptorType::STORAGE_BUFFER, particles.handle(), 0, vk::WHOLE_SIZE)
+                    .buffer(2, vk::DescriptorType::STORAGE_BUFFER, alive.handle(), 0, vk::WHOLE_SIZE)
+                    .buffer(3, vk::DescriptorType::STORAGE_BUFFER, dead.handle(), 0, vk::WHOLE_SIZE)
+                    .buffer(4, vk::DescriptorType::STORAGE_BUFFER, counters.handle(), 0, vk::WHOLE_SIZE)
+                    .update(device, set);
+
+                Ok(set)
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            desc,
+            position: Point3::new(0.0, 0.0, 0.0),
+            emitting: true,
+            uniform,
+            _particles: particles,
+            _alive: alive,
+            _dead: dead,
+            pool_addresses,
+            counters,
+            sets,
+            current: 0,
+            carry: 0.0,
+            burst: 0,
+        })
+    }
+
+    pub fn desc(&self) -> &EmitterDesc {
+        &self.desc
+    }
+
+    pub fn position(&self) -> Point3<f32> {
+        self.position
+    }
+
+    /// Where new particles are emitted around; the ones already alive don't move with it.
+    pub fn set_position(&mut self, position: Point3<f32>) {
+        self.position = position;
+    }
+
+    pub fn is_emitting(&self) -> bool {
+        self.emitting
+    }
+
+    /// Stops or resumes emitting at `rate`. Particles already alive live out their lifetime.
+    pub fn set_emitting(&mut self, emitting: bool) {
+        self.emitting = emitting;
+        self.carry = 0.0;
+    }
+
+    /// Emits `count` particles on top of the rate in the next simulated frame, even while not emitting.
+    pub fn burst(&mut self, count: u32) {
+        self.burst = self.burst.saturating_add(count);
+    }
+
+    /// How many particles to emit this frame.
+    fn take_emit_count(&mut self, delta: f32) -> u32 {
+        if self.emitting {
+            self.carry += self.desc.rate.max(0.0) * delta;
+        }
+
+        let count = self.carry.floor();
+        self.carry -= count;
+
+        let count = (count as u32).saturating_add(std::mem::take(&mut self.burst));
+        count.min(self.desc.max_particles)
+    }
+
+    fn write_uniform(&mut self, frame_index: usize) -> anyhow::Result<()> {
+        let desc = &self.desc;
+        let speed = desc.speed_over_life.samples();
+        let size = desc.size_over_life.samples();
+        let velocity = desc.velocity_over_life.samples();
+
+        self.uniform.write(frame_index, &EmitterUniform {
+            position_radius: [self.position.x, self.position.y, self.position.z, desc.spawn_radius],
+            velocity_spread: [desc.velocity.x, desc.velocity.y, desc.velocity.z, desc.velocity_spread],
+            acceleration: [desc.acceleration.x, desc.acceleration.y, desc.acceleration.z, 0.0],
+            lifetime: [desc.lifetime[0], desc.lifetime[1], 0.0, 0.0],
+            pool: [desc.max_particles, 0, 0, 0],
+            scalar_curves: std::array::from_fn(|index| [speed[index], size[index], 0.0, 0.0]),
+            velocity_curve: velocity.map(|[x, y, z]| [x, y, z, 0.0]),
+            color_curve: desc.color_over_life.samples(),
+        })
+    }
+}
+
+/// Simulates [`ParticleEmitter`]s with three compute passes and draws them as camera facing billboards.
+///
+/// Emission pops free slots off the dead list onto the current alive list, the update pass ages and moves every
+/// particle on it, and compaction moves the survivors to the other alive list and the rest back to the dead list,
+/// counting the billboards to draw as it goes. Nothing is read back, so the CPU never waits on the simulation.
+pub struct ParticleSystem {
+    device: Arc<Device>,
+    set_layout: vk::DescriptorSetLayout,
+    emit: ComputePipeline,
+    update: ComputePipeline,
+    compact: ComputePipeline,
+    /// One per blend mode, indexed by `pipeline_index`.
+    pipelines: Vec<GraphicsPipeline>,
+    last_frame: Option<Instant>,
+    seed: u32,
+    /// Whether the billboards read the pool through buffer device addresses pushed with each draw rather than the
+    /// emitter's set, where the device supports them.
+    buffer_reference: bool,
+}
+
+impl ParticleSystem {
+    /// `frame_layout` is the renderer's set 0, which holds the camera in binding 0. Emitters go in set 1.
+    pub unsafe fn new(
+        device: &Arc<Device>,
+        layouts: &mut DescriptorLayoutCache,
+        compiler: &GlslCompiler,
+        target: &PipelineTarget,
+        frame_layout: vk::DescriptorSetLayout,
+    ) -> anyhow::Result<Self> {
+        let set_layout = layouts.get(&particle_set_layout())?;
+        let compute = |name: &str, source: &str| -> anyhow::Result<ComputePipeline> {
+            let source = insert_after_version(source, &format!("#define PARTICLE_SET 0\n{}", PARTICLES_GLSL));
+            let shader = ShaderModule::from_bytes_with_stage(
+                device,
+                name,
+                &compiler.compile_source(&source, vk::ShaderStageFlags::COMPUTE, name)?,
+                vk::ShaderStageFlags::COMPUTE,
+            )?;
+
+            ComputePipelineBuilder::new()
+                .shader(&shader)
+                .descriptor_set_layout(set_layout)
+                .push_constants::<SimulationParams>(0)
+                .build(device)
+        };
+
+        let emit = compute("particle_emit.comp", PARTICLE_EMIT_COMP)?;
+        let update = compute("particle_update.comp", PARTICLE_UPDATE_COMP)?;
+        let compact = compute("particle_compact.comp", PARTICLE_COMPACT_COMP)?;
+
+        // The extension has to come before the declarations of particles.glsl.
+        let buffer_reference = device.supports_buffer_device_address();
+        let buffer_reference_defines = if buffer_reference {
+            "#extension GL_EXT_buffer_reference : require\n#define BUFFER_REFERENCE\n"
+        } else {
+            ""
+        };
+        let vertex_source = insert_after_version(
+            PARTICLE_VERT,
+            &format!("{}#define PARTICLE_SET 1\n#define PARTICLES_READONLY\n{}", buffer_reference_defines, PARTICLES_GLSL),
+        );
+        let vertex = ShaderModule::from_bytes_with_stage(
+            device,
+            "particle.vert",
+            &compiler.compile_source(&vertex_source, vk::ShaderStageFlags::VERTEX, "particle.vert")?,
+            vk::ShaderStageFlags::VERTEX,
+        )?;
+        let fragment = ShaderModule::from_bytes_with_stage(
+            device,
+            "particle.frag",
+            &compiler.compile_source(PARTICLE_FRAG, vk::ShaderStageFlags::FRAGMENT, "particle.frag")?,
+            vk::ShaderStageFlags::FRAGMENT,
+        )?;
+
+        let pipelines = [BlendMode::Alpha, BlendMode::Premultiplied, BlendMode::Additive].into_iter()
+            .map(|blend| {
+                GraphicsPipelineBuilder::new()
+                    .shader(&vertex)
+                    .shader(&fragment)
+                    .cull_mode(vk::CullModeFlags::NONE)
+                    .depth(DepthState::READ_ONLY)
+                    .blend(blend)
+                    .descriptor_set_layout(frame_layout)
+                    .descriptor_set_layout(set_layout)
+                    .push_constants::<DrawParams>(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT, 0)
+                    .target(target.clone())
+                    .build(device)
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            device: device.clone(),
+            set_layout,
+            emit,
+            update,
+            compact,
+            pipelines,
+            last_frame: None,
+            seed: 0,
+            buffer_reference,
+        })
+    }
+
+    pub fn set_layout(&self) -> vk::DescriptorSetLayout {
+        self.set_layout
+    }
+
+    /// Writes the parameters of `emitters` for `frame_index` and records their simulation by the time passed
+    /// since the last call, followed by a barrier that makes it visible to `record`. Record it outside of any
+    /// render pass.
+    pub unsafe fn simulate(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        emitters: &mut [ParticleEmitter],
+    ) -> anyhow::Result<()> {
+        let now = Instant::now();
+        let delta = self.last_frame.map_or(0.0, |last_frame| (now - last_frame).as_secs_f32()).min(MAX_TIME_STEP);
+        self.last_frame = Some(now);
+
+        if emitters.is_empty() {
+            return Ok(());
+        }
+
+        let mut params = Vec::with_capacity(emitters.len());
+        for emitter in emitters.iter_mut() {
+            emitter.write_uniform(frame_index)?;
+            self.seed = self.seed.wrapping_add(1);
+            params.push(SimulationParams {
+                delta,
+                emit_count: emitter.take_emit_count(delta),
+                current: emitter.current,
+                seed: self.seed,
+            });
+        }
+
+        // The pools are shared by every frame in flight, so wait for the previous frame's billboards.
+        memory_barrier(&self.device, command_buffer, DRAW_READ, COMPUTE_READ_WRITE);
+
+        for (stage, pipeline) in [&self.emit, &self.update, &self.compact].into_iter().enumerate() {
+            if stage > 0 {
+                memory_barrier(&self.device, command_buffer, Access::COMPUTE_WRITE, COMPUTE_READ_WRITE);
+            }
+
+            pipeline.bind(command_buffer);
+            for (emitter, params) in emitters.iter().zip(&params) {
+                // Emission always runs, since its first invocation resets the list compaction fills.
+                let invocations = match stage {
+                    0 => params.emit_count.max(1),
+                    _ => emitter.desc.max_particles,
+                };
+
+                pipeline.bind_descriptor_sets(command_buffer, 0, &[emitter.sets[frame_index]]);
+                pipeline.push_constants(command_buffer, 0, params);
+                pipeline.dispatch(command_buffer, invocations.div_ceil(WORKGROUP_SIZE), 1, 1);
+            }
+        }
+
+        memory_barrier(&self.device, command_buffer, Access::COMPUTE_WRITE, DRAW_READ);
+
+        for emitter in emitters {
+            emitter.current = 1 - emitter.current;
+        }
+
+        Ok(())
+    }
+
+    /// Draws the particles of `emitters` simulated for `frame_index` into the current pass, which must have a
+    /// depth attachment they are tested against.
+    pub unsafe fn record(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        frame_set: vk::DescriptorSet,
+        frame_index: usize,
+        emitters: &[ParticleEmitter],
+    ) {
+        for emitter in emitters {
+            let pipeline = &self.pipelines[pipeline_index(emitter.desc.blend)];
+            pipeline.bind(command_buffer);
+            self.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.layout(),
+                0,
+                &[frame_set, emitter.sets[frame_index]],
+                &[],
+            );
+            pipeline.push_constants(
+                command_buffer,
+                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                0,
+                &DrawParams {
+                    current: emitter.current,
+                    premultiply: (emitter.desc.blend != BlendMode::Alpha) as u32,
+                    particles: emitter.pool_addresses[0],
+                    alive: emitter.pool_addresses[1],
+                },
+            );
+            self.device.cmd_draw_indirect(command_buffer, emitter.counters.handle(), DRAW_OFFSET, 1, 0);
+        }
+    }
+}
+
+fn pipeline_index(blend: BlendMode) -> usize {
+    match blend {
+        BlendMode::Opaque | BlendMode::Alpha => 0,
+        BlendMode::Premultiplied => 1,
+        BlendMode::Additive => 2,
+    }
+}
+
+fn particle_set_layout() -> SetLayoutDesc {
+    let stages = vk::ShaderStageFlags::COMPUTE | vk::ShaderStageFlags::VERTEX;
+    (1..5).fold(
+        SetLayoutDesc::new().binding(0, vk::DescriptorType::UNIFORM_BUFFER, stages),
+        |layout, binding| layout.binding(binding, vk::DescriptorType::STORAGE_BUFFER, stages),
+    )
+}
@@ -0,0 +1,193 @@
+use std::ffi::{CStr, CString};
+use ash::extensions::khr;
+use ash::vk;
+use log::{info, warn};
+use thiserror::Error;
+use crate::instance::Instance;
+use crate::requirements::{DeviceRequirements, DeviceSupport};
+
+pub const ADAPTER_ENV_VAR: &str = "LEGAMING_ADAPTER";
+
+#[derive(Error, Debug)]
+pub enum SelectionError {
+    #[error("No suitable GPU found")]
+    NoSuitableDevice,
+    #[error("No GPU matches adapter override '{0}'")]
+    AdapterNotFound(String),
+    #[error("GPU '{name}' was requested but is unsuitable: {reason}")]
+    AdapterUnsuitable { name: String, reason: String },
+    #[error("No GPU supports the required device extension {}", .0.to_string_lossy())]
+    MissingDeviceExtension(CString),
+}
+
+/// Which GPU to use. `Auto` picks the highest scoring suitable device; the other variants force a specific one by
+/// its position in `vkEnumeratePhysicalDevices` or by a case-insensitive substring of its name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum AdapterSelection {
+    #[default]
+    Auto,
+    Index(usize),
+    Name(String),
+}
+
+impl AdapterSelection {
+    /// Reads an override from `LEGAMING_ADAPTER`. Numeric values select by index, anything else by name.
+    pub fn from_env() -> Option<Self> {
+        let value = std::env::var(ADAPTER_ENV_VAR).ok()?;
+        let value = value.trim();
+
+        if value.is_empty() {
+            return None;
+        }
+
+        Some(match value.parse() {
+            Ok(index) => Self::Index(index),
+            Err(_) => Self::Name(value.to_owned()),
+        })
+    }
+
+    fn matches(&self, index: usize, name: &str) -> bool {
+        match self {
+            Self::Auto => true,
+            Self::Index(selected) => *selected == index,
+            Self::Name(selected) => name.to_lowercase().contains(&selected.to_lowercase()),
+        }
+    }
+}
+
+struct Candidate {
+    handle: vk::PhysicalDevice,
+    name: String,
+    device_type: vk::PhysicalDeviceType,
+    unsuitable_reason: Option<String>,
+    missing_extension: Option<CString>,
+    score: u64,
+}
+
+pub unsafe fn physical_device_name(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> String {
+    let properties = instance.get_physical_device_properties(physical_device);
+    CStr::from_ptr(properties.device_name.as_ptr()).to_string_lossy().into_owned()
+}
+
+unsafe fn score_device(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> u64 {
+    let properties = instance.get_physical_device_properties(physical_device);
+
+    let type_score = match properties.device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => 10_000,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => 1_000,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => 100,
+        vk::PhysicalDeviceType::CPU => 10,
+        _ => 0,
+    };
+
+    type_score + (properties.limits.max_image_dimension2_d / 1024) as u64
+}
+
+unsafe fn unsuitable_reason(
+    instance: &Instance,
+    physical_device: vk::PhysicalDevice,
+    surface: Option<(&khr::Surface, vk::SurfaceKHR)>,
+    requirements: &DeviceRequirements,
+    support: &DeviceSupport,
+) -> anyhow::Result<Option<String>> {
+    if let Some(missing) = requirements.missing(support) {
+        return Ok(Some(missing));
+    }
+
+    let families = instance.get_physical_device_queue_family_properties(physical_device);
+    if !families.iter().any(|family| family.queue_flags.contains(vk::QueueFlags::GRAPHICS)) {
+        return Ok(Some("no graphics queue family".into()));
+    }
+
+    if let Some((surface_fn, surface)) = surface {
+        let mut can_present = false;
+        for index in 0..families.len() {
+            can_present |= surface_fn.get_physical_device_surface_support(physical_device, index as u32, surface)?;
+        }
+
+        if !can_present {
+            return Ok(Some("cannot present to the surface".into()));
+        }
+
+        if surface_fn.get_physical_device_surface_formats(physical_device, surface)?.is_empty()
+            || surface_fn.get_physical_device_surface_present_modes(physical_device, surface)?.is_empty() {
+            return Ok(Some("no surface formats or present modes".into()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Scores every physical device (discrete > integrated > virtual > CPU) and returns the best one that meets the
+/// required parts of `requirements`, has a graphics queue and, when a surface is given, can present to it. An
+/// override from `LEGAMING_ADAPTER` takes precedence over `selection`.
+pub unsafe fn select_physical_device(
+    instance: &Instance,
+    surface: Option<(&khr::Surface, vk::SurfaceKHR)>,
+    requirements: &DeviceRequirements,
+    selection: &AdapterSelection,
+) -> anyhow::Result<vk::PhysicalDevice> {
+    let selection = AdapterSelection::from_env().unwrap_or_else(|| selection.clone());
+
+    let mut candidates = Vec::new();
+    for (index, handle) in instance.enumerate_physical_devices()?.into_iter().enumerate() {
+        let properties = instance.get_physical_device_properties(handle);
+        let name = physical_device_name(instance, handle);
+
+        if !selection.matches(index, &name) {
+            continue;
+        }
+
+        let support = DeviceSupport::query(instance, instance.api_version(), handle)?;
+        let candidate = Candidate {
+            handle,
+            device_type: properties.device_type,
+            unsuitable_reason: unsuitable_reason(instance, handle, surface, requirements, &support)?,
+            missing_extension: requirements.missing_extension(&support),
+            score: score_device(instance, handle),
+            name,
+        };
+
+        match &candidate.unsuitable_reason {
+            Some(reason) => warn!("GPU {} '{}' is unsuitable: {}", index, candidate.name, reason),
+            None => info!("GPU {} '{}' ({:?}) scored {}", index, candidate.name, candidate.device_type, candidate.score),
+        }
+
+        candidates.push(candidate);
+    }
+
+    if selection != AdapterSelection::Auto {
+        let candidate = candidates.first().ok_or_else(|| SelectionError::AdapterNotFound(format!("{:?}", selection)))?;
+
+        if let Some(extension) = candidate.missing_extension.clone() {
+            return Err(SelectionError::MissingDeviceExtension(extension).into());
+        }
+
+        if let Some(reason) = &candidate.unsuitable_reason {
+            return Err(SelectionError::AdapterUnsuitable {
+                name: candidate.name.clone(),
+                reason: reason.clone(),
+            }.into());
+        }
+
+        return Ok(candidate.handle);
+    }
+
+    let best = candidates.iter()
+        .filter(|candidate| candidate.unsuitable_reason.is_none())
+        .max_by_key(|candidate| candidate.score);
+
+    if let Some(best) = best {
+        return Ok(best.handle);
+    }
+
+    // Name the extension when that is what kept the highest scoring GPU out.
+    let missing_extension = candidates.iter()
+        .max_by_key(|candidate| candidate.score)
+        .and_then(|candidate| candidate.missing_extension.clone());
+
+    Err(match missing_extension {
+        Some(extension) => SelectionError::MissingDeviceExtension(extension),
+        None => SelectionError::NoSuitableDevice,
+    }.into())
+}
@@ -0,0 +1,673 @@
+use std::ffi::CString;
+use std::sync::Arc;
+use anyhow::anyhow;
+use ash::vk;
+use bytemuck::Pod;
+use log::warn;
+use crate::descriptors::{DescriptorLayoutCache, SetLayoutDesc};
+use crate::device::Device;
+use crate::render_pass::RenderPass;
+use crate::requirements::Feature;
+use crate::rendering::RenderingFormats;
+use crate::shader::ShaderModule;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VertexAttribute {
+    pub location: u32,
+    pub format: vk::Format,
+    pub offset: u32,
+}
+
+/// A `#[repr(C)]` vertex type whose fields are fed to the vertex shader. Implementations list one attribute per
+/// field, usually with `std::mem::offset_of!`.
+pub trait Vertex: Copy + 'static {
+    fn attributes() -> Vec<VertexAttribute>;
+}
+
+/// A shader stage referencing a module that must stay alive until the pipeline has been built.
+#[derive(Debug, Clone)]
+pub struct ShaderStage {
+    pub stage: vk::ShaderStageFlags,
+    pub module: vk::ShaderModule,
+    pub entry_point: CString,
+}
+
+impl ShaderStage {
+    pub fn new(stage: vk::ShaderStageFlags, module: vk::ShaderModule) -> Self {
+        Self {
+            stage,
+            module,
+            entry_point: CString::new("main").unwrap(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlendMode {
+    #[default]
+    Opaque,
+    /// Straight alpha: `src * a + dst * (1 - a)`.
+    Alpha,
+    /// Premultiplied alpha: `src + dst * (1 - a)`.
+    Premultiplied,
+    Additive,
+}
+
+impl BlendMode {
+    fn attachment_state(self) -> vk::PipelineColorBlendAttachmentState {
+        let (src_color, dst_color) = match self {
+            Self::Opaque => {
+                return vk::PipelineColorBlendAttachmentState {
+                    blend_enable: vk::FALSE,
+                    color_write_mask: vk::ColorComponentFlags::RGBA,
+                    ..Default::default()
+                };
+            }
+            Self::Alpha => (vk::BlendFactor::SRC_ALPHA, vk::BlendFactor::ONE_MINUS_SRC_ALPHA),
+            Self::Premultiplied => (vk::BlendFactor::ONE, vk::BlendFactor::ONE_MINUS_SRC_ALPHA),
+            Self::Additive => (vk::BlendFactor::ONE, vk::BlendFactor::ONE),
+        };
+
+        vk::PipelineColorBlendAttachmentState {
+            blend_enable: vk::TRUE,
+            src_color_blend_factor: src_color,
+            dst_color_blend_factor: dst_color,
+            color_blend_op: vk::BlendOp::ADD,
+            src_alpha_blend_factor: vk::BlendFactor::ONE,
+            dst_alpha_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+            alpha_blend_op: vk::BlendOp::ADD,
+            color_write_mask: vk::ColorComponentFlags::RGBA,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DepthState {
+    pub test: bool,
+    pub write: bool,
+    pub compare_op: vk::CompareOp,
+}
+
+impl DepthState {
+    pub const DISABLED: Self = Self {
+        test: false,
+        write: false,
+        compare_op: vk::CompareOp::ALWAYS,
+    };
+
+    /// Test and write with `LESS_OR_EQUAL`.
+    pub const READ_WRITE: Self = Self {
+        test: true,
+        write: true,
+        compare_op: vk::CompareOp::LESS_OR_EQUAL,
+    };
+
+    /// Test without writing, for transparent geometry drawn after the opaque pass.
+    pub const READ_ONLY: Self = Self {
+        test: true,
+        write: false,
+        compare_op: vk::CompareOp::LESS_OR_EQUAL,
+    };
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RasterState {
+    pub polygon_mode: vk::PolygonMode,
+    pub cull_mode: vk::CullModeFlags,
+    pub front_face: vk::FrontFace,
+    pub line_width: f32,
+    /// Constant and slope factors. With `DynamicState::DEPTH_BIAS` these are ignored and set with [`set_depth_bias`].
+    pub depth_bias: Option<(f32, f32)>,
+    /// Clamps depth to the viewport range instead of clipping against the near and far planes, so shadow casters
+    /// behind the light's near plane still write depth. Needs the `depthClamp` feature and is ignored without it.
+    pub depth_clamp: bool,
+}
+
+impl Default for RasterState {
+    fn default() -> Self {
+        Self {
+            polygon_mode: vk::PolygonMode::FILL,
+            cull_mode: vk::CullModeFlags::BACK,
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            line_width: 1.0,
+            depth_bias: None,
+            depth_clamp: false,
+        }
+    }
+}
+
+/// State that pipelines built with `GraphicsPipelineBuilder::extended_dynamic_state` take from the command buffer,
+/// core in Vulkan 1.3.
+pub const EXTENDED_DYNAMIC_STATES: [vk::DynamicState; 6] = [
+    vk::DynamicState::CULL_MODE,
+    vk::DynamicState::FRONT_FACE,
+    vk::DynamicState::PRIMITIVE_TOPOLOGY,
+    vk::DynamicState::DEPTH_TEST_ENABLE,
+    vk::DynamicState::DEPTH_WRITE_ENABLE,
+    vk::DynamicState::DEPTH_COMPARE_OP,
+];
+
+/// What a graphics pipeline renders into: a subpass of a render pass, or attachments of the given formats with
+/// dynamic rendering.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PipelineTarget {
+    RenderPass {
+        render_pass: vk::RenderPass,
+        subpass: u32,
+        color_count: usize,
+        samples: vk::SampleCountFlags,
+    },
+    Dynamic(RenderingFormats),
+}
+
+impl PipelineTarget {
+    fn color_count(&self) -> usize {
+        match self {
+            Self::RenderPass { color_count, .. } => *color_count,
+            Self::Dynamic(formats) => formats.color_formats.len(),
+        }
+    }
+
+    fn samples(&self) -> vk::SampleCountFlags {
+        match self {
+            Self::RenderPass { samples, .. } => *samples,
+            Self::Dynamic(formats) => formats.samples,
+        }
+    }
+
+    /// The views a multiview target renders, zero for a single view.
+    pub fn view_mask(&self) -> u32 {
+        match self {
+            Self::RenderPass { .. } => 0,
+            Self::Dynamic(formats) => formats.view_mask,
+        }
+    }
+}
+
+/// Builds a graphics pipeline and its layout. Viewport and scissor are dynamic by default, so pipelines don't
+/// have to be rebuilt on resize.
+pub struct GraphicsPipelineBuilder {
+    stages: Vec<ShaderStage>,
+    bindings: Vec<vk::VertexInputBindingDescription>,
+    attributes: Vec<vk::VertexInputAttributeDescription>,
+    topology: vk::PrimitiveTopology,
+    primitive_restart: bool,
+    raster: RasterState,
+    depth: DepthState,
+    blend: Vec<BlendMode>,
+    dynamic_states: Vec<vk::DynamicState>,
+    set_layouts: Vec<vk::DescriptorSetLayout>,
+    push_constant_ranges: Vec<vk::PushConstantRange>,
+    target: Option<PipelineTarget>,
+}
+
+impl GraphicsPipelineBuilder {
+    pub fn new() -> Self {
+        Self {
+            stages: Vec::new(),
+            bindings: Vec::new(),
+            attributes: Vec::new(),
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            primitive_restart: false,
+            raster: RasterState::default(),
+            depth: DepthState::DISABLED,
+            blend: Vec::new(),
+            dynamic_states: vec![vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR],
+            set_layouts: Vec::new(),
+            push_constant_ranges: Vec::new(),
+            target: None,
+        }
+    }
+
+    pub fn stage(mut self, stage: ShaderStage) -> Self {
+        self.stages.push(stage);
+        self
+    }
+
+    pub fn shader(self, module: &ShaderModule) -> Self {
+        self.stage(module.stage_info())
+    }
+
+    /// Starts from `modules` with the set layouts and push constants their reflection declares: bindings used by
+    /// several stages are merged, sets no stage uses in between get empty layouts, and push constants become a
+    /// single range visible to every stage using them. Fails when a module wasn't reflected or stages disagree on
+    /// a binding's type.
+    pub unsafe fn from_reflection(modules: &[&ShaderModule], layouts: &mut DescriptorLayoutCache) -> anyhow::Result<Self> {
+        let reflections = modules.iter()
+            .map(|module| module.reflection().ok_or_else(|| anyhow!("Shader '{}' has no reflection", module.name())))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut builder = modules.iter().fold(Self::new(), |builder, module| builder.shader(module));
+
+        let set_count = reflections.iter()
+            .flat_map(|reflection| &reflection.descriptor_bindings)
+            .map(|binding| binding.set + 1)
+            .max()
+            .unwrap_or(0);
+        for set in 0..set_count {
+            let desc = SetLayoutDesc::from_reflection(&reflections, set)?;
+            builder = builder.descriptor_set_layout(layouts.get(&desc)?);
+        }
+
+        let push_constants = reflections.iter()
+            .flat_map(|reflection| &reflection.push_constant_ranges)
+            .copied()
+            .reduce(|merged, range| {
+                let start = merged.offset.min(range.offset);
+                let end = (merged.offset + merged.size).max(range.offset + range.size);
+                vk::PushConstantRange {
+                    stage_flags: merged.stage_flags | range.stage_flags,
+                    offset: start,
+                    size: end - start,
+                }
+            });
+        if let Some(range) = push_constants {
+            builder = builder.push_constant_range(range);
+        }
+
+        Ok(builder)
+    }
+
+    /// Adds a per-vertex binding for `V` at `binding`.
+    pub fn vertex<V: Vertex>(self, binding: u32) -> Self {
+        self.vertex_binding::<V>(binding, vk::VertexInputRate::VERTEX)
+    }
+
+    /// Adds a per-instance binding for `V` at `binding`.
+    pub fn instance<V: Vertex>(self, binding: u32) -> Self {
+        self.vertex_binding::<V>(binding, vk::VertexInputRate::INSTANCE)
+    }
+
+    fn vertex_binding<V: Vertex>(mut self, binding: u32, input_rate: vk::VertexInputRate) -> Self {
+        self.bindings.push(vk::VertexInputBindingDescription {
+            binding,
+            stride: std::mem::size_of::<V>() as u32,
+            input_rate,
+        });
+
+        self.attributes.extend(V::attributes().into_iter().map(|attribute| vk::VertexInputAttributeDescription {
+            location: attribute.location,
+            binding,
+            format: attribute.format,
+            offset: attribute.offset,
+        }));
+
+        self
+    }
+
+    pub fn topology(mut self, topology: vk::PrimitiveTopology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    /// Restarts the strip or fan at the maximum index value (`0xFFFF` or `0xFFFFFFFF`), so one indexed draw can hold
+    /// several of them. Only allowed with strip and fan topologies.
+    pub fn primitive_restart(mut self, primitive_restart: bool) -> Self {
+        self.primitive_restart = primitive_restart;
+        self
+    }
+
+    pub fn raster(mut self, raster: RasterState) -> Self {
+        self.raster = raster;
+        self
+    }
+
+    pub fn cull_mode(mut self, cull_mode: vk::CullModeFlags) -> Self {
+        self.raster.cull_mode = cull_mode;
+        self
+    }
+
+    pub fn polygon_mode(mut self, polygon_mode: vk::PolygonMode) -> Self {
+        self.raster.polygon_mode = polygon_mode;
+        self
+    }
+
+    pub fn depth_bias(mut self, constant: f32, slope: f32) -> Self {
+        self.raster.depth_bias = Some((constant, slope));
+        self
+    }
+
+    /// Enables depth bias with factors set per draw through [`set_depth_bias`].
+    pub fn dynamic_depth_bias(mut self) -> Self {
+        self.raster.depth_bias.get_or_insert((0.0, 0.0));
+        self.dynamic_state(vk::DynamicState::DEPTH_BIAS)
+    }
+
+    /// See [`RasterState::depth_clamp`]. The app asks for the feature on every device that has it; `build` warns
+    /// when it wasn't enabled and clips instead.
+    pub fn depth_clamp(mut self, depth_clamp: bool) -> Self {
+        self.raster.depth_clamp = depth_clamp;
+        self
+    }
+
+    pub fn depth(mut self, depth: DepthState) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Blend mode for every color attachment. Use [`blend_attachments`](Self::blend_attachments) to set them
+    /// individually.
+    pub fn blend(mut self, blend: BlendMode) -> Self {
+        self.blend = vec![blend];
+        self
+    }
+
+    pub fn blend_attachments(mut self, blend: &[BlendMode]) -> Self {
+        self.blend = blend.to_vec();
+        self
+    }
+
+    pub fn dynamic_state(mut self, state: vk::DynamicState) -> Self {
+        if !self.dynamic_states.contains(&state) {
+            self.dynamic_states.push(state);
+        }
+        self
+    }
+
+    /// Makes every state in [`EXTENDED_DYNAMIC_STATES`] dynamic, so pipelines differing only in culling, topology
+    /// or depth state can be shared and set with `commands::set_extended_dynamic_state`. On devices without Vulkan 1.3
+    /// the builder's static state is used instead.
+    pub fn extended_dynamic_state(mut self) -> Self {
+        for state in EXTENDED_DYNAMIC_STATES {
+            self = self.dynamic_state(state);
+        }
+        self
+    }
+
+    pub fn descriptor_set_layout(mut self, layout: vk::DescriptorSetLayout) -> Self {
+        self.set_layouts.push(layout);
+        self
+    }
+
+    pub fn push_constant_range(mut self, range: vk::PushConstantRange) -> Self {
+        self.push_constant_ranges.push(range);
+        self
+    }
+
+    /// Declares a push constant range holding a `T` at `offset`, visible to `stages`.
+    pub fn push_constants<T: Pod>(self, stages: vk::ShaderStageFlags, offset: u32) -> Self {
+        self.push_constant_range(vk::PushConstantRange {
+            stage_flags: stages,
+            offset,
+            size: std::mem::size_of::<T>() as u32,
+        })
+    }
+
+    /// Targets `subpass` of `render_pass`, taking the color attachment count and sample count from it.
+    pub fn render_pass(self, render_pass: &RenderPass, subpass: u32) -> Self {
+        self.target(PipelineTarget::RenderPass {
+            render_pass: render_pass.handle(),
+            subpass,
+            color_count: render_pass.color_formats().len(),
+            samples: render_pass.samples(),
+        })
+    }
+
+    /// Targets dynamic rendering into attachments of `formats`.
+    pub fn rendering(self, formats: &RenderingFormats) -> Self {
+        self.target(PipelineTarget::Dynamic(formats.clone()))
+    }
+
+    pub fn target(mut self, target: PipelineTarget) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    pub unsafe fn build(&self, device: &Arc<Device>) -> anyhow::Result<GraphicsPipeline> {
+        let target = self.target.as_ref().ok_or(anyhow!("Graphics pipeline has no render pass or rendering formats"))?;
+
+        if self.stages.is_empty() {
+            return Err(anyhow!("Graphics pipeline has no shader stages"));
+        }
+
+        let strip = matches!(
+            self.topology,
+            vk::PrimitiveTopology::LINE_STRIP
+                | vk::PrimitiveTopology::TRIANGLE_STRIP
+                | vk::PrimitiveTopology::TRIANGLE_FAN
+                | vk::PrimitiveTopology::LINE_STRIP_WITH_ADJACENCY
+                | vk::PrimitiveTopology::TRIANGLE_STRIP_WITH_ADJACENCY
+        );
+        if self.primitive_restart && !strip {
+            return Err(anyhow!("Primitive restart needs a strip or fan topology, not {:?}", self.topology));
+        }
+
+        if matches!(target, PipelineTarget::Dynamic(formats) if formats.view_mask != 0) && !device.supports_multiview() {
+            return Err(anyhow!("Rendering with a view mask needs the multiview feature"));
+        }
+
+        let layout = create_pipeline_layout(device, &self.set_layouts, &self.push_constant_ranges)?;
+
+        let stages: Vec<vk::PipelineShaderStageCreateInfo> = self.stages.iter()
+            .map(|stage| vk::PipelineShaderStageCreateInfo::builder()
+                .stage(stage.stage)
+                .module(stage.module)
+                .name(&stage.entry_point)
+                .build())
+            .collect();
+
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_binding_descriptions(&self.bindings)
+            .vertex_attribute_descriptions(&self.attributes);
+
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(self.topology)
+            .primitive_restart_enable(self.primitive_restart);
+
+        let viewport = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+
+        let depth_clamp = self.raster.depth_clamp && device.capabilities().has_feature(Feature::DepthClamp);
+        if self.raster.depth_clamp && !depth_clamp {
+            warn!("depthClamp is not enabled, clipping depth instead of clamping it");
+        }
+
+        let (depth_bias_constant, depth_bias_slope) = self.raster.depth_bias.unwrap_or((0.0, 0.0));
+        let rasterization = vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(self.raster.polygon_mode)
+            .cull_mode(self.raster.cull_mode)
+            .front_face(self.raster.front_face)
+            .line_width(self.raster.line_width)
+            .depth_clamp_enable(depth_clamp)
+            .depth_bias_enable(self.raster.depth_bias.is_some())
+            .depth_bias_constant_factor(depth_bias_constant)
+            .depth_bias_slope_factor(depth_bias_slope);
+
+        let multisample = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(target.samples());
+
+        let depth_stencil = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(self.depth.test)
+            .depth_write_enable(self.depth.write)
+            .depth_compare_op(self.depth.compare_op);
+
+        let blend_attachments: Vec<vk::PipelineColorBlendAttachmentState> = (0..target.color_count())
+            .map(|index| {
+                let blend = self.blend.get(index).or(self.blend.first()).copied().unwrap_or_default();
+                blend.attachment_state()
+            })
+            .collect();
+
+        let color_blend = vk::PipelineColorBlendStateCreateInfo::builder()
+            .attachments(&blend_attachments);
+
+        let dynamic_states: Vec<vk::DynamicState> = self.dynamic_states.iter()
+            .copied()
+            .filter(|state| device.supports_extended_dynamic_state() || !EXTENDED_DYNAMIC_STATES.contains(state))
+            .collect();
+        if dynamic_states.len() < self.dynamic_states.len() {
+            warn!("Extended dynamic state needs Vulkan 1.3, building the pipeline with static state");
+        }
+
+        let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
+            .dynamic_states(&dynamic_states);
+
+        let mut create_info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport)
+            .rasterization_state(&rasterization)
+            .multisample_state(&multisample)
+            .depth_stencil_state(&depth_stencil)
+            .color_blend_state(&color_blend)
+            .dynamic_state(&dynamic_state)
+            .layout(layout);
+
+        let mut rendering_info;
+        match target {
+            PipelineTarget::RenderPass { render_pass, subpass, .. } => {
+                create_info = create_info.render_pass(*render_pass).subpass(*subpass);
+            }
+            PipelineTarget::Dynamic(formats) => {
+                rendering_info = vk::PipelineRenderingCreateInfo::builder()
+                    .color_attachment_formats(&formats.color_formats)
+                    .depth_attachment_format(formats.depth_format.unwrap_or(vk::Format::UNDEFINED))
+                    .stencil_attachment_format(formats.stencil_format().unwrap_or(vk::Format::UNDEFINED))
+                    .view_mask(formats.view_mask);
+                create_info = create_info.push_next(&mut rendering_info);
+            }
+        }
+
+        let create_info = create_info.build();
+
+        let pipeline = match device.create_graphics_pipelines(device.pipeline_cache(), &[create_info], None) {
+            Ok(pipelines) => pipelines[0],
+            Err((_, err)) => {
+                device.destroy_pipeline_layout(layout, None);
+                return Err(err.into());
+            }
+        };
+
+        Ok(GraphicsPipeline {
+            device: device.clone(),
+            handle: pipeline,
+            layout,
+            push_constant_ranges: self.push_constant_ranges.clone(),
+        })
+    }
+}
+
+impl Default for GraphicsPipelineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct GraphicsPipeline {
+    device: Arc<Device>,
+    handle: vk::Pipeline,
+    layout: vk::PipelineLayout,
+    push_constant_ranges: Vec<vk::PushConstantRange>,
+}
+
+impl GraphicsPipeline {
+    pub fn handle(&self) -> vk::Pipeline {
+        self.handle
+    }
+
+    pub fn layout(&self) -> vk::PipelineLayout {
+        self.layout
+    }
+
+    pub fn push_constant_ranges(&self) -> &[vk::PushConstantRange] {
+        &self.push_constant_ranges
+    }
+
+    pub unsafe fn bind(&self, command_buffer: vk::CommandBuffer) {
+        self.device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.handle);
+    }
+
+    /// Pushes `value` at `offset` for `stages`. In debug builds this asserts that the write lies inside a range
+    /// declared for all of `stages`.
+    pub unsafe fn push_constants<T: Pod>(&self, command_buffer: vk::CommandBuffer, stages: vk::ShaderStageFlags, offset: u32, value: &T) {
+        push_constants(&self.device, command_buffer, self.layout, &self.push_constant_ranges, stages, offset, value);
+    }
+}
+
+impl Drop for GraphicsPipeline {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_pipeline(self.handle, None);
+            self.device.destroy_pipeline_layout(self.layout, None);
+        }
+    }
+}
+
+/// Creates a pipeline layout, rejecting push constant ranges the device cannot hold.
+pub unsafe fn create_pipeline_layout(
+    device: &Device,
+    set_layouts: &[vk::DescriptorSetLayout],
+    push_constant_ranges: &[vk::PushConstantRange],
+) -> anyhow::Result<vk::PipelineLayout> {
+    let max_size = device.instance().get_physical_device_properties(device.physical_device()).limits.max_push_constants_size;
+
+    for range in push_constant_ranges {
+        if range.offset % 4 != 0 || range.size % 4 != 0 || range.size == 0 {
+            return Err(anyhow!("Push constant range {:?} must have a non-zero size and be 4-byte aligned", range));
+        }
+
+        if range.offset + range.size > max_size {
+            return Err(anyhow!("Push constant range {:?} exceeds maxPushConstantsSize ({})", range, max_size));
+        }
+    }
+
+    let create_info = vk::PipelineLayoutCreateInfo::builder()
+        .set_layouts(set_layouts)
+        .push_constant_ranges(push_constant_ranges);
+
+    Ok(device.create_pipeline_layout(&create_info, None)?)
+}
+
+pub(crate) unsafe fn push_constants<T: Pod>(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
+    layout: vk::PipelineLayout,
+    ranges: &[vk::PushConstantRange],
+    stages: vk::ShaderStageFlags,
+    offset: u32,
+    value: &T,
+) {
+    let bytes = bytemuck::bytes_of(value);
+
+    debug_assert!(
+        ranges.iter().any(|range| range.stage_flags.contains(stages)
+            && offset >= range.offset
+            && offset + bytes.len() as u32 <= range.offset + range.size),
+        "Push constant write of {} bytes at offset {} for {:?} is outside the declared ranges {:?}",
+        bytes.len(),
+        offset,
+        stages,
+        ranges,
+    );
+
+    device.cmd_push_constants(command_buffer, layout, stages, offset, bytes);
+}
+
+/// Sets the depth bias factors of a pipeline built with `GraphicsPipelineBuilder::dynamic_depth_bias`.
+pub unsafe fn set_depth_bias(device: &Device, command_buffer: vk::CommandBuffer, constant: f32, slope: f32) {
+    device.cmd_set_depth_bias(command_buffer, constant, 0.0, slope);
+}
+
+/// Sets a viewport covering `extent` and a matching scissor, for pipelines using the default dynamic state.
+pub unsafe fn set_viewport_and_scissor(device: &Device, command_buffer: vk::CommandBuffer, extent: vk::Extent2D) {
+    set_viewport_rect(device, command_buffer, vk::Rect2D {
+        offset: vk::Offset2D::default(),
+        extent,
+    });
+}
+
+/// Like [`set_viewport_and_scissor`] for a part of the target, e.g. one half of a split screen.
+pub unsafe fn set_viewport_rect(device: &Device, command_buffer: vk::CommandBuffer, rect: vk::Rect2D) {
+    device.cmd_set_viewport(command_buffer, 0, &[vk::Viewport {
+        x: rect.offset.x as f32,
+        y: rect.offset.y as f32,
+        width: rect.extent.width as f32,
+        height: rect.extent.height as f32,
+        min_depth: 0.0,
+        max_depth: 1.0,
+    }]);
+
+    device.cmd_set_scissor(command_buffer, 0, &[rect]);
+}
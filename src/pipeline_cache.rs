@@ -0,0 +1,102 @@
+use std::path::PathBuf;
+use ash::vk;
+use log::{debug, info, warn};
+
+/// Overrides the directory pipeline cache files are kept in.
+pub const PIPELINE_CACHE_DIR_ENV_VAR: &str = "LEGAMING_PIPELINE_CACHE_DIR";
+
+const HEADER_SIZE: usize = 16 + vk::UUID_SIZE;
+
+/// The header is written least significant byte first whatever the host's byte order.
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Checks the `VkPipelineCacheHeaderVersionOne` at the start of `data` against the current device, so a cache
+/// written by another GPU or driver version is discarded instead of handed to the driver.
+fn is_compatible(data: &[u8], properties: &vk::PhysicalDeviceProperties) -> bool {
+    if data.len() < HEADER_SIZE {
+        return false;
+    }
+
+    read_u32(data, 0) as usize >= HEADER_SIZE
+        && read_u32(data, 4) == vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+        && read_u32(data, 8) == properties.vendor_id
+        && read_u32(data, 12) == properties.device_id
+        && data[16..HEADER_SIZE] == properties.pipeline_cache_uuid
+}
+
+/// A `vk::PipelineCache` loaded from and saved to a file named after the vendor, device and driver version.
+pub struct PipelineCache {
+    handle: vk::PipelineCache,
+    path: PathBuf,
+}
+
+impl PipelineCache {
+    fn default_path(properties: &vk::PhysicalDeviceProperties) -> PathBuf {
+        let dir = std::env::var_os(PIPELINE_CACHE_DIR_ENV_VAR)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| std::env::temp_dir().join("legaming"));
+
+        dir.join(format!(
+            "pipeline_cache_{:04x}_{:04x}_{:08x}.bin",
+            properties.vendor_id,
+            properties.device_id,
+            properties.driver_version,
+        ))
+    }
+
+    pub unsafe fn load(device: &ash::Device, properties: &vk::PhysicalDeviceProperties) -> anyhow::Result<Self> {
+        let path = Self::default_path(properties);
+
+        let initial_data = std::fs::read(&path).ok()
+            .filter(|data| {
+                let compatible = is_compatible(data, properties);
+                if !compatible {
+                    warn!("Ignoring pipeline cache written for a different device or driver");
+                }
+                compatible
+            })
+            .unwrap_or_default();
+
+        let create_info = vk::PipelineCacheCreateInfo::builder()
+            .initial_data(&initial_data);
+
+        let handle = device.create_pipeline_cache(&create_info, None)?;
+        if !initial_data.is_empty() {
+            info!("Loaded {} byte pipeline cache", initial_data.len());
+        }
+
+        Ok(Self { handle, path })
+    }
+
+    pub fn handle(&self) -> vk::PipelineCache {
+        self.handle
+    }
+
+    /// Writes the cache to disk. Failures are logged, since a missing cache only costs startup time.
+    pub unsafe fn save(&self, device: &ash::Device) {
+        let path = &self.path;
+        let data = match device.get_pipeline_cache_data(self.handle) {
+            Ok(data) => data,
+            Err(err) => {
+                warn!("Failed to read pipeline cache data: {}", err);
+                return;
+            }
+        };
+
+        let written = path.parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(path, &data));
+
+        match written {
+            Ok(()) => debug!("Saved {} byte pipeline cache to {}", data.len(), path.display()),
+            Err(err) => warn!("Failed to save pipeline cache to {}: {}", path.display(), err),
+        }
+    }
+
+    pub unsafe fn destroy(&mut self, device: &ash::Device) {
+        device.destroy_pipeline_cache(self.handle, None);
+        self.handle = vk::PipelineCache::null();
+    }
+}
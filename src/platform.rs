@@ -1,8 +1,7 @@
-use std::ffi::{c_int, c_ulong, c_void, CStr};
+use std::ffi::{c_ulong, c_void, CStr};
 use std::num::{NonZeroIsize, NonZeroU32};
 use std::ptr::NonNull;
 use ash::extensions::khr;
-use ash::prelude::VkResult;
 use ash::vk;
 use ash::vk::{HINSTANCE, HWND};
 use thiserror::Error;
@@ -113,16 +112,16 @@ pub fn get_required_instance_extensions(window: &Window) -> anyhow::Result<Vec<&
     let display_handle = window.display_handle()?.as_raw();
 
     match (window_handle, display_handle) {
-        (RawWindowHandle::Win32(window_handle), RawDisplayHandle::Windows(_)) => {
+        (RawWindowHandle::Win32(_), RawDisplayHandle::Windows(_)) => {
             Ok(vec![khr::Surface::name(), khr::Win32Surface::name()])
         }
-        (RawWindowHandle::Wayland(window_handle), RawDisplayHandle::Wayland(display_handle)) => {
+        (RawWindowHandle::Wayland(_), RawDisplayHandle::Wayland(_)) => {
             Ok(vec![khr::Surface::name(), khr::WaylandSurface::name()])
         }
-        (RawWindowHandle::Xcb(window_handle), RawDisplayHandle::Xcb(display_handle)) => {
+        (RawWindowHandle::Xcb(_), RawDisplayHandle::Xcb(_)) => {
             Ok(vec![khr::Surface::name(), khr::XcbSurface::name()])
         }
-        (RawWindowHandle::Xlib(window_handle), RawDisplayHandle::Xlib(_display_handle)) => {
+        (RawWindowHandle::Xlib(_), RawDisplayHandle::Xlib(_)) => {
             Ok(vec![khr::Surface::name(), khr::XlibSurface::name()])
         }
         (_, _) => Err(CreateSurfaceError::Unsupported.into())
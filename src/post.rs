@@ -0,0 +1,890 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::anyhow;
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use crate::buffer::Buffer;
+use crate::commands::submit_one_time;
+use crate::compute::{memory_barrier, Access, ComputePipeline, ComputePipelineBuilder};
+use crate::descriptors::{DescriptorLayoutCache, DescriptorWriter, SetLayoutDesc, TransientDescriptors};
+use crate::device::Device;
+use crate::format::is_srgb;
+use crate::glsl::{insert_after_version, GlslCompiler};
+use crate::image::ImageDesc;
+use crate::pipeline::{BlendMode, DepthState, GraphicsPipeline, GraphicsPipelineBuilder, PipelineTarget};
+use crate::render_graph::{BufferAccess, GraphBuffer, GraphImage, ImageAccess, RenderGraph};
+use crate::rendering::RenderingFormats;
+use crate::sampler::SamplerDesc;
+use crate::shader::ShaderModule;
+
+const FULLSCREEN_VERT: &str = include_str!("../shaders/fullscreen.vert");
+const BLOOM_FRAG: &str = include_str!("../shaders/post_bloom.frag");
+const VIGNETTE_FRAG: &str = include_str!("../shaders/post_vignette.frag");
+const TONEMAP_FRAG: &str = include_str!("../shaders/tonemap.frag");
+const LUMINANCE_HISTOGRAM_COMP: &str = include_str!("../shaders/luminance_histogram.comp");
+const LUMINANCE_AVERAGE_COMP: &str = include_str!("../shaders/luminance_average.comp");
+
+/// What the scene is rendered into when post processing is on, so lighting can go past 1 until tone mapping.
+pub const HDR_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+/// Bins of the luminance histogram auto exposure is computed from. Must match the shaders.
+const HISTOGRAM_BINS: usize = 256;
+
+const HISTOGRAM_LOCAL_SIZE: u32 = 16;
+
+/// A full-screen effect in a [`PostStack`]. Effects run in the order they were added, each reading the image the
+/// one before it produced, in the stack's format and at the scene's extent.
+pub trait PostEffect {
+    fn name(&self) -> &str;
+
+    /// Disabled effects are skipped, passing their input on unchanged.
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    /// Adds the effect's passes to `graph`, reading `input` and returning the image holding the result, usually
+    /// one from `PostFrame::target`.
+    unsafe fn add_passes<'a>(&'a self, graph: &mut RenderGraph<'a>, frame: &mut PostFrame, input: GraphImage) -> anyhow::Result<GraphImage>;
+
+    /// Builds the effect's pipelines again on `device`, after the device the stack was created on was lost.
+    unsafe fn recreate(&mut self, device: &Arc<Device>, compiler: &GlslCompiler, format: vk::Format) -> anyhow::Result<()>;
+}
+
+/// What effects see of the frame while adding their passes.
+pub struct PostFrame<'d> {
+    frame_index: usize,
+    delta: Duration,
+    extent: vk::Extent2D,
+    format: vk::Format,
+    descriptors: &'d mut TransientDescriptors,
+}
+
+impl PostFrame<'_> {
+    pub fn frame_index(&self) -> usize {
+        self.frame_index
+    }
+
+    /// Time since the stack last added its passes, zero the first time.
+    pub fn delta(&self) -> Duration {
+        self.delta
+    }
+
+    /// The extent of the scene image the stack started from.
+    pub fn extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+
+    /// The format every effect reads and writes.
+    pub fn format(&self) -> vk::Format {
+        self.format
+    }
+
+    /// A transient image in the stack's format for an effect to draw into.
+    pub fn target(&self, graph: &mut RenderGraph, name: &str, extent: vk::Extent2D) -> GraphImage {
+        graph.create_image(name, ImageDesc::new_2d(extent.width, extent.height, self.format, vk::ImageUsageFlags::empty()))
+    }
+
+    /// A descriptor set that is only valid for this frame.
+    pub unsafe fn allocate_set(&mut self, layout: vk::DescriptorSetLayout) -> anyhow::Result<vk::DescriptorSet> {
+        self.descriptors.allocate(layout)
+    }
+}
+
+/// A fullscreen triangle running a fragment shader over its inputs, the building block of post effects. The
+/// shader gets its uv in location 0, its inputs as combined image samplers in set 0 (binding `n` for input `n`),
+/// then any storage buffers it reads in the bindings after them, and `P` as a fragment stage push constant, and
+/// writes location 0.
+pub struct FullscreenPass<P: Pod = ()> {
+    device: Arc<Device>,
+    _layouts: DescriptorLayoutCache,
+    set_layout: vk::DescriptorSetLayout,
+    pipeline: GraphicsPipeline,
+    inputs: usize,
+    buffers: usize,
+    params: PhantomData<P>,
+}
+
+impl<P: Pod> FullscreenPass<P> {
+    /// Compiles `fragment_source` for a pass with `inputs` inputs drawing into images of `format`.
+    pub unsafe fn new(
+        device: &Arc<Device>,
+        compiler: &GlslCompiler,
+        name: &str,
+        fragment_source: &str,
+        inputs: usize,
+        format: vk::Format,
+    ) -> anyhow::Result<Self> {
+        Self::with_buffers(device, compiler, name, fragment_source, inputs, 0, format)
+    }
+
+    /// Like [`new`](Self::new), for a shader that also reads `buffers` storage buffers.
+    pub unsafe fn with_buffers(
+        device: &Arc<Device>,
+        compiler: &GlslCompiler,
+        name: &str,
+        fragment_source: &str,
+        inputs: usize,
+        buffers: usize,
+        format: vk::Format,
+    ) -> anyhow::Result<Self> {
+        let vertex = ShaderModule::from_bytes_with_stage(
+            device,
+            "fullscreen.vert",
+            &compiler.compile_source(FULLSCREEN_VERT, vk::ShaderStageFlags::VERTEX, "fullscreen.vert")?,
+            vk::ShaderStageFlags::VERTEX,
+        )?;
+        let fragment = ShaderModule::from_bytes_with_stage(
+            device,
+            name,
+            &compiler.compile_source(fragment_source, vk::ShaderStageFlags::FRAGMENT, name)?,
+            vk::ShaderStageFlags::FRAGMENT,
+        )?;
+
+        let mut layouts = DescriptorLayoutCache::new(device);
+        let set_layout = layouts.get(&(0..(inputs + buffers) as u32).fold(SetLayoutDesc::new(), |layout, binding| {
+            let descriptor_type = if (binding as usize) < inputs {
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER
+            } else {
+                vk::DescriptorType::STORAGE_BUFFER
+            };
+
+            layout.binding(binding, descriptor_type, vk::ShaderStageFlags::FRAGMENT)
+        }))?;
+
+        let mut builder = GraphicsPipelineBuilder::new()
+            .shader(&vertex)
+            .shader(&fragment)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .depth(DepthState::DISABLED)
+            .blend(BlendMode::Opaque)
+            .descriptor_set_layout(set_layout)
+            .target(PipelineTarget::Dynamic(RenderingFormats::new(&[format], None)));
+
+        if std::mem::size_of::<P>() > 0 {
+            builder = builder.push_constants::<P>(vk::ShaderStageFlags::FRAGMENT, 0);
+        }
+
+        Ok(Self {
+            device: device.clone(),
+            _layouts: layouts,
+            set_layout,
+            pipeline: builder.build(device)?,
+            inputs,
+            buffers,
+            params: PhantomData,
+        })
+    }
+
+    /// Adds a pass drawing the shader into all of `output`, sampling `inputs` with linear filtering.
+    pub unsafe fn add<'a>(
+        &'a self,
+        graph: &mut RenderGraph<'a>,
+        frame: &mut PostFrame,
+        name: &str,
+        inputs: &[GraphImage],
+        output: GraphImage,
+        params: P,
+    ) -> anyhow::Result<()> {
+        self.add_with_buffers(graph, frame, name, inputs, &[], output, params)
+    }
+
+    /// Like [`add`](Self::add), also binding `buffers` for the shader to read.
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn add_with_buffers<'a>(
+        &'a self,
+        graph: &mut RenderGraph<'a>,
+        frame: &mut PostFrame,
+        name: &str,
+        inputs: &[GraphImage],
+        buffers: &[GraphBuffer],
+        output: GraphImage,
+        params: P,
+    ) -> anyhow::Result<()> {
+        if inputs.len() != self.inputs || buffers.len() != self.buffers {
+            return Err(anyhow!(
+                "Pass '{}' takes {} input(s) and {} buffer(s), got {} and {}",
+                name,
+                self.inputs,
+                self.buffers,
+                inputs.len(),
+                buffers.len(),
+            ));
+        }
+
+        let set = frame.allocate_set(self.set_layout)?;
+        let inputs = inputs.to_vec();
+        let buffers = buffers.to_vec();
+
+        let pass = inputs.iter()
+            .fold(graph.add_pass(name), |pass, &image| pass.image(image, ImageAccess::Sampled(vk::PipelineStageFlags::FRAGMENT_SHADER)));
+        buffers.iter()
+            .fold(pass, |pass, &buffer| pass.buffer(buffer, BufferAccess::StorageRead(vk::PipelineStageFlags::FRAGMENT_SHADER)))
+            .color(output, None)
+            .execute(move |ctx| {
+                let command_buffer = ctx.command_buffer();
+                let sampler = self.device.sampler(&SamplerDesc::linear_clamp())?;
+
+                let writer = inputs.iter()
+                    .enumerate()
+                    .fold(DescriptorWriter::new(), |writer, (binding, &image)| writer.image(
+                        binding as u32,
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        ctx.view(image),
+                        sampler,
+                        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    ));
+                buffers.iter()
+                    .enumerate()
+                    .fold(writer, |writer, (index, &buffer)| writer.buffer(
+                        (inputs.len() + index) as u32,
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        ctx.buffer(buffer),
+                        0,
+                        vk::WHOLE_SIZE,
+                    ))
+                    .update(&self.device, set);
+
+                self.pipeline.bind(command_buffer);
+                self.device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    self.pipeline.layout(),
+                    0,
+                    &[set],
+                    &[],
+                );
+
+                if std::mem::size_of::<P>() > 0 {
+                    self.pipeline.push_constants(command_buffer, vk::ShaderStageFlags::FRAGMENT, 0, &params);
+                }
+
+                self.device.cmd_draw(command_buffer, 3, 1, 0, 0);
+                Ok(())
+            });
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BloomSettings {
+    pub enabled: bool,
+    /// Brightness above which pixels bloom.
+    pub threshold: f32,
+    /// How far below `threshold` the bloom fades in, so it doesn't start abruptly.
+    pub knee: f32,
+    /// How much of the blurred highlights is added back onto the scene.
+    pub intensity: f32,
+    /// How many times the highlights are halved in size. More levels spread the glow further.
+    pub levels: u32,
+}
+
+impl BloomSettings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for BloomSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold: 1.0,
+            knee: 0.5,
+            intensity: 0.1,
+            levels: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VignetteSettings {
+    pub enabled: bool,
+    /// How much the corners are darkened, from 0 to 1.
+    pub intensity: f32,
+    /// Distance from the center, where the corners are at 1, at which darkening starts.
+    pub radius: f32,
+    /// Distance over which it fades in.
+    pub smoothness: f32,
+}
+
+impl VignetteSettings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for VignetteSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            intensity: 0.3,
+            radius: 0.5,
+            smoothness: 0.5,
+        }
+    }
+}
+
+/// The curve tone mapping squeezes the scene's range into the output's with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ToneMapOperator {
+    /// A fit of the ACES filmic curve, with a toe and a shoulder and some desaturation of highlights.
+    #[default]
+    Aces,
+    /// `x / (1 + x)`, which keeps hues but looks flatter.
+    Reinhard,
+}
+
+/// Meters the scene every frame from a histogram of its luminance and eases the exposure towards it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AutoExposureSettings {
+    /// The range of log2 luminance the histogram covers. Darker and brighter pixels land in its first and last bins.
+    pub min_log_luminance: f32,
+    pub max_log_luminance: f32,
+    /// How quickly the exposure follows changes in brightness, per second.
+    pub adaptation_rate: f32,
+}
+
+impl AutoExposureSettings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for AutoExposureSettings {
+    fn default() -> Self {
+        Self {
+            min_log_luminance: -8.0,
+            max_log_luminance: 4.0,
+            adaptation_rate: 1.5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ToneMappingSettings {
+    /// When off, the scene is only clamped to the output range, without exposure or a curve, e.g. to compare
+    /// against the raw HDR values.
+    pub enabled: bool,
+    pub operator: ToneMapOperator,
+    /// Multiplies the scene before it is mapped to the output range. With auto exposure, this compensates on top
+    /// of the metered exposure.
+    pub exposure: f32,
+    pub auto_exposure: Option<AutoExposureSettings>,
+}
+
+impl ToneMappingSettings {
+    pub fn with_enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    pub fn with_operator(mut self, operator: ToneMapOperator) -> Self {
+        self.operator = operator;
+        self
+    }
+
+    pub fn with_exposure(mut self, exposure: f32) -> Self {
+        self.exposure = exposure;
+        self
+    }
+
+    pub fn with_auto_exposure(mut self, auto_exposure: AutoExposureSettings) -> Self {
+        self.auto_exposure = Some(auto_exposure);
+        self
+    }
+}
+
+impl ToneMappingSettings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for ToneMappingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            operator: ToneMapOperator::default(),
+            exposure: 1.0,
+            auto_exposure: None,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct BloomParams {
+    threshold: f32,
+    knee: f32,
+    intensity: f32,
+}
+
+unsafe impl Zeroable for BloomParams {}
+unsafe impl Pod for BloomParams {}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct VignetteParams {
+    intensity: f32,
+    radius: f32,
+    smoothness: f32,
+}
+
+unsafe impl Zeroable for VignetteParams {}
+unsafe impl Pod for VignetteParams {}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ToneMappingParams {
+    exposure: f32,
+    operator: u32,
+    use_auto_exposure: u32,
+    encode_srgb: u32,
+}
+
+unsafe impl Zeroable for ToneMappingParams {}
+unsafe impl Pod for ToneMappingParams {}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct HistogramParams {
+    min_log_luminance: f32,
+    inverse_log_range: f32,
+}
+
+unsafe impl Zeroable for HistogramParams {}
+unsafe impl Pod for HistogramParams {}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct AverageParams {
+    min_log_luminance: f32,
+    log_range: f32,
+    adaptation: f32,
+    pixel_count: u32,
+}
+
+unsafe impl Zeroable for AverageParams {}
+unsafe impl Pod for AverageParams {}
+
+/// Thresholds the scene into a half-sized image, halves it `levels - 1` more times, then adds the levels back up
+/// from the smallest with a tent filter and onto the scene.
+struct Bloom {
+    settings: BloomSettings,
+    prefilter: FullscreenPass<BloomParams>,
+    downsample: FullscreenPass<BloomParams>,
+    upsample: FullscreenPass<BloomParams>,
+    composite: FullscreenPass<BloomParams>,
+}
+
+impl Bloom {
+    unsafe fn new(device: &Arc<Device>, compiler: &GlslCompiler, format: vk::Format, settings: BloomSettings) -> anyhow::Result<Self> {
+        let stage = |define: &str, inputs: usize| FullscreenPass::new(
+            device,
+            compiler,
+            &format!("post_bloom.frag ({})", define),
+            &insert_after_version(BLOOM_FRAG, &format!("#define {}\n", define)),
+            inputs,
+            format,
+        );
+
+        Ok(Self {
+            settings,
+            prefilter: stage("PREFILTER", 1)?,
+            downsample: stage("DOWNSAMPLE", 1)?,
+            upsample: stage("UPSAMPLE", 2)?,
+            composite: stage("COMPOSITE", 2)?,
+        })
+    }
+}
+
+impl PostEffect for Bloom {
+    fn name(&self) -> &str {
+        "bloom"
+    }
+
+    fn enabled(&self) -> bool {
+        self.settings.enabled && self.settings.levels > 0
+    }
+
+    unsafe fn add_passes<'a>(&'a self, graph: &mut RenderGraph<'a>, frame: &mut PostFrame, input: GraphImage) -> anyhow::Result<GraphImage> {
+        let params = BloomParams {
+            threshold: self.settings.threshold,
+            knee: self.settings.knee.max(0.0),
+            intensity: self.settings.intensity,
+        };
+
+        let mut levels = Vec::new();
+        let mut extent = frame.extent();
+        while levels.len() < self.settings.levels as usize && extent.width > 1 && extent.height > 1 {
+            extent = vk::Extent2D {
+                width: extent.width / 2,
+                height: extent.height / 2,
+            };
+
+            let level = frame.target(graph, &format!("bloom {}", levels.len()), extent);
+            match levels.last() {
+                None => self.prefilter.add(graph, frame, "bloom prefilter", &[input], level, params)?,
+                Some(&previous) => self.downsample.add(graph, frame, &format!("bloom downsample {}", levels.len()), &[previous], level, params)?,
+            }
+
+            levels.push(level);
+        }
+
+        let Some(&smallest) = levels.last() else {
+            return Ok(input);
+        };
+
+        let mut blurred = smallest;
+        for (index, &level) in levels.iter().enumerate().rev().skip(1) {
+            let upsampled = frame.target(graph, &format!("bloom upsample {}", index), graph.extent(level));
+            self.upsample.add(graph, frame, &format!("bloom upsample {}", index), &[blurred, level], upsampled, params)?;
+            blurred = upsampled;
+        }
+
+        let output = frame.target(graph, "bloom", frame.extent());
+        self.composite.add(graph, frame, "bloom composite", &[blurred, input], output, params)?;
+        Ok(output)
+    }
+
+    unsafe fn recreate(&mut self, device: &Arc<Device>, compiler: &GlslCompiler, format: vk::Format) -> anyhow::Result<()> {
+        *self = Self::new(device, compiler, format, self.settings)?;
+        Ok(())
+    }
+}
+
+struct Vignette {
+    settings: VignetteSettings,
+    pass: FullscreenPass<VignetteParams>,
+}
+
+impl Vignette {
+    unsafe fn new(device: &Arc<Device>, compiler: &GlslCompiler, format: vk::Format, settings: VignetteSettings) -> anyhow::Result<Self> {
+        Ok(Self {
+            settings,
+            pass: FullscreenPass::new(device, compiler, "post_vignette.frag", VIGNETTE_FRAG, 1, format)?,
+        })
+    }
+}
+
+impl PostEffect for Vignette {
+    fn name(&self) -> &str {
+        "vignette"
+    }
+
+    fn enabled(&self) -> bool {
+        self.settings.enabled
+    }
+
+    unsafe fn add_passes<'a>(&'a self, graph: &mut RenderGraph<'a>, frame: &mut PostFrame, input: GraphImage) -> anyhow::Result<GraphImage> {
+        let output = frame.target(graph, "vignette", frame.extent());
+        let params = VignetteParams {
+            intensity: self.settings.intensity,
+            radius: self.settings.radius,
+            smoothness: self.settings.smoothness.max(1e-4),
+        };
+
+        self.pass.add(graph, frame, "vignette", &[input], output, params)?;
+        Ok(output)
+    }
+
+    unsafe fn recreate(&mut self, device: &Arc<Device>, compiler: &GlslCompiler, format: vk::Format) -> anyhow::Result<()> {
+        *self = Self::new(device, compiler, format, self.settings)?;
+        Ok(())
+    }
+}
+
+/// Builds a luminance histogram of the scene and reduces it to an adapted average luminance, which tone mapping
+/// reads from the same buffer. The buffer outlives frames, so the average carries over from one to the next.
+struct AutoExposure {
+    device: Arc<Device>,
+    _layouts: DescriptorLayoutCache,
+    histogram_layout: vk::DescriptorSetLayout,
+    average_layout: vk::DescriptorSetLayout,
+    histogram: ComputePipeline,
+    average: ComputePipeline,
+    buffer: Buffer,
+}
+
+impl AutoExposure {
+    unsafe fn new(device: &Arc<Device>, compiler: &GlslCompiler) -> anyhow::Result<Self> {
+        let compile = |name: &str, source: &str| ShaderModule::from_bytes_with_stage(
+            device,
+            name,
+            &compiler.compile_source(source, vk::ShaderStageFlags::COMPUTE, name)?,
+            vk::ShaderStageFlags::COMPUTE,
+        );
+        let histogram_shader = compile("luminance_histogram.comp", LUMINANCE_HISTOGRAM_COMP)?;
+        let average_shader = compile("luminance_average.comp", LUMINANCE_AVERAGE_COMP)?;
+
+        let mut layouts = DescriptorLayoutCache::new(device);
+        let histogram_layout = layouts.get(
+            &SetLayoutDesc::new()
+                .binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE)
+                .binding(1, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE),
+        )?;
+        let average_layout = layouts.get(&SetLayoutDesc::new().binding(0, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE))?;
+
+        let histogram = ComputePipelineBuilder::new()
+            .shader(&histogram_shader)
+            .descriptor_set_layout(histogram_layout)
+            .push_constants::<HistogramParams>(0)
+            .build(device)?;
+        let average = ComputePipelineBuilder::new()
+            .shader(&average_shader)
+            .descriptor_set_layout(average_layout)
+            .push_constants::<AverageParams>(0)
+            .build(device)?;
+
+        // The histogram followed by the adapted luminance, starting out cleared.
+        let size = ((HISTOGRAM_BINS + 1) * std::mem::size_of::<u32>()) as vk::DeviceSize;
+        let buffer = Buffer::storage(device, "auto exposure", size)?;
+        submit_one_time(device, device.queue_families().graphics, device.graphics_queue(), |command_buffer| {
+            device.cmd_fill_buffer(command_buffer, buffer.handle(), 0, vk::WHOLE_SIZE, 0);
+        })?;
+
+        Ok(Self {
+            device: device.clone(),
+            _layouts: layouts,
+            histogram_layout,
+            average_layout,
+            histogram,
+            average,
+            buffer,
+        })
+    }
+
+    /// Adds the histogram and averaging passes over `scene` and returns the buffer tone mapping reads.
+    unsafe fn add_passes<'a>(
+        &'a self,
+        graph: &mut RenderGraph<'a>,
+        frame: &mut PostFrame,
+        scene: GraphImage,
+        settings: &AutoExposureSettings,
+    ) -> anyhow::Result<GraphBuffer> {
+        let buffer = graph.import_buffer("auto exposure", self.buffer.handle());
+        let histogram_set = frame.allocate_set(self.histogram_layout)?;
+        let average_set = frame.allocate_set(self.average_layout)?;
+
+        let extent = frame.extent();
+        let log_range = (settings.max_log_luminance - settings.min_log_luminance).max(1e-3);
+        let histogram_params = HistogramParams {
+            min_log_luminance: settings.min_log_luminance,
+            inverse_log_range: 1.0 / log_range,
+        };
+        let average_params = AverageParams {
+            min_log_luminance: settings.min_log_luminance,
+            log_range,
+            adaptation: 1.0 - (-frame.delta().as_secs_f32() * settings.adaptation_rate.max(0.0)).exp(),
+            pixel_count: extent.width * extent.height,
+        };
+
+        graph.add_pass("luminance histogram")
+            .image(scene, ImageAccess::Sampled(vk::PipelineStageFlags::COMPUTE_SHADER))
+            .buffer(buffer, BufferAccess::StorageWrite(vk::PipelineStageFlags::COMPUTE_SHADER))
+            .execute(move |ctx| {
+                let command_buffer = ctx.command_buffer();
+
+                // The previous frame's tone mapping may still be reading the buffer.
+                memory_barrier(&self.device, command_buffer, Access::FRAGMENT_READ, Access::COMPUTE_WRITE);
+
+                DescriptorWriter::new()
+                    .image(
+                        0,
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        ctx.view(scene),
+                        self.device.sampler(&SamplerDesc::nearest())?,
+                        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    )
+                    .buffer(1, vk::DescriptorType::STORAGE_BUFFER, ctx.buffer(buffer), 0, vk::WHOLE_SIZE)
+                    .update(&self.device, histogram_set);
+
+                self.histogram.bind(command_buffer);
+                self.histogram.bind_descriptor_sets(command_buffer, 0, &[histogram_set]);
+                self.histogram.push_constants(command_buffer, 0, &histogram_params);
+                self.histogram.dispatch_2d(command_buffer, extent.width, extent.height, [HISTOGRAM_LOCAL_SIZE; 2]);
+                Ok(())
+            });
+
+        graph.add_pass("luminance average")
+            .buffer(buffer, BufferAccess::StorageWrite(vk::PipelineStageFlags::COMPUTE_SHADER))
+            .execute(move |ctx| {
+                let command_buffer = ctx.command_buffer();
+
+                DescriptorWriter::new()
+                    .buffer(0, vk::DescriptorType::STORAGE_BUFFER, ctx.buffer(buffer), 0, vk::WHOLE_SIZE)
+                    .update(&self.device, average_set);
+
+                self.average.bind(command_buffer);
+                self.average.bind_descriptor_sets(command_buffer, 0, &[average_set]);
+                self.average.push_constants(command_buffer, 0, &average_params);
+                self.average.dispatch(command_buffer, 1, 1, 1);
+                Ok(())
+            });
+
+        Ok(buffer)
+    }
+}
+
+/// Full-screen passes between the main pass and the swapchain, recorded into the render graph. The scene is
+/// rendered into an [`HDR_FORMAT`] image, goes through bloom, the effects added with
+/// [`effects_mut`](Self::effects_mut) and the vignette, and is then tone mapped into the output.
+pub struct PostStack {
+    format: vk::Format,
+    output_format: vk::Format,
+    descriptors: TransientDescriptors,
+    last_frame: Option<Instant>,
+    bloom: Bloom,
+    effects: Vec<Box<dyn PostEffect>>,
+    vignette: Vignette,
+    tone_mapping: ToneMappingSettings,
+    tone_mapping_pass: FullscreenPass<ToneMappingParams>,
+    auto_exposure: AutoExposure,
+}
+
+impl PostStack {
+    pub unsafe fn new(
+        device: &Arc<Device>,
+        compiler: &GlslCompiler,
+        output_format: vk::Format,
+        frames_in_flight: usize,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            format: HDR_FORMAT,
+            output_format,
+            descriptors: TransientDescriptors::new(device, frames_in_flight),
+            last_frame: None,
+            bloom: Bloom::new(device, compiler, HDR_FORMAT, BloomSettings::default())?,
+            effects: Vec::new(),
+            vignette: Vignette::new(device, compiler, HDR_FORMAT, VignetteSettings::default())?,
+            tone_mapping: ToneMappingSettings::default(),
+            tone_mapping_pass: tone_mapping_pass(device, compiler, output_format)?,
+            auto_exposure: AutoExposure::new(device, compiler)?,
+        })
+    }
+
+    /// What the scene is rendered into and the effects read and write.
+    pub fn format(&self) -> vk::Format {
+        self.format
+    }
+
+    pub fn output_format(&self) -> vk::Format {
+        self.output_format
+    }
+
+    pub fn bloom(&self) -> &BloomSettings {
+        &self.bloom.settings
+    }
+
+    pub fn set_bloom(&mut self, settings: BloomSettings) {
+        self.bloom.settings = settings;
+    }
+
+    pub fn vignette(&self) -> &VignetteSettings {
+        &self.vignette.settings
+    }
+
+    pub fn set_vignette(&mut self, settings: VignetteSettings) {
+        self.vignette.settings = settings;
+    }
+
+    pub fn tone_mapping(&self) -> &ToneMappingSettings {
+        &self.tone_mapping
+    }
+
+    pub fn set_tone_mapping(&mut self, settings: ToneMappingSettings) {
+        self.tone_mapping = settings;
+    }
+
+    /// Custom effects, in the order they run between bloom and the vignette.
+    pub fn effects_mut(&mut self) -> &mut Vec<Box<dyn PostEffect>> {
+        &mut self.effects
+    }
+
+    /// Adds the passes of every enabled effect, reading the scene from `input`, and a last one tone mapping the
+    /// result into `output`.
+    pub unsafe fn add_passes<'a>(
+        &'a mut self,
+        graph: &mut RenderGraph<'a>,
+        input: GraphImage,
+        output: GraphImage,
+        frame_index: usize,
+    ) -> anyhow::Result<()> {
+        let now = Instant::now();
+        let delta = self.last_frame.map_or(Duration::ZERO, |last_frame| now - last_frame);
+        self.last_frame = Some(now);
+
+        let Self { format, output_format, descriptors, bloom, effects, vignette, tone_mapping, tone_mapping_pass, auto_exposure, .. } = self;
+
+        descriptors.begin_frame(frame_index)?;
+        let mut frame = PostFrame {
+            frame_index,
+            delta,
+            extent: graph.extent(input),
+            format: *format,
+            descriptors,
+        };
+
+        // Metered before any effect, so bloom and the vignette don't shift the exposure.
+        let exposure = match &tone_mapping.auto_exposure {
+            Some(settings) => Some(auto_exposure.add_passes(graph, &mut frame, input, settings)?),
+            None => None,
+        };
+
+        let effects = std::iter::once(&*bloom as &dyn PostEffect)
+            .chain(effects.iter().map(|effect| effect.as_ref()))
+            .chain(std::iter::once(&*vignette as &dyn PostEffect));
+
+        let mut image = input;
+        for effect in effects.filter(|effect| effect.enabled()) {
+            image = effect.add_passes(graph, &mut frame, image)
+                .map_err(|err| err.context(format!("Failed to add post effect '{}'", effect.name())))?;
+        }
+
+        let params = ToneMappingParams {
+            exposure: if tone_mapping.enabled { tone_mapping.exposure } else { 1.0 },
+            operator: match tone_mapping.operator {
+                _ if !tone_mapping.enabled => 2,
+                ToneMapOperator::Aces => 0,
+                ToneMapOperator::Reinhard => 1,
+            },
+            use_auto_exposure: (exposure.is_some() && tone_mapping.enabled) as u32,
+            encode_srgb: !is_srgb(*output_format) as u32,
+        };
+        let exposure = exposure.unwrap_or_else(|| graph.import_buffer("auto exposure", auto_exposure.buffer.handle()));
+        tone_mapping_pass.add_with_buffers(graph, &mut frame, "tone mapping", &[image], &[exposure], output, params)
+    }
+
+    /// Builds every effect again on `device`, after the device the stack was created on was lost.
+    pub unsafe fn recreate(
+        &mut self,
+        device: &Arc<Device>,
+        compiler: &GlslCompiler,
+        output_format: vk::Format,
+        frames_in_flight: usize,
+    ) -> anyhow::Result<()> {
+        self.output_format = output_format;
+        self.descriptors = TransientDescriptors::new(device, frames_in_flight);
+        self.bloom.recreate(device, compiler, self.format)?;
+        self.vignette.recreate(device, compiler, self.format)?;
+        self.tone_mapping_pass = tone_mapping_pass(device, compiler, output_format)?;
+        self.auto_exposure = AutoExposure::new(device, compiler)?;
+
+        for effect in &mut self.effects {
+            effect.recreate(device, compiler, self.format)
+                .map_err(|err| err.context(format!("Failed to recreate post effect '{}'", effect.name())))?;
+        }
+
+        Ok(())
+    }
+}
+
+unsafe fn tone_mapping_pass(
+    device: &Arc<Device>,
+    compiler: &GlslCompiler,
+    output_format: vk::Format,
+) -> anyhow::Result<FullscreenPass<ToneMappingParams>> {
+    FullscreenPass::with_buffers(device, compiler, "tonemap.frag", TONEMAP_FRAG, 1, 1, output_format)
+}
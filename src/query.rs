@@ -0,0 +1,283 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use ash::vk;
+use log::warn;
+use crate::device::Device;
+use crate::requirements::Feature;
+
+/// How many frames [`FrameTimes`] averages over unless configured otherwise.
+pub const DEFAULT_TIMING_WINDOW: usize = 60;
+
+/// A `vk::QueryPool` of `count` queries of one type.
+pub struct QueryPool {
+    device: Arc<Device>,
+    handle: vk::QueryPool,
+    count: u32,
+}
+
+impl QueryPool {
+    pub unsafe fn new(device: &Arc<Device>, query_type: vk::QueryType, count: u32) -> anyhow::Result<Self> {
+        let create_info = vk::QueryPoolCreateInfo::builder()
+            .query_type(query_type)
+            .query_count(count);
+
+        Ok(Self {
+            device: device.clone(),
+            handle: device.create_query_pool(&create_info, None)?,
+            count,
+        })
+    }
+
+    pub fn handle(&self) -> vk::QueryPool {
+        self.handle
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Records a reset of `count` queries from `first`, which every query needs before it is written again.
+    pub unsafe fn reset(&self, command_buffer: vk::CommandBuffer, first: u32, count: u32) {
+        self.device.cmd_reset_query_pool(command_buffer, self.handle, first, count);
+    }
+
+    /// Records the start of `query`, which has to be reset and must be ended in the same command buffer.
+    pub unsafe fn begin(&self, command_buffer: vk::CommandBuffer, query: u32, flags: vk::QueryControlFlags) {
+        self.device.cmd_begin_query(command_buffer, self.handle, query, flags);
+    }
+
+    pub unsafe fn end(&self, command_buffer: vk::CommandBuffer, query: u32) {
+        self.device.cmd_end_query(command_buffer, self.handle, query);
+    }
+
+    /// Reads the 64-bit results of `results.len()` queries from `first`. Returns false, leaving `results` alone,
+    /// when one of them isn't available yet.
+    pub unsafe fn results(&self, first: u32, results: &mut [u64]) -> anyhow::Result<bool> {
+        match self.device.get_query_pool_results(self.handle, first, results.len() as u32, results, vk::QueryResultFlags::TYPE_64) {
+            Ok(()) => Ok(true),
+            Err(vk::Result::NOT_READY) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl Drop for QueryPool {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_query_pool(self.handle, None);
+        }
+    }
+}
+
+/// The most recent frame times, for a stable number on a HUD instead of the jittery time of a single frame.
+#[derive(Debug, Clone)]
+pub struct FrameTimes {
+    samples: VecDeque<Duration>,
+    window: usize,
+}
+
+impl FrameTimes {
+    /// Averages over the last `window` frames, at least one.
+    pub fn new(window: usize) -> Self {
+        let window = window.max(1);
+        Self {
+            samples: VecDeque::with_capacity(window),
+            window,
+        }
+    }
+
+    pub fn push(&mut self, time: Duration) {
+        if self.samples.len() == self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(time);
+    }
+
+    /// The time of the most recent frame, `None` before the first one.
+    pub fn latest(&self) -> Option<Duration> {
+        self.samples.back().copied()
+    }
+
+    /// The average over the window, or over the frames so far until the window has filled up.
+    pub fn average(&self) -> Option<Duration> {
+        let total: Duration = self.samples.iter().sum();
+        (!self.samples.is_empty()).then(|| total / self.samples.len() as u32)
+    }
+
+    /// How many frames [`average`](Self::average) is currently taken over.
+    pub fn sample_count(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Changes the averaging window, dropping the oldest samples when it shrinks.
+    pub fn set_window(&mut self, window: usize) {
+        self.window = window.max(1);
+        while self.samples.len() > self.window {
+            self.samples.pop_front();
+        }
+    }
+}
+
+/// Measures how long the GPU spends on each frame's command buffer with a timestamp at its start and end. Each frame
+/// in flight has its own pair of queries, read back when the frame comes around again and its previous submission
+/// is known to be finished.
+pub struct GpuTimer {
+    pool: QueryPool,
+    /// Nanoseconds per timestamp tick.
+    period: f64,
+    /// Masks off the bits of a timestamp that aren't valid on the queue.
+    mask: u64,
+    written: Vec<bool>,
+    times: FrameTimes,
+    measured: u64,
+}
+
+impl GpuTimer {
+    /// Returns `None` with a warning when the graphics queue doesn't support timestamps.
+    pub unsafe fn new(device: &Arc<Device>, frames_in_flight: usize, window: usize) -> anyhow::Result<Option<Self>> {
+        let instance = device.instance();
+        let families = instance.get_physical_device_queue_family_properties(device.physical_device());
+        let valid_bits = families[device.queue_families().graphics as usize].timestamp_valid_bits;
+        if valid_bits == 0 {
+            warn!("The graphics queue doesn't support timestamps, GPU frame times are unavailable");
+            return Ok(None);
+        }
+
+        let period = instance.get_physical_device_properties(device.physical_device()).limits.timestamp_period;
+        Ok(Some(Self {
+            pool: QueryPool::new(device, vk::QueryType::TIMESTAMP, 2 * frames_in_flight as u32)?,
+            period: period as f64,
+            mask: if valid_bits >= 64 { u64::MAX } else { (1 << valid_bits) - 1 },
+            written: vec![false; frames_in_flight],
+            times: FrameTimes::new(window),
+            measured: 0,
+        }))
+    }
+
+    /// Collects the previous time of `frame_index` and writes the start timestamp. Must be recorded first in the
+    /// frame's command buffer, after waiting for the frame's previous submission.
+    pub unsafe fn begin(&mut self, command_buffer: vk::CommandBuffer, frame_index: usize) -> anyhow::Result<()> {
+        let first = 2 * frame_index as u32;
+        let mut timestamps = [0; 2];
+        if std::mem::take(&mut self.written[frame_index]) && self.pool.results(first, &mut timestamps)? {
+            let ticks = timestamps[1].wrapping_sub(timestamps[0]) & self.mask;
+            self.times.push(Duration::from_nanos((ticks as f64 * self.period) as u64));
+            self.measured += 1;
+        }
+
+        self.pool.reset(command_buffer, first, 2);
+        self.pool.device.cmd_write_timestamp(command_buffer, vk::PipelineStageFlags::TOP_OF_PIPE, self.pool.handle(), first);
+        Ok(())
+    }
+
+    /// Writes the end timestamp. Must be recorded last in the frame's command buffer.
+    pub unsafe fn end(&mut self, command_buffer: vk::CommandBuffer, frame_index: usize) {
+        let query = 2 * frame_index as u32 + 1;
+        self.pool.device.cmd_write_timestamp(command_buffer, vk::PipelineStageFlags::BOTTOM_OF_PIPE, self.pool.handle(), query);
+        self.written[frame_index] = true;
+    }
+
+    pub fn times(&self) -> &FrameTimes {
+        &self.times
+    }
+
+    /// How many frames have been measured so far, to tell when [`FrameTimes::latest`] is a new one.
+    pub fn measured(&self) -> u64 {
+        self.measured
+    }
+
+    pub fn set_window(&mut self, window: usize) {
+        self.times.set_window(window);
+    }
+}
+
+/// Occlusion queries around draws, `per_frame` of them for each frame in flight. A frame's results are read back
+/// when the frame comes around again, so [`samples`](Self::samples) lags `frames_in_flight` frames behind the draws,
+/// as anything deciding visibility from it has to allow for. Each frame slot keeps its own results, so a frame that
+/// used fewer queries doesn't leave another frame's results behind.
+pub struct OcclusionQueries {
+    pool: QueryPool,
+    per_frame: u32,
+    /// Counts exact samples where `Feature::OcclusionQueryPrecise` was enabled; otherwise a result only tells zero
+    /// from not.
+    precise: bool,
+    /// The queries each frame slot began, to read back only those.
+    used: Vec<u32>,
+    /// The results each frame slot read back last, `per_frame` long.
+    samples: Vec<Vec<Option<u64>>>,
+    /// The frame slot whose results were read back most recently.
+    latest: Option<usize>,
+}
+
+impl OcclusionQueries {
+    pub unsafe fn new(device: &Arc<Device>, frames_in_flight: usize, per_frame: u32) -> anyhow::Result<Self> {
+        Ok(Self {
+            pool: QueryPool::new(device, vk::QueryType::OCCLUSION, per_frame * frames_in_flight as u32)?,
+            per_frame,
+            precise: device.capabilities().has_feature(Feature::OcclusionQueryPrecise),
+            used: vec![0; frames_in_flight],
+            samples: vec![vec![None; per_frame as usize]; frames_in_flight],
+            latest: None,
+        })
+    }
+
+    /// Collects the results of `frame_index`'s previous queries and resets them. Must be recorded outside a render
+    /// pass, after waiting for the frame's previous submission.
+    pub unsafe fn begin_frame(&mut self, command_buffer: vk::CommandBuffer, frame_index: usize) -> anyhow::Result<()> {
+        let first = frame_index as u32 * self.per_frame;
+        let used = std::mem::take(&mut self.used[frame_index]);
+        if used > 0 {
+            let mut results = vec![0; used as usize];
+            if self.pool.results(first, &mut results)? {
+                let samples = &mut self.samples[frame_index];
+                samples.fill(None);
+                for (samples, result) in samples.iter_mut().zip(results) {
+                    *samples = Some(result);
+                }
+                self.latest = Some(frame_index);
+            }
+        }
+
+        self.pool.reset(command_buffer, first, self.per_frame);
+        Ok(())
+    }
+
+    /// Starts counting the samples that pass the depth and stencil tests for `query` of this frame. A frame has to
+    /// use its queries from 0 up without gaps, since one left out is never available to read back.
+    pub unsafe fn begin(&mut self, command_buffer: vk::CommandBuffer, frame_index: usize, query: u32) {
+        assert!(query < self.per_frame, "Occlusion query {} is out of range of {}", query, self.per_frame);
+        let flags = if self.precise { vk::QueryControlFlags::PRECISE } else { vk::QueryControlFlags::empty() };
+        self.pool.begin(command_buffer, frame_index as u32 * self.per_frame + query, flags);
+        self.used[frame_index] = self.used[frame_index].max(query + 1);
+    }
+
+    pub unsafe fn end(&self, command_buffer: vk::CommandBuffer, frame_index: usize, query: u32) {
+        self.pool.end(command_buffer, frame_index as u32 * self.per_frame + query);
+    }
+
+    /// How many samples passed for `query` in the latest frame read back, `None` until one has been or when that
+    /// frame didn't use it.
+    pub fn samples(&self, query: u32) -> Option<u64> {
+        self.latest.and_then(|frame_index| self.frame_samples(frame_index, query))
+    }
+
+    /// How many samples passed for `query` the last time `frame_index` was read back.
+    pub fn frame_samples(&self, frame_index: usize, query: u32) -> Option<u64> {
+        self.samples[frame_index].get(query as usize).copied().flatten()
+    }
+
+    /// Whether `query` saw any samples pass, treating an unknown result as visible.
+    pub fn is_visible(&self, query: u32) -> bool {
+        self.samples(query).is_none_or(|samples| samples > 0)
+    }
+
+    /// Whether [`samples`](Self::samples) are exact counts rather than just zero or not.
+    pub fn is_precise(&self) -> bool {
+        self.precise
+    }
+}
@@ -0,0 +1,796 @@
+use std::sync::Arc;
+use anyhow::anyhow;
+use ash::extensions::khr;
+use ash::vk;
+use bytemuck::Pod;
+use cgmath::Matrix4;
+use log::debug;
+use crate::allocator::MemoryLocation;
+use crate::buffer::Buffer;
+use crate::commands::submit_one_time;
+use crate::compute::{memory_barrier, Access};
+use crate::descriptors::{DescriptorLayoutCache, DescriptorWriter, SetLayoutDesc};
+use crate::device::Device;
+use crate::glsl::GlslCompiler;
+use crate::image::ImageDesc;
+use crate::pipeline::{create_pipeline_layout, push_constants, ShaderStage};
+use crate::render_graph::{GraphImage, ImageAccess, RenderGraph};
+use crate::requirements::{DeviceRequirements, Feature};
+use crate::sampler::SamplerDesc;
+use crate::shader::ShaderModule;
+use crate::ssao::{SsaoParams, SsaoQuality};
+
+const RT_AO_RGEN: &str = include_str!("../shaders/rt_ao.rgen");
+const RT_AO_RMISS: &str = include_str!("../shaders/rt_ao.rmiss");
+const RT_AO_RCHIT: &str = include_str!("../shaders/rt_ao.rchit");
+
+/// What ray traced occlusion is stored in before the blur. Storage images of it are supported everywhere, unlike
+/// [`AMBIENT_OCCLUSION_FORMAT`](crate::ssao::AMBIENT_OCCLUSION_FORMAT).
+pub const RAY_TRACED_OCCLUSION_FORMAT: vk::Format = vk::Format::R32_SFLOAT;
+
+/// Instances each frame's top level structure has room for before it first grows.
+const INITIAL_INSTANCE_CAPACITY: usize = 64;
+
+const BUILD_WRITE: Access = Access {
+    stage: vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+    access: vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR,
+};
+
+const RAY_TRACING_READ: Access = Access {
+    stage: vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+    access: vk::AccessFlags::ACCELERATION_STRUCTURE_READ_KHR,
+};
+
+/// The features [`RayTracing`] needs, all optional so devices without them still run with it off.
+pub fn ray_tracing_requirements() -> DeviceRequirements {
+    DeviceRequirements::new()
+        .optional_feature(Feature::BufferDeviceAddress)
+        .optional_feature(Feature::AccelerationStructure)
+        .optional_feature(Feature::RayTracingPipeline)
+}
+
+fn align_up(value: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    value.div_ceil(alignment.max(1)) * alignment.max(1)
+}
+
+/// The extension loaders and limits of `VK_KHR_acceleration_structure` and `VK_KHR_ray_tracing_pipeline`.
+pub struct RayTracing {
+    device: Arc<Device>,
+    acceleration_structure: khr::AccelerationStructure,
+    pipeline: khr::RayTracingPipeline,
+    handle_size: u32,
+    handle_alignment: u32,
+    base_alignment: u32,
+    max_recursion_depth: u32,
+    scratch_alignment: vk::DeviceSize,
+}
+
+impl RayTracing {
+    /// `None` unless the device was created with every feature of [`ray_tracing_requirements`].
+    pub unsafe fn new(device: &Arc<Device>) -> Option<Self> {
+        let capabilities = device.capabilities();
+        let supported = [Feature::BufferDeviceAddress, Feature::AccelerationStructure, Feature::RayTracingPipeline]
+            .iter()
+            .all(|&feature| capabilities.has_feature(feature));
+
+        if !supported {
+            return None;
+        }
+
+        let instance = device.instance().handle();
+        let properties = khr::RayTracingPipeline::get_properties(instance, device.physical_device());
+        let structure_properties = khr::AccelerationStructure::get_properties(instance, device.physical_device());
+        debug!(
+            "Ray tracing: {} byte group handles, recursion depth up to {}",
+            properties.shader_group_handle_size,
+            properties.max_ray_recursion_depth,
+        );
+
+        Some(Self {
+            device: device.clone(),
+            acceleration_structure: khr::AccelerationStructure::new(instance, device.handle()),
+            pipeline: khr::RayTracingPipeline::new(instance, device.handle()),
+            handle_size: properties.shader_group_handle_size,
+            handle_alignment: properties.shader_group_handle_alignment,
+            base_alignment: properties.shader_group_base_alignment,
+            max_recursion_depth: properties.max_ray_recursion_depth,
+            scratch_alignment: structure_properties.min_acceleration_structure_scratch_offset_alignment as vk::DeviceSize,
+        })
+    }
+
+    pub fn device(&self) -> &Arc<Device> {
+        &self.device
+    }
+
+    /// Builds an opaque bottom level structure from a triangle list, blocking until the build has finished on the
+    /// graphics queue.
+    pub unsafe fn build_bottom_level(&self, name: &str, positions: &[[f32; 3]], indices: &[u32]) -> anyhow::Result<AccelerationStructure> {
+        if positions.is_empty() || indices.is_empty() || !indices.len().is_multiple_of(3) {
+            return Err(anyhow!("Acceleration structure {} needs a non-empty triangle list", name));
+        }
+
+        let usage = vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS | vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR;
+        let vertex_buffer = Buffer::with_data(&self.device, &format!("{} positions", name), usage, positions)?;
+        let index_buffer = Buffer::with_data(&self.device, &format!("{} indices", name), usage, indices)?;
+
+        let triangles = vk::AccelerationStructureGeometryTrianglesDataKHR::builder()
+            .vertex_format(vk::Format::R32G32B32_SFLOAT)
+            .vertex_data(vk::DeviceOrHostAddressConstKHR { device_address: vertex_buffer.device_address() })
+            .vertex_stride(std::mem::size_of::<[f32; 3]>() as vk::DeviceSize)
+            .max_vertex(positions.len() as u32 - 1)
+            .index_type(vk::IndexType::UINT32)
+            .index_data(vk::DeviceOrHostAddressConstKHR { device_address: index_buffer.device_address() })
+            .build();
+        let geometry = vk::AccelerationStructureGeometryKHR::builder()
+            .geometry_type(vk::GeometryTypeKHR::TRIANGLES)
+            .geometry(vk::AccelerationStructureGeometryDataKHR { triangles })
+            .flags(vk::GeometryFlagsKHR::OPAQUE)
+            .build();
+        let geometries = [geometry];
+        let primitive_count = (indices.len() / 3) as u32;
+
+        let mut build_info = vk::AccelerationStructureBuildGeometryInfoKHR::builder()
+            .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+            .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
+            .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+            .geometries(&geometries)
+            .build();
+        let sizes = self.acceleration_structure.get_acceleration_structure_build_sizes(
+            vk::AccelerationStructureBuildTypeKHR::DEVICE,
+            &build_info,
+            &[primitive_count],
+        );
+
+        let structure = AccelerationStructure::new(self, name, vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL, sizes.acceleration_structure_size)?;
+        let scratch = self.scratch_buffer(name, sizes.build_scratch_size)?;
+        build_info.dst_acceleration_structure = structure.handle;
+        build_info.scratch_data = vk::DeviceOrHostAddressKHR { device_address: align_up(scratch.device_address(), self.scratch_alignment) };
+
+        let range = vk::AccelerationStructureBuildRangeInfoKHR {
+            primitive_count,
+            primitive_offset: 0,
+            first_vertex: 0,
+            transform_offset: 0,
+        };
+
+        let device = &self.device;
+        submit_one_time(device, device.queue_families().graphics, device.graphics_queue(), |command_buffer| {
+            self.acceleration_structure.cmd_build_acceleration_structures(command_buffer, &[build_info], &[&[range]]);
+        })?;
+
+        debug!("Built acceleration structure {} of {} triangles", name, primitive_count);
+        Ok(structure)
+    }
+
+    /// Traces a `width * height` grid of rays with the pipeline bound last, starting from the raygen shader of
+    /// `table`.
+    pub unsafe fn trace_rays(&self, command_buffer: vk::CommandBuffer, table: &ShaderBindingTable, width: u32, height: u32) {
+        self.pipeline.cmd_trace_rays(
+            command_buffer,
+            &table.raygen,
+            &table.miss,
+            &table.hit,
+            &vk::StridedDeviceAddressRegionKHR::default(),
+            width,
+            height,
+            1,
+        );
+    }
+
+    /// Padded so the scratch address can be aligned up.
+    unsafe fn scratch_buffer(&self, name: &str, size: vk::DeviceSize) -> anyhow::Result<Buffer> {
+        Buffer::new(
+            &self.device,
+            &format!("{} scratch", name),
+            size + self.scratch_alignment,
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+            MemoryLocation::GpuOnly,
+        )
+    }
+}
+
+/// A `vk::AccelerationStructureKHR` with the buffer it lives in.
+pub struct AccelerationStructure {
+    loader: khr::AccelerationStructure,
+    handle: vk::AccelerationStructureKHR,
+    address: vk::DeviceAddress,
+    _buffer: Buffer,
+}
+
+impl AccelerationStructure {
+    unsafe fn new(
+        ray_tracing: &RayTracing,
+        name: &str,
+        ty: vk::AccelerationStructureTypeKHR,
+        size: vk::DeviceSize,
+    ) -> anyhow::Result<Self> {
+        let buffer = Buffer::new(
+            &ray_tracing.device,
+            name,
+            size,
+            vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+            MemoryLocation::GpuOnly,
+        )?;
+
+        let create_info = vk::AccelerationStructureCreateInfoKHR::builder()
+            .buffer(buffer.handle())
+            .size(size)
+            .ty(ty);
+        let loader = ray_tracing.acceleration_structure.clone();
+        let handle = loader.create_acceleration_structure(&create_info, None)?;
+        let address = loader.get_acceleration_structure_device_address(
+            &vk::AccelerationStructureDeviceAddressInfoKHR::builder().acceleration_structure(handle),
+        );
+
+        Ok(Self {
+            loader,
+            handle,
+            address,
+            _buffer: buffer,
+        })
+    }
+
+    pub fn handle(&self) -> vk::AccelerationStructureKHR {
+        self.handle
+    }
+
+    pub fn device_address(&self) -> vk::DeviceAddress {
+        self.address
+    }
+
+    /// An instance of this bottom level structure for a top level one, placed by `transform`. `custom_index` is
+    /// what hit shaders read as `gl_InstanceCustomIndexEXT`, and must fit in 24 bits.
+    pub fn instance(&self, transform: Matrix4<f32>, custom_index: u32) -> vk::AccelerationStructureInstanceKHR {
+        // Row major and without the last row, unlike cgmath's column major matrices.
+        let mut matrix = [0.0; 12];
+        for row in 0..3 {
+            for column in 0..4 {
+                matrix[row * 4 + column] = transform[column][row];
+            }
+        }
+
+        vk::AccelerationStructureInstanceKHR {
+            transform: vk::TransformMatrixKHR { matrix },
+            instance_custom_index_and_mask: vk::Packed24_8::new(custom_index, 0xff),
+            instance_shader_binding_table_record_offset_and_flags: vk::Packed24_8::new(
+                0,
+                vk::GeometryInstanceFlagsKHR::TRIANGLE_FACING_CULL_DISABLE.as_raw() as u8,
+            ),
+            acceleration_structure_reference: vk::AccelerationStructureReferenceKHR { device_handle: self.address },
+        }
+    }
+}
+
+impl Drop for AccelerationStructure {
+    fn drop(&mut self) {
+        unsafe {
+            self.loader.destroy_acceleration_structure(self.handle, None);
+        }
+    }
+}
+
+/// A top level structure rebuilt from scratch every time it's recorded, sized for as many instances as it has
+/// been built with so far. Keep one per frame in flight.
+pub struct TopLevelAccelerationStructure {
+    capacity: usize,
+    instances: Buffer,
+    /// `None` until the first build, and again after the instance buffer grew.
+    structure: Option<(AccelerationStructure, Buffer)>,
+}
+
+impl TopLevelAccelerationStructure {
+    pub unsafe fn new(ray_tracing: &RayTracing, frame_index: usize) -> anyhow::Result<Self> {
+        Ok(Self {
+            capacity: INITIAL_INSTANCE_CAPACITY,
+            instances: Self::instance_buffer(ray_tracing, frame_index, INITIAL_INSTANCE_CAPACITY)?,
+            structure: None,
+        })
+    }
+
+    /// `None` before the first `record_build`.
+    pub fn handle(&self) -> Option<vk::AccelerationStructureKHR> {
+        self.structure.as_ref().map(|(structure, _)| structure.handle())
+    }
+
+    /// Writes `instances` and records building the structure from them, followed by a barrier making it visible to
+    /// ray tracing shaders. The previous build of this structure must have finished on the GPU.
+    pub unsafe fn record_build(
+        &mut self,
+        ray_tracing: &RayTracing,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        instances: &[vk::AccelerationStructureInstanceKHR],
+    ) -> anyhow::Result<()> {
+        if instances.len() > self.capacity {
+            self.capacity = instances.len().next_power_of_two();
+            self.instances = Self::instance_buffer(ray_tracing, frame_index, self.capacity)?;
+            self.structure = None;
+        }
+
+        // The instance struct holds a union, so it isn't `Pod`.
+        let bytes = std::slice::from_raw_parts(instances.as_ptr() as *const u8, std::mem::size_of_val(instances));
+        self.instances.mapped_slice_mut::<u8>()?[..bytes.len()].copy_from_slice(bytes);
+
+        let geometry = vk::AccelerationStructureGeometryKHR::builder()
+            .geometry_type(vk::GeometryTypeKHR::INSTANCES)
+            .geometry(vk::AccelerationStructureGeometryDataKHR {
+                instances: vk::AccelerationStructureGeometryInstancesDataKHR::builder()
+                    .array_of_pointers(false)
+                    .data(vk::DeviceOrHostAddressConstKHR { device_address: self.instances.device_address() })
+                    .build(),
+            })
+            .build();
+        let geometries = [geometry];
+
+        let mut build_info = vk::AccelerationStructureBuildGeometryInfoKHR::builder()
+            .ty(vk::AccelerationStructureTypeKHR::TOP_LEVEL)
+            .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_BUILD)
+            .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+            .geometries(&geometries)
+            .build();
+
+        if self.structure.is_none() {
+            let name = format!("top level structure {}", frame_index);
+            let sizes = ray_tracing.acceleration_structure.get_acceleration_structure_build_sizes(
+                vk::AccelerationStructureBuildTypeKHR::DEVICE,
+                &build_info,
+                &[self.capacity as u32],
+            );
+            let structure = AccelerationStructure::new(ray_tracing, &name, vk::AccelerationStructureTypeKHR::TOP_LEVEL, sizes.acceleration_structure_size)?;
+            let scratch = ray_tracing.scratch_buffer(&name, sizes.build_scratch_size)?;
+            self.structure = Some((structure, scratch));
+        }
+
+        let (structure, scratch) = self.structure.as_ref().unwrap();
+        build_info.dst_acceleration_structure = structure.handle;
+        build_info.scratch_data = vk::DeviceOrHostAddressKHR {
+            device_address: align_up(scratch.device_address(), ray_tracing.scratch_alignment),
+        };
+
+        let range = vk::AccelerationStructureBuildRangeInfoKHR {
+            primitive_count: instances.len() as u32,
+            primitive_offset: 0,
+            first_vertex: 0,
+            transform_offset: 0,
+        };
+
+        ray_tracing.acceleration_structure.cmd_build_acceleration_structures(command_buffer, &[build_info], &[&[range]]);
+        memory_barrier(&ray_tracing.device, command_buffer, BUILD_WRITE, RAY_TRACING_READ);
+        Ok(())
+    }
+
+    unsafe fn instance_buffer(ray_tracing: &RayTracing, frame_index: usize, capacity: usize) -> anyhow::Result<Buffer> {
+        Buffer::new(
+            &ray_tracing.device,
+            &format!("top level instances {}", frame_index),
+            (capacity * std::mem::size_of::<vk::AccelerationStructureInstanceKHR>()) as vk::DeviceSize,
+            vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS | vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR,
+            MemoryLocation::CpuToGpu,
+        )
+    }
+}
+
+/// One raygen shader, any number of miss shaders and a triangle hit group per closest hit shader, in the order
+/// they were added. Hit groups are picked by the instances' shader binding table offset, miss shaders by the
+/// `missIndex` of `traceRayEXT`.
+pub struct RayTracingPipelineBuilder {
+    raygen: Option<ShaderStage>,
+    miss: Vec<ShaderStage>,
+    closest_hit: Vec<ShaderStage>,
+    set_layouts: Vec<vk::DescriptorSetLayout>,
+    push_constant_ranges: Vec<vk::PushConstantRange>,
+    max_recursion_depth: u32,
+}
+
+impl Default for RayTracingPipelineBuilder {
+    fn default() -> Self {
+        Self {
+            raygen: None,
+            miss: Vec::new(),
+            closest_hit: Vec::new(),
+            set_layouts: Vec::new(),
+            push_constant_ranges: Vec::new(),
+            max_recursion_depth: 1,
+        }
+    }
+}
+
+impl RayTracingPipelineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn raygen(mut self, module: &ShaderModule) -> Self {
+        self.raygen = Some(module.stage_info());
+        self
+    }
+
+    pub fn miss(mut self, module: &ShaderModule) -> Self {
+        self.miss.push(module.stage_info());
+        self
+    }
+
+    pub fn closest_hit(mut self, module: &ShaderModule) -> Self {
+        self.closest_hit.push(module.stage_info());
+        self
+    }
+
+    pub fn descriptor_set_layout(mut self, layout: vk::DescriptorSetLayout) -> Self {
+        self.set_layouts.push(layout);
+        self
+    }
+
+    /// Declares a push constant range holding a `T` at `offset`, visible to `stages`.
+    pub fn push_constants<T: Pod>(mut self, stages: vk::ShaderStageFlags, offset: u32) -> Self {
+        self.push_constant_ranges.push(vk::PushConstantRange {
+            stage_flags: stages,
+            offset,
+            size: std::mem::size_of::<T>() as u32,
+        });
+        self
+    }
+
+    /// How deep hit and miss shaders may trace more rays; 1 when only the raygen shader traces. Clamped to what
+    /// the device supports.
+    pub fn max_recursion_depth(mut self, depth: u32) -> Self {
+        self.max_recursion_depth = depth;
+        self
+    }
+
+    pub unsafe fn build(&self, ray_tracing: &RayTracing) -> anyhow::Result<RayTracingPipeline> {
+        let raygen = self.raygen.as_ref().ok_or(anyhow!("Ray tracing pipeline has no raygen shader"))?;
+        let expected = std::iter::once((raygen, vk::ShaderStageFlags::RAYGEN_KHR))
+            .chain(self.miss.iter().map(|stage| (stage, vk::ShaderStageFlags::MISS_KHR)))
+            .chain(self.closest_hit.iter().map(|stage| (stage, vk::ShaderStageFlags::CLOSEST_HIT_KHR)));
+
+        let mut stages = Vec::new();
+        for (stage, expected) in expected {
+            if stage.stage != expected {
+                return Err(anyhow!("Ray tracing pipeline was given a {:?} shader as a {:?} one", stage.stage, expected));
+            }
+
+            stages.push(vk::PipelineShaderStageCreateInfo::builder()
+                .stage(stage.stage)
+                .module(stage.module)
+                .name(&stage.entry_point)
+                .build());
+        }
+
+        let general = |index: usize| vk::RayTracingShaderGroupCreateInfoKHR::builder()
+            .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
+            .general_shader(index as u32)
+            .closest_hit_shader(vk::SHADER_UNUSED_KHR)
+            .any_hit_shader(vk::SHADER_UNUSED_KHR)
+            .intersection_shader(vk::SHADER_UNUSED_KHR)
+            .build();
+        let hit = |index: usize| vk::RayTracingShaderGroupCreateInfoKHR::builder()
+            .ty(vk::RayTracingShaderGroupTypeKHR::TRIANGLES_HIT_GROUP)
+            .general_shader(vk::SHADER_UNUSED_KHR)
+            .closest_hit_shader(index as u32)
+            .any_hit_shader(vk::SHADER_UNUSED_KHR)
+            .intersection_shader(vk::SHADER_UNUSED_KHR)
+            .build();
+        let groups: Vec<_> = (0..=self.miss.len())
+            .map(general)
+            .chain((0..self.closest_hit.len()).map(|index| hit(1 + self.miss.len() + index)))
+            .collect();
+
+        let layout = create_pipeline_layout(&ray_tracing.device, &self.set_layouts, &self.push_constant_ranges)?;
+        let create_info = vk::RayTracingPipelineCreateInfoKHR::builder()
+            .stages(&stages)
+            .groups(&groups)
+            .max_pipeline_ray_recursion_depth(self.max_recursion_depth.min(ray_tracing.max_recursion_depth))
+            .layout(layout)
+            .build();
+
+        let handle = match ray_tracing.pipeline.create_ray_tracing_pipelines(
+            vk::DeferredOperationKHR::null(),
+            ray_tracing.device.pipeline_cache(),
+            &[create_info],
+            None,
+        ) {
+            Ok(pipelines) => pipelines[0],
+            Err(err) => {
+                ray_tracing.device.destroy_pipeline_layout(layout, None);
+                return Err(err.into());
+            }
+        };
+
+        Ok(RayTracingPipeline {
+            device: ray_tracing.device.clone(),
+            handle,
+            layout,
+            push_constant_ranges: self.push_constant_ranges.clone(),
+            miss_count: self.miss.len() as u32,
+            hit_count: self.closest_hit.len() as u32,
+        })
+    }
+}
+
+pub struct RayTracingPipeline {
+    device: Arc<Device>,
+    handle: vk::Pipeline,
+    layout: vk::PipelineLayout,
+    push_constant_ranges: Vec<vk::PushConstantRange>,
+    miss_count: u32,
+    hit_count: u32,
+}
+
+impl RayTracingPipeline {
+    pub fn handle(&self) -> vk::Pipeline {
+        self.handle
+    }
+
+    pub fn layout(&self) -> vk::PipelineLayout {
+        self.layout
+    }
+
+    pub unsafe fn bind(&self, command_buffer: vk::CommandBuffer) {
+        self.device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::RAY_TRACING_KHR, self.handle);
+    }
+
+    pub unsafe fn bind_descriptor_sets(&self, command_buffer: vk::CommandBuffer, first_set: u32, sets: &[vk::DescriptorSet]) {
+        self.device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::RAY_TRACING_KHR, self.layout, first_set, sets, &[]);
+    }
+
+    pub unsafe fn push_constants<T: Pod>(&self, command_buffer: vk::CommandBuffer, stages: vk::ShaderStageFlags, offset: u32, value: &T) {
+        push_constants(&self.device, command_buffer, self.layout, &self.push_constant_ranges, stages, offset, value);
+    }
+}
+
+impl Drop for RayTracingPipeline {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_pipeline(self.handle, None);
+            self.device.destroy_pipeline_layout(self.layout, None);
+        }
+    }
+}
+
+/// The group handles of a [`RayTracingPipeline`] laid out in a host visible buffer, one region each for its raygen,
+/// miss and hit groups.
+pub struct ShaderBindingTable {
+    _buffer: Buffer,
+    raygen: vk::StridedDeviceAddressRegionKHR,
+    miss: vk::StridedDeviceAddressRegionKHR,
+    hit: vk::StridedDeviceAddressRegionKHR,
+}
+
+impl ShaderBindingTable {
+    pub unsafe fn new(ray_tracing: &RayTracing, pipeline: &RayTracingPipeline) -> anyhow::Result<Self> {
+        let handle_size = ray_tracing.handle_size as vk::DeviceSize;
+        let base_alignment = ray_tracing.base_alignment as vk::DeviceSize;
+        let stride = align_up(handle_size, ray_tracing.handle_alignment as vk::DeviceSize);
+        let group_count = 1 + pipeline.miss_count + pipeline.hit_count;
+
+        let handles = ray_tracing.pipeline.get_ray_tracing_shader_group_handles(
+            pipeline.handle,
+            0,
+            group_count,
+            group_count as usize * handle_size as usize,
+        )?;
+
+        // The raygen region's size must equal its stride.
+        let raygen_stride = align_up(stride, base_alignment);
+        let region = |stride: vk::DeviceSize, count: u32| vk::StridedDeviceAddressRegionKHR {
+            device_address: 0,
+            stride,
+            size: align_up(stride * count as vk::DeviceSize, base_alignment),
+        };
+        let mut raygen = region(raygen_stride, 1);
+        let mut miss = region(stride, pipeline.miss_count);
+        let mut hit = region(stride, pipeline.hit_count);
+
+        // Padded so the regions can start at an aligned address wherever the buffer lands.
+        let mut buffer = Buffer::new(
+            &ray_tracing.device,
+            "shader binding table",
+            raygen.size + miss.size + hit.size + base_alignment,
+            vk::BufferUsageFlags::SHADER_BINDING_TABLE_KHR | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+            MemoryLocation::CpuToGpu,
+        )?;
+        let address = buffer.device_address();
+        let start = align_up(address, base_alignment);
+
+        let mut offset = start - address;
+        let mut group = 0;
+        let mapped = buffer.mapped_slice_mut::<u8>()?;
+        for (region, count) in [(&mut raygen, 1), (&mut miss, pipeline.miss_count), (&mut hit, pipeline.hit_count)] {
+            if count > 0 {
+                region.device_address = address + offset;
+            }
+
+            for index in 0..count as vk::DeviceSize {
+                let handle = &handles[group * handle_size as usize..][..handle_size as usize];
+                let at = (offset + index * region.stride) as usize;
+                mapped[at..at + handle.len()].copy_from_slice(handle);
+                group += 1;
+            }
+
+            offset += region.size;
+        }
+
+        Ok(Self {
+            _buffer: buffer,
+            raygen,
+            miss,
+            hit,
+        })
+    }
+}
+
+/// Ambient occlusion of the deferred path traced against the scene's opaque meshes, in place of the screen-space
+/// estimate of [`Ssao`](crate::ssao::Ssao), whose blur it's smoothed with. Reads the G-buffer normals and positions
+/// and writes a [`RAY_TRACED_OCCLUSION_FORMAT`] image.
+pub struct RayTracedAo {
+    ray_tracing: RayTracing,
+    pipeline: RayTracingPipeline,
+    binding_table: ShaderBindingTable,
+    /// Holds the acceleration structure descriptors the shared descriptor allocators don't reserve.
+    pool: vk::DescriptorPool,
+    /// Written every frame, since the G-buffer and the top level structure change.
+    sets: Vec<vk::DescriptorSet>,
+    top_levels: Vec<TopLevelAccelerationStructure>,
+    /// In the order of the renderer's meshes, and built the first frame they might be drawn. `None` for meshes
+    /// without triangles.
+    bottom_levels: Vec<Option<AccelerationStructure>>,
+}
+
+impl RayTracedAo {
+    /// `None` when the device can't trace rays.
+    pub unsafe fn new(
+        device: &Arc<Device>,
+        layouts: &mut DescriptorLayoutCache,
+        compiler: &GlslCompiler,
+        frames_in_flight: usize,
+    ) -> anyhow::Result<Option<Self>> {
+        let Some(ray_tracing) = RayTracing::new(device) else {
+            return Ok(None);
+        };
+
+        let module = |name: &str, source: &str, stage: vk::ShaderStageFlags| {
+            ShaderModule::from_bytes_with_stage(device, name, &compiler.compile_source(source, stage, name)?, stage)
+        };
+        let raygen = module("rt_ao.rgen", RT_AO_RGEN, vk::ShaderStageFlags::RAYGEN_KHR)?;
+        let miss = module("rt_ao.rmiss", RT_AO_RMISS, vk::ShaderStageFlags::MISS_KHR)?;
+        let closest_hit = module("rt_ao.rchit", RT_AO_RCHIT, vk::ShaderStageFlags::CLOSEST_HIT_KHR)?;
+
+        let layout = layouts.get(&SetLayoutDesc::new()
+            .binding(0, vk::DescriptorType::ACCELERATION_STRUCTURE_KHR, vk::ShaderStageFlags::RAYGEN_KHR)
+            .binding(1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::RAYGEN_KHR)
+            .binding(2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::RAYGEN_KHR)
+            .binding(3, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::RAYGEN_KHR))?;
+
+        let pipeline = RayTracingPipelineBuilder::new()
+            .raygen(&raygen)
+            .miss(&miss)
+            .closest_hit(&closest_hit)
+            .descriptor_set_layout(layout)
+            .push_constants::<SsaoParams>(vk::ShaderStageFlags::RAYGEN_KHR, 0)
+            .build(&ray_tracing)?;
+        let binding_table = ShaderBindingTable::new(&ray_tracing, &pipeline)?;
+
+        let frames = frames_in_flight as u32;
+        let pool_sizes = [
+            vk::DescriptorPoolSize { ty: vk::DescriptorType::ACCELERATION_STRUCTURE_KHR, descriptor_count: frames },
+            vk::DescriptorPoolSize { ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER, descriptor_count: 2 * frames },
+            vk::DescriptorPoolSize { ty: vk::DescriptorType::STORAGE_IMAGE, descriptor_count: frames },
+        ];
+        let pool = device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder().max_sets(frames).pool_sizes(&pool_sizes),
+            None,
+        )?;
+
+        let set_layouts = vec![layout; frames_in_flight];
+        let sets = match device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder().descriptor_pool(pool).set_layouts(&set_layouts),
+        ) {
+            Ok(sets) => sets,
+            Err(err) => {
+                device.destroy_descriptor_pool(pool, None);
+                return Err(err.into());
+            }
+        };
+
+        let mut ao = Self {
+            ray_tracing,
+            pipeline,
+            binding_table,
+            pool,
+            sets,
+            top_levels: Vec::new(),
+            bottom_levels: Vec::new(),
+        };
+
+        ao.top_levels = (0..frames_in_flight)
+            .map(|frame_index| TopLevelAccelerationStructure::new(&ao.ray_tracing, frame_index))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Some(ao))
+    }
+
+    /// How many meshes have a bottom level structure, which `build_bottom_level` adds one at a time.
+    pub fn bottom_level_count(&self) -> usize {
+        self.bottom_levels.len()
+    }
+
+    /// Builds the structure of the next mesh, blocking until it's done. Meshes without triangles are skipped.
+    pub unsafe fn build_bottom_level(&mut self, name: &str, positions: &[[f32; 3]], indices: &[u32]) -> anyhow::Result<()> {
+        let structure = match indices.len() >= 3 {
+            true => Some(self.ray_tracing.build_bottom_level(name, positions, indices)?),
+            false => None,
+        };
+
+        self.bottom_levels.push(structure);
+        Ok(())
+    }
+
+    /// Records building this frame's top level structure from `(mesh, transform)` pairs, where meshes index the
+    /// bottom level structures. Call it before the render graph's passes are recorded.
+    pub unsafe fn record_scene(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        instances: impl Iterator<Item = (usize, Matrix4<f32>)>,
+    ) -> anyhow::Result<()> {
+        let instances: Vec<_> = instances
+            .filter_map(|(mesh, transform)| Some(self.bottom_levels.get(mesh)?.as_ref()?.instance(transform, mesh as u32)))
+            .collect();
+
+        self.top_levels[frame_index].record_build(&self.ray_tracing, command_buffer, frame_index, &instances)
+    }
+
+    /// Adds the pass tracing the occlusion of the G-buffer's `normal` and `position` images against the scene
+    /// `record_scene` built this frame, with the sample count, reach and intensity of `quality`. Returns the
+    /// unblurred occlusion.
+    pub unsafe fn add_pass<'a>(
+        &'a self,
+        graph: &mut RenderGraph<'a>,
+        normal: GraphImage,
+        position: GraphImage,
+        frame_index: usize,
+        quality: SsaoQuality,
+    ) -> GraphImage {
+        let extent = graph.extent(position);
+        let occlusion = graph.create_image(
+            "ray traced ambient occlusion",
+            ImageDesc::new_2d(extent.width, extent.height, RAY_TRACED_OCCLUSION_FORMAT, vk::ImageUsageFlags::empty()),
+        );
+        let params = SsaoParams::from(quality);
+        let set = self.sets[frame_index];
+        let top_level = self.top_levels[frame_index].handle();
+
+        graph.add_pass("ray traced ambient occlusion")
+            .image(normal, ImageAccess::Sampled(vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR))
+            .image(position, ImageAccess::Sampled(vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR))
+            .image(occlusion, ImageAccess::StorageWrite(vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR))
+            .execute(move |ctx| {
+                let device = &self.ray_tracing.device;
+                let command_buffer = ctx.command_buffer();
+                let top_level = top_level.ok_or(anyhow!("Ray traced ambient occlusion was added before the scene was recorded"))?;
+                let sampler = device.sampler(&SamplerDesc::nearest())?;
+
+                DescriptorWriter::new()
+                    .acceleration_structure(0, top_level)
+                    .image(1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, ctx.view(normal), sampler, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                    .image(2, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, ctx.view(position), sampler, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                    .image(3, vk::DescriptorType::STORAGE_IMAGE, ctx.view(occlusion), vk::Sampler::null(), vk::ImageLayout::GENERAL)
+                    .update(device, set);
+
+                self.pipeline.bind(command_buffer);
+                self.pipeline.bind_descriptor_sets(command_buffer, 0, &[set]);
+                self.pipeline.push_constants(command_buffer, vk::ShaderStageFlags::RAYGEN_KHR, 0, &params);
+                self.ray_tracing.trace_rays(command_buffer, &self.binding_table, extent.width, extent.height);
+                Ok(())
+            });
+
+        occlusion
+    }
+}
+
+impl Drop for RayTracedAo {
+    fn drop(&mut self) {
+        unsafe {
+            self.ray_tracing.device.destroy_descriptor_pool(self.pool, None);
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use std::sync::Arc;
+use ash::vk;
+use log::info;
+use crate::device::Device;
+
+/// How many times `App` recreates a lost surface that fails again right away, unless configured otherwise.
+pub const DEFAULT_SURFACE_RETRIES: u32 = 3;
+
+/// What was lost when the driver reported `VK_ERROR_DEVICE_LOST` or `VK_ERROR_SURFACE_LOST_KHR`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Loss {
+    Device,
+    Surface,
+}
+
+impl Loss {
+    /// Finds a device- or surface-lost result anywhere in the chain of `err`.
+    pub fn from_error(err: &anyhow::Error) -> Option<Self> {
+        err.chain()
+            .find_map(|cause| match cause.downcast_ref::<vk::Result>() {
+                Some(&vk::Result::ERROR_DEVICE_LOST) => Some(Self::Device),
+                Some(&vk::Result::ERROR_SURFACE_LOST_KHR) => Some(Self::Surface),
+                _ => None,
+            })
+    }
+}
+
+/// Recreates a resource on a given device. Loaders are called once on registration and again on every new device
+/// after a loss, so they should replace whatever they created before, e.g. through an `Rc<RefCell<_>>` they share
+/// with the renderer.
+pub type ResourceLoader = Box<dyn FnMut(&Arc<Device>) -> anyhow::Result<()>>;
+
+/// Loaders for the resources that have to be uploaded again when the device is recreated.
+#[derive(Default)]
+pub struct ResourceRegistry {
+    loaders: Vec<(String, ResourceLoader)>,
+}
+
+impl ResourceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `loader` on `device` and keeps it for later reloads.
+    pub fn register(&mut self, name: &str, device: &Arc<Device>, mut loader: ResourceLoader) -> anyhow::Result<()> {
+        loader(device)?;
+        self.loaders.push((name.to_owned(), loader));
+        Ok(())
+    }
+
+    pub fn reload_all(&mut self, device: &Arc<Device>) -> anyhow::Result<()> {
+        for (name, loader) in &mut self.loaders {
+            loader(device).map_err(|err| err.context(format!("Failed to reload resource '{}'", name)))?;
+        }
+
+        info!("Reloaded {} resource(s)", self.loaders.len());
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.loaders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.loaders.is_empty()
+    }
+}
@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::fmt;
+use ash::vk;
+use thiserror::Error;
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+
+const OP_NAME: u32 = 5;
+const OP_ENTRY_POINT: u32 = 15;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+const OP_TYPE_ACCELERATION_STRUCTURE: u32 = 5341;
+
+const DECORATION_BLOCK: u32 = 2;
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BUILT_IN: u32 = 11;
+const DECORATION_LOCATION: u32 = 30;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+const STORAGE_CLASS_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_CLASS_INPUT: u32 = 1;
+const STORAGE_CLASS_UNIFORM: u32 = 2;
+const STORAGE_CLASS_PUSH_CONSTANT: u32 = 9;
+const STORAGE_CLASS_STORAGE_BUFFER: u32 = 12;
+
+const DIM_BUFFER: u32 = 5;
+const DIM_SUBPASS_DATA: u32 = 6;
+
+#[derive(Error, Debug)]
+pub enum ReflectError {
+    #[error("SPIR-V byte length {0} is not a multiple of 4")]
+    UnalignedLength(usize),
+    #[error("Invalid SPIR-V magic number {0:#010x}")]
+    InvalidMagic(u32),
+    #[error("SPIR-V module is truncated")]
+    Truncated,
+    #[error("SPIR-V module has no entry point")]
+    NoEntryPoint,
+    #[error("Unsupported execution model {0}")]
+    UnsupportedExecutionModel(u32),
+    #[error("Variable {0} refers to an unknown type")]
+    UnknownType(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DescriptorBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    /// Number of descriptors in the binding. Zero for runtime-sized arrays.
+    pub count: u32,
+    pub stage: vk::ShaderStageFlags,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VertexInput {
+    pub location: u32,
+    pub format: vk::Format,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ShaderReflection {
+    pub entry_point: String,
+    pub stage: vk::ShaderStageFlags,
+    pub descriptor_bindings: Vec<DescriptorBinding>,
+    pub push_constant_ranges: Vec<vk::PushConstantRange>,
+    pub vertex_inputs: Vec<VertexInput>,
+}
+
+#[derive(Debug, Clone)]
+enum Type {
+    Int { width: u32, signed: bool },
+    Float { width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { dim: u32, sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray { element: u32 },
+    Struct { members: Vec<u32> },
+    Pointer { pointee: u32 },
+    AccelerationStructure,
+}
+
+#[derive(Default)]
+struct Module {
+    names: HashMap<u32, String>,
+    decorations: HashMap<(u32, u32), u32>,
+    flags: HashMap<u32, Vec<u32>>,
+    member_decorations: HashMap<(u32, u32, u32), u32>,
+    types: HashMap<u32, Type>,
+    constants: HashMap<u32, u32>,
+    variables: Vec<(u32, u32, u32)>,
+    entry_point: Option<(u32, String, Vec<u32>)>,
+}
+
+impl Module {
+    fn decoration(&self, id: u32, decoration: u32) -> Option<u32> {
+        self.decorations.get(&(id, decoration)).copied()
+    }
+
+    fn is_decorated(&self, id: u32, decoration: u32) -> bool {
+        self.flags.get(&id).is_some_and(|flags| flags.contains(&decoration))
+    }
+
+    fn ty(&self, id: u32) -> Result<&Type, ReflectError> {
+        self.types.get(&id).ok_or(ReflectError::UnknownType(id))
+    }
+
+    fn size_of(&self, id: u32) -> Result<u32, ReflectError> {
+        Ok(match self.ty(id)? {
+            Type::Int { width, .. } | Type::Float { width } => width / 8,
+            Type::Vector { component, count } => self.size_of(*component)? * count,
+            Type::Matrix { column, count } => self.size_of(*column)? * count,
+            Type::Array { element, length } => match self.decoration(id, DECORATION_ARRAY_STRIDE) {
+                Some(stride) => stride * length,
+                None => self.size_of(*element)? * length,
+            },
+            Type::Struct { members } => {
+                let mut size = 0;
+                for (index, &member) in members.iter().enumerate() {
+                    let offset = self.member_decorations
+                        .get(&(id, index as u32, DECORATION_OFFSET))
+                        .copied()
+                        .unwrap_or(size);
+
+                    let member_size = match (self.ty(member)?, self.member_decorations.get(&(id, index as u32, DECORATION_MATRIX_STRIDE))) {
+                        (Type::Matrix { count, .. }, Some(stride)) => stride * count,
+                        _ => self.size_of(member)?,
+                    };
+
+                    size = size.max(offset + member_size);
+                }
+                size
+            }
+            _ => 0,
+        })
+    }
+
+    fn vertex_format(&self, id: u32) -> Result<vk::Format, ReflectError> {
+        let (component, count) = match self.ty(id)? {
+            Type::Vector { component, count } => (*component, *count),
+            _ => (id, 1),
+        };
+
+        let format = match (self.ty(component)?, count) {
+            (Type::Float { width: 32 }, 1) => vk::Format::R32_SFLOAT,
+            (Type::Float { width: 32 }, 2) => vk::Format::R32G32_SFLOAT,
+            (Type::Float { width: 32 }, 3) => vk::Format::R32G32B32_SFLOAT,
+            (Type::Float { width: 32 }, 4) => vk::Format::R32G32B32A32_SFLOAT,
+            (Type::Int { width: 32, signed: true }, 1) => vk::Format::R32_SINT,
+            (Type::Int { width: 32, signed: true }, 2) => vk::Format::R32G32_SINT,
+            (Type::Int { width: 32, signed: true }, 3) => vk::Format::R32G32B32_SINT,
+            (Type::Int { width: 32, signed: true }, 4) => vk::Format::R32G32B32A32_SINT,
+            (Type::Int { width: 32, signed: false }, 1) => vk::Format::R32_UINT,
+            (Type::Int { width: 32, signed: false }, 2) => vk::Format::R32G32_UINT,
+            (Type::Int { width: 32, signed: false }, 3) => vk::Format::R32G32B32_UINT,
+            (Type::Int { width: 32, signed: false }, 4) => vk::Format::R32G32B32A32_UINT,
+            _ => vk::Format::UNDEFINED,
+        };
+
+        Ok(format)
+    }
+
+    fn descriptor_info(&self, storage_class: u32, pointee: u32) -> Result<Option<(vk::DescriptorType, u32)>, ReflectError> {
+        let (element, count) = match self.ty(pointee)? {
+            Type::Array { element, length } => (*element, *length),
+            Type::RuntimeArray { element } => (*element, 0),
+            _ => (pointee, 1),
+        };
+
+        let descriptor_type = match (storage_class, self.ty(element)?) {
+            (STORAGE_CLASS_UNIFORM_CONSTANT, Type::Sampler) => vk::DescriptorType::SAMPLER,
+            (STORAGE_CLASS_UNIFORM_CONSTANT, Type::SampledImage) => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            (STORAGE_CLASS_UNIFORM_CONSTANT, Type::AccelerationStructure) => vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+            (STORAGE_CLASS_UNIFORM_CONSTANT, Type::Image { dim, sampled }) => match (*dim, *sampled) {
+                (DIM_BUFFER, 2) => vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+                (DIM_BUFFER, _) => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+                (DIM_SUBPASS_DATA, _) => vk::DescriptorType::INPUT_ATTACHMENT,
+                (_, 2) => vk::DescriptorType::STORAGE_IMAGE,
+                _ => vk::DescriptorType::SAMPLED_IMAGE,
+            },
+            (STORAGE_CLASS_UNIFORM, Type::Struct { .. }) if self.is_decorated(element, DECORATION_BUFFER_BLOCK) => {
+                vk::DescriptorType::STORAGE_BUFFER
+            }
+            (STORAGE_CLASS_UNIFORM, Type::Struct { .. }) if self.is_decorated(element, DECORATION_BLOCK) => {
+                vk::DescriptorType::UNIFORM_BUFFER
+            }
+            (STORAGE_CLASS_STORAGE_BUFFER, Type::Struct { .. }) => vk::DescriptorType::STORAGE_BUFFER,
+            _ => return Ok(None),
+        };
+
+        Ok(Some((descriptor_type, count)))
+    }
+}
+
+fn parse_string(words: &[u32]) -> (String, usize) {
+    let mut bytes = Vec::new();
+    let mut consumed = 0;
+
+    'words: for word in words {
+        consumed += 1;
+        for byte in word.to_le_bytes() {
+            if byte == 0 {
+                break 'words;
+            }
+            bytes.push(byte);
+        }
+    }
+
+    (String::from_utf8_lossy(&bytes).into_owned(), consumed)
+}
+
+fn execution_model_stage(model: u32) -> Result<vk::ShaderStageFlags, ReflectError> {
+    Ok(match model {
+        0 => vk::ShaderStageFlags::VERTEX,
+        1 => vk::ShaderStageFlags::TESSELLATION_CONTROL,
+        2 => vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+        3 => vk::ShaderStageFlags::GEOMETRY,
+        4 => vk::ShaderStageFlags::FRAGMENT,
+        5 => vk::ShaderStageFlags::COMPUTE,
+        5313 => vk::ShaderStageFlags::RAYGEN_KHR,
+        5314 => vk::ShaderStageFlags::INTERSECTION_KHR,
+        5315 => vk::ShaderStageFlags::ANY_HIT_KHR,
+        5316 => vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+        5317 => vk::ShaderStageFlags::MISS_KHR,
+        5318 => vk::ShaderStageFlags::CALLABLE_KHR,
+        5364 => vk::ShaderStageFlags::TASK_EXT,
+        5365 => vk::ShaderStageFlags::MESH_EXT,
+        model => return Err(ReflectError::UnsupportedExecutionModel(model)),
+    })
+}
+
+pub fn spirv_words(spirv: &[u8]) -> Result<Vec<u32>, ReflectError> {
+    if !spirv.len().is_multiple_of(4) {
+        return Err(ReflectError::UnalignedLength(spirv.len()));
+    }
+
+    let mut words: Vec<u32> = spirv.chunks_exact(4)
+        .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect();
+
+    match words.first() {
+        Some(&SPIRV_MAGIC) => {}
+        Some(&magic) if magic.swap_bytes() == SPIRV_MAGIC => {
+            words.iter_mut().for_each(|word| *word = word.swap_bytes());
+        }
+        Some(&magic) => return Err(ReflectError::InvalidMagic(magic)),
+        None => return Err(ReflectError::Truncated),
+    }
+
+    Ok(words)
+}
+
+/// Reflects the descriptor bindings, push constant ranges and vertex inputs of the first entry point in a SPIR-V
+/// module.
+pub fn reflect_shader(spirv: &[u8]) -> Result<ShaderReflection, ReflectError> {
+    let words = spirv_words(spirv)?;
+    if words.len() < 5 {
+        return Err(ReflectError::Truncated);
+    }
+
+    let mut module = Module::default();
+    let mut cursor = 5;
+
+    while cursor < words.len() {
+        let word_count = (words[cursor] >> 16) as usize;
+        let opcode = words[cursor] & 0xffff;
+
+        if word_count == 0 || cursor + word_count > words.len() {
+            return Err(ReflectError::Truncated);
+        }
+
+        let operands = &words[cursor + 1..cursor + word_count];
+        cursor += word_count;
+
+        match opcode {
+            OP_NAME if !operands.is_empty() => {
+                module.names.insert(operands[0], parse_string(&operands[1..]).0);
+            }
+            OP_ENTRY_POINT if operands.len() >= 3 && module.entry_point.is_none() => {
+                let (name, consumed) = parse_string(&operands[2..]);
+                let interface = operands[2 + consumed..].to_vec();
+                module.entry_point = Some((operands[0], name, interface));
+            }
+            OP_DECORATE if operands.len() >= 2 => {
+                module.flags.entry(operands[0]).or_default().push(operands[1]);
+                if let Some(&value) = operands.get(2) {
+                    module.decorations.insert((operands[0], operands[1]), value);
+                }
+            }
+            OP_MEMBER_DECORATE if operands.len() >= 4 => {
+                module.member_decorations.insert((operands[0], operands[1], operands[2]), operands[3]);
+            }
+            OP_TYPE_INT if operands.len() >= 3 => {
+                module.types.insert(operands[0], Type::Int { width: operands[1], signed: operands[2] != 0 });
+            }
+            OP_TYPE_FLOAT if operands.len() >= 2 => {
+                module.types.insert(operands[0], Type::Float { width: operands[1] });
+            }
+            OP_TYPE_VECTOR if operands.len() >= 3 => {
+                module.types.insert(operands[0], Type::Vector { component: operands[1], count: operands[2] });
+            }
+            OP_TYPE_MATRIX if operands.len() >= 3 => {
+                module.types.insert(operands[0], Type::Matrix { column: operands[1], count: operands[2] });
+            }
+            OP_TYPE_IMAGE if operands.len() >= 7 => {
+                module.types.insert(operands[0], Type::Image { dim: operands[2], sampled: operands[6] });
+            }
+            OP_TYPE_SAMPLER if !operands.is_empty() => {
+                module.types.insert(operands[0], Type::Sampler);
+            }
+            OP_TYPE_SAMPLED_IMAGE if !operands.is_empty() => {
+                module.types.insert(operands[0], Type::SampledImage);
+            }
+            OP_TYPE_ARRAY if operands.len() >= 3 => {
+                let length = module.constants.get(&operands[2]).copied().unwrap_or(1);
+                module.types.insert(operands[0], Type::Array { element: operands[1], length });
+            }
+            OP_TYPE_RUNTIME_ARRAY if operands.len() >= 2 => {
+                module.types.insert(operands[0], Type::RuntimeArray { element: operands[1] });
+            }
+            OP_TYPE_STRUCT if !operands.is_empty() => {
+                module.types.insert(operands[0], Type::Struct { members: operands[1..].to_vec() });
+            }
+            OP_TYPE_POINTER if operands.len() >= 3 => {
+                module.types.insert(operands[0], Type::Pointer { pointee: operands[2] });
+            }
+            OP_TYPE_ACCELERATION_STRUCTURE if !operands.is_empty() => {
+                module.types.insert(operands[0], Type::AccelerationStructure);
+            }
+            OP_CONSTANT if operands.len() >= 3 => {
+                module.constants.insert(operands[1], operands[2]);
+            }
+            OP_VARIABLE if operands.len() >= 3 => {
+                module.variables.push((operands[0], operands[1], operands[2]));
+            }
+            _ => {}
+        }
+    }
+
+    let (execution_model, entry_point, interface) = module.entry_point.clone().ok_or(ReflectError::NoEntryPoint)?;
+    let stage = execution_model_stage(execution_model)?;
+
+    let mut descriptor_bindings = Vec::new();
+    let mut push_constant_ranges = Vec::new();
+    let mut vertex_inputs = Vec::new();
+
+    for &(pointer_type, id, storage_class) in &module.variables {
+        let pointee = match module.ty(pointer_type)? {
+            Type::Pointer { pointee, .. } => *pointee,
+            _ => return Err(ReflectError::UnknownType(pointer_type)),
+        };
+
+        match storage_class {
+            STORAGE_CLASS_PUSH_CONSTANT => {
+                push_constant_ranges.push(vk::PushConstantRange {
+                    stage_flags: stage,
+                    offset: 0,
+                    size: module.size_of(pointee)?,
+                });
+            }
+            STORAGE_CLASS_INPUT if stage == vk::ShaderStageFlags::VERTEX => {
+                if !interface.contains(&id) || module.is_decorated(id, DECORATION_BUILT_IN) {
+                    continue;
+                }
+
+                if let Some(location) = module.decoration(id, DECORATION_LOCATION) {
+                    vertex_inputs.push(VertexInput {
+                        location,
+                        format: module.vertex_format(pointee)?,
+                        name: module.names.get(&id).cloned(),
+                    });
+                }
+            }
+            STORAGE_CLASS_UNIFORM_CONSTANT | STORAGE_CLASS_UNIFORM | STORAGE_CLASS_STORAGE_BUFFER => {
+                let Some((descriptor_type, count)) = module.descriptor_info(storage_class, pointee)? else {
+                    continue;
+                };
+
+                let name = module.names.get(&id)
+                    .or_else(|| module.names.get(&pointee))
+                    .filter(|name| !name.is_empty())
+                    .cloned();
+
+                descriptor_bindings.push(DescriptorBinding {
+                    set: module.decoration(id, DECORATION_DESCRIPTOR_SET).unwrap_or(0),
+                    binding: module.decoration(id, DECORATION_BINDING).unwrap_or(0),
+                    descriptor_type,
+                    count,
+                    stage,
+                    name,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    descriptor_bindings.sort_by_key(|binding| (binding.set, binding.binding));
+    vertex_inputs.sort_by_key(|input| input.location);
+
+    Ok(ShaderReflection {
+        entry_point,
+        stage,
+        descriptor_bindings,
+        push_constant_ranges,
+        vertex_inputs,
+    })
+}
+
+impl fmt::Display for ShaderReflection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Entry point '{}' ({:?})", self.entry_point, self.stage)?;
+
+        for binding in &self.descriptor_bindings {
+            writeln!(
+                f,
+                "  set {} binding {}: {:?} x{} {}",
+                binding.set,
+                binding.binding,
+                binding.descriptor_type,
+                binding.count,
+                binding.name.as_deref().unwrap_or(""),
+            )?;
+        }
+
+        for range in &self.push_constant_ranges {
+            writeln!(f, "  push constants: offset {} size {}", range.offset, range.size)?;
+        }
+
+        for input in &self.vertex_inputs {
+            writeln!(f, "  input location {}: {:?} {}", input.location, input.format, input.name.as_deref().unwrap_or(""))?;
+        }
+
+        Ok(())
+    }
+}
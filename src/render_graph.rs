@@ -0,0 +1,978 @@
+use std::sync::Arc;
+use ash::vk;
+use log::debug;
+use thiserror::Error;
+use crate::allocator::{Allocation, AllocationDesc, MemoryLocation};
+use crate::device::Device;
+use crate::frame_arena::{ArenaVec, FrameArena};
+use crate::handles::{GpuResources, HandleError, ImageHandle};
+use crate::image::{create_image_handle, create_image_view, full_range, Image, ImageDesc};
+use crate::pipeline::set_viewport_and_scissor;
+use crate::rendering::{ColorAttachment, DepthAttachment, RenderingPass};
+
+const WRITE_ACCESS: vk::AccessFlags = vk::AccessFlags::from_raw(
+    vk::AccessFlags::SHADER_WRITE.as_raw()
+        | vk::AccessFlags::COLOR_ATTACHMENT_WRITE.as_raw()
+        | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE.as_raw()
+        | vk::AccessFlags::TRANSFER_WRITE.as_raw()
+        | vk::AccessFlags::HOST_WRITE.as_raw()
+        | vk::AccessFlags::MEMORY_WRITE.as_raw(),
+);
+
+#[derive(Error, Debug)]
+pub enum RenderGraphError {
+    #[error("Pass '{0}' renders to attachments, which needs dynamic rendering")]
+    DynamicRenderingUnsupported(String),
+    #[error("Attachments of pass '{0}' don't all have the same extent")]
+    MismatchedExtents(String),
+    #[error("Pass '{0}' renders with a view mask, which needs the multiview feature")]
+    MultiviewUnsupported(String),
+    #[error("Pass '{pass}' uses image '{image}' in two different layouts")]
+    ConflictingLayouts { pass: String, image: String },
+}
+
+/// An image declared in a [`RenderGraph`]. Only valid for the graph it came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GraphImage(usize);
+
+/// A buffer imported into a [`RenderGraph`]. Only valid for the graph it came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GraphBuffer(usize);
+
+/// Layout of an image together with the stages and accesses that last used it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageState {
+    pub layout: vk::ImageLayout,
+    pub stages: vk::PipelineStageFlags,
+    pub access: vk::AccessFlags,
+}
+
+impl ImageState {
+    /// An image nothing has used yet.
+    pub const UNDEFINED: Self = Self {
+        layout: vk::ImageLayout::UNDEFINED,
+        stages: vk::PipelineStageFlags::empty(),
+        access: vk::AccessFlags::empty(),
+    };
+
+    pub fn new(layout: vk::ImageLayout, stages: vk::PipelineStageFlags, access: vk::AccessFlags) -> Self {
+        Self { layout, stages, access }
+    }
+}
+
+/// An image owned outside the graph, such as a swapchain image.
+#[derive(Debug, Clone, Copy)]
+pub struct ImportedImage {
+    pub image: vk::Image,
+    pub view: vk::ImageView,
+    pub extent: vk::Extent2D,
+    pub range: vk::ImageSubresourceRange,
+    /// State the image is in when the graph starts. Passes wait for `stages` before touching it.
+    pub initial: ImageState,
+    /// Layout the image is left in after the last pass, e.g. `PRESENT_SRC_KHR`.
+    pub final_layout: Option<vk::ImageLayout>,
+    /// Whether the contents are still needed after the graph. When they aren't, attachments that are last written
+    /// in the graph are not stored and passes only writing the image can be culled.
+    pub keep_contents: bool,
+}
+
+impl ImportedImage {
+    pub fn new(image: vk::Image, view: vk::ImageView, extent: vk::Extent2D, range: vk::ImageSubresourceRange) -> Self {
+        Self {
+            image,
+            view,
+            extent,
+            range,
+            initial: ImageState::UNDEFINED,
+            final_layout: None,
+            keep_contents: true,
+        }
+    }
+
+    pub fn from_image(image: &Image) -> Self {
+        Self::new(image.handle(), image.view(), image.extent(), image.full_range())
+    }
+
+    /// The image behind `handle`, failing when it was destroyed.
+    pub fn from_handle(resources: &GpuResources, handle: ImageHandle) -> Result<Self, HandleError> {
+        resources.try_image(handle).map(Self::from_image)
+    }
+
+    pub fn with_initial_state(mut self, initial: ImageState) -> Self {
+        self.initial = initial;
+        self
+    }
+
+    pub fn with_final_layout(mut self, final_layout: vk::ImageLayout) -> Self {
+        self.final_layout = Some(final_layout);
+        self
+    }
+
+    pub fn discard_contents(mut self) -> Self {
+        self.keep_contents = false;
+        self
+    }
+}
+
+/// How a pass uses an image other than as an attachment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageAccess {
+    /// Sampled in the given shader stages.
+    Sampled(vk::PipelineStageFlags),
+    StorageRead(vk::PipelineStageFlags),
+    StorageWrite(vk::PipelineStageFlags),
+    TransferSrc,
+    TransferDst,
+}
+
+/// How a pass uses a buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferAccess {
+    Vertex,
+    Index,
+    Indirect,
+    Uniform(vk::PipelineStageFlags),
+    StorageRead(vk::PipelineStageFlags),
+    StorageWrite(vk::PipelineStageFlags),
+    TransferSrc,
+    TransferDst,
+}
+
+impl BufferAccess {
+    fn sync(self) -> (vk::PipelineStageFlags, vk::AccessFlags) {
+        match self {
+            Self::Vertex => (vk::PipelineStageFlags::VERTEX_INPUT, vk::AccessFlags::VERTEX_ATTRIBUTE_READ),
+            Self::Index => (vk::PipelineStageFlags::VERTEX_INPUT, vk::AccessFlags::INDEX_READ),
+            Self::Indirect => (vk::PipelineStageFlags::DRAW_INDIRECT, vk::AccessFlags::INDIRECT_COMMAND_READ),
+            Self::Uniform(stages) => (stages, vk::AccessFlags::UNIFORM_READ),
+            Self::StorageRead(stages) => (stages, vk::AccessFlags::SHADER_READ),
+            Self::StorageWrite(stages) => (stages, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE),
+            Self::TransferSrc => (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_READ),
+            Self::TransferDst => (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE),
+        }
+    }
+
+    fn writes(self) -> bool {
+        matches!(self, Self::StorageWrite(_) | Self::TransferDst)
+    }
+}
+
+/// One use of an image by a pass, with everything needed to synchronize it.
+#[derive(Debug, Clone, Copy)]
+struct ImageUse {
+    layout: vk::ImageLayout,
+    stages: vk::PipelineStageFlags,
+    access: vk::AccessFlags,
+    usage: vk::ImageUsageFlags,
+    reads: bool,
+    writes: bool,
+}
+
+impl ImageUse {
+    fn color(load: bool) -> Self {
+        Self {
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            stages: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            access: if load {
+                vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+            } else {
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+            },
+            usage: vk::ImageUsageFlags::COLOR_ATTACHMENT,
+            reads: load,
+            writes: true,
+        }
+    }
+
+    fn depth(load: bool, write: bool) -> Self {
+        Self {
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            stages: vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            access: if write {
+                vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE
+            } else {
+                vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+            },
+            usage: vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            reads: load,
+            writes: write,
+        }
+    }
+
+    fn access(access: ImageAccess) -> Self {
+        let (layout, stages, flags, usage, writes) = match access {
+            ImageAccess::Sampled(stages) => (
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                stages,
+                vk::AccessFlags::SHADER_READ,
+                vk::ImageUsageFlags::SAMPLED,
+                false,
+            ),
+            ImageAccess::StorageRead(stages) => (
+                vk::ImageLayout::GENERAL,
+                stages,
+                vk::AccessFlags::SHADER_READ,
+                vk::ImageUsageFlags::STORAGE,
+                false,
+            ),
+            ImageAccess::StorageWrite(stages) => (
+                vk::ImageLayout::GENERAL,
+                stages,
+                vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+                vk::ImageUsageFlags::STORAGE,
+                true,
+            ),
+            ImageAccess::TransferSrc => (
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::AccessFlags::TRANSFER_READ,
+                vk::ImageUsageFlags::TRANSFER_SRC,
+                false,
+            ),
+            ImageAccess::TransferDst => (
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::AccessFlags::TRANSFER_WRITE,
+                vk::ImageUsageFlags::TRANSFER_DST,
+                true,
+            ),
+        };
+
+        Self {
+            layout,
+            stages,
+            access: flags,
+            usage,
+            reads: !writes,
+            writes,
+        }
+    }
+}
+
+enum ImageResource {
+    Transient(ImageDesc),
+    Imported(ImportedImage),
+}
+
+struct ImageNode {
+    name: String,
+    resource: ImageResource,
+}
+
+impl ImageNode {
+    fn extent(&self) -> vk::Extent2D {
+        match &self.resource {
+            ImageResource::Transient(desc) => vk::Extent2D {
+                width: desc.width,
+                height: desc.height,
+            },
+            ImageResource::Imported(imported) => imported.extent,
+        }
+    }
+
+    /// Whether writes to the image are visible outside the graph.
+    fn is_output(&self) -> bool {
+        matches!(&self.resource, ImageResource::Imported(imported) if imported.keep_contents)
+    }
+}
+
+struct ColorTarget {
+    image: GraphImage,
+    clear: Option<[f32; 4]>,
+    resolve: Option<GraphImage>,
+}
+
+struct DepthTarget {
+    image: GraphImage,
+    clear: Option<f32>,
+}
+
+type PassCallback<'a> = Box<dyn FnOnce(&PassContext) -> anyhow::Result<()> + 'a>;
+
+struct PassNode<'a> {
+    name: String,
+    images: Vec<(GraphImage, ImageUse)>,
+    buffers: Vec<(GraphBuffer, BufferAccess)>,
+    colors: Vec<ColorTarget>,
+    depth: Option<DepthTarget>,
+    keep: bool,
+    view_mask: u32,
+    callback: Option<PassCallback<'a>>,
+}
+
+impl PassNode<'_> {
+    fn has_attachments(&self) -> bool {
+        !self.colors.is_empty() || self.depth.is_some()
+    }
+
+    /// Adds a use of `image`, merging it with an earlier use in the same layout.
+    fn use_image(&mut self, image: GraphImage, image_use: ImageUse) {
+        let existing = self.images.iter_mut()
+            .find(|(other, other_use)| *other == image && other_use.layout == image_use.layout);
+
+        match existing {
+            Some((_, other_use)) => {
+                other_use.stages |= image_use.stages;
+                other_use.access |= image_use.access;
+                other_use.usage |= image_use.usage;
+                other_use.reads |= image_use.reads;
+                other_use.writes |= image_use.writes;
+            }
+            None => self.images.push((image, image_use)),
+        }
+    }
+}
+
+/// Declares what a pass reads and writes. Finish it with [`execute`](Self::execute).
+pub struct PassBuilder<'g, 'a> {
+    graph: &'g mut RenderGraph<'a>,
+    node: PassNode<'a>,
+}
+
+impl<'a> PassBuilder<'_, 'a> {
+    /// Renders to `image` as the next color attachment, cleared to `clear` or with its contents loaded when `None`.
+    pub fn color(mut self, image: GraphImage, clear: Option<[f32; 4]>) -> Self {
+        self.node.use_image(image, ImageUse::color(clear.is_none()));
+        self.node.colors.push(ColorTarget { image, clear, resolve: None });
+        self
+    }
+
+    /// Renders to the multisampled `image` and resolves it into `resolve` at the end of the pass.
+    pub fn color_resolved(mut self, image: GraphImage, resolve: GraphImage, clear: Option<[f32; 4]>) -> Self {
+        self.node.use_image(image, ImageUse::color(clear.is_none()));
+        self.node.use_image(resolve, ImageUse::color(false));
+        self.node.colors.push(ColorTarget { image, clear, resolve: Some(resolve) });
+        self
+    }
+
+    /// Depth tests against and writes `image`, cleared to `clear` or loaded when `None`.
+    pub fn depth(mut self, image: GraphImage, clear: Option<f32>) -> Self {
+        self.node.use_image(image, ImageUse::depth(clear.is_none(), true));
+        self.node.depth = Some(DepthTarget { image, clear });
+        self
+    }
+
+    /// Depth tests against the existing contents of `image`. Pipelines used in the pass must not write depth.
+    pub fn depth_read_only(mut self, image: GraphImage) -> Self {
+        self.node.use_image(image, ImageUse::depth(true, false));
+        self.node.depth = Some(DepthTarget { image, clear: None });
+        self
+    }
+
+    pub fn image(mut self, image: GraphImage, access: ImageAccess) -> Self {
+        self.node.use_image(image, ImageUse::access(access));
+        self
+    }
+
+    pub fn buffer(mut self, buffer: GraphBuffer, access: BufferAccess) -> Self {
+        self.node.buffers.push((buffer, access));
+        self
+    }
+
+    /// Renders the views in `view_mask` to the matching layers of the attachments in one pass, see
+    /// [`RenderingPass::with_view_mask`]. Attachments created for it need an [`ImageDesc::array`] of enough layers.
+    pub fn multiview(mut self, view_mask: u32) -> Self {
+        self.node.view_mask = view_mask;
+        self
+    }
+
+    /// Never culls the pass, for passes with effects the graph can't see.
+    pub fn keep(mut self) -> Self {
+        self.node.keep = true;
+        self
+    }
+
+    /// Adds the pass with `callback` recording its commands. Passes with attachments are recorded inside dynamic
+    /// rendering with the viewport and scissor already set to the attachment extent.
+    pub fn execute(mut self, callback: impl FnOnce(&PassContext) -> anyhow::Result<()> + 'a) {
+        self.node.callback = Some(Box::new(callback));
+        self.graph.passes.push(self.node);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ResolvedImage {
+    image: vk::Image,
+    view: vk::ImageView,
+}
+
+/// What a pass callback records with.
+pub struct PassContext<'r> {
+    device: &'r Arc<Device>,
+    command_buffer: vk::CommandBuffer,
+    extent: vk::Extent2D,
+    images: &'r [ResolvedImage],
+    buffers: &'r [vk::Buffer],
+}
+
+impl PassContext<'_> {
+    pub fn device(&self) -> &Arc<Device> {
+        self.device
+    }
+
+    pub fn command_buffer(&self) -> vk::CommandBuffer {
+        self.command_buffer
+    }
+
+    /// The render area of a pass with attachments, zero otherwise.
+    pub fn extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+
+    pub fn image(&self, image: GraphImage) -> vk::Image {
+        self.images[image.0].image
+    }
+
+    pub fn view(&self, image: GraphImage) -> vk::ImageView {
+        self.images[image.0].view
+    }
+
+    pub fn buffer(&self, buffer: GraphBuffer) -> vk::Buffer {
+        self.buffers[buffer.0]
+    }
+}
+
+/// Barriers collected for one pass and recorded as a single `vkCmdPipelineBarrier`.
+struct BarrierBatch<'f> {
+    src_stages: vk::PipelineStageFlags,
+    dst_stages: vk::PipelineStageFlags,
+    images: ArenaVec<'f, vk::ImageMemoryBarrier>,
+    buffers: ArenaVec<'f, vk::BufferMemoryBarrier>,
+}
+
+impl<'f> BarrierBatch<'f> {
+    fn new(arena: &'f FrameArena) -> Self {
+        Self {
+            src_stages: vk::PipelineStageFlags::empty(),
+            dst_stages: vk::PipelineStageFlags::empty(),
+            images: arena.vec(),
+            buffers: arena.vec(),
+        }
+    }
+
+    /// Moves `state` to `image_use`, adding a barrier unless both only read the image in the same layout.
+    fn image(&mut self, image: vk::Image, range: vk::ImageSubresourceRange, state: &mut ImageState, image_use: &ImageUse) {
+        let written = state.access.intersects(WRITE_ACCESS);
+        let needs_barrier = state.layout != image_use.layout
+            || (!state.stages.is_empty() && (written || image_use.writes));
+
+        if !needs_barrier {
+            state.stages |= image_use.stages;
+            state.access |= image_use.access;
+            return;
+        }
+
+        self.src_stages |= if state.stages.is_empty() { vk::PipelineStageFlags::TOP_OF_PIPE } else { state.stages };
+        self.dst_stages |= image_use.stages;
+        self.images.push(vk::ImageMemoryBarrier::builder()
+            .src_access_mask(state.access & WRITE_ACCESS)
+            .dst_access_mask(image_use.access)
+            .old_layout(state.layout)
+            .new_layout(image_use.layout)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(range)
+            .build());
+
+        *state = ImageState::new(image_use.layout, image_use.stages, image_use.access);
+    }
+
+    fn buffer(&mut self, buffer: vk::Buffer, state: &mut (vk::PipelineStageFlags, vk::AccessFlags), access: BufferAccess) {
+        let (stages, flags) = access.sync();
+        let written = state.1.intersects(WRITE_ACCESS);
+
+        if state.0.is_empty() || !(written || access.writes()) {
+            state.0 |= stages;
+            state.1 |= flags;
+            return;
+        }
+
+        self.src_stages |= state.0;
+        self.dst_stages |= stages;
+        self.buffers.push(vk::BufferMemoryBarrier::builder()
+            .src_access_mask(state.1 & WRITE_ACCESS)
+            .dst_access_mask(flags)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .build());
+
+        *state = (stages, flags);
+    }
+
+    unsafe fn record(&self, device: &Device, command_buffer: vk::CommandBuffer) {
+        if self.images.is_empty() && self.buffers.is_empty() {
+            return;
+        }
+
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            self.src_stages,
+            self.dst_stages,
+            vk::DependencyFlags::empty(),
+            &[],
+            &self.buffers,
+            &self.images,
+        );
+    }
+}
+
+/// A frame's passes and the resources they use. Passes declare their attachments, images and buffers up front; the
+/// graph then culls passes whose results are never used, runs the rest in the order they were added, and inserts
+/// every layout transition and barrier between them. Images created with [`create_image`](Self::create_image) are
+/// transient: they only live during the graph, and ones whose lifetimes don't overlap share memory.
+///
+/// Build a new graph every frame and keep the [`TransientImages`] it executes with.
+#[derive(Default)]
+pub struct RenderGraph<'a> {
+    images: Vec<ImageNode>,
+    buffers: Vec<(String, vk::Buffer)>,
+    passes: Vec<PassNode<'a>>,
+}
+
+impl<'a> RenderGraph<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declares a transient image. Usage flags needed by the passes using it are added to `desc.usage`, and its
+    /// contents are undefined before the first pass writes it.
+    pub fn create_image(&mut self, name: &str, desc: ImageDesc) -> GraphImage {
+        self.images.push(ImageNode {
+            name: name.to_owned(),
+            resource: ImageResource::Transient(desc),
+        });
+        GraphImage(self.images.len() - 1)
+    }
+
+    pub fn import_image(&mut self, name: &str, image: ImportedImage) -> GraphImage {
+        self.images.push(ImageNode {
+            name: name.to_owned(),
+            resource: ImageResource::Imported(image),
+        });
+        GraphImage(self.images.len() - 1)
+    }
+
+    /// Imports a buffer. Work recorded before the graph must already be synchronized with it, as the uploader does.
+    /// Passes writing a buffer are never culled.
+    pub fn import_buffer(&mut self, name: &str, buffer: vk::Buffer) -> GraphBuffer {
+        self.buffers.push((name.to_owned(), buffer));
+        GraphBuffer(self.buffers.len() - 1)
+    }
+
+    pub fn extent(&self, image: GraphImage) -> vk::Extent2D {
+        self.images[image.0].extent()
+    }
+
+    pub fn add_pass(&mut self, name: &str) -> PassBuilder<'_, 'a> {
+        PassBuilder {
+            graph: self,
+            node: PassNode {
+                name: name.to_owned(),
+                images: Vec::new(),
+                buffers: Vec::new(),
+                colors: Vec::new(),
+                depth: None,
+                keep: false,
+                view_mask: 0,
+                callback: None,
+            },
+        }
+    }
+
+    /// Indices of the passes that contribute to an output, in the order they were added. Walking backwards, a pass
+    /// is live if it writes an output or something a later live pass reads.
+    fn live_passes(&self) -> Vec<usize> {
+        let mut wanted = vec![false; self.images.len()];
+        let mut live = vec![false; self.passes.len()];
+
+        for (index, pass) in self.passes.iter().enumerate().rev() {
+            let writes_output = pass.images.iter()
+                .any(|(image, image_use)| image_use.writes && (self.images[image.0].is_output() || wanted[image.0]))
+                || pass.buffers.iter().any(|(_, access)| access.writes());
+
+            if !pass.keep && !writes_output {
+                continue;
+            }
+
+            live[index] = true;
+            for (image, image_use) in &pass.images {
+                if image_use.writes && !image_use.reads {
+                    wanted[image.0] = false;
+                }
+            }
+
+            for (image, image_use) in &pass.images {
+                if image_use.reads {
+                    wanted[image.0] = true;
+                }
+            }
+        }
+
+        (0..self.passes.len()).filter(|&index| live[index]).collect()
+    }
+
+    fn validate(&self, device: &Device, order: &[usize]) -> anyhow::Result<()> {
+        for pass in order.iter().map(|&index| &self.passes[index]) {
+            for (position, (image, image_use)) in pass.images.iter().enumerate() {
+                if pass.images[..position].iter().any(|(other, other_use)| other == image && other_use.layout != image_use.layout) {
+                    return Err(RenderGraphError::ConflictingLayouts {
+                        pass: pass.name.clone(),
+                        image: self.images[image.0].name.clone(),
+                    }.into());
+                }
+            }
+
+            if !pass.has_attachments() {
+                continue;
+            }
+
+            if !device.supports_dynamic_rendering() {
+                return Err(RenderGraphError::DynamicRenderingUnsupported(pass.name.clone()).into());
+            }
+
+            if pass.view_mask != 0 && !device.supports_multiview() {
+                return Err(RenderGraphError::MultiviewUnsupported(pass.name.clone()).into());
+            }
+
+            let mut extents = pass.colors.iter()
+                .flat_map(|color| std::iter::once(color.image).chain(color.resolve))
+                .chain(pass.depth.as_ref().map(|depth| depth.image))
+                .map(|image| self.extent(image));
+
+            let first = extents.next().unwrap();
+            if extents.any(|extent| extent != first) {
+                return Err(RenderGraphError::MismatchedExtents(pass.name.clone()).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Culls unused passes, allocates transient images from `transients` and records every remaining pass into
+    /// `command_buffer`, building the barrier lists in `arena`.
+    pub unsafe fn execute(
+        mut self,
+        transients: &mut TransientImages,
+        arena: &FrameArena,
+        command_buffer: vk::CommandBuffer,
+    ) -> anyhow::Result<()> {
+        let device = transients.device.clone();
+        let order = self.live_passes();
+        self.validate(&device, &order)?;
+
+        // Position of the first and last live pass using each image, plus the usage they need.
+        let mut lifetimes: Vec<Option<(usize, usize, vk::ImageUsageFlags)>> = vec![None; self.images.len()];
+        for (position, &index) in order.iter().enumerate() {
+            for (image, image_use) in &self.passes[index].images {
+                let lifetime = lifetimes[image.0].get_or_insert((position, position, vk::ImageUsageFlags::empty()));
+                lifetime.1 = position;
+                lifetime.2 |= image_use.usage;
+            }
+        }
+
+        let requests: Vec<TransientRequest> = self.images.iter()
+            .zip(&lifetimes)
+            .enumerate()
+            .filter_map(|(index, (node, lifetime))| match (&node.resource, lifetime) {
+                (ImageResource::Transient(desc), Some((first, last, usage))) => Some(TransientRequest {
+                    image: index,
+                    name: node.name.clone(),
+                    desc: ImageDesc { usage: desc.usage | *usage, ..*desc },
+                    first: *first,
+                    last: *last,
+                }),
+                _ => None,
+            })
+            .collect();
+
+        transients.prepare(&requests)?;
+
+        let mut resolved = vec![ResolvedImage { image: vk::Image::null(), view: vk::ImageView::null() }; self.images.len()];
+        let mut states: Vec<Option<ImageState>> = vec![None; self.images.len()];
+        let mut ranges = vec![vk::ImageSubresourceRange::default(); self.images.len()];
+
+        for (index, node) in self.images.iter().enumerate() {
+            if let ImageResource::Imported(imported) = &node.resource {
+                resolved[index] = ResolvedImage { image: imported.image, view: imported.view };
+                states[index] = Some(imported.initial);
+                ranges[index] = imported.range;
+            }
+        }
+
+        for (slot, request) in requests.iter().enumerate() {
+            let image = &transients.images[slot];
+            resolved[request.image] = ResolvedImage { image: image.image, view: image.view };
+            ranges[request.image] = full_range(&request.desc);
+        }
+
+        let transient_slots: Vec<Option<usize>> = (0..self.images.len())
+            .map(|index| requests.iter().position(|request| request.image == index))
+            .collect();
+        let buffers: Vec<vk::Buffer> = self.buffers.iter().map(|(_, buffer)| *buffer).collect();
+        let mut buffer_states = vec![(vk::PipelineStageFlags::empty(), vk::AccessFlags::empty()); buffers.len()];
+
+        for (position, &index) in order.iter().enumerate() {
+            let pass = &mut self.passes[index];
+            let mut barriers = BarrierBatch::new(arena);
+
+            for (image, image_use) in &pass.images {
+                let transient = transient_slots[image.0].map(|slot| &mut transients.images[slot]);
+
+                // A transient image's memory was last used by whichever image used the same slot before it.
+                let state = states[image.0].get_or_insert_with(|| {
+                    let memory = transient.as_ref().map_or(ImageState::UNDEFINED, |image| transients.slots[image.slot].last);
+                    ImageState { layout: vk::ImageLayout::UNDEFINED, ..memory }
+                });
+
+                barriers.image(resolved[image.0].image, ranges[image.0], state, image_use);
+
+                if let Some(transient) = transient {
+                    transients.slots[transient.slot].last = *state;
+                }
+            }
+
+            for &(buffer, access) in &pass.buffers {
+                barriers.buffer(buffers[buffer.0], &mut buffer_states[buffer.0], access);
+            }
+
+            barriers.record(&device, command_buffer);
+
+            let store_op = |image: GraphImage| {
+                let used_later = lifetimes[image.0].is_some_and(|(_, last, _)| last > position);
+                if used_later || self.images[image.0].is_output() {
+                    vk::AttachmentStoreOp::STORE
+                } else {
+                    vk::AttachmentStoreOp::DONT_CARE
+                }
+            };
+
+            let callback = pass.callback.take().unwrap();
+            if !pass.has_attachments() {
+                callback(&PassContext {
+                    device: &device,
+                    command_buffer,
+                    extent: vk::Extent2D::default(),
+                    images: &resolved,
+                    buffers: &buffers,
+                })?;
+                continue;
+            }
+
+            let extent = pass.colors.first()
+                .map(|color| color.image)
+                .or(pass.depth.as_ref().map(|depth| depth.image))
+                .map(|image| self.images[image.0].extent())
+                .unwrap();
+
+            let mut rendering = RenderingPass::new(extent).with_view_mask(pass.view_mask);
+            for color in &pass.colors {
+                let mut attachment = ColorAttachment::new(resolved[color.image.0].view).with_store_op(store_op(color.image));
+                attachment = match color.clear {
+                    Some(clear) => attachment.with_clear(clear),
+                    None => attachment.with_load_op(vk::AttachmentLoadOp::LOAD),
+                };
+
+                if let Some(resolve) = color.resolve {
+                    attachment = attachment.with_resolve(resolved[resolve.0].view);
+                }
+
+                rendering = rendering.color(attachment);
+            }
+
+            if let Some(depth) = &pass.depth {
+                let attachment = DepthAttachment::new(resolved[depth.image.0].view).with_store_op(store_op(depth.image));
+                rendering = rendering.depth(match depth.clear {
+                    Some(clear) => attachment.with_clear(clear),
+                    None => attachment.with_load_op(vk::AttachmentLoadOp::LOAD),
+                });
+            }
+
+            rendering.begin(&device, command_buffer);
+            set_viewport_and_scissor(&device, command_buffer, extent);
+            let result = callback(&PassContext {
+                device: &device,
+                command_buffer,
+                extent,
+                images: &resolved,
+                buffers: &buffers,
+            });
+            rendering.end(&device, command_buffer);
+            result?;
+        }
+
+        let mut barriers = BarrierBatch::new(arena);
+        for (index, node) in self.images.iter().enumerate() {
+            let ImageResource::Imported(imported) = &node.resource else {
+                continue;
+            };
+
+            if let Some(final_layout) = imported.final_layout {
+                let state = states[index].get_or_insert(imported.initial);
+                let final_use = ImageUse {
+                    layout: final_layout,
+                    stages: vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    access: vk::AccessFlags::empty(),
+                    usage: vk::ImageUsageFlags::empty(),
+                    reads: true,
+                    writes: false,
+                };
+
+                barriers.image(imported.image, imported.range, state, &final_use);
+            }
+        }
+
+        barriers.record(&device, command_buffer);
+        Ok(())
+    }
+}
+
+/// A transient image as the graph asked for it. Two graphs with the same requests can reuse the same images.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TransientRequest {
+    image: usize,
+    name: String,
+    desc: ImageDesc,
+    first: usize,
+    last: usize,
+}
+
+struct TransientImage {
+    image: vk::Image,
+    view: vk::ImageView,
+    slot: usize,
+}
+
+/// Memory shared by transient images whose lifetimes don't overlap.
+struct MemorySlot {
+    allocation: Allocation,
+    /// How the image that last used the memory left it, so the next one can wait for it.
+    last: ImageState,
+}
+
+/// The images and memory backing a graph's transient images, kept from frame to frame. They are only recreated
+/// when the transient images a graph asks for change, e.g. after a resize, and recreating them waits for the device
+/// to go idle.
+pub struct TransientImages {
+    device: Arc<Device>,
+    requests: Vec<TransientRequest>,
+    images: Vec<TransientImage>,
+    slots: Vec<MemorySlot>,
+}
+
+impl TransientImages {
+    pub fn new(device: &Arc<Device>) -> Self {
+        Self {
+            device: device.clone(),
+            requests: Vec::new(),
+            images: Vec::new(),
+            slots: Vec::new(),
+        }
+    }
+
+    /// Total memory backing the transient images, after aliasing.
+    pub fn memory_size(&self) -> vk::DeviceSize {
+        self.slots.iter().map(|slot| slot.allocation.size()).sum()
+    }
+
+    unsafe fn prepare(&mut self, requests: &[TransientRequest]) -> anyhow::Result<()> {
+        if self.requests == requests {
+            return Ok(());
+        }
+
+        self.device.device_wait_idle()?;
+        self.destroy();
+
+        for request in requests {
+            let image = create_image_handle(&self.device, &request.desc)?;
+            self.images.push(TransientImage { image, view: vk::ImageView::null(), slot: 0 });
+        }
+
+        let requirements: Vec<vk::MemoryRequirements> = self.images.iter()
+            .map(|image| self.device.get_image_memory_requirements(image.image))
+            .collect();
+
+        // Place the largest images first, each into the first slot it is compatible with and doesn't overlap any
+        // image in.
+        let mut by_size: Vec<usize> = (0..requests.len()).collect();
+        by_size.sort_by_key(|&index| std::cmp::Reverse(requirements[index].size));
+
+        let mut slots: Vec<(vk::MemoryRequirements, Vec<usize>)> = Vec::new();
+        for index in by_size {
+            let request = &requests[index];
+            let slot = slots.iter().position(|(slot_requirements, members)| {
+                slot_requirements.memory_type_bits & requirements[index].memory_type_bits != 0
+                    && members.iter().all(|&other| requests[other].last < request.first || request.last < requests[other].first)
+            });
+
+            match slot {
+                Some(slot) => {
+                    let (slot_requirements, members) = &mut slots[slot];
+                    slot_requirements.size = slot_requirements.size.max(requirements[index].size);
+                    slot_requirements.alignment = slot_requirements.alignment.max(requirements[index].alignment);
+                    slot_requirements.memory_type_bits &= requirements[index].memory_type_bits;
+                    members.push(index);
+                    self.images[index].slot = slot;
+                }
+                None => {
+                    self.images[index].slot = slots.len();
+                    slots.push((requirements[index], vec![index]));
+                }
+            }
+        }
+
+        for (slot_requirements, members) in &slots {
+            let allocation = self.device.allocate(&AllocationDesc {
+                name: &requests[members[0]].name,
+                requirements: *slot_requirements,
+                location: MemoryLocation::GpuOnly,
+                linear: false,
+            })?;
+
+            self.slots.push(MemorySlot { allocation, last: ImageState::UNDEFINED });
+        }
+
+        for (image, request) in self.images.iter_mut().zip(requests) {
+            let allocation = &self.slots[image.slot].allocation;
+            self.device.bind_image_memory(image.image, allocation.memory(), allocation.offset())?;
+            image.view = create_image_view(&self.device, image.image, &request.desc)?;
+        }
+
+        debug!(
+            "Created {} transient image(s) in {} memory slot(s), {} bytes",
+            self.images.len(),
+            self.slots.len(),
+            self.memory_size(),
+        );
+
+        self.requests = requests.to_vec();
+        Ok(())
+    }
+
+    unsafe fn destroy(&mut self) {
+        for image in self.images.drain(..) {
+            if image.view != vk::ImageView::null() {
+                self.device.destroy_image_view(image.view, None);
+            }
+
+            self.device.destroy_image(image.image, None);
+        }
+
+        for slot in self.slots.drain(..) {
+            self.device.free(slot.allocation);
+        }
+
+        self.requests.clear();
+    }
+}
+
+impl Drop for TransientImages {
+    fn drop(&mut self) {
+        unsafe {
+            self.destroy();
+        }
+    }
+}
@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use ash::vk;
+use crate::device::Device;
+
+/// One attachment of a render pass. The constructors cover the usual cases; the `with_*` methods adjust them.
+#[derive(Debug, Clone, Copy)]
+pub struct AttachmentDesc {
+    pub format: vk::Format,
+    pub samples: vk::SampleCountFlags,
+    pub load_op: vk::AttachmentLoadOp,
+    pub store_op: vk::AttachmentStoreOp,
+    pub stencil_load_op: vk::AttachmentLoadOp,
+    pub stencil_store_op: vk::AttachmentStoreOp,
+    pub initial_layout: vk::ImageLayout,
+    pub final_layout: vk::ImageLayout,
+}
+
+impl AttachmentDesc {
+    /// A color attachment that is cleared and stored, ending in `COLOR_ATTACHMENT_OPTIMAL`.
+    pub fn color(format: vk::Format) -> Self {
+        Self {
+            format,
+            samples: vk::SampleCountFlags::TYPE_1,
+            load_op: vk::AttachmentLoadOp::CLEAR,
+            store_op: vk::AttachmentStoreOp::STORE,
+            stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+            stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+            initial_layout: vk::ImageLayout::UNDEFINED,
+            final_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }
+    }
+
+    /// A color attachment that is cleared and handed to presentation.
+    pub fn present(format: vk::Format) -> Self {
+        Self::color(format).with_final_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+    }
+
+    /// A depth (and stencil) attachment that is cleared and discarded after the pass.
+    pub fn depth(format: vk::Format) -> Self {
+        Self {
+            format,
+            samples: vk::SampleCountFlags::TYPE_1,
+            load_op: vk::AttachmentLoadOp::CLEAR,
+            store_op: vk::AttachmentStoreOp::DONT_CARE,
+            stencil_load_op: vk::AttachmentLoadOp::CLEAR,
+            stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+            initial_layout: vk::ImageLayout::UNDEFINED,
+            final_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        }
+    }
+
+    pub fn with_samples(mut self, samples: vk::SampleCountFlags) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    pub fn with_load_op(mut self, load_op: vk::AttachmentLoadOp) -> Self {
+        self.load_op = load_op;
+        self
+    }
+
+    pub fn with_store_op(mut self, store_op: vk::AttachmentStoreOp) -> Self {
+        self.store_op = store_op;
+        self
+    }
+
+    pub fn with_stencil_ops(mut self, load_op: vk::AttachmentLoadOp, store_op: vk::AttachmentStoreOp) -> Self {
+        self.stencil_load_op = load_op;
+        self.stencil_store_op = store_op;
+        self
+    }
+
+    pub fn with_initial_layout(mut self, layout: vk::ImageLayout) -> Self {
+        self.initial_layout = layout;
+        self
+    }
+
+    pub fn with_final_layout(mut self, layout: vk::ImageLayout) -> Self {
+        self.final_layout = layout;
+        self
+    }
+
+    fn to_vk(self) -> vk::AttachmentDescription {
+        vk::AttachmentDescription {
+            flags: vk::AttachmentDescriptionFlags::empty(),
+            format: self.format,
+            samples: self.samples,
+            load_op: self.load_op,
+            store_op: self.store_op,
+            stencil_load_op: self.stencil_load_op,
+            stencil_store_op: self.stencil_store_op,
+            initial_layout: self.initial_layout,
+            final_layout: self.final_layout,
+        }
+    }
+}
+
+/// Builds a single-subpass render pass from color attachments, an optional depth attachment and optional resolve
+/// attachments. When no dependency is added, one from `VK_SUBPASS_EXTERNAL` covering color output and depth tests
+/// is used.
+#[derive(Debug, Clone, Default)]
+pub struct RenderPassBuilder {
+    color_attachments: Vec<AttachmentDesc>,
+    resolve_attachments: Vec<AttachmentDesc>,
+    depth_attachment: Option<AttachmentDesc>,
+    dependencies: Vec<vk::SubpassDependency>,
+}
+
+impl RenderPassBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn color(mut self, attachment: AttachmentDesc) -> Self {
+        self.color_attachments.push(attachment);
+        self
+    }
+
+    /// Adds a resolve target for the color attachment with the same index.
+    pub fn resolve(mut self, attachment: AttachmentDesc) -> Self {
+        self.resolve_attachments.push(attachment);
+        self
+    }
+
+    pub fn depth(mut self, attachment: AttachmentDesc) -> Self {
+        self.depth_attachment = Some(attachment);
+        self
+    }
+
+    pub fn dependency(mut self, dependency: vk::SubpassDependency) -> Self {
+        self.dependencies.push(dependency);
+        self
+    }
+
+    fn default_dependency(&self) -> vk::SubpassDependency {
+        let mut stages = vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT;
+        let mut access = vk::AccessFlags::COLOR_ATTACHMENT_WRITE;
+
+        if self.depth_attachment.is_some() {
+            stages |= vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS;
+            access |= vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE;
+        }
+
+        vk::SubpassDependency {
+            src_subpass: vk::SUBPASS_EXTERNAL,
+            dst_subpass: 0,
+            src_stage_mask: stages,
+            dst_stage_mask: stages,
+            src_access_mask: vk::AccessFlags::empty(),
+            dst_access_mask: access,
+            dependency_flags: vk::DependencyFlags::empty(),
+        }
+    }
+
+    /// Attachments are ordered colors, then depth, then resolves; framebuffers must list their views the same way.
+    pub unsafe fn build(&self, device: &Arc<Device>) -> anyhow::Result<RenderPass> {
+        let mut attachments: Vec<vk::AttachmentDescription> = self.color_attachments.iter()
+            .map(|attachment| attachment.to_vk())
+            .collect();
+
+        let color_refs: Vec<vk::AttachmentReference> = (0..self.color_attachments.len() as u32)
+            .map(|attachment| vk::AttachmentReference {
+                attachment,
+                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            })
+            .collect();
+
+        let depth_ref = self.depth_attachment.map(|depth| {
+            attachments.push(depth.to_vk());
+            vk::AttachmentReference {
+                attachment: attachments.len() as u32 - 1,
+                layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            }
+        });
+
+        let resolve_refs: Vec<vk::AttachmentReference> = self.resolve_attachments.iter()
+            .map(|resolve| {
+                attachments.push(resolve.to_vk());
+                vk::AttachmentReference {
+                    attachment: attachments.len() as u32 - 1,
+                    layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                }
+            })
+            .collect();
+
+        let mut subpass = vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .color_attachments(&color_refs);
+
+        if !resolve_refs.is_empty() {
+            subpass = subpass.resolve_attachments(&resolve_refs);
+        }
+
+        if let Some(depth_ref) = depth_ref.as_ref() {
+            subpass = subpass.depth_stencil_attachment(depth_ref);
+        }
+
+        let dependencies = if self.dependencies.is_empty() {
+            vec![self.default_dependency()]
+        } else {
+            self.dependencies.clone()
+        };
+
+        let subpasses = [subpass.build()];
+        let create_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&dependencies);
+
+        Ok(RenderPass {
+            device: device.clone(),
+            handle: device.create_render_pass(&create_info, None)?,
+            color_formats: self.color_attachments.iter().map(|attachment| attachment.format).collect(),
+            depth_format: self.depth_attachment.map(|attachment| attachment.format),
+            samples: self.color_attachments.first()
+                .or(self.depth_attachment.as_ref())
+                .map_or(vk::SampleCountFlags::TYPE_1, |attachment| attachment.samples),
+        })
+    }
+}
+
+pub struct RenderPass {
+    device: Arc<Device>,
+    handle: vk::RenderPass,
+    color_formats: Vec<vk::Format>,
+    depth_format: Option<vk::Format>,
+    samples: vk::SampleCountFlags,
+}
+
+impl RenderPass {
+    pub fn handle(&self) -> vk::RenderPass {
+        self.handle
+    }
+
+    pub fn color_formats(&self) -> &[vk::Format] {
+        &self.color_formats
+    }
+
+    pub fn depth_format(&self) -> Option<vk::Format> {
+        self.depth_format
+    }
+
+    pub fn samples(&self) -> vk::SampleCountFlags {
+        self.samples
+    }
+
+    /// Begins the pass over the whole `extent` with inline subpass contents.
+    pub unsafe fn begin(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        framebuffer: vk::Framebuffer,
+        extent: vk::Extent2D,
+        clear_values: &[vk::ClearValue],
+    ) {
+        let begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.handle)
+            .framebuffer(framebuffer)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D::default(),
+                extent,
+            })
+            .clear_values(clear_values);
+
+        self.device.cmd_begin_render_pass(command_buffer, &begin_info, vk::SubpassContents::INLINE);
+    }
+
+    pub unsafe fn end(&self, command_buffer: vk::CommandBuffer) {
+        self.device.cmd_end_render_pass(command_buffer);
+    }
+}
+
+impl Drop for RenderPass {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_render_pass(self.handle, None);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct FramebufferKey {
+    render_pass: vk::RenderPass,
+    attachments: Vec<vk::ImageView>,
+    width: u32,
+    height: u32,
+}
+
+/// Creates framebuffers on first use and keeps them keyed by render pass, attachments and extent. Image view
+/// handles can be reused by the driver once destroyed, so the cache must be cleared whenever the views it was
+/// filled with go away (e.g. on swapchain recreation).
+pub struct FramebufferCache {
+    device: Arc<Device>,
+    framebuffers: HashMap<FramebufferKey, vk::Framebuffer>,
+}
+
+impl FramebufferCache {
+    pub fn new(device: &Arc<Device>) -> Self {
+        Self {
+            device: device.clone(),
+            framebuffers: HashMap::new(),
+        }
+    }
+
+    pub unsafe fn get(
+        &mut self,
+        render_pass: &RenderPass,
+        attachments: &[vk::ImageView],
+        extent: vk::Extent2D,
+    ) -> anyhow::Result<vk::Framebuffer> {
+        let key = FramebufferKey {
+            render_pass: render_pass.handle(),
+            attachments: attachments.to_vec(),
+            width: extent.width,
+            height: extent.height,
+        };
+
+        if let Some(&framebuffer) = self.framebuffers.get(&key) {
+            return Ok(framebuffer);
+        }
+
+        let create_info = vk::FramebufferCreateInfo::builder()
+            .render_pass(render_pass.handle())
+            .attachments(attachments)
+            .width(extent.width)
+            .height(extent.height)
+            .layers(1);
+
+        let framebuffer = self.device.create_framebuffer(&create_info, None)?;
+        self.framebuffers.insert(key, framebuffer);
+        Ok(framebuffer)
+    }
+
+    pub fn len(&self) -> usize {
+        self.framebuffers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.framebuffers.is_empty()
+    }
+
+    /// Destroys every cached framebuffer. None of them may still be in use by the GPU.
+    pub unsafe fn clear(&mut self) {
+        for (_, framebuffer) in self.framebuffers.drain() {
+            self.device.destroy_framebuffer(framebuffer, None);
+        }
+    }
+}
+
+impl Drop for FramebufferCache {
+    fn drop(&mut self) {
+        unsafe {
+            self.clear();
+        }
+    }
+}
@@ -0,0 +1,543 @@
+use std::mem::offset_of;
+use std::sync::Arc;
+use anyhow::anyhow;
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use cgmath::{Matrix4, Rad, Vector2, Vector3};
+use log::debug;
+use crate::allocator::MemoryLocation;
+use crate::buffer::Buffer;
+use crate::descriptors::{DescriptorAllocator, DescriptorLayoutCache, DescriptorWriter, SetLayoutDesc};
+use crate::device::Device;
+use crate::frame_arena::FrameArena;
+use crate::glsl::GlslCompiler;
+use crate::image::Texture;
+use crate::pipeline::{BlendMode, DepthState, GraphicsPipeline, GraphicsPipelineBuilder, PipelineTarget, Vertex, VertexAttribute};
+use crate::sampler::SamplerDesc;
+use crate::shader::ShaderModule;
+
+const SPRITE_VERT: &str = include_str!("../shaders/sprite.vert");
+const SPRITE_FRAG: &str = include_str!("../shaders/sprite.frag");
+
+/// Sprites the vertex and index buffers have room for before they first grow.
+const INITIAL_CAPACITY: usize = 1024;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SpriteVertex {
+    pub position: [f32; 2],
+    pub uv: [f32; 2],
+    pub color: [f32; 4],
+}
+
+unsafe impl Zeroable for SpriteVertex {}
+unsafe impl Pod for SpriteVertex {}
+
+impl Vertex for SpriteVertex {
+    fn attributes() -> Vec<VertexAttribute> {
+        vec![
+            VertexAttribute {
+                location: 0,
+                format: vk::Format::R32G32_SFLOAT,
+                offset: offset_of!(SpriteVertex, position) as u32,
+            },
+            VertexAttribute {
+                location: 1,
+                format: vk::Format::R32G32_SFLOAT,
+                offset: offset_of!(SpriteVertex, uv) as u32,
+            },
+            VertexAttribute {
+                location: 2,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                offset: offset_of!(SpriteVertex, color) as u32,
+            },
+        ]
+    }
+}
+
+/// A texture owned by a [`Renderer2d`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TextureId(usize);
+
+/// The part of a texture a sprite shows, in normalized texture coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UvRect {
+    pub min: Vector2<f32>,
+    pub max: Vector2<f32>,
+}
+
+impl UvRect {
+    pub const FULL: Self = Self {
+        min: Vector2::new(0.0, 0.0),
+        max: Vector2::new(1.0, 1.0),
+    };
+
+    pub fn new(min: Vector2<f32>, max: Vector2<f32>) -> Self {
+        Self { min, max }
+    }
+
+    /// The rectangle at `x, y` with the given size in pixels of a texture of `texture_size` pixels, e.g. one frame
+    /// of a sprite sheet.
+    pub fn from_pixels(x: u32, y: u32, width: u32, height: u32, texture_size: (u32, u32)) -> Self {
+        let (texture_width, texture_height) = (texture_size.0 as f32, texture_size.1 as f32);
+        Self {
+            min: Vector2::new(x as f32 / texture_width, y as f32 / texture_height),
+            max: Vector2::new((x + width) as f32 / texture_width, (y + height) as f32 / texture_height),
+        }
+    }
+
+    pub fn flip_x(self) -> Self {
+        Self {
+            min: Vector2::new(self.max.x, self.min.y),
+            max: Vector2::new(self.min.x, self.max.y),
+        }
+    }
+
+    pub fn flip_y(self) -> Self {
+        Self {
+            min: Vector2::new(self.min.x, self.max.y),
+            max: Vector2::new(self.max.x, self.min.y),
+        }
+    }
+}
+
+impl Default for UvRect {
+    fn default() -> Self {
+        Self::FULL
+    }
+}
+
+/// Where a sprite is drawn. `origin` is the point of the sprite, in fractions of its size, that sits at `position`
+/// and that it rotates around; the default is the top-left corner.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform2d {
+    pub position: Vector2<f32>,
+    pub size: Vector2<f32>,
+    pub rotation: Rad<f32>,
+    pub origin: Vector2<f32>,
+}
+
+impl Transform2d {
+    pub fn new(position: Vector2<f32>, size: Vector2<f32>) -> Self {
+        Self {
+            position,
+            size,
+            rotation: Rad(0.0),
+            origin: Vector2::new(0.0, 0.0),
+        }
+    }
+
+    pub fn with_rotation(mut self, rotation: impl Into<Rad<f32>>) -> Self {
+        self.rotation = rotation.into();
+        self
+    }
+
+    pub fn with_origin(mut self, origin: Vector2<f32>) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    /// Positions and rotates the sprite around its center.
+    pub fn centered(self) -> Self {
+        self.with_origin(Vector2::new(0.5, 0.5))
+    }
+
+    /// Top-left, top-right, bottom-right and bottom-left corners.
+    fn corners(&self) -> [Vector2<f32>; 4] {
+        let (sin, cos) = self.rotation.0.sin_cos();
+        let offset = Vector2::new(self.origin.x * self.size.x, self.origin.y * self.size.y);
+
+        [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)].map(|(x, y)| {
+            let local = Vector2::new(x * self.size.x, y * self.size.y) - offset;
+            self.position + Vector2::new(local.x * cos - local.y * sin, local.x * sin + local.y * cos)
+        })
+    }
+}
+
+/// A view of the 2D world. World units are pixels at a zoom of 1 with y pointing down, and `position` is the world
+/// point shown at the center of the screen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Camera2d {
+    pub position: Vector2<f32>,
+    pub zoom: f32,
+}
+
+impl Camera2d {
+    pub fn new(position: Vector2<f32>) -> Self {
+        Self { position, zoom: 1.0 }
+    }
+
+    pub fn with_zoom(mut self, zoom: f32) -> Self {
+        self.zoom = zoom;
+        self
+    }
+
+    pub fn view_projection(&self, extent: vk::Extent2D) -> Matrix4<f32> {
+        let width = extent.width.max(1) as f32;
+        let height = extent.height.max(1) as f32;
+
+        Matrix4::from_nonuniform_scale(2.0 * self.zoom / width, 2.0 * self.zoom / height, 1.0)
+            * Matrix4::from_translation(Vector3::new(-self.position.x, -self.position.y, 0.0))
+    }
+
+    /// Maps pixel coordinates with the origin at the top-left corner of the screen, which is what sprites are drawn
+    /// in when no camera is set.
+    pub fn screen(extent: vk::Extent2D) -> Self {
+        Self::new(Vector2::new(extent.width as f32 / 2.0, extent.height as f32 / 2.0))
+    }
+}
+
+/// How queued sprites are ordered before drawing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpriteSort {
+    /// Draw order is submission order, so later sprites cover earlier ones. Consecutive sprites with the same
+    /// texture still share a draw.
+    #[default]
+    Submission,
+    /// Groups sprites by texture for the fewest draws. Only use it when overlapping sprites don't need a particular
+    /// order, e.g. when they are opaque or don't overlap.
+    Texture,
+}
+
+struct SpriteTexture {
+    name: String,
+    width: u32,
+    height: u32,
+    sampler: SamplerDesc,
+    /// Kept to upload the texture again when the renderer moves to a new device.
+    pixels: Vec<u8>,
+    texture: Texture,
+    set: vk::DescriptorSet,
+}
+
+struct QueuedSprite {
+    texture: TextureId,
+    vertices: [SpriteVertex; 4],
+}
+
+/// Batches sprites drawn during a frame into one vertex buffer and records them with as few draws as the sort mode
+/// allows. Textures are owned by the renderer and survive device loss, since it keeps their pixels around.
+pub struct Renderer2d {
+    device: Arc<Device>,
+    layouts: DescriptorLayoutCache,
+    descriptor_allocator: DescriptorAllocator,
+    set_layout: vk::DescriptorSetLayout,
+    pipeline: GraphicsPipeline,
+    textures: Vec<SpriteTexture>,
+    vertex_buffers: Vec<Buffer>,
+    index_buffer: Buffer,
+    capacity: usize,
+    sprites: Vec<QueuedSprite>,
+    camera: Option<Camera2d>,
+    sort: SpriteSort,
+    white: TextureId,
+}
+
+impl Renderer2d {
+    pub unsafe fn new(
+        device: &Arc<Device>,
+        target: &PipelineTarget,
+        compiler: &GlslCompiler,
+        frames_in_flight: usize,
+    ) -> anyhow::Result<Self> {
+        let mut layouts = DescriptorLayoutCache::new(device);
+        let set_layout = layouts.get(&sprite_set_layout())?;
+        let pipeline = build_pipeline(device, target, compiler, set_layout)?;
+
+        let mut renderer = Self {
+            device: device.clone(),
+            layouts,
+            descriptor_allocator: DescriptorAllocator::new(device),
+            set_layout,
+            pipeline,
+            textures: Vec::new(),
+            vertex_buffers: create_vertex_buffers(device, frames_in_flight, INITIAL_CAPACITY)?,
+            index_buffer: create_index_buffer(device, INITIAL_CAPACITY)?,
+            capacity: INITIAL_CAPACITY,
+            sprites: Vec::new(),
+            camera: None,
+            sort: SpriteSort::default(),
+            white: TextureId(0),
+        };
+
+        renderer.white = renderer.create_texture("white", 1, 1, &[255; 4], SamplerDesc::nearest())?;
+        Ok(renderer)
+    }
+
+    /// Uploads an sRGB RGBA8 texture of `width * height * 4` bytes.
+    pub unsafe fn create_texture(
+        &mut self,
+        name: &str,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+        sampler: SamplerDesc,
+    ) -> anyhow::Result<TextureId> {
+        let texture = Texture::from_rgba8(&self.device, name, width, height, pixels, false)?;
+        let set = self.descriptor_allocator.allocate(self.set_layout)?;
+        write_texture_set(&self.device, set, &texture, &sampler)?;
+
+        self.textures.push(SpriteTexture {
+            name: name.to_owned(),
+            width,
+            height,
+            sampler,
+            pixels: pixels.to_vec(),
+            texture,
+            set,
+        });
+
+        Ok(TextureId(self.textures.len() - 1))
+    }
+
+    /// Replaces the pixels of `texture`, which may change its size. Waits for the device to go idle.
+    pub unsafe fn update_texture(&mut self, texture: TextureId, width: u32, height: u32, pixels: &[u8]) -> anyhow::Result<()> {
+        let entry = &mut self.textures[texture.0];
+        let new_texture = Texture::from_rgba8(&self.device, &entry.name, width, height, pixels, false)?;
+
+        self.device.device_wait_idle()?;
+        write_texture_set(&self.device, entry.set, &new_texture, &entry.sampler)?;
+
+        entry.texture = new_texture;
+        entry.width = width;
+        entry.height = height;
+        entry.pixels = pixels.to_vec();
+        Ok(())
+    }
+
+    /// A 1x1 white texture, for drawing solid colored rectangles.
+    pub fn white_texture(&self) -> TextureId {
+        self.white
+    }
+
+    pub fn texture_size(&self, texture: TextureId) -> (u32, u32) {
+        let entry = &self.textures[texture.0];
+        (entry.width, entry.height)
+    }
+
+    /// Draws in world space through `camera`, or in screen pixels when `None`.
+    pub fn set_camera(&mut self, camera: Option<Camera2d>) {
+        self.camera = camera;
+    }
+
+    pub fn camera(&self) -> Option<&Camera2d> {
+        self.camera.as_ref()
+    }
+
+    pub fn set_sort(&mut self, sort: SpriteSort) {
+        self.sort = sort;
+    }
+
+    /// Queues a sprite for this frame. `color` is linear and multiplies the texture.
+    pub fn draw_sprite(&mut self, texture: TextureId, transform: &Transform2d, uv_rect: UvRect, color: [f32; 4]) {
+        let corners = transform.corners();
+        let uvs = [
+            [uv_rect.min.x, uv_rect.min.y],
+            [uv_rect.max.x, uv_rect.min.y],
+            [uv_rect.max.x, uv_rect.max.y],
+            [uv_rect.min.x, uv_rect.max.y],
+        ];
+
+        let vertices = [0, 1, 2, 3].map(|corner| SpriteVertex {
+            position: corners[corner].into(),
+            uv: uvs[corner],
+            color,
+        });
+
+        self.sprites.push(QueuedSprite { texture, vertices });
+    }
+
+    /// Queues a solid rectangle.
+    pub fn draw_rect(&mut self, transform: &Transform2d, color: [f32; 4]) {
+        self.draw_sprite(self.white, transform, UvRect::FULL, color);
+    }
+
+    pub fn queued_sprites(&self) -> usize {
+        self.sprites.len()
+    }
+
+    /// Drops the queued sprites without drawing them, for a frame that is skipped.
+    pub fn discard_frame(&mut self) {
+        self.sprites.clear();
+    }
+
+    /// Number of draws the queued sprites will take.
+    pub fn batch_count(&self) -> usize {
+        self.batches().len()
+    }
+
+    /// Ranges of consecutive queued sprites sharing a texture, as `(texture, first, count)`.
+    fn batches(&self) -> Vec<(TextureId, usize, usize)> {
+        let mut batches: Vec<(TextureId, usize, usize)> = Vec::new();
+
+        for (index, sprite) in self.sprites.iter().enumerate() {
+            match batches.last_mut() {
+                Some((texture, _, count)) if *texture == sprite.texture => *count += 1,
+                _ => batches.push((sprite.texture, index, 1)),
+            }
+        }
+
+        batches
+    }
+
+    /// Grows the buffers to hold `sprites`. The index buffer is shared by every frame, so this waits for the device
+    /// to go idle.
+    unsafe fn reserve(&mut self, sprites: usize) -> anyhow::Result<()> {
+        if sprites <= self.capacity {
+            return Ok(());
+        }
+
+        let capacity = sprites.next_power_of_two();
+        debug!("Growing sprite buffers to {} sprites", capacity);
+
+        self.device.device_wait_idle()?;
+        self.vertex_buffers = create_vertex_buffers(&self.device, self.vertex_buffers.len(), capacity)?;
+        self.index_buffer = create_index_buffer(&self.device, capacity)?;
+        self.capacity = capacity;
+        Ok(())
+    }
+
+    /// Writes the sprites queued this frame into the vertex buffer of `frame_index` and records their draws into
+    /// the current pass, then clears the queue. The viewport must already be set to `extent`.
+    pub unsafe fn record(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        extent: vk::Extent2D,
+        arena: &FrameArena,
+    ) -> anyhow::Result<()> {
+        if self.sprites.is_empty() {
+            return Ok(());
+        }
+
+        if self.sort == SpriteSort::Texture {
+            self.sprites.sort_by_key(|sprite| sprite.texture);
+        }
+
+        self.reserve(self.sprites.len())?;
+
+        let mut vertices = arena.vec::<SpriteVertex>();
+        for sprite in &self.sprites {
+            for vertex in sprite.vertices {
+                vertices.push(vertex);
+            }
+        }
+        let vertex_buffer = &mut self.vertex_buffers[frame_index];
+        vertex_buffer.write(0, &vertices)?;
+
+        let camera = self.camera.unwrap_or_else(|| Camera2d::screen(extent));
+        let view_projection: [[f32; 4]; 4] = camera.view_projection(extent).into();
+
+        self.pipeline.bind(command_buffer);
+        self.pipeline.push_constants(command_buffer, vk::ShaderStageFlags::VERTEX, 0, &view_projection);
+        self.device.cmd_bind_vertex_buffers(command_buffer, 0, &[vertex_buffer.handle()], &[0]);
+        self.device.cmd_bind_index_buffer(command_buffer, self.index_buffer.handle(), 0, vk::IndexType::UINT32);
+
+        for (texture, first, count) in self.batches() {
+            let set = self.textures.get(texture.0).ok_or(anyhow!("Unknown sprite texture {:?}", texture))?.set;
+
+            self.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.pipeline.layout(),
+                0,
+                &[set],
+                &[],
+            );
+            self.device.cmd_draw_indexed(command_buffer, count as u32 * 6, 1, first as u32 * 6, 0, 0);
+        }
+
+        self.sprites.clear();
+        Ok(())
+    }
+
+    /// Rebuilds the pipeline and every texture on `device`, after the device the renderer was created on was lost.
+    pub unsafe fn recreate(&mut self, device: &Arc<Device>, target: &PipelineTarget, compiler: &GlslCompiler) -> anyhow::Result<()> {
+        let frames_in_flight = self.vertex_buffers.len();
+        let textures = std::mem::take(&mut self.textures);
+        self.sprites.clear();
+
+        let mut layouts = DescriptorLayoutCache::new(device);
+        let set_layout = layouts.get(&sprite_set_layout())?;
+
+        self.pipeline = build_pipeline(device, target, compiler, set_layout)?;
+        self.vertex_buffers = create_vertex_buffers(device, frames_in_flight, self.capacity)?;
+        self.index_buffer = create_index_buffer(device, self.capacity)?;
+        self.descriptor_allocator = DescriptorAllocator::new(device);
+        self.layouts = layouts;
+        self.set_layout = set_layout;
+        self.device = device.clone();
+
+        for texture in textures {
+            self.create_texture(&texture.name, texture.width, texture.height, &texture.pixels, texture.sampler)?;
+        }
+
+        Ok(())
+    }
+}
+
+unsafe fn write_texture_set(device: &Device, set: vk::DescriptorSet, texture: &Texture, sampler: &SamplerDesc) -> anyhow::Result<()> {
+    DescriptorWriter::new()
+        .image(
+            0,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            texture.view(),
+            device.sampler(sampler)?,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        )
+        .update(device, set);
+
+    Ok(())
+}
+
+fn sprite_set_layout() -> SetLayoutDesc {
+    SetLayoutDesc::new().binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::FRAGMENT)
+}
+
+unsafe fn build_pipeline(
+    device: &Arc<Device>,
+    target: &PipelineTarget,
+    compiler: &GlslCompiler,
+    set_layout: vk::DescriptorSetLayout,
+) -> anyhow::Result<GraphicsPipeline> {
+    let vertex = ShaderModule::from_bytes_with_stage(
+        device,
+        "sprite.vert",
+        &compiler.compile_source(SPRITE_VERT, vk::ShaderStageFlags::VERTEX, "sprite.vert")?,
+        vk::ShaderStageFlags::VERTEX,
+    )?;
+    let fragment = ShaderModule::from_bytes_with_stage(
+        device,
+        "sprite.frag",
+        &compiler.compile_source(SPRITE_FRAG, vk::ShaderStageFlags::FRAGMENT, "sprite.frag")?,
+        vk::ShaderStageFlags::FRAGMENT,
+    )?;
+
+    GraphicsPipelineBuilder::new()
+        .shader(&vertex)
+        .shader(&fragment)
+        .vertex::<SpriteVertex>(0)
+        .cull_mode(vk::CullModeFlags::NONE)
+        .depth(DepthState::DISABLED)
+        .blend(BlendMode::Alpha)
+        .descriptor_set_layout(set_layout)
+        .push_constants::<[[f32; 4]; 4]>(vk::ShaderStageFlags::VERTEX, 0)
+        .target(target.clone())
+        .build(device)
+}
+
+unsafe fn create_vertex_buffers(device: &Arc<Device>, frames_in_flight: usize, capacity: usize) -> anyhow::Result<Vec<Buffer>> {
+    let size = (capacity * 4 * std::mem::size_of::<SpriteVertex>()) as vk::DeviceSize;
+
+    (0..frames_in_flight)
+        .map(|_| Buffer::new(device, "sprite vertices", size, vk::BufferUsageFlags::VERTEX_BUFFER, MemoryLocation::CpuToGpu))
+        .collect()
+}
+
+unsafe fn create_index_buffer(device: &Arc<Device>, capacity: usize) -> anyhow::Result<Buffer> {
+    let indices: Vec<u32> = (0..capacity as u32)
+        .flat_map(|sprite| [0, 1, 2, 2, 3, 0].map(|corner| sprite * 4 + corner))
+        .collect();
+
+    Buffer::index(device, "sprite indices", &indices)
+}
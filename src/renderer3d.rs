@@ -0,0 +1,2560 @@
+use std::mem::offset_of;
+use std::sync::Arc;
+use anyhow::anyhow;
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use cgmath::{Deg, EuclideanSpace, InnerSpace, Matrix4, Point3, Rad, Vector3};
+use log::{debug, warn};
+use crate::bindless::{bindless_capacity, supports_bindless, BindlessTextures, TextureHandle};
+use crate::allocator::MemoryLocation;
+use crate::animation::{Skeleton, Transform};
+use crate::buffer::{Buffer, PerFrameStorage, PerFrameUniform};
+use crate::culling::{Aabb, BoundingSphere, CullingStats, Frustum};
+use crate::debug_draw::DebugDraw;
+use crate::debug_view::{DebugView, DebugViewPipelines};
+use crate::descriptors::{DescriptorAllocator, DescriptorLayoutCache, DescriptorWriter, SetLayoutDesc};
+use crate::device::Device;
+use crate::frame_arena::FrameArena;
+use crate::glsl::{insert_after_version, GlslCompiler};
+use crate::image::{ImageDesc, Texture};
+use crate::indirect::{GpuObject, IndirectCulling, MeshArena, CASTS_SHADOWS, DRAWN};
+use crate::lighting::{with_lighting, DirectionalLight, Light, LightCulling};
+use crate::lod::{with_lod_dither, LodGroupDesc};
+use crate::material::{DefaultTexture, Material, MaterialDesc, MaterialInstance, MATERIAL_INDEX_OFFSET, MATERIAL_SET};
+use crate::meshlet::{supports_mesh_shading, MeshShading, MeshletArena, MeshletData};
+use crate::occlusion::{supports_depth_format, HiZPyramid};
+use crate::particles::{EmitterDesc, ParticleEmitter, ParticleSystem};
+use crate::pipeline::{
+    set_viewport_and_scissor, set_viewport_rect, BlendMode, DepthState, GraphicsPipeline, GraphicsPipelineBuilder, PipelineTarget, Vertex,
+    VertexAttribute,
+};
+#[cfg(feature = "ray-tracing")]
+use crate::ray_tracing::RayTracedAo;
+use crate::render_graph::{GraphImage, ImageAccess, RenderGraph};
+use crate::requirements::Feature;
+use crate::rendering::RenderingFormats;
+use crate::sampler::SamplerDesc;
+use crate::shader::ShaderModule;
+use crate::shadows::{ShadowMaps, ShadowQuality};
+use crate::skinning::{MorphDeltas, SkinPushConstants, SkinnedMesh, SkinnedMeshData, SkinnedVertex, SKINNED_MESH_VERT};
+use crate::skybox::{CubemapSource, Skybox};
+use crate::ssao::{Ssao, SsaoQuality};
+
+pub(crate) const MESH_VERT: &str = include_str!("../shaders/mesh.vert");
+const MESH_FRAG: &str = include_str!("../shaders/mesh.frag");
+const MESH_GBUFFER_FRAG: &str = include_str!("../shaders/mesh_gbuffer.frag");
+const PBR_FRAG: &str = include_str!("../shaders/pbr.frag");
+const FULLSCREEN_VERT: &str = include_str!("../shaders/fullscreen.vert");
+const DEFERRED_LIGHTING_FRAG: &str = include_str!("../shaders/deferred_lighting.frag");
+
+/// Formats of the G-buffer targets of the deferred path, in the order G-buffer shaders write them: albedo and
+/// occlusion, world space normal and roughness, world space position with a w of 1 where a surface was drawn, and
+/// emissive and metallic.
+pub const GBUFFER_FORMATS: [vk::Format; 4] = [
+    vk::Format::R8G8B8A8_SRGB,
+    vk::Format::R16G16B16A16_SFLOAT,
+    vk::Format::R32G32B32A32_SFLOAT,
+    vk::Format::R16G16B16A16_SFLOAT,
+];
+
+const GBUFFER_NAMES: [&str; 4] = ["gbuffer albedo", "gbuffer normal", "gbuffer position", "gbuffer emissive"];
+
+/// Instances each frame's instance buffer has room for before it first grows.
+const INITIAL_INSTANCE_CAPACITY: usize = 256;
+
+/// Joint matrices each frame's palette buffer has room for before it first grows.
+const INITIAL_PALETTE_CAPACITY: usize = 256;
+
+/// Morph target weights each frame's weight buffer has room for before it first grows.
+const INITIAL_MORPH_WEIGHT_CAPACITY: usize = 64;
+
+/// Views of a multiview target the [`CameraUniform`] has a matrix for.
+pub const MAX_VIEWS: usize = 2;
+
+/// Distance between the eyes of the stereo pair a multiview target is drawn with, in world units (meters, by the
+/// default camera's clip planes).
+pub const DEFAULT_EYE_SEPARATION: f32 = 0.064;
+
+/// Where the frame set holds the texture array of bindless materials, which `bindless.glsl` declares.
+const BINDLESS_TEXTURES_BINDING: u32 = 9;
+
+/// Maps OpenGL clip space, which `cgmath::perspective` produces, to Vulkan's: y points down and depth goes from 0
+/// to 1.
+pub(crate) const VULKAN_CLIP: Matrix4<f32> = Matrix4::new(
+    1.0, 0.0, 0.0, 0.0,
+    0.0, -1.0, 0.0, 0.0,
+    0.0, 0.0, 0.5, 0.0,
+    0.0, 0.0, 0.5, 1.0,
+);
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MeshVertex {
+    pub position: [f32; 3],
+    pub normal: [f32; 3],
+    pub uv: [f32; 2],
+}
+
+unsafe impl Zeroable for MeshVertex {}
+unsafe impl Pod for MeshVertex {}
+
+impl Vertex for MeshVertex {
+    fn attributes() -> Vec<VertexAttribute> {
+        vec![
+            VertexAttribute {
+                location: 0,
+                format: vk::Format::R32G32B32_SFLOAT,
+                offset: offset_of!(MeshVertex, position) as u32,
+            },
+            VertexAttribute {
+                location: 1,
+                format: vk::Format::R32G32B32_SFLOAT,
+                offset: offset_of!(MeshVertex, normal) as u32,
+            },
+            VertexAttribute {
+                location: 2,
+                format: vk::Format::R32G32_SFLOAT,
+                offset: offset_of!(MeshVertex, uv) as u32,
+            },
+        ]
+    }
+}
+
+/// What a material's vertex shader gets of each instance of a mesh: its model matrix in locations 3 to 6, read as a
+/// `mat4` at location 3, four floats of its own in location 7 and the fade `lod_dither` takes in location 8.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct InstanceData {
+    pub model: [[f32; 4]; 4],
+    pub custom: [f32; 4],
+    /// Set while the instance's LOD levels cross-fade, 0 otherwise.
+    pub(crate) lod_fade: f32,
+}
+
+unsafe impl Zeroable for InstanceData {}
+unsafe impl Pod for InstanceData {}
+
+impl InstanceData {
+    pub fn new(transform: Matrix4<f32>) -> Self {
+        Self {
+            model: transform.into(),
+            custom: [0.0; 4],
+            lod_fade: 0.0,
+        }
+    }
+
+    pub fn with_custom(mut self, custom: [f32; 4]) -> Self {
+        self.custom = custom;
+        self
+    }
+}
+
+impl Vertex for InstanceData {
+    fn attributes() -> Vec<VertexAttribute> {
+        let columns = (0..4).map(|column| VertexAttribute {
+            location: 3 + column,
+            format: vk::Format::R32G32B32A32_SFLOAT,
+            offset: (offset_of!(InstanceData, model) + column as usize * std::mem::size_of::<[f32; 4]>()) as u32,
+        });
+
+        columns
+            .chain([VertexAttribute {
+                location: 7,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                offset: offset_of!(InstanceData, custom) as u32,
+            }, VertexAttribute {
+                location: 8,
+                format: vk::Format::R32_SFLOAT,
+                offset: offset_of!(InstanceData, lod_fade) as u32,
+            }])
+            .collect()
+    }
+}
+
+/// Vertices and triangle list indices of a mesh on the CPU.
+#[derive(Debug, Clone, Default)]
+pub struct MeshData {
+    pub vertices: Vec<MeshVertex>,
+    pub indices: Vec<u32>,
+}
+
+impl MeshData {
+    pub fn new(vertices: Vec<MeshVertex>, indices: Vec<u32>) -> Self {
+        Self { vertices, indices }
+    }
+
+    /// A unit cube centered on the origin, with its own normals and a full texture on every face.
+    pub fn cube() -> Self {
+        // Normal, then the directions of u and v on the face, seen from outside with v pointing up.
+        let faces: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+            ([1.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, 1.0, 0.0]),
+            ([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),
+            ([0.0, 1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, -1.0]),
+            ([0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+            ([0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+            ([0.0, 0.0, -1.0], [-1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+        ];
+
+        let mut data = Self::default();
+        for (normal, u, v) in faces {
+            let first = data.vertices.len() as u32;
+
+            for (su, sv) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+                data.vertices.push(MeshVertex {
+                    position: [0, 1, 2].map(|axis| (normal[axis] + u[axis] * su + v[axis] * sv) * 0.5),
+                    normal,
+                    uv: [(su + 1.0) * 0.5, (1.0 - sv) * 0.5],
+                });
+            }
+
+            data.indices.extend([0, 1, 2, 2, 3, 0].map(|corner| first + corner));
+        }
+
+        data
+    }
+
+    /// `None` without vertices.
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        Aabb::from_points(self.positions())
+    }
+
+    /// `None` without vertices.
+    pub fn bounding_sphere(&self) -> Option<BoundingSphere> {
+        BoundingSphere::from_points(self.positions())
+    }
+
+    fn positions(&self) -> impl Iterator<Item = Point3<f32>> + Clone + '_ {
+        self.vertices.iter().map(|vertex| Point3::from(vertex.position))
+    }
+}
+
+/// Device local vertex and index buffers of a mesh, with its bounds.
+pub struct Mesh {
+    vertex_buffer: Buffer,
+    index_buffer: Buffer,
+    index_count: u32,
+    aabb: Aabb,
+    bounding_sphere: BoundingSphere,
+}
+
+impl Mesh {
+    pub unsafe fn new(device: &Arc<Device>, name: &str, data: &MeshData) -> anyhow::Result<Self> {
+        if data.vertices.is_empty() || data.indices.is_empty() {
+            return Err(anyhow!("Mesh '{}' has no geometry", name));
+        }
+
+        Ok(Self {
+            vertex_buffer: Buffer::vertex(device, &format!("{} vertices", name), &data.vertices)?,
+            index_buffer: Buffer::index(device, &format!("{} indices", name), &data.indices)?,
+            index_count: data.indices.len() as u32,
+            aabb: data.bounding_box().expect("the mesh has vertices"),
+            bounding_sphere: data.bounding_sphere().expect("the mesh has vertices"),
+        })
+    }
+
+    pub fn vertex_buffer(&self) -> &Buffer {
+        &self.vertex_buffer
+    }
+
+    pub fn index_buffer(&self) -> &Buffer {
+        &self.index_buffer
+    }
+
+    pub fn index_count(&self) -> u32 {
+        self.index_count
+    }
+
+    /// In the mesh's own space.
+    pub fn aabb(&self) -> &Aabb {
+        &self.aabb
+    }
+
+    /// In the mesh's own space.
+    pub fn bounding_sphere(&self) -> &BoundingSphere {
+        &self.bounding_sphere
+    }
+
+    pub unsafe fn bind(&self, device: &Device, command_buffer: vk::CommandBuffer) {
+        device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.vertex_buffer.handle()], &[0]);
+        device.cmd_bind_index_buffer(command_buffer, self.index_buffer.handle(), 0, vk::IndexType::UINT32);
+    }
+}
+
+/// A perspective camera in a right-handed world with y up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Camera {
+    pub position: Point3<f32>,
+    pub target: Point3<f32>,
+    pub up: Vector3<f32>,
+    /// Vertical field of view.
+    pub fov_y: Rad<f32>,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Camera {
+    pub fn look_at(position: Point3<f32>, target: Point3<f32>) -> Self {
+        Self {
+            position,
+            target,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_fov(mut self, fov_y: impl Into<Rad<f32>>) -> Self {
+        self.fov_y = fov_y.into();
+        self
+    }
+
+    pub fn with_clip(mut self, near: f32, far: f32) -> Self {
+        self.near = near;
+        self.far = far;
+        self
+    }
+
+    pub fn view(&self) -> Matrix4<f32> {
+        Matrix4::look_at_rh(self.position, self.target, self.up)
+    }
+
+    pub fn projection(&self, extent: vk::Extent2D) -> Matrix4<f32> {
+        let aspect = extent.width.max(1) as f32 / extent.height.max(1) as f32;
+        VULKAN_CLIP * cgmath::perspective(self.fov_y, aspect, self.near, self.far)
+    }
+
+    /// The left and right eye, `separation` apart and looking in parallel in the camera's direction.
+    pub fn eyes(&self, separation: f32) -> [Self; 2] {
+        let right = (self.target - self.position).cross(self.up).normalize_to(separation / 2.0);
+        let eye = |offset: Vector3<f32>| Self {
+            position: self.position + offset,
+            target: self.target + offset,
+            ..*self
+        };
+        [eye(-right), eye(right)]
+    }
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            position: Point3::new(0.0, 0.0, 3.0),
+            target: Point3::origin(),
+            up: Vector3::unit_y(),
+            fov_y: Deg(60.0).into(),
+            near: 0.1,
+            far: 100.0,
+        }
+    }
+}
+
+/// What `mesh.vert` sees of the camera, in set 0, binding 0.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CameraUniform {
+    pub view: [[f32; 4]; 4],
+    pub projection: [[f32; 4]; 4],
+    pub view_projection: [[f32; 4]; 4],
+    pub position: [f32; 4],
+    /// x is the [`DebugView::shader_mode`] the lighting shades with. Shaders that don't light can leave it out.
+    pub debug: [u32; 4],
+    /// `view_projection` of each view of a multiview target, which `mesh.vert` picks by `gl_ViewIndex`. All equal to
+    /// `view_projection` unless set with [`with_views`](Self::with_views).
+    pub view_projections: [[[f32; 4]; 4]; MAX_VIEWS],
+}
+
+unsafe impl Zeroable for CameraUniform {}
+unsafe impl Pod for CameraUniform {}
+
+impl CameraUniform {
+    pub fn new(camera: &Camera, extent: vk::Extent2D) -> Self {
+        let view = camera.view();
+        let projection = camera.projection(extent);
+
+        Self {
+            view: view.into(),
+            projection: projection.into(),
+            view_projection: (projection * view).into(),
+            position: camera.position.to_homogeneous().into(),
+            debug: [0; 4],
+            view_projections: [(projection * view).into(); MAX_VIEWS],
+        }
+    }
+
+    /// Sees the views of a multiview target through `cameras`, each drawn into `extent`.
+    pub fn with_views(mut self, cameras: &[Camera; MAX_VIEWS], extent: vk::Extent2D) -> Self {
+        for (view_projection, camera) in self.view_projections.iter_mut().zip(cameras) {
+            *view_projection = (camera.projection(extent) * camera.view()).into();
+        }
+        self
+    }
+
+    pub fn with_debug_view(mut self, view: DebugView) -> Self {
+        self.debug[0] = view.shader_mode();
+        self
+    }
+}
+
+/// A part of the target the scene is drawn into through its own camera, for split-screen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    pub rect: vk::Rect2D,
+    pub camera: Camera,
+}
+
+impl Viewport {
+    pub fn new(rect: vk::Rect2D, camera: Camera) -> Self {
+        Self { rect, camera }
+    }
+
+    /// The left and right halves of `extent`, seen through `left` and `right`.
+    pub fn side_by_side(extent: vk::Extent2D, left: Camera, right: Camera) -> [Self; 2] {
+        let half = extent.width / 2;
+        [
+            Self::new(vk::Rect2D {
+                offset: vk::Offset2D::default(),
+                extent: vk::Extent2D { width: half, height: extent.height },
+            }, left),
+            Self::new(vk::Rect2D {
+                offset: vk::Offset2D { x: half as i32, y: 0 },
+                extent: vk::Extent2D { width: extent.width - half, height: extent.height },
+            }, right),
+        ]
+    }
+}
+
+/// What a viewport after the first needs of its own to be drawn through its camera.
+struct SplitView {
+    camera_uniform: PerFrameUniform<CameraUniform>,
+    lighting: LightCulling,
+    frame_sets: Vec<vk::DescriptorSet>,
+}
+
+/// A mesh owned by a [`Renderer3d`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MeshId(usize);
+
+/// A [`SkinnedMesh`] owned by a [`Renderer3d`], with the skeleton its vertices are weighted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SkinnedMeshId(usize);
+
+/// A texture owned by a [`Renderer3d`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TextureId(usize);
+
+/// A [`Material`] owned by a [`Renderer3d`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MaterialId(usize);
+
+/// A [`MaterialInstance`] owned by a [`Renderer3d`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MaterialInstanceId(usize);
+
+/// A cube map owned by a [`Renderer3d`], drawn behind the scene with `set_environment`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CubemapId(usize);
+
+/// An [`LodGroupDesc`] registered with a [`Renderer3d`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LodGroupId(usize);
+
+/// A [`ParticleEmitter`] owned by a [`Renderer3d`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EmitterId(usize);
+
+/// How a [`Renderer3d`] shades its meshes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RenderPath {
+    /// Every material shader loops over the lights of its fragment's cluster.
+    #[default]
+    Forward,
+    /// Materials with a G-buffer shader write their surfaces into a G-buffer, which a fullscreen pass then shades
+    /// once per pixel. Scales to many more lights, but only supports opaque materials without MSAA; everything
+    /// else is still drawn forward on top.
+    Deferred,
+}
+
+/// How a [`Renderer3d`] issues its draws.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DrawSubmission {
+    /// An instanced draw per batch, recorded on the CPU.
+    #[default]
+    Direct,
+    /// The frame's objects are uploaded to GPU buffers, where a compute pass culls them against the camera's
+    /// frustum and writes their draw commands, leaving a single `vkCmdDrawIndexedIndirectCount` per material
+    /// instance and pass. Needs the `drawIndirectCount` and `drawIndirectFirstInstance` features.
+    Indirect,
+    /// A mesh task draw per batch, whose task shader culls each instance's meshlets against the camera's frustum
+    /// and by their normal cones before the mesh shader draws them. Needs the `taskShader` and `meshShader`
+    /// features. Materials with a vertex shader other than the one of [`lit_material`] still draw vertices.
+    Meshlets,
+}
+
+/// The description of the material every renderer starts with: a `base_color` multiplying a
+/// `base_color_texture`, lit by the directional light and the frame's clustered lights.
+pub fn lit_material() -> MaterialDesc {
+    MaterialDesc::new("lit", MESH_VERT, &with_lod_dither(&with_lighting(MESH_FRAG)))
+        .with_gbuffer_shader(&with_lod_dither(MESH_GBUFFER_FRAG))
+        .with_skinned_shader(SKINNED_MESH_VERT)
+        .with_bindless()
+        .color("base_color", [1.0; 4])
+        .texture("base_color_texture")
+}
+
+/// A metallic-roughness material with the factors and texture slots of a glTF material, shaded with a
+/// Cook-Torrance BRDF. Factors multiply their textures and default to what glTF specifies. Normal,
+/// metallic-roughness and occlusion maps hold data rather than colors, so create them with
+/// `Renderer3d::create_linear_texture`.
+pub fn pbr_material() -> MaterialDesc {
+    MaterialDesc::new("pbr", MESH_VERT, &with_lod_dither(&with_lighting(PBR_FRAG)))
+        .with_gbuffer_shader(&with_lod_dither(&insert_after_version(PBR_FRAG, "#define GBUFFER")))
+        .with_skinned_shader(SKINNED_MESH_VERT)
+        .with_bindless()
+        .color("base_color", [1.0; 4])
+        .vec3("emissive", [0.0; 3])
+        .float("metallic", 1.0)
+        .float("roughness", 1.0)
+        .float("normal_scale", 1.0)
+        .float("occlusion_strength", 1.0)
+        .texture("base_color_texture")
+        .texture_with_default("normal_texture", DefaultTexture::FlatNormal)
+        .texture("metallic_roughness_texture")
+        .texture("occlusion_texture")
+        .texture("emissive_texture")
+}
+
+struct StoredMesh {
+    name: String,
+    /// Kept to upload the mesh again when the renderer moves to a new device.
+    data: MeshData,
+    /// Split when the mesh is created, for [`DrawSubmission::Meshlets`].
+    meshlets: MeshletData,
+    mesh: Mesh,
+}
+
+struct StoredSkinnedMesh {
+    name: String,
+    data: SkinnedMeshData,
+    skeleton: Skeleton,
+    mesh: SkinnedMesh,
+}
+
+struct StoredTexture {
+    name: String,
+    width: u32,
+    height: u32,
+    format: vk::Format,
+    sampler: SamplerDesc,
+    pixels: Vec<u8>,
+    texture: Texture,
+    /// Where the texture is in the frame set's texture array, with bindless textures.
+    handle: Option<TextureHandle>,
+}
+
+struct StoredCubemap {
+    name: String,
+    source: CubemapSource,
+    texture: Texture,
+    set: vk::DescriptorSet,
+}
+
+struct StoredInstance {
+    material: MaterialId,
+    /// Which of its material's instances it is, which draws of bindless materials push.
+    record: u32,
+    /// What `set_texture` put in each slot, to put it back after the instance moves to a new device.
+    textures: Vec<Option<TextureId>>,
+    instance: MaterialInstance,
+}
+
+/// Which of the queued draws `record_draws` records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DrawPass {
+    Gbuffer,
+    ForwardOpaque,
+    ForwardTransparent,
+}
+
+struct DrawCommand {
+    material: MaterialId,
+    instance: MaterialInstanceId,
+    mesh: MeshId,
+    data: InstanceData,
+    /// Replaced by the draws of the levels `prepare` picks.
+    lod: Option<LodGroupId>,
+    /// Cleared by frustum culling. Culled draws are only kept to cast shadows.
+    visible: bool,
+}
+
+impl DrawCommand {
+    fn key(&self) -> (MaterialId, MaterialInstanceId, MeshId) {
+        (self.material, self.instance, self.mesh)
+    }
+
+    /// Visible draws first, so culled shadow casters don't split their batches.
+    fn sort_key(&self) -> (bool, MaterialId, MaterialInstanceId, MeshId) {
+        (!self.visible, self.material, self.instance, self.mesh)
+    }
+}
+
+/// A skinned mesh posed by the joints from `first_joint` on in the frame's palette buffer, and morphed by the
+/// weights from `first_weight` on unless it has no weights.
+struct SkinnedDraw {
+    material: MaterialId,
+    instance: MaterialInstanceId,
+    mesh: SkinnedMeshId,
+    model: [[f32; 4]; 4],
+    first_joint: u32,
+    first_weight: u32,
+    morphed: bool,
+}
+
+impl SkinnedDraw {
+    fn key(&self) -> (MaterialId, MaterialInstanceId, SkinnedMeshId) {
+        (self.material, self.instance, self.mesh)
+    }
+}
+
+/// Consecutive instances of the sorted draw queue that share a mesh and a material instance, drawn with one call.
+struct DrawBatch {
+    material: MaterialId,
+    instance: MaterialInstanceId,
+    mesh: MeshId,
+    first_instance: u32,
+    instance_count: u32,
+    visible: bool,
+}
+
+/// Consecutive visible instances of the sorted draw queue that share a material instance, whatever their mesh. With
+/// [`DrawSubmission::Indirect`], the culling pass writes their commands from `first_command` on.
+struct DrawGroup {
+    material: MaterialId,
+    instance: MaterialInstanceId,
+    first_command: u32,
+    max_count: u32,
+}
+
+/// One draw call of `record_draws`.
+enum DrawCall<'r> {
+    Batch(&'r DrawBatch),
+    Group(&'r IndirectCulling, u32, &'r DrawGroup),
+}
+
+impl DrawCall<'_> {
+    fn key(&self) -> (MaterialId, MaterialInstanceId) {
+        match self {
+            Self::Batch(batch) => (batch.material, batch.instance),
+            Self::Group(_, _, group) => (group.material, group.instance),
+        }
+    }
+}
+
+/// The resolve pass of the deferred path: a fullscreen triangle shading the G-buffer, which it reads from set 1.
+struct DeferredLighting {
+    gbuffer_target: PipelineTarget,
+    pipeline: GraphicsPipeline,
+    ssao: Ssao,
+    /// `None` when the device can't trace rays.
+    #[cfg(feature = "ray-tracing")]
+    ray_traced: Option<RayTracedAo>,
+    /// Written every frame, since the G-buffer is a transient of the render graph.
+    sets: Vec<vk::DescriptorSet>,
+}
+
+impl DeferredLighting {
+    /// `None` when `target` can't host the deferred path: it needs dynamic rendering into single sampled
+    /// attachments with depth, for one view.
+    unsafe fn new(
+        device: &Arc<Device>,
+        layouts: &mut DescriptorLayoutCache,
+        allocator: &mut DescriptorAllocator,
+        compiler: &GlslCompiler,
+        target: &PipelineTarget,
+        frame_layout: vk::DescriptorSetLayout,
+        frames_in_flight: usize,
+    ) -> anyhow::Result<Option<Self>> {
+        let PipelineTarget::Dynamic(formats) = target else {
+            return Ok(None);
+        };
+
+        if formats.samples != vk::SampleCountFlags::TYPE_1 || formats.depth_format.is_none() || formats.view_mask != 0 {
+            return Ok(None);
+        }
+
+        let vertex = ShaderModule::from_bytes_with_stage(
+            device,
+            "fullscreen.vert",
+            &compiler.compile_source(FULLSCREEN_VERT, vk::ShaderStageFlags::VERTEX, "fullscreen.vert")?,
+            vk::ShaderStageFlags::VERTEX,
+        )?;
+        let fragment = ShaderModule::from_bytes_with_stage(
+            device,
+            "deferred_lighting.frag",
+            &compiler.compile_source(&with_lighting(DEFERRED_LIGHTING_FRAG), vk::ShaderStageFlags::FRAGMENT, "deferred_lighting.frag")?,
+            vk::ShaderStageFlags::FRAGMENT,
+        )?;
+
+        let gbuffer_layout = layouts.get(&gbuffer_set_layout())?;
+        let pipeline = GraphicsPipelineBuilder::new()
+            .shader(&vertex)
+            .shader(&fragment)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .depth(DepthState::DISABLED)
+            .blend(BlendMode::Opaque)
+            .descriptor_set_layout(frame_layout)
+            .descriptor_set_layout(gbuffer_layout)
+            .target(PipelineTarget::Dynamic(RenderingFormats::new(&formats.color_formats, None)))
+            .build(device)?;
+
+        let sets = (0..frames_in_flight)
+            .map(|_| allocator.allocate(gbuffer_layout))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Some(Self {
+            gbuffer_target: PipelineTarget::Dynamic(RenderingFormats::new(&GBUFFER_FORMATS, formats.depth_format)),
+            pipeline,
+            ssao: Ssao::new(device, layouts, allocator, compiler, frame_layout, frames_in_flight)?,
+            #[cfg(feature = "ray-tracing")]
+            ray_traced: RayTracedAo::new(device, layouts, compiler, frames_in_flight)?,
+            sets,
+        }))
+    }
+}
+
+/// Draws meshes into the main pass. Meshes, textures, materials and their instances are created up front; every
+/// frame, `draw` and `draw_instanced` queue instances of a mesh with a material instance, and the queue is recorded
+/// sorted by material, instance and mesh so each is only bound once and every run of the same mesh and material
+/// instance is one instanced draw. With [`DrawSubmission::Indirect`], the GPU culls and writes the draws instead, and
+/// with [`DrawSubmission::Meshlets`], task shaders cull each instance's meshlets.
+/// With [`RenderPath::Deferred`], materials that support it are drawn by the passes from `add_deferred_passes`
+/// instead, which can also darken their ambient light with screen-space ambient occlusion, or with the `ray-tracing`
+/// feature, ambient occlusion traced against the scene.
+///
+/// Material shaders get the [`CameraUniform`], the light clusters and the shadow maps in set 0 (see
+/// `lighting::with_lighting`) and each instance's [`InstanceData`] as vertex attributes; their own parameters and
+/// textures are in set 1. On devices with the descriptor indexing features, every texture is also in one array in
+/// set 0, which bindless materials such as the stock ones sample by handle, binding one set for all their instances
+/// rather than one per instance. Opaque materials cast shadows. The cube map set with `set_environment` fills the pixels
+/// nothing was drawn to. Particle emitters are simulated by `prepare` and drawn after the transparent meshes, and
+/// the lines queued on [`debug_draw_mut`](Self::debug_draw_mut) after everything else.
+///
+/// Skinned meshes are queued one at a time with `draw_skinned` and a pose, or `draw_morphed` with morph target
+/// weights as well, and always drawn forward after the static meshes of the same blend mode. They aren't culled and
+/// don't cast shadows.
+///
+/// `set_debug_view` swaps the lit scene for a [`DebugView`], e.g. normals or overdraw.
+///
+/// `set_viewports` draws the scene into several parts of the target, each through its own camera, for split-screen.
+/// The first viewport's camera becomes the renderer's, which LOD selection and the shadow cascades follow; the
+/// others get their own camera uniform and light clusters. Split frames are always drawn forward with direct draws.
+pub struct Renderer3d {
+    device: Arc<Device>,
+    layouts: DescriptorLayoutCache,
+    descriptor_allocator: DescriptorAllocator,
+    frame_layout: vk::DescriptorSetLayout,
+    camera_uniform: PerFrameUniform<CameraUniform>,
+    frame_sets: Vec<vk::DescriptorSet>,
+    camera: Camera,
+    /// Of the eyes a multiview target is drawn with.
+    eye_separation: f32,
+    /// Empty to draw `camera` over the whole target.
+    viewports: Vec<Viewport>,
+    /// One for every viewport after the first, which uses the renderer's own camera uniform and frame sets.
+    split_views: Vec<SplitView>,
+    /// The target size `prepare` was given, to restore the viewport after drawing split views.
+    extent: vk::Extent2D,
+    lighting: LightCulling,
+    lights: Vec<Light>,
+    directional_light: DirectionalLight,
+    shadows: ShadowMaps,
+    meshes: Vec<StoredMesh>,
+    skinned_meshes: Vec<StoredSkinnedMesh>,
+    textures: Vec<StoredTexture>,
+    /// The frame set's texture array, `None` on devices without the descriptor indexing features, where instances
+    /// bind their textures in sets of their own.
+    bindless: Option<BindlessTextures>,
+    materials: Vec<Material>,
+    instances: Vec<StoredInstance>,
+    cubemaps: Vec<StoredCubemap>,
+    skybox: Skybox,
+    environment: Option<CubemapId>,
+    particles: ParticleSystem,
+    emitters: Vec<ParticleEmitter>,
+    debug_draw: DebugDraw,
+    debug_view: DebugView,
+    debug_view_pipelines: DebugViewPipelines,
+    lod_groups: Vec<LodGroupDesc>,
+    draws: Vec<DrawCommand>,
+    skinned_draws: Vec<SkinnedDraw>,
+    joint_palettes: PerFrameStorage<[[f32; 4]; 4]>,
+    morph_weights: PerFrameStorage<f32>,
+    /// Rebuilt by `prepare` whenever skinned meshes were added.
+    morph_deltas: MorphDeltas,
+    batches: Vec<DrawBatch>,
+    groups: Vec<DrawGroup>,
+    /// One per frame in flight, holding the instances of the frame's batches.
+    instance_buffers: Vec<Buffer>,
+    draw_submission: DrawSubmission,
+    frustum_culling: bool,
+    culling_stats: CullingStats,
+    /// `None` when the device can't draw indirectly with a count.
+    indirect: Option<IndirectCulling>,
+    /// `None` without indirect draws or when the target's depth can't be sampled.
+    occlusion: Option<HiZPyramid>,
+    occlusion_culling: bool,
+    /// Every mesh in one pair of buffers for indirect draws, built on first use and whenever meshes were added.
+    mesh_arena: Option<MeshArena>,
+    /// `None` when the device has no task and mesh shaders.
+    mesh_shading: Option<MeshShading>,
+    /// Every mesh's meshlets in one set of buffers, built on first use and whenever meshes were added.
+    meshlet_arena: Option<MeshletArena>,
+    render_path: RenderPath,
+    ambient_occlusion: Option<SsaoQuality>,
+    #[cfg(feature = "ray-tracing")]
+    ray_traced_ambient_occlusion: bool,
+    /// `None` when the target doesn't support the deferred path.
+    deferred: Option<DeferredLighting>,
+    target: PipelineTarget,
+    white: TextureId,
+    black: TextureId,
+    flat_normal: TextureId,
+}
+
+impl Renderer3d {
+    pub unsafe fn new(
+        device: &Arc<Device>,
+        target: &PipelineTarget,
+        compiler: &GlslCompiler,
+        frames_in_flight: usize,
+    ) -> anyhow::Result<Self> {
+        let mut layouts = DescriptorLayoutCache::new(device);
+        let mut descriptor_allocator = DescriptorAllocator::new(device);
+        let (frame_layout, bindless) = create_frame_layout(device, &mut layouts, frames_in_flight)?;
+        let mesh_shading = MeshShading::new(device, &mut layouts, &mut descriptor_allocator, compiler, frames_in_flight)?;
+        let deferred = DeferredLighting::new(
+            device,
+            &mut layouts,
+            &mut descriptor_allocator,
+            compiler,
+            target,
+            frame_layout,
+            frames_in_flight,
+        )?;
+        let skybox = Skybox::new(device, &mut layouts, compiler, target, frame_layout)?;
+        let particles = ParticleSystem::new(device, &mut layouts, compiler, target, frame_layout)?;
+        let debug_draw = DebugDraw::new(device, compiler, target, frame_layout, frames_in_flight)?;
+
+        let mut renderer = Self {
+            device: device.clone(),
+            layouts,
+            descriptor_allocator,
+            frame_layout,
+            camera_uniform: PerFrameUniform::new(device, "camera", frames_in_flight)?,
+            frame_sets: Vec::new(),
+            camera: Camera::default(),
+            eye_separation: DEFAULT_EYE_SEPARATION,
+            viewports: Vec::new(),
+            split_views: Vec::new(),
+            extent: vk::Extent2D::default(),
+            lighting: LightCulling::new(device, compiler, frames_in_flight)?,
+            lights: Vec::new(),
+            directional_light: DirectionalLight::default(),
+            shadows: ShadowMaps::new(device, compiler, target, ShadowQuality::default(), frames_in_flight)?,
+            meshes: Vec::new(),
+            skinned_meshes: Vec::new(),
+            textures: Vec::new(),
+            bindless,
+            materials: Vec::new(),
+            instances: Vec::new(),
+            cubemaps: Vec::new(),
+            skybox,
+            environment: None,
+            particles,
+            emitters: Vec::new(),
+            debug_draw,
+            debug_view: DebugView::Lit,
+            debug_view_pipelines: DebugViewPipelines::new(device, compiler, mesh_pipeline_base(frame_layout, target.clone()), &mesh_vertex_shader(target))?,
+            lod_groups: Vec::new(),
+            draws: Vec::new(),
+            skinned_draws: Vec::new(),
+            joint_palettes: PerFrameStorage::new(device, "joint palette", INITIAL_PALETTE_CAPACITY, frames_in_flight)?,
+            morph_weights: PerFrameStorage::new(device, "morph weights", INITIAL_MORPH_WEIGHT_CAPACITY, frames_in_flight)?,
+            morph_deltas: MorphDeltas::new(device, [])?,
+            batches: Vec::new(),
+            groups: Vec::new(),
+            instance_buffers: create_instance_buffers(device, frames_in_flight)?,
+            draw_submission: DrawSubmission::Direct,
+            frustum_culling: true,
+            culling_stats: CullingStats::default(),
+            indirect: create_indirect_culling(device, compiler, frames_in_flight)?,
+            occlusion: None,
+            occlusion_culling: true,
+            mesh_arena: None,
+            mesh_shading,
+            meshlet_arena: None,
+            render_path: RenderPath::Forward,
+            ambient_occlusion: None,
+            #[cfg(feature = "ray-tracing")]
+            ray_traced_ambient_occlusion: false,
+            deferred,
+            target: target.clone(),
+            white: TextureId(0),
+            black: TextureId(0),
+            flat_normal: TextureId(0),
+        };
+
+        renderer.occlusion = create_occlusion(device, compiler, target, renderer.indirect.is_some(), frames_in_flight)?;
+        renderer.allocate_frame_sets(frames_in_flight)?;
+        renderer.white = renderer.create_texture("white", 1, 1, &[255; 4], SamplerDesc::nearest())?;
+        renderer.black = renderer.create_texture("black", 1, 1, &[0, 0, 0, 255], SamplerDesc::nearest())?;
+        renderer.flat_normal = renderer.create_linear_texture("flat normal", 1, 1, &[128, 128, 255, 255], SamplerDesc::nearest())?;
+        renderer.create_material(compiler, lit_material())?;
+        renderer.create_material(compiler, pbr_material())?;
+        Ok(renderer)
+    }
+
+    pub unsafe fn create_mesh(&mut self, name: &str, data: MeshData) -> anyhow::Result<MeshId> {
+        let mesh = Mesh::new(&self.device, name, &data)?;
+        let positions: Vec<[f32; 3]> = data.vertices.iter().map(|vertex| vertex.position).collect();
+        self.meshes.push(StoredMesh {
+            name: name.to_owned(),
+            meshlets: MeshletData::build(&positions, &data.indices),
+            data,
+            mesh,
+        });
+
+        Ok(MeshId(self.meshes.len() - 1))
+    }
+
+    /// Fails when vertices are weighted to joints `skeleton` doesn't have. The next `prepare` uploads the morph
+    /// targets of every skinned mesh again, which waits for the device to go idle.
+    pub unsafe fn create_skinned_mesh(&mut self, name: &str, data: SkinnedMeshData, skeleton: Skeleton) -> anyhow::Result<SkinnedMeshId> {
+        if data.joint_count() > skeleton.joint_count() {
+            return Err(anyhow!(
+                "Skinned mesh '{}' is weighted to {} joints, but its skeleton has {}",
+                name,
+                data.joint_count(),
+                skeleton.joint_count(),
+            ));
+        }
+
+        let mesh = SkinnedMesh::new(&self.device, name, &data)?;
+        self.skinned_meshes.push(StoredSkinnedMesh {
+            name: name.to_owned(),
+            data,
+            skeleton,
+            mesh,
+        });
+
+        Ok(SkinnedMeshId(self.skinned_meshes.len() - 1))
+    }
+
+    pub fn skinned_mesh(&self, mesh: SkinnedMeshId) -> &SkinnedMesh {
+        &self.skinned_meshes[mesh.0].mesh
+    }
+
+    /// What poses passed to `draw_skinned` for `mesh` are made of.
+    pub fn skeleton(&self, mesh: SkinnedMeshId) -> &Skeleton {
+        &self.skinned_meshes[mesh.0].skeleton
+    }
+
+    /// Uploads an sRGB RGBA8 texture of `width * height * 4` bytes with a full mip chain.
+    pub unsafe fn create_texture(
+        &mut self,
+        name: &str,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+        sampler: SamplerDesc,
+    ) -> anyhow::Result<TextureId> {
+        self.store_texture(name, width, height, vk::Format::R8G8B8A8_SRGB, pixels, sampler)
+    }
+
+    /// Like [`create_texture`](Self::create_texture), for data such as normals or roughness that is read as is
+    /// instead of being decoded from sRGB.
+    pub unsafe fn create_linear_texture(
+        &mut self,
+        name: &str,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+        sampler: SamplerDesc,
+    ) -> anyhow::Result<TextureId> {
+        self.store_texture(name, width, height, vk::Format::R8G8B8A8_UNORM, pixels, sampler)
+    }
+
+    unsafe fn store_texture(
+        &mut self,
+        name: &str,
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        pixels: &[u8],
+        sampler: SamplerDesc,
+    ) -> anyhow::Result<TextureId> {
+        if pixels.len() != width as usize * height as usize * 4 {
+            return Err(anyhow!("Texture '{}' expects {} bytes of RGBA8, got {}", name, width * height * 4, pixels.len()));
+        }
+
+        let desc = ImageDesc::new_2d(width, height, format, vk::ImageUsageFlags::SAMPLED);
+        let texture = Texture::from_pixels(&self.device, name, desc, pixels, true)?;
+        let handle = match &mut self.bindless {
+            Some(bindless) => Some(bindless.insert(texture.view(), self.device.sampler(&sampler)?)?),
+            None => None,
+        };
+
+        self.textures.push(StoredTexture {
+            name: name.to_owned(),
+            width,
+            height,
+            format,
+            sampler,
+            pixels: pixels.to_vec(),
+            texture,
+            handle,
+        });
+
+        Ok(TextureId(self.textures.len() - 1))
+    }
+
+    pub unsafe fn create_material(&mut self, compiler: &GlslCompiler, mut desc: MaterialDesc) -> anyhow::Result<MaterialId> {
+        let stock_vertex_shader = desc.vertex_shader == MESH_VERT;
+        let multiview = self.target.view_mask() != 0;
+        if stock_vertex_shader {
+            desc.vertex_shader = mesh_vertex_shader(&self.target);
+        }
+
+        let base = self.pipeline_base(self.target.clone());
+        let gbuffer_base = self.deferred.as_ref().map(|deferred| self.pipeline_base(deferred.gbuffer_target.clone()));
+        let skinned_base = GraphicsPipelineBuilder::new()
+            .vertex::<SkinnedVertex>(0)
+            .push_constants::<SkinPushConstants>(vk::ShaderStageFlags::VERTEX, 0)
+            .descriptor_set_layout(self.frame_layout)
+            .target(self.target.clone());
+        // The mesh shader only stands in for the stock vertex shader, and not for multiview, which it would need
+        // `multiviewMeshShader` for.
+        let meshlet_bases = self.mesh_shading.as_ref()
+            .filter(|_| stock_vertex_shader && !multiview)
+            .map(|mesh_shading| (
+                mesh_shading.pipeline_base(self.frame_layout, self.target.clone()),
+                self.deferred.as_ref().map(|deferred| mesh_shading.pipeline_base(self.frame_layout, deferred.gbuffer_target.clone())),
+                mesh_shading.set_layout(),
+            ));
+
+        let mut material = Material::new(&self.device, &mut self.layouts, compiler, desc, base, gbuffer_base, Some(skinned_base))?;
+        if let Some((base, gbuffer_base, meshlet_layout)) = meshlet_bases {
+            material = material.with_meshlet_pipelines(&self.device, compiler, base, gbuffer_base, meshlet_layout)?;
+        }
+
+        let material = material.with_records(&self.device, &mut self.descriptor_allocator, self.frame_sets.len())?;
+        self.materials.push(material);
+        Ok(MaterialId(self.materials.len() - 1))
+    }
+
+    /// The material from [`lit_material`].
+    pub fn default_material(&self) -> MaterialId {
+        MaterialId(0)
+    }
+
+    /// The material from [`pbr_material`].
+    pub fn pbr_material(&self) -> MaterialId {
+        MaterialId(1)
+    }
+
+    pub fn material(&self, material: MaterialId) -> &Material {
+        &self.materials[material.0]
+    }
+
+    /// An instance of `material` with default parameters and the default texture of every slot.
+    pub unsafe fn create_instance(&mut self, material: MaterialId) -> anyhow::Result<MaterialInstanceId> {
+        let white = self.texture_binding(self.white)?;
+        let stored_material = self.materials.get(material.0).ok_or(anyhow!("Unknown material {:?}", material))?;
+        let instance = MaterialInstance::new(
+            &self.device,
+            stored_material,
+            &mut self.descriptor_allocator,
+            self.frame_sets.len(),
+            white,
+        )?;
+
+        let record = self.instances.iter().filter(|stored| stored.material == material).count() as u32;
+        self.instances.push(StoredInstance {
+            material,
+            record,
+            textures: vec![None; stored_material.desc().texture_slots().len()],
+            instance,
+        });
+
+        let instance = MaterialInstanceId(self.instances.len() - 1);
+        self.apply_default_textures(instance)?;
+        Ok(instance)
+    }
+
+    pub fn instance(&self, instance: MaterialInstanceId) -> &MaterialInstance {
+        &self.instances[instance.0].instance
+    }
+
+    /// For `set_param`. Textures go through [`set_texture`](Self::set_texture) instead, so they can be restored
+    /// after a device loss.
+    pub fn instance_mut(&mut self, instance: MaterialInstanceId) -> &mut MaterialInstance {
+        &mut self.instances[instance.0].instance
+    }
+
+    pub fn set_texture(&mut self, instance: MaterialInstanceId, slot: &str, texture: TextureId) -> anyhow::Result<()> {
+        unsafe { self.put_texture(instance, slot, texture)? };
+        let stored = &mut self.instances[instance.0];
+        let index = self.materials[stored.material.0].desc().texture_slots().iter()
+            .position(|name| name == slot)
+            .expect("set_texture checked the slot");
+        stored.textures[index] = Some(texture);
+        Ok(())
+    }
+
+    /// Where `texture` is in set 0's texture array, `None` without bindless textures.
+    pub fn texture_handle(&self, texture: TextureId) -> Option<TextureHandle> {
+        self.textures.get(texture.0)?.handle
+    }
+
+    /// A 1x1 white texture.
+    pub fn white_texture(&self) -> TextureId {
+        self.white
+    }
+
+    pub fn default_texture(&self, default: DefaultTexture) -> TextureId {
+        match default {
+            DefaultTexture::White => self.white,
+            DefaultTexture::Black => self.black,
+            DefaultTexture::FlatNormal => self.flat_normal,
+        }
+    }
+
+    /// Loads a cube map, converting equirectangular panoramas on the GPU, which waits for the device to go idle.
+    pub unsafe fn create_cubemap(&mut self, compiler: &GlslCompiler, name: &str, source: CubemapSource) -> anyhow::Result<CubemapId> {
+        let texture = source.load(&self.device, compiler, name)?;
+        let set = self.skybox.create_set(&self.device, &mut self.descriptor_allocator, &texture)?;
+        self.cubemaps.push(StoredCubemap {
+            name: name.to_owned(),
+            source,
+            texture,
+            set,
+        });
+
+        Ok(CubemapId(self.cubemaps.len() - 1))
+    }
+
+    pub fn cubemap(&self, cubemap: CubemapId) -> &Texture {
+        &self.cubemaps[cubemap.0].texture
+    }
+
+    /// Draws `environment` as the skybox from the next frame on, or nothing behind the scene with `None`.
+    pub fn set_environment(&mut self, environment: Option<CubemapId>) {
+        self.environment = environment;
+    }
+
+    pub fn environment(&self) -> Option<CubemapId> {
+        self.environment
+    }
+
+    /// An emitter at the origin, emitting from the next frame on.
+    pub unsafe fn create_emitter(&mut self, desc: EmitterDesc) -> anyhow::Result<EmitterId> {
+        let emitter = ParticleEmitter::new(&self.device, &self.particles, &mut self.descriptor_allocator, desc, self.frame_sets.len())?;
+        self.emitters.push(emitter);
+        Ok(EmitterId(self.emitters.len() - 1))
+    }
+
+    /// Instances drawn with `draw_lod` are bounded by the first level's mesh.
+    pub fn create_lod_group(&mut self, desc: LodGroupDesc) -> anyhow::Result<LodGroupId> {
+        desc.validate()?;
+        if let Some(level) = desc.levels.iter().find(|level| level.mesh.0 >= self.meshes.len()) {
+            return Err(anyhow!("Unknown mesh {:?} in LOD group", level.mesh));
+        }
+
+        self.lod_groups.push(desc);
+        Ok(LodGroupId(self.lod_groups.len() - 1))
+    }
+
+    pub fn lod_group(&self, lod: LodGroupId) -> &LodGroupDesc {
+        &self.lod_groups[lod.0]
+    }
+
+    pub fn emitter(&self, emitter: EmitterId) -> &ParticleEmitter {
+        &self.emitters[emitter.0]
+    }
+
+    /// For moving the emitter, toggling it and bursts.
+    pub fn emitter_mut(&mut self, emitter: EmitterId) -> &mut ParticleEmitter {
+        &mut self.emitters[emitter.0]
+    }
+
+    pub fn debug_draw(&self) -> &DebugDraw {
+        &self.debug_draw
+    }
+
+    /// For queuing debug lines and labels this frame.
+    pub fn debug_draw_mut(&mut self) -> &mut DebugDraw {
+        &mut self.debug_draw
+    }
+
+    pub fn mesh(&self, mesh: MeshId) -> &Mesh {
+        &self.meshes[mesh.0].mesh
+    }
+
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    pub fn camera_mut(&mut self) -> &mut Camera {
+        &mut self.camera
+    }
+
+    pub fn set_camera(&mut self, camera: Camera) {
+        self.camera = camera;
+    }
+
+    /// How far apart [`Camera::eyes`] places the two views of a multiview target.
+    pub fn set_eye_separation(&mut self, separation: f32) {
+        self.eye_separation = separation;
+    }
+
+    /// Draws the scene into each of `viewports` from the next frame on, or over the whole target with the
+    /// renderer's camera when empty. The first viewport's camera replaces the renderer's.
+    pub unsafe fn set_viewports(&mut self, compiler: &GlslCompiler, viewports: Vec<Viewport>) -> anyhow::Result<()> {
+        let frames_in_flight = self.frame_sets.len();
+        while self.split_views.len() + 1 < viewports.len() {
+            let view = SplitView {
+                camera_uniform: PerFrameUniform::new(&self.device, "split view camera", frames_in_flight)?,
+                lighting: LightCulling::new(&self.device, compiler, frames_in_flight)?,
+                frame_sets: self.allocate_frame_set_handles(frames_in_flight)?,
+            };
+
+            for frame_index in 0..frames_in_flight {
+                self.write_frame_set_for(view.frame_sets[frame_index], frame_index, &view.camera_uniform, &view.lighting)?;
+            }
+            self.split_views.push(view);
+        }
+
+        if let Some(first) = viewports.first() {
+            self.camera = first.camera;
+        }
+
+        self.viewports = viewports;
+        Ok(())
+    }
+
+    pub fn viewports(&self) -> &[Viewport] {
+        &self.viewports
+    }
+
+    /// Queues `mesh` for this frame, placed in the world by `transform`.
+    pub fn draw(&mut self, mesh: MeshId, instance: MaterialInstanceId, transform: Matrix4<f32>) {
+        self.draw_instanced(mesh, instance, &[InstanceData::new(transform)]);
+    }
+
+    /// Queues an instance of `mesh` for every element of `instances` this frame.
+    pub fn draw_instanced(&mut self, mesh: MeshId, instance: MaterialInstanceId, instances: &[InstanceData]) {
+        let material = self.instances[instance.0].material;
+        self.draws.extend(instances.iter().map(|&data| DrawCommand {
+            material,
+            instance,
+            mesh,
+            data,
+            lod: None,
+            visible: true,
+        }));
+    }
+
+    /// Queues `lod`, placed in the world by `transform`, with the levels `prepare` picks for it.
+    pub fn draw_lod(&mut self, lod: LodGroupId, instance: MaterialInstanceId, transform: Matrix4<f32>) {
+        self.draw_lod_instanced(lod, instance, &[InstanceData::new(transform)]);
+    }
+
+    /// Queues an instance of `lod` for every element of `instances`, each with its own levels.
+    pub fn draw_lod_instanced(&mut self, lod: LodGroupId, instance: MaterialInstanceId, instances: &[InstanceData]) {
+        let material = self.instances[instance.0].material;
+        let mesh = self.lod_groups[lod.0].levels[0].mesh;
+        self.draws.extend(instances.iter().map(|&data| DrawCommand {
+            material,
+            instance,
+            mesh,
+            data,
+            lod: Some(lod),
+            visible: true,
+        }));
+    }
+
+    /// Queues `mesh` for this frame in `pose`, one local transform per joint of its skeleton, e.g. from
+    /// `AnimationPlayer::pose`, with its morph targets left out. Fails when `instance`'s material has no skinned
+    /// shader or the pose doesn't fit.
+    pub fn draw_skinned(&mut self, mesh: SkinnedMeshId, instance: MaterialInstanceId, transform: Matrix4<f32>, pose: &[Transform]) -> anyhow::Result<()> {
+        self.draw_morphed(mesh, instance, transform, pose, &[])
+    }
+
+    /// Like [`draw_skinned`](Self::draw_skinned), blending in each morph target of `mesh` by its weight in
+    /// `weights`, e.g. from `AnimationPlayer::weights`. Empty weights leave the mesh unmorphed.
+    pub fn draw_morphed(
+        &mut self,
+        mesh: SkinnedMeshId,
+        instance: MaterialInstanceId,
+        transform: Matrix4<f32>,
+        pose: &[Transform],
+        weights: &[f32],
+    ) -> anyhow::Result<()> {
+        let material = self.instances[instance.0].material;
+        if self.materials[material.0].skinned_pipeline().is_none() {
+            return Err(anyhow!("Material '{}' can't draw skinned meshes", self.materials[material.0].name()));
+        }
+
+        let stored = self.skinned_meshes.get(mesh.0).ok_or(anyhow!("Unknown skinned mesh {:?}", mesh))?;
+        let morph_target_count = stored.mesh.morph_target_count() as usize;
+        if !weights.is_empty() && weights.len() != morph_target_count {
+            return Err(anyhow!("Skinned mesh '{}' has {} morph targets, got {} weights", stored.name, morph_target_count, weights.len()));
+        }
+
+        let first_joint = self.joint_palettes.values_mut().len() as u32;
+        stored.skeleton.write_palette(pose, self.joint_palettes.values_mut())?;
+        let first_weight = self.morph_weights.values_mut().len() as u32;
+        self.morph_weights.values_mut().extend_from_slice(weights);
+        self.skinned_draws.push(SkinnedDraw {
+            material,
+            instance,
+            mesh,
+            model: transform.into(),
+            first_joint,
+            first_weight,
+            morphed: !weights.is_empty(),
+        });
+
+        Ok(())
+    }
+
+    /// Instances queued this frame.
+    pub fn queued_draws(&self) -> usize {
+        self.draws.len()
+    }
+
+    /// Draw calls `prepare` batched this frame's instances into, including the ones only drawn into shadow maps.
+    pub fn draw_batches(&self) -> usize {
+        self.batches.len()
+    }
+
+    pub fn frustum_culling(&self) -> bool {
+        self.frustum_culling
+    }
+
+    /// Whether `prepare` leaves out instances outside the camera's view, on by default.
+    pub fn set_frustum_culling(&mut self, frustum_culling: bool) {
+        self.frustum_culling = frustum_culling;
+    }
+
+    pub fn occlusion_culling(&self) -> bool {
+        self.occlusion_culling
+    }
+
+    /// Whether indirect draws also leave out instances hidden behind what the previous frame drew, on by default.
+    /// Needs `add_occlusion_pass` every frame. Instances that come into view from behind an occluder can show up a
+    /// frame late, as can everything after a camera cut.
+    pub fn set_occlusion_culling(&mut self, occlusion_culling: bool) {
+        self.occlusion_culling = occlusion_culling;
+    }
+
+    /// What culling did in the last `prepare`, e.g. for a debug overlay. Occlusion culling happens on the GPU and
+    /// isn't counted.
+    pub fn culling_stats(&self) -> CullingStats {
+        self.culling_stats
+    }
+
+    /// Lights the meshes drawn this frame.
+    pub fn add_light(&mut self, light: Light) {
+        self.lights.push(light);
+    }
+
+    pub fn queued_lights(&self) -> usize {
+        self.lights.len()
+    }
+
+    pub fn directional_light(&self) -> &DirectionalLight {
+        &self.directional_light
+    }
+
+    pub fn set_directional_light(&mut self, light: DirectionalLight) {
+        self.directional_light = light;
+    }
+
+    pub fn shadow_quality(&self) -> &ShadowQuality {
+        self.shadows.quality()
+    }
+
+    /// Waits for the device to go idle when the resolution changes.
+    pub unsafe fn set_shadow_quality(&mut self, quality: ShadowQuality) -> anyhow::Result<()> {
+        if self.shadows.set_quality(quality)? {
+            for frame_index in 0..self.frame_sets.len() {
+                self.write_frame_set(frame_index)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Takes effect from the next frame. Falls back to forward, with a warning, on targets the deferred path
+    /// doesn't support.
+    pub fn set_render_path(&mut self, render_path: RenderPath) {
+        if render_path == RenderPath::Deferred && self.deferred.is_none() {
+            warn!("The deferred render path needs dynamic rendering without MSAA, drawing forward instead");
+        }
+
+        self.render_path = render_path;
+    }
+
+    /// The path frames are drawn with, which is forward when deferred was asked for but isn't supported.
+    pub fn render_path(&self) -> RenderPath {
+        match self.deferred_lighting() {
+            Some(_) => RenderPath::Deferred,
+            None => RenderPath::Forward,
+        }
+    }
+
+    /// Takes effect from the next frame. `None` turns ambient occlusion off. Only the deferred path has the normals
+    /// it needs before shading, so frames drawn forward aren't occluded.
+    pub fn set_ambient_occlusion(&mut self, quality: Option<SsaoQuality>) {
+        self.ambient_occlusion = quality;
+    }
+
+    pub fn ambient_occlusion(&self) -> Option<SsaoQuality> {
+        self.ambient_occlusion
+    }
+
+    /// Takes effect from the next frame. Traces the ambient occlusion set with `set_ambient_occlusion` against the
+    /// opaque meshes instead of estimating it from the screen, falling back to the estimate, with a warning, on
+    /// devices that can't trace rays. The first frame drawing with it builds an acceleration structure for every
+    /// mesh created so far, as does the first after more were created, which waits for the device each time.
+    #[cfg(feature = "ray-tracing")]
+    pub fn set_ray_traced_ambient_occlusion(&mut self, ray_traced: bool) {
+        if ray_traced && self.deferred.as_ref().and_then(|deferred| deferred.ray_traced.as_ref()).is_none() {
+            warn!("Ray traced ambient occlusion needs the deferred path and the accelerationStructure, rayTracingPipeline and bufferDeviceAddress features, using screen-space ambient occlusion instead");
+        }
+
+        self.ray_traced_ambient_occlusion = ray_traced;
+    }
+
+    /// Whether frames are occluded by tracing rays, which they aren't without ambient occlusion, when drawn forward
+    /// or when the device can't trace rays.
+    #[cfg(feature = "ray-tracing")]
+    pub fn ray_traced_ambient_occlusion(&self) -> bool {
+        self.ray_traced_occlusion().is_some()
+    }
+
+    /// Takes effect from the next frame. Falls back to direct draws, with a warning, on devices without indirect
+    /// count draws, and from meshlets to direct draws on devices without mesh shaders. Indirect and meshlet draws
+    /// copy every mesh into one arena on first use and after meshes were added, which waits for the device to go
+    /// idle.
+    pub fn set_draw_submission(&mut self, draw_submission: DrawSubmission) {
+        if draw_submission == DrawSubmission::Indirect && self.indirect.is_none() {
+            warn!("Indirect draws need the drawIndirectCount and drawIndirectFirstInstance features, drawing directly instead");
+        }
+
+        if draw_submission == DrawSubmission::Meshlets && self.mesh_shading.is_none() {
+            warn!("Meshlet draws need the taskShader and meshShader features, drawing directly instead");
+        }
+
+        self.draw_submission = draw_submission;
+    }
+
+    /// How frames are drawn, which is directly when indirect or meshlet draws were asked for but aren't supported.
+    pub fn draw_submission(&self) -> DrawSubmission {
+        match (self.indirect_culling(), self.mesh_shading()) {
+            (Some(_), _) => DrawSubmission::Indirect,
+            (None, Some(_)) => DrawSubmission::Meshlets,
+            (None, None) => DrawSubmission::Direct,
+        }
+    }
+
+    /// Takes effect from the next frame. The wireframe view draws lit, with a warning, on devices without the
+    /// `fillModeNonSolid` feature.
+    pub fn set_debug_view(&mut self, debug_view: DebugView) {
+        if !self.debug_view_pipelines.supports(debug_view) {
+            warn!("The wireframe debug view needs the fillModeNonSolid feature, drawing lit instead");
+        }
+
+        self.debug_view = debug_view;
+    }
+
+    pub fn debug_view(&self) -> DebugView {
+        self.debug_view
+    }
+
+    /// Picks LOD levels, culls, sorts and batches the queued draws, updates the camera uniform, changed material instances and the shadow uniform of
+    /// `frame_index`, uploads the instances, joint palettes and debug lines, assigns this frame's lights to clusters and simulates the particles. Records compute passes,
+    /// so call it before the passes the renderer draws into begin. Lists only needed while preparing are built in `arena`.
+    /// On a multiview target, `extent` is the size of one view.
+    pub unsafe fn prepare(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        extent: vk::Extent2D,
+        arena: &FrameArena,
+    ) -> anyhow::Result<()> {
+        self.extent = extent;
+        let primary = self.viewports.first().map_or(vk::Rect2D { offset: vk::Offset2D::default(), extent }, |viewport| viewport.rect);
+
+        // The skybox is drawn with the camera even when nothing else is. On a multiview target, each view is seen
+        // from one eye, `extent` being the size of a view.
+        let mut camera_uniform = CameraUniform::new(&self.camera, primary.extent).with_debug_view(self.debug_view);
+        if self.target.view_mask() != 0 {
+            camera_uniform = camera_uniform.with_views(&self.camera.eyes(self.eye_separation), primary.extent);
+        }
+        self.camera_uniform.write(frame_index, &camera_uniform)?;
+        for (view, viewport) in self.split_views.iter_mut().zip(self.viewports.iter().skip(1)) {
+            view.camera_uniform.write(frame_index, &CameraUniform::new(&viewport.camera, viewport.rect.extent).with_debug_view(self.debug_view))?;
+        }
+        self.particles.simulate(command_buffer, frame_index, &mut self.emitters)?;
+        self.debug_draw.prepare(frame_index)?;
+
+        let frustum = Frustum::from_view_projection(self.camera.projection(primary.extent) * self.camera.view());
+        let split_frusta = arena.alloc_from_iter(self.viewports.iter()
+            .skip(1)
+            .map(|viewport| Frustum::from_view_projection(viewport.camera.projection(viewport.rect.extent) * viewport.camera.view())));
+        self.select_lods();
+        self.cull_draws(&frustum, split_frusta)?;
+        self.prepare_occlusion(extent)?;
+
+        if self.draws.is_empty() && self.skinned_draws.is_empty() {
+            self.lights.clear();
+            return Ok(());
+        }
+
+        self.draws.sort_by_key(DrawCommand::sort_key);
+        self.write_batches(frame_index, arena)?;
+        self.record_indirect_culling(command_buffer, frame_index, &frustum)?;
+        self.prepare_meshlets(frame_index)?;
+        self.culling_stats.draw_calls = match self.indirect_culling() {
+            Some(_) => self.groups.len(),
+            None => self.batches.iter().filter(|batch| batch.visible).count(),
+        } + self.skinned_draws.len();
+
+        self.prepare_skinning(frame_index)?;
+        #[cfg(feature = "ray-tracing")]
+        self.prepare_ray_traced_occlusion(command_buffer, frame_index)?;
+
+        // Before the instances, which clear what changed.
+        for (index, material) in self.materials.iter_mut().enumerate() {
+            if let Some(records) = material.records_mut() {
+                let mut instances = arena.vec();
+                for stored in self.instances.iter().filter(|stored| stored.material.0 == index) {
+                    instances.push(&stored.instance);
+                }
+                records.upload(frame_index, &instances)?;
+            }
+        }
+
+        for stored in &mut self.instances {
+            stored.instance.prepare(frame_index)?;
+        }
+
+        let shadow_slots = self.shadows.update(frame_index, &self.camera, primary.extent, &self.directional_light, &self.lights)?;
+        self.lighting.record(command_buffer, frame_index, &self.camera, primary, &self.lights, &shadow_slots)?;
+        for (view, viewport) in self.split_views.iter_mut().zip(self.viewports.iter().skip(1)) {
+            view.lighting.record(command_buffer, frame_index, &viewport.camera, viewport.rect, &self.lights, &shadow_slots)?;
+        }
+        self.lights.clear();
+        Ok(())
+    }
+
+    /// Adds a depth-only pass for every shadow map `prepare` picked this frame and returns their images. Passes
+    /// shading with the renderer's materials must sample all of them in the fragment stage.
+    pub unsafe fn add_shadow_passes<'a>(&'a self, graph: &mut RenderGraph<'a>, frame_index: usize) -> Vec<GraphImage> {
+        let Some(pipeline) = self.shadows.pipeline() else {
+            return Vec::new();
+        };
+
+        if self.draws.is_empty() && self.skinned_draws.is_empty() {
+            return Vec::new();
+        }
+
+        self.shadows.views().iter()
+            .map(|view| {
+                let image = graph.import_image(&format!("shadow map {}", view.layer), self.shadows.imported_layer(view.layer));
+                let view_projection = view.view_projection;
+
+                graph.add_pass(&format!("shadow {}", view.layer))
+                    .depth(image, Some(1.0))
+                    .execute(move |ctx| self.record_shadow_casters(ctx.command_buffer(), pipeline, view_projection, frame_index));
+
+                image
+            })
+            .collect()
+    }
+
+    /// Adds a G-buffer pass, which clears and writes `depth`, the ambient occlusion passes when it's on, and a
+    /// lighting pass shading the G-buffer into `output`, cleared to `clear` first. Returns whether it did: nothing is added when drawing forward or when
+    /// nothing was queued, and the pass `record` draws into then has to clear both itself. `shadow_maps` are the
+    /// images from `add_shadow_passes`.
+    pub unsafe fn add_deferred_passes<'a>(
+        &'a self,
+        graph: &mut RenderGraph<'a>,
+        output: GraphImage,
+        depth: GraphImage,
+        shadow_maps: &[GraphImage],
+        frame_index: usize,
+        clear: [f32; 4],
+    ) -> bool {
+        let Some(deferred) = self.deferred_lighting() else {
+            return false;
+        };
+
+        if self.draws.is_empty() {
+            return false;
+        }
+
+        let extent = graph.extent(output);
+        let gbuffer: [GraphImage; GBUFFER_FORMATS.len()] = std::array::from_fn(|index| graph.create_image(
+            GBUFFER_NAMES[index],
+            ImageDesc::new_2d(extent.width, extent.height, GBUFFER_FORMATS[index], vk::ImageUsageFlags::empty()),
+        ));
+
+        gbuffer.iter()
+            .fold(graph.add_pass("gbuffer"), |pass, &image| pass.color(image, Some([0.0; 4])))
+            .depth(depth, Some(1.0))
+            .execute(move |ctx| self.record_draws(ctx.command_buffer(), frame_index, self.frame_sets[frame_index], DrawPass::Gbuffer));
+
+        let frame_set = self.frame_sets[frame_index];
+        let occlusion = self.ambient_occlusion.map(|quality| {
+            #[cfg(feature = "ray-tracing")]
+            if let Some(ray_traced) = self.ray_traced_occlusion() {
+                let occlusion = ray_traced.add_pass(graph, gbuffer[1], gbuffer[2], frame_index, quality);
+                return deferred.ssao.add_blur(graph, frame_set, occlusion, gbuffer[2], frame_index, quality);
+            }
+
+            deferred.ssao.add_passes(graph, frame_set, gbuffer[1], gbuffer[2], frame_index, quality)
+        });
+
+        gbuffer.iter()
+            .chain(&occlusion)
+            .chain(shadow_maps)
+            .fold(graph.add_pass("deferred lighting"), |pass, &image| pass.image(image, ImageAccess::Sampled(vk::PipelineStageFlags::FRAGMENT_SHADER)))
+            .color(output, Some(clear))
+            .execute(move |ctx| {
+                let command_buffer = ctx.command_buffer();
+                let set = deferred.sets[frame_index];
+                let sampler = self.device.sampler(&SamplerDesc::nearest())?;
+                let (occlusion_view, occlusion_sampler) = match occlusion {
+                    Some(image) => (ctx.view(image), sampler),
+                    None => self.texture_binding(self.white)?,
+                };
+
+                gbuffer.iter()
+                    .enumerate()
+                    .fold(DescriptorWriter::new(), |writer, (binding, &image)| writer.image(
+                        binding as u32,
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        ctx.view(image),
+                        sampler,
+                        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    ))
+                    .image(
+                        GBUFFER_FORMATS.len() as u32,
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        occlusion_view,
+                        occlusion_sampler,
+                        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    )
+                    .update(&self.device, set);
+
+                deferred.pipeline.bind(command_buffer);
+                self.device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    deferred.pipeline.layout(),
+                    0,
+                    &[self.frame_sets[frame_index], set],
+                    &[],
+                );
+                self.device.cmd_draw(command_buffer, 3, 1, 0, 0);
+                Ok(())
+            });
+
+        true
+    }
+
+    /// Adds a pass reducing `depth` into the HiZ pyramid the next frame is occlusion culled against. Add it after
+    /// every pass drawing into `depth`. Does nothing unless drawing indirectly with occlusion culling on; the depth
+    /// image must be single sampled and have `SAMPLED` usage.
+    pub unsafe fn add_occlusion_pass<'a>(&'a self, graph: &mut RenderGraph<'a>, depth: GraphImage, frame_index: usize) {
+        let Some(occlusion) = &self.occlusion else {
+            return;
+        };
+
+        if !self.occlusion_culling || self.indirect_culling().is_none() {
+            return;
+        }
+
+        let view_projection = self.camera.projection(graph.extent(depth)) * self.camera.view();
+        occlusion.add_pass(graph, depth, frame_index, view_projection);
+    }
+
+    /// Records the queued draws into the current pass, leaving out the ones `add_deferred_passes` draws, with the
+    /// skybox of the environment between the opaque and the transparent ones and the particles last. `prepare` must
+    /// have been recorded for this frame before the pass began, and the pass must have a depth attachment.
+    pub unsafe fn record(&self, command_buffer: vk::CommandBuffer, frame_index: usize) -> anyhow::Result<()> {
+        if self.viewports.is_empty() {
+            return self.record_view(command_buffer, frame_index, self.frame_sets[frame_index]);
+        }
+
+        let frame_sets = std::iter::once(self.frame_sets[frame_index]).chain(self.split_views.iter().map(|view| view.frame_sets[frame_index]));
+        for (viewport, frame_set) in self.viewports.iter().zip(frame_sets) {
+            set_viewport_rect(&self.device, command_buffer, viewport.rect);
+            self.record_view(command_buffer, frame_index, frame_set)?;
+        }
+
+        set_viewport_and_scissor(&self.device, command_buffer, self.extent);
+        Ok(())
+    }
+
+    /// Records the forward draws through the camera of `frame_set`.
+    unsafe fn record_view(&self, command_buffer: vk::CommandBuffer, frame_index: usize, frame_set: vk::DescriptorSet) -> anyhow::Result<()> {
+        self.record_draws(command_buffer, frame_index, frame_set, DrawPass::ForwardOpaque)?;
+        self.record_skinned_draws(command_buffer, frame_index, frame_set, true)?;
+
+        if let Some(environment) = self.environment.filter(|_| self.debug_view_pipeline().is_none()) {
+            let cubemap = self.cubemaps.get(environment.0).ok_or(anyhow!("Unknown cube map {:?}", environment))?;
+            self.skybox.record(&self.device, command_buffer, frame_set, cubemap.set);
+        }
+
+        self.record_draws(command_buffer, frame_index, frame_set, DrawPass::ForwardTransparent)?;
+        self.record_skinned_draws(command_buffer, frame_index, frame_set, false)?;
+        self.particles.record(command_buffer, frame_set, frame_index, &self.emitters);
+        self.debug_draw.record(command_buffer, frame_set, frame_index);
+        Ok(())
+    }
+
+    /// Clears the draw and light queues once the frame is recorded, or when it is skipped without being prepared.
+    pub fn end_frame(&mut self) {
+        self.draws.clear();
+        self.lights.clear();
+        self.skinned_draws.clear();
+        self.joint_palettes.clear();
+        self.morph_weights.clear();
+        self.debug_draw.clear();
+        self.batches.clear();
+        self.groups.clear();
+    }
+
+    /// The ray traced occlusion frames are drawn with, when it's on and supported.
+    #[cfg(feature = "ray-tracing")]
+    fn ray_traced_occlusion(&self) -> Option<&RayTracedAo> {
+        if !self.ray_traced_ambient_occlusion || self.ambient_occlusion.is_none() {
+            return None;
+        }
+
+        self.deferred_lighting()?.ray_traced.as_ref()
+    }
+
+    /// Builds the acceleration structures of meshes created since the last frame and records building this frame's
+    /// scene from the opaque draws, culled ones included, when drawing with ray traced occlusion.
+    #[cfg(feature = "ray-tracing")]
+    unsafe fn prepare_ray_traced_occlusion(&mut self, command_buffer: vk::CommandBuffer, frame_index: usize) -> anyhow::Result<()> {
+        if self.ray_traced_occlusion().is_none() {
+            return Ok(());
+        }
+
+        let Some(ray_traced) = self.deferred.as_mut().and_then(|deferred| deferred.ray_traced.as_mut()) else {
+            return Ok(());
+        };
+
+        for stored in &self.meshes[ray_traced.bottom_level_count()..] {
+            let positions: Vec<[f32; 3]> = stored.data.vertices.iter().map(|vertex| vertex.position).collect();
+            ray_traced.build_bottom_level(&stored.name, &positions, &stored.data.indices)?;
+        }
+
+        let materials = &self.materials;
+        let instances = self.draws.iter()
+            .filter(|draw| materials[draw.material.0].desc().blend == BlendMode::Opaque)
+            .map(|draw| (draw.mesh.0, Matrix4::from(draw.data.model)));
+        ray_traced.record_scene(command_buffer, frame_index, instances)
+    }
+
+    /// Builds the meshlet arena if meshes were added since, and points this frame's meshlet set at it and the
+    /// instances, when drawing meshlets.
+    unsafe fn prepare_meshlets(&mut self, frame_index: usize) -> anyhow::Result<()> {
+        if self.mesh_shading().is_none() {
+            return Ok(());
+        }
+
+        if self.meshlet_arena.as_ref().is_none_or(|arena| arena.mesh_count() != self.meshes.len()) {
+            // Frames in flight may still draw from the old arena.
+            self.device.device_wait_idle()?;
+            self.meshlet_arena = Some(MeshletArena::new(&self.device, self.meshes.iter().map(|stored| (&stored.data, &stored.meshlets)))?);
+        }
+
+        let (mesh_shading, arena) = self.meshlet_draws().expect("the arena was just built");
+        mesh_shading.write_set(frame_index, &self.instance_buffers[frame_index], arena);
+        Ok(())
+    }
+
+    /// Groups the sorted queue by material instance and records the culling pass writing their commands, when
+    /// drawing indirectly.
+    unsafe fn record_indirect_culling(&mut self, command_buffer: vk::CommandBuffer, frame_index: usize, frustum: &Frustum) -> anyhow::Result<()> {
+        self.groups.clear();
+        if self.indirect_culling().is_none() {
+            return Ok(());
+        }
+
+        if self.mesh_arena.as_ref().is_none_or(|arena| arena.mesh_count() != self.meshes.len()) {
+            // Frames in flight may still draw from the old arena.
+            self.device.device_wait_idle()?;
+            self.mesh_arena = Some(MeshArena::new(&self.device, self.meshes.iter().map(|stored| &stored.data))?);
+        }
+
+        let arena = self.mesh_arena.as_ref().expect("the arena was just built");
+        let mut objects = Vec::with_capacity(self.draws.len());
+        for (index, draw) in self.draws.iter().enumerate() {
+            if draw.visible {
+                match self.groups.last_mut() {
+                    Some(group) if (group.material, group.instance) == (draw.material, draw.instance) => group.max_count += 1,
+                    _ => self.groups.push(DrawGroup {
+                        material: draw.material,
+                        instance: draw.instance,
+                        first_command: index as u32,
+                        max_count: 1,
+                    }),
+                }
+            }
+
+            let mesh = &self.meshes.get(draw.mesh.0).ok_or(anyhow!("Unknown mesh {:?}", draw.mesh))?.mesh;
+            let range = arena.range(draw.mesh.0);
+            let casts_shadows = self.materials[draw.material.0].desc().blend == BlendMode::Opaque;
+            let (group, first_command) = match (draw.visible, self.groups.last()) {
+                (true, Some(group)) => (self.groups.len() as u32 - 1, group.first_command),
+                _ => (0, 0),
+            };
+
+            objects.push(GpuObject {
+                sphere: mesh.bounding_sphere().transform(&Matrix4::from(draw.data.model)).to_vec4(),
+                index_count: range.index_count,
+                first_index: range.first_index,
+                vertex_offset: range.vertex_offset,
+                group,
+                first_command,
+                flags: if casts_shadows { CASTS_SHADOWS } else { 0 } | if draw.visible { DRAWN } else { 0 },
+                _padding: [0; 2],
+            });
+        }
+
+        let group_count = self.groups.len() as u32;
+        let indirect = self.indirect.as_mut().expect("indirect_culling checked it");
+        let occlusion = self.occlusion.as_ref().filter(|_| self.occlusion_culling);
+        indirect.record(command_buffer, frame_index, &objects, group_count, frustum, occlusion)
+    }
+
+    /// Replaces every queued `draw_lod` with the levels its distance from the camera and coverage of the screen
+    /// pick, dropping the ones past their last level.
+    fn select_lods(&mut self) {
+        if self.draws.iter().all(|draw| draw.lod.is_none()) {
+            return;
+        }
+
+        let half_height = (self.camera.fov_y * 0.5).0.tan();
+        for draw in std::mem::take(&mut self.draws) {
+            let Some(lod) = draw.lod else {
+                self.draws.push(draw);
+                continue;
+            };
+
+            let desc = &self.lod_groups[lod.0];
+            let sphere = self.meshes[desc.levels[0].mesh.0].mesh.bounding_sphere().transform(&Matrix4::from(draw.data.model));
+            let distance = (sphere.center - self.camera.position).magnitude();
+            let coverage = (sphere.radius / (distance * half_height).max(f32::EPSILON)).min(1.0);
+
+            for choice in desc.select(distance, coverage).into_iter().flatten() {
+                self.draws.push(DrawCommand {
+                    mesh: desc.levels[choice.level].mesh,
+                    data: InstanceData {
+                        lod_fade: choice.fade,
+                        ..draw.data
+                    },
+                    lod: None,
+                    ..draw
+                });
+            }
+        }
+    }
+
+    /// Sorts the skinned draws and uploads their palettes and weights, along with the morph targets of meshes that
+    /// were added since the last frame.
+    unsafe fn prepare_skinning(&mut self, frame_index: usize) -> anyhow::Result<()> {
+        self.skinned_draws.sort_by_key(SkinnedDraw::key);
+
+        if self.morph_deltas.mesh_count() != self.skinned_meshes.len() {
+            // Frames in flight may still read the old deltas, and every frame set points at them.
+            self.device.device_wait_idle()?;
+            self.morph_deltas = MorphDeltas::new(&self.device, self.skinned_meshes.iter().map(|stored| &stored.data))?;
+            for frame_index in 0..self.frame_sets.len() {
+                self.write_frame_set(frame_index)?;
+            }
+        }
+
+        let palettes_grew = self.joint_palettes.upload(frame_index)?;
+        let weights_grew = self.morph_weights.upload(frame_index)?;
+        if palettes_grew || weights_grew {
+            self.write_frame_set(frame_index)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sizes the HiZ pyramid for this frame's depth buffer, or drops what it holds when occlusion culling is off,
+    /// since it would be from an arbitrarily old frame once it's back on.
+    unsafe fn prepare_occlusion(&mut self, extent: vk::Extent2D) -> anyhow::Result<()> {
+        let enabled = self.occlusion_culling && self.indirect_culling().is_some();
+        let Some(occlusion) = &mut self.occlusion else {
+            return Ok(());
+        };
+
+        if !enabled {
+            occlusion.invalidate();
+            return Ok(());
+        }
+
+        occlusion.resize(vk::Extent2D {
+            width: extent.width.max(1),
+            height: extent.height.max(1),
+        })
+    }
+
+    /// Marks the queued draws outside `frustum` as culled, dropping the ones that don't cast shadows, and counts
+    /// them in the culling stats. Spheres rule out most objects cheaply; boxes catch what spheres overestimate.
+    fn cull_draws(&mut self, frustum: &Frustum, split_frusta: &[Frustum]) -> anyhow::Result<()> {
+        let objects = self.draws.len();
+
+        if self.frustum_culling {
+            for draw in &mut self.draws {
+                let mesh = &self.meshes.get(draw.mesh.0).ok_or(anyhow!("Unknown mesh {:?}", draw.mesh))?.mesh;
+                let model = Matrix4::from(draw.data.model);
+                let (sphere, aabb) = (mesh.bounding_sphere().transform(&model), mesh.aabb().transform(&model));
+                draw.visible = std::iter::once(frustum)
+                    .chain(split_frusta)
+                    .any(|frustum| frustum.intersects_sphere(&sphere) && frustum.intersects_aabb(&aabb));
+            }
+
+            let materials = &self.materials;
+            self.draws.retain(|draw| draw.visible || materials[draw.material.0].desc().blend == BlendMode::Opaque);
+        }
+
+        let submitted = self.draws.iter().filter(|draw| draw.visible).count();
+        self.culling_stats = CullingStats {
+            objects,
+            culled: objects - submitted,
+            submitted,
+            draw_calls: 0,
+        };
+
+        Ok(())
+    }
+
+    /// Groups the sorted queue into batches and writes their instances, in order, into the instance buffer of
+    /// `frame_index`, which grows when they don't fit. Only this frame's buffer is replaced, so nothing waits.
+    unsafe fn write_batches(&mut self, frame_index: usize, arena: &FrameArena) -> anyhow::Result<()> {
+        self.batches.clear();
+        for (index, draw) in self.draws.iter().enumerate() {
+            match self.batches.last_mut() {
+                Some(batch) if (batch.material, batch.instance, batch.mesh) == draw.key() && batch.visible == draw.visible => {
+                    batch.instance_count += 1;
+                }
+                _ => self.batches.push(DrawBatch {
+                    material: draw.material,
+                    instance: draw.instance,
+                    mesh: draw.mesh,
+                    first_instance: index as u32,
+                    instance_count: 1,
+                    visible: draw.visible,
+                }),
+            }
+        }
+
+        let size = (self.draws.len() * std::mem::size_of::<InstanceData>()) as vk::DeviceSize;
+        if size > self.instance_buffers[frame_index].size() {
+            let capacity = self.draws.len().next_power_of_two();
+            debug!("Growing the instance buffer of frame {} to {} instances", frame_index, capacity);
+            self.instance_buffers[frame_index] = create_instance_buffer(&self.device, capacity)?;
+        }
+
+        let instances = arena.alloc_from_iter(self.draws.iter().map(|draw| draw.data));
+        self.instance_buffers[frame_index].write(0, instances)
+    }
+
+    /// Draws every opaque mesh into a shadow map with `pipeline`.
+    unsafe fn record_shadow_casters(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        pipeline: &GraphicsPipeline,
+        view_projection: Matrix4<f32>,
+        frame_index: usize,
+    ) -> anyhow::Result<()> {
+        pipeline.bind(command_buffer);
+        self.shadows.set_depth_bias(command_buffer);
+
+        let light_view_projection: [[f32; 4]; 4] = view_projection.into();
+        pipeline.push_constants(command_buffer, vk::ShaderStageFlags::VERTEX, 0, &light_view_projection);
+        self.bind_instance_buffer(command_buffer, frame_index);
+
+        if let Some((indirect, arena)) = self.indirect_draws() {
+            arena.bind(&self.device, command_buffer);
+            indirect.draw_shadow_casters(command_buffer, frame_index);
+            return Ok(());
+        }
+
+        let mut bound_mesh = None;
+        for batch in &self.batches {
+            if self.materials[batch.material.0].desc().blend != BlendMode::Opaque {
+                continue;
+            }
+
+            let mesh = &self.meshes.get(batch.mesh.0).ok_or(anyhow!("Unknown mesh {:?}", batch.mesh))?.mesh;
+            if bound_mesh != Some(batch.mesh) {
+                mesh.bind(&self.device, command_buffer);
+                bound_mesh = Some(batch.mesh);
+            }
+
+            self.device.cmd_draw_indexed(command_buffer, mesh.index_count(), batch.instance_count, 0, 0, batch.first_instance);
+        }
+
+        Ok(())
+    }
+
+    /// Binds the instance buffer of `frame_index` to binding 1, where it stays across pipeline binds.
+    unsafe fn bind_instance_buffer(&self, command_buffer: vk::CommandBuffer, frame_index: usize) {
+        self.device.cmd_bind_vertex_buffers(command_buffer, 1, &[self.instance_buffers[frame_index].handle()], &[0]);
+    }
+
+    unsafe fn record_draws(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        frame_set: vk::DescriptorSet,
+        pass: DrawPass,
+    ) -> anyhow::Result<()> {
+        let mut bound_material = None;
+        let mut bound_instance = None;
+        let mut bound_mesh = None;
+        let meshlets = self.meshlet_draws().filter(|_| self.debug_view_pipeline().is_none());
+        self.bind_instance_buffer(command_buffer, frame_index);
+
+        let calls: Vec<DrawCall> = match self.indirect_draws() {
+            Some((indirect, arena)) => {
+                arena.bind(&self.device, command_buffer);
+                self.groups.iter()
+                    .enumerate()
+                    .map(|(index, group)| DrawCall::Group(indirect, index as u32, group))
+                    .collect()
+            }
+            None => self.batches.iter().filter(|batch| batch.visible).map(DrawCall::Batch).collect(),
+        };
+
+        for call in calls {
+            let (material_id, instance_id) = call.key();
+            let material = &self.materials[material_id.0];
+            let opaque = material.desc().blend == BlendMode::Opaque;
+            let debug_view = self.debug_view_pipeline();
+            let pipeline = match (pass, debug_view, self.deferred_lighting().and(material.gbuffer_pipeline())) {
+                (DrawPass::ForwardOpaque, Some((pipeline, _)), _) => pipeline,
+                (_, Some(_), _) => continue,
+                (DrawPass::Gbuffer, None, Some(pipeline)) => pipeline,
+                (DrawPass::ForwardOpaque, None, None) if opaque => material.pipeline(),
+                (DrawPass::ForwardTransparent, None, None) if !opaque => material.pipeline(),
+                _ => continue,
+            };
+            let meshlet_pipeline = meshlets.and(match pass {
+                DrawPass::Gbuffer => material.meshlet_gbuffer_pipeline(),
+                _ => material.meshlet_pipeline(),
+            });
+            let pipeline = meshlet_pipeline.unwrap_or(pipeline);
+            let layout = pipeline.layout();
+
+            if bound_material != Some(material_id) {
+                pipeline.bind(command_buffer);
+                if let (Some((mesh_shading, _)), Some(_)) = (meshlets, meshlet_pipeline) {
+                    mesh_shading.bind_set(command_buffer, pipeline, frame_index);
+                }
+                if let Some((_, color)) = debug_view {
+                    pipeline.push_constants(command_buffer, vk::ShaderStageFlags::FRAGMENT, 0, &color);
+                }
+                self.device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    layout,
+                    0,
+                    &[frame_set],
+                    &[],
+                );
+                if debug_view.is_none() {
+                    self.bind_material_records(command_buffer, pipeline, material, frame_index);
+                }
+                bound_material = Some(material_id);
+                bound_instance = None;
+            }
+
+            // The debug view pipelines have no material set.
+            if debug_view.is_none() && bound_instance != Some(instance_id) {
+                self.bind_instance(command_buffer, pipeline, material, instance_id, frame_index);
+                bound_instance = Some(instance_id);
+            }
+
+            match call {
+                DrawCall::Batch(batch) => match (meshlets, meshlet_pipeline) {
+                    (Some((mesh_shading, arena)), Some(pipeline)) => {
+                        // Cones only tell which side of a meshlet faces away.
+                        let cone_culling = material.desc().cull_mode == vk::CullModeFlags::BACK;
+                        mesh_shading.draw(command_buffer, pipeline, arena.range(batch.mesh.0), batch.first_instance, batch.instance_count, cone_culling);
+                    }
+                    _ => {
+                        let mesh = &self.meshes.get(batch.mesh.0).ok_or(anyhow!("Unknown mesh {:?}", batch.mesh))?.mesh;
+                        if bound_mesh != Some(batch.mesh) {
+                            mesh.bind(&self.device, command_buffer);
+                            bound_mesh = Some(batch.mesh);
+                        }
+
+                        self.device.cmd_draw_indexed(command_buffer, mesh.index_count(), batch.instance_count, 0, 0, batch.first_instance);
+                    }
+                },
+                DrawCall::Group(indirect, index, group) => {
+                    indirect.draw_group(command_buffer, frame_index, index, group.first_command, group.max_count);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records the skinned draws with opaque materials, or with the other ones. The wireframe and overdraw views leave
+    /// them out.
+    unsafe fn record_skinned_draws(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        frame_set: vk::DescriptorSet,
+        opaque: bool,
+    ) -> anyhow::Result<()> {
+        if self.debug_view_pipeline().is_some() {
+            return Ok(());
+        }
+
+        let mut bound_material = None;
+        let mut bound_instance = None;
+        let mut bound_mesh = None;
+
+        for draw in &self.skinned_draws {
+            let material = &self.materials[draw.material.0];
+            if (material.desc().blend == BlendMode::Opaque) != opaque {
+                continue;
+            }
+
+            let pipeline = material.skinned_pipeline().expect("draw_skinned checked the material");
+            let layout = pipeline.layout();
+
+            if bound_material != Some(draw.material) {
+                pipeline.bind(command_buffer);
+                self.device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    layout,
+                    0,
+                    &[frame_set],
+                    &[],
+                );
+                self.bind_material_records(command_buffer, pipeline, material, frame_index);
+                bound_material = Some(draw.material);
+                bound_instance = None;
+            }
+
+            if bound_instance != Some(draw.instance) {
+                self.bind_instance(command_buffer, pipeline, material, draw.instance, frame_index);
+                bound_instance = Some(draw.instance);
+            }
+
+            let mesh = &self.skinned_meshes[draw.mesh.0].mesh;
+            if bound_mesh != Some(draw.mesh) {
+                mesh.bind(&self.device, command_buffer);
+                bound_mesh = Some(draw.mesh);
+            }
+
+            pipeline.push_constants(command_buffer, vk::ShaderStageFlags::VERTEX, 0, &SkinPushConstants {
+                model: draw.model,
+                first_joint: draw.first_joint,
+                first_weight: draw.first_weight,
+                first_delta: self.morph_deltas.offset(draw.mesh.0),
+                morph_target_count: if draw.morphed { mesh.morph_target_count() } else { 0 },
+            });
+            self.device.cmd_draw_indexed(command_buffer, mesh.index_count(), 1, 0, 0, 0);
+        }
+
+        Ok(())
+    }
+
+    /// Rebuilds meshes, skinned meshes, textures, cube maps, materials, their instances and the emitters on `device`, after the device
+    /// the renderer was created on was lost. Emitters start over without particles.
+    pub unsafe fn recreate(&mut self, device: &Arc<Device>, target: &PipelineTarget, compiler: &GlslCompiler) -> anyhow::Result<()> {
+        let frames_in_flight = self.frame_sets.len();
+        let meshes = std::mem::take(&mut self.meshes);
+        let skinned_meshes = std::mem::take(&mut self.skinned_meshes);
+        let textures = std::mem::take(&mut self.textures);
+        let materials = std::mem::take(&mut self.materials);
+        let cubemaps = std::mem::take(&mut self.cubemaps);
+        let emitters = std::mem::take(&mut self.emitters);
+        self.draws.clear();
+        self.skinned_draws.clear();
+        self.batches.clear();
+        self.groups.clear();
+        self.lights.clear();
+        self.mesh_arena = None;
+        self.meshlet_arena = None;
+        // Rebuilt on the new device by the next `set_viewports`.
+        self.viewports.clear();
+        self.split_views.clear();
+
+        self.descriptor_allocator = DescriptorAllocator::new(device);
+        let mut layouts = DescriptorLayoutCache::new(device);
+        (self.frame_layout, self.bindless) = create_frame_layout(device, &mut layouts, frames_in_flight)?;
+        self.mesh_shading = MeshShading::new(device, &mut layouts, &mut self.descriptor_allocator, compiler, frames_in_flight)?;
+        self.deferred = DeferredLighting::new(
+            device,
+            &mut layouts,
+            &mut self.descriptor_allocator,
+            compiler,
+            target,
+            self.frame_layout,
+            frames_in_flight,
+        )?;
+        self.skybox = Skybox::new(device, &mut layouts, compiler, target, self.frame_layout)?;
+        self.particles = ParticleSystem::new(device, &mut layouts, compiler, target, self.frame_layout)?;
+        self.debug_draw = DebugDraw::new(device, compiler, target, self.frame_layout, frames_in_flight)?;
+        self.debug_view_pipelines = DebugViewPipelines::new(device, compiler, mesh_pipeline_base(self.frame_layout, target.clone()), &mesh_vertex_shader(target))?;
+        self.layouts = layouts;
+        self.camera_uniform = PerFrameUniform::new(device, "camera", frames_in_flight)?;
+        self.instance_buffers = create_instance_buffers(device, frames_in_flight)?;
+        self.joint_palettes = PerFrameStorage::new(device, "joint palette", INITIAL_PALETTE_CAPACITY, frames_in_flight)?;
+        self.morph_weights = PerFrameStorage::new(device, "morph weights", INITIAL_MORPH_WEIGHT_CAPACITY, frames_in_flight)?;
+        self.morph_deltas = MorphDeltas::new(device, [])?;
+        self.indirect = create_indirect_culling(device, compiler, frames_in_flight)?;
+        self.occlusion = create_occlusion(device, compiler, target, self.indirect.is_some(), frames_in_flight)?;
+        self.lighting = LightCulling::new(device, compiler, frames_in_flight)?;
+        self.shadows = ShadowMaps::new(device, compiler, target, *self.shadows.quality(), frames_in_flight)?;
+        self.target = target.clone();
+        self.device = device.clone();
+        self.allocate_frame_sets(frames_in_flight)?;
+
+        for mesh in meshes {
+            self.create_mesh(&mesh.name, mesh.data)?;
+        }
+
+        for mesh in skinned_meshes {
+            self.create_skinned_mesh(&mesh.name, mesh.data, mesh.skeleton)?;
+        }
+
+        for texture in textures {
+            self.store_texture(&texture.name, texture.width, texture.height, texture.format, &texture.pixels, texture.sampler)?;
+        }
+
+        for cubemap in cubemaps {
+            self.create_cubemap(compiler, &cubemap.name, cubemap.source)?;
+        }
+
+        for material in materials {
+            self.create_material(compiler, material.desc().clone())?;
+        }
+
+        for emitter in emitters {
+            let id = self.create_emitter(emitter.desc().clone())?;
+            self.emitters[id.0].set_position(emitter.position());
+            self.emitters[id.0].set_emitting(emitter.is_emitting());
+        }
+
+        let white = self.texture_binding(self.white)?;
+        for index in 0..self.instances.len() {
+            let stored = &mut self.instances[index];
+            stored.instance.recreate(device, &self.materials[stored.material.0], &mut self.descriptor_allocator, white)?;
+            let textures = stored.textures.clone();
+            self.apply_default_textures(MaterialInstanceId(index))?;
+
+            for (slot, texture) in textures.into_iter().enumerate() {
+                if let Some(texture) = texture {
+                    let name = self.materials[self.instances[index].material.0].desc().texture_slots()[slot].clone();
+                    self.set_texture(MaterialInstanceId(index), &name, texture)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn pipeline_base(&self, target: PipelineTarget) -> GraphicsPipelineBuilder {
+        mesh_pipeline_base(self.frame_layout, target)
+    }
+
+    /// GPU culling only knows one camera over the whole target, so viewports are drawn directly.
+    fn indirect_culling(&self) -> Option<&IndirectCulling> {
+        self.indirect.as_ref().filter(|_| self.draw_submission == DrawSubmission::Indirect && self.viewports.is_empty())
+    }
+
+    fn mesh_shading(&self) -> Option<&MeshShading> {
+        self.mesh_shading.as_ref().filter(|_| self.draw_submission == DrawSubmission::Meshlets && self.viewports.is_empty())
+    }
+
+    /// What meshlet draws need, once `prepare` built the arena.
+    fn meshlet_draws(&self) -> Option<(&MeshShading, &MeshletArena)> {
+        Some((self.mesh_shading()?, self.meshlet_arena.as_ref()?))
+    }
+
+    /// What indirect draws need, once `prepare` built the arena.
+    fn indirect_draws(&self) -> Option<(&IndirectCulling, &MeshArena)> {
+        Some((self.indirect_culling()?, self.mesh_arena.as_ref()?))
+    }
+
+    /// Draws everything forward while a debug view replaces the materials, or the screen is split.
+    fn deferred_lighting(&self) -> Option<&DeferredLighting> {
+        self.deferred.as_ref().filter(|_| {
+            self.render_path == RenderPath::Deferred && self.debug_view_pipeline().is_none() && self.viewports.is_empty()
+        })
+    }
+
+    /// Binds the set every instance of a bindless material shares.
+    unsafe fn bind_material_records(&self, command_buffer: vk::CommandBuffer, pipeline: &GraphicsPipeline, material: &Material, frame_index: usize) {
+        if let Some(records) = material.records() {
+            self.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.layout(),
+                MATERIAL_SET,
+                &[records.set(frame_index)],
+                &[],
+            );
+        }
+    }
+
+    /// Binds the set of `instance`, or for bindless materials, pushes which of the material's instances it is.
+    unsafe fn bind_instance(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        pipeline: &GraphicsPipeline,
+        material: &Material,
+        instance: MaterialInstanceId,
+        frame_index: usize,
+    ) {
+        let stored = &self.instances[instance.0];
+        match material.records() {
+            Some(_) => pipeline.push_constants(command_buffer, vk::ShaderStageFlags::FRAGMENT, MATERIAL_INDEX_OFFSET, &stored.record),
+            None => self.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.layout(),
+                MATERIAL_SET,
+                &[stored.instance.set(frame_index)],
+                &[],
+            ),
+        }
+    }
+
+    /// What static meshes are drawn with in place of their materials, in the wireframe and overdraw views.
+    fn debug_view_pipeline(&self) -> Option<(&GraphicsPipeline, [f32; 4])> {
+        self.debug_view_pipelines.pipeline(self.debug_view)
+    }
+
+    /// Puts the default texture in every slot of `instance` that `set_texture` hasn't filled.
+    unsafe fn apply_default_textures(&mut self, instance: MaterialInstanceId) -> anyhow::Result<()> {
+        let stored = &self.instances[instance.0];
+        let desc = self.materials[stored.material.0].desc();
+        let defaults: Vec<(String, TextureId)> = desc.texture_slots().iter()
+            .enumerate()
+            .filter(|&(slot, _)| stored.textures[slot].is_none())
+            .map(|(slot, name)| (name.clone(), self.default_texture(desc.texture_default(slot))))
+            .collect();
+
+        for (name, texture) in defaults {
+            self.put_texture(instance, &name, texture)?;
+        }
+
+        Ok(())
+    }
+
+    /// Puts `texture` in a slot of `instance`, by handle when its material is bindless.
+    unsafe fn put_texture(&mut self, instance: MaterialInstanceId, slot: &str, texture: TextureId) -> anyhow::Result<()> {
+        let binding = self.texture_binding(texture)?;
+        let handle = self.textures[texture.0].handle;
+        let instance = &mut self.instances[instance.0].instance;
+
+        match handle.filter(|_| instance.is_bindless()) {
+            Some(handle) => instance.set_texture_handle(slot, handle)?,
+            None => instance.set_texture(slot, binding.0, binding.1)?,
+        }
+
+        Ok(())
+    }
+
+    unsafe fn texture_binding(&self, texture: TextureId) -> anyhow::Result<(vk::ImageView, vk::Sampler)> {
+        let stored = self.textures.get(texture.0).ok_or(anyhow!("Unknown texture {:?}", texture))?;
+        Ok((stored.texture.view(), self.device.sampler(&stored.sampler)?))
+    }
+
+    unsafe fn allocate_frame_sets(&mut self, frames_in_flight: usize) -> anyhow::Result<()> {
+        self.frame_sets = self.allocate_frame_set_handles(frames_in_flight)?;
+
+        for frame_index in 0..frames_in_flight {
+            self.write_frame_set(frame_index)?;
+        }
+
+        Ok(())
+    }
+
+    unsafe fn allocate_frame_set_handles(&mut self, frames_in_flight: usize) -> anyhow::Result<Vec<vk::DescriptorSet>> {
+        // Sets with the texture array come from its own pool.
+        (0..frames_in_flight)
+            .map(|_| match &mut self.bindless {
+                Some(bindless) => bindless.allocate(self.frame_layout),
+                None => self.descriptor_allocator.allocate(self.frame_layout),
+            })
+            .collect()
+    }
+
+    /// Writes the frame set of `frame_index` of the renderer and of every split view.
+    unsafe fn write_frame_set(&self, frame_index: usize) -> anyhow::Result<()> {
+        self.write_frame_set_for(self.frame_sets[frame_index], frame_index, &self.camera_uniform, &self.lighting)?;
+        for view in &self.split_views {
+            self.write_frame_set_for(view.frame_sets[frame_index], frame_index, &view.camera_uniform, &view.lighting)?;
+        }
+
+        Ok(())
+    }
+
+    unsafe fn write_frame_set_for(
+        &self,
+        set: vk::DescriptorSet,
+        frame_index: usize,
+        camera_uniform: &PerFrameUniform<CameraUniform>,
+        lighting: &LightCulling,
+    ) -> anyhow::Result<()> {
+        let camera = camera_uniform.descriptor_info(frame_index);
+        let clusters = lighting.params_info(frame_index);
+        let shadows = self.shadows.uniform_info(frame_index);
+
+        DescriptorWriter::new()
+            .buffer(0, vk::DescriptorType::UNIFORM_BUFFER, camera.buffer, camera.offset, camera.range)
+            .buffer(1, vk::DescriptorType::STORAGE_BUFFER, lighting.light_buffer(frame_index).handle(), 0, vk::WHOLE_SIZE)
+            .buffer(2, vk::DescriptorType::STORAGE_BUFFER, lighting.cluster_buffer(frame_index).handle(), 0, vk::WHOLE_SIZE)
+            .buffer(3, vk::DescriptorType::UNIFORM_BUFFER, clusters.buffer, clusters.offset, clusters.range)
+            .buffer(4, vk::DescriptorType::UNIFORM_BUFFER, shadows.buffer, shadows.offset, shadows.range)
+            .image(
+                5,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                self.shadows.view(),
+                self.device.sampler(&SamplerDesc::shadow())?,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            )
+            .buffer(6, vk::DescriptorType::STORAGE_BUFFER, self.joint_palettes.buffer(frame_index).handle(), 0, vk::WHOLE_SIZE)
+            .buffer(7, vk::DescriptorType::STORAGE_BUFFER, self.morph_deltas.buffer().handle(), 0, vk::WHOLE_SIZE)
+            .buffer(8, vk::DescriptorType::STORAGE_BUFFER, self.morph_weights.buffer(frame_index).handle(), 0, vk::WHOLE_SIZE)
+            .update(&self.device, set);
+
+        Ok(())
+    }
+}
+
+/// `None` when `device` lacks the features indirect draws need.
+unsafe fn create_indirect_culling(device: &Arc<Device>, compiler: &GlslCompiler, frames_in_flight: usize) -> anyhow::Result<Option<IndirectCulling>> {
+    let capabilities = device.capabilities();
+    if !capabilities.has_feature(Feature::DrawIndirectCount) || !capabilities.has_feature(Feature::DrawIndirectFirstInstance) {
+        return Ok(None);
+    }
+
+    IndirectCulling::new(device, compiler, frames_in_flight).map(Some)
+}
+
+/// `None` when indirect draws aren't supported or the renderer's depth buffer can't be sampled, which takes dynamic
+/// rendering into single sampled attachments of one view.
+unsafe fn create_occlusion(
+    device: &Arc<Device>,
+    compiler: &GlslCompiler,
+    target: &PipelineTarget,
+    indirect: bool,
+    frames_in_flight: usize,
+) -> anyhow::Result<Option<HiZPyramid>> {
+    let PipelineTarget::Dynamic(formats) = target else {
+        return Ok(None);
+    };
+
+    if !indirect || formats.samples != vk::SampleCountFlags::TYPE_1 || formats.view_mask != 0 || !formats.depth_format.is_some_and(supports_depth_format) {
+        return Ok(None);
+    }
+
+    HiZPyramid::new(device, compiler, frames_in_flight).map(Some)
+}
+
+/// `mesh.vert` for pipelines drawing into `target`, which on a multiview target places each view with its own matrix.
+pub(crate) fn mesh_vertex_shader(target: &PipelineTarget) -> String {
+    match target.view_mask() {
+        0 => MESH_VERT.to_owned(),
+        _ => insert_after_version(MESH_VERT, "#define MULTIVIEW"),
+    }
+}
+
+/// What every material pipeline shares: mesh vertices, instance data and the camera set.
+fn mesh_pipeline_base(frame_layout: vk::DescriptorSetLayout, target: PipelineTarget) -> GraphicsPipelineBuilder {
+    GraphicsPipelineBuilder::new()
+        .vertex::<MeshVertex>(0)
+        .instance::<InstanceData>(1)
+        .descriptor_set_layout(frame_layout)
+        .target(target)
+}
+
+unsafe fn create_instance_buffer(device: &Arc<Device>, capacity: usize) -> anyhow::Result<Buffer> {
+    let size = (capacity * std::mem::size_of::<InstanceData>()) as vk::DeviceSize;
+    // Mesh shaders read instances from a storage buffer.
+    Buffer::new(device, "instances", size, vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER, MemoryLocation::CpuToGpu)
+}
+
+unsafe fn create_instance_buffers(device: &Arc<Device>, frames_in_flight: usize) -> anyhow::Result<Vec<Buffer>> {
+    (0..frames_in_flight)
+        .map(|_| create_instance_buffer(device, INITIAL_INSTANCE_CAPACITY))
+        .collect()
+}
+
+/// The frame set's layout, and the texture array at [`BINDLESS_TEXTURES_BINDING`] on devices with bindless textures.
+unsafe fn create_frame_layout(
+    device: &Arc<Device>,
+    layouts: &mut DescriptorLayoutCache,
+    frames_in_flight: usize,
+) -> anyhow::Result<(vk::DescriptorSetLayout, Option<BindlessTextures>)> {
+    let capacity = supports_bindless(device).then(|| bindless_capacity(device));
+    let desc = frame_set_layout(supports_mesh_shading(device), capacity);
+    let bindless = capacity
+        .map(|capacity| BindlessTextures::new(device, &desc, BINDLESS_TEXTURES_BINDING, capacity, frames_in_flight))
+        .transpose()?;
+
+    Ok((layouts.get(&desc)?, bindless))
+}
+
+/// The camera is read by the task and mesh shaders of meshlet draws as well, on devices that have them.
+fn frame_set_layout(mesh_shading: bool, bindless_capacity: Option<u32>) -> SetLayoutDesc {
+    let camera_stages = match mesh_shading {
+        true => vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT | vk::ShaderStageFlags::TASK_EXT | vk::ShaderStageFlags::MESH_EXT,
+        false => vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+    };
+
+    let layout = SetLayoutDesc::new()
+        .binding(0, vk::DescriptorType::UNIFORM_BUFFER, camera_stages)
+        .binding(1, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::FRAGMENT)
+        .binding(2, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::FRAGMENT)
+        .binding(3, vk::DescriptorType::UNIFORM_BUFFER, vk::ShaderStageFlags::FRAGMENT)
+        .binding(4, vk::DescriptorType::UNIFORM_BUFFER, vk::ShaderStageFlags::FRAGMENT)
+        .binding(5, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::FRAGMENT)
+        .binding(6, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::VERTEX)
+        .binding(7, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::VERTEX)
+        .binding(8, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::VERTEX);
+
+    match bindless_capacity {
+        Some(capacity) => layout.bindless_array(BINDLESS_TEXTURES_BINDING, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, capacity, vk::ShaderStageFlags::FRAGMENT),
+        None => layout,
+    }
+}
+
+/// The G-buffer images, then the ambient occlusion.
+fn gbuffer_set_layout() -> SetLayoutDesc {
+    (0..=GBUFFER_FORMATS.len() as u32).fold(SetLayoutDesc::new(), |layout, binding| {
+        layout.binding(binding, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::FRAGMENT)
+    })
+}
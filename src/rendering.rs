@@ -0,0 +1,218 @@
+use ash::vk;
+use crate::device::Device;
+use crate::format::has_stencil_component;
+
+/// View mask rendering views 0 and 1, the two eyes of a stereo pass, to the first two layers of its attachments.
+pub const STEREO_VIEW_MASK: u32 = 0b11;
+
+/// Attachment formats and sample count a pipeline is built against when it renders with dynamic rendering instead
+/// of a render pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderingFormats {
+    pub color_formats: Vec<vk::Format>,
+    pub depth_format: Option<vk::Format>,
+    pub samples: vk::SampleCountFlags,
+    /// The views a pass renders with multiview, one bit per attachment layer. 0 renders a single view.
+    pub view_mask: u32,
+}
+
+impl RenderingFormats {
+    pub fn new(color_formats: &[vk::Format], depth_format: Option<vk::Format>) -> Self {
+        Self {
+            color_formats: color_formats.to_vec(),
+            depth_format,
+            samples: vk::SampleCountFlags::TYPE_1,
+            view_mask: 0,
+        }
+    }
+
+    pub fn with_samples(mut self, samples: vk::SampleCountFlags) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    /// Builds for passes rendering the views in `view_mask`, which has to match the pass's
+    /// [`RenderingPass::with_view_mask`] and needs `Feature::Multiview`.
+    pub fn with_view_mask(mut self, view_mask: u32) -> Self {
+        self.view_mask = view_mask;
+        self
+    }
+
+    pub fn stencil_format(&self) -> Option<vk::Format> {
+        self.depth_format.filter(|&format| has_stencil_component(format))
+    }
+}
+
+/// A color attachment of a dynamic rendering pass, rendered in `COLOR_ATTACHMENT_OPTIMAL`. Cleared and stored by
+/// default.
+#[derive(Debug, Clone, Copy)]
+pub struct ColorAttachment {
+    pub view: vk::ImageView,
+    pub load_op: vk::AttachmentLoadOp,
+    pub store_op: vk::AttachmentStoreOp,
+    pub clear: [f32; 4],
+    /// Single-sampled view the attachment is averaged into at the end of the pass.
+    pub resolve: Option<vk::ImageView>,
+}
+
+impl ColorAttachment {
+    pub fn new(view: vk::ImageView) -> Self {
+        Self {
+            view,
+            load_op: vk::AttachmentLoadOp::CLEAR,
+            store_op: vk::AttachmentStoreOp::STORE,
+            clear: [0.0; 4],
+            resolve: None,
+        }
+    }
+
+    pub fn with_clear(mut self, clear: [f32; 4]) -> Self {
+        self.load_op = vk::AttachmentLoadOp::CLEAR;
+        self.clear = clear;
+        self
+    }
+
+    pub fn with_load_op(mut self, load_op: vk::AttachmentLoadOp) -> Self {
+        self.load_op = load_op;
+        self
+    }
+
+    pub fn with_store_op(mut self, store_op: vk::AttachmentStoreOp) -> Self {
+        self.store_op = store_op;
+        self
+    }
+
+    pub fn with_resolve(mut self, view: vk::ImageView) -> Self {
+        self.resolve = Some(view);
+        self
+    }
+}
+
+/// The depth attachment of a dynamic rendering pass, rendered in `DEPTH_STENCIL_ATTACHMENT_OPTIMAL`. Cleared to
+/// 1.0 and discarded by default.
+#[derive(Debug, Clone, Copy)]
+pub struct DepthAttachment {
+    pub view: vk::ImageView,
+    pub load_op: vk::AttachmentLoadOp,
+    pub store_op: vk::AttachmentStoreOp,
+    pub clear: f32,
+}
+
+impl DepthAttachment {
+    pub fn new(view: vk::ImageView) -> Self {
+        Self {
+            view,
+            load_op: vk::AttachmentLoadOp::CLEAR,
+            store_op: vk::AttachmentStoreOp::DONT_CARE,
+            clear: 1.0,
+        }
+    }
+
+    pub fn with_clear(mut self, clear: f32) -> Self {
+        self.load_op = vk::AttachmentLoadOp::CLEAR;
+        self.clear = clear;
+        self
+    }
+
+    pub fn with_load_op(mut self, load_op: vk::AttachmentLoadOp) -> Self {
+        self.load_op = load_op;
+        self
+    }
+
+    pub fn with_store_op(mut self, store_op: vk::AttachmentStoreOp) -> Self {
+        self.store_op = store_op;
+        self
+    }
+}
+
+/// A single pass recorded with `vkCmdBeginRendering`, needing neither a render pass nor a framebuffer. Attachments
+/// must already be in their attachment layouts when the pass begins.
+#[derive(Debug, Clone)]
+pub struct RenderingPass {
+    extent: vk::Extent2D,
+    colors: Vec<ColorAttachment>,
+    depth: Option<DepthAttachment>,
+    view_mask: u32,
+}
+
+impl RenderingPass {
+    pub fn new(extent: vk::Extent2D) -> Self {
+        Self {
+            extent,
+            colors: Vec::new(),
+            depth: None,
+            view_mask: 0,
+        }
+    }
+
+    /// Renders every draw once for each view in `view_mask`, to the attachment layer of the same index, with the
+    /// view in `gl_ViewIndex`. The attachments need that many layers and pipelines the same mask in their
+    /// [`RenderingFormats`].
+    pub fn with_view_mask(mut self, view_mask: u32) -> Self {
+        self.view_mask = view_mask;
+        self
+    }
+
+    pub fn color(mut self, attachment: ColorAttachment) -> Self {
+        self.colors.push(attachment);
+        self
+    }
+
+    pub fn depth(mut self, attachment: DepthAttachment) -> Self {
+        self.depth = Some(attachment);
+        self
+    }
+
+    pub unsafe fn begin(&self, device: &Device, command_buffer: vk::CommandBuffer) {
+        let colors: Vec<vk::RenderingAttachmentInfo> = self.colors.iter()
+            .map(|color| {
+                let mut info = vk::RenderingAttachmentInfo::builder()
+                    .image_view(color.view)
+                    .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                    .load_op(color.load_op)
+                    .store_op(color.store_op)
+                    .clear_value(vk::ClearValue {
+                        color: vk::ClearColorValue { float32: color.clear },
+                    });
+
+                if let Some(resolve) = color.resolve {
+                    info = info
+                        .resolve_mode(vk::ResolveModeFlags::AVERAGE)
+                        .resolve_image_view(resolve)
+                        .resolve_image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+                }
+
+                info.build()
+            })
+            .collect();
+
+        let depth = self.depth.map(|depth| vk::RenderingAttachmentInfo::builder()
+            .image_view(depth.view)
+            .image_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+            .load_op(depth.load_op)
+            .store_op(depth.store_op)
+            .clear_value(vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue { depth: depth.clear, stencil: 0 },
+            })
+            .build());
+
+        let mut rendering_info = vk::RenderingInfo::builder()
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D::default(),
+                extent: self.extent,
+            })
+            .layer_count(1)
+            .view_mask(self.view_mask)
+            .color_attachments(&colors);
+
+        if let Some(depth) = depth.as_ref() {
+            rendering_info = rendering_info.depth_attachment(depth);
+        }
+
+        device.cmd_begin_rendering(command_buffer, &rendering_info);
+    }
+
+    pub unsafe fn end(&self, device: &Device, command_buffer: vk::CommandBuffer) {
+        device.cmd_end_rendering(command_buffer);
+    }
+}
@@ -0,0 +1,478 @@
+use std::collections::BTreeSet;
+use std::ffi::{c_void, CStr, CString};
+use std::fmt;
+use ash::extensions::{ext, khr};
+use ash::vk;
+
+/// A device feature that can be requested through [`DeviceRequirements`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Feature {
+    SamplerAnisotropy,
+    FillModeNonSolid,
+    WideLines,
+    DepthClamp,
+    MultiDrawIndirect,
+    DrawIndirectFirstInstance,
+    GeometryShader,
+    TessellationShader,
+    PipelineStatisticsQuery,
+    OcclusionQueryPrecise,
+    ShaderInt64,
+    ShaderSampledImageArrayDynamicIndexing,
+    ShaderDrawParameters,
+    Multiview,
+    TimelineSemaphore,
+    BufferDeviceAddress,
+    DescriptorIndexing,
+    RuntimeDescriptorArray,
+    DescriptorBindingPartiallyBound,
+    DescriptorBindingVariableDescriptorCount,
+    DescriptorBindingSampledImageUpdateAfterBind,
+    ShaderSampledImageArrayNonUniformIndexing,
+    DrawIndirectCount,
+    ScalarBlockLayout,
+    HostQueryReset,
+    DynamicRendering,
+    Synchronization2,
+    // `VK_KHR_maintenance5` and `VK_KHR_maintenance6` have no bindings in the ash release this crate builds against
+    // (0.37.3, Vulkan 1.3.251 headers), which predates both, so they can't be chained into device creation yet.
+    // They belong here, next to their predecessor, once ash is updated.
+    Maintenance4,
+    TaskShader,
+    MeshShader,
+    AccelerationStructure,
+    RayTracingPipeline,
+    RayQuery,
+}
+
+impl Feature {
+    pub const ALL: &'static [Feature] = &[
+        Self::SamplerAnisotropy,
+        Self::FillModeNonSolid,
+        Self::WideLines,
+        Self::DepthClamp,
+        Self::MultiDrawIndirect,
+        Self::DrawIndirectFirstInstance,
+        Self::GeometryShader,
+        Self::TessellationShader,
+        Self::PipelineStatisticsQuery,
+        Self::OcclusionQueryPrecise,
+        Self::ShaderInt64,
+        Self::ShaderSampledImageArrayDynamicIndexing,
+        Self::ShaderDrawParameters,
+        Self::Multiview,
+        Self::TimelineSemaphore,
+        Self::BufferDeviceAddress,
+        Self::DescriptorIndexing,
+        Self::RuntimeDescriptorArray,
+        Self::DescriptorBindingPartiallyBound,
+        Self::DescriptorBindingVariableDescriptorCount,
+        Self::DescriptorBindingSampledImageUpdateAfterBind,
+        Self::ShaderSampledImageArrayNonUniformIndexing,
+        Self::DrawIndirectCount,
+        Self::ScalarBlockLayout,
+        Self::HostQueryReset,
+        Self::DynamicRendering,
+        Self::Synchronization2,
+        Self::Maintenance4,
+        Self::TaskShader,
+        Self::MeshShader,
+        Self::AccelerationStructure,
+        Self::RayTracingPipeline,
+        Self::RayQuery,
+    ];
+
+    /// The feature's member name in the Vulkan feature structs.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::SamplerAnisotropy => "samplerAnisotropy",
+            Self::FillModeNonSolid => "fillModeNonSolid",
+            Self::WideLines => "wideLines",
+            Self::DepthClamp => "depthClamp",
+            Self::MultiDrawIndirect => "multiDrawIndirect",
+            Self::DrawIndirectFirstInstance => "drawIndirectFirstInstance",
+            Self::GeometryShader => "geometryShader",
+            Self::TessellationShader => "tessellationShader",
+            Self::PipelineStatisticsQuery => "pipelineStatisticsQuery",
+            Self::OcclusionQueryPrecise => "occlusionQueryPrecise",
+            Self::ShaderInt64 => "shaderInt64",
+            Self::ShaderSampledImageArrayDynamicIndexing => "shaderSampledImageArrayDynamicIndexing",
+            Self::ShaderDrawParameters => "shaderDrawParameters",
+            Self::Multiview => "multiview",
+            Self::TimelineSemaphore => "timelineSemaphore",
+            Self::BufferDeviceAddress => "bufferDeviceAddress",
+            Self::DescriptorIndexing => "descriptorIndexing",
+            Self::RuntimeDescriptorArray => "runtimeDescriptorArray",
+            Self::DescriptorBindingPartiallyBound => "descriptorBindingPartiallyBound",
+            Self::DescriptorBindingVariableDescriptorCount => "descriptorBindingVariableDescriptorCount",
+            Self::DescriptorBindingSampledImageUpdateAfterBind => "descriptorBindingSampledImageUpdateAfterBind",
+            Self::ShaderSampledImageArrayNonUniformIndexing => "shaderSampledImageArrayNonUniformIndexing",
+            Self::DrawIndirectCount => "drawIndirectCount",
+            Self::ScalarBlockLayout => "scalarBlockLayout",
+            Self::HostQueryReset => "hostQueryReset",
+            Self::DynamicRendering => "dynamicRendering",
+            Self::Synchronization2 => "synchronization2",
+            Self::Maintenance4 => "maintenance4",
+            Self::TaskShader => "taskShader",
+            Self::MeshShader => "meshShader",
+            Self::AccelerationStructure => "accelerationStructure",
+            Self::RayTracingPipeline => "rayTracingPipeline",
+            Self::RayQuery => "rayQuery",
+        }
+    }
+
+    /// Lowest Vulkan version (of both instance and device) the feature can be queried with.
+    fn api_version(self) -> u32 {
+        match self {
+            Self::ShaderDrawParameters | Self::Multiview => vk::API_VERSION_1_1,
+            Self::TimelineSemaphore
+            | Self::BufferDeviceAddress
+            | Self::DescriptorIndexing
+            | Self::RuntimeDescriptorArray
+            | Self::DescriptorBindingPartiallyBound
+            | Self::DescriptorBindingVariableDescriptorCount
+            | Self::DescriptorBindingSampledImageUpdateAfterBind
+            | Self::ShaderSampledImageArrayNonUniformIndexing
+            | Self::DrawIndirectCount
+            | Self::ScalarBlockLayout
+            | Self::HostQueryReset
+            | Self::TaskShader
+            | Self::MeshShader
+            | Self::AccelerationStructure
+            | Self::RayTracingPipeline
+            | Self::RayQuery => vk::API_VERSION_1_2,
+            Self::DynamicRendering | Self::Synchronization2 | Self::Maintenance4 => vk::API_VERSION_1_3,
+            _ => vk::API_VERSION_1_0,
+        }
+    }
+
+    /// Device extensions the feature lives in, which are enabled along with it.
+    pub fn extensions(self) -> &'static [&'static CStr] {
+        const MESH_SHADER: &[&CStr] = &[ext::MeshShader::name()];
+        const ACCELERATION_STRUCTURE: &[&CStr] = &[khr::AccelerationStructure::name(), khr::DeferredHostOperations::name()];
+        const RAY_TRACING_PIPELINE: &[&CStr] = &[
+            khr::RayTracingPipeline::name(),
+            khr::AccelerationStructure::name(),
+            khr::DeferredHostOperations::name(),
+        ];
+        const RAY_QUERY: &[&CStr] = &[
+            vk::KhrRayQueryFn::name(),
+            khr::AccelerationStructure::name(),
+            khr::DeferredHostOperations::name(),
+        ];
+
+        match self {
+            Self::TaskShader | Self::MeshShader => MESH_SHADER,
+            Self::AccelerationStructure => ACCELERATION_STRUCTURE,
+            Self::RayTracingPipeline => RAY_TRACING_PIPELINE,
+            Self::RayQuery => RAY_QUERY,
+            _ => &[],
+        }
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Extensions and features a renderer needs (required) or can make use of (optional). Devices missing a required
+/// item are rejected during selection; optional items are enabled when present and reported through
+/// [`DeviceCapabilities`].
+#[derive(Debug, Clone, Default)]
+pub struct DeviceRequirements {
+    required_extensions: BTreeSet<CString>,
+    optional_extensions: BTreeSet<CString>,
+    required_features: BTreeSet<Feature>,
+    optional_features: BTreeSet<Feature>,
+}
+
+impl DeviceRequirements {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rejects devices without the extension `name`, on top of the ones the requested features need. The name is
+    /// copied, so it can come from a config file as well as from ash.
+    pub fn require_extension(mut self, name: &CStr) -> Self {
+        self.required_extensions.insert(name.to_owned());
+        self
+    }
+
+    pub fn optional_extension(mut self, name: &CStr) -> Self {
+        self.optional_extensions.insert(name.to_owned());
+        self
+    }
+
+    pub fn require_feature(mut self, feature: Feature) -> Self {
+        self.required_features.insert(feature);
+        self
+    }
+
+    pub fn optional_feature(mut self, feature: Feature) -> Self {
+        self.optional_features.insert(feature);
+        self
+    }
+
+    /// Adds everything `other` asks for. An item required by either side stays required.
+    pub fn merge(mut self, other: &DeviceRequirements) -> Self {
+        self.required_extensions.extend(other.required_extensions.iter().cloned());
+        self.optional_extensions.extend(other.optional_extensions.iter().cloned());
+        self.required_features.extend(other.required_features.iter().copied());
+        self.optional_features.extend(other.optional_features.iter().copied());
+        self
+    }
+
+    /// The first required item `support` lacks, described for logging.
+    pub fn missing(&self, support: &DeviceSupport) -> Option<String> {
+        if let Some(extension) = self.missing_extension(support) {
+            return Some(format!("missing extension {}", extension.to_string_lossy()));
+        }
+
+        self.required_features.iter()
+            .find(|&&feature| !support.has_feature(feature))
+            .map(|feature| format!("missing feature {}", feature))
+    }
+
+    /// The first required extension, asked for directly or by a required feature, that `support` lacks.
+    pub fn missing_extension(&self, support: &DeviceSupport) -> Option<CString> {
+        self.required_extensions.iter()
+            .map(CString::as_c_str)
+            .chain(self.required_features.iter().flat_map(|feature| feature.extensions().iter().copied()))
+            .find(|&extension| !support.has_extension(extension))
+            .map(CStr::to_owned)
+    }
+
+    /// The capabilities to enable on a device with `support`: every required item plus the optional ones it has.
+    /// Call [`missing`](Self::missing) first.
+    pub fn resolve(&self, support: &DeviceSupport) -> DeviceCapabilities {
+        let features: BTreeSet<Feature> = self.required_features.iter()
+            .chain(self.optional_features.iter().filter(|&&feature| {
+                support.has_feature(feature) && feature.extensions().iter().all(|&extension| support.has_extension(extension))
+            }))
+            .copied()
+            .collect();
+
+        let extensions = self.required_extensions.iter()
+            .chain(self.optional_extensions.iter().filter(|extension| support.has_extension(extension)))
+            .map(CString::as_c_str)
+            .chain(features.iter().flat_map(|feature| feature.extensions().iter().copied()))
+            .map(CStr::to_owned)
+            .collect();
+
+        DeviceCapabilities {
+            api_version: support.api_version,
+            extensions,
+            features,
+        }
+    }
+
+    pub fn optional_features(&self) -> impl Iterator<Item = Feature> + '_ {
+        self.optional_features.iter().copied()
+    }
+}
+
+/// The feature structs queried from or passed to a device, chained through `p_next` only while a call needs them.
+#[derive(Default)]
+struct FeatureStructs {
+    core: vk::PhysicalDeviceFeatures,
+    vulkan11: vk::PhysicalDeviceVulkan11Features,
+    vulkan12: vk::PhysicalDeviceVulkan12Features,
+    vulkan13: vk::PhysicalDeviceVulkan13Features,
+    mesh_shader: vk::PhysicalDeviceMeshShaderFeaturesEXT,
+    acceleration_structure: vk::PhysicalDeviceAccelerationStructureFeaturesKHR,
+    ray_tracing_pipeline: vk::PhysicalDeviceRayTracingPipelineFeaturesKHR,
+    ray_query: vk::PhysicalDeviceRayQueryFeaturesKHR,
+}
+
+impl FeatureStructs {
+    fn flag(&mut self, feature: Feature) -> &mut vk::Bool32 {
+        match feature {
+            Feature::SamplerAnisotropy => &mut self.core.sampler_anisotropy,
+            Feature::FillModeNonSolid => &mut self.core.fill_mode_non_solid,
+            Feature::WideLines => &mut self.core.wide_lines,
+            Feature::DepthClamp => &mut self.core.depth_clamp,
+            Feature::MultiDrawIndirect => &mut self.core.multi_draw_indirect,
+            Feature::DrawIndirectFirstInstance => &mut self.core.draw_indirect_first_instance,
+            Feature::GeometryShader => &mut self.core.geometry_shader,
+            Feature::TessellationShader => &mut self.core.tessellation_shader,
+            Feature::PipelineStatisticsQuery => &mut self.core.pipeline_statistics_query,
+            Feature::OcclusionQueryPrecise => &mut self.core.occlusion_query_precise,
+            Feature::ShaderInt64 => &mut self.core.shader_int64,
+            Feature::ShaderSampledImageArrayDynamicIndexing => &mut self.core.shader_sampled_image_array_dynamic_indexing,
+            Feature::ShaderDrawParameters => &mut self.vulkan11.shader_draw_parameters,
+            Feature::Multiview => &mut self.vulkan11.multiview,
+            Feature::TimelineSemaphore => &mut self.vulkan12.timeline_semaphore,
+            Feature::BufferDeviceAddress => &mut self.vulkan12.buffer_device_address,
+            Feature::DescriptorIndexing => &mut self.vulkan12.descriptor_indexing,
+            Feature::RuntimeDescriptorArray => &mut self.vulkan12.runtime_descriptor_array,
+            Feature::DescriptorBindingPartiallyBound => &mut self.vulkan12.descriptor_binding_partially_bound,
+            Feature::DescriptorBindingVariableDescriptorCount => &mut self.vulkan12.descriptor_binding_variable_descriptor_count,
+            Feature::DescriptorBindingSampledImageUpdateAfterBind => &mut self.vulkan12.descriptor_binding_sampled_image_update_after_bind,
+            Feature::ShaderSampledImageArrayNonUniformIndexing => &mut self.vulkan12.shader_sampled_image_array_non_uniform_indexing,
+            Feature::DrawIndirectCount => &mut self.vulkan12.draw_indirect_count,
+            Feature::ScalarBlockLayout => &mut self.vulkan12.scalar_block_layout,
+            Feature::HostQueryReset => &mut self.vulkan12.host_query_reset,
+            Feature::DynamicRendering => &mut self.vulkan13.dynamic_rendering,
+            Feature::Synchronization2 => &mut self.vulkan13.synchronization2,
+            Feature::Maintenance4 => &mut self.vulkan13.maintenance4,
+            Feature::TaskShader => &mut self.mesh_shader.task_shader,
+            Feature::MeshShader => &mut self.mesh_shader.mesh_shader,
+            Feature::AccelerationStructure => &mut self.acceleration_structure.acceleration_structure,
+            Feature::RayTracingPipeline => &mut self.ray_tracing_pipeline.ray_tracing_pipeline,
+            Feature::RayQuery => &mut self.ray_query.ray_query,
+        }
+    }
+
+    /// Links the structs usable with `api_version` and `has_extension` into a `VkPhysicalDeviceFeatures2` chain.
+    /// `self` must not move while the returned struct is in use.
+    unsafe fn chain(&mut self, api_version: u32, has_extension: impl Fn(&CStr) -> bool) -> vk::PhysicalDeviceFeatures2 {
+        let mut structs: Vec<*mut vk::BaseOutStructure> = Vec::new();
+        if api_version >= vk::API_VERSION_1_1 {
+            structs.push(&mut self.vulkan11 as *mut _ as *mut vk::BaseOutStructure);
+        }
+        if api_version >= vk::API_VERSION_1_2 {
+            structs.push(&mut self.vulkan12 as *mut _ as *mut vk::BaseOutStructure);
+        }
+        if api_version >= vk::API_VERSION_1_3 {
+            structs.push(&mut self.vulkan13 as *mut _ as *mut vk::BaseOutStructure);
+        }
+        if api_version >= vk::API_VERSION_1_2 {
+            if has_extension(ext::MeshShader::name()) {
+                structs.push(&mut self.mesh_shader as *mut _ as *mut vk::BaseOutStructure);
+            }
+            if has_extension(khr::AccelerationStructure::name()) {
+                structs.push(&mut self.acceleration_structure as *mut _ as *mut vk::BaseOutStructure);
+            }
+            if has_extension(khr::RayTracingPipeline::name()) {
+                structs.push(&mut self.ray_tracing_pipeline as *mut _ as *mut vk::BaseOutStructure);
+            }
+            if has_extension(vk::KhrRayQueryFn::name()) {
+                structs.push(&mut self.ray_query as *mut _ as *mut vk::BaseOutStructure);
+            }
+        }
+
+        let mut next = std::ptr::null_mut();
+        for &structure in structs.iter().rev() {
+            (*structure).p_next = next;
+            next = structure;
+        }
+
+        vk::PhysicalDeviceFeatures2 {
+            p_next: next as *mut c_void,
+            features: self.core,
+            ..Default::default()
+        }
+    }
+}
+
+/// What a physical device supports, as far as [`DeviceRequirements`] can ask for it.
+pub struct DeviceSupport {
+    api_version: u32,
+    extensions: BTreeSet<CString>,
+    features: BTreeSet<Feature>,
+}
+
+impl DeviceSupport {
+    /// Queries extensions and features. Versioned feature structs are only queried when both `instance_api_version`
+    /// and the device's own version allow it.
+    pub unsafe fn query(
+        instance: &ash::Instance,
+        instance_api_version: u32,
+        physical_device: vk::PhysicalDevice,
+    ) -> anyhow::Result<Self> {
+        let device_api_version = instance.get_physical_device_properties(physical_device).api_version;
+        let api_version = instance_api_version.min(device_api_version);
+
+        let extensions: BTreeSet<CString> = instance.enumerate_device_extension_properties(physical_device)?
+            .iter()
+            .map(|properties| CStr::from_ptr(properties.extension_name.as_ptr()).to_owned())
+            .collect();
+
+        let mut structs = FeatureStructs::default();
+        if api_version >= vk::API_VERSION_1_1 {
+            let mut features2 = structs.chain(api_version, |name| extensions.contains(name));
+            instance.get_physical_device_features2(physical_device, &mut features2);
+            structs.core = features2.features;
+        } else {
+            structs.core = instance.get_physical_device_features(physical_device);
+        }
+
+        let features = Feature::ALL.iter()
+            .copied()
+            .filter(|&feature| api_version >= feature.api_version() && *structs.flag(feature) == vk::TRUE)
+            .collect();
+
+        Ok(Self {
+            api_version,
+            extensions,
+            features,
+        })
+    }
+
+    pub fn api_version(&self) -> u32 {
+        self.api_version
+    }
+
+    pub fn has_extension(&self, name: &CStr) -> bool {
+        self.extensions.contains(name)
+    }
+
+    pub fn has_feature(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+}
+
+/// The extensions and features a device was created with. Renderer code branches on these instead of querying
+/// the physical device again.
+#[derive(Debug, Clone)]
+pub struct DeviceCapabilities {
+    api_version: u32,
+    extensions: BTreeSet<CString>,
+    features: BTreeSet<Feature>,
+}
+
+impl DeviceCapabilities {
+    pub fn api_version(&self) -> u32 {
+        self.api_version
+    }
+
+    pub fn has_extension(&self, name: &CStr) -> bool {
+        self.extensions.contains(name)
+    }
+
+    pub fn has_feature(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+
+    pub fn extensions(&self) -> impl Iterator<Item = &CStr> {
+        self.extensions.iter().map(CString::as_c_str)
+    }
+
+    pub fn features(&self) -> impl Iterator<Item = Feature> + '_ {
+        self.features.iter().copied()
+    }
+
+    /// Calls `create` with a `VkPhysicalDeviceFeatures2` chain enabling exactly these features, to be pushed onto a
+    /// `VkDeviceCreateInfo`.
+    pub unsafe fn with_feature_chain<R>(&self, create: impl FnOnce(&mut vk::PhysicalDeviceFeatures2) -> R) -> R {
+        let mut structs = FeatureStructs::default();
+        for &feature in &self.features {
+            *structs.flag(feature) = vk::TRUE;
+        }
+
+        let mut features2 = structs.chain(self.api_version, |name| self.extensions.contains(name));
+        create(&mut features2)
+    }
+
+    /// The core 1.0 features these capabilities enable.
+    pub fn core_features(&self) -> vk::PhysicalDeviceFeatures {
+        let mut structs = FeatureStructs::default();
+        for &feature in &self.features {
+            *structs.flag(feature) = vk::TRUE;
+        }
+
+        structs.core
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use ash::vk;
+
+/// Everything that distinguishes one sampler from another. Samplers are deduplicated by this description, so
+/// textures share a handful of them.
+#[derive(Debug, Clone, Copy)]
+pub struct SamplerDesc {
+    pub mag_filter: vk::Filter,
+    pub min_filter: vk::Filter,
+    pub mipmap_mode: vk::SamplerMipmapMode,
+    pub address_mode: [vk::SamplerAddressMode; 3],
+    /// Requested anisotropy; clamped to the device limit and ignored when the device doesn't support it.
+    pub max_anisotropy: Option<f32>,
+    pub compare_op: Option<vk::CompareOp>,
+    pub border_color: vk::BorderColor,
+    pub min_lod: f32,
+    pub max_lod: f32,
+}
+
+impl SamplerDesc {
+    pub const fn linear() -> Self {
+        Self {
+            mag_filter: vk::Filter::LINEAR,
+            min_filter: vk::Filter::LINEAR,
+            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+            address_mode: [vk::SamplerAddressMode::REPEAT; 3],
+            max_anisotropy: None,
+            compare_op: None,
+            border_color: vk::BorderColor::FLOAT_OPAQUE_BLACK,
+            min_lod: 0.0,
+            max_lod: vk::LOD_CLAMP_NONE,
+        }
+    }
+
+    pub const fn nearest() -> Self {
+        Self {
+            mag_filter: vk::Filter::NEAREST,
+            min_filter: vk::Filter::NEAREST,
+            mipmap_mode: vk::SamplerMipmapMode::NEAREST,
+            ..Self::linear()
+        }
+    }
+
+    /// Linear filtering with clamped edges, for render targets sampled by later passes.
+    pub const fn linear_clamp() -> Self {
+        Self {
+            address_mode: [vk::SamplerAddressMode::CLAMP_TO_EDGE; 3],
+            ..Self::linear()
+        }
+    }
+
+    /// Linear depth comparison with clamped edges, e.g. `CompareOp::LESS`. Bound as a combined image sampler and
+    /// read through a `sampler2DShadow`, every lookup returns the filtered result of four comparisons (hardware PCF).
+    pub const fn comparison(compare_op: vk::CompareOp) -> Self {
+        Self {
+            address_mode: [vk::SamplerAddressMode::CLAMP_TO_EDGE; 3],
+            compare_op: Some(compare_op),
+            mipmap_mode: vk::SamplerMipmapMode::NEAREST,
+            ..Self::linear()
+        }
+    }
+
+    /// [`comparison`](Self::comparison) with white borders, so nothing outside the shadow map is in shadow.
+    pub const fn shadow() -> Self {
+        Self {
+            address_mode: [vk::SamplerAddressMode::CLAMP_TO_BORDER; 3],
+            border_color: vk::BorderColor::FLOAT_OPAQUE_WHITE,
+            ..Self::comparison(vk::CompareOp::LESS_OR_EQUAL)
+        }
+    }
+
+    pub const fn with_address_mode(mut self, address_mode: vk::SamplerAddressMode) -> Self {
+        self.address_mode = [address_mode; 3];
+        self
+    }
+
+    /// Turns the sampler into a comparison sampler, or back with `None`.
+    pub const fn with_compare_op(mut self, compare_op: Option<vk::CompareOp>) -> Self {
+        self.compare_op = compare_op;
+        self
+    }
+
+    pub const fn with_anisotropy(mut self, max_anisotropy: f32) -> Self {
+        self.max_anisotropy = Some(max_anisotropy);
+        self
+    }
+
+    fn key(&self) -> impl Eq + Hash {
+        (
+            self.mag_filter,
+            self.min_filter,
+            self.mipmap_mode,
+            self.address_mode,
+            self.max_anisotropy.map(f32::to_bits),
+            self.compare_op,
+            self.border_color,
+            self.min_lod.to_bits(),
+            self.max_lod.to_bits(),
+        )
+    }
+}
+
+impl Default for SamplerDesc {
+    fn default() -> Self {
+        Self::linear()
+    }
+}
+
+impl PartialEq for SamplerDesc {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for SamplerDesc {}
+
+impl Hash for SamplerDesc {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
+/// Owned by the device; samplers live until the device is destroyed.
+pub struct SamplerCache {
+    samplers: HashMap<SamplerDesc, vk::Sampler>,
+    anisotropy_limit: Option<f32>,
+}
+
+impl SamplerCache {
+    /// `anisotropy_limit` is `maxSamplerAnisotropy` when the `samplerAnisotropy` feature is enabled, else `None`.
+    pub fn new(anisotropy_limit: Option<f32>) -> Self {
+        Self {
+            samplers: HashMap::new(),
+            anisotropy_limit,
+        }
+    }
+
+    pub unsafe fn get(&mut self, device: &ash::Device, desc: &SamplerDesc) -> anyhow::Result<vk::Sampler> {
+        if let Some(&sampler) = self.samplers.get(desc) {
+            return Ok(sampler);
+        }
+
+        let anisotropy = desc.max_anisotropy
+            .zip(self.anisotropy_limit)
+            .map(|(requested, limit)| requested.min(limit))
+            .filter(|&anisotropy| anisotropy > 1.0);
+
+        let create_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(desc.mag_filter)
+            .min_filter(desc.min_filter)
+            .mipmap_mode(desc.mipmap_mode)
+            .address_mode_u(desc.address_mode[0])
+            .address_mode_v(desc.address_mode[1])
+            .address_mode_w(desc.address_mode[2])
+            .anisotropy_enable(anisotropy.is_some())
+            .max_anisotropy(anisotropy.unwrap_or(1.0))
+            .compare_enable(desc.compare_op.is_some())
+            .compare_op(desc.compare_op.unwrap_or(vk::CompareOp::ALWAYS))
+            .border_color(desc.border_color)
+            .min_lod(desc.min_lod)
+            .max_lod(desc.max_lod);
+
+        let sampler = device.create_sampler(&create_info, None)?;
+        self.samplers.insert(*desc, sampler);
+        Ok(sampler)
+    }
+
+    pub fn len(&self) -> usize {
+        self.samplers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samplers.is_empty()
+    }
+
+    pub unsafe fn destroy(&mut self, device: &ash::Device) {
+        for (_, sampler) in self.samplers.drain() {
+            device.destroy_sampler(sampler, None);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use anyhow::Context;
+use ash::vk;
+use log::warn;
+use thiserror::Error;
+use crate::device::Device;
+use crate::pipeline::ShaderStage;
+use crate::reflect::{reflect_shader, spirv_words, ShaderReflection};
+
+#[derive(Error, Debug)]
+pub enum ShaderError {
+    #[error("Cannot tell the stage of shader '{0}'; pass it explicitly")]
+    UnknownStage(String),
+}
+
+/// A `vk::ShaderModule` together with what was reflected from its SPIR-V. Modules only need to live until the
+/// pipelines using them are built.
+pub struct ShaderModule {
+    device: Arc<Device>,
+    handle: vk::ShaderModule,
+    name: String,
+    stage: vk::ShaderStageFlags,
+    entry_point: CString,
+    reflection: Option<ShaderReflection>,
+    path: Option<PathBuf>,
+}
+
+impl ShaderModule {
+    /// Creates a module from SPIR-V bytes, e.g. from `include_bytes!`. The bytes are copied, so they don't need to
+    /// be 4-byte aligned, but their length must be a multiple of 4. The stage and entry point come from reflection.
+    pub unsafe fn from_bytes(device: &Arc<Device>, name: &str, spirv: &[u8]) -> anyhow::Result<Self> {
+        Self::create(device, name, spirv, None)
+    }
+
+    /// Like [`from_bytes`](Self::from_bytes), for SPIR-V the reflection parser cannot handle.
+    pub unsafe fn from_bytes_with_stage(
+        device: &Arc<Device>,
+        name: &str,
+        spirv: &[u8],
+        stage: vk::ShaderStageFlags,
+    ) -> anyhow::Result<Self> {
+        Self::create(device, name, spirv, Some(stage))
+    }
+
+    pub unsafe fn from_file(device: &Arc<Device>, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let spirv = std::fs::read(path).with_context(|| format!("Failed to read shader {}", path.display()))?;
+
+        let module = Self::create(device, &path.display().to_string(), &spirv, None)?;
+        Ok(module.with_path(path))
+    }
+
+    pub(crate) fn with_path(mut self, path: &Path) -> Self {
+        self.path = Some(path.to_owned());
+        self
+    }
+
+    unsafe fn create(
+        device: &Arc<Device>,
+        name: &str,
+        spirv: &[u8],
+        stage: Option<vk::ShaderStageFlags>,
+    ) -> anyhow::Result<Self> {
+        let words = spirv_words(spirv).with_context(|| format!("Invalid SPIR-V in shader '{}'", name))?;
+
+        let reflection = match reflect_shader(spirv) {
+            Ok(reflection) => Some(reflection),
+            Err(err) => {
+                warn!("Could not reflect shader '{}': {}", name, err);
+                None
+            }
+        };
+
+        let stage = stage
+            .or(reflection.as_ref().map(|reflection| reflection.stage))
+            .ok_or_else(|| ShaderError::UnknownStage(name.to_owned()))?;
+
+        let entry_point = reflection.as_ref()
+            .map_or("main", |reflection| reflection.entry_point.as_str());
+        let entry_point = CString::new(entry_point)?;
+
+        let create_info = vk::ShaderModuleCreateInfo::builder()
+            .code(&words);
+
+        Ok(Self {
+            device: device.clone(),
+            handle: device.create_shader_module(&create_info, None)?,
+            name: name.to_owned(),
+            stage,
+            entry_point,
+            reflection,
+            path: None,
+        })
+    }
+
+    pub fn handle(&self) -> vk::ShaderModule {
+        self.handle
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn stage(&self) -> vk::ShaderStageFlags {
+        self.stage
+    }
+
+    pub fn reflection(&self) -> Option<&ShaderReflection> {
+        self.reflection.as_ref()
+    }
+
+    /// The file the module was loaded or compiled from, if any.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The pipeline stage description for this module.
+    pub fn stage_info(&self) -> ShaderStage {
+        ShaderStage {
+            stage: self.stage,
+            module: self.handle,
+            entry_point: self.entry_point.clone(),
+        }
+    }
+}
+
+impl Drop for ShaderModule {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_shader_module(self.handle, None);
+        }
+    }
+}
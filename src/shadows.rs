@@ -0,0 +1,411 @@
+use std::sync::Arc;
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Point3, Rad, SquareMatrix, Vector3, Vector4, Zero};
+use crate::buffer::PerFrameUniform;
+use crate::commands::submit_one_time;
+use crate::device::Device;
+use crate::glsl::GlslCompiler;
+use crate::image::{Image, ImageDesc};
+use crate::lighting::{DirectionalLight, Light, LightKind, MAX_LIGHTS};
+use crate::pipeline::{set_depth_bias, DepthState, GraphicsPipeline, GraphicsPipelineBuilder, PipelineTarget};
+use crate::render_graph::{ImageState, ImportedImage};
+use crate::renderer3d::{Camera, InstanceData, MeshVertex, VULKAN_CLIP};
+use crate::rendering::RenderingFormats;
+use crate::shader::ShaderModule;
+
+const SHADOW_VERT: &str = include_str!("../shaders/shadow.vert");
+
+pub const SHADOW_FORMAT: vk::Format = vk::Format::D32_SFLOAT;
+
+/// Cascades the directional light's shadow can be split into. Must match the shaders.
+pub const MAX_CASCADES: usize = 4;
+
+/// Spot lights casting shadows beyond this many in a frame are lit without them. Must match the shaders.
+pub const MAX_SHADOWED_SPOT_LIGHTS: usize = 4;
+
+/// Cascades take the first layers of the shadow map array, shadowed spot lights the ones after them.
+const LAYER_COUNT: u32 = (MAX_CASCADES + MAX_SHADOWED_SPOT_LIGHTS) as u32;
+
+const SPOT_SHADOW_NEAR: f32 = 0.05;
+
+/// Resolution and filtering of shadow maps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShadowQuality {
+    /// Width and height of every shadow map.
+    pub resolution: u32,
+    /// Cascades of the directional light, up to [`MAX_CASCADES`]. 0 turns its shadows off.
+    pub cascades: u32,
+    /// How far from the camera the cascades reach, capped by its far plane.
+    pub distance: f32,
+    /// Spaces cascade splits evenly at 0 and logarithmically at 1.
+    pub split_lambda: f32,
+    /// PCF averages a square of `2 * pcf_radius + 1` texels on each side around every lookup.
+    pub pcf_radius: u32,
+    pub depth_bias_constant: f32,
+    pub depth_bias_slope: f32,
+}
+
+impl ShadowQuality {
+    pub const LOW: Self = Self {
+        resolution: 1024,
+        cascades: 2,
+        distance: 30.0,
+        split_lambda: 0.7,
+        pcf_radius: 0,
+        depth_bias_constant: 2.0,
+        depth_bias_slope: 2.5,
+    };
+
+    pub const MEDIUM: Self = Self {
+        resolution: 2048,
+        cascades: 3,
+        distance: 50.0,
+        split_lambda: 0.75,
+        pcf_radius: 1,
+        depth_bias_constant: 1.25,
+        depth_bias_slope: 1.75,
+    };
+
+    pub const HIGH: Self = Self {
+        resolution: 4096,
+        cascades: 4,
+        distance: 80.0,
+        split_lambda: 0.8,
+        pcf_radius: 2,
+        depth_bias_constant: 1.0,
+        depth_bias_slope: 1.5,
+    };
+}
+
+impl Default for ShadowQuality {
+    fn default() -> Self {
+        Self::MEDIUM
+    }
+}
+
+/// What the lighting shaders see of the directional light and the shadow maps, in set 0, binding 4.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ShadowUniform {
+    pub cascade_view_projections: [[[f32; 4]; 4]; MAX_CASCADES],
+    pub spot_view_projections: [[[f32; 4]; 4]; MAX_SHADOWED_SPOT_LIGHTS],
+    /// View space depth each cascade ends at.
+    pub cascade_splits: [f32; 4],
+    /// Direction towards the directional light, then its intensity.
+    pub sun_direction: [f32; 4],
+    /// Linear RGB of the directional light, then the ambient term.
+    pub sun_color: [f32; 4],
+    /// Cascades in use, the PCF radius and the number of shadowed spot lights.
+    pub counts: [u32; 4],
+    /// Size of a shadow map texel in UV space.
+    pub texel_size: [f32; 4],
+}
+
+unsafe impl Zeroable for ShadowUniform {}
+unsafe impl Pod for ShadowUniform {}
+
+/// A shadow map to render this frame.
+#[derive(Debug, Clone, Copy)]
+pub struct ShadowView {
+    pub layer: u32,
+    pub view_projection: Matrix4<f32>,
+}
+
+/// Depth-only shadow maps for the cascades of the directional light and for spot lights, as layers of one array
+/// image. Between frames the whole array stays in `SHADER_READ_ONLY_OPTIMAL`, so it can be bound even when
+/// nothing renders into it.
+///
+/// Rendering them needs dynamic rendering; without it nothing casts shadows.
+pub struct ShadowMaps {
+    device: Arc<Device>,
+    quality: ShadowQuality,
+    image: Image,
+    layer_views: Vec<vk::ImageView>,
+    pipeline: Option<GraphicsPipeline>,
+    uniform: PerFrameUniform<ShadowUniform>,
+    views: Vec<ShadowView>,
+}
+
+impl ShadowMaps {
+    pub unsafe fn new(
+        device: &Arc<Device>,
+        compiler: &GlslCompiler,
+        target: &PipelineTarget,
+        quality: ShadowQuality,
+        frames_in_flight: usize,
+    ) -> anyhow::Result<Self> {
+        let pipeline = match target {
+            PipelineTarget::Dynamic(_) => Some(create_pipeline(device, compiler)?),
+            PipelineTarget::RenderPass { .. } => None,
+        };
+
+        let (image, layer_views) = create_maps(device, quality.resolution)?;
+
+        Ok(Self {
+            device: device.clone(),
+            quality,
+            image,
+            layer_views,
+            pipeline,
+            uniform: PerFrameUniform::new(device, "shadows", frames_in_flight)?,
+            views: Vec::new(),
+        })
+    }
+
+    pub fn quality(&self) -> &ShadowQuality {
+        &self.quality
+    }
+
+    /// Recreates the shadow maps when the resolution changes, waiting for the device to go idle first, and returns
+    /// whether it did. Descriptors of the old [`view`](Self::view) must be rewritten then.
+    pub unsafe fn set_quality(&mut self, quality: ShadowQuality) -> anyhow::Result<bool> {
+        let resized = quality.resolution != self.quality.resolution;
+        if resized {
+            self.device.device_wait_idle()?;
+            self.destroy_layer_views();
+            (self.image, self.layer_views) = create_maps(&self.device, quality.resolution)?;
+        }
+
+        self.quality = quality;
+        Ok(resized)
+    }
+
+    /// Every layer, for sampling as a `sampler2DArrayShadow` with `SamplerDesc::shadow`.
+    pub fn view(&self) -> vk::ImageView {
+        self.image.view()
+    }
+
+    pub fn uniform_info(&self, frame_index: usize) -> vk::DescriptorBufferInfo {
+        self.uniform.descriptor_info(frame_index)
+    }
+
+    /// `None` without dynamic rendering.
+    pub fn pipeline(&self) -> Option<&GraphicsPipeline> {
+        self.pipeline.as_ref()
+    }
+
+    /// The shadow maps [`update`](Self::update) picked for this frame.
+    pub fn views(&self) -> &[ShadowView] {
+        &self.views
+    }
+
+    /// One layer of the array for the render graph. Its contents are replaced every frame, so it starts out
+    /// undefined, but only after the previous frame's fragment shaders are done with it.
+    pub fn imported_layer(&self, layer: u32) -> ImportedImage {
+        let range = vk::ImageSubresourceRange {
+            base_array_layer: layer,
+            layer_count: 1,
+            ..self.image.full_range()
+        };
+
+        ImportedImage::new(self.image.handle(), self.layer_views[layer as usize], self.image.extent(), range)
+            .with_initial_state(ImageState::new(vk::ImageLayout::UNDEFINED, vk::PipelineStageFlags::FRAGMENT_SHADER, vk::AccessFlags::empty()))
+            .with_final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+    }
+
+    pub unsafe fn set_depth_bias(&self, command_buffer: vk::CommandBuffer) {
+        set_depth_bias(&self.device, command_buffer, self.quality.depth_bias_constant, self.quality.depth_bias_slope);
+    }
+
+    /// Fits the cascades to `camera` and picks the spot lights that get a shadow map, then writes the uniform of
+    /// `frame_index`. Returns the shadow map slot of each light, as `GpuLight::new` takes it.
+    pub unsafe fn update(
+        &mut self,
+        frame_index: usize,
+        camera: &Camera,
+        extent: vk::Extent2D,
+        sun: &DirectionalLight,
+        lights: &[Light],
+    ) -> anyhow::Result<Vec<Option<u32>>> {
+        let mut uniform = ShadowUniform::zeroed();
+        let direction = sun.direction.normalize();
+        uniform.sun_direction = [-direction.x, -direction.y, -direction.z, sun.intensity];
+        uniform.sun_color = [sun.color[0], sun.color[1], sun.color[2], sun.ambient];
+        uniform.texel_size = [1.0 / self.quality.resolution as f32, 0.0, 0.0, 0.0];
+        uniform.counts[1] = self.quality.pcf_radius;
+
+        self.views.clear();
+        let mut slots = vec![None; lights.len().min(MAX_LIGHTS)];
+
+        if self.pipeline.is_some() {
+            if sun.casts_shadows {
+                let cascades = fit_cascades(camera, extent, direction, &self.quality);
+                for (index, (split, view_projection)) in cascades.into_iter().enumerate() {
+                    uniform.cascade_splits[index] = split;
+                    uniform.cascade_view_projections[index] = view_projection.into();
+                    self.views.push(ShadowView {
+                        layer: index as u32,
+                        view_projection,
+                    });
+                }
+
+                uniform.counts[0] = self.views.len() as u32;
+            }
+
+            let shadowed = lights.iter()
+                .take(MAX_LIGHTS)
+                .enumerate()
+                .filter_map(|(index, light)| spot_view_projection(light).map(|view_projection| (index, view_projection)))
+                .take(MAX_SHADOWED_SPOT_LIGHTS);
+
+            for (slot, (index, view_projection)) in shadowed.enumerate() {
+                slots[index] = Some(slot as u32);
+                uniform.spot_view_projections[slot] = view_projection.into();
+                uniform.counts[2] += 1;
+                self.views.push(ShadowView {
+                    layer: (MAX_CASCADES + slot) as u32,
+                    view_projection,
+                });
+            }
+        }
+
+        self.uniform.write(frame_index, &uniform)?;
+        Ok(slots)
+    }
+
+    unsafe fn destroy_layer_views(&mut self) {
+        for view in self.layer_views.drain(..) {
+            self.device.destroy_image_view(view, None);
+        }
+    }
+}
+
+impl Drop for ShadowMaps {
+    fn drop(&mut self) {
+        unsafe {
+            self.destroy_layer_views();
+        }
+    }
+}
+
+unsafe fn create_pipeline(device: &Arc<Device>, compiler: &GlslCompiler) -> anyhow::Result<GraphicsPipeline> {
+    let vertex = ShaderModule::from_bytes_with_stage(
+        device,
+        "shadow.vert",
+        &compiler.compile_source(SHADOW_VERT, vk::ShaderStageFlags::VERTEX, "shadow.vert")?,
+        vk::ShaderStageFlags::VERTEX,
+    )?;
+
+    // Both faces are drawn, so thin casters and open meshes still shadow; the bias keeps surfaces off themselves.
+    GraphicsPipelineBuilder::new()
+        .shader(&vertex)
+        .vertex::<MeshVertex>(0)
+        .instance::<InstanceData>(1)
+        .cull_mode(vk::CullModeFlags::NONE)
+        .dynamic_depth_bias()
+        .depth_clamp(true)
+        .depth(DepthState::READ_WRITE)
+        .push_constants::<[[f32; 4]; 4]>(vk::ShaderStageFlags::VERTEX, 0)
+        .target(PipelineTarget::Dynamic(RenderingFormats::new(&[], Some(SHADOW_FORMAT))))
+        .build(device)
+}
+
+/// The array image and a view of each of its layers, transitioned for sampling.
+unsafe fn create_maps(device: &Arc<Device>, resolution: u32) -> anyhow::Result<(Image, Vec<vk::ImageView>)> {
+    let desc = ImageDesc::new_2d(
+        resolution,
+        resolution,
+        SHADOW_FORMAT,
+        vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+    ).array(LAYER_COUNT);
+    let image = Image::new(device, "shadow maps", &desc)?;
+
+    submit_one_time(device, device.queue_families().graphics, device.graphics_queue(), |command_buffer| {
+        image.transition(command_buffer, vk::ImageLayout::UNDEFINED, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+    })?;
+
+    let layer_views = (0..LAYER_COUNT)
+        .map(|layer| {
+            let create_info = vk::ImageViewCreateInfo::builder()
+                .image(image.handle())
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(SHADOW_FORMAT)
+                .subresource_range(vk::ImageSubresourceRange {
+                    base_array_layer: layer,
+                    layer_count: 1,
+                    ..image.full_range()
+                });
+
+            Ok(device.create_image_view(&create_info, None)?)
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    Ok((image, layer_views))
+}
+
+/// Splits the camera's view range into cascades and fits an orthographic projection to each. Returns where each
+/// cascade ends in view space and its view projection matrix.
+fn fit_cascades(camera: &Camera, extent: vk::Extent2D, direction: Vector3<f32>, quality: &ShadowQuality) -> Vec<(f32, Matrix4<f32>)> {
+    let count = quality.cascades.min(MAX_CASCADES as u32);
+    let near = camera.near;
+    let far = camera.far.min(quality.distance).max(near);
+    let aspect = extent.width.max(1) as f32 / extent.height.max(1) as f32;
+    let view = camera.view();
+
+    let mut start = near;
+    (1..=count)
+        .map(|index| {
+            let t = index as f32 / count as f32;
+            let logarithmic = near * (far / near).powf(t);
+            let linear = near + (far - near) * t;
+            let end = quality.split_lambda * logarithmic + (1.0 - quality.split_lambda) * linear;
+
+            let projection = VULKAN_CLIP * cgmath::perspective(camera.fov_y, aspect, start, end);
+            let inverse = (projection * view).invert().unwrap_or_else(Matrix4::identity);
+            let corners: Vec<Vector3<f32>> = [-1.0, 1.0].into_iter()
+                .flat_map(|x| [-1.0, 1.0].map(move |y| (x, y)))
+                .flat_map(|(x, y)| [0.0, 1.0].map(move |z| inverse * Vector4::new(x, y, z, 1.0)))
+                .map(|corner| corner.truncate() / corner.w)
+                .collect();
+
+            // A bounding sphere keeps the cascade the same size as the camera turns, so the texels don't change
+            // size either.
+            let center = corners.iter().fold(Vector3::zero(), |sum, corner| sum + corner) / corners.len() as f32;
+            let radius = corners.iter().map(|corner| (corner - center).magnitude()).fold(0.0, f32::max);
+            let radius = (radius * 16.0).ceil() / 16.0;
+
+            start = end;
+            (end, cascade_view_projection(Point3::from_vec(center), radius, direction, quality.resolution))
+        })
+        .collect()
+}
+
+fn cascade_view_projection(center: Point3<f32>, radius: f32, direction: Vector3<f32>, resolution: u32) -> Matrix4<f32> {
+    // Reaches past the sphere towards the light, for casters outside the camera's view.
+    let view = Matrix4::look_at_rh(center - direction * radius * 2.0, center, up_for(direction));
+    let projection = VULKAN_CLIP * cgmath::ortho(-radius, radius, -radius, radius, 0.0, radius * 3.0);
+    let mut view_projection = projection * view;
+
+    // Only moves the cascade by whole texels, so shadow edges don't shimmer as the camera moves.
+    let half_resolution = resolution as f32 / 2.0;
+    let origin = view_projection * Vector4::new(0.0, 0.0, 0.0, 1.0);
+    let (x, y) = (origin.x * half_resolution, origin.y * half_resolution);
+    view_projection.w.x += (x.round() - x) / half_resolution;
+    view_projection.w.y += (y.round() - y) / half_resolution;
+    view_projection
+}
+
+/// `None` for lights that don't cast shadows.
+fn spot_view_projection(light: &Light) -> Option<Matrix4<f32>> {
+    let LightKind::Spot { direction, outer_angle, .. } = light.kind else {
+        return None;
+    };
+
+    if !light.casts_shadows {
+        return None;
+    }
+
+    let fov = Rad((outer_angle.0 * 2.0).clamp(0.01, 3.0));
+    let view = Matrix4::look_at_rh(light.position, light.position + direction, up_for(direction));
+    let projection = VULKAN_CLIP * cgmath::perspective(fov, 1.0, SPOT_SHADOW_NEAR, light.range.max(SPOT_SHADOW_NEAR * 2.0));
+    Some(projection * view)
+}
+
+fn up_for(direction: Vector3<f32>) -> Vector3<f32> {
+    if direction.y.abs() > 0.99 {
+        Vector3::unit_z()
+    } else {
+        Vector3::unit_y()
+    }
+}
@@ -0,0 +1,224 @@
+use std::mem::offset_of;
+use std::sync::Arc;
+use anyhow::anyhow;
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use crate::buffer::Buffer;
+use crate::device::Device;
+use crate::pipeline::{Vertex, VertexAttribute};
+
+/// The vertex shader of the built-in materials for skinned meshes. It writes the same outputs as their static one.
+pub const SKINNED_MESH_VERT: &str = include_str!("../shaders/skinned_mesh.vert");
+
+/// A [`MeshVertex`](crate::renderer3d::MeshVertex) weighted to up to four joints, in locations 9 and 10 after
+/// what the instance data of static meshes takes.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SkinnedVertex {
+    pub position: [f32; 3],
+    pub normal: [f32; 3],
+    pub uv: [f32; 2],
+    pub joints: [u32; 4],
+    /// Should add up to 1.
+    pub weights: [f32; 4],
+}
+
+unsafe impl Zeroable for SkinnedVertex {}
+unsafe impl Pod for SkinnedVertex {}
+
+impl Vertex for SkinnedVertex {
+    fn attributes() -> Vec<VertexAttribute> {
+        vec![
+            VertexAttribute {
+                location: 0,
+                format: vk::Format::R32G32B32_SFLOAT,
+                offset: offset_of!(SkinnedVertex, position) as u32,
+            },
+            VertexAttribute {
+                location: 1,
+                format: vk::Format::R32G32B32_SFLOAT,
+                offset: offset_of!(SkinnedVertex, normal) as u32,
+            },
+            VertexAttribute {
+                location: 2,
+                format: vk::Format::R32G32_SFLOAT,
+                offset: offset_of!(SkinnedVertex, uv) as u32,
+            },
+            VertexAttribute {
+                location: 9,
+                format: vk::Format::R32G32B32A32_UINT,
+                offset: offset_of!(SkinnedVertex, joints) as u32,
+            },
+            VertexAttribute {
+                location: 10,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                offset: offset_of!(SkinnedVertex, weights) as u32,
+            },
+        ]
+    }
+}
+
+/// A blend shape, like a glTF morph target: offsets added to every vertex in proportion to the target's weight.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MorphTarget {
+    pub name: String,
+    /// One per vertex.
+    pub positions: Vec<[f32; 3]>,
+    /// One per vertex, or none to leave normals alone.
+    pub normals: Vec<[f32; 3]>,
+}
+
+impl MorphTarget {
+    pub fn new(name: &str, positions: Vec<[f32; 3]>) -> Self {
+        Self {
+            name: name.to_owned(),
+            positions,
+            normals: Vec::new(),
+        }
+    }
+
+    pub fn with_normals(mut self, normals: Vec<[f32; 3]>) -> Self {
+        self.normals = normals;
+        self
+    }
+}
+
+/// Vertices and triangle list indices of a skinned mesh on the CPU, with its morph targets.
+#[derive(Debug, Clone, Default)]
+pub struct SkinnedMeshData {
+    pub vertices: Vec<SkinnedVertex>,
+    pub indices: Vec<u32>,
+    pub morph_targets: Vec<MorphTarget>,
+}
+
+impl SkinnedMeshData {
+    pub fn new(vertices: Vec<SkinnedVertex>, indices: Vec<u32>) -> Self {
+        Self {
+            vertices,
+            indices,
+            morph_targets: Vec::new(),
+        }
+    }
+
+    pub fn with_morph_target(mut self, target: MorphTarget) -> Self {
+        self.morph_targets.push(target);
+        self
+    }
+
+    /// One more than the highest joint any vertex is weighted to.
+    pub fn joint_count(&self) -> usize {
+        self.vertices.iter()
+            .flat_map(|vertex| vertex.joints.iter().zip(vertex.weights).filter(|(_, weight)| *weight != 0.0))
+            .map(|(&joint, _)| joint as usize + 1)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Device local vertex and index buffers of a skinned mesh.
+pub struct SkinnedMesh {
+    vertex_buffer: Buffer,
+    index_buffer: Buffer,
+    index_count: u32,
+    morph_target_count: u32,
+}
+
+impl SkinnedMesh {
+    pub unsafe fn new(device: &Arc<Device>, name: &str, data: &SkinnedMeshData) -> anyhow::Result<Self> {
+        if data.vertices.is_empty() || data.indices.is_empty() {
+            return Err(anyhow!("Skinned mesh '{}' has no geometry", name));
+        }
+
+        let vertex_count = data.vertices.len();
+        if let Some(target) = data.morph_targets.iter().find(|target| {
+            target.positions.len() != vertex_count || !(target.normals.is_empty() || target.normals.len() == vertex_count)
+        }) {
+            return Err(anyhow!("Morph target '{}' of skinned mesh '{}' doesn't have a delta for each of its {} vertices", target.name, name, vertex_count));
+        }
+
+        Ok(Self {
+            vertex_buffer: Buffer::vertex(device, &format!("{} vertices", name), &data.vertices)?,
+            index_buffer: Buffer::index(device, &format!("{} indices", name), &data.indices)?,
+            index_count: data.indices.len() as u32,
+            morph_target_count: data.morph_targets.len() as u32,
+        })
+    }
+
+    pub fn index_count(&self) -> u32 {
+        self.index_count
+    }
+
+    pub fn morph_target_count(&self) -> u32 {
+        self.morph_target_count
+    }
+
+    pub unsafe fn bind(&self, device: &Device, command_buffer: vk::CommandBuffer) {
+        device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.vertex_buffer.handle()], &[0]);
+        device.cmd_bind_index_buffer(command_buffer, self.index_buffer.handle(), 0, vk::IndexType::UINT32);
+    }
+}
+
+/// Pushed to the vertex shader of each skinned draw.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SkinPushConstants {
+    pub model: [[f32; 4]; 4],
+    /// Where the draw's joints start in the palette buffer.
+    pub first_joint: u32,
+    /// Where the draw's morph weights start in the weight buffer.
+    pub first_weight: u32,
+    /// Where the mesh's deltas start in [`MorphDeltas`].
+    pub first_delta: u32,
+    /// 0 leaves the mesh unmorphed.
+    pub morph_target_count: u32,
+}
+
+unsafe impl Zeroable for SkinPushConstants {}
+unsafe impl Pod for SkinPushConstants {}
+
+/// The morph target deltas of every skinned mesh in one device local storage buffer, each vertex's deltas after
+/// each other: for every target, its position delta and its normal delta, as `vec4`s.
+pub struct MorphDeltas {
+    buffer: Buffer,
+    /// Where each mesh's deltas start in `vec4`s.
+    offsets: Vec<u32>,
+}
+
+impl MorphDeltas {
+    pub unsafe fn new<'a>(device: &Arc<Device>, meshes: impl IntoIterator<Item = &'a SkinnedMeshData>) -> anyhow::Result<Self> {
+        let mut deltas: Vec<[f32; 4]> = Vec::new();
+        let mut offsets = Vec::new();
+        for data in meshes {
+            offsets.push(deltas.len() as u32);
+            for vertex in 0..data.vertices.len() {
+                for target in &data.morph_targets {
+                    let [x, y, z] = target.positions[vertex];
+                    let [nx, ny, nz] = target.normals.get(vertex).copied().unwrap_or_default();
+                    deltas.extend([[x, y, z, 0.0], [nx, ny, nz, 0.0]]);
+                }
+            }
+        }
+
+        // Storage buffers can't be empty, and the frame set always points at one.
+        if deltas.is_empty() {
+            deltas.push([0.0; 4]);
+        }
+
+        Ok(Self {
+            buffer: Buffer::with_data(device, "morph deltas", vk::BufferUsageFlags::STORAGE_BUFFER, &deltas)?,
+            offsets,
+        })
+    }
+
+    pub fn buffer(&self) -> &Buffer {
+        &self.buffer
+    }
+
+    pub fn mesh_count(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn offset(&self, mesh: usize) -> u32 {
+        self.offsets[mesh]
+    }
+}
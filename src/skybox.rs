@@ -0,0 +1,283 @@
+use std::sync::Arc;
+use anyhow::anyhow;
+use ash::vk;
+use crate::commands::submit_one_time;
+use crate::compute::ComputePipelineBuilder;
+use crate::descriptors::{DescriptorAllocator, DescriptorLayoutCache, DescriptorWriter, SetLayoutDesc};
+use crate::device::Device;
+use crate::glsl::GlslCompiler;
+use crate::image::{Image, ImageDesc, Texture};
+use crate::pipeline::{BlendMode, DepthState, GraphicsPipeline, GraphicsPipelineBuilder, PipelineTarget};
+use crate::sampler::SamplerDesc;
+use crate::shader::ShaderModule;
+use crate::upload::{UploadToken, Uploader};
+
+const SKYBOX_VERT: &str = include_str!("../shaders/skybox.vert");
+const SKYBOX_FRAG: &str = include_str!("../shaders/skybox.frag");
+const EQUIRECT_TO_CUBE_COMP: &str = include_str!("../shaders/equirect_to_cube.comp");
+
+/// Format of cube maps converted from equirectangular panoramas, which keeps their dynamic range.
+pub const HDR_CUBEMAP_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+const CONVERSION_LOCAL_SIZE: u32 = 8;
+
+/// Pixel data a cube map is made from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CubemapSource {
+    /// Six square sRGB RGBA8 faces of `size * size * 4` bytes each, one after the other in Vulkan's face order:
+    /// +X, -X, +Y, -Y, +Z, -Z.
+    Faces { size: u32, pixels: Vec<u8> },
+    /// An RGBA32F panorama of `width * height * 4` floats covering every direction, with +Y at the top row.
+    /// Converted into `face_size` faces on the GPU.
+    Equirectangular {
+        width: u32,
+        height: u32,
+        pixels: Vec<f32>,
+        face_size: u32,
+    },
+}
+
+impl CubemapSource {
+    /// Uploads the cube map, leaving it in `SHADER_READ_ONLY_OPTIMAL`. Faces get a full mip chain; converted
+    /// panoramas only have one level.
+    pub unsafe fn load(&self, device: &Arc<Device>, compiler: &GlslCompiler, name: &str) -> anyhow::Result<Texture> {
+        match self {
+            Self::Faces { size, pixels } => {
+                let expected = (*size as usize).pow(2) * 4 * 6;
+                if pixels.len() != expected {
+                    return Err(anyhow!("Cube map '{}' has {} bytes of faces, expected {}", name, pixels.len(), expected));
+                }
+
+                let desc = ImageDesc::new_2d(*size, *size, vk::Format::R8G8B8A8_SRGB, vk::ImageUsageFlags::SAMPLED).cube();
+                Texture::from_pixels(device, name, desc, pixels, true)
+            }
+            Self::Equirectangular { width, height, pixels, face_size } => {
+                let expected = *width as usize * *height as usize * 4;
+                if pixels.len() != expected || *face_size == 0 {
+                    return Err(anyhow!("Panorama '{}' has {} floats, expected {}", name, pixels.len(), expected));
+                }
+
+                convert_equirectangular(device, compiler, name, *width, *height, pixels, *face_size)
+            }
+        }
+    }
+}
+
+/// One face of a cube map for [`load_cubemap`]: `width * height` tightly packed texels of `format`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CubemapFace {
+    pub width: u32,
+    pub height: u32,
+    pub format: vk::Format,
+    pub pixels: Vec<u8>,
+}
+
+/// Creates a cube map from `faces` in Vulkan's face order (+X, -X, +Y, -Y, +Z, -Z) and uploads them through
+/// `uploader` without waiting for the copy. The texture has a single mip level, ends up in
+/// `SHADER_READ_ONLY_OPTIMAL` and may be sampled, e.g. with `SamplerDesc::linear_clamp`, once the returned token is
+/// ready. Faces must be square and all of the same size and format.
+pub unsafe fn load_cubemap(
+    device: &Arc<Device>,
+    uploader: &mut Uploader,
+    name: &str,
+    faces: &[CubemapFace; 6],
+) -> anyhow::Result<(Texture, UploadToken)> {
+    let first = &faces[0];
+    if first.width != first.height || first.width == 0 {
+        return Err(anyhow!("Cube map '{}' has {}x{} faces, expected square ones", name, first.width, first.height));
+    }
+
+    let texels = first.width as usize * first.height as usize;
+    if first.pixels.is_empty() || !first.pixels.len().is_multiple_of(texels) {
+        return Err(anyhow!("Cube map '{}' has {} bytes for {} texels per face", name, first.pixels.len(), texels));
+    }
+
+    for (index, face) in faces.iter().enumerate().skip(1) {
+        if face.width != first.width || face.height != first.height || face.format != first.format || face.pixels.len() != first.pixels.len() {
+            return Err(anyhow!(
+                "Face {} of cube map '{}' is {}x{} {:?} in {} bytes, unlike face 0 at {}x{} {:?} in {} bytes",
+                index, name, face.width, face.height, face.format, face.pixels.len(),
+                first.width, first.height, first.format, first.pixels.len(),
+            ));
+        }
+    }
+
+    let desc = ImageDesc::new_2d(first.width, first.height, first.format, vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST).cube();
+    let image = Image::new(device, name, &desc)?;
+    let pixels: Vec<u8> = faces.iter().flat_map(|face| &face.pixels).copied().collect();
+    let token = uploader.upload_image(&image, &pixels, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)?;
+    Ok((Texture::from_image(image), token))
+}
+
+/// Draws a cube map behind everything: a fullscreen triangle on the far plane, depth tested with `EQUAL` so it
+/// only covers pixels where the depth buffer still holds its clear value of 1.
+pub struct Skybox {
+    pipeline: GraphicsPipeline,
+    set_layout: vk::DescriptorSetLayout,
+}
+
+impl Skybox {
+    /// `frame_layout` is the renderer's set 0, which holds the camera in binding 0. The cube map goes in set 1.
+    pub unsafe fn new(
+        device: &Arc<Device>,
+        layouts: &mut DescriptorLayoutCache,
+        compiler: &GlslCompiler,
+        target: &PipelineTarget,
+        frame_layout: vk::DescriptorSetLayout,
+    ) -> anyhow::Result<Self> {
+        let vertex = ShaderModule::from_bytes_with_stage(
+            device,
+            "skybox.vert",
+            &compiler.compile_source(SKYBOX_VERT, vk::ShaderStageFlags::VERTEX, "skybox.vert")?,
+            vk::ShaderStageFlags::VERTEX,
+        )?;
+        let fragment = ShaderModule::from_bytes_with_stage(
+            device,
+            "skybox.frag",
+            &compiler.compile_source(SKYBOX_FRAG, vk::ShaderStageFlags::FRAGMENT, "skybox.frag")?,
+            vk::ShaderStageFlags::FRAGMENT,
+        )?;
+
+        let set_layout = layouts.get(&skybox_set_layout())?;
+        let pipeline = GraphicsPipelineBuilder::new()
+            .shader(&vertex)
+            .shader(&fragment)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .depth(DepthState {
+                test: true,
+                write: false,
+                compare_op: vk::CompareOp::EQUAL,
+            })
+            .blend(BlendMode::Opaque)
+            .descriptor_set_layout(frame_layout)
+            .descriptor_set_layout(set_layout)
+            .target(target.clone())
+            .build(device)?;
+
+        Ok(Self { pipeline, set_layout })
+    }
+
+    pub fn set_layout(&self) -> vk::DescriptorSetLayout {
+        self.set_layout
+    }
+
+    /// Allocates and writes the set 1 `record` binds for `cubemap`.
+    pub unsafe fn create_set(
+        &self,
+        device: &Device,
+        allocator: &mut DescriptorAllocator,
+        cubemap: &Texture,
+    ) -> anyhow::Result<vk::DescriptorSet> {
+        let set = allocator.allocate(self.set_layout)?;
+        DescriptorWriter::new()
+            .image(
+                0,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                cubemap.view(),
+                device.sampler(&SamplerDesc::linear_clamp())?,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            )
+            .update(device, set);
+
+        Ok(set)
+    }
+
+    /// Draws the cube map in `cubemap_set` into the current pass, which must have a depth attachment.
+    pub unsafe fn record(&self, device: &Device, command_buffer: vk::CommandBuffer, frame_set: vk::DescriptorSet, cubemap_set: vk::DescriptorSet) {
+        self.pipeline.bind(command_buffer);
+        device.cmd_bind_descriptor_sets(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            self.pipeline.layout(),
+            0,
+            &[frame_set, cubemap_set],
+            &[],
+        );
+        device.cmd_draw(command_buffer, 3, 1, 0, 0);
+    }
+}
+
+fn skybox_set_layout() -> SetLayoutDesc {
+    SetLayoutDesc::new().binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::FRAGMENT)
+}
+
+/// Uploads the panorama and resamples it into each face of a cube map with a compute pass, waiting for it to finish.
+unsafe fn convert_equirectangular(
+    device: &Arc<Device>,
+    compiler: &GlslCompiler,
+    name: &str,
+    width: u32,
+    height: u32,
+    pixels: &[f32],
+    face_size: u32,
+) -> anyhow::Result<Texture> {
+    let panorama = Texture::from_pixels(
+        device,
+        &format!("{} panorama", name),
+        ImageDesc::new_2d(width, height, vk::Format::R32G32B32A32_SFLOAT, vk::ImageUsageFlags::SAMPLED),
+        bytemuck::cast_slice(pixels),
+        false,
+    )?;
+
+    let desc = ImageDesc::new_2d(
+        face_size,
+        face_size,
+        HDR_CUBEMAP_FORMAT,
+        vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+    ).cube();
+    let cubemap = Image::new(device, name, &desc)?;
+
+    let shader = ShaderModule::from_bytes_with_stage(
+        device,
+        "equirect_to_cube.comp",
+        &compiler.compile_source(EQUIRECT_TO_CUBE_COMP, vk::ShaderStageFlags::COMPUTE, "equirect_to_cube.comp")?,
+        vk::ShaderStageFlags::COMPUTE,
+    )?;
+
+    let mut layouts = DescriptorLayoutCache::new(device);
+    let mut allocator = DescriptorAllocator::new(device);
+    let set_layout = layouts.get(
+        &SetLayoutDesc::new()
+            .binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE)
+            .binding(1, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE),
+    )?;
+    let pipeline = ComputePipelineBuilder::new()
+        .shader(&shader)
+        .descriptor_set_layout(set_layout)
+        .build(device)?;
+
+    // Storage images can't be cube views, so the shader writes the faces as array layers.
+    let view_info = vk::ImageViewCreateInfo::builder()
+        .image(cubemap.handle())
+        .view_type(vk::ImageViewType::TYPE_2D_ARRAY)
+        .format(HDR_CUBEMAP_FORMAT)
+        .subresource_range(cubemap.full_range());
+    let storage_view = device.create_image_view(&view_info, None)?;
+
+    let result = (|| -> anyhow::Result<()> {
+        let set = allocator.allocate(set_layout)?;
+        DescriptorWriter::new()
+            .image(
+                0,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                panorama.view(),
+                device.sampler(&SamplerDesc::nearest())?,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            )
+            .image(1, vk::DescriptorType::STORAGE_IMAGE, storage_view, vk::Sampler::null(), vk::ImageLayout::GENERAL)
+            .update(device, set);
+
+        let groups = face_size.div_ceil(CONVERSION_LOCAL_SIZE);
+        submit_one_time(device, device.queue_families().graphics, device.graphics_queue(), |command_buffer| {
+            cubemap.transition(command_buffer, vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL);
+            pipeline.bind(command_buffer);
+            pipeline.bind_descriptor_sets(command_buffer, 0, &[set]);
+            pipeline.dispatch(command_buffer, groups, groups, 6);
+            cubemap.transition(command_buffer, vk::ImageLayout::GENERAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+        })
+    })();
+
+    device.destroy_image_view(storage_view, None);
+    result?;
+    Ok(Texture::from_image(cubemap))
+}
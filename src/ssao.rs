@@ -0,0 +1,236 @@
+use std::sync::Arc;
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use crate::descriptors::{DescriptorAllocator, DescriptorLayoutCache, DescriptorWriter, SetLayoutDesc};
+use crate::device::Device;
+use crate::glsl::{insert_after_version, GlslCompiler};
+use crate::image::ImageDesc;
+use crate::pipeline::{BlendMode, DepthState, GraphicsPipeline, GraphicsPipelineBuilder, PipelineTarget};
+use crate::render_graph::{GraphImage, ImageAccess, RenderGraph};
+use crate::rendering::RenderingFormats;
+use crate::sampler::SamplerDesc;
+use crate::shader::ShaderModule;
+
+const FULLSCREEN_VERT: &str = include_str!("../shaders/fullscreen.vert");
+const SSAO_FRAG: &str = include_str!("../shaders/ssao.frag");
+
+/// What the occlusion and its blur are stored in: 1 for unoccluded, 0 for fully occluded.
+pub const AMBIENT_OCCLUSION_FORMAT: vk::Format = vk::Format::R8_UNORM;
+
+/// Sample count, reach and smoothing of screen-space ambient occlusion.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SsaoQuality {
+    /// Samples per pixel over the hemisphere around its normal.
+    pub samples: u32,
+    /// How far from the surface, in world units, geometry occludes it.
+    pub radius: f32,
+    /// How far in front of a sample geometry has to be to occlude it, against surfaces occluding themselves.
+    pub bias: f32,
+    /// Scales how dark fully occluded pixels get, from 0 to 1.
+    pub intensity: f32,
+    /// The blur averages a square of `2 * blur_radius + 1` texels on each side. 0 leaves the noise in.
+    pub blur_radius: u32,
+}
+
+impl SsaoQuality {
+    pub const LOW: Self = Self {
+        samples: 8,
+        radius: 0.5,
+        bias: 0.025,
+        intensity: 1.0,
+        blur_radius: 1,
+    };
+
+    pub const MEDIUM: Self = Self {
+        samples: 16,
+        radius: 0.5,
+        bias: 0.025,
+        intensity: 1.0,
+        blur_radius: 2,
+    };
+
+    pub const HIGH: Self = Self {
+        samples: 32,
+        radius: 0.75,
+        bias: 0.02,
+        intensity: 1.0,
+        blur_radius: 3,
+    };
+}
+
+impl Default for SsaoQuality {
+    fn default() -> Self {
+        Self::MEDIUM
+    }
+}
+
+/// The push constants of the occlusion and blur passes.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SsaoParams {
+    samples: u32,
+    radius: f32,
+    bias: f32,
+    intensity: f32,
+    blur_radius: i32,
+}
+
+unsafe impl Zeroable for SsaoParams {}
+unsafe impl Pod for SsaoParams {}
+
+impl From<SsaoQuality> for SsaoParams {
+    fn from(quality: SsaoQuality) -> Self {
+        Self {
+            samples: quality.samples,
+            radius: quality.radius,
+            bias: quality.bias,
+            intensity: quality.intensity,
+            blur_radius: quality.blur_radius as i32,
+        }
+    }
+}
+
+/// The occlusion and blur passes of the deferred path, reading the G-buffer normals and positions in set 1 and
+/// the camera from the frame set in set 0.
+pub struct Ssao {
+    device: Arc<Device>,
+    occlusion: GraphicsPipeline,
+    blur: GraphicsPipeline,
+    /// Written every frame, since the G-buffer is a transient of the render graph.
+    occlusion_sets: Vec<vk::DescriptorSet>,
+    blur_sets: Vec<vk::DescriptorSet>,
+}
+
+impl Ssao {
+    pub unsafe fn new(
+        device: &Arc<Device>,
+        layouts: &mut DescriptorLayoutCache,
+        allocator: &mut DescriptorAllocator,
+        compiler: &GlslCompiler,
+        frame_layout: vk::DescriptorSetLayout,
+        frames_in_flight: usize,
+    ) -> anyhow::Result<Self> {
+        let vertex = ShaderModule::from_bytes_with_stage(
+            device,
+            "fullscreen.vert",
+            &compiler.compile_source(FULLSCREEN_VERT, vk::ShaderStageFlags::VERTEX, "fullscreen.vert")?,
+            vk::ShaderStageFlags::VERTEX,
+        )?;
+        let layout = layouts.get(&SetLayoutDesc::new()
+            .binding(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::FRAGMENT)
+            .binding(1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::FRAGMENT))?;
+
+        let pipeline = |name: &str, source: &str| -> anyhow::Result<GraphicsPipeline> {
+            let fragment = ShaderModule::from_bytes_with_stage(
+                device,
+                name,
+                &compiler.compile_source(source, vk::ShaderStageFlags::FRAGMENT, name)?,
+                vk::ShaderStageFlags::FRAGMENT,
+            )?;
+
+            GraphicsPipelineBuilder::new()
+                .shader(&vertex)
+                .shader(&fragment)
+                .cull_mode(vk::CullModeFlags::NONE)
+                .depth(DepthState::DISABLED)
+                .blend(BlendMode::Opaque)
+                .descriptor_set_layout(frame_layout)
+                .descriptor_set_layout(layout)
+                .push_constants::<SsaoParams>(vk::ShaderStageFlags::FRAGMENT, 0)
+                .target(PipelineTarget::Dynamic(RenderingFormats::new(&[AMBIENT_OCCLUSION_FORMAT], None)))
+                .build(device)
+        };
+
+        let mut sets = || (0..frames_in_flight)
+            .map(|_| allocator.allocate(layout))
+            .collect::<anyhow::Result<Vec<_>>>();
+
+        Ok(Self {
+            device: device.clone(),
+            occlusion: pipeline("ssao.frag", SSAO_FRAG)?,
+            blur: pipeline("ssao.frag (BLUR)", &insert_after_version(SSAO_FRAG, "#define BLUR\n"))?,
+            occlusion_sets: sets()?,
+            blur_sets: sets()?,
+        })
+    }
+
+    /// Adds the passes occluding the G-buffer's `normal` and `position` images, and blurring the result unless
+    /// `quality` turns that off. Returns the image to shade with.
+    pub unsafe fn add_passes<'a>(
+        &'a self,
+        graph: &mut RenderGraph<'a>,
+        frame_set: vk::DescriptorSet,
+        normal: GraphImage,
+        position: GraphImage,
+        frame_index: usize,
+        quality: SsaoQuality,
+    ) -> GraphImage {
+        let extent = graph.extent(position);
+        let desc = ImageDesc::new_2d(extent.width, extent.height, AMBIENT_OCCLUSION_FORMAT, vk::ImageUsageFlags::empty());
+
+        let occlusion = graph.create_image("ambient occlusion", desc);
+        self.add_pass(graph, "ssao", &self.occlusion, [frame_set, self.occlusion_sets[frame_index]], [normal, position], occlusion, quality.into());
+        self.add_blur(graph, frame_set, occlusion, position, frame_index, quality)
+    }
+
+    /// Adds the pass blurring `occlusion`, an image of any sampled format, across the surfaces of `position`.
+    /// Returns the blurred image, or `occlusion` itself when `quality` turns the blur off.
+    pub unsafe fn add_blur<'a>(
+        &'a self,
+        graph: &mut RenderGraph<'a>,
+        frame_set: vk::DescriptorSet,
+        occlusion: GraphImage,
+        position: GraphImage,
+        frame_index: usize,
+        quality: SsaoQuality,
+    ) -> GraphImage {
+        if quality.blur_radius == 0 {
+            return occlusion;
+        }
+
+        let extent = graph.extent(position);
+        let blurred = graph.create_image(
+            "ambient occlusion blurred",
+            ImageDesc::new_2d(extent.width, extent.height, AMBIENT_OCCLUSION_FORMAT, vk::ImageUsageFlags::empty()),
+        );
+        self.add_pass(graph, "ssao blur", &self.blur, [frame_set, self.blur_sets[frame_index]], [occlusion, position], blurred, quality.into());
+        blurred
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn add_pass<'a>(
+        &'a self,
+        graph: &mut RenderGraph<'a>,
+        name: &str,
+        pipeline: &'a GraphicsPipeline,
+        sets: [vk::DescriptorSet; 2],
+        inputs: [GraphImage; 2],
+        output: GraphImage,
+        params: SsaoParams,
+    ) {
+        inputs.iter()
+            .fold(graph.add_pass(name), |pass, &image| pass.image(image, ImageAccess::Sampled(vk::PipelineStageFlags::FRAGMENT_SHADER)))
+            .color(output, Some([1.0; 4]))
+            .execute(move |ctx| {
+                let command_buffer = ctx.command_buffer();
+                let sampler = self.device.sampler(&SamplerDesc::nearest())?;
+
+                inputs.iter()
+                    .enumerate()
+                    .fold(DescriptorWriter::new(), |writer, (binding, &image)| writer.image(
+                        binding as u32,
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        ctx.view(image),
+                        sampler,
+                        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    ))
+                    .update(&self.device, sets[1]);
+
+                pipeline.bind(command_buffer);
+                self.device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.layout(), 0, &sets, &[]);
+                pipeline.push_constants(command_buffer, vk::ShaderStageFlags::FRAGMENT, 0, &params);
+                self.device.cmd_draw(command_buffer, 3, 1, 0, 0);
+                Ok(())
+            });
+    }
+}
@@ -0,0 +1,47 @@
+use std::sync::Arc;
+use ash::extensions::khr;
+use ash::vk;
+use log::info;
+use winit::window::Window;
+use crate::instance::Instance;
+use crate::platform::create_surface;
+
+pub struct Surface {
+    instance: Arc<Instance>,
+    loader: khr::Surface,
+    handle: vk::SurfaceKHR,
+}
+
+impl Surface {
+    /// The window must outlive the returned surface.
+    pub unsafe fn new(instance: &Arc<Instance>, window: &Window) -> anyhow::Result<Arc<Self>> {
+        let handle = create_surface(window, instance.entry(), instance)?;
+        info!("Created surface");
+
+        Ok(Arc::new(Self {
+            instance: instance.clone(),
+            loader: khr::Surface::new(instance.entry(), instance),
+            handle,
+        }))
+    }
+
+    pub fn instance(&self) -> &Arc<Instance> {
+        &self.instance
+    }
+
+    pub fn loader(&self) -> &khr::Surface {
+        &self.loader
+    }
+
+    pub fn handle(&self) -> vk::SurfaceKHR {
+        self.handle
+    }
+}
+
+impl Drop for Surface {
+    fn drop(&mut self) {
+        unsafe {
+            self.loader.destroy_surface(self.handle, None);
+        }
+    }
+}
@@ -0,0 +1,488 @@
+use anyhow::anyhow;
+use ash::extensions::khr;
+use std::sync::Arc;
+use std::time::Duration;
+use ash::vk;
+use log::{debug, info, warn};
+use crate::device::Device;
+use crate::format::{is_srgb, select_surface_format};
+use crate::surface::Surface;
+
+/// Surface formats tried first, in order. Both have the hardware encode linear shader output to sRGB on write.
+pub const SURFACE_FORMAT_PREFERENCES: [vk::SurfaceFormatKHR; 2] = [
+    vk::SurfaceFormatKHR {
+        format: vk::Format::B8G8R8A8_SRGB,
+        color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+    },
+    vk::SurfaceFormatKHR {
+        format: vk::Format::R8G8B8A8_SRGB,
+        color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+    },
+];
+
+/// Requested presentation behavior. Modes the surface doesn't support fall back to the next one in
+/// [`modes`](Self::modes), ending at FIFO, which is always available.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PresentPreference {
+    /// Waits for vertical blank; no tearing, latency of up to the queue length.
+    #[default]
+    Vsync,
+    /// Replaces the queued image with the newest one; no tearing and lower latency than `Vsync`. Falls back to FIFO
+    /// rather than `CappedImmediate`, which would tear.
+    Mailbox,
+    /// Presents immediately and may tear.
+    Immediate,
+    /// Vsync while the frame rate keeps up, tearing instead of stalling when a frame is late.
+    AdaptiveVsync,
+    /// Presents immediately, with the app sleeping between frames to stay at the monitor's refresh rate: the latency
+    /// of `Immediate` without rendering frames that are never shown. Without IMMEDIATE it falls back to plain FIFO.
+    CappedImmediate,
+}
+
+impl PresentPreference {
+    /// Present modes to try, in order.
+    pub fn modes(self) -> &'static [vk::PresentModeKHR] {
+        match self {
+            Self::Vsync => &[vk::PresentModeKHR::FIFO],
+            Self::Mailbox => &[vk::PresentModeKHR::MAILBOX, vk::PresentModeKHR::FIFO],
+            Self::Immediate => &[vk::PresentModeKHR::IMMEDIATE, vk::PresentModeKHR::MAILBOX, vk::PresentModeKHR::FIFO],
+            Self::AdaptiveVsync => &[vk::PresentModeKHR::FIFO_RELAXED, vk::PresentModeKHR::FIFO],
+            Self::CappedImmediate => &[vk::PresentModeKHR::IMMEDIATE, vk::PresentModeKHR::FIFO],
+        }
+    }
+}
+
+/// How many times creating the swapchain is tried when the surface reports a transient error, see [`retry_transient`].
+const CREATE_ATTEMPTS: u32 = 4;
+
+/// Pause before the first retry, doubled for each one after it.
+const CREATE_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+/// Errors some drivers report for a moment while the output behind the surface changes, e.g. when a monitor is
+/// plugged in or the compositor restarts, and that go away when asked again.
+fn is_transient(result: vk::Result) -> bool {
+    matches!(result, vk::Result::ERROR_SURFACE_LOST_KHR | vk::Result::ERROR_INITIALIZATION_FAILED)
+}
+
+/// Runs `call` until it succeeds, fails with an error that isn't [transient](is_transient) or has been tried
+/// [`CREATE_ATTEMPTS`] times. A surface loss that outlasts the retries is left to the app's recovery, which creates
+/// the surface again.
+fn retry_transient<T>(what: &str, mut call: impl FnMut() -> Result<T, vk::Result>) -> Result<T, vk::Result> {
+    let mut delay = CREATE_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match call() {
+            Err(result) if is_transient(result) && attempt < CREATE_ATTEMPTS => {
+                warn!("{} failed with {:?}, retrying in {:?} ({}/{})", what, result, delay, attempt, CREATE_ATTEMPTS);
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// MAILBOX only replaces queued images instead of waiting when one is displayed, one is queued and one is being
+/// rendered, so it asks for at least 3. The other modes get one more than the minimum, so acquire doesn't block on the
+/// presentation engine. The count is clamped to the surface's maximum, with a warning when that falls short.
+fn choose_image_count(capabilities: &vk::SurfaceCapabilitiesKHR, present_mode: vk::PresentModeKHR) -> u32 {
+    let requested = match present_mode {
+        vk::PresentModeKHR::MAILBOX => capabilities.min_image_count.max(3),
+        _ => capabilities.min_image_count + 1,
+    };
+
+    if capabilities.max_image_count > 0 && requested > capabilities.max_image_count {
+        let image_count = capabilities.max_image_count;
+        if present_mode == vk::PresentModeKHR::MAILBOX {
+            warn!("The surface allows at most {} swapchain image(s), so MAILBOX behaves like double buffering", image_count);
+        } else {
+            warn!("The surface allows at most {} swapchain image(s) instead of the {} requested", image_count, requested);
+        }
+
+        return image_count;
+    }
+
+    requested
+}
+
+pub struct Swapchain {
+    loader: khr::Swapchain,
+    device: Arc<Device>,
+    surface: Arc<Surface>,
+    queue_family_indices: Vec<u32>,
+    handle: vk::SwapchainKHR,
+    images: Vec<vk::Image>,
+    image_views: Vec<vk::ImageView>,
+    format: vk::SurfaceFormatKHR,
+    present_mode: vk::PresentModeKHR,
+    present_preference: PresentPreference,
+    extent: vk::Extent2D,
+    desired_extent: vk::Extent2D,
+    needs_recreate: bool,
+    generation: u64,
+    /// Regions of the image being rendered that changed, for the next present.
+    damage: Vec<vk::RectLayerKHR>,
+}
+
+impl Swapchain {
+    pub unsafe fn new(
+        device: &Arc<Device>,
+        surface: &Arc<Surface>,
+        desired_extent: vk::Extent2D,
+        present_preference: PresentPreference,
+    ) -> anyhow::Result<Self> {
+        let queue_families = device.queue_families();
+        let queue_family_indices = if queue_families.graphics == queue_families.present {
+            vec![]
+        } else {
+            vec![queue_families.graphics, queue_families.present]
+        };
+
+        let mut swapchain = Self {
+            loader: khr::Swapchain::new(device.instance(), device),
+            device: device.clone(),
+            surface: surface.clone(),
+            queue_family_indices,
+            handle: vk::SwapchainKHR::null(),
+            images: Vec::new(),
+            image_views: Vec::new(),
+            format: vk::SurfaceFormatKHR::default(),
+            present_mode: vk::PresentModeKHR::FIFO,
+            present_preference,
+            extent: vk::Extent2D::default(),
+            desired_extent,
+            needs_recreate: false,
+            generation: 0,
+            damage: Vec::new(),
+        };
+
+        swapchain.create()?;
+        Ok(swapchain)
+    }
+
+    /// Prefers the 8-bit sRGB formats in [`SURFACE_FORMAT_PREFERENCES`], then any other sRGB format, and only falls
+    /// back to a linear format when the surface has nothing else.
+    unsafe fn choose_format(&self) -> anyhow::Result<vk::SurfaceFormatKHR> {
+        let formats = self.surface.loader().get_physical_device_surface_formats(self.device.physical_device(), self.surface.handle())?;
+        let format = select_surface_format(&formats, &SURFACE_FORMAT_PREFERENCES).ok_or(anyhow!("Surface has no formats"))?;
+
+        if !is_srgb(format.format) {
+            warn!("Surface has no sRGB format, shaders have to encode their output with {:?}", format.format);
+        }
+
+        Ok(format)
+    }
+
+    unsafe fn choose_present_mode(&self) -> anyhow::Result<vk::PresentModeKHR> {
+        let supported = self.surface.loader().get_physical_device_surface_present_modes(self.device.physical_device(), self.surface.handle())?;
+        let modes = self.present_preference.modes();
+        let mode = modes.iter()
+            .copied()
+            .find(|mode| supported.contains(mode))
+            .unwrap_or(vk::PresentModeKHR::FIFO);
+
+        if mode != modes[0] {
+            warn!("{:?} is not supported by the surface, presenting with {:?}", modes[0], mode);
+        }
+
+        Ok(mode)
+    }
+
+    fn choose_extent(&self, capabilities: &vk::SurfaceCapabilitiesKHR) -> vk::Extent2D {
+        if capabilities.current_extent.width != u32::MAX {
+            return capabilities.current_extent;
+        }
+
+        vk::Extent2D {
+            width: self.desired_extent.width.clamp(capabilities.min_image_extent.width, capabilities.max_image_extent.width),
+            height: self.desired_extent.height.clamp(capabilities.min_image_extent.height, capabilities.max_image_extent.height),
+        }
+    }
+
+    unsafe fn create(&mut self) -> anyhow::Result<()> {
+        let capabilities = retry_transient("Querying surface capabilities", || {
+            self.surface.loader().get_physical_device_surface_capabilities(self.device.physical_device(), self.surface.handle())
+        })?;
+
+        let format = self.choose_format()?;
+        let present_mode = self.choose_present_mode()?;
+        let extent = self.choose_extent(&capabilities);
+
+        let image_count = choose_image_count(&capabilities, present_mode);
+
+        let composite_alpha = [
+            vk::CompositeAlphaFlagsKHR::OPAQUE,
+            vk::CompositeAlphaFlagsKHR::INHERIT,
+            vk::CompositeAlphaFlagsKHR::PRE_MULTIPLIED,
+            vk::CompositeAlphaFlagsKHR::POST_MULTIPLIED,
+        ]
+            .into_iter()
+            .find(|&flag| capabilities.supported_composite_alpha.contains(flag))
+            .unwrap_or(vk::CompositeAlphaFlagsKHR::OPAQUE);
+
+        let sharing_mode = if self.queue_family_indices.is_empty() {
+            vk::SharingMode::EXCLUSIVE
+        } else {
+            vk::SharingMode::CONCURRENT
+        };
+
+        let create_info = vk::SwapchainCreateInfoKHR::builder()
+            .surface(self.surface.handle())
+            .min_image_count(image_count)
+            .image_format(format.format)
+            .image_color_space(format.color_space)
+            .image_extent(extent)
+            .image_array_layers(1)
+            .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_DST)
+            .image_sharing_mode(sharing_mode)
+            .queue_family_indices(&self.queue_family_indices)
+            .pre_transform(capabilities.current_transform)
+            .composite_alpha(composite_alpha)
+            .present_mode(present_mode)
+            .clipped(true);
+
+        // A failed create still retires the old swapchain, which can't be handed over again.
+        let mut old_swapchain = self.handle;
+        let handle = retry_transient("Creating the swapchain", || {
+            let create_info = vk::SwapchainCreateInfoKHR {
+                old_swapchain,
+                ..*create_info
+            };
+            let result = self.loader.create_swapchain(&create_info, None);
+            if result.is_err() {
+                old_swapchain = vk::SwapchainKHR::null();
+            }
+            result
+        })?;
+        let images = self.loader.get_swapchain_images(handle)?;
+
+        let mut image_views = Vec::with_capacity(images.len());
+        for &image in &images {
+            let view_create_info = vk::ImageViewCreateInfo::builder()
+                .image(image)
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(format.format)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                });
+
+            image_views.push(self.device.create_image_view(&view_create_info, None)?);
+        }
+
+        info!(
+            "Created swapchain: {} image(s), {}x{}, {:?} {:?}, {:?}",
+            images.len(),
+            extent.width,
+            extent.height,
+            format.format,
+            format.color_space,
+            present_mode,
+        );
+
+        self.handle = handle;
+        self.images = images;
+        self.image_views = image_views;
+        self.format = format;
+        self.present_mode = present_mode;
+        self.extent = extent;
+        self.needs_recreate = false;
+        self.generation += 1;
+
+        Ok(())
+    }
+
+    unsafe fn destroy(&self, handle: vk::SwapchainKHR, image_views: Vec<vk::ImageView>) {
+        for view in image_views {
+            self.device.destroy_image_view(view, None);
+        }
+
+        if handle != vk::SwapchainKHR::null() {
+            self.loader.destroy_swapchain(handle, None);
+        }
+    }
+
+    /// Creates a new swapchain with the current one passed as `old_swapchain`, so presentation can hand its images
+    /// over instead of starting from scratch, and destroys the retired one afterwards.
+    pub unsafe fn recreate(&mut self) -> anyhow::Result<()> {
+        debug!("Recreating swapchain");
+
+        let old_handle = self.handle;
+        let old_image_views = std::mem::take(&mut self.image_views);
+        let result = self.create();
+        if result.is_err() {
+            // The old swapchain is retired even when creating the new one failed.
+            self.handle = vk::SwapchainKHR::null();
+            self.images.clear();
+        }
+
+        // Frames in flight may still render to the old images or wait on their presentation.
+        self.device.device_wait_idle()?;
+        self.destroy(old_handle, old_image_views);
+        result
+    }
+
+    /// Records a new window size. The swapchain is recreated on the next acquire.
+    pub fn resize(&mut self, extent: vk::Extent2D) {
+        if extent != self.desired_extent {
+            self.desired_extent = extent;
+            self.needs_recreate = true;
+        }
+    }
+
+    /// Switches presentation behavior. The swapchain is recreated on the next acquire.
+    pub fn set_present_preference(&mut self, present_preference: PresentPreference) {
+        if present_preference != self.present_preference {
+            self.present_preference = present_preference;
+            self.needs_recreate = true;
+        }
+    }
+
+    pub fn present_preference(&self) -> PresentPreference {
+        self.present_preference
+    }
+
+    pub fn is_minimized(&self) -> bool {
+        self.desired_extent.width == 0 || self.desired_extent.height == 0
+    }
+
+    /// Acquires the next image, recreating the swapchain first when it was resized or reported as out of date or
+    /// suboptimal. Returns `None` when there is nothing to render to this frame (window minimized, or the swapchain
+    /// went out of date during acquire and was recreated).
+    pub unsafe fn acquire_next_image(&mut self, semaphore: vk::Semaphore) -> anyhow::Result<Option<u32>> {
+        if self.is_minimized() {
+            return Ok(None);
+        }
+
+        if self.needs_recreate {
+            self.recreate()?;
+        }
+
+        match self.loader.acquire_next_image(self.handle, u64::MAX, semaphore, vk::Fence::null()) {
+            Ok((index, suboptimal)) => {
+                self.needs_recreate |= suboptimal;
+                Ok(Some(index))
+            }
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                self.recreate()?;
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Whether the device has `VK_KHR_incremental_present`, without which [`add_damage`](Self::add_damage) is ignored.
+    pub fn supports_incremental_present(&self) -> bool {
+        self.device.capabilities().has_extension(vk::KhrIncrementalPresentFn::name())
+    }
+
+    /// Marks `rect` of the image being rendered as changed since the previous one, so the next present only asks
+    /// the compositor to update the marked regions. Without any, or without `VK_KHR_incremental_present`, the whole
+    /// image is presented. Regions are clipped to the swapchain extent.
+    pub fn add_damage(&mut self, rect: vk::Rect2D) {
+        let x = rect.offset.x.clamp(0, self.extent.width as i32);
+        let y = rect.offset.y.clamp(0, self.extent.height as i32);
+        let right = (rect.offset.x as i64 + rect.extent.width as i64).min(self.extent.width as i64);
+        let bottom = (rect.offset.y as i64 + rect.extent.height as i64).min(self.extent.height as i64);
+        if right <= x as i64 || bottom <= y as i64 {
+            return;
+        }
+
+        self.damage.push(vk::RectLayerKHR {
+            offset: vk::Offset2D { x, y },
+            extent: vk::Extent2D {
+                width: (right - x as i64) as u32,
+                height: (bottom - y as i64) as u32,
+            },
+            layer: 0,
+        });
+    }
+
+    /// Drops the regions marked since the last present, for a frame that is skipped.
+    pub fn clear_damage(&mut self) {
+        self.damage.clear();
+    }
+
+    pub unsafe fn present(&mut self, queue: vk::Queue, wait_semaphores: &[vk::Semaphore], image_index: u32) -> anyhow::Result<()> {
+        let swapchains = [self.handle];
+        let image_indices = [image_index];
+        let damage = std::mem::take(&mut self.damage);
+
+        let mut present_info = vk::PresentInfoKHR::builder()
+            .wait_semaphores(wait_semaphores)
+            .swapchains(&swapchains)
+            .image_indices(&image_indices);
+
+        let regions = [vk::PresentRegionKHR::builder().rectangles(&damage).build()];
+        let mut present_regions = vk::PresentRegionsKHR::builder().regions(&regions);
+        if !damage.is_empty() && self.supports_incremental_present() {
+            present_info = present_info.push_next(&mut present_regions);
+        }
+
+        match self.loader.queue_present(queue, &present_info) {
+            Ok(suboptimal) => {
+                self.needs_recreate |= suboptimal;
+                Ok(())
+            }
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                self.needs_recreate = true;
+                Ok(())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn handle(&self) -> vk::SwapchainKHR {
+        self.handle
+    }
+
+    pub fn images(&self) -> &[vk::Image] {
+        &self.images
+    }
+
+    pub fn image_views(&self) -> &[vk::ImageView] {
+        &self.image_views
+    }
+
+    pub fn format(&self) -> vk::SurfaceFormatKHR {
+        self.format
+    }
+
+    /// The format of the swapchain images, which pipelines rendering to them have to be built against.
+    pub fn color_format(&self) -> vk::Format {
+        self.format.format
+    }
+
+    /// Whether the swapchain images have an sRGB format, so the hardware encodes the linear values shaders write.
+    /// When false, shaders have to apply the sRGB transfer function themselves or colors come out too dark.
+    pub fn encodes_srgb(&self) -> bool {
+        is_srgb(self.format.format)
+    }
+
+    pub fn present_mode(&self) -> vk::PresentModeKHR {
+        self.present_mode
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+
+    /// Incremented every time the swapchain is (re)created, so anything built from its images can tell when to
+    /// rebuild.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+impl Drop for Swapchain {
+    fn drop(&mut self) {
+        unsafe {
+            let image_views = std::mem::take(&mut self.image_views);
+            self.destroy(self.handle, image_views);
+        }
+    }
+}
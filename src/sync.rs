@@ -0,0 +1,153 @@
+use std::sync::Arc;
+use ash::vk;
+use thiserror::Error;
+use crate::device::Device;
+use crate::timeline::{GpuTimeline, Submission, TimelinePoint};
+
+pub const DEFAULT_FRAMES_IN_FLIGHT: usize = 2;
+
+#[derive(Error, Debug)]
+pub enum FrameSyncError {
+    #[error("Frames in flight must be 2 or 3, got {0}")]
+    InvalidFramesInFlight(usize),
+}
+
+/// When a frame waits for the GPU, trading CPU/GPU overlap against input latency.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LatencyMode {
+    /// Only the frame slot's last submission is waited for before the update callback runs, so the CPU works up to
+    /// `frames_in_flight - 1` frames ahead of the GPU. Input is sampled that many frames before it shows.
+    #[default]
+    Throughput,
+    /// Waits for every earlier frame before the update callback runs, then acquires and records right away. Input
+    /// is sampled just before the frame showing it is rendered, but the CPU idles while the GPU renders and the GPU
+    /// while the CPU records, lowering the frame rate of GPU-bound scenes.
+    LowLatency,
+}
+
+/// Frame pacing for `frames_in_flight` frames. Every submission signals the next value of one timeline, and a frame
+/// waits for the value its slot signalled last time. Presentation still needs binary semaphores: image-available
+/// semaphores belong to a frame, render-finished semaphores to a swapchain image, because presentation may still be
+/// waiting on them after the frame's timeline value was reached.
+pub struct FrameSync {
+    device: Arc<Device>,
+    timeline: GpuTimeline,
+    frame_values: Vec<u64>,
+    image_available: Vec<vk::Semaphore>,
+    render_finished: Vec<vk::Semaphore>,
+    current_frame: usize,
+}
+
+impl FrameSync {
+    pub unsafe fn new(device: &Arc<Device>, frames_in_flight: usize, image_count: usize) -> anyhow::Result<Self> {
+        if !(2..=3).contains(&frames_in_flight) {
+            return Err(FrameSyncError::InvalidFramesInFlight(frames_in_flight).into());
+        }
+
+        let mut sync = Self {
+            device: device.clone(),
+            timeline: GpuTimeline::new(device, 0)?,
+            frame_values: vec![0; frames_in_flight],
+            image_available: Vec::with_capacity(frames_in_flight),
+            render_finished: Vec::new(),
+            current_frame: 0,
+        };
+
+        for _ in 0..frames_in_flight {
+            sync.image_available.push(device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?);
+        }
+
+        sync.set_image_count(image_count)?;
+        Ok(sync)
+    }
+
+    /// Matches the render-finished semaphores to the swapchain image count. Only call this while none of them are
+    /// pending, e.g. right after the swapchain was recreated.
+    pub unsafe fn set_image_count(&mut self, image_count: usize) -> anyhow::Result<()> {
+        while self.render_finished.len() > image_count {
+            let semaphore = self.render_finished.pop().unwrap();
+            self.device.destroy_semaphore(semaphore, None);
+        }
+
+        while self.render_finished.len() < image_count {
+            self.render_finished.push(self.device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?);
+        }
+
+        Ok(())
+    }
+
+    pub fn frames_in_flight(&self) -> usize {
+        self.frame_values.len()
+    }
+
+    pub fn current_frame(&self) -> usize {
+        self.current_frame
+    }
+
+    pub fn image_available(&self) -> vk::Semaphore {
+        self.image_available[self.current_frame]
+    }
+
+    /// The frame timeline, for other queues that need to wait on rendering.
+    pub fn timeline(&self) -> &GpuTimeline {
+        &self.timeline
+    }
+
+    pub fn render_finished(&self, image_index: u32) -> vk::Semaphore {
+        self.render_finished[image_index as usize]
+    }
+
+    /// The timeline value the current frame's submission will signal, which anything it uses is retired at.
+    pub fn pending_value(&self) -> u64 {
+        self.timeline.last_value() + 1
+    }
+
+    /// Blocks until the GPU has finished the last submission made for the current frame.
+    pub unsafe fn wait_for_current_frame(&self) -> anyhow::Result<()> {
+        self.timeline.wait(self.frame_values[self.current_frame])
+    }
+
+    /// Blocks until the GPU has finished every frame submitted so far.
+    pub unsafe fn wait_for_all_frames(&self) -> anyhow::Result<()> {
+        self.timeline.wait(self.timeline.last_value())
+    }
+
+    /// Submits `command_buffers` for the current frame: waits on the image-available semaphore and every point in
+    /// `waits`, then signals the render-finished semaphore of `image_index` and the frame's next timeline value.
+    pub unsafe fn submit(
+        &mut self,
+        queue: vk::Queue,
+        command_buffers: &[vk::CommandBuffer],
+        image_index: u32,
+        waits: &[(TimelinePoint, vk::PipelineStageFlags)],
+    ) -> anyhow::Result<()> {
+        // Only reserved once the submission went through, so a failed one doesn't leave behind a value that nothing
+        // signals and every later wait would hang on.
+        let value = self.pending_value();
+
+        let submission = waits.iter().fold(Submission::new(), |submission, &(point, stage)| submission.wait(point, stage))
+            .wait(TimelinePoint::binary(self.image_available()), vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::TRANSFER)
+            .signal(TimelinePoint::binary(self.render_finished(image_index)))
+            .signal(self.timeline.at(value));
+
+        submission.submit(&self.device, queue, command_buffers)?;
+        let reserved = self.timeline.next_value();
+        debug_assert_eq!(reserved, value, "The timeline advanced during the frame's submission");
+        self.frame_values[self.current_frame] = value;
+        Ok(())
+    }
+
+    pub fn advance(&mut self) {
+        self.current_frame = (self.current_frame + 1) % self.frames_in_flight();
+    }
+}
+
+impl Drop for FrameSync {
+    fn drop(&mut self) {
+        unsafe {
+            for &semaphore in self.image_available.iter().chain(&self.render_finished) {
+                self.device.destroy_semaphore(semaphore, None);
+            }
+        }
+    }
+}
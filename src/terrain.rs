@@ -0,0 +1,353 @@
+use anyhow::anyhow;
+use ash::vk;
+use cgmath::{InnerSpace, Matrix4, Point3, SquareMatrix, Vector3};
+use crate::culling::Aabb;
+use crate::glsl::insert_after_version;
+use crate::lighting::with_lighting;
+use crate::material::{DefaultTexture, MaterialDesc};
+use crate::renderer3d::{MaterialInstanceId, MeshData, MeshId, MeshVertex, Renderer3d, MESH_VERT};
+
+const TERRAIN_FRAG: &str = include_str!("../shaders/terrain.frag");
+
+/// The description of a splatting material for [`Terrain`]: up to four tiled layers, blended by a control map
+/// stretched over the whole terrain. The control map's red, green and blue say how much of layers 1 to 3 to
+/// use, and layer 0 gets the rest; it holds data rather than colors, so create it with
+/// `Renderer3d::create_linear_texture`. `tiling` is how often the layers repeat across the terrain.
+pub fn terrain_material() -> MaterialDesc {
+    MaterialDesc::new("terrain", MESH_VERT, &with_lighting(TERRAIN_FRAG))
+        .with_gbuffer_shader(&insert_after_version(TERRAIN_FRAG, "#define GBUFFER"))
+        // Skirts hang down from both sides of a chunk's edges.
+        .with_cull_mode(vk::CullModeFlags::NONE)
+        .color("base_color", [1.0; 4])
+        .float("tiling", 64.0)
+        .texture_with_default("control_texture", DefaultTexture::Black)
+        .texture("layer0_texture")
+        .texture("layer1_texture")
+        .texture("layer2_texture")
+        .texture("layer3_texture")
+}
+
+/// A grid of heights from 0 to 1, stretched over a terrain's extent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heightmap {
+    width: u32,
+    depth: u32,
+    heights: Vec<f32>,
+}
+
+impl Heightmap {
+    /// `heights` holds `width * depth` samples, row by row along x.
+    pub fn new(width: u32, depth: u32, heights: Vec<f32>) -> anyhow::Result<Self> {
+        if width < 2 || depth < 2 {
+            return Err(anyhow!("Heightmaps need at least 2x2 samples, got {}x{}", width, depth));
+        }
+
+        if heights.len() != width as usize * depth as usize {
+            return Err(anyhow!("Heightmap of {}x{} expects {} samples, got {}", width, depth, width * depth, heights.len()));
+        }
+
+        Ok(Self { width, depth, heights })
+    }
+
+    /// From 8-bit grayscale pixels.
+    pub fn from_pixels(width: u32, depth: u32, pixels: &[u8]) -> anyhow::Result<Self> {
+        Self::new(width, depth, pixels.iter().map(|&pixel| pixel as f32 / 255.0).collect())
+    }
+
+    pub fn from_fn(width: u32, depth: u32, height: impl Fn(u32, u32) -> f32) -> anyhow::Result<Self> {
+        Self::new(width, depth, (0..depth).flat_map(|z| (0..width).map(move |x| (x, z))).map(|(x, z)| height(x, z)).collect())
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// The sample at `x`, `z`, clamped to the edges.
+    pub fn height(&self, x: i64, z: i64) -> f32 {
+        let x = x.clamp(0, self.width as i64 - 1) as usize;
+        let z = z.clamp(0, self.depth as i64 - 1) as usize;
+        self.heights[z * self.width as usize + x]
+    }
+
+    /// Bilinearly filtered, with `u` and `v` from 0 to 1 across the map.
+    pub fn sample(&self, u: f32, v: f32) -> f32 {
+        let x = u.clamp(0.0, 1.0) * (self.width - 1) as f32;
+        let z = v.clamp(0.0, 1.0) * (self.depth - 1) as f32;
+        let (x0, z0) = (x.floor() as i64, z.floor() as i64);
+        let (fx, fz) = (x.fract(), z.fract());
+
+        let top = self.height(x0, z0) * (1.0 - fx) + self.height(x0 + 1, z0) * fx;
+        let bottom = self.height(x0, z0 + 1) * (1.0 - fx) + self.height(x0 + 1, z0 + 1) * fx;
+        top * (1.0 - fz) + bottom * fz
+    }
+
+    /// The surface normal at `u`, `v` once the map spans `extent` in the world, from central differences.
+    pub fn normal(&self, u: f32, v: f32, extent: Vector3<f32>) -> Vector3<f32> {
+        let (du, dv) = (1.0 / (self.width - 1) as f32, 1.0 / (self.depth - 1) as f32);
+        let dx = (self.sample(u + du, v) - self.sample(u - du, v)) * extent.y / (2.0 * du * extent.x);
+        let dz = (self.sample(u, v + dv) - self.sample(u, v - dv)) * extent.y / (2.0 * dv * extent.z);
+        Vector3::new(-dx, 1.0, -dz).normalize()
+    }
+}
+
+/// The extent of a [`Terrain`] and how it's split into chunks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TerrainDesc {
+    /// Width and depth in the world.
+    pub size: f32,
+    /// The height a heightmap value of 1 reaches.
+    pub height_scale: f32,
+    /// Quads along each side of every chunk, whatever its level.
+    pub chunk_resolution: u32,
+    /// Levels of the quadtree. The root covers the whole terrain and every level below halves its chunks' size.
+    pub lod_levels: u32,
+    /// Chunks closer to the camera than this many times their size are split into their children.
+    pub lod_distance: f32,
+    /// How far skirts hang down from chunk edges, hiding the cracks between chunks of different levels.
+    pub skirt_depth: f32,
+}
+
+impl TerrainDesc {
+    pub fn new(size: f32, height_scale: f32) -> Self {
+        Self {
+            size,
+            height_scale,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_chunk_resolution(mut self, chunk_resolution: u32) -> Self {
+        self.chunk_resolution = chunk_resolution;
+        self
+    }
+
+    pub fn with_lod_levels(mut self, lod_levels: u32) -> Self {
+        self.lod_levels = lod_levels;
+        self
+    }
+
+    pub fn with_lod_distance(mut self, lod_distance: f32) -> Self {
+        self.lod_distance = lod_distance;
+        self
+    }
+
+    pub fn with_skirt_depth(mut self, skirt_depth: f32) -> Self {
+        self.skirt_depth = skirt_depth;
+        self
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.size <= 0.0 || self.height_scale < 0.0 || self.skirt_depth < 0.0 || self.lod_distance <= 0.0 {
+            return Err(anyhow!("Invalid terrain dimensions {:?}", self));
+        }
+
+        if self.chunk_resolution == 0 || !(1..=8).contains(&self.lod_levels) {
+            return Err(anyhow!("Terrains need a chunk resolution of at least 1 and 1 to 8 LOD levels"));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for TerrainDesc {
+    fn default() -> Self {
+        Self {
+            size: 256.0,
+            height_scale: 32.0,
+            chunk_resolution: 32,
+            lod_levels: 4,
+            lod_distance: 2.0,
+            skirt_depth: 1.0,
+        }
+    }
+}
+
+struct TerrainNode {
+    mesh: MeshId,
+    aabb: Aabb,
+    size: f32,
+    children: Option<[usize; 4]>,
+}
+
+/// A heightmap split into a quadtree of chunk meshes, centered on the origin with y up. Every frame, `draw` walks
+/// the quadtree from the camera and queues the coarsest chunks detailed enough for their distance as regular
+/// draws, so they are frustum culled and cast shadows like any other opaque mesh.
+pub struct Terrain {
+    desc: TerrainDesc,
+    heightmap: Heightmap,
+    nodes: Vec<TerrainNode>,
+}
+
+impl Terrain {
+    /// Builds the meshes of every chunk of every level in `renderer`.
+    pub unsafe fn new(renderer: &mut Renderer3d, name: &str, heightmap: Heightmap, desc: TerrainDesc) -> anyhow::Result<Self> {
+        desc.validate()?;
+
+        let mut terrain = Self {
+            desc,
+            heightmap,
+            nodes: Vec::new(),
+        };
+
+        let half = desc.size * 0.5;
+        terrain.build_node(renderer, name, -half, -half, desc.size, 0)?;
+        Ok(terrain)
+    }
+
+    pub fn desc(&self) -> &TerrainDesc {
+        &self.desc
+    }
+
+    pub fn heightmap(&self) -> &Heightmap {
+        &self.heightmap
+    }
+
+    /// Chunks across all levels.
+    pub fn chunk_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// The height of the full resolution surface at `x`, `z`, or `None` off the terrain.
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        let (u, v) = self.uv(x, z)?;
+        Some(self.heightmap.sample(u, v) * self.desc.height_scale)
+    }
+
+    pub fn normal_at(&self, x: f32, z: f32) -> Option<Vector3<f32>> {
+        let (u, v) = self.uv(x, z)?;
+        Some(self.heightmap.normal(u, v, self.extent()))
+    }
+
+    /// The chunks to draw for a camera at `camera`.
+    pub fn select_chunks(&self, camera: Point3<f32>) -> Vec<MeshId> {
+        let mut chunks = Vec::new();
+        self.select_node(0, camera, &mut chunks);
+        chunks
+    }
+
+    /// Queues the chunks selected for the renderer's camera with `instance`, usually of a [`terrain_material`].
+    /// Returns how many it queued.
+    pub fn draw(&self, renderer: &mut Renderer3d, instance: MaterialInstanceId) -> usize {
+        let chunks = self.select_chunks(renderer.camera().position);
+        for &mesh in &chunks {
+            renderer.draw(mesh, instance, Matrix4::identity());
+        }
+
+        chunks.len()
+    }
+
+    fn select_node(&self, index: usize, camera: Point3<f32>, chunks: &mut Vec<MeshId>) {
+        let node = &self.nodes[index];
+        if let Some(children) = node.children {
+            let closest = Point3::new(
+                camera.x.clamp(node.aabb.min.x, node.aabb.max.x),
+                camera.y.clamp(node.aabb.min.y, node.aabb.max.y),
+                camera.z.clamp(node.aabb.min.z, node.aabb.max.z),
+            );
+
+            if (camera - closest).magnitude() < node.size * self.desc.lod_distance {
+                for child in children {
+                    self.select_node(child, camera, chunks);
+                }
+
+                return;
+            }
+        }
+
+        chunks.push(node.mesh);
+    }
+
+    /// Adds the node covering `size` from `x`, `z` on and its descendants, returning its index.
+    unsafe fn build_node(&mut self, renderer: &mut Renderer3d, name: &str, x: f32, z: f32, size: f32, level: u32) -> anyhow::Result<usize> {
+        let data = self.chunk_data(x, z, size);
+        let aabb = data.bounding_box().expect("chunks have vertices");
+        let mesh = renderer.create_mesh(&format!("{} chunk {} ({}, {})", name, level, x, z), data)?;
+
+        let index = self.nodes.len();
+        self.nodes.push(TerrainNode {
+            mesh,
+            aabb,
+            size,
+            children: None,
+        });
+
+        if level + 1 < self.desc.lod_levels {
+            let half = size * 0.5;
+            let children = [
+                self.build_node(renderer, name, x, z, half, level + 1)?,
+                self.build_node(renderer, name, x + half, z, half, level + 1)?,
+                self.build_node(renderer, name, x, z + half, half, level + 1)?,
+                self.build_node(renderer, name, x + half, z + half, half, level + 1)?,
+            ];
+            self.nodes[index].children = Some(children);
+        }
+
+        Ok(index)
+    }
+
+    /// A grid of `chunk_resolution` quads a side over the square from `x`, `z` on, with a skirt around it.
+    fn chunk_data(&self, x: f32, z: f32, size: f32) -> MeshData {
+        let resolution = self.desc.chunk_resolution;
+        let row = resolution + 1;
+        let extent = self.extent();
+        let half = self.desc.size * 0.5;
+
+        let mut data = MeshData::default();
+        for j in 0..row {
+            for i in 0..row {
+                let world_x = x + size * i as f32 / resolution as f32;
+                let world_z = z + size * j as f32 / resolution as f32;
+                let (u, v) = ((world_x + half) / self.desc.size, (world_z + half) / self.desc.size);
+
+                data.vertices.push(MeshVertex {
+                    position: [world_x, self.heightmap.sample(u, v) * self.desc.height_scale, world_z],
+                    normal: self.heightmap.normal(u, v, extent).into(),
+                    uv: [u, v],
+                });
+            }
+        }
+
+        for j in 0..resolution {
+            for i in 0..resolution {
+                let corner = j * row + i;
+                data.indices.extend([corner, corner + row, corner + 1, corner + 1, corner + row, corner + row + 1]);
+            }
+        }
+
+        // The border, going around the grid, each vertex followed by its copy at the bottom of the skirt.
+        let border = (0..resolution)
+            .chain((0..resolution).map(|j| j * row + resolution))
+            .chain((0..resolution).map(|i| resolution * row + resolution - i))
+            .chain((0..resolution).map(|j| (resolution - j) * row));
+        let first_skirt = data.vertices.len() as u32;
+        for vertex in border {
+            let mut skirt = data.vertices[vertex as usize];
+            skirt.position[1] -= self.desc.skirt_depth;
+            data.vertices.push(data.vertices[vertex as usize]);
+            data.vertices.push(skirt);
+        }
+
+        let border_count = 4 * resolution;
+        for edge in 0..border_count {
+            let top = first_skirt + edge * 2;
+            let next = first_skirt + (edge + 1) % border_count * 2;
+            data.indices.extend([top, top + 1, next, next, top + 1, next + 1]);
+        }
+
+        data
+    }
+
+    fn extent(&self) -> Vector3<f32> {
+        Vector3::new(self.desc.size, self.desc.height_scale, self.desc.size)
+    }
+
+    fn uv(&self, x: f32, z: f32) -> Option<(f32, f32)> {
+        let half = self.desc.size * 0.5;
+        let (u, v) = ((x + half) / self.desc.size, (z + half) / self.desc.size);
+        ((0.0..=1.0).contains(&u) && (0.0..=1.0).contains(&v)).then_some((u, v))
+    }
+}
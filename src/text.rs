@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use ab_glyph::{point, Font as _, FontVec, GlyphId, PxScale, PxScaleFont, ScaleFont};
+use anyhow::anyhow;
+use cgmath::Vector2;
+use log::debug;
+use crate::renderer2d::{Renderer2d, TextureId, Transform2d, UvRect};
+use crate::sampler::SamplerDesc;
+
+const ATLAS_WIDTH: u32 = 512;
+const INITIAL_ATLAS_HEIGHT: u32 = 64;
+
+/// Keeps every glyph well inside one shelf of the atlas.
+const MAX_PIXEL_SIZE: f32 = 256.0;
+
+/// Empty pixels around every glyph in the atlas, so filtering doesn't pull in its neighbours.
+const GLYPH_PADDING: u32 = 1;
+
+/// Glyphs are white and carry their coverage in alpha, so the sprite color tints them.
+const EMPTY_PIXEL: [u8; 4] = [255, 255, 255, 0];
+
+/// How a string is drawn by [`Font::draw_text`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextStyle {
+    /// Font size in pixels, or world units under a camera.
+    pub size: f32,
+    pub color: [f32; 4],
+    /// Breaks lines between words so they stay narrower than this, where a single word allows it.
+    pub max_width: Option<f32>,
+    /// Multiplies the line height the font asks for.
+    pub line_spacing: f32,
+}
+
+impl TextStyle {
+    pub fn new(size: f32) -> Self {
+        Self {
+            size,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_color(mut self, color: [f32; 4]) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn with_max_width(mut self, max_width: f32) -> Self {
+        self.max_width = Some(max_width);
+        self
+    }
+
+    pub fn with_line_spacing(mut self, line_spacing: f32) -> Self {
+        self.line_spacing = line_spacing;
+        self
+    }
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        Self {
+            size: 16.0,
+            color: [1.0; 4],
+            max_width: None,
+            line_spacing: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AtlasGlyph {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    /// Top-left corner of the bitmap relative to the pen position on the baseline, in atlas pixels.
+    offset: Vector2<f32>,
+}
+
+struct LaidOutGlyph {
+    id: GlyphId,
+    /// Pen position on the baseline, relative to the top-left corner of the text.
+    position: Vector2<f32>,
+}
+
+/// A TrueType or OpenType font rasterized at one size into an atlas texture of a [`Renderer2d`]. Printable ASCII
+/// is rasterized up front, anything else the first time it is drawn. Drawing at other sizes scales the glyphs, so
+/// rasterize at the largest size the font is used at.
+pub struct Font {
+    font: FontVec,
+    pixel_size: f32,
+    atlas: TextureId,
+    atlas_height: u32,
+    pixels: Vec<u8>,
+    /// `None` for glyphs without an outline, e.g. spaces.
+    glyphs: HashMap<GlyphId, Option<AtlasGlyph>>,
+    cursor: (u32, u32),
+    row_height: u32,
+    dirty: bool,
+}
+
+impl Font {
+    pub unsafe fn from_bytes(renderer: &mut Renderer2d, name: &str, data: Vec<u8>, pixel_size: f32) -> anyhow::Result<Self> {
+        if pixel_size <= 0.0 || pixel_size > MAX_PIXEL_SIZE {
+            return Err(anyhow!("Font '{}' can't be rasterized at {} pixels, the most is {}", name, pixel_size, MAX_PIXEL_SIZE));
+        }
+
+        let font = FontVec::try_from_vec(data).map_err(|err| anyhow!("Failed to load font '{}': {}", name, err))?;
+        let pixels = EMPTY_PIXEL.repeat((ATLAS_WIDTH * INITIAL_ATLAS_HEIGHT) as usize);
+        let atlas = renderer.create_texture(name, ATLAS_WIDTH, INITIAL_ATLAS_HEIGHT, &pixels, SamplerDesc::linear_clamp())?;
+
+        let mut font = Self {
+            font,
+            pixel_size,
+            atlas,
+            atlas_height: INITIAL_ATLAS_HEIGHT,
+            pixels,
+            glyphs: HashMap::new(),
+            cursor: (GLYPH_PADDING, GLYPH_PADDING),
+            row_height: 0,
+            dirty: false,
+        };
+
+        for c in ' '..='~' {
+            font.rasterize(font.font.glyph_id(c));
+        }
+
+        font.flush(renderer)?;
+        Ok(font)
+    }
+
+    pub unsafe fn from_file(
+        renderer: &mut Renderer2d,
+        path: impl AsRef<std::path::Path>,
+        pixel_size: f32,
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        Self::from_bytes(renderer, &path.to_string_lossy(), std::fs::read(path)?, pixel_size)
+    }
+
+    pub fn atlas(&self) -> TextureId {
+        self.atlas
+    }
+
+    /// Queues `text` on `renderer` with its top-left corner at `position` and returns the size it takes up. Glyphs
+    /// that aren't in the atlas yet are added to it, which waits for the device to go idle.
+    pub unsafe fn draw_text(
+        &mut self,
+        renderer: &mut Renderer2d,
+        text: &str,
+        position: Vector2<f32>,
+        style: &TextStyle,
+    ) -> anyhow::Result<Vector2<f32>> {
+        let (glyphs, size) = self.layout(text, style);
+
+        for glyph in &glyphs {
+            self.rasterize(glyph.id);
+        }
+
+        self.flush(renderer)?;
+
+        let scale = style.size / self.pixel_size;
+        let atlas_size = (ATLAS_WIDTH, self.atlas_height);
+
+        for glyph in &glyphs {
+            let Some(Some(atlas_glyph)) = self.glyphs.get(&glyph.id) else {
+                continue;
+            };
+
+            let transform = Transform2d::new(
+                position + glyph.position + atlas_glyph.offset * scale,
+                Vector2::new(atlas_glyph.width as f32, atlas_glyph.height as f32) * scale,
+            );
+            let uv_rect = UvRect::from_pixels(atlas_glyph.x, atlas_glyph.y, atlas_glyph.width, atlas_glyph.height, atlas_size);
+
+            renderer.draw_sprite(self.atlas, &transform, uv_rect, style.color);
+        }
+
+        Ok(size)
+    }
+
+    /// The size `text` takes up when drawn with `style`.
+    pub fn measure(&self, text: &str, style: &TextStyle) -> Vector2<f32> {
+        self.layout(text, style).1
+    }
+
+    /// Places every glyph of `text`, breaking lines at `\n` and between words that would go past `max_width`.
+    fn layout(&self, text: &str, style: &TextStyle) -> (Vec<LaidOutGlyph>, Vector2<f32>) {
+        let font = self.font.as_scaled(PxScale::from(style.size));
+        let line_height = (font.height() + font.line_gap()) * style.line_spacing;
+        let space = font.glyph_id(' ');
+
+        let mut glyphs = Vec::new();
+        let mut width: f32 = 0.0;
+        let mut y = font.ascent();
+
+        for line in text.split('\n') {
+            let mut x = 0.0;
+            let mut previous: Option<GlyphId> = None;
+
+            for (index, word) in line.split(' ').enumerate() {
+                if index > 0 {
+                    let space_advance = previous.map_or(0.0, |previous| font.kern(previous, space)) + font.h_advance(space);
+                    let word_width = word_width(&font, word);
+
+                    if style.max_width.is_some_and(|max_width| x > 0.0 && x + space_advance + word_width > max_width) {
+                        width = width.max(x);
+                        x = 0.0;
+                        y += line_height;
+                        previous = None;
+                    } else {
+                        x += space_advance;
+                        previous = Some(space);
+                    }
+                }
+
+                for c in word.chars() {
+                    let id = font.glyph_id(c);
+                    if let Some(previous) = previous {
+                        x += font.kern(previous, id);
+                    }
+
+                    glyphs.push(LaidOutGlyph {
+                        id,
+                        position: Vector2::new(x, y),
+                    });
+
+                    x += font.h_advance(id);
+                    previous = Some(id);
+                }
+            }
+
+            width = width.max(x);
+            y += line_height;
+        }
+
+        (glyphs, Vector2::new(width, y - font.ascent()))
+    }
+
+    fn rasterize(&mut self, id: GlyphId) {
+        if self.glyphs.contains_key(&id) {
+            return;
+        }
+
+        let glyph = id.with_scale_and_position(PxScale::from(self.pixel_size), point(0.0, 0.0));
+        let atlas_glyph = self.font.outline_glyph(glyph).map(|outlined| {
+            let bounds = outlined.px_bounds();
+            let (width, height) = (bounds.width() as u32, bounds.height() as u32);
+            let (x, y) = self.allocate(width, height);
+
+            let pixels = &mut self.pixels;
+            outlined.draw(|glyph_x, glyph_y, coverage| {
+                let index = (((y + glyph_y) * ATLAS_WIDTH + x + glyph_x) * 4 + 3) as usize;
+                pixels[index] = (coverage.clamp(0.0, 1.0) * 255.0) as u8;
+            });
+
+            AtlasGlyph {
+                x,
+                y,
+                width,
+                height,
+                offset: Vector2::new(bounds.min.x, bounds.min.y),
+            }
+        });
+
+        self.glyphs.insert(id, atlas_glyph);
+        self.dirty |= atlas_glyph.is_some();
+    }
+
+    /// Finds room for a glyph on the current shelf or a new one below it, growing the atlas when it is full.
+    fn allocate(&mut self, width: u32, height: u32) -> (u32, u32) {
+        if self.cursor.0 + width + GLYPH_PADDING > ATLAS_WIDTH {
+            self.cursor = (GLYPH_PADDING, self.cursor.1 + self.row_height);
+            self.row_height = 0;
+        }
+
+        while self.cursor.1 + height + GLYPH_PADDING > self.atlas_height {
+            self.atlas_height *= 2;
+            self.pixels.resize((ATLAS_WIDTH * self.atlas_height * 4) as usize, 0);
+            for pixel in self.pixels.chunks_exact_mut(4).skip((ATLAS_WIDTH * self.atlas_height / 2) as usize) {
+                pixel.copy_from_slice(&EMPTY_PIXEL);
+            }
+
+            debug!("Growing glyph atlas to {}x{}", ATLAS_WIDTH, self.atlas_height);
+        }
+
+        let position = self.cursor;
+        self.cursor.0 += width + GLYPH_PADDING;
+        self.row_height = self.row_height.max(height + GLYPH_PADDING);
+        position
+    }
+
+    unsafe fn flush(&mut self, renderer: &mut Renderer2d) -> anyhow::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        renderer.update_texture(self.atlas, ATLAS_WIDTH, self.atlas_height, &self.pixels)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+fn word_width(font: &PxScaleFont<&FontVec>, word: &str) -> f32 {
+    let mut width = 0.0;
+    let mut previous: Option<GlyphId> = None;
+
+    for c in word.chars() {
+        let id = font.glyph_id(c);
+        if let Some(previous) = previous {
+            width += font.kern(previous, id);
+        }
+
+        width += font.h_advance(id);
+        previous = Some(id);
+    }
+
+    width
+}
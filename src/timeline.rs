@@ -0,0 +1,214 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use ash::vk;
+use crate::device::Device;
+
+/// A timeline semaphore: a monotonically increasing 64-bit counter that queues and the host can signal and wait on.
+/// One timeline replaces the per-submission fences and binary semaphores that would otherwise be needed to track
+/// completion of a stream of work.
+pub struct GpuTimeline {
+    device: Arc<Device>,
+    handle: vk::Semaphore,
+    last_value: u64,
+}
+
+impl GpuTimeline {
+    pub unsafe fn new(device: &Arc<Device>, initial_value: u64) -> anyhow::Result<Self> {
+        let mut type_info = vk::SemaphoreTypeCreateInfo::builder()
+            .semaphore_type(vk::SemaphoreType::TIMELINE)
+            .initial_value(initial_value);
+
+        let create_info = vk::SemaphoreCreateInfo::builder()
+            .push_next(&mut type_info);
+
+        Ok(Self {
+            device: device.clone(),
+            handle: device.create_semaphore(&create_info, None)?,
+            last_value: initial_value,
+        })
+    }
+
+    pub fn handle(&self) -> vk::Semaphore {
+        self.handle
+    }
+
+    /// Reserves the next value for a GPU or host signal.
+    pub fn next_value(&mut self) -> u64 {
+        self.last_value += 1;
+        self.last_value
+    }
+
+    /// The most recently reserved value. Waiting on it waits for all work submitted against this timeline so far.
+    pub fn last_value(&self) -> u64 {
+        self.last_value
+    }
+
+    /// The value the semaphore has currently reached on the GPU.
+    pub unsafe fn value(&self) -> anyhow::Result<u64> {
+        Ok(self.device.get_semaphore_counter_value(self.handle)?)
+    }
+
+    pub unsafe fn is_reached(&self, value: u64) -> anyhow::Result<bool> {
+        Ok(self.value()? >= value)
+    }
+
+    /// Signals `value` from the host.
+    pub unsafe fn signal(&mut self, value: u64) -> anyhow::Result<()> {
+        let signal_info = vk::SemaphoreSignalInfo::builder()
+            .semaphore(self.handle)
+            .value(value);
+
+        self.device.signal_semaphore(&signal_info)?;
+        self.last_value = self.last_value.max(value);
+        Ok(())
+    }
+
+    /// Blocks until the semaphore reaches `value`.
+    pub unsafe fn wait(&self, value: u64) -> anyhow::Result<()> {
+        self.wait_timeout(value, None).map(|_| ())
+    }
+
+    /// Like [`wait`](Self::wait) but gives up after `timeout`, returning whether `value` was reached.
+    pub unsafe fn wait_timeout(&self, value: u64, timeout: Option<Duration>) -> anyhow::Result<bool> {
+        let semaphores = [self.handle];
+        let values = [value];
+        let wait_info = vk::SemaphoreWaitInfo::builder()
+            .semaphores(&semaphores)
+            .values(&values);
+
+        let timeout = timeout.map_or(u64::MAX, |timeout| timeout.as_nanos().min(u64::MAX as u128) as u64);
+        match self.device.wait_semaphores(&wait_info, timeout) {
+            Ok(()) => Ok(true),
+            Err(vk::Result::TIMEOUT) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// A wait on `value` for a [`Submission`].
+    pub fn at(&self, value: u64) -> TimelinePoint {
+        TimelinePoint {
+            semaphore: self.handle,
+            value,
+        }
+    }
+}
+
+impl Drop for GpuTimeline {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_semaphore(self.handle, None);
+        }
+    }
+}
+
+/// A semaphore and the value to wait for or signal. Binary semaphores use a value of 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimelinePoint {
+    pub semaphore: vk::Semaphore,
+    pub value: u64,
+}
+
+impl TimelinePoint {
+    pub fn binary(semaphore: vk::Semaphore) -> Self {
+        Self { semaphore, value: 0 }
+    }
+}
+
+/// Collects the waits and signals of one queue submission, mixing timeline and binary semaphores, and expresses
+/// cross-queue dependencies such as graphics work waiting for the transfer queue.
+#[derive(Default)]
+pub struct Submission {
+    wait_semaphores: Vec<vk::Semaphore>,
+    wait_values: Vec<u64>,
+    wait_stages: Vec<vk::PipelineStageFlags>,
+    signal_semaphores: Vec<vk::Semaphore>,
+    signal_values: Vec<u64>,
+}
+
+impl Submission {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn wait(mut self, point: TimelinePoint, stage: vk::PipelineStageFlags) -> Self {
+        self.wait_semaphores.push(point.semaphore);
+        self.wait_values.push(point.value);
+        self.wait_stages.push(stage);
+        self
+    }
+
+    pub fn signal(mut self, point: TimelinePoint) -> Self {
+        self.signal_semaphores.push(point.semaphore);
+        self.signal_values.push(point.value);
+        self
+    }
+
+    pub unsafe fn submit(&self, device: &Device, queue: vk::Queue, command_buffers: &[vk::CommandBuffer]) -> anyhow::Result<()> {
+        let mut timeline_info = vk::TimelineSemaphoreSubmitInfo::builder()
+            .wait_semaphore_values(&self.wait_values)
+            .signal_semaphore_values(&self.signal_values);
+
+        let submit_info = vk::SubmitInfo::builder()
+            .wait_semaphores(&self.wait_semaphores)
+            .wait_dst_stage_mask(&self.wait_stages)
+            .command_buffers(command_buffers)
+            .signal_semaphores(&self.signal_semaphores)
+            .push_next(&mut timeline_info)
+            .build();
+
+        device.queue_submit(queue, &[submit_info], vk::Fence::null())?;
+        Ok(())
+    }
+}
+
+/// Objects destroyed while the GPU may still be using them, each kept until a timeline reaches the value it was
+/// retired at: the value signalled by the last submission that can use it.
+pub struct RetireQueue<T> {
+    /// Oldest first, since values only ever increase.
+    retired: VecDeque<(u64, T)>,
+}
+
+impl<T> RetireQueue<T> {
+    pub fn new() -> Self {
+        Self { retired: VecDeque::new() }
+    }
+
+    /// Keeps `object` until `value` is reached. Values must not decrease from one call to the next.
+    pub fn retire(&mut self, value: u64, object: T) {
+        debug_assert!(self.retired.back().is_none_or(|&(last, _)| last <= value), "Retired at {} after a later value", value);
+        self.retired.push_back((value, object));
+    }
+
+    /// The oldest object whose value `completed` has reached, for freeing them one at a time.
+    pub fn pop_completed(&mut self, completed: u64) -> Option<T> {
+        match self.retired.front() {
+            Some(&(value, _)) if value <= completed => self.retired.pop_front().map(|(_, object)| object),
+            _ => None,
+        }
+    }
+
+    /// Drops everything `completed` has reached.
+    pub fn free_completed(&mut self, completed: u64) {
+        while self.pop_completed(completed).is_some() {}
+    }
+
+    /// Drops everything at once, for when the device is idle or lost.
+    pub fn clear(&mut self) {
+        self.retired.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.retired.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.retired.is_empty()
+    }
+}
+
+impl<T> Default for RetireQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
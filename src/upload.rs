@@ -0,0 +1,423 @@
+use std::sync::Arc;
+use anyhow::anyhow;
+use ash::vk;
+use bytemuck::Pod;
+use log::debug;
+use crate::allocator::MemoryLocation;
+use crate::buffer::Buffer;
+use crate::device::Device;
+use crate::image::Image;
+use crate::timeline::{GpuTimeline, RetireQueue, Submission};
+
+/// Size of each frame's part of the staging buffer uploads are copied through unless configured otherwise.
+pub const DEFAULT_STAGING_SIZE: vk::DeviceSize = 8 << 20;
+
+/// Offsets into the staging buffer are multiples of this, and of an image's texel size.
+const STAGING_ALIGNMENT: vk::DeviceSize = 16;
+
+/// Identifies one upload by the value it signals on the uploader's timeline. Resources written by it may only be
+/// used after [`Uploader::acquire_ready`] has reported it as complete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UploadToken(u64);
+
+enum Release {
+    Buffer(vk::Buffer),
+    Image {
+        image: vk::Image,
+        range: vk::ImageSubresourceRange,
+        layout: vk::ImageLayout,
+    },
+}
+
+struct PendingUpload {
+    token: UploadToken,
+    command_buffer: vk::CommandBuffer,
+    release: Release,
+}
+
+/// The part of the staging buffer one frame in flight bump allocates from.
+#[derive(Debug, Clone, Copy, Default)]
+struct StagingRegion {
+    /// Bytes in use from the start of the region.
+    used: vk::DeviceSize,
+    /// Timeline value of the latest upload staged in the region, after which nothing reads from it anymore.
+    last_upload: u64,
+}
+
+/// Where an upload's data was copied to on the host.
+struct Staged {
+    buffer: vk::Buffer,
+    offset: vk::DeviceSize,
+    dedicated: Option<Buffer>,
+}
+
+/// Records staging copies on the transfer queue, which is a dedicated family when the device has one, so large
+/// uploads don't block the main thread or the graphics queue. Ownership of the written resources moves to the
+/// graphics family through release barriers here and acquire barriers recorded by [`acquire_ready`].
+///
+/// Data is staged in one persistent host visible buffer with a region for each frame in flight, so loading many
+/// resources in a frame doesn't allocate a buffer for each. A frame bump allocates from its own region, which
+/// [`acquire_ready`] rewinds when the frame comes around again and every copy staged in it has finished. An upload
+/// that doesn't fit what's left of the region gets a temporary buffer of its own instead.
+///
+/// [`acquire_ready`]: Self::acquire_ready
+pub struct Uploader {
+    device: Arc<Device>,
+    pool: vk::CommandPool,
+    timeline: GpuTimeline,
+    pending: Vec<PendingUpload>,
+    acquired: u64,
+    staging: Buffer,
+    region_size: vk::DeviceSize,
+    regions: Vec<StagingRegion>,
+    current_region: usize,
+    /// Buffers of their own for uploads that didn't fit their frame's region, kept until the copy has finished.
+    dedicated_staging: RetireQueue<Buffer>,
+    /// Bytes staged, in either kind of buffer, since the last `acquire_ready`.
+    staged_this_frame: vk::DeviceSize,
+}
+
+impl Uploader {
+    pub unsafe fn new(device: &Arc<Device>, frames_in_flight: usize) -> anyhow::Result<Self> {
+        Self::with_staging_size(device, DEFAULT_STAGING_SIZE, frames_in_flight)
+    }
+
+    /// Stages in `staging_size` bytes per frame in flight.
+    pub unsafe fn with_staging_size(device: &Arc<Device>, staging_size: vk::DeviceSize, frames_in_flight: usize) -> anyhow::Result<Self> {
+        let create_info = vk::CommandPoolCreateInfo::builder()
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+            .queue_family_index(device.queue_families().transfer);
+
+        let frames_in_flight = frames_in_flight.max(1);
+        let region_size = staging_size.max(STAGING_ALIGNMENT).next_multiple_of(STAGING_ALIGNMENT);
+        let staging_size = region_size * frames_in_flight as vk::DeviceSize;
+
+        Ok(Self {
+            device: device.clone(),
+            pool: device.create_command_pool(&create_info, None)?,
+            timeline: GpuTimeline::new(device, 0)?,
+            pending: Vec::new(),
+            acquired: 0,
+            staging: Buffer::new(device, "upload staging", staging_size, vk::BufferUsageFlags::TRANSFER_SRC, MemoryLocation::CpuToGpu)?,
+            region_size,
+            regions: vec![StagingRegion::default(); frames_in_flight],
+            current_region: 0,
+            dedicated_staging: RetireQueue::new(),
+            staged_this_frame: 0,
+        })
+    }
+
+    /// Copies `data` into the current frame's staging region at a multiple of `alignment`, or into a temporary
+    /// buffer when it doesn't fit.
+    unsafe fn stage<T: Pod>(&mut self, data: &[T], alignment: vk::DeviceSize) -> anyhow::Result<Staged> {
+        let size = std::mem::size_of_val(data) as vk::DeviceSize;
+        self.staged_this_frame += size;
+
+        let base = self.current_region as vk::DeviceSize * self.region_size;
+        let region = &mut self.regions[self.current_region];
+        let offset = (base + region.used).next_multiple_of(alignment);
+        if offset + size <= base + self.region_size {
+            region.used = offset + size - base;
+            self.staging.write(offset, data)?;
+            return Ok(Staged {
+                buffer: self.staging.handle(),
+                offset,
+                dedicated: None,
+            });
+        }
+
+        debug!("Upload of {} bytes doesn't fit the frame's staging region, staging it on its own", size);
+        let mut dedicated = Buffer::new(&self.device, "upload staging", size.max(4), vk::BufferUsageFlags::TRANSFER_SRC, MemoryLocation::CpuToGpu)?;
+        dedicated.write(0, data)?;
+        Ok(Staged {
+            buffer: dedicated.handle(),
+            offset: 0,
+            dedicated: Some(dedicated),
+        })
+    }
+
+    fn transfers_ownership(&self) -> bool {
+        let families = self.device.queue_families();
+        families.transfer != families.graphics
+    }
+
+    unsafe fn submit<F>(&mut self, dedicated_staging: Option<Buffer>, release: Release, record: F) -> anyhow::Result<UploadToken>
+    where
+        F: FnOnce(&Device, vk::CommandBuffer),
+    {
+        let allocate_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(self.pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+        let command_buffer = self.device.allocate_command_buffers(&allocate_info)?[0];
+
+        let begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        self.device.begin_command_buffer(command_buffer, &begin_info)?;
+        record(&self.device, command_buffer);
+
+        if self.transfers_ownership() {
+            self.record_ownership_barrier(command_buffer, &release, false);
+        }
+
+        self.device.end_command_buffer(command_buffer)?;
+
+        let command_buffers = [command_buffer];
+        let value = self.timeline.last_value() + 1;
+        let submission = Submission::new().signal(self.timeline.at(value));
+
+        if let Err(err) = submission.submit(&self.device, self.device.transfer_queue(), &command_buffers) {
+            self.device.free_command_buffers(self.pool, &command_buffers);
+            return Err(err);
+        }
+
+        let token = UploadToken(self.timeline.next_value());
+        match dedicated_staging {
+            Some(staging) => self.dedicated_staging.retire(token.0, staging),
+            None => self.regions[self.current_region].last_upload = token.0,
+        }
+        self.pending.push(PendingUpload {
+            token,
+            command_buffer,
+            release,
+        });
+
+        Ok(token)
+    }
+
+    /// Release barrier on the transfer queue (`acquire == false`) or the matching acquire barrier on the graphics
+    /// queue. Both carry the same layout transition, as the spec requires. Without a separate transfer family only
+    /// the acquire side is recorded, as a plain barrier.
+    unsafe fn record_ownership_barrier(&self, command_buffer: vk::CommandBuffer, release: &Release, acquire: bool) {
+        let families = self.device.queue_families();
+        let (src_family, dst_family) = if self.transfers_ownership() {
+            (families.transfer, families.graphics)
+        } else {
+            (vk::QUEUE_FAMILY_IGNORED, vk::QUEUE_FAMILY_IGNORED)
+        };
+
+        let (src_stage, src_access, dst_stage, dst_access) = match (acquire, self.transfers_ownership()) {
+            (false, _) => (
+                vk::PipelineStageFlags::TRANSFER,
+                vk::AccessFlags::TRANSFER_WRITE,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::AccessFlags::empty(),
+            ),
+            (true, true) => (
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::AccessFlags::empty(),
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::AccessFlags::MEMORY_READ,
+            ),
+            (true, false) => (
+                vk::PipelineStageFlags::TRANSFER,
+                vk::AccessFlags::TRANSFER_WRITE,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::AccessFlags::MEMORY_READ,
+            ),
+        };
+
+        match *release {
+            Release::Buffer(buffer) => {
+                let barrier = vk::BufferMemoryBarrier::builder()
+                    .src_access_mask(src_access)
+                    .dst_access_mask(dst_access)
+                    .src_queue_family_index(src_family)
+                    .dst_queue_family_index(dst_family)
+                    .buffer(buffer)
+                    .offset(0)
+                    .size(vk::WHOLE_SIZE)
+                    .build();
+
+                self.device.cmd_pipeline_barrier(command_buffer, src_stage, dst_stage, vk::DependencyFlags::empty(), &[], &[barrier], &[]);
+            }
+            Release::Image { image, range, layout } => {
+                let barrier = vk::ImageMemoryBarrier::builder()
+                    .src_access_mask(src_access)
+                    .dst_access_mask(dst_access)
+                    .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                    .new_layout(layout)
+                    .src_queue_family_index(src_family)
+                    .dst_queue_family_index(dst_family)
+                    .image(image)
+                    .subresource_range(range)
+                    .build();
+
+                self.device.cmd_pipeline_barrier(command_buffer, src_stage, dst_stage, vk::DependencyFlags::empty(), &[], &[], &[barrier]);
+            }
+        }
+    }
+
+    /// Starts copying `data` to `offset` of a device local `buffer`.
+    pub unsafe fn upload_buffer<T: Pod>(&mut self, buffer: &Buffer, offset: vk::DeviceSize, data: &[T]) -> anyhow::Result<UploadToken> {
+        let size = std::mem::size_of_val(data) as vk::DeviceSize;
+        if offset + size > buffer.size() {
+            return Err(anyhow!("Upload of {} bytes at offset {} does not fit a buffer of {} bytes", size, offset, buffer.size()));
+        }
+
+        let staged = self.stage(data, STAGING_ALIGNMENT)?;
+
+        let (src, src_offset, dst) = (staged.buffer, staged.offset, buffer.handle());
+        self.submit(staged.dedicated, Release::Buffer(dst), |device, command_buffer| {
+            device.cmd_copy_buffer(command_buffer, src, dst, &[vk::BufferCopy {
+                src_offset,
+                dst_offset: offset,
+                size,
+            }]);
+        })
+    }
+
+    /// Writes `data` to `offset` of `buffer`, copying straight into its memory when it is mapped and going through
+    /// [`upload_buffer`](Self::upload_buffer) when it is device local. Returns the upload's token when it was
+    /// staged; a direct write is seen by anything submitted afterwards.
+    pub unsafe fn write_buffer<T: Pod>(&mut self, buffer: &mut Buffer, offset: vk::DeviceSize, data: &[T]) -> anyhow::Result<Option<UploadToken>> {
+        if buffer.is_mapped() {
+            buffer.write(offset, data)?;
+            return Ok(None);
+        }
+
+        self.upload_buffer(buffer, offset, data).map(Some)
+    }
+
+    /// Starts copying tightly packed pixels into mip level 0 of every layer of `image`. The image ends up in
+    /// `final_layout`; mip chains have to be generated on the graphics queue afterwards.
+    pub unsafe fn upload_image(&mut self, image: &Image, pixels: &[u8], final_layout: vk::ImageLayout) -> anyhow::Result<UploadToken> {
+        let desc = *image.desc();
+        // Buffer to image copies have to start at a multiple of the texel size.
+        let texels = (desc.width * desc.height * desc.array_layers).max(1) as vk::DeviceSize;
+        let texel_size = (pixels.len() as vk::DeviceSize / texels).max(1);
+        let staged = self.stage(pixels, lcm(texel_size, STAGING_ALIGNMENT))?;
+
+        let range = vk::ImageSubresourceRange {
+            aspect_mask: image.aspect(),
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: desc.array_layers,
+        };
+
+        let region = vk::BufferImageCopy {
+            buffer_offset: staged.offset,
+            buffer_row_length: 0,
+            buffer_image_height: 0,
+            image_subresource: vk::ImageSubresourceLayers {
+                aspect_mask: image.aspect(),
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: desc.array_layers,
+            },
+            image_offset: vk::Offset3D::default(),
+            image_extent: vk::Extent3D {
+                width: desc.width,
+                height: desc.height,
+                depth: 1,
+            },
+        };
+
+        let (src, dst) = (staged.buffer, image.handle());
+        let release = Release::Image { image: dst, range, layout: final_layout };
+
+        self.submit(staged.dedicated, release, |device, command_buffer| {
+            let to_transfer = vk::ImageMemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::empty())
+                .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(dst)
+                .subresource_range(range)
+                .build();
+
+            device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TOP_OF_PIPE, vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(), &[], &[], &[to_transfer]);
+            device.cmd_copy_buffer_to_image(command_buffer, src, dst, vk::ImageLayout::TRANSFER_DST_OPTIMAL, &[region]);
+        })
+    }
+
+    /// Records acquire barriers on the graphics queue for every upload whose copy has finished, frees their staging
+    /// memory and returns the most recent token that is now safe to use. Call this at the start of each frame's
+    /// command buffer; uploads until the next call stage in `frame_index`'s region, which is rewound here once its
+    /// copies have finished.
+    pub unsafe fn acquire_ready(&mut self, command_buffer: vk::CommandBuffer, frame_index: usize) -> anyhow::Result<Option<UploadToken>> {
+        let completed = self.timeline.value()?;
+        let finished = self.pending.iter().take_while(|upload| upload.token.0 <= completed).count();
+
+        for upload in self.pending.drain(..finished).collect::<Vec<_>>() {
+            self.record_ownership_barrier(command_buffer, &upload.release, true);
+            self.device.free_command_buffers(self.pool, &[upload.command_buffer]);
+            self.acquired = upload.token.0;
+            debug!("Upload {} complete", upload.token.0);
+        }
+        self.dedicated_staging.free_completed(completed);
+
+        // A copy still reading the region keeps its bytes; the frame allocates after them until the next time around.
+        self.current_region = frame_index % self.regions.len();
+        let region = &mut self.regions[self.current_region];
+        if region.last_upload <= completed {
+            region.used = 0;
+        }
+        self.staged_this_frame = 0;
+
+        Ok((self.acquired > 0).then_some(UploadToken(self.acquired)))
+    }
+
+    /// Whether `token` has been acquired by [`acquire_ready`](Self::acquire_ready).
+    pub fn is_ready(&self, token: UploadToken) -> bool {
+        token.0 <= self.acquired
+    }
+
+    /// Whether the copy for `token` has finished, so the next [`acquire_ready`](Self::acquire_ready) picks it up.
+    pub unsafe fn is_complete(&self, token: UploadToken) -> anyhow::Result<bool> {
+        Ok(self.is_ready(token) || self.timeline.is_reached(token.0)?)
+    }
+
+    /// Blocks until the copy for `token` has finished on the transfer queue. It still has to go through
+    /// [`acquire_ready`](Self::acquire_ready) before use.
+    pub unsafe fn wait(&self, token: UploadToken) -> anyhow::Result<()> {
+        self.timeline.wait(token.0)
+    }
+
+    /// The timeline uploads signal, for submissions on other queues that depend on them.
+    pub fn timeline(&self) -> &GpuTimeline {
+        &self.timeline
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Size of each frame's staging region.
+    pub fn staging_size(&self) -> vk::DeviceSize {
+        self.region_size
+    }
+
+    /// How much of the current frame's staging region is in use.
+    pub fn staging_used(&self) -> vk::DeviceSize {
+        self.regions[self.current_region].used
+    }
+
+    /// Bytes uploaded since the last [`acquire_ready`](Self::acquire_ready), whether or not they fit the staging buffer.
+    pub fn staged_this_frame(&self) -> vk::DeviceSize {
+        self.staged_this_frame
+    }
+}
+
+fn lcm(a: vk::DeviceSize, b: vk::DeviceSize) -> vk::DeviceSize {
+    let (mut x, mut y) = (a, b);
+    while y != 0 {
+        (x, y) = (y, x % y);
+    }
+    a / x * b
+}
+
+impl Drop for Uploader {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = self.timeline.wait(self.timeline.last_value());
+            self.pending.clear();
+            self.dedicated_staging.clear();
+
+            self.device.destroy_command_pool(self.pool, None);
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::VecDeque;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use ash::extensions::ext;
+use ash::vk;
+use log::{debug, error, info, warn};
+
+pub const VALIDATION_LAYER_NAME: &CStr = c"VK_LAYER_KHRONOS_validation";
+
+/// Lets the instance configure its layers; provided by recent validation layers.
+pub const LAYER_SETTINGS_EXTENSION_NAME: &CStr = c"VK_EXT_layer_settings";
+
+const MESSAGE_ID_FILTER_SETTING: &CStr = c"message_id_filter";
+const LAYER_SETTINGS_CREATE_INFO: vk::StructureType = vk::StructureType::from_raw(1_000_496_000);
+const LAYER_SETTING_TYPE_STRING: i32 = 7;
+
+/// `VkLayerSettingEXT`, which this version of ash has no bindings for.
+#[repr(C)]
+struct LayerSetting {
+    layer_name: *const c_char,
+    setting_name: *const c_char,
+    ty: i32,
+    value_count: u32,
+    values: *const c_void,
+}
+
+/// `VkLayerSettingsCreateInfoEXT`.
+#[repr(C)]
+struct LayerSettingsCreateInfo {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    setting_count: u32,
+    settings: *const LayerSetting,
+}
+
+/// The validation layer's `message_id_filter` setting for the muted message IDs, so the layer doesn't produce those
+/// messages in the first place. Points into itself, hence the box; it must outlive instance creation.
+pub struct MessageIdFilter {
+    _ids: CString,
+    values: [*const c_char; 1],
+    setting: LayerSetting,
+    create_info: LayerSettingsCreateInfo,
+}
+
+impl MessageIdFilter {
+    /// `None` without muted IDs. `next` is chained after the filter's create info.
+    pub fn new(config: &ValidationConfig, next: *const c_void) -> Option<Box<Self>> {
+        if config.muted_message_ids.is_empty() {
+            return None;
+        }
+
+        let ids = CString::new(config.muted_message_ids.join(",")).ok()?;
+        let mut filter = Box::new(Self {
+            values: [ids.as_ptr()],
+            _ids: ids,
+            setting: LayerSetting {
+                layer_name: VALIDATION_LAYER_NAME.as_ptr(),
+                setting_name: MESSAGE_ID_FILTER_SETTING.as_ptr(),
+                ty: LAYER_SETTING_TYPE_STRING,
+                value_count: 1,
+                values: std::ptr::null(),
+            },
+            create_info: LayerSettingsCreateInfo {
+                s_type: LAYER_SETTINGS_CREATE_INFO,
+                p_next: next,
+                setting_count: 1,
+                settings: std::ptr::null(),
+            },
+        });
+
+        filter.setting.values = filter.values.as_ptr() as *const c_void;
+        filter.create_info.settings = &filter.setting;
+        Some(filter)
+    }
+
+    /// For the `p_next` chain of `VkInstanceCreateInfo`.
+    pub fn as_ptr(&self) -> *const c_void {
+        &self.create_info as *const LayerSettingsCreateInfo as *const c_void
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ValidationConfig {
+    /// Severities forwarded to `log`. Errors are always delivered to the messenger so they can be counted, but are only
+    /// logged when included here.
+    pub severities: vk::DebugUtilsMessageSeverityFlagsEXT,
+    pub message_types: vk::DebugUtilsMessageTypeFlagsEXT,
+    /// Message ID names (usually VUIDs such as `VUID-vkCmdDraw-None-02859`) that are dropped by the debug callback.
+    /// Muted errors don't count towards the error count either. Layers with `VK_EXT_layer_settings` also get them
+    /// as their `message_id_filter`, so they skip those checks altogether.
+    pub muted_message_ids: Vec<String>,
+    /// How many of the latest logged messages the messenger keeps for `DebugMessenger::recent_messages`.
+    pub recent_message_count: usize,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            severities: vk::DebugUtilsMessageSeverityFlagsEXT::WARNING | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
+            message_types: vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
+            muted_message_ids: Vec::new(),
+            recent_message_count: DEFAULT_RECENT_MESSAGE_COUNT,
+        }
+    }
+}
+
+pub const DEFAULT_RECENT_MESSAGE_COUNT: usize = 16;
+
+/// A message the debug callback logged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationMessage {
+    pub severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    pub message_id: Option<String>,
+    pub text: String,
+}
+
+impl ValidationConfig {
+    /// Subscribes to every severity and to general, validation and performance messages. Device address binding
+    /// messages are left out since they need `VK_EXT_device_address_binding_report`, which isn't enabled.
+    pub fn verbose() -> Self {
+        Self {
+            severities: vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE
+                | vk::DebugUtilsMessageSeverityFlagsEXT::INFO
+                | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+                | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
+            message_types: vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
+                | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION
+                | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_severities(mut self, severities: vk::DebugUtilsMessageSeverityFlagsEXT) -> Self {
+        self.severities = severities;
+        self
+    }
+
+    pub fn with_message_types(mut self, message_types: vk::DebugUtilsMessageTypeFlagsEXT) -> Self {
+        self.message_types = message_types;
+        self
+    }
+
+    pub fn with_recent_message_count(mut self, count: usize) -> Self {
+        self.recent_message_count = count;
+        self
+    }
+
+    pub fn mute(mut self, message_id: impl Into<String>) -> Self {
+        self.muted_message_ids.push(message_id.into());
+        self
+    }
+
+    fn is_muted(&self, message_id: &CStr) -> bool {
+        let message_id = message_id.to_string_lossy();
+        self.muted_message_ids.iter().any(|muted| *muted == message_id)
+    }
+}
+
+pub struct MessengerState {
+    config: ValidationConfig,
+    error_count: AtomicU32,
+    /// The callback may run on any thread that calls into Vulkan.
+    recent: Mutex<VecDeque<ValidationMessage>>,
+}
+
+impl MessengerState {
+    pub fn new(config: ValidationConfig) -> Box<Self> {
+        Box::new(Self {
+            recent: Mutex::new(VecDeque::with_capacity(config.recent_message_count)),
+            config,
+            error_count: AtomicU32::new(0),
+        })
+    }
+
+    /// The latest logged messages, oldest first.
+    pub fn recent_messages(&self) -> Vec<ValidationMessage> {
+        self.recent.lock().map_or_else(|_| Vec::new(), |recent| recent.iter().cloned().collect())
+    }
+
+    fn record(&self, message: ValidationMessage) {
+        let Ok(mut recent) = self.recent.lock() else {
+            return;
+        };
+
+        if recent.len() == self.config.recent_message_count {
+            recent.pop_front();
+        }
+        if self.config.recent_message_count > 0 {
+            recent.push_back(message);
+        }
+    }
+
+    pub fn error_count(&self) -> u32 {
+        self.error_count.load(Ordering::Relaxed)
+    }
+
+    pub fn config(&self) -> &ValidationConfig {
+        &self.config
+    }
+
+    /// Create info for a messenger reporting into this state. The returned struct borrows `self` through a raw
+    /// pointer, so the state must outlive any instance or messenger created with it.
+    pub fn create_info(&self) -> vk::DebugUtilsMessengerCreateInfoEXT {
+        vk::DebugUtilsMessengerCreateInfoEXT::builder()
+            .message_severity(self.config.severities | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR)
+            .message_type(self.config.message_types)
+            .pfn_user_callback(Some(debug_callback))
+            .user_data(self as *const Self as *mut c_void)
+            .build()
+    }
+}
+
+pub struct DebugMessenger {
+    debug_utils: ext::DebugUtils,
+    messenger: vk::DebugUtilsMessengerEXT,
+    state: Box<MessengerState>,
+}
+
+impl DebugMessenger {
+    pub unsafe fn new(entry: &ash::Entry, instance: &ash::Instance, state: Box<MessengerState>) -> anyhow::Result<Self> {
+        let debug_utils = ext::DebugUtils::new(entry, instance);
+        let messenger = debug_utils.create_debug_utils_messenger(&state.create_info(), None)?;
+
+        Ok(Self {
+            debug_utils,
+            messenger,
+            state,
+        })
+    }
+
+    pub fn error_count(&self) -> u32 {
+        self.state.error_count()
+    }
+
+    /// The latest messages logged, up to `ValidationConfig::recent_message_count`, oldest first.
+    pub fn recent_messages(&self) -> Vec<ValidationMessage> {
+        self.state.recent_messages()
+    }
+
+    /// Opens a label on `queue` that capture tools show around everything submitted until
+    /// [`end_queue_label`](Self::end_queue_label).
+    pub unsafe fn begin_queue_label(&self, queue: vk::Queue, name: &str) {
+        let name = CString::new(name).unwrap_or_default();
+        let label = vk::DebugUtilsLabelEXT::builder().label_name(&name);
+        self.debug_utils.queue_begin_debug_utils_label(queue, &label);
+    }
+
+    pub unsafe fn end_queue_label(&self, queue: vk::Queue) {
+        self.debug_utils.queue_end_debug_utils_label(queue);
+    }
+}
+
+impl Drop for DebugMessenger {
+    fn drop(&mut self) {
+        unsafe {
+            self.debug_utils.destroy_debug_utils_messenger(self.messenger, None);
+        }
+    }
+}
+
+/// Whether the validation layer provides `VK_EXT_layer_settings`, which [`MessageIdFilter`] needs.
+pub fn supports_layer_settings(entry: &ash::Entry) -> anyhow::Result<bool> {
+    let extensions = entry.enumerate_instance_extension_properties(Some(VALIDATION_LAYER_NAME))?;
+
+    Ok(extensions.iter().any(|extension| {
+        let name = unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) };
+        name == LAYER_SETTINGS_EXTENSION_NAME
+    }))
+}
+
+pub fn is_validation_layer_available(entry: &ash::Entry) -> anyhow::Result<bool> {
+    let layers = entry.enumerate_instance_layer_properties()?;
+
+    Ok(layers.iter().any(|layer| {
+        let name = unsafe { CStr::from_ptr(layer.layer_name.as_ptr()) };
+        name == VALIDATION_LAYER_NAME
+    }))
+}
+
+unsafe extern "system" fn debug_callback(
+    message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+    p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+    p_user_data: *mut c_void,
+) -> vk::Bool32 {
+    let Some(callback_data) = p_callback_data.as_ref() else {
+        return vk::FALSE;
+    };
+    let state = (p_user_data as *const MessengerState).as_ref();
+
+    let message_id = (!callback_data.p_message_id_name.is_null()).then(|| CStr::from_ptr(callback_data.p_message_id_name));
+    if let Some(message_id) = message_id {
+        if state.is_some_and(|state| state.config.is_muted(message_id)) {
+            return vk::FALSE;
+        }
+    }
+
+    if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+        if let Some(state) = state {
+            state.error_count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    if state.is_some_and(|state| !state.config.severities.intersects(message_severity)) {
+        return vk::FALSE;
+    }
+
+    let message = if callback_data.p_message.is_null() {
+        "(no message)".into()
+    } else {
+        CStr::from_ptr(callback_data.p_message).to_string_lossy()
+    };
+
+    if let Some(state) = state {
+        state.record(ValidationMessage {
+            severity: message_severity,
+            message_id: message_id.map(|message_id| message_id.to_string_lossy().into_owned()),
+            text: message.clone().into_owned(),
+        });
+    }
+
+    if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+        error!("[{:?}] {}", message_type, message);
+    } else if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::WARNING) {
+        warn!("[{:?}] {}", message_type, message);
+    } else if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::INFO) {
+        info!("[{:?}] {}", message_type, message);
+    } else {
+        debug!("[{:?}] {}", message_type, message);
+    }
+
+    vk::FALSE
+}
@@ -0,0 +1,97 @@
+use ash::vk;
+use cgmath::Vector2;
+use crate::renderer2d::{Renderer2d, Transform2d};
+use crate::text::{Font, TextStyle};
+use crate::validation::ValidationMessage;
+
+const TEXT_SIZE: f32 = 14.0;
+const MARGIN: f32 = 8.0;
+const BACKGROUND: [f32; 4] = [0.0, 0.0, 0.0, 0.75];
+const ERROR_COLOR: [f32; 4] = [1.0, 0.35, 0.35, 1.0];
+const WARNING_COLOR: [f32; 4] = [1.0, 0.85, 0.3, 1.0];
+const INFO_COLOR: [f32; 4] = [0.75, 0.75, 0.75, 1.0];
+
+/// Lists the latest validation messages over the top of the screen, shown and hidden with a hotkey. Drawn with a
+/// [`Renderer2d`] in screen pixels, so it lands in the wrong place while the renderer has a camera set.
+pub struct ValidationOverlay {
+    font: Font,
+    visible: bool,
+}
+
+impl ValidationOverlay {
+    /// Starts hidden.
+    pub unsafe fn new(renderer: &mut Renderer2d, font_data: Vec<u8>) -> anyhow::Result<Self> {
+        Ok(Self {
+            font: Font::from_bytes(renderer, "validation overlay", font_data, TEXT_SIZE)?,
+            visible: false,
+        })
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn set_visible(&mut self, visible: bool) {
+        self.visible = visible;
+    }
+
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+    }
+
+    /// Queues `messages`, oldest first, on a dark panel as wide as `extent` and returns the area it covers. Does
+    /// nothing while hidden.
+    pub unsafe fn draw(
+        &mut self,
+        renderer: &mut Renderer2d,
+        messages: &[ValidationMessage],
+        extent: vk::Extent2D,
+    ) -> anyhow::Result<Option<vk::Rect2D>> {
+        if !self.visible {
+            return Ok(None);
+        }
+
+        let max_width = extent.width as f32 - 2.0 * MARGIN;
+        let lines: Vec<(String, TextStyle)> = if messages.is_empty() {
+            vec![("No validation messages".to_owned(), TextStyle::new(TEXT_SIZE).with_color(INFO_COLOR))]
+        } else {
+            messages.iter()
+                .map(|message| {
+                    let text = match &message.message_id {
+                        Some(message_id) => format!("[{}] {}", message_id, message.text),
+                        None => message.text.clone(),
+                    };
+                    (text, TextStyle::new(TEXT_SIZE).with_color(severity_color(message.severity)).with_max_width(max_width))
+                })
+                .collect()
+        };
+
+        let height = lines.iter()
+            .map(|(text, style)| self.font.measure(text, style).y)
+            .sum::<f32>() + 2.0 * MARGIN;
+        renderer.draw_rect(&Transform2d::new(Vector2::new(0.0, 0.0), Vector2::new(extent.width as f32, height)), BACKGROUND);
+
+        let mut position = Vector2::new(MARGIN, MARGIN);
+        for (text, style) in &lines {
+            position.y += self.font.draw_text(renderer, text, position, style)?.y;
+        }
+
+        Ok(Some(vk::Rect2D {
+            offset: vk::Offset2D::default(),
+            extent: vk::Extent2D {
+                width: extent.width,
+                height: (height.ceil() as u32).min(extent.height),
+            },
+        }))
+    }
+}
+
+fn severity_color(severity: vk::DebugUtilsMessageSeverityFlagsEXT) -> [f32; 4] {
+    if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+        ERROR_COLOR
+    } else if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::WARNING) {
+        WARNING_COLOR
+    } else {
+        INFO_COLOR
+    }
+}